@@ -0,0 +1,372 @@
+// Headless runs of test ROM suites with a pass/fail scoreboard, so the
+// accuracy impact of a change can be measured with one command.
+//
+// Suites:
+//   nestest   the bundled nestest.nes, compared against nestest_no_cycle.log
+//   <name>    every .nes file in <dir>/<name>/, default dir test-roms/
+//
+// ROMs in a directory suite are expected to report like blargg's tests: once
+// $6001-$6003 hold DE B0 61, $6000 is the status (0x80 running, 0x81 press
+// reset, below 0x80 the result with 0 meaning passed) and $6004 on holds a
+// zero-terminated message. Test ROMs aren't redistributable, so nothing but
+// nestest is bundled; put the suites you have under test-roms/.
+
+use crate::bus::Bus;
+use crate::cartridge::{self, Rom};
+use crate::cpu::CPU;
+use crate::json;
+use crate::trace::trace;
+use crate::trace_compare::{Progress, TraceComparison, TraceState};
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+
+pub const NESTEST_ROM: &str = "nestest.nes";
+pub const NESTEST_LOG: &str = "nestest_no_cycle.log";
+pub const DEFAULT_DIR: &str = "test-roms";
+// a minute of emulated time, the slowest blargg tests need about half that
+pub const DEFAULT_MAX_FRAMES: usize = 3600;
+// the reset a 0x81 status asks for has to come at least 100ms later
+const RESET_DELAY_FRAMES: usize = 10;
+const SIGNATURE: [u8; 3] = [0xde, 0xb0, 0x61];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Passed,
+    Failed(String),
+    // the emulator couldn't run it: unsupported mapper, a panic in the core
+    Error(String),
+    TimedOut,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Outcome::Passed => write!(f, "pass"),
+            Outcome::Failed(reason) => write!(f, "FAIL  {}", reason),
+            Outcome::Error(reason) => write!(f, "ERROR {}", reason),
+            Outcome::TimedOut => write!(f, "FAIL  timed out"),
+        }
+    }
+}
+
+pub struct TestResult {
+    pub suite: String,
+    pub name: String,
+    pub outcome: Outcome,
+}
+
+pub struct Scoreboard {
+    pub results: Vec<TestResult>,
+}
+
+impl Default for Scoreboard {
+    fn default() -> Self {
+        Scoreboard::new()
+    }
+}
+
+impl Scoreboard {
+    pub fn new() -> Self {
+        Scoreboard { results: vec![] }
+    }
+
+    pub fn add(&mut self, suite: &str, name: &str, outcome: Outcome) {
+        self.results.push(TestResult {
+            suite: suite.to_string(),
+            name: name.to_string(),
+            outcome,
+        });
+    }
+
+    pub fn passed(&self) -> usize {
+        self.results.iter().filter(|result| result.outcome == Outcome::Passed).count()
+    }
+
+    pub fn all_passed(&self) -> bool {
+        self.passed() == self.results.len()
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        let mut suites: Vec<&str> = vec![];
+        for result in self.results.iter() {
+            if !suites.contains(&result.suite.as_str()) {
+                suites.push(&result.suite);
+            }
+        }
+        for suite in suites {
+            let results: Vec<&TestResult> = self.results.iter().filter(|result| result.suite == suite).collect();
+            let passed = results.iter().filter(|result| result.outcome == Outcome::Passed).count();
+            text.push_str(&format!("{}: {}/{}\n", suite, passed, results.len()));
+            for result in results {
+                text.push_str(&format!("  {:<40} {}\n", result.name, result.outcome));
+            }
+        }
+        text.push_str(&format!("total: {}/{} passed\n", self.passed(), self.results.len()));
+        text
+    }
+
+    pub fn to_json(&self) -> String {
+        let results = self
+            .results
+            .iter()
+            .map(|result| {
+                let (status, detail) = match &result.outcome {
+                    Outcome::Passed => ("pass", String::new()),
+                    Outcome::Failed(reason) => ("fail", reason.clone()),
+                    Outcome::Error(reason) => ("error", reason.clone()),
+                    Outcome::TimedOut => ("timeout", String::new()),
+                };
+                format!(
+                    "{{\"suite\":\"{}\",\"name\":\"{}\",\"status\":\"{}\",\"detail\":\"{}\"}}",
+                    json::escape(&result.suite),
+                    json::escape(&result.name),
+                    status,
+                    json::escape(&detail)
+                )
+            })
+            .collect::<Vec<String>>()
+            .join(",\n    ");
+        format!(
+            "{{\n  \"passed\": {},\n  \"total\": {},\n  \"results\": [\n    {}\n  ]\n}}\n",
+            self.passed(),
+            self.results.len(),
+            results
+        )
+    }
+}
+
+// Unsupported hardware panics in the core; one test crashing shouldn't take
+// the rest of the scoreboard with it.
+fn contain_crash<F: FnOnce() -> Outcome>(run: F) -> Outcome {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let outcome = panic::catch_unwind(AssertUnwindSafe(run));
+    panic::set_hook(default_hook);
+    outcome.unwrap_or_else(|panic| {
+        let message = panic
+            .downcast_ref::<String>()
+            .cloned()
+            .or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()))
+            .unwrap_or_default();
+        Outcome::Error(format!("crashed: {}", message))
+    })
+}
+
+// nestest in automation mode: start at $C000 and match every logged instruction
+pub fn run_nestest(rom: &[u8], reference_log: &str) -> Outcome {
+    let mut comparison = match TraceComparison::parse(reference_log) {
+        Ok(comparison) => comparison,
+        Err(err) => return Outcome::Error(err),
+    };
+    let mapper = match Rom::new(&rom.to_vec()).and_then(cartridge::create_mapper) {
+        Ok(mapper) => mapper,
+        Err(err) => return Outcome::Error(err),
+    };
+    contain_crash(move || {
+        let bus = Bus::with_mapper(mapper, |_ppu, _joypad1, _joypad2| {});
+        let mut cpu = CPU::new(bus);
+        cpu.reset();
+        cpu.program_counter = 0xc000;
+        let mut outcome = Outcome::TimedOut;
+        cpu.run_with_callback(|cpu| {
+            let state = TraceState {
+                pc: cpu.program_counter,
+                a: cpu.register_a,
+                x: cpu.register_x,
+                y: cpu.register_y,
+                p: cpu.register_p.bits(),
+                sp: cpu.stack_pointer,
+            };
+            match comparison.step(state, &trace(cpu)) {
+                Progress::Matching => return,
+                Progress::Diverged(divergence) => {
+                    outcome = Outcome::Failed(format!(
+                        "diverged at instruction {} in {}",
+                        divergence.instruction,
+                        divergence.actual.differences(&divergence.expected).join(", ")
+                    ))
+                }
+                Progress::Finished(_) => outcome = Outcome::Passed,
+            }
+            cpu.stop();
+        });
+        outcome
+    })
+}
+
+fn status_message(cpu: &CPU) -> String {
+    let mut message = vec![];
+    for addr in 0x6004..0x7000 {
+        match cpu.bus.peek(addr) {
+            0 => break,
+            byte => message.push(byte),
+        }
+    }
+    String::from_utf8_lossy(&message).trim().replace('\n', " ")
+}
+
+// blargg's $6000 status protocol, see the top of the file
+pub fn run_status_rom(rom: &[u8], max_frames: usize) -> Outcome {
+    let mapper = match Rom::new(&rom.to_vec()).and_then(cartridge::create_mapper) {
+        Ok(mapper) => mapper,
+        Err(err) => return Outcome::Error(err),
+    };
+    contain_crash(move || {
+        let bus = Bus::with_mapper(mapper, |_ppu, _joypad1, _joypad2| {});
+        let mut cpu = CPU::new(bus);
+        cpu.reset();
+        let mut outcome = Outcome::TimedOut;
+        let mut last_frame = 0;
+        let mut reset_at = None;
+        cpu.run_with_callback(|cpu| {
+            let frame = cpu.bus.frame_count();
+            if frame == last_frame {
+                return;
+            }
+            last_frame = frame;
+            if frame >= max_frames {
+                cpu.stop();
+                return;
+            }
+            let signature = [cpu.bus.peek(0x6001), cpu.bus.peek(0x6002), cpu.bus.peek(0x6003)];
+            if signature != SIGNATURE {
+                return;
+            }
+            match cpu.bus.peek(0x6000) {
+                0x80 => {}
+                0x81 => match reset_at {
+                    None => reset_at = Some(frame + RESET_DELAY_FRAMES),
+                    Some(at) if frame >= at => {
+                        reset_at = None;
+                        cpu.soft_reset();
+                    }
+                    Some(_) => {}
+                },
+                0 => {
+                    outcome = Outcome::Passed;
+                    cpu.stop();
+                }
+                code => {
+                    outcome = Outcome::Failed(format!("#{} {}", code, status_message(cpu)));
+                    cpu.stop();
+                }
+            }
+        });
+        outcome
+    })
+}
+
+pub fn run_nestest_suite(scoreboard: &mut Scoreboard) {
+    let outcome = match (std::fs::read(NESTEST_ROM), std::fs::read_to_string(NESTEST_LOG)) {
+        (Ok(rom), Ok(log)) => run_nestest(&rom, &log),
+        (Err(err), _) => Outcome::Error(format!("failed to read {}: {}", NESTEST_ROM, err)),
+        (_, Err(err)) => Outcome::Error(format!("failed to read {}: {}", NESTEST_LOG, err)),
+    };
+    scoreboard.add("nestest", NESTEST_ROM, outcome);
+}
+
+// every .nes file under the suite directory, subdirectories included
+pub fn run_directory_suite(scoreboard: &mut Scoreboard, suite: &str, dir: &Path, max_frames: usize) -> Result<(), String> {
+    let mut roms = vec![];
+    collect_roms(dir, &mut roms).map_err(|err| format!("failed to read {}: {}", dir.display(), err))?;
+    if roms.is_empty() {
+        return Err(format!("no .nes files in {}", dir.display()));
+    }
+    roms.sort();
+    for path in roms {
+        let name = path.strip_prefix(dir).unwrap_or(&path).display().to_string();
+        let outcome = match std::fs::read(&path) {
+            Ok(rom) => run_status_rom(&rom, max_frames),
+            Err(err) => Outcome::Error(format!("failed to read: {}", err)),
+        };
+        scoreboard.add(suite, &name, outcome);
+    }
+    Ok(())
+}
+
+fn collect_roms(dir: &Path, roms: &mut Vec<std::path::PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_roms(&path, roms)?;
+        } else if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("nes")) {
+            roms.push(path);
+        }
+    }
+    Ok(())
+}
+
+// the directory suites available under `dir`, sorted
+pub fn directory_suites(dir: &Path) -> Vec<String> {
+    let mut suites: Vec<String> = match std::fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_dir())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect(),
+        Err(_) => vec![],
+    };
+    suites.sort();
+    suites
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom_containing;
+
+    fn nes_file(mut program: Vec<u8>) -> Vec<u8> {
+        program.resize(0x8000, 0);
+        program[0x7ffc] = 0x00; // reset vector -> $8000
+        program[0x7ffd] = 0x80;
+        let rom = test_rom_containing(program);
+        let mut bytes = vec![0x4e, 0x45, 0x53, 0x1a, 0x02, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        bytes.extend(rom.prg_rom);
+        bytes.extend(rom.chr_rom);
+        bytes
+    }
+
+    fn reporting_rom(status: u8) -> Vec<u8> {
+        let mut program = vec![];
+        // the message, then the signature and the status
+        for (i, byte) in b"bad\0".iter().enumerate() {
+            program.extend([0xa9, *byte, 0x8d, 0x04 + i as u8, 0x60]); // LDA #byte; STA $6004+i
+        }
+        for (i, byte) in SIGNATURE.iter().enumerate() {
+            program.extend([0xa9, *byte, 0x8d, 0x01 + i as u8, 0x60]);
+        }
+        program.extend([0xa9, status, 0x8d, 0x00, 0x60]); // LDA #status; STA $6000
+        let end = 0x8000 + program.len() as u16;
+        program.extend([0x4c, end as u8, (end >> 8) as u8]); // JMP to itself
+        nes_file(program)
+    }
+
+    #[test]
+    fn test_status_protocol_and_scoreboard() {
+        let mut scoreboard = Scoreboard::new();
+        scoreboard.add("cpu", "pass.nes", run_status_rom(&reporting_rom(0), 60));
+        scoreboard.add("cpu", "fail.nes", run_status_rom(&reporting_rom(3), 60));
+        scoreboard.add("cpu", "running.nes", run_status_rom(&reporting_rom(0x80), 60));
+        // a panic in the core
+        scoreboard.add("cpu", "crash.nes", contain_crash(|| panic!("unsupported hardware")));
+
+        let outcomes: Vec<&Outcome> = scoreboard.results.iter().map(|result| &result.outcome).collect();
+        assert_eq!(outcomes[0], &Outcome::Passed);
+        assert_eq!(outcomes[1], &Outcome::Failed("#3 bad".to_string()));
+        assert_eq!(outcomes[2], &Outcome::TimedOut);
+        assert!(matches!(outcomes[3], Outcome::Error(_)));
+
+        assert!(!scoreboard.all_passed());
+        assert!(scoreboard.to_text().contains("cpu: 1/4\n"));
+        assert!(scoreboard.to_json().contains("{\"suite\":\"cpu\",\"name\":\"fail.nes\",\"status\":\"fail\",\"detail\":\"#3 bad\"}"));
+    }
+
+    #[test]
+    fn test_bundled_nestest_passes() {
+        let mut scoreboard = Scoreboard::new();
+        run_nestest_suite(&mut scoreboard);
+        assert_eq!(scoreboard.results[0].outcome, Outcome::Passed);
+    }
+}
@@ -0,0 +1,1301 @@
+// The APU: decodes what the game writes to $4000-$4017 and runs the five
+// channels, the frame counter and the DMC sample reader. Games rely on the
+// frame counter's and the DMC's IRQs and on the CPU cycles DMC fetches steal.
+//
+// channel() reports each channel's registers as the game wrote them, for tools
+// that follow the music (midi.rs); outputs() is what the channels' DACs put
+// out this cycle. Once the frontend sets a sample rate the outputs are mixed
+// with the cartridge's expansion audio (mixer.rs) every cycle, and each change
+// of the mix goes into a band-limited resampler (blip.rs) whose output lands in
+// `samples`, which the frontend drains every frame. The scope, when on, point
+// samples each channel's output at the same rate for the debugger's
+// oscilloscopes.
+
+use crate::blip::BlipBuffer;
+use crate::mixer::{self, HighPass, Mixer, Source};
+use crate::region::Region;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+use std::collections::VecDeque;
+
+// CPU cycles between handing the resampler's output over to `samples`
+const BLIP_BLOCK: u32 = 1024;
+// output samples each oscilloscope keeps, a little over a frame at 44.1kHz
+pub const SCOPE_SAMPLES: usize = 1024;
+
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28,
+    32, 30,
+];
+// 12.5%, 25%, 50% and 25% negated
+const DUTY_CYCLES: [[u8; 8]; 4] =
+    [[0, 1, 0, 0, 0, 0, 0, 0], [0, 1, 1, 0, 0, 0, 0, 0], [0, 1, 1, 1, 1, 0, 0, 0], [1, 0, 0, 1, 1, 1, 1, 1]];
+const TRIANGLE_STEPS: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    Dmc,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 5] = [Channel::Pulse1, Channel::Pulse2, Channel::Triangle, Channel::Noise, Channel::Dmc];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Channel::Pulse1 => "pulse1",
+            Channel::Pulse2 => "pulse2",
+            Channel::Triangle => "triangle",
+            Channel::Noise => "noise",
+            Channel::Dmc => "dmc",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChannelState {
+    pub enabled: bool,
+    // timer period as written; the DMC's is its rate in CPU cycles
+    pub period: u16,
+    // 0-15; pulse envelopes are reported at full volume
+    pub volume: u8,
+    // the length counter was reloaded since the last take_restarted()
+    pub restarted: bool,
+    // pulse duty cycle 0-3, 1 for noise in its short mode
+    pub duty: u8,
+    // what is left of the note, the DMC's in sample bytes
+    pub length: u16,
+    pub output: u8,
+}
+
+// volume envelope of the pulse and noise channels, clocked every quarter frame
+#[derive(Debug, Clone, Copy)]
+struct Envelope {
+    start: bool,
+    looping: bool,
+    constant: bool,
+    volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    fn new() -> Self {
+        Envelope { start: false, looping: false, constant: false, volume: 0, divider: 0, decay: 0 }
+    }
+
+    fn write(&mut self, data: u8) {
+        self.looping = data & 0b10_0000 != 0;
+        self.constant = data & 0b1_0000 != 0;
+        self.volume = data & 0b1111;
+    }
+
+    fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.constant {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+
+    // what channel() reports: constant volumes as set, envelopes as full
+    fn reported(&self) -> u8 {
+        if self.constant {
+            self.volume
+        } else {
+            15
+        }
+    }
+}
+
+// clocked every half frame; a disabled channel's counter stays at 0
+#[derive(Debug, Clone, Copy)]
+struct LengthCounter {
+    enabled: bool,
+    halted: bool,
+    count: u8,
+}
+
+impl LengthCounter {
+    fn new() -> Self {
+        LengthCounter { enabled: false, halted: false, count: 0 }
+    }
+
+    fn load(&mut self, index: u8) {
+        if self.enabled {
+            self.count = LENGTH_TABLE[index as usize & 0x1f];
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.count = 0;
+        }
+    }
+
+    fn clock(&mut self) {
+        if !self.halted && self.count > 0 {
+            self.count -= 1;
+        }
+    }
+}
+
+struct Pulse {
+    // pulse 1 negates its sweep in ones' complement, one lower than pulse 2
+    ones_complement: bool,
+    duty: u8,
+    step: u8,
+    period: u16,
+    // counts down CPU cycles, two per timer tick
+    timer: u16,
+    envelope: Envelope,
+    length: LengthCounter,
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_reload: bool,
+    sweep_divider: u8,
+    restarted: bool,
+}
+
+impl Pulse {
+    fn new(ones_complement: bool) -> Self {
+        Pulse {
+            ones_complement,
+            duty: 0,
+            step: 0,
+            period: 0,
+            timer: 0,
+            envelope: Envelope::new(),
+            length: LengthCounter::new(),
+            sweep_enabled: false,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_reload: false,
+            sweep_divider: 0,
+            restarted: false,
+        }
+    }
+
+    // `register` is 0-3 for $4000-$4003 or $4004-$4007
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.duty = data >> 6;
+                self.envelope.write(data);
+                self.length.halted = data & 0b10_0000 != 0;
+            }
+            1 => {
+                self.sweep_enabled = data & 0b1000_0000 != 0;
+                self.sweep_period = (data >> 4) & 0b111;
+                self.sweep_negate = data & 0b1000 != 0;
+                self.sweep_shift = data & 0b111;
+                self.sweep_reload = true;
+            }
+            2 => self.period = (self.period & 0x700) | data as u16,
+            _ => {
+                self.period = (self.period & 0xff) | ((data as u16 & 0b111) << 8);
+                self.length.load(data >> 3);
+                self.step = 0;
+                self.envelope.start = true;
+                self.restarted = true;
+            }
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period * 2 + 1;
+            self.step = (self.step + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn sweep_target(&self) -> u16 {
+        let change = self.period >> self.sweep_shift;
+        if self.sweep_negate {
+            self.period.saturating_sub(change + self.ones_complement as u16)
+        } else {
+            self.period + change
+        }
+    }
+
+    // periods below 8 and sweeps past $7FF silence the channel, swept or not
+    fn muted(&self) -> bool {
+        self.period < 8 || self.sweep_target() > 0x7ff
+    }
+
+    fn clock_sweep(&mut self) {
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.muted() {
+            self.period = self.sweep_target();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.muted() || self.length.count == 0 || DUTY_CYCLES[self.duty as usize][self.step as usize] == 0 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+
+    fn state(&self) -> ChannelState {
+        ChannelState {
+            enabled: self.length.enabled,
+            period: self.period,
+            volume: self.envelope.reported(),
+            restarted: self.restarted,
+            duty: self.duty,
+            length: self.length.count as u16,
+            output: self.output(),
+        }
+    }
+}
+
+struct Triangle {
+    period: u16,
+    timer: u16,
+    step: u8,
+    length: LengthCounter,
+    // $4008: the control flag also halts the length counter
+    control: bool,
+    linear_reload_value: u8,
+    linear_reload: bool,
+    linear: u8,
+    restarted: bool,
+}
+
+impl Triangle {
+    fn new() -> Self {
+        Triangle {
+            period: 0,
+            timer: 0,
+            step: 0,
+            length: LengthCounter::new(),
+            control: false,
+            linear_reload_value: 0,
+            linear_reload: false,
+            linear: 0,
+            restarted: false,
+        }
+    }
+
+    // `register` is 0-3 for $4008-$400B
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.control = data & 0b1000_0000 != 0;
+                self.length.halted = self.control;
+                self.linear_reload_value = data & 0x7f;
+            }
+            1 => {}
+            2 => self.period = (self.period & 0x700) | data as u16,
+            _ => {
+                self.period = (self.period & 0xff) | ((data as u16 & 0b111) << 8);
+                self.length.load(data >> 3);
+                self.linear_reload = true;
+                self.restarted = true;
+            }
+        }
+    }
+
+    // one step per CPU cycle, the triangle runs twice as fast as the pulses
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.length.count > 0 && self.linear > 0 {
+                self.step = (self.step + 1) % 32;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_linear(&mut self) {
+        if self.linear_reload {
+            self.linear = self.linear_reload_value;
+        } else if self.linear > 0 {
+            self.linear -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    // a stopped triangle holds its level rather than dropping to 0
+    fn output(&self) -> u8 {
+        TRIANGLE_STEPS[self.step as usize]
+    }
+
+    fn state(&self) -> ChannelState {
+        ChannelState {
+            enabled: self.length.enabled,
+            period: self.period,
+            // the linear counter reload value silences the triangle when 0
+            volume: if self.linear_reload_value == 0 { 0 } else { 15 },
+            restarted: self.restarted,
+            duty: 0,
+            length: self.length.count as u16,
+            output: self.output(),
+        }
+    }
+}
+
+struct Noise {
+    // the region's table, CPU cycles per shift
+    periods: &'static [u16; 16],
+    period: u16,
+    timer: u16,
+    short_mode: bool,
+    shift: u16,
+    envelope: Envelope,
+    length: LengthCounter,
+    restarted: bool,
+}
+
+impl Noise {
+    fn new() -> Self {
+        Noise {
+            periods: Region::Ntsc.noise_periods(),
+            period: Region::Ntsc.noise_periods()[0],
+            timer: 0,
+            short_mode: false,
+            shift: 1,
+            envelope: Envelope::new(),
+            length: LengthCounter::new(),
+            restarted: false,
+        }
+    }
+
+    // `register` is 0-3 for $400C-$400F
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.envelope.write(data);
+                self.length.halted = data & 0b10_0000 != 0;
+            }
+            1 => {}
+            2 => {
+                self.short_mode = data & 0b1000_0000 != 0;
+                self.period = self.periods[(data & 0x0f) as usize];
+            }
+            _ => {
+                self.length.load(data >> 3);
+                self.envelope.start = true;
+                self.restarted = true;
+            }
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period - 1;
+            let tap = if self.short_mode { 6 } else { 1 };
+            let feedback = (self.shift ^ (self.shift >> tap)) & 1;
+            self.shift = (self.shift >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.shift & 1 != 0 || self.length.count == 0 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+
+    fn state(&self) -> ChannelState {
+        ChannelState {
+            enabled: self.length.enabled,
+            period: self.period,
+            volume: self.envelope.reported(),
+            restarted: self.restarted,
+            duty: self.short_mode as u8,
+            length: self.length.count as u16,
+            output: self.output(),
+        }
+    }
+}
+
+// The delta modulation channel. Its memory reader fetches sample bytes over
+// DMA, which the bus performs: it polls dma_request() and answers with fill().
+pub struct Dmc {
+    irq_enabled: bool,
+    looping: bool,
+    // the region's table, CPU cycles per output bit
+    rates: &'static [u16; 16],
+    rate: u16,
+    timer: u16,
+    // $4012/$4013
+    sample_addr: u16,
+    sample_length: u16,
+    current_addr: u16,
+    bytes_remaining: u16,
+    buffer: Option<u8>,
+    shift: u8,
+    bits_remaining: u8,
+    silent: bool,
+    // 7 bit output level, $4011
+    pub level: u8,
+    irq: bool,
+}
+
+impl Dmc {
+    fn new() -> Self {
+        Dmc {
+            irq_enabled: false,
+            looping: false,
+            rates: Region::Ntsc.dmc_rates(),
+            rate: Region::Ntsc.dmc_rates()[0],
+            timer: Region::Ntsc.dmc_rates()[0],
+            sample_addr: 0xc000,
+            sample_length: 1,
+            current_addr: 0xc000,
+            bytes_remaining: 0,
+            buffer: None,
+            shift: 0,
+            bits_remaining: 8,
+            silent: true,
+            level: 0,
+            irq: false,
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_addr = self.sample_addr;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    fn tick(&mut self, cycles: u8) {
+        for _ in 0..cycles {
+            self.timer -= 1;
+            if self.timer == 0 {
+                self.timer = self.rate;
+                self.clock_output();
+            }
+        }
+    }
+
+    fn clock_output(&mut self) {
+        if !self.silent {
+            if self.shift & 1 != 0 {
+                if self.level <= 125 {
+                    self.level += 2;
+                }
+            } else if self.level >= 2 {
+                self.level -= 2;
+            }
+        }
+        self.shift >>= 1;
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.buffer.take() {
+                Some(sample) => {
+                    self.shift = sample;
+                    self.silent = false;
+                }
+                None => self.silent = true,
+            }
+        }
+    }
+
+    pub fn irq(&self) -> bool {
+        self.irq
+    }
+
+    // the address to fetch while the sample buffer is empty and bytes remain
+    pub fn dma_request(&self) -> Option<u16> {
+        if self.buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_addr)
+        } else {
+            None
+        }
+    }
+
+    pub fn fill(&mut self, sample: u8) {
+        self.buffer = Some(sample);
+        // the address wraps from $FFFF to $8000
+        self.current_addr = self.current_addr.checked_add(1).unwrap_or(0x8000);
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4010 => {
+                self.irq_enabled = data & 0b1000_0000 != 0;
+                self.looping = data & 0b0100_0000 != 0;
+                self.rate = self.rates[(data & 0x0f) as usize];
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+            }
+            0x4011 => self.level = data & 0x7f,
+            0x4012 => self.sample_addr = 0xc000 + data as u16 * 64,
+            0x4013 => self.sample_length = data as u16 * 16 + 1,
+            _ => {}
+        }
+    }
+}
+
+// Each channel's output over the last SCOPE_SAMPLES samples, oldest first.
+pub struct Scope {
+    channels: Vec<VecDeque<u8>>,
+}
+
+impl Scope {
+    fn new() -> Self {
+        Scope { channels: vec![VecDeque::with_capacity(SCOPE_SAMPLES); Channel::ALL.len()] }
+    }
+
+    fn push(&mut self, outputs: [u8; 5]) {
+        for (history, output) in self.channels.iter_mut().zip(outputs.iter()) {
+            if history.len() == SCOPE_SAMPLES {
+                history.pop_front();
+            }
+            history.push_back(*output);
+        }
+    }
+
+    pub fn channel(&self, channel: Channel) -> &VecDeque<u8> {
+        &self.channels[channel as usize]
+    }
+}
+
+// One band-limited, DC-blocked output at the frontend's sample rate: the mix,
+// or one source alone for recording it separately.
+struct Track {
+    blip: BlipBuffer,
+    // what the resampler last got
+    level: f32,
+    high_pass: HighPass,
+}
+
+impl Track {
+    fn new(clock_rate: f64, sample_rate: f64) -> Self {
+        Track { blip: BlipBuffer::new(clock_rate, sample_rate), level: 0.0, high_pass: HighPass::new() }
+    }
+
+    // the level from `clock` CPU cycles into the block on
+    fn set_level(&mut self, clock: u32, level: f32) {
+        if level != self.level {
+            self.blip.add_delta(clock, level - self.level);
+            self.level = level;
+        }
+    }
+
+    fn end_block(&mut self, out: &mut Vec<f32>) {
+        let start = out.len();
+        self.blip.end_block(BLIP_BLOCK, out);
+        for sample in out[start..].iter_mut() {
+            *sample = self.high_pass.filter(*sample);
+        }
+    }
+}
+
+pub struct ApuRegisters {
+    pulse: [Pulse; 2],
+    triangle: Triangle,
+    noise: Noise,
+    // $4017
+    five_step_mode: bool,
+    frame_irq_inhibit: bool,
+    frame_cycles: usize,
+    // the step of the frame sequence coming up next
+    frame_step: usize,
+    frame_irq: bool,
+    pub dmc: Dmc,
+    region: Region,
+    // CPU cycles per output sample, none until the frontend wants sound
+    cycles_per_sample: Option<f64>,
+    sample_rate: Option<f64>,
+    sample_clock: f64,
+    output: Option<Track>,
+    // in Source::ALL order, while stems are on and there is a sample rate
+    stems: Vec<Track>,
+    stems_on: bool,
+    // CPU cycles into the resampler's block
+    blip_clock: u32,
+    pub samples: Vec<f32>,
+    // each source alone, unaffected by the mixer, in Source::ALL order
+    pub stem_samples: Vec<Vec<f32>>,
+    // the cartridge's sound, mixed in with the channels
+    expansion: f32,
+    pub mixer: Mixer,
+    pub scope: Option<Scope>,
+}
+
+impl Default for ApuRegisters {
+    fn default() -> Self {
+        ApuRegisters::new()
+    }
+}
+
+impl ApuRegisters {
+    pub fn new() -> Self {
+        ApuRegisters {
+            pulse: [Pulse::new(true), Pulse::new(false)],
+            triangle: Triangle::new(),
+            noise: Noise::new(),
+            five_step_mode: false,
+            frame_irq_inhibit: false,
+            frame_cycles: 0,
+            frame_step: 0,
+            frame_irq: false,
+            dmc: Dmc::new(),
+            region: Region::Ntsc,
+            cycles_per_sample: None,
+            sample_rate: None,
+            sample_clock: 0.0,
+            output: None,
+            stems: vec![],
+            stems_on: false,
+            blip_clock: 0,
+            samples: vec![],
+            stem_samples: vec![vec![]; Source::ALL.len()],
+            expansion: 0.0,
+            mixer: Mixer::new(),
+            scope: None,
+        }
+    }
+
+    // power on state; the frontend's sample rate, mixer, scope and stems stay
+    pub fn power_cycle(&mut self) {
+        let mut apu = ApuRegisters::new();
+        apu.samples = std::mem::take(&mut self.samples);
+        apu.stem_samples = std::mem::take(&mut self.stem_samples);
+        apu.stems_on = self.stems_on;
+        apu.mixer = std::mem::take(&mut self.mixer);
+        apu.scope = self.scope.take();
+        apu.set_region(self.region);
+        apu.set_sample_rate(self.sample_rate);
+        *self = apu;
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.dmc.rates = region.dmc_rates();
+        self.noise.periods = region.noise_periods();
+        self.set_sample_rate(self.sample_rate);
+    }
+
+    // samples per second to mix the channels into, None stops mixing
+    pub fn set_sample_rate(&mut self, rate: Option<f64>) {
+        self.sample_rate = rate;
+        self.cycles_per_sample = rate.map(|rate| self.region.cpu_clock_hz() / rate);
+        let clock_rate = self.region.cpu_clock_hz();
+        match (rate, self.output.as_mut()) {
+            (Some(rate), Some(output)) => {
+                output.blip.set_rates(clock_rate, rate);
+                self.stems.iter_mut().for_each(|stem| stem.blip.set_rates(clock_rate, rate));
+            }
+            (Some(rate), None) => self.output = Some(Track::new(clock_rate, rate)),
+            (None, _) => self.output = None,
+        }
+        self.set_stems(self.stems_on);
+    }
+
+    // whether to also resample each source alone into `stem_samples`
+    pub fn set_stems(&mut self, on: bool) {
+        self.stems_on = on;
+        match self.sample_rate {
+            Some(rate) if on && self.stems.is_empty() => {
+                let clock_rate = self.region.cpu_clock_hz();
+                self.stems = Source::ALL.iter().map(|_| Track::new(clock_rate, rate)).collect();
+            }
+            Some(_) if on => {}
+            _ => self.stems.clear(),
+        }
+    }
+
+    pub fn set_expansion(&mut self, level: f32) {
+        self.expansion = level;
+    }
+
+    pub fn set_scope(&mut self, on: bool) {
+        if on != self.scope.is_some() {
+            self.scope = on.then(Scope::new);
+        }
+    }
+
+    pub fn tick(&mut self, cycles: u8) {
+        self.dmc.tick(cycles);
+        for _ in 0..cycles {
+            self.pulse[0].clock_timer();
+            self.pulse[1].clock_timer();
+            self.triangle.clock_timer();
+            self.noise.clock_timer();
+            self.clock_frame_counter();
+            if self.output.is_some() {
+                self.mix();
+            }
+            if let Some(cycles_per_sample) = self.cycles_per_sample {
+                self.sample_clock += 1.0;
+                if self.sample_clock >= cycles_per_sample {
+                    self.sample_clock -= cycles_per_sample;
+                    let outputs = self.outputs();
+                    if let Some(scope) = self.scope.as_mut() {
+                        scope.push(outputs);
+                    }
+                }
+            }
+        }
+    }
+
+    // feeds this cycle's mix, and each source for the stems, to the
+    // resamplers, and hands over their samples every BLIP_BLOCK cycles
+    fn mix(&mut self) {
+        let outputs = self.outputs();
+        let clock = self.blip_clock;
+        if let Some(output) = self.output.as_mut() {
+            output.set_level(clock, self.mixer.level(outputs, self.expansion));
+        }
+        for (source, stem) in Source::ALL.iter().zip(self.stems.iter_mut()) {
+            // the channels come in the same order as their sources
+            let mut levels = [0.0; 5];
+            let expansion = match source {
+                Source::Expansion => self.expansion,
+                _ => {
+                    levels[*source as usize] = outputs[*source as usize] as f32;
+                    0.0
+                }
+            };
+            stem.set_level(clock, mixer::dac(levels, expansion));
+        }
+        self.blip_clock += 1;
+        if self.blip_clock == BLIP_BLOCK {
+            self.blip_clock = 0;
+            if let Some(output) = self.output.as_mut() {
+                output.end_block(&mut self.samples);
+            }
+            for (stem, samples) in self.stems.iter_mut().zip(self.stem_samples.iter_mut()) {
+                stem.end_block(samples);
+            }
+        }
+    }
+
+    // Four steps of a quarter of the period each, IRQ on the fourth, or five
+    // without the IRQ where the fourth step does nothing.
+    fn clock_frame_counter(&mut self) {
+        self.frame_cycles += 1;
+        let step = self.frame_step + 1;
+        if self.frame_cycles < self.region.frame_counter_period() * step / 4 {
+            return;
+        }
+        let steps = if self.five_step_mode { 5 } else { 4 };
+        if !(self.five_step_mode && step == 4) {
+            self.clock_quarter_frame();
+        }
+        if step == 2 || step == steps {
+            self.clock_half_frame();
+        }
+        if step == 4 && !self.five_step_mode && !self.frame_irq_inhibit {
+            self.frame_irq = true;
+        }
+        if step == steps {
+            self.frame_step = 0;
+            self.frame_cycles = 0;
+        } else {
+            self.frame_step = step;
+        }
+    }
+
+    fn clock_quarter_frame(&mut self) {
+        self.pulse[0].envelope.clock();
+        self.pulse[1].envelope.clock();
+        self.noise.envelope.clock();
+        self.triangle.clock_linear();
+    }
+
+    fn clock_half_frame(&mut self) {
+        for pulse in self.pulse.iter_mut() {
+            pulse.length.clock();
+            pulse.clock_sweep();
+        }
+        self.triangle.length.clock();
+        self.noise.length.clock();
+    }
+
+    // the APU's contribution to the CPU IRQ line
+    pub fn irq(&self) -> bool {
+        self.frame_irq || self.dmc.irq
+    }
+
+    pub fn frame_irq(&self) -> bool {
+        self.frame_irq
+    }
+
+    // $4015 read: which length counters are running and the IRQ flags;
+    // reading acknowledges the frame IRQ but not the DMC one
+    pub fn read_status(&mut self) -> u8 {
+        let status = (self.pulse[0].length.count > 0) as u8
+            | ((self.pulse[1].length.count > 0) as u8) << 1
+            | ((self.triangle.length.count > 0) as u8) << 2
+            | ((self.noise.length.count > 0) as u8) << 3
+            | ((self.dmc.bytes_remaining > 0) as u8) << 4
+            | (self.frame_irq as u8) << 6
+            | (self.dmc.irq as u8) << 7;
+        self.frame_irq = false;
+        status
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4000..=0x4003 => self.pulse[0].write(addr - 0x4000, data),
+            0x4004..=0x4007 => self.pulse[1].write(addr - 0x4004, data),
+            0x4008..=0x400b => self.triangle.write(addr - 0x4008, data),
+            0x400c..=0x400f => self.noise.write(addr - 0x400c, data),
+            0x4015 => {
+                self.pulse[0].length.set_enabled(data & 0b0001 != 0);
+                self.pulse[1].length.set_enabled(data & 0b0010 != 0);
+                self.triangle.length.set_enabled(data & 0b0100 != 0);
+                self.noise.length.set_enabled(data & 0b1000 != 0);
+                // enabling the DMC restarts a finished sample, disabling stops it
+                if data & 0b1_0000 == 0 {
+                    self.dmc.bytes_remaining = 0;
+                } else if self.dmc.bytes_remaining == 0 {
+                    self.dmc.restart();
+                }
+                self.dmc.irq = false;
+            }
+            0x4010..=0x4013 => self.dmc.write(addr, data),
+            // the sequence restarts, in 5-step mode with a quarter and half frame
+            // straight away; setting the inhibit bit also clears a pending IRQ
+            0x4017 => {
+                self.five_step_mode = data & 0b1000_0000 != 0;
+                self.frame_irq_inhibit = data & 0b0100_0000 != 0;
+                if self.frame_irq_inhibit {
+                    self.frame_irq = false;
+                }
+                self.frame_cycles = 0;
+                self.frame_step = 0;
+                if self.five_step_mode {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn channel(&self, channel: Channel) -> ChannelState {
+        match channel {
+            Channel::Pulse1 => self.pulse[0].state(),
+            Channel::Pulse2 => self.pulse[1].state(),
+            Channel::Triangle => self.triangle.state(),
+            Channel::Noise => self.noise.state(),
+            Channel::Dmc => ChannelState {
+                enabled: self.dmc.bytes_remaining > 0,
+                period: self.dmc.rate,
+                volume: self.dmc.level >> 3,
+                restarted: false,
+                duty: 0,
+                length: self.dmc.bytes_remaining,
+                output: self.dmc.level,
+            },
+        }
+    }
+
+    // what each channel's DAC puts out: 0-15, the DMC 0-127
+    pub fn outputs(&self) -> [u8; 5] {
+        [self.pulse[0].output(), self.pulse[1].output(), self.triangle.output(), self.noise.output(), self.dmc.level]
+    }
+
+    pub fn take_restarted(&mut self, channel: Channel) -> bool {
+        let restarted = match channel {
+            Channel::Pulse1 => &mut self.pulse[0].restarted,
+            Channel::Pulse2 => &mut self.pulse[1].restarted,
+            Channel::Triangle => &mut self.triangle.restarted,
+            Channel::Noise => &mut self.noise.restarted,
+            Channel::Dmc => return false,
+        };
+        std::mem::replace(restarted, false)
+    }
+
+    // None while the channel is silent or its period is out of the audible range
+    pub fn frequency(&self, channel: Channel) -> Option<f64> {
+        let state = self.channel(channel);
+        if !state.enabled || state.volume == 0 {
+            return None;
+        }
+        let clock_hz = self.region.cpu_clock_hz();
+        match channel {
+            // periods below 8 mute the pulse sweep unit
+            Channel::Pulse1 | Channel::Pulse2 if state.period >= 8 => {
+                Some(clock_hz / (16.0 * (state.period as f64 + 1.0)))
+            }
+            Channel::Triangle if state.period >= 2 => Some(clock_hz / (32.0 * (state.period as f64 + 1.0))),
+            _ => None,
+        }
+    }
+}
+
+impl Savestate for Envelope {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.start);
+        state.bool(self.looping);
+        state.bool(self.constant);
+        state.u8(self.volume);
+        state.u8(self.divider);
+        state.u8(self.decay);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.start = state.bool()?;
+        self.looping = state.bool()?;
+        self.constant = state.bool()?;
+        self.volume = state.u8()?;
+        self.divider = state.u8()?;
+        self.decay = state.u8()?;
+        Ok(())
+    }
+}
+
+impl Savestate for LengthCounter {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        state.bool(self.halted);
+        state.u8(self.count);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.enabled = state.bool()?;
+        self.halted = state.bool()?;
+        self.count = state.u8()?;
+        Ok(())
+    }
+}
+
+impl Savestate for Pulse {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.duty);
+        state.u8(self.step);
+        state.u16(self.period);
+        state.u16(self.timer);
+        self.envelope.save_state(state);
+        self.length.save_state(state);
+        state.bool(self.sweep_enabled);
+        state.u8(self.sweep_period);
+        state.bool(self.sweep_negate);
+        state.u8(self.sweep_shift);
+        state.bool(self.sweep_reload);
+        state.u8(self.sweep_divider);
+        state.bool(self.restarted);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.duty = state.u8()? & 0b11;
+        self.step = state.u8()? % 8;
+        self.period = state.u16()?;
+        self.timer = state.u16()?;
+        self.envelope.load_state(state)?;
+        self.length.load_state(state)?;
+        self.sweep_enabled = state.bool()?;
+        self.sweep_period = state.u8()?;
+        self.sweep_negate = state.bool()?;
+        self.sweep_shift = state.u8()?;
+        self.sweep_reload = state.bool()?;
+        self.sweep_divider = state.u8()?;
+        self.restarted = state.bool()?;
+        Ok(())
+    }
+}
+
+impl Savestate for Triangle {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.period);
+        state.u16(self.timer);
+        state.u8(self.step);
+        self.length.save_state(state);
+        state.bool(self.control);
+        state.u8(self.linear_reload_value);
+        state.bool(self.linear_reload);
+        state.u8(self.linear);
+        state.bool(self.restarted);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.period = state.u16()?;
+        self.timer = state.u16()?;
+        self.step = state.u8()? % 32;
+        self.length.load_state(state)?;
+        self.control = state.bool()?;
+        self.linear_reload_value = state.u8()?;
+        self.linear_reload = state.bool()?;
+        self.linear = state.u8()?;
+        self.restarted = state.bool()?;
+        Ok(())
+    }
+}
+
+impl Savestate for Noise {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.period);
+        state.u16(self.timer);
+        state.bool(self.short_mode);
+        state.u16(self.shift);
+        self.envelope.save_state(state);
+        self.length.save_state(state);
+        state.bool(self.restarted);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.period = state.u16()?.max(1);
+        self.timer = state.u16()?;
+        self.short_mode = state.bool()?;
+        self.shift = state.u16()?;
+        self.envelope.load_state(state)?;
+        self.length.load_state(state)?;
+        self.restarted = state.bool()?;
+        Ok(())
+    }
+}
+
+impl Savestate for ApuRegisters {
+    fn save_state(&self, state: &mut StateWriter) {
+        for pulse in self.pulse.iter() {
+            pulse.save_state(state);
+        }
+        self.triangle.save_state(state);
+        self.noise.save_state(state);
+        state.bool(self.five_step_mode);
+        state.bool(self.frame_irq_inhibit);
+        state.usize(self.frame_cycles);
+        state.usize(self.frame_step);
+        state.bool(self.frame_irq);
+        self.dmc.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        for pulse in self.pulse.iter_mut() {
+            pulse.load_state(state)?;
+        }
+        self.triangle.load_state(state)?;
+        self.noise.load_state(state)?;
+        self.five_step_mode = state.bool()?;
+        self.frame_irq_inhibit = state.bool()?;
+        self.frame_cycles = state.usize()?;
+        self.frame_step = state.usize()?.min(4);
+        self.frame_irq = state.bool()?;
+        self.dmc.load_state(state)
+    }
+}
+
+impl Savestate for Dmc {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.irq_enabled);
+        state.bool(self.looping);
+        state.u16(self.rate);
+        state.u16(self.timer);
+        state.u16(self.sample_addr);
+        state.u16(self.sample_length);
+        state.u16(self.current_addr);
+        state.u16(self.bytes_remaining);
+        state.bool(self.buffer.is_some());
+        state.u8(self.buffer.unwrap_or(0));
+        state.u8(self.shift);
+        state.u8(self.bits_remaining);
+        state.bool(self.silent);
+        state.u8(self.level);
+        state.bool(self.irq);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.irq_enabled = state.bool()?;
+        self.looping = state.bool()?;
+        self.rate = state.u16()?;
+        self.timer = state.u16()?;
+        self.sample_addr = state.u16()?;
+        self.sample_length = state.u16()?;
+        self.current_addr = state.u16()?;
+        self.bytes_remaining = state.u16()?;
+        let buffered = state.bool()?;
+        let sample = state.u8()?;
+        self.buffer = if buffered { Some(sample) } else { None };
+        self.shift = state.u8()?;
+        self.bits_remaining = state.u8()?;
+        self.silent = state.bool()?;
+        self.level = state.u8()?;
+        self.irq = state.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn test_decode_pulse_and_triangle() {
+        let mut apu = ApuRegisters::new();
+        apu.write(0x4000, 0b0011_1000);
+        apu.write(0x4002, 0xfd);
+        apu.write(0x4003, 0x00);
+        assert_eq!(apu.frequency(Channel::Pulse1), None);
+
+        apu.write(0x4015, 0b101);
+        // $0FD is A4
+        let frequency = apu.frequency(Channel::Pulse1).unwrap();
+        assert!((frequency - 440.0).abs() < 1.0);
+        assert_eq!(apu.channel(Channel::Pulse1).volume, 8);
+        assert!(apu.take_restarted(Channel::Pulse1));
+        assert!(!apu.take_restarted(Channel::Pulse1));
+
+        apu.write(0x4008, 0x81);
+        apu.write(0x400a, 0x7e);
+        apu.write(0x400b, 0x00);
+        let frequency = apu.frequency(Channel::Triangle).unwrap();
+        assert!((frequency - 440.0).abs() < 1.0);
+        apu.write(0x4008, 0x80);
+        assert_eq!(apu.frequency(Channel::Triangle), None);
+    }
+
+    #[test]
+    fn test_channels_play_and_stop() {
+        let mut apu = ApuRegisters::new();
+        apu.set_sample_rate(Some(44100.0));
+        apu.set_scope(true);
+        apu.write(0x4015, 0b1111);
+        // pulse 1 at 50% duty, constant volume 12, 10 frames long
+        apu.write(0x4000, 0b1001_1100);
+        apu.write(0x4002, 0xfd);
+        apu.write(0x4003, 0b0000_1000);
+        // noise with an envelope decaying every quarter frame
+        apu.write(0x400c, 0b0000_0000);
+        apu.write(0x400e, 0x04);
+        apu.write(0x400f, 0b0000_1000);
+        assert_eq!(apu.read_status() & 0x0f, 0b1001);
+        assert_eq!(apu.channel(Channel::Pulse1).length, 254);
+
+        let mut pulse_levels = BTreeSet::new();
+        let mut noise_levels = BTreeSet::new();
+        for _ in 0..10000 {
+            apu.tick(3);
+            pulse_levels.insert(apu.outputs()[0]);
+            noise_levels.insert(apu.outputs()[3]);
+        }
+        assert_eq!(pulse_levels.into_iter().collect::<Vec<u8>>(), vec![0, 12]);
+        assert!(noise_levels.len() > 2, "{:?}", noise_levels);
+        // about 29 * 1024 / 40.6 samples in whole resampler blocks, the scope
+        // keeps the last of about 30000 / 40.6
+        assert!((725..=735).contains(&apu.samples.len()), "{}", apu.samples.len());
+        assert!(apu.samples.iter().any(|sample| sample.abs() > 0.05));
+        let scope = apu.scope.as_ref().unwrap().channel(Channel::Pulse1).len();
+        assert!((735..=740).contains(&scope), "{}", scope);
+
+        // disabling a channel stops it at once
+        apu.write(0x4015, 0b0001);
+        assert_eq!(apu.read_status() & 0x0f, 0b0001);
+        assert_eq!(apu.outputs()[3], 0);
+        // and takes no new notes
+        apu.write(0x400f, 0b0000_1000);
+        assert_eq!(apu.channel(Channel::Noise).length, 0);
+    }
+
+    #[test]
+    fn test_frame_counter_irq() {
+        let mut apu = ApuRegisters::new();
+        for _ in 0..Region::Ntsc.frame_counter_period() / 200 {
+            apu.tick(200);
+        }
+        assert!(!apu.irq());
+        apu.tick(200);
+        assert!(apu.irq());
+        assert_eq!(apu.read_status() & 0x40, 0x40);
+        assert!(!apu.irq());
+
+        // inhibited, or in 5-step mode, it never fires
+        for setting in [0x40, 0x80] {
+            apu.write(0x4017, setting);
+            for _ in 0..Region::Ntsc.frame_counter_period() {
+                apu.tick(3);
+            }
+            assert!(!apu.irq());
+        }
+    }
+
+    #[test]
+    fn test_dmc_fetches_and_irq() {
+        let mut apu = ApuRegisters::new();
+        apu.write(0x4010, 0x8f); // IRQ on, fastest rate
+        apu.write(0x4012, 0x01); // $C040
+        apu.write(0x4013, 0x00); // 1 byte
+        assert_eq!(apu.dmc.dma_request(), None);
+
+        apu.write(0x4015, 0b1_0000);
+        assert_eq!(apu.read_status() & 0x10, 0x10);
+        assert_eq!(apu.dmc.dma_request(), Some(0xc040));
+        apu.dmc.fill(0xff);
+        assert_eq!(apu.dmc.dma_request(), None);
+        assert!(apu.irq());
+        // reading $4015 leaves the DMC IRQ set, writing it clears it
+        assert_eq!(apu.read_status() & 0x90, 0x80);
+        apu.write(0x4015, 0);
+        assert!(!apu.irq());
+
+        // the byte in the buffer plays out: 8 rising bits after one silent byte
+        apu.write(0x4011, 0x40);
+        // the timer still counts down the power-on rate (428) before 54 applies
+        for _ in 0..4 {
+            apu.tick(107);
+        }
+        for _ in 0..7 {
+            apu.tick(54);
+        }
+        assert_eq!(apu.dmc.level, 0x40);
+        for _ in 0..4 {
+            apu.tick(54);
+        }
+        assert_eq!(apu.dmc.level, 0x48);
+
+        // looping samples never finish
+        apu.write(0x4010, 0x40);
+        apu.write(0x4015, 0b1_0000);
+        for _ in 0..3 {
+            apu.dmc.fill(0);
+            apu.dmc.buffer = None;
+        }
+        assert_eq!(apu.dmc.dma_request(), Some(0xc040));
+    }
+}
@@ -0,0 +1,210 @@
+// Audio output path: the emulator pushes samples into a ring buffer which the
+// SDL audio thread drains. Whenever the ring can't supply real-time samples
+// (paused, fast-forward, rewind, or plain underrun) the output holds the last
+// sample and fades it to silence instead of clicking or looping garbage, and
+// fades back in when samples flow again.
+//
+// The device is asked for SAMPLE_RATE; the APU resamples to whatever rate it
+// gives, and the frontend pushes each frame's samples once the frame is done.
+// Video paces the emulator, so the APU's rate never quite matches the sound
+// card's clock: each frame the frontend nudges the resampling rate by up to
+// MAX_RATE_DELTA to keep the ring half full, rather than letting it drift into
+// underruns or dropped samples over a long session.
+
+use sdl2::audio::AudioCallback;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+pub const SAMPLE_RATE: i32 = 44100;
+// ~10ms
+const FADE_SAMPLES: f32 = 441.0;
+// ~100ms of latency before old samples get dropped
+const RING_CAPACITY: usize = 4410;
+// 0.5%, a pitch change nobody hears
+const MAX_RATE_DELTA: f64 = 0.005;
+// weight of each frame's fill level in the running average; the device takes
+// samples in bursts, the average follows the trend rather than the bursts
+const FILL_SMOOTHING: f64 = 0.05;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PlaybackState {
+    Running,
+    Paused,
+    FastForward,
+    Rewind,
+}
+
+pub struct AudioRing {
+    samples: VecDeque<f32>,
+    state: PlaybackState,
+    gain: f32,
+    last_sample: f32,
+    // running average of samples.len()
+    fill: f64,
+}
+
+impl AudioRing {
+    pub fn new() -> Self {
+        AudioRing {
+            samples: VecDeque::with_capacity(RING_CAPACITY),
+            state: PlaybackState::Running,
+            gain: 0.0,
+            last_sample: 0.0,
+            fill: RING_CAPACITY as f64 / 2.0,
+        }
+    }
+
+    // Set by the frontend: Paused where emulation stops, FastForward and
+    // Rewind once a frame while the speed is unlocked or rewind is held.
+    // Anything but Running mutes the output.
+    pub fn set_state(&mut self, state: PlaybackState) {
+        if state != PlaybackState::Running {
+            // stale samples would play out of sync once we resume
+            self.samples.clear();
+        }
+        self.state = state;
+    }
+
+    pub fn state(&self) -> PlaybackState {
+        self.state
+    }
+
+    pub fn push(&mut self, samples: &[f32]) {
+        if self.state != PlaybackState::Running {
+            return;
+        }
+        for &sample in samples {
+            if self.samples.len() == RING_CAPACITY {
+                self.samples.pop_front();
+            }
+            self.samples.push_back(sample);
+        }
+    }
+
+    // What to multiply the APU's sample rate by for the next frame: above 1
+    // while the ring runs low, below while it fills up. Called once a frame.
+    pub fn rate_adjustment(&mut self) -> f64 {
+        if self.state != PlaybackState::Running {
+            return 1.0;
+        }
+        self.fill += (self.samples.len() as f64 - self.fill) * FILL_SMOOTHING;
+        let target = RING_CAPACITY as f64 / 2.0;
+        1.0 + MAX_RATE_DELTA * (target - self.fill) / target
+    }
+
+    pub fn fill(&mut self, out: &mut [f32]) {
+        for sample in out.iter_mut() {
+            let next = match self.state {
+                PlaybackState::Running => self.samples.pop_front(),
+                _ => None,
+            };
+            let target = match next {
+                Some(next) => {
+                    self.last_sample = next;
+                    1.0
+                }
+                None => 0.0,
+            };
+            if self.gain < target {
+                self.gain = (self.gain + 1.0 / FADE_SAMPLES).min(target);
+            } else if self.gain > target {
+                self.gain = (self.gain - 1.0 / FADE_SAMPLES).max(target);
+            }
+            *sample = self.last_sample * self.gain;
+        }
+    }
+}
+
+pub type SharedAudioRing = Arc<Mutex<AudioRing>>;
+
+pub struct AudioOutput {
+    pub ring: SharedAudioRing,
+}
+
+impl AudioCallback for AudioOutput {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        match self.ring.lock() {
+            Ok(mut ring) => ring.fill(out),
+            Err(_) => out.iter_mut().for_each(|sample| *sample = 0.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_underrun_fades_out_instead_of_cutting() {
+        let mut ring = AudioRing::new();
+        ring.push(&[0.5; 1000]);
+        let mut out = [0.0; 1000];
+        ring.fill(&mut out);
+        // fades in from silence
+        assert!(out[0] < 0.01);
+        assert_eq!(out[999], 0.5);
+
+        // underrun: the last sample is held and faded
+        let mut out = [1.0; 1000];
+        ring.fill(&mut out);
+        assert!(out[0] > 0.49);
+        assert!(out.windows(2).all(|w| w[1] <= w[0]));
+        assert_eq!(out[999], 0.0);
+    }
+
+    #[test]
+    fn test_rate_follows_fill_level() {
+        let mut ring = AudioRing::new();
+        // starved, the rate creeps up towards +0.5%
+        let mut adjustment = 1.0;
+        for _ in 0..100 {
+            adjustment = ring.rate_adjustment();
+        }
+        assert!(adjustment > 1.004 && adjustment <= 1.005, "{}", adjustment);
+
+        // overfull, it goes the other way
+        ring.push(&[0.0; RING_CAPACITY]);
+        for _ in 0..100 {
+            adjustment = ring.rate_adjustment();
+        }
+        assert!((0.995..0.996).contains(&adjustment), "{}", adjustment);
+
+        // half full is just right
+        let mut out = vec![0.0; RING_CAPACITY / 2];
+        ring.fill(&mut out);
+        for _ in 0..300 {
+            adjustment = ring.rate_adjustment();
+        }
+        assert!((adjustment - 1.0).abs() < 0.0001, "{}", adjustment);
+
+        // paused, nothing is playing to keep up with
+        ring.set_state(PlaybackState::Paused);
+        assert_eq!(ring.rate_adjustment(), 1.0);
+    }
+
+    #[test]
+    fn test_paused_drops_samples_and_mutes() {
+        for state in [PlaybackState::Paused, PlaybackState::FastForward, PlaybackState::Rewind] {
+            let mut ring = AudioRing::new();
+            ring.push(&[0.5; 1000]);
+            ring.set_state(state);
+            assert_eq!(ring.state(), state);
+            ring.push(&[0.5; 1000]);
+
+            let mut out = [1.0; 1000];
+            ring.fill(&mut out);
+            assert!(out.iter().all(|&sample| sample == 0.0), "{:?}", state);
+        }
+
+        let mut ring = AudioRing::new();
+        ring.set_state(PlaybackState::FastForward);
+
+        ring.set_state(PlaybackState::Running);
+        ring.push(&[0.25; 1000]);
+        let mut out = [0.0; 1000];
+        ring.fill(&mut out);
+        assert_eq!(out[999], 0.25);
+    }
+}
@@ -0,0 +1,292 @@
+// Key and gamepad bindings, read from `bindings.toml` in the working directory
+// and written there with the defaults when it doesn't exist:
+//
+// [hotkeys]                # see hotkeys.rs
+// soft_reset = "F2"
+//
+// [player1]                # player1 to player4, SDL key names per button
+// a = "K"
+// select = ""              # an empty name unbinds
+// turbo_a = "I"            # turbo_a and turbo_b auto-fire while held
+//
+// [gamepad]                # SDL GameController button names, for every pad
+// a = "b"
+//
+// [turbo]                  # frames down, then up, 1 - 30 each
+// frames_on = 2
+// frames_off = 2
+//
+// Tables and buttons not listed keep their defaults. Names are only checked
+// for clashes here; the frontend turns them into SDL codes, and rereads the
+// file on the reload_bindings hotkey.
+
+use crate::hotkeys::{Hotkey, Hotkeys};
+use crate::joypad::JoypadButton;
+use toml::value::Table;
+use toml::Value;
+
+pub const DEFAULT_PATH: &str = "bindings.toml";
+pub const PLAYERS: usize = 4;
+pub const MAX_TURBO_FRAMES: u8 = 30;
+
+// what a key or gamepad button does to its player's joypad
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PadInput {
+    Button(JoypadButton),
+    Turbo(JoypadButton),
+}
+
+impl PadInput {
+    pub const ALL: [PadInput; 10] = [
+        PadInput::Button(JoypadButton::BUTTON_A),
+        PadInput::Button(JoypadButton::BUTTON_B),
+        PadInput::Button(JoypadButton::SELECT),
+        PadInput::Button(JoypadButton::START),
+        PadInput::Button(JoypadButton::UP),
+        PadInput::Button(JoypadButton::DOWN),
+        PadInput::Button(JoypadButton::LEFT),
+        PadInput::Button(JoypadButton::RIGHT),
+        PadInput::Turbo(JoypadButton::BUTTON_A),
+        PadInput::Turbo(JoypadButton::BUTTON_B),
+    ];
+
+    // the key in a [playerN] or [gamepad] table
+    pub fn name(self) -> &'static str {
+        match self {
+            PadInput::Button(button) => button.name(),
+            PadInput::Turbo(JoypadButton::BUTTON_A) => "turbo_a",
+            PadInput::Turbo(JoypadButton::BUTTON_B) => "turbo_b",
+            PadInput::Turbo(_) => "turbo",
+        }
+    }
+
+    pub fn parse(name: &str) -> Result<PadInput, String> {
+        match name.to_lowercase().as_str() {
+            "turbo_a" => Ok(PadInput::Turbo(JoypadButton::BUTTON_A)),
+            "turbo_b" => Ok(PadInput::Turbo(JoypadButton::BUTTON_B)),
+            other => JoypadButton::parse(other).map(PadInput::Button),
+        }
+    }
+}
+
+// in PadInput::ALL order
+const DEFAULT_KEYS: [[&str; 10]; PLAYERS] = [
+    ["K", "L", "Space", "Return", "Up", "Down", "Left", "Right", "I", "O"],
+    ["N", "M", "C", "V", "W", "S", "A", "D", "H", "J"],
+    // players 3 and 4 only play through a Four Score
+    ["Keypad 0", "Keypad .", "Keypad +", "Keypad Enter", "Keypad 8", "Keypad 2", "Keypad 4", "Keypad 6", "Keypad 1", "Keypad 3"],
+    ["Right Shift", "Right Ctrl", "Insert", "PageUp", "Home", "End", "Delete", "PageDown", "", ""],
+];
+
+// by position: the right face button is A and the bottom one B, as on an
+// NES controller, with turbo above and left of them
+const DEFAULT_GAMEPAD: [&str; 10] = ["b", "a", "back", "start", "dpup", "dpdown", "dpleft", "dpright", "y", "x"];
+
+const DEFAULT_TURBO_FRAMES: u8 = 2;
+
+pub struct Bindings {
+    pub hotkeys: Hotkeys,
+    // by player, in PadInput::ALL order, None when unbound
+    keys: Vec<Vec<Option<String>>>,
+    gamepad: Vec<Option<String>>,
+    pub turbo_frames_on: u8,
+    pub turbo_frames_off: u8,
+}
+
+impl Default for Bindings {
+    fn default() -> Self {
+        Bindings::new()
+    }
+}
+
+impl Bindings {
+    pub fn new() -> Self {
+        let names = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| if name.is_empty() { None } else { Some(name.to_string()) })
+                .collect()
+        };
+        Bindings {
+            hotkeys: Hotkeys::new(),
+            keys: DEFAULT_KEYS.iter().map(|keys| names(keys)).collect(),
+            gamepad: names(&DEFAULT_GAMEPAD),
+            turbo_frames_on: DEFAULT_TURBO_FRAMES,
+            turbo_frames_off: DEFAULT_TURBO_FRAMES,
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Bindings, String> {
+        let root = text.parse::<Value>().map_err(|e| format!("bindings: {}", e))?;
+        let root = root.as_table().ok_or("bindings: expected tables of bindings")?;
+        let mut bindings = Bindings::new();
+        for (name, value) in root {
+            let table = value.as_table().ok_or(format!("bindings: {} should be a table", name))?;
+            if name == "hotkeys" {
+                bindings.hotkeys = Hotkeys::from_table(table)?;
+            } else if name == "gamepad" {
+                parse_buttons(name, table, &mut bindings.gamepad)?;
+            } else if name == "turbo" {
+                for (key, value) in table {
+                    let frames = value
+                        .as_integer()
+                        .filter(|frames| (1..=MAX_TURBO_FRAMES as i64).contains(frames))
+                        .ok_or(format!("bindings: turbo: {} should be between 1 and {} frames", key, MAX_TURBO_FRAMES))?;
+                    match key.as_str() {
+                        "frames_on" => bindings.turbo_frames_on = frames as u8,
+                        "frames_off" => bindings.turbo_frames_off = frames as u8,
+                        other => return Err(format!("bindings: turbo: unknown setting '{}'", other)),
+                    }
+                }
+            } else {
+                let player = (1..=PLAYERS)
+                    .find(|player| *name == format!("player{}", player))
+                    .ok_or(format!("bindings: unknown table '{}'", name))?;
+                parse_buttons(name, table, &mut bindings.keys[player - 1])?;
+            }
+        }
+        Ok(bindings)
+    }
+
+    // writes the defaults when the file doesn't exist
+    pub fn load_or_create(path: &str) -> Result<Bindings, String> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Bindings::parse(&text).map_err(|e| format!("{}: {}", path, e)),
+            Err(_) => {
+                let bindings = Bindings::new();
+                match std::fs::write(path, bindings.to_toml()) {
+                    Ok(_) => println!("default bindings written to {}", path),
+                    Err(err) => println!("warning: can't write the default bindings to {}: {}", path, err),
+                }
+                Ok(bindings)
+            }
+        }
+    }
+
+    // `player` from 0
+    pub fn keys(&self, player: usize) -> impl Iterator<Item = (PadInput, &str)> + '_ {
+        bound(&self.keys[player])
+    }
+
+    pub fn gamepad_buttons(&self) -> impl Iterator<Item = (PadInput, &str)> + '_ {
+        bound(&self.gamepad)
+    }
+
+    // keys bound twice, see Hotkeys::conflicts
+    pub fn conflicts(&self) -> Vec<String> {
+        let game_keys: Vec<(String, String)> = (0..PLAYERS)
+            .flat_map(|player| {
+                self.keys(player)
+                    .map(move |(input, key)| (key.to_string(), format!("player {} {}", player + 1, input.name())))
+            })
+            .collect();
+        let mut conflicts = vec![];
+        for (i, (key, action)) in game_keys.iter().enumerate() {
+            for (other_key, other) in &game_keys[i + 1..] {
+                if key.eq_ignore_ascii_case(other_key) {
+                    conflicts.push(format!("{} is bound to both {} and {}", key, action, other));
+                }
+            }
+        }
+        conflicts.extend(self.hotkeys.conflicts(&game_keys));
+        conflicts
+    }
+
+    // every binding, unbound ones as "", with what the file format takes
+    pub fn to_toml(&self) -> String {
+        let mut text = String::new();
+        text.push_str("# Keys are SDL key names, as in \"Left Shift\", \"Q\", \"Keypad 1\". Gamepad\n");
+        text.push_str("# buttons are SDL GameController names: a, b, x, y, back, start,\n");
+        text.push_str("# leftshoulder, rightshoulder, dpup, dpdown, dpleft, dpright.\n");
+        text.push_str("# An empty name unbinds.\n\n[hotkeys]\n");
+        for hotkey in Hotkey::ALL.iter() {
+            let key = self.hotkeys.key(*hotkey).unwrap_or("");
+            text.push_str(&format!("{} = {}    # {}\n", hotkey.name(), quote(key), hotkey.describe()));
+        }
+        for (player, keys) in self.keys.iter().enumerate() {
+            text.push_str(&format!("\n[player{}]\n", player + 1));
+            write_buttons(&mut text, keys);
+        }
+        text.push_str("\n[gamepad]\n");
+        write_buttons(&mut text, &self.gamepad);
+        text.push_str(&format!(
+            "\n[turbo]\nframes_on = {}\nframes_off = {}\n",
+            self.turbo_frames_on, self.turbo_frames_off
+        ));
+        text
+    }
+}
+
+fn parse_buttons(name: &str, table: &Table, names: &mut [Option<String>]) -> Result<(), String> {
+    for (input, value) in table {
+        let input = PadInput::parse(input).map_err(|e| format!("bindings: {}: {}", name, e))?;
+        let i = PadInput::ALL.iter().position(|other| *other == input).unwrap();
+        let value = value
+            .as_str()
+            .ok_or(format!("bindings: {}: {} should be a name", name, input.name()))?;
+        names[i] = if value.is_empty() { None } else { Some(value.to_string()) };
+    }
+    Ok(())
+}
+
+fn bound(names: &[Option<String>]) -> impl Iterator<Item = (PadInput, &str)> + '_ {
+    PadInput::ALL
+        .iter()
+        .zip(names.iter())
+        .filter_map(|(input, name)| name.as_deref().map(|name| (*input, name)))
+}
+
+fn write_buttons(text: &mut String, names: &[Option<String>]) {
+    for (input, name) in PadInput::ALL.iter().zip(names.iter()) {
+        text.push_str(&format!("{} = {}\n", input.name(), quote(name.as_deref().unwrap_or(""))));
+    }
+}
+
+// a TOML string; key names are ASCII, where Rust's escapes are TOML's too
+fn quote(name: &str) -> String {
+    format!("{:?}", name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_bindings() {
+        let bindings = Bindings::parse("[player2]\na = \"J\"\nselect = \"\"\n[gamepad]\nstart = \"guide\"\n[hotkeys]\nquit = \"Q\"").unwrap();
+        let player2: Vec<(PadInput, &str)> = bindings.keys(1).collect();
+        assert_eq!(player2[0], (PadInput::Button(JoypadButton::BUTTON_A), "J"));
+        assert_eq!(player2[1], (PadInput::Button(JoypadButton::BUTTON_B), "M"));
+        assert!(player2.iter().all(|(input, _)| *input != PadInput::Button(JoypadButton::SELECT)));
+        assert_eq!(bindings.keys(0).next(), Some((PadInput::Button(JoypadButton::BUTTON_A), "K")));
+        assert!(bindings.gamepad_buttons().any(|binding| binding == (PadInput::Button(JoypadButton::START), "guide")));
+        assert_eq!(bindings.hotkeys.key(Hotkey::Quit), Some("Q"));
+
+        let bindings = Bindings::parse("[player4]\nturbo_b = \"Right Alt\"\n[turbo]\nframes_on = 1\nframes_off = 3").unwrap();
+        assert_eq!(bindings.keys(3).last(), Some((PadInput::Turbo(JoypadButton::BUTTON_B), "Right Alt")));
+        assert_eq!((bindings.turbo_frames_on, bindings.turbo_frames_off), (1, 3));
+        assert!(Bindings::parse("[turbo]\nframes_on = 0").is_err());
+        assert!(Bindings::parse("[turbo]\nrate = 2").is_err());
+
+        assert!(Bindings::parse("[player5]\na = \"J\"").is_err());
+        assert!(Bindings::parse("[player1]\nturbo = \"J\"").is_err());
+        assert!(Bindings::parse("[player1]\na = 1").is_err());
+        assert!(Bindings::parse("[hotkeys]\nfast_forward = \"R\"").is_err());
+        assert!(Bindings::parse("quit = \"Q\"").is_err());
+    }
+
+    #[test]
+    fn test_defaults_round_trip() {
+        let defaults = Bindings::new();
+        assert!(defaults.conflicts().is_empty(), "{:?}", defaults.conflicts());
+        let text = defaults.to_toml();
+        assert!(text.contains("frame_advance = \"\\\\\""), "{}", text);
+        let parsed = Bindings::parse(&text).unwrap();
+        assert_eq!(parsed.to_toml(), text);
+
+        let bindings = Bindings::parse("[player1]\nb = \"\"\n[player3]\nstart = \"k\"").unwrap();
+        assert_eq!(bindings.conflicts(), vec!["K is bound to both player 1 a and player 3 start".to_string()]);
+        let b = PadInput::Button(JoypadButton::BUTTON_B);
+        assert!(Bindings::parse(&bindings.to_toml()).unwrap().keys(0).all(|(input, _)| input != b));
+    }
+}
@@ -0,0 +1,154 @@
+// Band-limited synthesis, after blargg's blip_buf: instead of sampling the
+// APU's output every ~40 CPU cycles, which aliases everything above half the
+// sample rate back into the audible range, every change of the output level
+// is added to the output as a band-limited step placed at the exact CPU cycle
+// it happened. The steps come from a windowed-sinc kernel (cut off at 90% of
+// Nyquist, Blackman window) precomputed at PHASES sub-sample offsets.
+//
+// The buffer holds differences; reading integrates them. Time is counted in
+// input clocks from the start of the current block, and end_block() hands
+// over the samples the block completed.
+
+use std::f64::consts::PI;
+
+// kernel taps; each step shows up in the output about TAPS / 2 samples late
+const TAPS: usize = 16;
+const PHASES: usize = 64;
+// of the Nyquist frequency
+const CUTOFF: f64 = 0.9;
+
+pub struct BlipBuffer {
+    // output samples per input clock
+    factor: f64,
+    // where clock 0 of the current block falls, in output samples from the
+    // start of `buffer`
+    offset: f64,
+    buffer: Vec<f32>,
+    integrator: f32,
+    kernel: Vec<[f32; TAPS]>,
+}
+
+impl BlipBuffer {
+    pub fn new(clock_rate: f64, sample_rate: f64) -> Self {
+        BlipBuffer {
+            factor: sample_rate / clock_rate,
+            offset: 0.0,
+            buffer: vec![0.0; TAPS],
+            integrator: 0.0,
+            kernel: (0..PHASES).map(|phase| step_kernel(phase as f64 / PHASES as f64)).collect(),
+        }
+    }
+
+    pub fn set_rates(&mut self, clock_rate: f64, sample_rate: f64) {
+        self.factor = sample_rate / clock_rate;
+    }
+
+    // the output changes by `delta` at `clock` clocks into the block
+    pub fn add_delta(&mut self, clock: u32, delta: f32) {
+        let position = self.offset + clock as f64 * self.factor;
+        let sample = position as usize;
+        let phase = ((position - sample as f64) * PHASES as f64) as usize;
+        let end = sample + TAPS;
+        if self.buffer.len() < end {
+            self.buffer.resize(end, 0.0);
+        }
+        for (out, tap) in self.buffer[sample..end].iter_mut().zip(self.kernel[phase].iter()) {
+            *out += delta * tap;
+        }
+    }
+
+    // Ends the block `clocks` clocks long and appends the samples it
+    // completed to `out`. The next block's clock 0 is this one's `clocks`.
+    pub fn end_block(&mut self, clocks: u32, out: &mut Vec<f32>) {
+        let position = self.offset + clocks as f64 * self.factor;
+        let complete = position as usize;
+        if self.buffer.len() < complete + TAPS {
+            self.buffer.resize(complete + TAPS, 0.0);
+        }
+        for delta in self.buffer.drain(..complete) {
+            self.integrator += delta;
+            out.push(self.integrator);
+        }
+        self.offset = position - complete as f64;
+    }
+}
+
+// How much of a unit step at `fraction` of a sample past the kernel's center
+// lands in each output sample: the windowed sinc integrated over each
+// sample's span, normalized to add up to exactly 1.
+fn step_kernel(fraction: f64) -> [f32; TAPS] {
+    const STEPS: usize = 16;
+    let impulse = |t: f64| {
+        let x = CUTOFF * t;
+        let sinc = if x == 0.0 { 1.0 } else { (PI * x).sin() / (PI * x) };
+        // Blackman over the kernel's span
+        let w = (t + TAPS as f64 / 2.0) / TAPS as f64;
+        let window = if (0.0..=1.0).contains(&w) {
+            0.42 - 0.5 * (2.0 * PI * w).cos() + 0.08 * (4.0 * PI * w).cos()
+        } else {
+            0.0
+        };
+        CUTOFF * sinc * window
+    };
+    let mut taps = [0.0; TAPS];
+    for (k, tap) in taps.iter_mut().enumerate() {
+        let start = k as f64 - TAPS as f64 / 2.0 - fraction;
+        // midpoint rule over the sample's span
+        *tap = (0..STEPS).map(|i| impulse(start + (i as f64 + 0.5) / STEPS as f64)).sum::<f64>() / STEPS as f64;
+    }
+    let sum: f64 = taps.iter().sum();
+    let mut kernel = [0.0; TAPS];
+    for (out, tap) in kernel.iter_mut().zip(taps.iter()) {
+        *out = (tap / sum) as f32;
+    }
+    kernel
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_steps_settle_at_their_height() {
+        // 100 clocks per sample
+        let mut blip = BlipBuffer::new(4_410_000.0, 44_100.0);
+        let mut out = vec![];
+        blip.add_delta(250, 1.0);
+        blip.end_block(1000, &mut out);
+        blip.add_delta(50, -0.5);
+        blip.end_block(3000, &mut out);
+        assert_eq!(out.len(), 40);
+        // the step at 2.5 samples rises TAPS / 2 samples later, with a little
+        // ringing either side
+        assert!(out[..8].iter().all(|sample| sample.abs() < 0.05), "{:?}", out);
+        assert!(out[9] < 0.5 && out[10] > 0.5, "{:?}", out);
+        assert!(out[12..18].iter().all(|sample| (sample - 1.0).abs() < 0.06), "{:?}", out);
+        assert!((out[39] - 0.5).abs() < 0.001, "{:?}", out);
+    }
+
+    #[test]
+    fn test_no_aliasing_above_nyquist() {
+        // a square wave at 30kHz sampled at 44.1kHz: naive sampling folds it
+        // down to a loud 14.1kHz, band-limited it is all but gone
+        let clock_rate = 1_789_773.0;
+        let mut blip = BlipBuffer::new(clock_rate, 44_100.0);
+        let half_period = clock_rate / 60_000.0;
+        let mut out = vec![];
+        let mut level = 0.0;
+        let mut edge = 0.0;
+        for block in 0..100 {
+            while edge < (block + 1) as f64 * 10_000.0 {
+                let delta = if level == 0.0 { 1.0 } else { -1.0 };
+                level += delta;
+                blip.add_delta((edge - block as f64 * 10_000.0) as u32, delta);
+                edge += half_period;
+            }
+            blip.end_block(10_000, &mut out);
+        }
+        let samples = &out[100..];
+        let mean = samples.iter().sum::<f32>() / samples.len() as f32;
+        let rms = (samples.iter().map(|sample| (sample - mean).powi(2)).sum::<f32>() / samples.len() as f32).sqrt();
+        // a naively sampled square wave of height 1 has an RMS of 0.5
+        assert!(rms < 0.1, "{}", rms);
+    }
+}
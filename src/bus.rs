@@ -1,111 +1,174 @@
+use crate::apu::ApuRegisters;
+use crate::cdl::SharedCodeDataLog;
+use crate::cheats::Cheats;
 use crate::cpu::Mem;
-use crate::cartridge::Rom;
-use crate::ppu::NesPPU;
+use crate::debugger::Breakpoints;
+use crate::events::{EmuEvent, EventBus};
+use crate::heatmap::RamHeatmap;
+use crate::hex_editor::MemorySpace;
+use crate::cartridge;
+use crate::cartridge::{Mirroring, Rom};
+use crate::cartridge::SharedMapper;
+use crate::ppu::{NesPPU, SpriteLimit};
 use crate::ppu::PPU;
-use crate::joypad::Joypad;
+use crate::ppu::watch::PpuWatch;
+use crate::input::{self, InputDevice, LIGHT_LINES};
+use crate::joypad::{DeviceType, JoypadButton};
+use crate::labels::Labels;
+use crate::opcodes::OPCODE_TABLE;
+use crate::region::Region;
+use crate::render;
+use crate::render::frame::Frame;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+use crate::stats::{Component, Profiler};
+use crate::timeline::{Timeline, TimelineEvent, IRQ_SOURCES};
+use crate::traps::Traps;
 
 const RAM: u16 = 0x0000;
 const RAM_MIRRORS_END: u16 = 0x1FFF;
 const PPU_REGISTERS: u16 = 0x2000;
 const PPU_REGISTERS_MIRRORS_END: u16 = 0x3FFF;
+// luma a light gun takes for light, out of 255
+const BRIGHT: u32 = 0xc0;
 
 impl Mem for Bus<'_> {
     fn mem_read(&mut self, addr: u16) -> u8 {
-        match addr {
+        self.service_dmc_dma(addr);
+        let data = match addr {
             RAM..=RAM_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b00000111_11111111;
-                self.cpu_vram[mirror_down_addr as usize]
-            }
-            0x2000 | 0x2001 | 0x2003 | 0x2005 | 0x2006 | 0x4014 => {
-                0
+                if let Some(heatmap) = self.heatmap.as_mut() {
+                    heatmap.read(mirror_down_addr as usize);
+                }
+                self.cheats.apply(mirror_down_addr, self.cpu_vram[mirror_down_addr as usize])
             }
-            0x2002 => self.ppu.read_status(),
-            0x2004 => self.ppu.read_oam_data(),
-            0x2007 => self.ppu.read_data(),
+            // the eight registers repeat every 8 bytes up to $3FFF
+            PPU_REGISTERS..=PPU_REGISTERS_MIRRORS_END => match addr & 0x2007 {
+                0x2002 => self.ppu.read_status(),
+                0x2004 => self.ppu.read_oam_data(),
+                0x2007 => {
+                    self.watch_ppu_access(false);
+                    self.ppu.read_data()
+                }
+                // write-only
+                _ => self.open_bus,
+            },
 
-            0x4000..=0x4015 => {
-                //ignore APU 
-                0
+            // bit 5 isn't driven, and the read is internal to the CPU, so it
+            // doesn't change the open bus either
+            0x4015 => {
+                let data = self.apu.read_status() | (self.open_bus & 0x20);
+                self.watch_read(addr, data);
+                return data;
             }
 
-            0x4016 => {
-                self.joypad1.read()
-            }
+            // write-only APU registers and OAM DMA
+            0x4000..=0x4014 => self.open_bus,
 
-            0x4017 => {
-                self.joypad2.read()
-            }
-            0x2008..=PPU_REGISTERS_MIRRORS_END => {
-                let mirror_down_addr = addr & 0b00100000_00000111;
-                self.mem_read(mirror_down_addr)
-            }
-            0x8000..=0xFFFF => self.read_prg_rom(addr),
+            // the controllers drive the low 5 bits at most
+            0x4016 => (self.open_bus & 0xe0) | self.read_port(0),
 
-            _ => {
-                println!("Ignoring mem access at {:x}", addr);
-                0
+            0x4017 => (self.open_bus & 0xe0) | self.read_port(1),
+            0x6000..=0xFFFF => {
+                let started = self.profiler.start();
+                let data = self.mapper.borrow().prg_read(addr);
+                self.profiler.add(Component::Mapper, started);
+                if let Some(cdl) = self.cdl.as_ref() {
+                    if let Some(offset) = self.mapper.borrow().prg_offset(addr) {
+                        cdl.borrow_mut().read(offset, addr);
+                    }
+                }
+                self.cheats.apply(addr, data)
             }
-        }
+
+            // nothing answers $4018-$5FFF: the last value on the bus is read back
+            _ => self.open_bus,
+        };
+        self.open_bus = data;
+        self.watch_read(addr, data);
+        data
     }
 
     fn mem_write(&mut self, addr: u16, data: u8){
+        self.open_bus = data;
+        if self.breakpoints.watching() {
+            self.breakpoints.write(addr, data, self.traps.instruction_pc);
+        }
         match addr {
             RAM..=RAM_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b11111111111;
+                if let Some(heatmap) = self.heatmap.as_mut() {
+                    heatmap.write(mirror_down_addr as usize);
+                }
                 self.cpu_vram[mirror_down_addr as usize] = data;
             }
-            0x2000 => {
-                self.ppu.write_to_ctrl(data);
-            }
-            0x2001 => {
-                self.ppu.write_to_mask(data);
-            }
-
-            0x2002 => panic!("attempt to write to PPU status register"),
-
-            0x2003 => {
-                self.ppu.write_to_oam_addr(data);
-            }
-            0x2004 => {
-                self.ppu.write_to_oam_data(data);
-            }
-            0x2005 => {
-                self.ppu.write_to_scroll(data);
-            }
-
-            0x2006 => {
-                self.ppu.write_to_ppu_addr(data);
-            }
-            0x2007 => {
-                self.ppu.write_to_data(data);
-            }
+            PPU_REGISTERS..=PPU_REGISTERS_MIRRORS_END => match addr & 0x2007 {
+                0x2000 => self.ppu.write_to_ctrl(data),
+                0x2001 => self.ppu.write_to_mask(data),
+                // status is read-only, the write goes nowhere
+                0x2002 => {}
+                0x2003 => self.ppu.write_to_oam_addr(data),
+                0x2004 => {
+                    self.ppu.write_to_oam_data(data);
+                    self.report_ppu_writes(addr);
+                }
+                0x2005 => self.ppu.write_to_scroll(data),
+                0x2006 => self.ppu.write_to_ppu_addr(data),
+                _ => {
+                    self.watch_ppu_access(true);
+                    self.ppu.write_to_data(data);
+                    self.report_ppu_writes(addr);
+                }
+            },
             0x4000..=0x4013 | 0x4015 => {
-                //ignore APU 
+                self.apu.write(addr, data);
             }
 
+            // the strobe reaches both controller ports
             0x4016 => {
-                self.joypad1.write(data);
+                self.record(0, TimelineEvent::ControllerStrobe { value: data & 1 });
+                for port in self.ports.iter_mut() {
+                    port.write(data);
+                }
             }
 
             0x4017 => {
-                self.joypad2.write(data);
+                self.apu.write(addr, data);
             }
 
+            // halts the CPU for 513 cycles, 514 when it starts on an odd cycle
             0x4014 => {
                 let mut buffer: [u8; 256] = [0; 256];
                 let hi: u16 = (data as u16) << 8;
+                let alignment = ((self.cycles + self.deferred_cycles.unwrap_or(0)) % 2) as u8;
+                self.record(513 + alignment as usize, TimelineEvent::OamDma { page: data });
+                self.oam_dma_active = true;
+                self.tick(1 + alignment);
                 for i in 0..256u16 {
                     buffer[i as usize] = self.mem_read(hi + i);
+                    self.tick(2);
                 }
+                self.oam_dma_active = false;
 
                 self.ppu.write_oam_dma(&buffer);
+                self.report_ppu_writes(addr);
             }
 
-            0x2008..=PPU_REGISTERS_MIRRORS_END => {
-                let mirror_down_addr = addr & 0b00100000_00000111;
-                self.mem_write(mirror_down_addr, data);
+            0x6000..=0xFFFF => {
+                let started = self.profiler.start();
+                let handled = self.mapper.borrow_mut().prg_write(addr, data);
+                self.profiler.add(Component::Mapper, started);
+                if handled && addr >= 0x8000 {
+                    self.events.emit(EmuEvent::BankSwitch { addr, data });
+                }
+                if !handled {
+                    if addr >= 0x8000 {
+                        self.traps.rom_write(addr, data);
+                    } else {
+                        println!("Ignoring mem write-access at {:x}", addr);
+                    }
+                }
             }
-            0x8000..=0xFFFF => panic!("Attempt to write to Cartridge ROM space: {:x}", addr),
 
             _ => {
                 println!("Ignoring mem write-access at {:x}", addr);
@@ -113,56 +176,588 @@ impl Mem for Bus<'_> {
         }
     }
 }
+impl Savestate for Bus<'_> {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.cpu_vram);
+        state.usize(self.cycles);
+        state.usize(self.frames);
+        state.u8(self.open_bus);
+        state.u8(self.dot_remainder as u8);
+        self.ppu.save_state(state);
+        self.mapper.borrow().save_state(state);
+        for port in self.ports.iter() {
+            state.u8(port.device_type() as u8);
+            port.save_state(state);
+        }
+        self.apu.save_state(state);
+        self.events.emit(EmuEvent::StateSaved);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        state.bytes_into(&mut self.cpu_vram)?;
+        self.cycles = state.usize()?;
+        self.frames = state.usize()?;
+        self.open_bus = state.u8()?;
+        self.dot_remainder = state.u8()? as u32;
+        self.ppu.load_state(state)?;
+        self.mapper.borrow_mut().load_state(state)?;
+        for (i, port) in self.ports.iter_mut().enumerate() {
+            if state.u8()? != port.device_type() as u8 {
+                return Err(format!("the state was saved with another device in controller port {}", i + 1));
+            }
+            port.load_state(state)?;
+        }
+        self.apu.load_state(state)?;
+        self.events.emit(EmuEvent::StateLoaded);
+        Ok(())
+    }
+}
+
+// called once a frame as vblank starts, with the controller ports
+type GameloopCallback<'call> = Box<dyn FnMut(&mut NesPPU, &mut dyn InputDevice, &mut dyn InputDevice) + 'call>;
+
 pub struct Bus<'call> {
    cpu_vram: [u8; 2048],
-   prg_rom: Vec<u8>,
+   // repeated over RAM on power-up; real consoles come up with a board-specific pattern
+   pub ram_pattern: [u8; 8],
+   mapper: SharedMapper,
    ppu: NesPPU,
    cycles: usize,
-   joypad1: Joypad,
-   joypad2: Joypad,
+   frames: usize,
+   ports: [Box<dyn InputDevice>; 2],
+   // the picture so far this frame, for light guns; the frame it was drawn in
+   light_frame: Option<(usize, Frame)>,
+   pub traps: Traps,
+   pub breakpoints: Breakpoints,
+   pub cheats: Cheats,
+   pub labels: Labels,
+   pub profiler: Profiler,
+   pub apu: ApuRegisters,
+   pub events: EventBus,
+   oam_dma_active: bool,
+   // last value on the CPU data bus, read back from addresses nothing drives
+   open_bus: u8,
+   timeline_requested: bool,
+   timeline: Option<Timeline>,
+   finished_timeline: Option<Timeline>,
+   heatmap: Option<RamHeatmap>,
+   finished_heatmap: Option<RamHeatmap>,
+   cdl: Option<SharedCodeDataLog>,
+   // IRQ sources as of the last tick, for spotting new assertions
+   irq_lines: [bool; 3],
+   region: Region,
+   // PAL's PPU runs 3.2 dots per CPU cycle; the fifths of a dot not yet run
+   dot_remainder: u32,
+   // ticks held back while the CPU steps one cycle at a time
+   deferred_cycles: Option<usize>,
 
-   gameloop_callback: Box<dyn FnMut(&NesPPU, &mut Joypad, &mut Joypad) + 'call>,
+   gameloop_callback: GameloopCallback<'call>,
 }
 
 impl<'a> Bus<'a> {
    pub fn new<'call, F>(rom: Rom, gameloop_callback: F) -> Bus<'call>
    where
-        F: FnMut(&NesPPU, &mut Joypad, &mut Joypad) + 'call,
+        F: FnMut(&mut NesPPU, &mut dyn InputDevice, &mut dyn InputDevice) + 'call,
+   {
+        let region = Region::select(rom.region, None);
+        let mapper = cartridge::create_mapper(rom).unwrap();
+        let mut bus = Bus::with_mapper(mapper, gameloop_callback);
+        bus.set_region(region);
+        bus
+   }
+
+   pub fn with_mapper<'call, F>(mapper: SharedMapper, gameloop_callback: F) -> Bus<'call>
+   where
+        F: FnMut(&mut NesPPU, &mut dyn InputDevice, &mut dyn InputDevice) + 'call,
    {
-        let ppu = NesPPU::new(rom.chr_rom, rom.screen_mirroring);
+        let ppu = NesPPU::with_mapper(mapper.clone());
         Bus {
             cpu_vram: [0; 2048],
-            prg_rom: rom.prg_rom,
+            ram_pattern: [0; 8],
+            mapper,
             ppu: ppu,
             cycles: 0,
-            joypad1: Joypad::new(),
-            joypad2: Joypad::new(),
+            frames: 0,
+            ports: [input::create(DeviceType::Standard, 0), input::create(DeviceType::Standard, 1)],
+            light_frame: None,
+            traps: Traps::new(),
+            breakpoints: Breakpoints::new(),
+            cheats: Cheats::new(),
+            labels: Labels::new(),
+            profiler: Profiler::new(),
+            apu: ApuRegisters::new(),
+            events: EventBus::new(),
+            oam_dma_active: false,
+            open_bus: 0,
+            timeline_requested: false,
+            timeline: None,
+            finished_timeline: None,
+            heatmap: None,
+            finished_heatmap: None,
+            cdl: None,
+            irq_lines: [false; 3],
+            region: Region::Ntsc,
+            dot_remainder: 0,
+            deferred_cycles: None,
             gameloop_callback: Box::from(gameloop_callback),
         }
    }
-    fn read_prg_rom(&self, mut addr: u16) -> u8 {
-        addr -= 0x8000;
-        if self.prg_rom.len() == 0x4000 && addr >= 0x4000 {
-            addr = addr % 0x4000; // mirroring
-        }
-        self.prg_rom[addr as usize]
+    // only internal RAM and cartridge ROM hold code on NROM boards
+    pub fn is_executable(&self, addr: u16) -> bool {
+        matches!(addr, RAM..=RAM_MIRRORS_END | 0x8000..=0xFFFF)
     }
 
     pub fn tick(&mut self, cycles: u8){
+        if let Some(deferred) = self.deferred_cycles.as_mut() {
+            *deferred += cycles as usize;
+            return;
+        }
         self.cycles += cycles as usize;
+        let expansion = self.mapper.borrow().expansion_audio();
+        self.apu.set_expansion(expansion);
+        let started = self.profiler.start();
+        self.apu.tick(cycles);
+        self.profiler.add(Component::Apu, started);
+        self.mapper.borrow_mut().tick(cycles);
 
         let nmi_before = self.ppu.nmi_interrupt.is_some();
-        self.ppu.tick(cycles * 3);
+        let started = self.profiler.start();
+        let dots = self.ppu_dots(cycles);
+        let scanline = self.ppu.position().0;
+        let frame_done = self.ppu.tick(dots);
+        self.profiler.add(Component::Ppu, started);
+        if frame_done {
+            self.events.emit(EmuEvent::FrameEnd(self.frames));
+            if let Some(timeline) = self.timeline.take() {
+                self.finished_timeline = Some(timeline);
+            }
+            self.frames += 1;
+            self.profiler.end_frame();
+            if self.heatmap.as_mut().is_some_and(|heatmap| heatmap.end_frame()) {
+                self.finished_heatmap = self.heatmap.take();
+            }
+            if self.timeline_requested {
+                self.timeline_requested = false;
+                self.timeline = Some(Timeline::new(self.frames, self.cycles));
+                self.irq_lines = self.irq_sources();
+            }
+            self.events.emit(EmuEvent::FrameStart(self.frames));
+        }
+        if self.breakpoints.on_scanlines() && self.ppu.position().0 != scanline {
+            self.breakpoints.scanline(self.ppu.position().0, self.frames);
+        }
         let nmi_after = self.ppu.nmi_interrupt.is_some();
 
+        if self.timeline.is_some() {
+            if !nmi_before && nmi_after {
+                self.record(0, TimelineEvent::Nmi);
+            }
+            let irq_lines = self.irq_sources();
+            for (i, source) in IRQ_SOURCES.iter().enumerate() {
+                if irq_lines[i] && !self.irq_lines[i] {
+                    self.record(0, TimelineEvent::Irq(*source));
+                }
+            }
+            self.irq_lines = irq_lines;
+        }
+
         if !nmi_before && nmi_after {
-            (self.gameloop_callback)(&self.ppu, &mut self.joypad1, &mut self.joypad2);
+            let started = self.profiler.start();
+            let [port1, port2] = &mut self.ports;
+            (self.gameloop_callback)(&mut self.ppu, port1.as_mut(), port2.as_mut());
+            self.profiler.add(Component::Frontend, started);
+        }
+    }
+
+    // records the next whole frame; take_timeline() hands it over once it ends
+    pub fn request_timeline(&mut self) {
+        self.timeline_requested = true;
+    }
+
+    pub fn take_timeline(&mut self) -> Option<Timeline> {
+        self.finished_timeline.take()
+    }
+
+    // counts RAM accesses from now on for `frames` frames, take_heatmap() hands
+    // the counts over once they are done
+    pub fn request_heatmap(&mut self, frames: usize) {
+        self.heatmap = Some(RamHeatmap::new(frames));
+    }
+
+    pub fn take_heatmap(&mut self) -> Option<RamHeatmap> {
+        self.finished_heatmap.take()
+    }
+
+    // where in PRG ROM the mapper has the address, None outside of it
+    pub fn prg_offset(&self, addr: u16) -> Option<usize> {
+        self.mapper.borrow().prg_offset(addr)
+    }
+
+    // the label for an address, going by the bank mapped in for ROM
+    pub fn label_at(&self, addr: u16) -> Option<&str> {
+        self.labels.lookup(addr, self.mapper.borrow().prg_offset(addr))
+    }
+
+    // disassembly with label names in place of addresses
+    pub fn label_operands(&self, text: &str) -> String {
+        if self.labels.is_empty() {
+            return text.to_string();
+        }
+        let mapper = self.mapper.borrow();
+        self.labels.substitute(text, |addr| mapper.prg_offset(addr))
+    }
+
+    // Code/Data Logger, see cdl.rs. The PPU logs CHR accesses to the same log.
+    pub fn set_cdl(&mut self, cdl: Option<SharedCodeDataLog>) {
+        self.ppu.cdl = cdl.clone();
+        self.cdl = cdl;
+    }
+
+    // before the CPU fetches the instruction at `pc`
+    pub fn log_instruction(&mut self, pc: u16) {
+        let cdl = match self.cdl.as_ref() {
+            Some(cdl) => cdl,
+            None => return,
+        };
+        let opcode = match OPCODE_TABLE[self.peek(pc) as usize] {
+            Some(opcode) => opcode,
+            None => return,
+        };
+        let mapper = self.mapper.borrow();
+        let offsets: Vec<Option<usize>> =
+            (0..opcode.bytes as u16).map(|i| mapper.prg_offset(pc.wrapping_add(i))).collect();
+        cdl.borrow_mut().execute(pc, opcode, &offsets);
+    }
+
+    fn record(&mut self, duration: usize, event: TimelineEvent) {
+        if let Some(timeline) = self.timeline.as_mut() {
+            timeline.record(self.cycles, self.ppu.position(), duration, event);
+        }
+    }
+
+    fn watch_read(&mut self, addr: u16, data: u8) {
+        if self.breakpoints.watching() {
+            self.breakpoints.read(addr, data, self.traps.instruction_pc);
+        }
+    }
+
+    // before the $2007 access moves the PPU address on
+    fn watch_ppu_access(&mut self, write: bool) {
+        if self.breakpoints.watching() {
+            self.breakpoints.ppu_access(self.ppu.vram_addr(), write, self.traps.instruction_pc);
+        }
+    }
+
+    // PPU watchpoints the write to `register` set off
+    fn report_ppu_writes(&mut self, register: u16) {
+        for write in self.ppu.take_watch_hits() {
+            self.traps.ppu_write(write, register, self.frames, self.ppu.position());
         }
     }
 
+    pub fn add_ppu_watch(&mut self, watch: PpuWatch) {
+        self.ppu.watches.push(watch);
+    }
+
+    // in IRQ_SOURCES order
+    fn irq_sources(&self) -> [bool; 3] {
+        [self.mapper.borrow().irq(), self.apu.frame_irq(), self.apu.dmc.irq()]
+    }
+
+    // The DMC halts the CPU to fetch its next sample byte, at the CPU's next
+    // read. The halted CPU keeps repeating that read, so a $4016/$4017 read
+    // clocks the controller an extra time and a button bit is lost.
+    fn service_dmc_dma(&mut self, cpu_addr: u16) {
+        let sample_addr = match self.apu.dmc.dma_request() {
+            Some(sample_addr) => sample_addr,
+            None => return,
+        };
+        let sample = self.mapper.borrow().prg_read(sample_addr);
+        self.apu.dmc.fill(sample);
+        if let Some(cdl) = self.cdl.as_ref() {
+            if let Some(offset) = self.mapper.borrow().prg_offset(sample_addr) {
+                cdl.borrow_mut().dmc_read(offset, sample_addr);
+            }
+        }
+        match cpu_addr {
+            0x4016 => {
+                self.ports[0].read();
+            }
+            0x4017 => {
+                self.ports[1].read();
+            }
+            _ => {}
+        }
+        // OAM DMA already holds the bus, so the DMC only adds two cycles to it
+        let stall = if self.oam_dma_active { 2 } else { 4 };
+        self.record(stall as usize, TimelineEvent::DmcDma { addr: sample_addr });
+        self.tick(stall);
+    }
+
+    // reset button: the PPU drops its register state, RAM and VRAM survive
+    pub fn soft_reset(&mut self) {
+        self.ppu.reset();
+        // reset silences the APU as if $4015 were cleared
+        self.apu.write(0x4015, 0);
+    }
+
+    pub fn power_cycle(&mut self) {
+        for (i, byte) in self.cpu_vram.iter_mut().enumerate() {
+            *byte = self.ram_pattern[i % self.ram_pattern.len()];
+        }
+        self.mapper.borrow_mut().power_cycle();
+        let overclock_scanlines = self.ppu.overclock_scanlines;
+        let sprite_limit = self.ppu.sprite_limit;
+        let watches = std::mem::take(&mut self.ppu.watches);
+        let cdl = self.ppu.cdl.take();
+        self.ppu = NesPPU::with_mapper(self.mapper.clone());
+        self.ppu.watches = watches;
+        self.ppu.cdl = cdl;
+        self.ppu.overclock_scanlines = overclock_scanlines;
+        self.ppu.sprite_limit = sprite_limit;
+        self.ports = [input::create(self.ports[0].device_type(), 0), input::create(self.ports[1].device_type(), 1)];
+        self.apu.power_cycle();
+        self.set_region(self.region);
+        self.dot_remainder = 0;
+        self.cycles = 0;
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.ppu.region = region;
+        self.apu.set_region(region);
+    }
+
+    fn ppu_dots(&mut self, cycles: u8) -> u8 {
+        let (dots, per_cycles) = self.region.dots_per_cycle();
+        let total = cycles as u32 * dots + self.dot_remainder;
+        self.dot_remainder = total % per_cycles;
+        (total / per_cycles) as u8
+    }
+
+    pub fn set_overclock_scanlines(&mut self, lines: u16) {
+        self.ppu.overclock_scanlines = lines;
+    }
+
+    pub fn set_sprite_limit(&mut self, limit: SpriteLimit) {
+        self.ppu.sprite_limit = limit;
+    }
+
+    // CPU cycles since power-on
+    pub fn cycles(&self) -> usize {
+        self.cycles
+    }
+
+    // ticks are only counted until end_deferred_ticks(), which returns them;
+    // lets the CPU run an instruction and then pay its cycles out one by one
+    pub fn defer_ticks(&mut self) {
+        self.deferred_cycles = Some(0);
+    }
+
+    pub fn end_deferred_ticks(&mut self) -> usize {
+        self.deferred_cycles.take().unwrap_or(0)
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frames
+    }
+
+    // for frontends that drive input directly instead of from the gameloop callback
+    pub fn set_buttons(&mut self, port1: JoypadButton, port2: JoypadButton) {
+        self.ports[0].set_buttons(port1);
+        self.ports[1].set_buttons(port2);
+    }
+
+    // `port` 0 or 1; what was plugged in before is dropped
+    pub fn plug(&mut self, port: usize, device: Box<dyn InputDevice>) {
+        self.ports[port] = device;
+    }
+
+    pub fn port(&mut self, port: usize) -> &mut dyn InputDevice {
+        self.ports[port].as_mut()
+    }
+
+    fn read_port(&mut self, port: usize) -> u8 {
+        if let Some((x, y)) = self.ports[port].aim() {
+            let light = self.light_at(x, y);
+            self.ports[port].sense_light(light);
+        }
+        self.ports[port].read()
+    }
+
+    // Whether a light gun aimed at (x, y) sees light now: the beam drew the
+    // spot bright within the last LIGHT_LINES scanlines. The picture is drawn
+    // at most once a frame, the first time it's needed.
+    fn light_at(&mut self, x: u8, y: u8) -> bool {
+        let scanline = self.ppu.position().0;
+        if scanline < y as u16 || scanline >= y as u16 + LIGHT_LINES {
+            return false;
+        }
+        let frames = self.frames;
+        if self.light_frame.as_ref().is_none_or(|(drawn, _)| *drawn != frames) {
+            let mut frame = Frame::new();
+            render::render_peek(&self.ppu, &mut frame);
+            self.light_frame = Some((frames, frame));
+        }
+        let (r, g, b) = self.light_frame.as_ref().unwrap().1.get_pixel(x as usize, y as usize);
+        (r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000 >= BRIGHT
+    }
+
+    // the 2KB of work RAM as the game wrote it, frozen addresses included
+    pub fn ram(&self) -> &[u8; 2048] {
+        &self.cpu_vram
+    }
+
+    // side-effect free read of RAM and cartridge space, for tools watching memory
+    pub fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            RAM..=RAM_MIRRORS_END => self.cpu_vram[(addr & 0b00000111_11111111) as usize],
+            0x6000..=0xFFFF => self.mapper.borrow().prg_read(addr),
+            _ => 0,
+        }
+    }
+
+    // a copy of one memory space for the hex editor, without side effects
+    pub fn memory(&self, space: MemorySpace) -> Vec<u8> {
+        match space {
+            MemorySpace::Ram => self.cpu_vram.to_vec(),
+            MemorySpace::PrgRam => self.mapper.borrow().prg_ram().map(|ram| ram.to_vec()).unwrap_or_default(),
+            MemorySpace::Vram => self.ppu.vram[..self.vram_size()].to_vec(),
+            MemorySpace::Oam => self.ppu.oam_data.to_vec(),
+            MemorySpace::Palette => self.ppu.palette_table.to_vec(),
+        }
+    }
+
+    // edits a byte in place; unlike mem_write this never reaches a register
+    pub fn poke(&mut self, space: MemorySpace, offset: usize, value: u8) -> Result<(), String> {
+        let vram_size = self.vram_size();
+        let mut mapper = self.mapper.borrow_mut();
+        let memory: &mut [u8] = match space {
+            MemorySpace::Ram => &mut self.cpu_vram,
+            MemorySpace::PrgRam => mapper.prg_ram_mut().unwrap_or(&mut []),
+            MemorySpace::Vram => &mut self.ppu.vram[..vram_size],
+            MemorySpace::Oam => &mut self.ppu.oam_data,
+            MemorySpace::Palette => &mut self.ppu.palette_table,
+        };
+        let size = memory.len();
+        let byte = memory
+            .get_mut(offset)
+            .ok_or(format!("offset {:#x} is outside {} ({} bytes)", offset, space.name(), size))?;
+        *byte = value;
+        Ok(())
+    }
+
+    // the second 2KB of nametable memory only exists on four-screen boards
+    fn vram_size(&self) -> usize {
+        match self.ppu.mirroring() {
+            Mirroring::FOUR_SCREEN => 0x1000,
+            _ => 0x800,
+        }
+    }
+
+    // for viewers that draw from PPU state between frames
+    pub fn ppu(&self) -> &NesPPU {
+        &self.ppu
+    }
+
     pub fn poll_nmi_status(&mut self) -> Option<u8> {
         self.ppu.poll_nmi_status()
     }
 
+    // The IRQ line is level triggered and shared: any source holds it low
+    // until that source is acknowledged (mapper register write, $4015 read).
+    pub fn poll_irq_status(&self) -> bool {
+        self.mapper.borrow().irq() || self.apu.irq()
+    }
+
     
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+
+    #[test]
+    fn test_open_bus_reads() {
+        let mut bus = Bus::new(test_rom(), |_: &mut NesPPU, _: &mut dyn InputDevice, _: &mut dyn InputDevice| {});
+        bus.mem_write(0x0000, 0xa5);
+        assert_eq!(bus.mem_read(0x5000), 0xa5);
+        assert_eq!(bus.mem_read(0x4000), 0xa5);
+
+        // the controller only drives bit 0 here
+        bus.mem_write(0x0001, 0xff);
+        bus.mem_read(0x0001);
+        assert_eq!(bus.mem_read(0x4016), 0xe0);
+        assert_eq!(bus.mem_read(0x4017), 0xe0);
+
+        // $4015 keeps bit 5 from the bus and leaves the bus alone
+        bus.mem_read(0x0001);
+        assert_eq!(bus.mem_read(0x4015) & 0x20, 0x20);
+        assert_eq!(bus.mem_read(0x5000), 0xff);
+    }
+
+    #[test]
+    fn test_ppu_register_mirrors() {
+        let mut bus = Bus::new(test_rom(), |_: &mut NesPPU, _: &mut dyn InputDevice, _: &mut dyn InputDevice| {});
+        // $3FFE is $2006 and $2FFF is $2007: the sprite backdrop entry $3F10
+        bus.mem_write(0x3ffe, 0x3f);
+        bus.mem_write(0x200e, 0x10);
+        bus.mem_write(0x2fff, 0x2c);
+        // which is the backdrop at $3F00, read through $3FF7
+        bus.mem_write(0x2006, 0x3f);
+        bus.mem_write(0x3456, 0x00);
+        assert_eq!(bus.mem_read(0x3ff7), 0x2c);
+
+        // writing status goes nowhere, write-only registers read the open bus
+        bus.mem_write(0x200a, 0x5a);
+        assert_eq!(bus.mem_read(0x3ff8), 0x5a);
+        assert_eq!(bus.mem_read(0x2005), 0x5a);
+    }
+
+    #[test]
+    fn test_devices_in_the_ports() {
+        let mut bus = Bus::new(test_rom(), |_: &mut NesPPU, _: &mut dyn InputDevice, _: &mut dyn InputDevice| {});
+        bus.plug(1, input::create(DeviceType::Zapper, 1));
+        bus.set_buttons(JoypadButton::BUTTON_A, JoypadButton::BUTTON_A);
+        bus.port(1).set_pointer(128, 100, true);
+        bus.mem_write(0x4016, 1);
+        bus.mem_write(0x4016, 0);
+        assert_eq!(bus.mem_read(0x4016) & 0x1f, 0x01);
+        // the beam is nowhere near the Zapper's aim: no light, trigger pulled
+        assert_eq!(bus.mem_read(0x4017) & 0x1f, 0x18);
+
+        // a state only loads into the same devices, and survives a power cycle
+        let state = crate::savestate::save(&bus);
+        bus.power_cycle();
+        assert_eq!(bus.port(1).device_type(), DeviceType::Zapper);
+        crate::savestate::load(&mut bus, &state).unwrap();
+        bus.plug(1, input::create(DeviceType::Standard, 1));
+        assert_eq!(
+            crate::savestate::load(&mut bus, &state).err().unwrap(),
+            "the state was saved with another device in controller port 2"
+        );
+    }
+
+    #[test]
+    fn test_pal_timing() {
+        let mut rom = test_rom();
+        rom.region = Some(Region::Pal);
+        let mut bus = Bus::new(rom, |_: &mut NesPPU, _: &mut dyn InputDevice, _: &mut dyn InputDevice| {});
+        assert_eq!(bus.ppu().region, Region::Pal);
+
+        // 3.2 dots per cycle: two 106392-dot frames take 66495 CPU cycles
+        while bus.frame_count() < 2 {
+            bus.tick(1);
+        }
+        let first = bus.cycles();
+        while bus.frame_count() < 4 {
+            bus.tick(1);
+        }
+        assert_eq!(bus.cycles() - first, 66495);
+    }
+}
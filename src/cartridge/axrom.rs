@@ -0,0 +1,132 @@
+use super::{chr_memory, Mapper, Mirroring, Rom};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x8000;
+
+// Mapper 7: 32kb PRG bank switching and single-screen mirroring, 8kb of CHR RAM.
+// https://www.nesdev.org/wiki/AxROM
+pub struct Axrom {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    prg_bank: usize,
+    mirroring: Mirroring,
+}
+
+impl Axrom {
+    pub fn new(rom: Rom) -> Self {
+        let (chr, chr_is_ram) = chr_memory(rom.chr_rom);
+        Axrom {
+            prg_rom: rom.prg_rom,
+            chr,
+            chr_is_ram,
+            prg_bank: 0,
+            mirroring: Mirroring::SINGLE_SCREEN_LOWER,
+        }
+    }
+
+    fn prg_bank_count(&self) -> usize {
+        (self.prg_rom.len() / PRG_BANK_SIZE).max(1)
+    }
+}
+
+impl Savestate for Axrom {
+    fn save_state(&self, state: &mut StateWriter) {
+        if self.chr_is_ram {
+            state.bytes(&self.chr);
+        }
+        state.usize(self.prg_bank);
+        self.mirroring.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        if self.chr_is_ram {
+            state.bytes_into(&mut self.chr)?;
+        }
+        self.prg_bank = state.usize()? % self.prg_bank_count();
+        self.mirroring.load_state(state)
+    }
+}
+
+impl Mapper for Axrom {
+    fn prg_read(&self, addr: u16) -> u8 {
+        if addr < 0x8000 {
+            return 0;
+        }
+        self.prg_rom[self.prg_offset(addr).unwrap()]
+    }
+
+    // 7  bit  0
+    // ---M -PPP
+    //    |  +++- 32kb PRG bank at $8000
+    //    +------ nametable page for single-screen mirroring
+    fn prg_write(&mut self, addr: u16, data: u8) -> bool {
+        if addr < 0x8000 {
+            return false;
+        }
+        self.prg_bank = (data & 0b111) as usize % self.prg_bank_count();
+        self.mirroring = if data & 0b1_0000 == 0 {
+            Mirroring::SINGLE_SCREEN_LOWER
+        } else {
+            Mirroring::SINGLE_SCREEN_UPPER
+        };
+        true
+    }
+
+    fn chr_peek(&self, addr: u16) -> u8 {
+        self.chr[addr as usize]
+    }
+
+    fn chr_write(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            self.chr[addr as usize] = data;
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> Option<usize> {
+        if addr < 0x8000 {
+            return None;
+        }
+        Some((self.prg_bank * PRG_BANK_SIZE + (addr - 0x8000) as usize) % self.prg_rom.len())
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn power_cycle(&mut self) {
+        self.prg_bank = 0;
+        self.mirroring = Mirroring::SINGLE_SCREEN_LOWER;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_prg_bank_and_mirroring_select() {
+        let prg_rom = (0..4).flat_map(|bank| vec![bank as u8; PRG_BANK_SIZE]).collect();
+        let mut axrom = Axrom::new(Rom {
+            prg_rom,
+            chr_rom: vec![],
+            trainer: None,
+            battery: false,
+            mapper: 7,
+            screen_mirroring: Mirroring::HORIZONTAL,
+            expansion_device: 0,
+            region: None,
+        });
+
+        assert_eq!(axrom.prg_read(0x8000), 0);
+        assert_eq!(axrom.mirroring(), Mirroring::SINGLE_SCREEN_LOWER);
+
+        axrom.prg_write(0x8000, 0b1_0010);
+        assert_eq!(axrom.prg_read(0x8000), 2);
+        assert_eq!(axrom.prg_read(0xffff), 2);
+        assert_eq!(axrom.mirroring(), Mirroring::SINGLE_SCREEN_UPPER);
+
+        axrom.chr_write(0x0100, 0x66);
+        assert_eq!(axrom.chr_read(0x0100), 0x66);
+    }
+}
@@ -0,0 +1,721 @@
+use super::{chr_memory, Mapper, Mirroring, Rom};
+use crate::region::Region;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const PRG_BANK_SIZE: usize = 0x4000;
+const CHR_BANK_SIZE: usize = 0x0400;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum EepromKind {
+    // 128 bytes, Xicor protocol: no device address, bits LSB first
+    X24C01,
+    // 256 bytes, standard I2C with device address $A0, bits MSB first
+    C24C02,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Target {
+    Device,
+    Word,
+    Data,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Phase {
+    Idle,
+    Receive(Target),
+    // the EEPROM pulls SDA low for the 9th clock
+    Acknowledge(Target),
+    Send,
+    // the 9th clock of a read, low SDA from the CPU asks for the next byte
+    MasterAcknowledge,
+}
+
+// Serial EEPROM driven by bit-banging SCL/SDA through register $xD.
+// https://www.nesdev.org/wiki/Bandai_FCG_board#Serial_EEPROM
+pub struct Eeprom {
+    kind: EepromKind,
+    data: Vec<u8>,
+    phase: Phase,
+    scl: bool,
+    sda: bool,
+    // what the EEPROM drives onto SDA, released (high) when idle
+    out: bool,
+    byte: u8,
+    bits: u8,
+    address: u8,
+    acknowledging: bool,
+}
+
+impl Eeprom {
+    pub fn new(kind: EepromKind) -> Self {
+        let size = match kind {
+            EepromKind::X24C01 => 128,
+            EepromKind::C24C02 => 256,
+        };
+        Eeprom {
+            kind,
+            data: vec![0; size],
+            phase: Phase::Idle,
+            scl: false,
+            sda: false,
+            out: true,
+            byte: 0,
+            bits: 0,
+            address: 0,
+            acknowledging: false,
+        }
+    }
+
+    fn msb_first(&self) -> bool {
+        self.kind == EepromKind::C24C02
+    }
+
+    pub fn write_lines(&mut self, scl: bool, sda: bool) {
+        let rising = !self.scl && scl;
+        let falling = self.scl && !scl;
+        if self.scl && scl && self.sda != sda {
+            if sda {
+                self.phase = Phase::Idle;
+            } else {
+                let first = match self.kind {
+                    EepromKind::X24C01 => Target::Word,
+                    EepromKind::C24C02 => Target::Device,
+                };
+                self.phase = Phase::Receive(first);
+                self.byte = 0;
+                self.bits = 0;
+            }
+            self.out = true;
+        } else if rising {
+            self.clock_rising(sda);
+        } else if falling {
+            self.clock_falling();
+        }
+        self.scl = scl;
+        self.sda = sda;
+    }
+
+    pub fn read_line(&self) -> bool {
+        self.out
+    }
+
+    fn clock_rising(&mut self, sda: bool) {
+        match self.phase {
+            Phase::Receive(target) => {
+                self.byte = if self.msb_first() {
+                    self.byte << 1 | sda as u8
+                } else {
+                    self.byte >> 1 | (sda as u8) << 7
+                };
+                self.bits += 1;
+                if self.bits == 8 {
+                    self.phase = Phase::Acknowledge(target);
+                    self.acknowledging = false;
+                }
+            }
+            Phase::MasterAcknowledge => {
+                if sda {
+                    self.phase = Phase::Idle;
+                } else {
+                    self.address = self.wrap(self.address.wrapping_add(1));
+                    self.phase = Phase::Send;
+                    self.bits = 0;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn clock_falling(&mut self) {
+        match self.phase {
+            Phase::Acknowledge(target) => {
+                if !self.acknowledging {
+                    self.out = false;
+                    self.acknowledging = true;
+                    return;
+                }
+                self.out = true;
+                self.byte_received(target);
+            }
+            Phase::Send => self.send_bit(),
+            _ => {}
+        }
+    }
+
+    fn byte_received(&mut self, target: Target) {
+        let byte = self.byte;
+        self.byte = 0;
+        self.bits = 0;
+        self.phase = match (target, self.kind) {
+            (Target::Device, _) if byte & 0xfe != 0xa0 => Phase::Idle,
+            (Target::Device, _) if byte & 1 == 1 => Phase::Send,
+            (Target::Device, _) => Phase::Receive(Target::Word),
+            (Target::Word, EepromKind::C24C02) => {
+                self.address = byte;
+                Phase::Receive(Target::Data)
+            }
+            (Target::Word, EepromKind::X24C01) => {
+                self.address = byte & 0x7f;
+                if byte & 0x80 != 0 {
+                    Phase::Send
+                } else {
+                    Phase::Receive(Target::Data)
+                }
+            }
+            (Target::Data, _) => {
+                let address = self.wrap(self.address);
+                self.data[address as usize] = byte;
+                // writes wrap around within a page
+                let page = match self.kind {
+                    EepromKind::X24C01 => 4,
+                    EepromKind::C24C02 => 8,
+                };
+                self.address = (address & !(page - 1)) | (address.wrapping_add(1) & (page - 1));
+                Phase::Receive(Target::Data)
+            }
+        };
+        if self.phase == Phase::Send {
+            self.send_bit();
+        }
+    }
+
+    fn send_bit(&mut self) {
+        if self.bits == 8 {
+            self.out = true;
+            self.phase = Phase::MasterAcknowledge;
+            return;
+        }
+        let data = self.data[self.wrap(self.address) as usize];
+        let bit = if self.msb_first() { 7 - self.bits } else { self.bits };
+        self.out = data >> bit & 1 == 1;
+        self.bits += 1;
+    }
+
+    fn wrap(&self, address: u8) -> u8 {
+        (address as usize % self.data.len()) as u8
+    }
+}
+
+impl Savestate for Eeprom {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.data);
+        let (phase, target) = match self.phase {
+            Phase::Idle => (0, Target::Device),
+            Phase::Receive(target) => (1, target),
+            Phase::Acknowledge(target) => (2, target),
+            Phase::Send => (3, Target::Device),
+            Phase::MasterAcknowledge => (4, Target::Device),
+        };
+        state.u8(phase);
+        state.u8(target as u8);
+        state.bool(self.scl);
+        state.bool(self.sda);
+        state.bool(self.out);
+        state.u8(self.byte);
+        state.u8(self.bits);
+        state.u8(self.address);
+        state.bool(self.acknowledging);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        state.bytes_into(&mut self.data)?;
+        let phase = state.u8()?;
+        let target = match state.u8()? {
+            0 => Target::Device,
+            1 => Target::Word,
+            2 => Target::Data,
+            other => return Err(format!("savestate: unknown eeprom target {}", other)),
+        };
+        self.phase = match phase {
+            0 => Phase::Idle,
+            1 => Phase::Receive(target),
+            2 => Phase::Acknowledge(target),
+            3 => Phase::Send,
+            4 => Phase::MasterAcknowledge,
+            other => return Err(format!("savestate: unknown eeprom phase {}", other)),
+        };
+        self.scl = state.bool()?;
+        self.sda = state.bool()?;
+        self.out = state.bool()?;
+        self.byte = state.u8()?;
+        self.bits = state.u8()?;
+        self.address = state.u8()?;
+        self.acknowledging = state.bool()?;
+        Ok(())
+    }
+}
+
+// Real-time clock for homebrew. The real boards have none, so it sits on
+// register $xE which they ignore: writing bit 7 latches the time and selects
+// the register in bits 0-2, after which reads from $xxxE in $6000-$7FFF
+// return it in BCD. Games that never write $xE keep seeing the EEPROM.
+//
+//   0 seconds  1 minutes  2 hours  3 weekday (0 = Sunday)
+//   4 day      5 month    6 year   7 century
+//
+// The clock starts from the host's UTC time at power-on and then follows
+// emulated CPU cycles, so savestates and replays read the same time.
+pub struct Rtc {
+    seconds: u64,
+    cycles: u32,
+    cycles_per_second: u32,
+    enabled: bool,
+    selected: u8,
+    latched: [u8; 8],
+}
+
+impl Rtc {
+    pub fn new(cycles_per_second: u32) -> Self {
+        let seconds = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+        Rtc {
+            seconds,
+            cycles: 0,
+            cycles_per_second,
+            enabled: false,
+            selected: 0,
+            latched: [0; 8],
+        }
+    }
+
+    fn tick(&mut self, cycles: u8) {
+        self.cycles += cycles as u32;
+        if self.cycles >= self.cycles_per_second {
+            self.cycles -= self.cycles_per_second;
+            self.seconds += 1;
+        }
+    }
+
+    fn write(&mut self, data: u8) {
+        self.enabled = data & 0b1000_0000 != 0;
+        self.selected = data & 0b111;
+        if self.enabled {
+            self.latched = calendar(self.seconds);
+        }
+    }
+
+    fn read(&self) -> u8 {
+        let value = self.latched[self.selected as usize];
+        ((value / 10) << 4) | (value % 10)
+    }
+}
+
+impl Savestate for Rtc {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u64(self.seconds);
+        state.u32(self.cycles);
+        state.bool(self.enabled);
+        state.u8(self.selected);
+        state.bytes(&self.latched);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.seconds = state.u64()?;
+        self.cycles = state.u32()?;
+        self.enabled = state.bool()?;
+        self.selected = state.u8()?;
+        state.bytes_into(&mut self.latched)?;
+        Ok(())
+    }
+}
+
+// seconds since 1970 to the RTC registers, using the days-to-civil algorithm
+// from http://howardhinnant.github.io/date_algorithms.html
+fn calendar(seconds: u64) -> [u8; 8] {
+    let days = seconds / 86400;
+    let time = seconds % 86400;
+    let weekday = (days + 4) % 7;
+
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z % 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as u64;
+
+    [
+        (time % 60) as u8,
+        (time / 60 % 60) as u8,
+        (time / 3600) as u8,
+        weekday as u8,
+        day as u8,
+        month as u8,
+        (year % 100) as u8,
+        (year / 100 % 100) as u8,
+    ]
+}
+
+// Mappers 16 and 159 (Bandai FCG-1/2 and LZ93D50): 16kb switchable PRG bank,
+// eight 1kb CHR banks, a CPU cycle IRQ counter and a serial EEPROM for saves.
+// The registers are mirrored every 16 bytes at $6000-$7FFF (FCG) and
+// $8000-$FFFF (LZ93D50), with the homebrew clock above on $xE.
+// https://www.nesdev.org/wiki/Bandai_FCG_board
+pub struct Bandai {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    mirroring: Mirroring,
+
+    prg_bank: u8,
+    chr_banks: [u8; 8],
+    irq_enabled: bool,
+    irq_counter: u16,
+    irq_latch: u16,
+    irq_pending: bool,
+    eeprom: Eeprom,
+    eeprom_read_enabled: bool,
+    rtc: Rtc,
+}
+
+impl Bandai {
+    pub fn new(rom: Rom, eeprom: EepromKind) -> Self {
+        let (chr, chr_is_ram) = chr_memory(rom.chr_rom);
+        let cycles_per_second = Region::select(rom.region, None).cpu_clock_hz() as u32;
+        Bandai {
+            prg_rom: rom.prg_rom,
+            chr,
+            chr_is_ram,
+            mirroring: rom.screen_mirroring,
+            prg_bank: 0,
+            chr_banks: [0; 8],
+            irq_enabled: false,
+            irq_counter: 0,
+            irq_latch: 0,
+            irq_pending: false,
+            eeprom: Eeprom::new(eeprom),
+            eeprom_read_enabled: false,
+            rtc: Rtc::new(cycles_per_second),
+        }
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        let window = (addr as usize) / CHR_BANK_SIZE;
+        let bank = self.chr_banks[window] as usize % (self.chr.len() / CHR_BANK_SIZE);
+        bank * CHR_BANK_SIZE + (addr as usize) % CHR_BANK_SIZE
+    }
+
+    fn write_register(&mut self, register: u16, data: u8) {
+        match register {
+            0x0..=0x7 => self.chr_banks[register as usize] = data,
+            0x8 => self.prg_bank = data & 0b1111,
+            0x9 => {
+                self.mirroring = match data & 0b11 {
+                    0 => Mirroring::VERTICAL,
+                    1 => Mirroring::HORIZONTAL,
+                    2 => Mirroring::SINGLE_SCREEN_LOWER,
+                    _ => Mirroring::SINGLE_SCREEN_UPPER,
+                };
+            }
+            // LZ93D50: enabling copies the latch into the counter and acknowledges
+            0xa => {
+                self.irq_enabled = data & 1 != 0;
+                self.irq_counter = self.irq_latch;
+                self.irq_pending = false;
+            }
+            0xb => self.irq_latch = (self.irq_latch & 0xff00) | data as u16,
+            0xc => self.irq_latch = (self.irq_latch & 0x00ff) | (data as u16) << 8,
+            0xd => {
+                self.eeprom_read_enabled = data & 0b1000_0000 != 0;
+                self.eeprom.write_lines(data & 0b0010_0000 != 0, data & 0b0100_0000 != 0);
+            }
+            0xe => self.rtc.write(data),
+            _ => {}
+        }
+    }
+}
+
+impl Savestate for Bandai {
+    fn save_state(&self, state: &mut StateWriter) {
+        if self.chr_is_ram {
+            state.bytes(&self.chr);
+        }
+        self.mirroring.save_state(state);
+        state.u8(self.prg_bank);
+        state.bytes(&self.chr_banks);
+        state.bool(self.irq_enabled);
+        state.u16(self.irq_counter);
+        state.u16(self.irq_latch);
+        state.bool(self.irq_pending);
+        self.eeprom.save_state(state);
+        state.bool(self.eeprom_read_enabled);
+        self.rtc.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        if self.chr_is_ram {
+            state.bytes_into(&mut self.chr)?;
+        }
+        self.mirroring.load_state(state)?;
+        self.prg_bank = state.u8()?;
+        state.bytes_into(&mut self.chr_banks)?;
+        self.irq_enabled = state.bool()?;
+        self.irq_counter = state.u16()?;
+        self.irq_latch = state.u16()?;
+        self.irq_pending = state.bool()?;
+        self.eeprom.load_state(state)?;
+        self.eeprom_read_enabled = state.bool()?;
+        self.rtc.load_state(state)?;
+        Ok(())
+    }
+}
+
+impl Mapper for Bandai {
+    fn prg_read(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7fff if addr & 0xf == 0xe && self.rtc.enabled => self.rtc.read(),
+            // EEPROM data out on bit 4, the rest is open bus
+            0x6000..=0x7fff if self.eeprom_read_enabled && self.eeprom.read_line() => 0b1_0000,
+            0x8000..=0xffff => self.prg_rom[self.prg_offset(addr).unwrap()],
+            _ => 0,
+        }
+    }
+
+    fn prg_write(&mut self, addr: u16, data: u8) -> bool {
+        self.write_register(addr & 0xf, data);
+        true
+    }
+
+    fn chr_peek(&self, addr: u16) -> u8 {
+        self.chr[self.chr_offset(addr)]
+    }
+
+    fn chr_write(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            let offset = self.chr_offset(addr);
+            self.chr[offset] = data;
+        } else {
+            println!("attempt to write to chr rom space {}", addr);
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn tick(&mut self, cycles: u8) {
+        self.rtc.tick(cycles);
+        if !self.irq_enabled {
+            return;
+        }
+        let (counter, wrapped) = self.irq_counter.overflowing_sub(cycles as u16);
+        if counter == 0 || wrapped {
+            self.irq_pending = true;
+        }
+        self.irq_counter = counter;
+    }
+
+    // the EEPROM is the board's save memory
+    fn prg_ram(&self) -> Option<&[u8]> {
+        Some(&self.eeprom.data)
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.eeprom.data)
+    }
+
+    fn power_cycle(&mut self) {
+        self.prg_bank = 0;
+        self.chr_banks = [0; 8];
+        self.irq_enabled = false;
+        self.irq_pending = false;
+        self.eeprom_read_enabled = false;
+        self.rtc.enabled = false;
+    }
+
+    // a switchable 16kb bank at $8000, the last one fixed at $C000
+    fn prg_offset(&self, addr: u16) -> Option<usize> {
+        let bank_count = self.prg_rom.len() / PRG_BANK_SIZE;
+        match addr {
+            0x8000..=0xbfff => Some(self.prg_bank as usize % bank_count * PRG_BANK_SIZE + (addr - 0x8000) as usize),
+            0xc000..=0xffff => Some((bank_count - 1) * PRG_BANK_SIZE + (addr - 0xc000) as usize),
+            _ => None,
+        }
+    }
+
+    fn chr_banks(&self) -> Vec<usize> {
+        (0..8).map(|window| self.chr_offset(window as u16 * CHR_BANK_SIZE as u16)).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_bandai(eeprom: EepromKind) -> Bandai {
+        // 8 PRG banks and 32 CHR banks, each filled with its own index
+        let prg_rom = (0..8).flat_map(|bank| vec![bank as u8; PRG_BANK_SIZE]).collect();
+        let chr_rom = (0..32).flat_map(|bank| vec![bank as u8; CHR_BANK_SIZE]).collect();
+        Bandai::new(
+            Rom {
+                prg_rom,
+                chr_rom,
+                trainer: None,
+                battery: true,
+                mapper: 16,
+                screen_mirroring: Mirroring::VERTICAL,
+                expansion_device: 0,
+                region: None,
+            },
+            eeprom,
+        )
+    }
+
+    // bit-banging through $800D the way games do
+    struct EepromPort<'a>(&'a mut Bandai);
+
+    impl EepromPort<'_> {
+        fn lines(&mut self, scl: bool, sda: bool) {
+            self.0.prg_write(0x800d, 0x80 | (scl as u8) << 5 | (sda as u8) << 6);
+        }
+
+        fn sda(&self) -> bool {
+            self.0.prg_read(0x6000) & 0b1_0000 != 0
+        }
+
+        fn start(&mut self) {
+            self.lines(false, true);
+            self.lines(true, true);
+            self.lines(true, false);
+            self.lines(false, false);
+        }
+
+        fn stop(&mut self) {
+            self.lines(false, false);
+            self.lines(true, false);
+            self.lines(true, true);
+        }
+
+        fn clock_bit(&mut self, bit: bool) -> bool {
+            self.lines(false, bit);
+            self.lines(true, bit);
+            let sampled = self.sda();
+            self.lines(false, bit);
+            sampled
+        }
+
+        // returns true when the EEPROM acknowledged
+        fn send(&mut self, byte: u8, msb_first: bool) -> bool {
+            for i in 0..8 {
+                let bit = if msb_first { 7 - i } else { i };
+                self.clock_bit(byte >> bit & 1 == 1);
+            }
+            !self.clock_bit(true)
+        }
+
+        fn receive(&mut self, msb_first: bool, more: bool) -> u8 {
+            let mut byte = 0;
+            for i in 0..8 {
+                let bit = if msb_first { 7 - i } else { i };
+                byte |= (self.clock_bit(true) as u8) << bit;
+            }
+            self.clock_bit(!more);
+            byte
+        }
+    }
+
+    #[test]
+    fn test_banking_and_cycle_irq() {
+        let mut bandai = test_bandai(EepromKind::C24C02);
+        bandai.prg_write(0x8008, 3);
+        bandai.prg_write(0x6003, 17);
+        bandai.prg_write(0x8009, 1);
+        assert_eq!(bandai.prg_read(0x8000), 3);
+        assert_eq!(bandai.prg_read(0xc000), 7);
+        assert_eq!(bandai.chr_read(0x0c00), 17);
+        assert_eq!(bandai.mirroring(), Mirroring::HORIZONTAL);
+
+        bandai.prg_write(0x800b, 100);
+        bandai.prg_write(0x800c, 0);
+        bandai.prg_write(0x800a, 1);
+        bandai.tick(99);
+        assert!(!bandai.irq());
+        bandai.tick(1);
+        assert!(bandai.irq());
+        bandai.prg_write(0x800a, 0);
+        assert!(!bandai.irq());
+    }
+
+    #[test]
+    fn test_24c02_write_then_read_back() {
+        let mut bandai = test_bandai(EepromKind::C24C02);
+        let mut bus = EepromPort(&mut bandai);
+        bus.start();
+        assert!(bus.send(0xa0, true));
+        assert!(bus.send(0x10, true));
+        assert!(bus.send(0x42, true));
+        assert!(bus.send(0x43, true));
+        bus.stop();
+
+        // random read: dummy write of the address, repeated start, read
+        bus.start();
+        bus.send(0xa0, true);
+        bus.send(0x10, true);
+        bus.start();
+        assert!(bus.send(0xa1, true));
+        assert_eq!(bus.receive(true, true), 0x42);
+        assert_eq!(bus.receive(true, false), 0x43);
+        bus.stop();
+
+        assert_eq!(bandai.prg_ram().unwrap()[0x10..0x12], [0x42, 0x43]);
+        // a .sav restores it
+        let mut restored = test_bandai(EepromKind::C24C02);
+        restored.prg_ram_mut().unwrap().copy_from_slice(bandai.prg_ram().unwrap());
+        let mut bus = EepromPort(&mut restored);
+        bus.start();
+        bus.send(0xa0, true);
+        bus.send(0x11, true);
+        bus.start();
+        bus.send(0xa1, true);
+        assert_eq!(bus.receive(true, false), 0x43);
+    }
+
+    #[test]
+    fn test_x24c01_lsb_first_protocol() {
+        let mut bandai = test_bandai(EepromKind::X24C01);
+        let mut bus = EepromPort(&mut bandai);
+        bus.start();
+        assert!(bus.send(0x05, false)); // write at $05
+        assert!(bus.send(0x9c, false));
+        bus.stop();
+
+        bus.start();
+        assert!(bus.send(0x85, false)); // read from $05
+        assert_eq!(bus.receive(false, false), 0x9c);
+        bus.stop();
+        assert_eq!(bandai.prg_ram().unwrap().len(), 128);
+    }
+
+    #[test]
+    fn test_rtc_follows_cpu_cycles() {
+        let mut bandai = test_bandai(EepromKind::C24C02);
+        // Friday 2021-12-31 23:59:59 UTC
+        bandai.rtc.seconds = 1_640_995_199;
+        assert_eq!(bandai.prg_read(0x600e), 0, "the port stays off until $xE is written");
+
+        bandai.prg_write(0x800e, 0x80);
+        assert_eq!(bandai.prg_read(0x600e), 0x59);
+        bandai.prg_write(0x800e, 0x83);
+        assert_eq!(bandai.prg_read(0x7ffe), 5);
+
+        for _ in 0..1_789_773 / 7 + 1 {
+            bandai.tick(7);
+        }
+        let read = |bandai: &mut Bandai, register: u8| {
+            bandai.prg_write(0x800e, 0x80 | register);
+            bandai.prg_read(0x600e)
+        };
+        let time: Vec<u8> = (0..8).map(|register| read(&mut bandai, register)).collect();
+        assert_eq!(time, vec![0x00, 0x00, 0x00, 0x06, 0x01, 0x01, 0x22, 0x20]);
+
+        bandai.prg_write(0x800e, 0x00);
+        assert_eq!(bandai.prg_read(0x600e), 0);
+    }
+}
@@ -0,0 +1,105 @@
+// Battery-backed PRG RAM, stored next to the ROM as <rom>.sav. Like FCEUX the
+// file is a raw dump of $6000-$7FFF with no header.
+
+use super::SharedMapper;
+use std::path::{Path, PathBuf};
+
+pub struct BatterySave {
+    mapper: SharedMapper,
+    path: PathBuf,
+}
+
+impl BatterySave {
+    pub fn new(mapper: SharedMapper, rom_path: &str) -> Self {
+        BatterySave {
+            mapper,
+            path: Path::new(rom_path).with_extension("sav"),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Returns false when there is no save yet. A file of the wrong size is
+    // rejected rather than half loaded.
+    pub fn load(&self) -> Result<bool, String> {
+        if !self.path.exists() {
+            return Ok(false);
+        }
+        let data = std::fs::read(&self.path)
+            .map_err(|err| format!("failed to read {}: {}", self.path.display(), err))?;
+        let mut mapper = self.mapper.borrow_mut();
+        let ram = match mapper.prg_ram_mut() {
+            Some(ram) => ram,
+            None => return Err("the cartridge has a battery but this mapper has no PRG RAM".to_string()),
+        };
+        if data.len() != ram.len() {
+            return Err(format!(
+                "{} is {} bytes, expected {}",
+                self.path.display(),
+                data.len(),
+                ram.len()
+            ));
+        }
+        ram.copy_from_slice(&data);
+        Ok(true)
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let mapper = self.mapper.borrow();
+        let ram = match mapper.prg_ram() {
+            Some(ram) => ram,
+            None => return Ok(()),
+        };
+        std::fs::write(&self.path, ram).map_err(|err| format!("failed to write {}: {}", self.path.display(), err))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::{create_mapper, Mirroring, Rom};
+
+    fn battery_mapper() -> SharedMapper {
+        create_mapper(Rom {
+            prg_rom: vec![0; 0x8000],
+            chr_rom: vec![0; 0x2000],
+            trainer: None,
+            battery: true,
+            mapper: 4,
+            screen_mirroring: Mirroring::VERTICAL,
+            expansion_device: 0,
+            region: None,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_sav_round_trip() {
+        let dir = std::env::temp_dir().join(format!("nes-battery-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let rom_path = dir.join("zelda.nes");
+        let rom_path = rom_path.to_str().unwrap();
+
+        let mapper = battery_mapper();
+        let battery = BatterySave::new(mapper.clone(), rom_path);
+        assert_eq!(battery.path(), dir.join("zelda.sav"));
+        assert_eq!(battery.load(), Ok(false));
+
+        mapper.borrow_mut().prg_write(0x6000, 0x12);
+        mapper.borrow_mut().prg_write(0x7fff, 0x34);
+        battery.save().unwrap();
+        assert_eq!(std::fs::read(battery.path()).unwrap().len(), 0x2000);
+
+        let mapper = battery_mapper();
+        let battery = BatterySave::new(mapper.clone(), rom_path);
+        assert_eq!(battery.load(), Ok(true));
+        assert_eq!(mapper.borrow().prg_read(0x6000), 0x12);
+        assert_eq!(mapper.borrow().prg_read(0x7fff), 0x34);
+
+        std::fs::write(battery.path(), [0; 100]).unwrap();
+        assert!(battery.load().is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,126 @@
+use super::{Mirroring, Rom, CHR_ROM_PAGE_SIZE, NES_TAG, PRG_ROM_PAGE_SIZE};
+
+// Where the NMI and IRQ vectors point unless set: an RTI the builder puts
+// right below the vectors
+const DEFAULT_HANDLER: u16 = 0xfff9;
+const RTI: u8 = 0x40;
+
+/// Builds iNES images in memory, for tests and examples that shouldn't need a
+/// ROM file. The default is a 32kb NROM board with 8kb of blank CHR, the reset
+/// vector at $8000 and the NMI and IRQ vectors at an RTI.
+///
+/// ```
+/// use nes_book_emu::cartridge::builder::RomBuilder;
+///
+/// // LDA #$01; STA $00; JMP $8004
+/// let rom = RomBuilder::new()
+///     .program(0x8000, &[0xa9, 0x01, 0x85, 0x00, 0x4c, 0x04, 0x80])
+///     .rom();
+/// assert_eq!(rom.mapper, 0);
+/// assert_eq!(rom.prg_rom[0..2], [0xa9, 0x01]);
+/// ```
+pub struct RomBuilder {
+    mapper: u8,
+    mirroring: Mirroring,
+    battery: bool,
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+}
+
+impl Default for RomBuilder {
+    fn default() -> Self {
+        RomBuilder::new()
+    }
+}
+
+impl RomBuilder {
+    pub fn new() -> Self {
+        let builder = RomBuilder {
+            mapper: 0,
+            mirroring: Mirroring::HORIZONTAL,
+            battery: false,
+            prg: vec![0; 2 * PRG_ROM_PAGE_SIZE],
+            chr: vec![0; CHR_ROM_PAGE_SIZE],
+        };
+        builder
+            .program(DEFAULT_HANDLER, &[RTI])
+            .nmi_vector(DEFAULT_HANDLER)
+            .reset_vector(0x8000)
+            .irq_vector(DEFAULT_HANDLER)
+    }
+
+    pub fn mapper(mut self, mapper: u8) -> Self {
+        self.mapper = mapper;
+        self
+    }
+
+    pub fn mirroring(mut self, mirroring: Mirroring) -> Self {
+        self.mirroring = mirroring;
+        self
+    }
+
+    pub fn battery(mut self, battery: bool) -> Self {
+        self.battery = battery;
+        self
+    }
+
+    // PRG size in 16kb pages, keeping what was already written from the start
+    pub fn prg_pages(mut self, pages: usize) -> Self {
+        self.prg.resize(pages * PRG_ROM_PAGE_SIZE, 0);
+        self
+    }
+
+    // Code or data at a CPU address in $8000-$FFFF, as NROM maps it: the last
+    // 32kb of PRG, or a 16kb PRG mirrored into both halves.
+    pub fn program(mut self, addr: u16, bytes: &[u8]) -> Self {
+        assert!(addr >= 0x8000, "PRG starts at $8000, not ${:04X}", addr);
+        let window = self.prg.len().min(2 * PRG_ROM_PAGE_SIZE);
+        let base = self.prg.len() - window;
+        for (i, byte) in bytes.iter().enumerate() {
+            let offset = (addr as usize - 0x8000 + i) % window;
+            self.prg[base + offset] = *byte;
+        }
+        self
+    }
+
+    pub fn nmi_vector(self, addr: u16) -> Self {
+        self.program(0xfffa, &addr.to_le_bytes())
+    }
+
+    pub fn reset_vector(self, addr: u16) -> Self {
+        self.program(0xfffc, &addr.to_le_bytes())
+    }
+
+    pub fn irq_vector(self, addr: u16) -> Self {
+        self.program(0xfffe, &addr.to_le_bytes())
+    }
+
+    // empty for CHR RAM
+    pub fn chr(mut self, chr: Vec<u8>) -> Self {
+        self.chr = chr;
+        self
+    }
+
+    // the iNES file
+    pub fn build(&self) -> Vec<u8> {
+        let (four_screen, vertical) = match self.mirroring {
+            Mirroring::FOUR_SCREEN => (true, false),
+            Mirroring::VERTICAL => (false, true),
+            _ => (false, false),
+        };
+        let flags6 = (self.mapper << 4) | (four_screen as u8) << 3 | (self.battery as u8) << 1 | vertical as u8;
+        let mut raw = NES_TAG.to_vec();
+        raw.push((self.prg.len() / PRG_ROM_PAGE_SIZE) as u8);
+        raw.push((self.chr.len() / CHR_ROM_PAGE_SIZE) as u8);
+        raw.push(flags6);
+        raw.push(self.mapper & 0xf0);
+        raw.resize(16, 0);
+        raw.extend(&self.prg);
+        raw.extend(&self.chr);
+        raw
+    }
+
+    pub fn rom(&self) -> Rom {
+        Rom::new(&self.build()).unwrap()
+    }
+}
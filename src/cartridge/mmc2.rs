@@ -0,0 +1,253 @@
+use super::{Mapper, Mirroring, Rom};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x1000;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Latch {
+    FD,
+    FE,
+}
+
+// Mapper 9 (Punch-Out!!): 8kb switchable PRG bank and two 4kb CHR windows, each
+// with an FD/FE register pair picked by a latch that flips when the PPU fetches
+// tile $FD or $FE. https://www.nesdev.org/wiki/MMC2
+pub struct Mmc2 {
+    prg_rom: Vec<u8>,
+    chr_rom: Vec<u8>,
+    prg_ram: [u8; 0x2000],
+    mirroring: Mirroring,
+
+    prg_bank: u8,
+    // [window][latch]: $B000/$C000 for $0000, $D000/$E000 for $1000
+    chr_registers: [[u8; 2]; 2],
+    latches: [Latch; 2],
+}
+
+impl Mmc2 {
+    pub fn new(rom: Rom) -> Self {
+        Mmc2 {
+            prg_rom: rom.prg_rom,
+            chr_rom: rom.chr_rom,
+            prg_ram: [0; 0x2000],
+            mirroring: rom.screen_mirroring,
+            prg_bank: 0,
+            chr_registers: [[0; 2]; 2],
+            latches: [Latch::FE; 2],
+        }
+    }
+
+    fn prg_bank_count(&self) -> usize {
+        self.prg_rom.len() / PRG_BANK_SIZE
+    }
+
+    fn chr_bank(&self, window: usize) -> usize {
+        let register = self.chr_registers[window][self.latches[window] as usize];
+        register as usize % (self.chr_rom.len() / CHR_BANK_SIZE).max(1)
+    }
+
+    // The latch flips after the fetch, so the $FD/$FE tile itself is still
+    // drawn from the previous bank. Only $0FD8/$0FE8 trigger the first window,
+    // the whole $xFD8-$xFDF/$xFE8-$xFEF ranges trigger the second.
+    fn update_latch(&mut self, addr: u16) {
+        match addr {
+            0x0fd8 => self.latches[0] = Latch::FD,
+            0x0fe8 => self.latches[0] = Latch::FE,
+            0x1fd8..=0x1fdf => self.latches[1] = Latch::FD,
+            0x1fe8..=0x1fef => self.latches[1] = Latch::FE,
+            _ => {}
+        }
+    }
+}
+
+impl Savestate for Mmc2 {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.prg_ram);
+        self.mirroring.save_state(state);
+        state.u8(self.prg_bank);
+        for registers in self.chr_registers.iter() {
+            state.bytes(registers);
+        }
+        for latch in self.latches.iter() {
+            state.bool(*latch == Latch::FE);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        state.bytes_into(&mut self.prg_ram)?;
+        self.mirroring.load_state(state)?;
+        self.prg_bank = state.u8()?;
+        for registers in self.chr_registers.iter_mut() {
+            state.bytes_into(registers)?;
+        }
+        for latch in self.latches.iter_mut() {
+            *latch = if state.bool()? { Latch::FE } else { Latch::FD };
+        }
+        Ok(())
+    }
+}
+
+impl Mapper for Mmc2 {
+    fn prg_read(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7fff => self.prg_ram[(addr - 0x6000) as usize],
+            0x8000..=0xffff => self.prg_rom[self.prg_offset(addr).unwrap()],
+            _ => 0,
+        }
+    }
+
+    fn prg_write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            0x6000..=0x7fff => self.prg_ram[(addr - 0x6000) as usize] = data,
+            0xa000..=0xafff => self.prg_bank = data & 0b1111,
+            0xb000..=0xbfff => self.chr_registers[0][Latch::FD as usize] = data & 0b1_1111,
+            0xc000..=0xcfff => self.chr_registers[0][Latch::FE as usize] = data & 0b1_1111,
+            0xd000..=0xdfff => self.chr_registers[1][Latch::FD as usize] = data & 0b1_1111,
+            0xe000..=0xefff => self.chr_registers[1][Latch::FE as usize] = data & 0b1_1111,
+            0xf000..=0xffff => {
+                self.mirroring = if data & 1 == 0 {
+                    Mirroring::VERTICAL
+                } else {
+                    Mirroring::HORIZONTAL
+                };
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn chr_peek(&self, addr: u16) -> u8 {
+        let window = (addr as usize) / CHR_BANK_SIZE;
+        let offset = (addr as usize) % CHR_BANK_SIZE;
+        self.chr_rom[self.chr_bank(window) * CHR_BANK_SIZE + offset]
+    }
+
+    fn chr_read(&mut self, addr: u16) -> u8 {
+        let data = self.chr_peek(addr);
+        self.update_latch(addr);
+        data
+    }
+
+    fn chr_write(&mut self, addr: u16, _data: u8) {
+        println!("attempt to write to chr rom space {}", addr);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn prg_ram(&self) -> Option<&[u8]> {
+        Some(&self.prg_ram)
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn power_cycle(&mut self) {
+        self.prg_bank = 0;
+        self.chr_registers = [[0; 2]; 2];
+        self.latches = [Latch::FE; 2];
+    }
+
+    fn prg_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x8000..=0x9fff => {
+                let bank = self.prg_bank as usize % self.prg_bank_count();
+                Some(bank * PRG_BANK_SIZE + (addr - 0x8000) as usize)
+            }
+            // last three banks are fixed at $A000-$FFFF
+            0xa000..=0xffff => Some(self.prg_rom.len() - 3 * PRG_BANK_SIZE + (addr - 0xa000) as usize),
+            _ => None,
+        }
+    }
+
+    fn chr_banks(&self) -> Vec<usize> {
+        (0..8).map(|window| self.chr_bank(window / 4) * CHR_BANK_SIZE + (window % 4) * 0x400).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::SharedMapper;
+    use crate::ppu::{NesPPU, PPU};
+    use crate::render::{self, frame::Frame};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn test_mmc2() -> Mmc2 {
+        // 16 PRG banks and 32 CHR banks, each filled with its own index
+        let prg_rom = (0..16).flat_map(|bank| vec![bank as u8; PRG_BANK_SIZE]).collect();
+        let chr_rom = (0..32).flat_map(|bank| vec![bank as u8; CHR_BANK_SIZE]).collect();
+        Mmc2::new(Rom {
+            prg_rom,
+            chr_rom,
+            trainer: None,
+            battery: false,
+            mapper: 9,
+            screen_mirroring: Mirroring::VERTICAL,
+            expansion_device: 0,
+            region: None,
+        })
+    }
+
+    #[test]
+    fn test_prg_banking() {
+        let mut mmc2 = test_mmc2();
+        mmc2.prg_write(0xa000, 5);
+
+        assert_eq!(mmc2.prg_read(0x8000), 5);
+        assert_eq!(mmc2.prg_read(0xa000), 13);
+        assert_eq!(mmc2.prg_read(0xc000), 14);
+        assert_eq!(mmc2.prg_read(0xffff), 15);
+    }
+
+    #[test]
+    fn test_chr_latch_switches_after_fd_fe_fetch() {
+        let mut mmc2 = test_mmc2();
+        mmc2.prg_write(0xb000, 1);
+        mmc2.prg_write(0xc000, 2);
+        mmc2.prg_write(0xd000, 3);
+        mmc2.prg_write(0xe000, 4);
+
+        assert_eq!(mmc2.chr_read(0x0000), 2);
+        assert_eq!(mmc2.chr_read(0x1000), 4);
+
+        // fetching tile $FD switches on the last byte, the tile itself keeps the old bank
+        assert_eq!(mmc2.chr_read(0x0fd8), 2);
+        assert_eq!(mmc2.chr_read(0x0000), 1);
+        // only $0FD8 exactly triggers the first window
+        mmc2.chr_read(0x0fe9);
+        assert_eq!(mmc2.chr_read(0x0000), 1);
+        mmc2.chr_read(0x0fe8);
+        assert_eq!(mmc2.chr_read(0x0000), 2);
+
+        mmc2.chr_read(0x1fdb);
+        assert_eq!(mmc2.chr_read(0x1000), 3);
+        assert_eq!(mmc2.chr_read(0x0000), 2);
+
+        mmc2.prg_write(0xf000, 1);
+        assert_eq!(mmc2.mirroring(), Mirroring::HORIZONTAL);
+    }
+
+    #[test]
+    fn test_peeking_leaves_the_latches() {
+        let mut mmc2 = test_mmc2();
+        mmc2.prg_write(0xb000, 1);
+        mmc2.prg_write(0xc000, 2);
+        assert_eq!(mmc2.chr_peek(0x0fd8), 2);
+        assert_eq!(mmc2.chr_read(0x0000), 2);
+
+        // a picture drawn for tools, over a screen of tile $FD, doesn't either
+        let mapper: SharedMapper = Rc::new(RefCell::new(mmc2));
+        let mut ppu = NesPPU::with_mapper(mapper.clone());
+        ppu.vram.fill(0xfd);
+        ppu.write_to_mask(0b0000_1000);
+        render::render_peek(&ppu, &mut Frame::new());
+        assert_eq!(mapper.borrow_mut().chr_read(0x0000), 2);
+        render::render(&ppu, &mut Frame::new());
+        assert_eq!(mapper.borrow_mut().chr_read(0x0000), 1);
+    }
+}
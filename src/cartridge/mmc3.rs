@@ -0,0 +1,356 @@
+use super::{chr_memory, Mapper, Mirroring, Rom};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+// A12 has to stay low for about three M2 cycles before a rise counts, which
+// keeps the back-to-back fetches of a line from clocking the counter twice
+const A12_LOW_DOTS: u64 = 10;
+
+// Mapper 4: 8kb PRG banks, 1kb/2kb CHR banks and a scanline counter that
+// raises IRQ. The counter is clocked by rises of PPU A12, once per line when
+// the background and sprites use different pattern tables.
+// https://www.nesdev.org/wiki/MMC3
+pub struct Mmc3 {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    prg_ram: [u8; 0x2000],
+    four_screen: bool,
+    mirroring: Mirroring,
+
+    bank_select: u8,
+    bank_registers: [u8; 8],
+
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+    a12_high: bool,
+    a12_fell_at: u64,
+}
+
+impl Mmc3 {
+    pub fn new(rom: Rom) -> Self {
+        let (chr, chr_is_ram) = chr_memory(rom.chr_rom);
+        Mmc3 {
+            prg_rom: rom.prg_rom,
+            chr,
+            chr_is_ram,
+            prg_ram: [0; 0x2000],
+            four_screen: rom.screen_mirroring == Mirroring::FOUR_SCREEN,
+            mirroring: rom.screen_mirroring,
+            bank_select: 0,
+            bank_registers: [0, 2, 4, 5, 6, 7, 0, 1],
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_pending: false,
+            a12_high: false,
+            a12_fell_at: 0,
+        }
+    }
+
+    fn prg_bank_count(&self) -> usize {
+        self.prg_rom.len() / PRG_BANK_SIZE
+    }
+
+    // 8kb bank mapped into the $8000/$A000/$C000/$E000 window
+    fn prg_bank(&self, window: usize) -> usize {
+        let second_last = self.prg_bank_count().saturating_sub(2);
+        let swap_c000 = self.bank_select & 0b0100_0000 != 0;
+        let bank = match (window, swap_c000) {
+            (0, false) | (2, true) => self.bank_registers[6] as usize,
+            (0, true) | (2, false) => second_last,
+            (1, _) => self.bank_registers[7] as usize,
+            _ => self.prg_bank_count() - 1,
+        };
+        bank % self.prg_bank_count()
+    }
+
+    // 1kb bank mapped into each 1kb window of $0000-$1FFF
+    fn chr_bank(&self, window: usize) -> usize {
+        let window = if self.bank_select & 0b1000_0000 != 0 {
+            window ^ 0b100
+        } else {
+            window
+        };
+        let r = &self.bank_registers;
+        let bank = match window {
+            0 => r[0] & 0xfe,
+            1 => r[0] | 1,
+            2 => r[1] & 0xfe,
+            3 => r[1] | 1,
+            _ => r[window - 2],
+        } as usize;
+        bank % (self.chr.len() / CHR_BANK_SIZE)
+    }
+
+    fn clock_irq_counter(&mut self) {
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+
+        if self.irq_counter == 0 && self.irq_enabled {
+            self.irq_pending = true;
+        }
+    }
+}
+
+impl Savestate for Mmc3 {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.prg_ram);
+        if self.chr_is_ram {
+            state.bytes(&self.chr);
+        }
+        self.mirroring.save_state(state);
+        state.u8(self.bank_select);
+        state.bytes(&self.bank_registers);
+        state.u8(self.irq_latch);
+        state.u8(self.irq_counter);
+        state.bool(self.irq_reload);
+        state.bool(self.irq_enabled);
+        state.bool(self.irq_pending);
+        state.bool(self.a12_high);
+        state.u64(self.a12_fell_at);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        state.bytes_into(&mut self.prg_ram)?;
+        if self.chr_is_ram {
+            state.bytes_into(&mut self.chr)?;
+        }
+        self.mirroring.load_state(state)?;
+        self.bank_select = state.u8()?;
+        state.bytes_into(&mut self.bank_registers)?;
+        self.irq_latch = state.u8()?;
+        self.irq_counter = state.u8()?;
+        self.irq_reload = state.bool()?;
+        self.irq_enabled = state.bool()?;
+        self.irq_pending = state.bool()?;
+        self.a12_high = state.bool()?;
+        self.a12_fell_at = state.u64()?;
+        Ok(())
+    }
+}
+
+impl Mapper for Mmc3 {
+    fn prg_read(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7fff => self.prg_ram[(addr - 0x6000) as usize],
+            0x8000..=0xffff => self.prg_rom[self.prg_offset(addr).unwrap()],
+            _ => 0,
+        }
+    }
+
+    fn prg_write(&mut self, addr: u16, data: u8) -> bool {
+        let even = addr & 1 == 0;
+        match addr {
+            0x6000..=0x7fff => self.prg_ram[(addr - 0x6000) as usize] = data,
+            0x8000..=0x9fff if even => self.bank_select = data,
+            0x8000..=0x9fff => self.bank_registers[(self.bank_select & 0b111) as usize] = data,
+            0xa000..=0xbfff if even => {
+                if !self.four_screen {
+                    self.mirroring = if data & 1 == 0 {
+                        Mirroring::VERTICAL
+                    } else {
+                        Mirroring::HORIZONTAL
+                    };
+                }
+            }
+            0xa000..=0xbfff => { /* PRG RAM protect, not enforced */ }
+            0xc000..=0xdfff if even => self.irq_latch = data,
+            0xc000..=0xdfff => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+            0xe000..=0xffff if even => {
+                self.irq_enabled = false;
+                self.irq_pending = false;
+            }
+            0xe000..=0xffff => self.irq_enabled = true,
+            _ => return false,
+        }
+        true
+    }
+
+    fn chr_peek(&self, addr: u16) -> u8 {
+        let window = (addr as usize) / CHR_BANK_SIZE;
+        let offset = (addr as usize) % CHR_BANK_SIZE;
+        self.chr[self.chr_bank(window) * CHR_BANK_SIZE + offset]
+    }
+
+    fn chr_write(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            let window = (addr as usize) / CHR_BANK_SIZE;
+            let offset = (addr as usize) % CHR_BANK_SIZE;
+            let bank = self.chr_bank(window);
+            self.chr[bank * CHR_BANK_SIZE + offset] = data;
+        } else {
+            println!("attempt to write to chr rom space {}", addr);
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn prg_ram(&self) -> Option<&[u8]> {
+        Some(&self.prg_ram)
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn ppu_a12(&mut self, high: bool, dot: u64) {
+        if high && !self.a12_high && dot.saturating_sub(self.a12_fell_at) >= A12_LOW_DOTS {
+            self.clock_irq_counter();
+        }
+        if !high && self.a12_high {
+            self.a12_fell_at = dot;
+        }
+        self.a12_high = high;
+    }
+
+    fn power_cycle(&mut self) {
+        self.bank_select = 0;
+        self.bank_registers = [0, 2, 4, 5, 6, 7, 0, 1];
+        self.irq_latch = 0;
+        self.irq_counter = 0;
+        self.irq_reload = false;
+        self.irq_enabled = false;
+        self.irq_pending = false;
+        self.a12_high = false;
+        self.a12_fell_at = 0;
+    }
+
+    fn prg_offset(&self, addr: u16) -> Option<usize> {
+        if addr < 0x8000 {
+            return None;
+        }
+        let window = ((addr - 0x8000) as usize) / PRG_BANK_SIZE;
+        let offset = (addr as usize) % PRG_BANK_SIZE;
+        Some(self.prg_bank(window) * PRG_BANK_SIZE + offset)
+    }
+
+    fn chr_banks(&self) -> Vec<usize> {
+        (0..8).map(|window| self.chr_bank(window) * CHR_BANK_SIZE).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_mmc3() -> Mmc3 {
+        // 8 PRG banks and 16 CHR banks, each filled with its own index
+        let prg_rom = (0..8).flat_map(|bank| vec![bank as u8; PRG_BANK_SIZE]).collect();
+        let chr_rom = (0..16).flat_map(|bank| vec![bank as u8; CHR_BANK_SIZE]).collect();
+        Mmc3::new(Rom {
+            prg_rom,
+            chr_rom,
+            trainer: None,
+            battery: false,
+            mapper: 4,
+            screen_mirroring: Mirroring::VERTICAL,
+            expansion_device: 0,
+            region: None,
+        })
+    }
+
+    #[test]
+    fn test_prg_banking() {
+        let mut mmc3 = test_mmc3();
+        mmc3.prg_write(0x8000, 6);
+        mmc3.prg_write(0x8001, 3);
+        mmc3.prg_write(0x8000, 7);
+        mmc3.prg_write(0x8001, 4);
+
+        assert_eq!(mmc3.prg_read(0x8000), 3);
+        assert_eq!(mmc3.prg_read(0xa000), 4);
+        assert_eq!(mmc3.prg_read(0xc000), 6);
+        assert_eq!(mmc3.prg_read(0xe000), 7);
+
+        mmc3.prg_write(0x8000, 0b0100_0000);
+        assert_eq!(mmc3.prg_read(0x8000), 6);
+        assert_eq!(mmc3.prg_read(0xc000), 3);
+    }
+
+    #[test]
+    fn test_chr_banking_and_mirroring() {
+        let mut mmc3 = test_mmc3();
+        mmc3.prg_write(0x8000, 0);
+        mmc3.prg_write(0x8001, 9);
+        mmc3.prg_write(0x8000, 2);
+        mmc3.prg_write(0x8001, 12);
+
+        assert_eq!(mmc3.chr_read(0x0000), 8);
+        assert_eq!(mmc3.chr_read(0x0400), 9);
+        assert_eq!(mmc3.chr_read(0x1000), 12);
+
+        mmc3.prg_write(0x8000, 0b1000_0000);
+        assert_eq!(mmc3.chr_read(0x1000), 8);
+        assert_eq!(mmc3.chr_read(0x0000), 12);
+
+        mmc3.prg_write(0xa000, 1);
+        assert_eq!(mmc3.mirroring(), Mirroring::HORIZONTAL);
+    }
+
+    // a line with the background at $0000 and sprites at $1000
+    fn line(mmc3: &mut Mmc3, line: u64) {
+        mmc3.ppu_a12(false, line * 341 + 5);
+        mmc3.ppu_a12(true, line * 341 + 261);
+    }
+
+    #[test]
+    fn test_scanline_irq() {
+        let mut mmc3 = test_mmc3();
+        mmc3.prg_write(0xc000, 2);
+        mmc3.prg_write(0xc001, 0);
+        mmc3.prg_write(0xe001, 0);
+
+        line(&mut mmc3, 0); // reload to 2
+        assert!(!mmc3.irq());
+        line(&mut mmc3, 1); // 1
+        assert!(!mmc3.irq());
+        line(&mut mmc3, 2); // 0
+        assert!(mmc3.irq());
+
+        mmc3.prg_write(0xe000, 0);
+        assert!(!mmc3.irq());
+    }
+
+    #[test]
+    fn test_a12_rises_close_together_count_once() {
+        let mut mmc3 = test_mmc3();
+        mmc3.prg_write(0xc000, 5);
+        mmc3.prg_write(0xc001, 0);
+        line(&mut mmc3, 0);
+        assert_eq!(mmc3.irq_counter, 5);
+
+        // 8x16 sprites from both tables: A12 drops for one fetch in between
+        mmc3.ppu_a12(false, 341 + 5);
+        mmc3.ppu_a12(true, 341 + 261);
+        mmc3.ppu_a12(false, 341 + 269);
+        mmc3.ppu_a12(true, 341 + 277);
+        assert_eq!(mmc3.irq_counter, 4);
+    }
+
+    #[test]
+    fn test_small_prg_rom() {
+        let mut mmc3 = test_mmc3();
+        mmc3.prg_rom.truncate(PRG_BANK_SIZE);
+        assert_eq!(mmc3.prg_read(0x8000), 0);
+        assert_eq!(mmc3.prg_read(0xe000), 0);
+    }
+}
@@ -0,0 +1,506 @@
+pub mod axrom;
+pub mod bandai;
+pub mod battery;
+pub mod builder;
+pub mod mmc2;
+pub mod mmc3;
+pub mod nointro;
+pub mod nrom;
+
+use crate::region::Region;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
+const PRG_ROM_PAGE_SIZE: usize = 16384;
+const CHR_ROM_PAGE_SIZE: usize = 8192;
+const CHR_RAM_SIZE: usize = 8192;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[allow(non_camel_case_types)]
+pub enum Mirroring {
+   VERTICAL,
+   HORIZONTAL,
+   FOUR_SCREEN,
+   SINGLE_SCREEN_LOWER,
+   SINGLE_SCREEN_UPPER,
+}
+
+impl Savestate for Mirroring {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(*self as u8);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        *self = match state.u8()? {
+            0 => Mirroring::VERTICAL,
+            1 => Mirroring::HORIZONTAL,
+            2 => Mirroring::FOUR_SCREEN,
+            3 => Mirroring::SINGLE_SCREEN_LOWER,
+            4 => Mirroring::SINGLE_SCREEN_UPPER,
+            other => return Err(format!("savestate: unknown mirroring {}", other)),
+        };
+        Ok(())
+    }
+}
+
+pub struct Rom {
+    pub prg_rom: Vec<u8>, // Accessed by CPU
+    pub chr_rom: Vec<u8>, // Accessed by PPU for graphics
+    pub trainer: Option<Vec<u8>>, // 512 bytes copied to $7000-$71FF
+    pub battery: bool, // PRG RAM is kept alive between sessions
+    pub mapper: u8,
+    pub screen_mirroring: Mirroring,
+    pub expansion_device: u8, // NES 2.0 default expansion device, 0 (unspecified) for iNES files
+    pub region: Option<Region>, // None when the header doesn't say, or the game runs on any console
+}
+
+// Cartridge hardware as seen from the CPU ($6000-$FFFF) and the PPU ($0000-$1FFF).
+// The bus and the PPU share one instance, so bank switches made by CPU writes
+// are visible to the PPU straight away.
+// Savestates hold the bank registers and RAM, not the ROM contents.
+pub trait Mapper: Savestate {
+    fn prg_read(&self, addr: u16) -> u8;
+
+    // returns false when nothing on the cartridge handles the write
+    fn prg_write(&mut self, addr: u16, data: u8) -> bool;
+
+    // the byte a PPU fetch would see, without the fetch's side effects, for
+    // debuggers and other tools
+    fn chr_peek(&self, addr: u16) -> u8;
+
+    // A PPU fetch. Mutable so that mappers latching on PPU fetches
+    // (MMC2/MMC4) can switch banks.
+    fn chr_read(&mut self, addr: u16) -> u8 {
+        self.chr_peek(addr)
+    }
+
+    fn chr_write(&mut self, addr: u16, data: u8);
+
+    fn mirroring(&self) -> Mirroring;
+
+    fn irq(&self) -> bool {
+        false
+    }
+
+    // A12 of the PPU address bus changed, `dot` PPU dots after power-on: the
+    // pattern fetches of rendering lines and $2006/$2007 accesses. Used by
+    // MMC3-style IRQ counters, which count its rises.
+    fn ppu_a12(&mut self, _high: bool, _dot: u64) {}
+
+    // CPU cycles, for IRQ counters clocked by M2
+    fn tick(&mut self, _cycles: u8) {}
+
+    // back to the power-on register state; the reset button doesn't reach the cartridge
+    fn power_cycle(&mut self) {}
+
+    // save memory kept by a battery: 8kb work RAM at $6000-$7FFF, or the
+    // EEPROM on boards that have one. None on boards without either.
+    fn prg_ram(&self) -> Option<&[u8]> {
+        None
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+
+    // PRG ROM offset mapped at a CPU address, for the code/data logger.
+    // None outside of PRG ROM.
+    fn prg_offset(&self, _addr: u16) -> Option<usize> {
+        None
+    }
+
+    // Sound from the cartridge's own chip (VRC6, MMC5, ...), on the scale of
+    // the APU's mixed output. Silent on boards without one.
+    fn expansion_audio(&self) -> f32 {
+        0.0
+    }
+
+    // CHR offset mapped into each 1kb window of $0000-$1FFF
+    fn chr_banks(&self) -> Vec<usize> {
+        (0..8).map(|window| window * 0x400).collect()
+    }
+}
+
+// Boards reporting 0 CHR banks carry 8kb of CHR RAM that the game fills
+// through $2007. Returns the pattern memory and whether it is writable.
+pub fn chr_memory(chr_rom: Vec<u8>) -> (Vec<u8>, bool) {
+    if chr_rom.is_empty() {
+        (vec![0; CHR_RAM_SIZE], true)
+    } else {
+        (chr_rom, false)
+    }
+}
+
+pub type SharedMapper = Rc<RefCell<dyn Mapper>>;
+
+type MapperConstructor = fn(Rom) -> SharedMapper;
+
+// iNES mapper number -> mapper implementation
+const MAPPERS: &[(u8, MapperConstructor)] = &[
+    (0, |rom| Rc::new(RefCell::new(nrom::Nrom::new(rom)))),
+    (4, |rom| Rc::new(RefCell::new(mmc3::Mmc3::new(rom)))),
+    (7, |rom| Rc::new(RefCell::new(axrom::Axrom::new(rom)))),
+    (9, |rom| Rc::new(RefCell::new(mmc2::Mmc2::new(rom)))),
+    (16, |rom| Rc::new(RefCell::new(bandai::Bandai::new(rom, bandai::EepromKind::C24C02)))),
+    (159, |rom| Rc::new(RefCell::new(bandai::Bandai::new(rom, bandai::EepromKind::X24C01)))),
+];
+
+pub fn create_mapper(mut rom: Rom) -> Result<SharedMapper, String> {
+    let trainer = rom.trainer.take();
+    let mapper = match MAPPERS.iter().find(|(number, _)| *number == rom.mapper) {
+        Some((_, constructor)) => constructor(rom),
+        None => return Err(format!("Mapper {} is not supported", rom.mapper)),
+    };
+
+    if let Some(trainer) = trainer {
+        let mut mapper = mapper.borrow_mut();
+        let loaded = trainer
+            .iter()
+            .enumerate()
+            .all(|(i, byte)| mapper.prg_write(0x7000 + i as u16, *byte));
+        if !loaded {
+            println!("warning: the trainer was not loaded, this board has no PRG RAM at $7000");
+        }
+    }
+    Ok(mapper)
+}
+
+// Read in the header and initialise from iNes1.0 and NES 2.0 files. Of the
+// NES 2.0 additions only the larger ROM sizes, the timing and the default
+// expansion device are used.
+impl Rom {
+    pub fn new(raw: &Vec<u8>) -> Result<Rom, String> {
+        if raw.len() < 16 || &raw[0..4] != NES_TAG {
+            return Err("File is not in iNes file format".to_string());
+        }
+
+        let mapper = (raw[7] & 0b1111_0000) | (raw[6] >> 4);
+
+        let nes2 = match (raw[7] >> 2) & 0b11 {
+            0 => false,
+            2 => true,
+            _ => return Err("Unknown iNes header version".to_string()),
+        };
+        // NES 2.0 high size nibbles: PRG in the low one, CHR in the high one
+        let (prg_msb, chr_msb) = if nes2 { (raw[9] & 0x0f, raw[9] >> 4) } else { (0, 0) };
+        if nes2 && raw[8] & 0x0f != 0 {
+            return Err(format!("Mapper {} is not supported", ((raw[8] as usize & 0x0f) << 8) | mapper as usize));
+        }
+        if prg_msb == 0x0f || chr_msb == 0x0f {
+            return Err("NES 2.0 exponent ROM sizes are not supported".to_string());
+        }
+
+        let four_screen = raw[6] & 0b1000 != 0;
+        let vertical_mirroring = raw[6] & 0b1 != 0;
+        let screen_mirroring = match (four_screen, vertical_mirroring) {
+           (true, _) => Mirroring::FOUR_SCREEN,
+           (false, true) => Mirroring::VERTICAL,
+           (false, false) => Mirroring::HORIZONTAL,
+       };
+
+        // NES 2.0 byte 12 names the timing. The iNES 1.0 PAL bit in byte 9 is
+        // only believed when the unused bytes are clear, old rippers wrote
+        // their names over them.
+        let region = if nes2 {
+            match raw[12] & 0b11 {
+                0 => Some(Region::Ntsc),
+                1 => Some(Region::Pal),
+                3 => Some(Region::Dendy),
+                _ => None,
+            }
+        } else if raw[9] & 1 != 0 && raw[12..16].iter().all(|&byte| byte == 0) {
+            Some(Region::Pal)
+        } else {
+            None
+        };
+
+        let prg_rom_size = ((prg_msb as usize) << 8 | raw[4] as usize) * PRG_ROM_PAGE_SIZE;
+        let chr_rom_size = ((chr_msb as usize) << 8 | raw[5] as usize) * CHR_ROM_PAGE_SIZE;
+
+        let has_trainer = raw[6] & 0b100 != 0;
+        let battery = raw[6] & 0b10 != 0;
+
+        let prg_rom_start = 16 + if has_trainer { 512 } else { 0 };
+        let chr_rom_start = prg_rom_start + prg_rom_size;
+
+        // extra bytes after CHR (overdumps, title blocks) are ignored
+        if raw.len() < chr_rom_start + chr_rom_size {
+            return Err(format!(
+                "ROM file is truncated: the header asks for {}kb PRG and {}kb CHR but the file has {} bytes",
+                prg_rom_size / 1024,
+                chr_rom_size / 1024,
+                raw.len()
+            ));
+        }
+
+        Ok(Rom {
+           prg_rom: raw[prg_rom_start..(prg_rom_start + prg_rom_size)].to_vec(),
+           chr_rom: raw[chr_rom_start..(chr_rom_start + chr_rom_size)].to_vec(),
+           trainer: if has_trainer { Some(raw[16..528].to_vec()) } else { None },
+           battery,
+           mapper: mapper,
+           screen_mirroring: screen_mirroring,
+           expansion_device: if nes2 { raw[15] & 0x3f } else { 0 },
+           region,
+       })
+    }
+}
+
+pub mod test {
+
+    use super::*;
+
+    struct TestRom {
+        header: Vec<u8>,
+        trainer: Option<Vec<u8>>,
+        pgp_rom: Vec<u8>,
+        chr_rom: Vec<u8>,
+    }
+
+    fn create_rom(rom: TestRom) -> Vec<u8> {
+        let mut result = Vec::with_capacity(
+            rom.header.len()
+                + rom.trainer.as_ref().map_or(0, |t| t.len())
+                + rom.pgp_rom.len()
+                + rom.chr_rom.len(),
+        );
+
+        result.extend(&rom.header);
+        if let Some(t) = rom.trainer {
+            result.extend(t);
+        }
+        result.extend(&rom.pgp_rom);
+        result.extend(&rom.chr_rom);
+
+        result
+    }
+
+    pub fn test_rom() -> Rom {
+        test_rom_containing(vec![])
+    }
+
+    pub fn test_rom_containing(program: Vec<u8>) -> Rom {
+        let mut pgp_rom_contents = program;
+        pgp_rom_contents.resize(2 * PRG_ROM_PAGE_SIZE, 0);
+
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x01, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: pgp_rom_contents,
+            chr_rom: vec![2; 1 * CHR_ROM_PAGE_SIZE],
+        });
+
+        Rom::new(&test_rom).unwrap()
+    }
+
+    #[test]
+    fn test() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; 1 * CHR_ROM_PAGE_SIZE],
+        });
+
+        let rom: Rom = Rom::new(&test_rom).unwrap();
+
+        assert_eq!(rom.chr_rom, vec!(2; 1 * CHR_ROM_PAGE_SIZE));
+        assert_eq!(rom.prg_rom, vec!(1; 2 * PRG_ROM_PAGE_SIZE));
+        assert_eq!(rom.mapper, 3);
+        assert_eq!(rom.screen_mirroring, Mirroring::VERTICAL);
+    }
+
+    #[test]
+    fn test_with_trainer() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E,
+                0x45,
+                0x53,
+                0x1A,
+                0x02,
+                0x01,
+                0x31 | 0b100,
+                00,
+                00,
+                00,
+                00,
+                00,
+                00,
+                00,
+                00,
+                00,
+            ],
+            trainer: Some(vec![0; 512]),
+            pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; 1 * CHR_ROM_PAGE_SIZE],
+        });
+
+        let rom: Rom = Rom::new(&test_rom).unwrap();
+
+        assert_eq!(rom.chr_rom, vec!(2; 1 * CHR_ROM_PAGE_SIZE));
+        assert_eq!(rom.prg_rom, vec!(1; 2 * PRG_ROM_PAGE_SIZE));
+        assert_eq!(rom.mapper, 3);
+        assert_eq!(rom.screen_mirroring, Mirroring::VERTICAL);
+    }
+
+    #[test]
+    fn test_nes2_header() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x31, 0x8, 00, 00, 00, 00, 00, 00, 00, 0x08,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; 1 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; 1 * CHR_ROM_PAGE_SIZE],
+        });
+        let rom = Rom::new(&test_rom).unwrap();
+        assert_eq!(rom.mapper, 3);
+        assert_eq!(rom.prg_rom.len(), PRG_ROM_PAGE_SIZE);
+        assert_eq!(rom.expansion_device, 0x08);
+
+        // byte 15 means nothing in an iNes 1.0 header
+        let mut ines = test_rom.clone();
+        ines[7] = 0;
+        assert_eq!(Rom::new(&ines).unwrap().expansion_device, 0);
+
+        // timing: NES 2.0 byte 12, iNES 1.0 byte 9 while 12-15 are clear
+        assert_eq!(rom.region, Some(Region::Ntsc));
+        let mut dendy = test_rom.clone();
+        dendy[12] = 3;
+        assert_eq!(Rom::new(&dendy).unwrap().region, Some(Region::Dendy));
+        dendy[12] = 2;
+        assert_eq!(Rom::new(&dendy).unwrap().region, None);
+        ines[9] = 1;
+        ines[15] = 0;
+        assert_eq!(Rom::new(&ines).unwrap().region, Some(Region::Pal));
+        ines[13] = b'D';
+        assert_eq!(Rom::new(&ines).unwrap().region, None);
+
+        let mut unknown = test_rom.clone();
+        unknown[7] = 0x4;
+        match Rom::new(&unknown) {
+            Result::Ok(_) => assert!(false, "should not load rom"),
+            Result::Err(str) => assert_eq!(str, "Unknown iNes header version"),
+        }
+    }
+
+    #[test]
+    fn test_unsupported_mapper() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0xf1, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; 1 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; 1 * CHR_ROM_PAGE_SIZE],
+        });
+        let rom = Rom::new(&test_rom).unwrap();
+        match create_mapper(rom) {
+            Result::Ok(_) => assert!(false, "should not create mapper"),
+            Result::Err(str) => assert_eq!(str, "Mapper 15 is not supported"),
+        }
+    }
+
+    #[test]
+    fn test_nrom_mirrors_16kb_prg() {
+        let mut prg = vec![0; PRG_ROM_PAGE_SIZE];
+        prg[0] = 0x66;
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x01, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: prg,
+            chr_rom: vec![2; 1 * CHR_ROM_PAGE_SIZE],
+        });
+        let mapper = create_mapper(Rom::new(&test_rom).unwrap()).unwrap();
+        let mut mapper = mapper.borrow_mut();
+
+        assert_eq!(mapper.prg_read(0x8000), 0x66);
+        assert_eq!(mapper.prg_read(0xc000), 0x66);
+        assert_eq!(mapper.chr_read(0x0010), 2);
+        assert!(!mapper.prg_write(0x8000, 1));
+        assert_eq!(mapper.mirroring(), Mirroring::VERTICAL);
+    }
+
+    #[test]
+    fn test_trainer_loaded_at_7000() {
+        let mut trainer = vec![0; 512];
+        trainer[0] = 0x11;
+        trainer[511] = 0x22;
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x01 | 0b100, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: Some(trainer),
+            pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; 1 * CHR_ROM_PAGE_SIZE],
+        });
+        let rom = Rom::new(&test_rom).unwrap();
+        assert_eq!(rom.trainer.as_ref().map(|t| t.len()), Some(512));
+
+        let mapper = create_mapper(rom).unwrap();
+        let mapper = mapper.borrow();
+        assert_eq!(mapper.prg_read(0x7000), 0x11);
+        assert_eq!(mapper.prg_read(0x71ff), 0x22);
+        assert_eq!(mapper.prg_read(0x8000), 1);
+    }
+
+    #[test]
+    fn test_truncated_and_oversized_prg() {
+        let mut truncated = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x01, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; 1 * CHR_ROM_PAGE_SIZE],
+        });
+        truncated.truncate(truncated.len() - 1);
+        assert!(Rom::new(&truncated).is_err());
+
+        let mut prg = vec![1; 4 * PRG_ROM_PAGE_SIZE];
+        prg[2 * PRG_ROM_PAGE_SIZE] = 0x33;
+        let oversized = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x04, 0x01, 0x01, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: prg,
+            chr_rom: vec![2; 1 * CHR_ROM_PAGE_SIZE],
+        });
+        let mapper = create_mapper(Rom::new(&oversized).unwrap()).unwrap();
+        assert_eq!(mapper.borrow().prg_read(0x8000), 0x33);
+    }
+
+    #[test]
+    fn test_zero_chr_banks_get_chr_ram() {
+        use crate::ppu::{NesPPU, PPU};
+
+        for (mapper, flags) in [(0, 0x01), (4, 0x41)] {
+            let test_rom = create_rom(TestRom {
+                header: vec![
+                    0x4E, 0x45, 0x53, 0x1A, 0x02, 0x00, flags, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+                ],
+                trainer: None,
+                pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+                chr_rom: vec![],
+            });
+            let rom = Rom::new(&test_rom).unwrap();
+            assert_eq!(rom.mapper, mapper);
+            let mapper = create_mapper(rom).unwrap();
+            let mut ppu = NesPPU::with_mapper(mapper.clone());
+            ppu.write_to_ppu_addr(0x1f);
+            ppu.write_to_ppu_addr(0xff);
+            ppu.write_to_data(0x66);
+            assert_eq!(mapper.borrow_mut().chr_read(0x1fff), 0x66);
+        }
+    }
+}
@@ -0,0 +1,222 @@
+// ROM verification against a No-Intro DAT (Logiqx XML, as downloaded from
+// datomatic.no-intro.org). No-Intro hashes NES ROMs without the iNES header,
+// so the header and trainer are skipped before hashing.
+//
+// <game name="Super Mario Bros. (World)">
+//     <rom name="Super Mario Bros. (World).nes" size="40960" crc="3337EC46" sha1="..." status="verified"/>
+// </game>
+
+use std::collections::BTreeSet;
+use std::fmt;
+
+// looked up in the working directory when no --dat is given
+pub const DEFAULT_DAT: &str = "nointro.dat";
+
+pub struct DatEntry {
+    pub name: String,
+    pub size: usize,
+    pub crc32: u32,
+    pub sha1: Option<String>,
+    pub bad_dump: bool,
+}
+
+pub struct NoIntroDat {
+    pub entries: Vec<DatEntry>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Verification {
+    Verified(String),
+    BadDump(String),
+    // a good dump followed by extra garbage
+    Overdump { name: String, extra_bytes: usize },
+    Unknown,
+}
+
+impl fmt::Display for Verification {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Verification::Verified(name) => write!(f, "verified good dump of '{}'", name),
+            Verification::BadDump(name) => write!(f, "known bad dump of '{}', expect glitches", name),
+            Verification::Overdump { name, extra_bytes } => {
+                write!(f, "overdump of '{}' with {} extra bytes", name, extra_bytes)
+            }
+            Verification::Unknown => write!(f, "not in the DAT, the ROM is modified (hack, translation) or a bad dump"),
+        }
+    }
+}
+
+pub struct RomHashes {
+    pub size: usize,
+    pub crc32: u32,
+    pub sha1: String,
+}
+
+// iNES payload: everything after the header and the trainer
+pub fn rom_payload(raw: &[u8]) -> &[u8] {
+    if raw.len() < 16 {
+        return &[];
+    }
+    let trainer = if raw[6] & 0b100 != 0 { 512 } else { 0 };
+    raw.get(16 + trainer..).unwrap_or(&[])
+}
+
+pub fn hash_rom(raw: &[u8]) -> RomHashes {
+    let payload = rom_payload(raw);
+    RomHashes {
+        size: payload.len(),
+        crc32: crc32fast::hash(payload),
+        sha1: sha1_smol::Sha1::from(payload).digest().to_string(),
+    }
+}
+
+impl NoIntroDat {
+    pub fn parse(xml: &str) -> Result<NoIntroDat, String> {
+        let mut entries = vec![];
+        for game in xml.split("<game ").skip(1) {
+            let game = game.split("</game>").next().unwrap_or("");
+            let name = attribute(game, "name").ok_or("dat: <game> without a name")?;
+            for rom in game.split("<rom ").skip(1) {
+                let tag = rom.split('>').next().unwrap_or("");
+                let size = attribute(tag, "size").and_then(|size| size.parse::<usize>().ok());
+                let crc32 = attribute(tag, "crc").and_then(|crc| u32::from_str_radix(&crc, 16).ok());
+                let (size, crc32) = match (size, crc32) {
+                    (Some(size), Some(crc32)) => (size, crc32),
+                    _ => return Err(format!("dat: '{}' has a rom without a valid size and crc", name)),
+                };
+                entries.push(DatEntry {
+                    name: name.clone(),
+                    size,
+                    crc32,
+                    sha1: attribute(tag, "sha1").map(|sha1| sha1.to_lowercase()),
+                    bad_dump: attribute(tag, "status").as_deref() == Some("baddump"),
+                });
+            }
+        }
+        if entries.is_empty() {
+            return Err("dat: no <game> entries found, is this a No-Intro XML DAT?".to_string());
+        }
+        Ok(NoIntroDat { entries })
+    }
+
+    pub fn load(path: &str) -> Result<NoIntroDat, String> {
+        let xml = std::fs::read_to_string(path).map_err(|err| format!("dat: failed to read {}: {}", path, err))?;
+        NoIntroDat::parse(&xml)
+    }
+
+    pub fn verify(&self, raw: &[u8]) -> Verification {
+        let hashes = hash_rom(raw);
+        let matched = self.entries.iter().find(|entry| {
+            entry.size == hashes.size
+                && entry.crc32 == hashes.crc32
+                && entry.sha1.as_ref().is_none_or(|sha1| *sha1 == hashes.sha1)
+        });
+        if let Some(entry) = matched {
+            return match entry.bad_dump {
+                true => Verification::BadDump(entry.name.clone()),
+                false => Verification::Verified(entry.name.clone()),
+            };
+        }
+
+        let payload = rom_payload(raw);
+        let smaller_sizes: BTreeSet<usize> = self
+            .entries
+            .iter()
+            .map(|entry| entry.size)
+            .filter(|&size| size < payload.len())
+            .collect();
+        for size in smaller_sizes {
+            let crc32 = crc32fast::hash(&payload[..size]);
+            if let Some(entry) = self.entries.iter().find(|e| e.size == size && e.crc32 == crc32) {
+                return Verification::Overdump {
+                    name: entry.name.clone(),
+                    extra_bytes: payload.len() - size,
+                };
+            }
+        }
+        Verification::Unknown
+    }
+}
+
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let pattern = format!(" {}=\"", name);
+    let start = format!(" {}", tag).find(&pattern)? + pattern.len() - 1;
+    let value = tag[start..].split('"').next()?;
+    Some(
+        value
+            .replace("&quot;", "\"")
+            .replace("&apos;", "'")
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&amp;", "&"),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_raw(payload: &[u8]) -> Vec<u8> {
+        let mut raw = vec![0x4E, 0x45, 0x53, 0x1A, 0x01, 0x00, 0x00, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        raw.extend(payload);
+        raw
+    }
+
+    fn test_dat(good: &[u8], bad: &[u8]) -> NoIntroDat {
+        let xml = format!(
+            r#"<?xml version="1.0"?>
+            <datafile>
+                <game name="Good &amp; Proper (World)">
+                    <rom name="good.nes" size="{}" crc="{:08X}" sha1="{}" status="verified"/>
+                </game>
+                <game name="Broken (USA)">
+                    <rom name="broken.nes" size="{}" crc="{:08x}" status="baddump"/>
+                </game>
+            </datafile>"#,
+            good.len(),
+            crc32fast::hash(good),
+            sha1_smol::Sha1::from(good).digest().to_string().to_uppercase(),
+            bad.len(),
+            crc32fast::hash(bad),
+        );
+        NoIntroDat::parse(&xml).unwrap()
+    }
+
+    #[test]
+    fn test_verify_against_dat() {
+        let good = vec![0x11; 0x4000];
+        let bad = vec![0x22; 0x4000];
+        let dat = test_dat(&good, &bad);
+        assert_eq!(dat.entries.len(), 2);
+
+        assert_eq!(dat.verify(&test_raw(&good)), Verification::Verified("Good & Proper (World)".to_string()));
+        assert_eq!(dat.verify(&test_raw(&bad)), Verification::BadDump("Broken (USA)".to_string()));
+
+        let mut overdump = good.clone();
+        overdump.extend(vec![0xff; 0x2000]);
+        assert_eq!(
+            dat.verify(&test_raw(&overdump)),
+            Verification::Overdump {
+                name: "Good & Proper (World)".to_string(),
+                extra_bytes: 0x2000
+            }
+        );
+
+        let mut hacked = good.clone();
+        hacked[0x100] = 0x12;
+        assert_eq!(dat.verify(&test_raw(&hacked)), Verification::Unknown);
+    }
+
+    #[test]
+    fn test_hash_skips_header_and_trainer() {
+        let payload = vec![0x33; 0x4000];
+        let mut raw = test_raw(&[]);
+        raw[6] |= 0b100;
+        raw.extend(vec![0xaa; 512]);
+        raw.extend(&payload);
+
+        let hashes = hash_rom(&raw);
+        assert_eq!(hashes.size, 0x4000);
+        assert_eq!(hashes.crc32, crc32fast::hash(&payload));
+    }
+}
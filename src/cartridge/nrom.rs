@@ -0,0 +1,107 @@
+use super::{chr_memory, Mapper, Mirroring, Rom};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+const MAX_PRG_ROM_SIZE: usize = 0x8000;
+
+// Mapper 0: up to 32kb of PRG ROM and 8kb of CHR ROM, no bank switching.
+// The 8kb of PRG RAM at $6000 is only on Family Basic boards, but trainers
+// need it and nothing else touches that range.
+// https://www.nesdev.org/wiki/NROM
+pub struct Nrom {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    prg_ram: [u8; 0x2000],
+    mirroring: Mirroring,
+}
+
+impl Nrom {
+    pub fn new(rom: Rom) -> Self {
+        let mut prg_rom = rom.prg_rom;
+        if prg_rom.len() > MAX_PRG_ROM_SIZE {
+            // the vectors are at the end, so the last 32kb is what boots
+            println!(
+                "warning: {}kb of PRG ROM on an NROM board, only the last 32kb is mapped",
+                prg_rom.len() / 1024
+            );
+            prg_rom = prg_rom.split_off(prg_rom.len() - MAX_PRG_ROM_SIZE);
+        }
+        let (chr, chr_is_ram) = chr_memory(rom.chr_rom);
+        Nrom {
+            prg_rom,
+            chr,
+            chr_is_ram,
+            prg_ram: [0; 0x2000],
+            mirroring: rom.screen_mirroring,
+        }
+    }
+}
+
+impl Savestate for Nrom {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.prg_ram);
+        if self.chr_is_ram {
+            state.bytes(&self.chr);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        state.bytes_into(&mut self.prg_ram)?;
+        if self.chr_is_ram {
+            state.bytes_into(&mut self.chr)?;
+        }
+        Ok(())
+    }
+}
+
+impl Mapper for Nrom {
+    fn prg_read(&self, addr: u16) -> u8 {
+        if addr < 0x8000 {
+            return self.prg_ram[(addr - 0x6000) as usize];
+        }
+        match self.prg_offset(addr) {
+            Some(offset) => self.prg_rom[offset],
+            None => 0,
+        }
+    }
+
+    fn prg_write(&mut self, addr: u16, data: u8) -> bool {
+        if addr < 0x8000 {
+            self.prg_ram[(addr - 0x6000) as usize] = data;
+            return true;
+        }
+        false
+    }
+
+    fn chr_peek(&self, addr: u16) -> u8 {
+        self.chr[addr as usize]
+    }
+
+    fn chr_write(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            self.chr[addr as usize] = data;
+        } else {
+            println!("attempt to write to chr rom space {}", addr);
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn prg_ram(&self) -> Option<&[u8]> {
+        Some(&self.prg_ram)
+    }
+
+    // 16kb boards repeat their ROM at $C000
+    fn prg_offset(&self, addr: u16) -> Option<usize> {
+        if addr < 0x8000 || self.prg_rom.is_empty() {
+            return None;
+        }
+        Some((addr - 0x8000) as usize % self.prg_rom.len())
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+}
@@ -0,0 +1,256 @@
+// Code/Data Logger: while the game runs, marks every PRG ROM byte executed as
+// code or read as data and every CHR ROM byte drawn or read through $2007, to
+// tell a disassembler what is what. Stored like FCEUX's .cdl files, one flag
+// byte per ROM byte, PRG first and CHR after it:
+//
+// PRG  bit 0     executed
+//      bit 1     read as data
+//      bits 2-3  CPU window it was last seen in, $8000/$A000/$C000/$E000
+//      bit 4     executed after a JMP (indirect)
+//      bit 5     read through a (zp,X) or (zp),Y pointer
+//      bit 6     played as a DMC sample
+// CHR  bit 0     drawn
+//      bit 1     read through $2007
+//
+// The bus and the PPU share one log; boards with CHR RAM have no CHR part.
+
+use crate::cpu::AddressingMode;
+use crate::opcodes::OpCode;
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+pub const CODE: u8 = 0x01;
+pub const DATA: u8 = 0x02;
+pub const INDIRECT_CODE: u8 = 0x10;
+pub const INDIRECT_DATA: u8 = 0x20;
+pub const PCM: u8 = 0x40;
+
+pub const DRAWN: u8 = 0x01;
+pub const READ: u8 = 0x02;
+
+const JMP_INDIRECT: u8 = 0x6c;
+
+pub type SharedCodeDataLog = Rc<RefCell<CodeDataLog>>;
+
+pub struct CodeDataLog {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+    // the instruction being executed: its first byte and length, so reading
+    // its operand doesn't count as data
+    instruction: (u16, u16),
+    indirect_reads: bool,
+    last_opcode: u8,
+}
+
+impl CodeDataLog {
+    pub fn new(prg_size: usize, chr_size: usize) -> Self {
+        CodeDataLog {
+            prg: vec![0; prg_size],
+            chr: vec![0; chr_size],
+            instruction: (0, 0),
+            indirect_reads: false,
+            last_opcode: 0,
+        }
+    }
+
+    // carries on from an earlier session's log of the same ROM
+    pub fn from_bytes(data: &[u8], prg_size: usize, chr_size: usize) -> Result<CodeDataLog, String> {
+        if data.len() != prg_size + chr_size {
+            return Err(format!(
+                "code/data log is {} bytes, this ROM needs {}",
+                data.len(),
+                prg_size + chr_size
+            ));
+        }
+        let mut log = CodeDataLog::new(prg_size, chr_size);
+        log.prg.copy_from_slice(&data[..prg_size]);
+        log.chr.copy_from_slice(&data[prg_size..]);
+        Ok(log)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = self.prg.clone();
+        data.extend_from_slice(&self.chr);
+        data
+    }
+
+    pub fn prg(&self) -> &[u8] {
+        &self.prg
+    }
+
+    pub fn chr(&self) -> &[u8] {
+        &self.chr
+    }
+
+    // Called before the CPU fetches the instruction at `pc`. `offsets` are
+    // the PRG ROM offsets of its bytes, None for bytes outside of ROM.
+    pub fn execute(&mut self, pc: u16, opcode: &OpCode, offsets: &[Option<usize>]) {
+        let mut flags = CODE;
+        if self.last_opcode == JMP_INDIRECT {
+            flags |= INDIRECT_CODE;
+        }
+        for (i, offset) in offsets.iter().enumerate() {
+            if let Some(offset) = offset {
+                self.mark_prg(*offset, pc.wrapping_add(i as u16), flags);
+            }
+        }
+        self.instruction = (pc, opcode.bytes as u16);
+        self.indirect_reads = matches!(opcode.mode, AddressingMode::Indirect_X | AddressingMode::Indirect_Y);
+        self.last_opcode = opcode.code;
+    }
+
+    // a CPU read of PRG ROM
+    pub fn read(&mut self, offset: usize, addr: u16) {
+        let (pc, len) = self.instruction;
+        if addr.wrapping_sub(pc) < len {
+            return;
+        }
+        let flags = if self.indirect_reads { DATA | INDIRECT_DATA } else { DATA };
+        self.mark_prg(offset, addr, flags);
+    }
+
+    pub fn dmc_read(&mut self, offset: usize, addr: u16) {
+        self.mark_prg(offset, addr, DATA | PCM);
+    }
+
+    pub fn chr_access(&mut self, offset: usize, flags: u8) {
+        if let Some(byte) = self.chr.get_mut(offset) {
+            *byte |= flags;
+        }
+    }
+
+    fn mark_prg(&mut self, offset: usize, addr: u16, flags: u8) {
+        if let Some(byte) = self.prg.get_mut(offset) {
+            // the window bits say where the byte was seen last
+            let window = ((addr >> 13) & 0b11) as u8;
+            *byte = (*byte & !0b1100) | flags | window << 2;
+        }
+    }
+
+    // "PRG 40.1% code, 12.5% data, 49.2% unused; CHR 80.0% drawn, 0.0% read"
+    pub fn summary(&self) -> String {
+        let percent = |bytes: &[u8], flags: u8| {
+            let count = bytes.iter().filter(|byte| *byte & flags != 0).count();
+            100.0 * count as f64 / bytes.len().max(1) as f64
+        };
+        let mut summary = format!(
+            "PRG {:.1}% code, {:.1}% data, {:.1}% unused",
+            percent(&self.prg, CODE),
+            percent(&self.prg, DATA),
+            100.0 - percent(&self.prg, CODE | DATA)
+        );
+        if !self.chr.is_empty() {
+            summary.push_str(&format!(
+                "; CHR {:.1}% drawn, {:.1}% read",
+                percent(&self.chr, DRAWN),
+                percent(&self.chr, READ)
+            ));
+        }
+        summary
+    }
+}
+
+// The log behind --cdl, read at startup when the file exists already and
+// written back on exit.
+pub struct CdlFile {
+    path: PathBuf,
+    pub log: SharedCodeDataLog,
+}
+
+impl CdlFile {
+    pub fn open(path: &str, prg_size: usize, chr_size: usize) -> Result<CdlFile, String> {
+        let path = Path::new(path).to_path_buf();
+        let log = match std::fs::read(&path) {
+            Ok(data) => CodeDataLog::from_bytes(&data, prg_size, chr_size)
+                .map_err(|err| format!("{}: {}", path.display(), err))?,
+            Err(_) => CodeDataLog::new(prg_size, chr_size),
+        };
+        Ok(CdlFile {
+            path,
+            log: Rc::new(RefCell::new(log)),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        std::fs::write(&self.path, self.log.borrow().to_bytes())
+            .map_err(|err| format!("failed to write {}: {}", self.path.display(), err))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test::test_rom_containing;
+    use crate::cpu::{Mem, CPU};
+
+    #[test]
+    fn test_code_and_data_flags() {
+        // LDA $8010; LDX #$00; LDA ($00,X); JMP ($0002)
+        let mut program = vec![0xad, 0x10, 0x80, 0xa2, 0x00, 0xa1, 0x00, 0x6c, 0x02, 0x00];
+        program.resize(0x20, 0xea);
+        program[0x18] = 0xea; // the JMP lands here
+        let rom = test_rom_containing(program);
+        let (prg_size, chr_size) = (rom.prg_rom.len(), rom.chr_rom.len());
+        let mut bus = Bus::new(rom, |_ppu, _joypad1, _joypad2| {});
+        let log = Rc::new(RefCell::new(CodeDataLog::new(prg_size, chr_size)));
+        bus.set_cdl(Some(log.clone()));
+        // ($00) -> $8014, ($02) -> $8018
+        bus.mem_write(0x00, 0x14);
+        bus.mem_write(0x01, 0x80);
+        bus.mem_write(0x02, 0x18);
+        bus.mem_write(0x03, 0x80);
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = 0x8000;
+        for _ in 0..5 {
+            cpu.step();
+        }
+
+        let log = log.borrow();
+        let prg = log.prg();
+        // opcode and operand bytes are code only, seen through the $8000 window
+        assert_eq!(&prg[0..10], &[CODE; 10]);
+        assert_eq!(prg[0x10], DATA);
+        assert_eq!(prg[0x14], DATA | INDIRECT_DATA);
+        assert_eq!(prg[0x18], CODE | INDIRECT_CODE);
+        // neither executed nor read
+        assert_eq!(prg[0x11], 0);
+        assert_eq!(prg[0x19], 0);
+        assert!(log.summary().starts_with("PRG 0.0% code"));
+        assert_eq!(log.to_bytes().len(), prg_size + chr_size);
+        assert!(CodeDataLog::from_bytes(&log.to_bytes(), prg_size, chr_size).is_ok());
+        assert!(CodeDataLog::from_bytes(&[0; 4], prg_size, chr_size).is_err());
+    }
+
+    #[test]
+    fn test_chr_logged_after_power_cycle() {
+        let rom = test_rom_containing(vec![]);
+        let (prg_size, chr_size) = (rom.prg_rom.len(), rom.chr_rom.len());
+        let mut bus = Bus::new(rom, |_ppu, _joypad1, _joypad2| {});
+        let log = Rc::new(RefCell::new(CodeDataLog::new(prg_size, chr_size)));
+        bus.set_cdl(Some(log.clone()));
+        bus.power_cycle();
+
+        // $2006 = $0010, then the buffered $2007 read reaches the CHR byte
+        bus.mem_write(0x2006, 0x00);
+        bus.mem_write(0x2006, 0x10);
+        bus.mem_read(0x2007);
+        assert_eq!(log.borrow().chr()[0x10], READ);
+    }
+
+    #[test]
+    fn test_window_bits() {
+        let mut log = CodeDataLog::new(0x8000, 0);
+        log.read(0x10, 0xe010);
+        assert_eq!(log.prg()[0x10], DATA | 0b1100);
+        log.read(0x10, 0xa010);
+        assert_eq!(log.prg()[0x10], DATA | 0b0100);
+        log.chr_access(5, DRAWN);
+        assert!(log.chr().is_empty());
+    }
+}
@@ -0,0 +1,319 @@
+// Cheat codes: Game Genie and Pro Action Rocky codes, patches on PRG reads.
+// A patch replaces the byte the CPU reads at an address in $8000-$FFFF; one
+// with a compare value only does so while the cartridge has that byte there,
+// which keeps it off the other banks mapped to the same address.
+//
+// Raw codes in FCEUX's format, "0075:09" or "0075?03:09" with a compare
+// value, patch any address the bus answers with RAM or the cartridge. On work
+// RAM that freezes the value: the game's writes still land, its reads don't
+// see them.
+//
+// Per-game cheats live in a `<rom>.cheats.toml` file:
+//
+// [[cheat]]
+// code = "SXIOPO"
+// name = "infinite lives"
+// enabled = true
+//
+// They can be added, removed and switched on and off while the game runs
+// (the debug server's cheat commands), which writes the file back.
+
+use std::path::{Path, PathBuf};
+use toml::Value;
+
+// https://www.nesdev.org/wiki/Game_Genie
+const GAME_GENIE_LETTERS: &str = "APZLGITYEOXUKSVN";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Patch {
+    pub address: u16,
+    pub value: u8,
+    pub compare: Option<u8>,
+}
+
+impl Patch {
+    // a 6 or 8 letter Game Genie code, an 8 hex digit Pro Action Rocky code
+    // or a raw code
+    pub fn decode(code: &str) -> Result<Patch, String> {
+        let code = code.trim().to_uppercase();
+        if code.contains(':') {
+            return decode_raw(&code);
+        }
+        match code.len() {
+            6 | 8 if code.chars().all(|c| GAME_GENIE_LETTERS.contains(c)) => Ok(decode_game_genie(&code)),
+            8 => match u32::from_str_radix(&code, 16) {
+                Ok(code) => Ok(decode_pro_action_rocky(code)),
+                Err(_) => Err(format!("'{}' is not a Game Genie or Pro Action Rocky code", code)),
+            },
+            _ => Err(format!("'{}' is not a Game Genie or Pro Action Rocky code", code)),
+        }
+    }
+
+    fn apply(&self, addr: u16, data: u8) -> u8 {
+        if addr == self.address && self.compare.is_none_or(|compare| compare == data) {
+            self.value
+        } else {
+            data
+        }
+    }
+}
+
+fn decode_raw(code: &str) -> Result<Patch, String> {
+    let invalid = || format!("'{}' should be address:value or address?compare:value in hex", code);
+    let (target, value) = code.split_once(':').ok_or_else(invalid)?;
+    let (address, compare) = match target.split_once('?') {
+        Some((address, compare)) => (address, Some(u8::from_str_radix(compare, 16).map_err(|_| invalid())?)),
+        None => (target, None),
+    };
+    let address = u16::from_str_radix(address, 16).map_err(|_| invalid())?;
+    let address = match address {
+        // work RAM repeats up to $1FFF
+        0x0000..=0x1fff => address & 0x7ff,
+        0x6000..=0xffff => address,
+        _ => return Err(format!("'{}': raw codes patch RAM ($0000-$1FFF) or the cartridge ($6000-$FFFF)", code)),
+    };
+    let value = u8::from_str_radix(value, 16).map_err(|_| invalid())?;
+    Ok(Patch { address, value, compare })
+}
+
+fn decode_game_genie(code: &str) -> Patch {
+    let n: Vec<u16> = code.chars().map(|c| GAME_GENIE_LETTERS.find(c).unwrap() as u16).collect();
+    let address = 0x8000
+        | ((n[3] & 7) << 12)
+        | ((n[5] & 7) << 8)
+        | ((n[4] & 8) << 8)
+        | ((n[2] & 7) << 4)
+        | ((n[1] & 8) << 4)
+        | (n[4] & 7)
+        | (n[3] & 8);
+    // the last letter carries the value's top bit
+    let value = ((n[1] & 7) << 4) | ((n[0] & 8) << 4) | (n[0] & 7) | (n[n.len() - 1] & 8);
+    let compare = if n.len() == 8 {
+        Some((((n[7] & 7) << 4) | ((n[6] & 8) << 4) | (n[6] & 7) | (n[5] & 8)) as u8)
+    } else {
+        None
+    };
+    Patch { address, value: value as u8, compare }
+}
+
+// Pro Action Rocky codes are a scrambled 32-bit word; this unscrambles them
+// the way Mesen does, into 15 address bits, a compare byte and a value byte.
+fn decode_pro_action_rocky(code: u32) -> Patch {
+    const BITS: [u32; 31] = [
+        3, 13, 14, 1, 6, 9, 5, 0, 12, 7, 2, 8, 10, 11, 4, // address
+        19, 21, 23, 22, 20, 17, 16, 18, // compare
+        29, 31, 24, 26, 25, 30, 27, 28, // value
+    ];
+    let mut key: u32 = 0x7e5e_e93a;
+    // bit 0 isn't used
+    let mut code = code >> 1;
+    let mut result: u32 = 0;
+    for bit in BITS.iter().rev() {
+        if ((key ^ code) >> 30) & 1 != 0 {
+            result |= 1 << bit;
+            key ^= 0x5c18_4b91;
+        }
+        code <<= 1;
+        key <<= 1;
+    }
+    Patch {
+        address: (result & 0x7fff) as u16 | 0x8000,
+        value: (result >> 24) as u8,
+        compare: Some((result >> 16) as u8),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cheat {
+    pub code: String,
+    pub name: String,
+    pub patch: Patch,
+    pub enabled: bool,
+}
+
+pub struct Cheats {
+    pub cheats: Vec<Cheat>,
+    // where changes are written back, none for cheats not from a file
+    path: Option<PathBuf>,
+}
+
+impl Default for Cheats {
+    fn default() -> Self {
+        Cheats::new()
+    }
+}
+
+impl Cheats {
+    pub fn new() -> Self {
+        Cheats { cheats: vec![], path: None }
+    }
+
+    pub fn parse(text: &str) -> Result<Cheats, String> {
+        let root = text.parse::<Value>().map_err(|e| format!("cheats: {}", e))?;
+        let entries = match root.get("cheat") {
+            Some(Value::Array(entries)) => entries.clone(),
+            Some(_) => return Err("cheats: `cheat` should be an array of tables ([[cheat]])".to_string()),
+            None => vec![],
+        };
+        let mut cheats = Cheats::new();
+        for (i, entry) in entries.iter().enumerate() {
+            let code = entry
+                .get("code")
+                .and_then(Value::as_str)
+                .ok_or(format!("cheats: cheat #{} is missing `code`", i + 1))?;
+            let name = entry.get("name").and_then(Value::as_str).unwrap_or(code);
+            let index = cheats.add(code, name).map_err(|e| format!("cheats: {}", e))?;
+            match entry.get("enabled") {
+                None => {}
+                Some(Value::Boolean(enabled)) => cheats.cheats[index].enabled = *enabled,
+                Some(_) => return Err(format!("cheats: '{}' has a non boolean `enabled`", name)),
+            }
+        }
+        Ok(cheats)
+    }
+
+    // Looks for `game.cheats.toml` next to `game.nes`; changes go there even
+    // when it doesn't exist yet.
+    pub fn load_for_rom(rom_path: &str) -> Result<Cheats, String> {
+        let path = Path::new(rom_path).with_extension("cheats.toml");
+        let mut cheats = match std::fs::read_to_string(&path) {
+            Ok(text) => Cheats::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))?,
+            Err(_) => Cheats::new(),
+        };
+        cheats.path = Some(path);
+        Ok(cheats)
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn to_toml(&self) -> String {
+        let mut text = String::new();
+        for cheat in self.cheats.iter() {
+            text.push_str(&format!(
+                "[[cheat]]\ncode = {}\nname = {}\nenabled = {}\n\n",
+                Value::String(cheat.code.clone()),
+                Value::String(cheat.name.clone()),
+                cheat.enabled
+            ));
+        }
+        text
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        match self.path.as_ref() {
+            Some(path) => std::fs::write(path, self.to_toml()).map_err(|err| format!("failed to write {}: {}", path.display(), err)),
+            None => Ok(()),
+        }
+    }
+
+    // enabled straight away; returns the new cheat's index
+    pub fn add(&mut self, code: &str, name: &str) -> Result<usize, String> {
+        let patch = Patch::decode(code)?;
+        self.cheats.push(Cheat {
+            code: code.trim().to_uppercase(),
+            name: name.to_string(),
+            patch,
+            enabled: true,
+        });
+        Ok(self.cheats.len() - 1)
+    }
+
+    pub fn remove(&mut self, index: usize) -> Result<Cheat, String> {
+        if index >= self.cheats.len() {
+            return Err(format!("no cheat #{}", index));
+        }
+        Ok(self.cheats.remove(index))
+    }
+
+    pub fn set_enabled(&mut self, index: usize, enabled: bool) -> Result<(), String> {
+        let cheat = self.cheats.get_mut(index).ok_or(format!("no cheat #{}", index))?;
+        cheat.enabled = enabled;
+        Ok(())
+    }
+
+    // what the CPU reads at `addr` when the cartridge has `data` there
+    pub fn apply(&self, addr: u16, data: u8) -> u8 {
+        self.cheats
+            .iter()
+            .filter(|cheat| cheat.enabled)
+            .fold(data, |patched, cheat| cheat.patch.apply(addr, patched))
+    }
+
+    pub fn describe(&self) -> String {
+        self.cheats
+            .iter()
+            .enumerate()
+            .map(|(i, cheat)| {
+                format!(
+                    "  #{} {} {} (${:04X}){}",
+                    i,
+                    cheat.code,
+                    cheat.name,
+                    cheat.patch.address,
+                    if cheat.enabled { "" } else { " [disabled]" }
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode_game_genie() {
+        // the examples from the nesdev wiki
+        assert_eq!(Patch::decode("GOSSIP"), Ok(Patch { address: 0xd1dd, value: 0x14, compare: None }));
+        assert_eq!(Patch::decode("sxiopo"), Ok(Patch { address: 0x91d9, value: 0xad, compare: None }));
+        assert_eq!(Patch::decode("ZEXPYGLA"), Ok(Patch { address: 0x94a7, value: 0x02, compare: Some(0x03) }));
+        assert!(Patch::decode("GOSSIQ").is_err());
+        assert!(Patch::decode("GOSS").is_err());
+
+        let rocky = Patch::decode("0F8A4ED3").unwrap();
+        assert!(rocky.address >= 0x8000 && rocky.compare.is_some());
+
+        assert_eq!(Patch::decode("0075:09"), Ok(Patch { address: 0x75, value: 0x09, compare: None }));
+        assert_eq!(Patch::decode("0875?03:09"), Ok(Patch { address: 0x75, value: 0x09, compare: Some(0x03) }));
+        assert_eq!(Patch::decode("6000:ff").unwrap().address, 0x6000);
+        assert!(Patch::decode("2002:00").is_err());
+        assert!(Patch::decode("0075:109").is_err());
+    }
+
+    #[test]
+    fn test_apply_and_persist() {
+        let mut cheats = Cheats::parse(
+            r#"
+            [[cheat]]
+            code = "GOSSIP"
+            name = "lives"
+
+            [[cheat]]
+            code = "ZEXPYGLA"
+            enabled = false
+            "#,
+        )
+        .unwrap();
+        assert_eq!(cheats.apply(0xd1dd, 0x00), 0x14);
+        assert_eq!(cheats.apply(0xd1de, 0x00), 0x00);
+        // disabled
+        assert_eq!(cheats.apply(0x94a7, 0x03), 0x03);
+
+        cheats.set_enabled(1, true).unwrap();
+        assert_eq!(cheats.apply(0x94a7, 0x03), 0x02);
+        // another bank at the same address is left alone
+        assert_eq!(cheats.apply(0x94a7, 0x04), 0x04);
+        assert!(cheats.set_enabled(2, true).is_err());
+
+        let reloaded = Cheats::parse(&cheats.to_toml()).unwrap();
+        assert_eq!(reloaded.cheats, cheats.cheats);
+        assert_eq!(reloaded.cheats[1].name, "ZEXPYGLA");
+
+        assert_eq!(cheats.remove(0).unwrap().code, "GOSSIP");
+        assert_eq!(cheats.apply(0xd1dd, 0x00), 0x00);
+        assert!(Cheats::parse("[[cheat]]\ncode = \"XYZ\"\n").is_err());
+    }
+}
@@ -1,8 +1,10 @@
-use crate::opcodes;
 use crate::opcodes::OpCode;
-use crate::opcodes::CPU_OPS_CODES;
+use crate::opcodes::OPCODE_TABLE;
 use crate::bus::Bus;
-use std::collections::HashMap;
+use crate::events::EmuEvent;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+use crate::traps::Trap;
+pub use interrupt::InterruptType;
 
 
 bitflags!{
@@ -29,6 +31,26 @@ pub struct CPU<'a> {
     pub program_counter: u16,
     pub stack_pointer: u8,
     pub bus: Bus<'a>,
+    stop_requested: bool,
+    // CLI, SEI and PLP change the I flag after the IRQ poll, so the next
+    // instruction boundary still sees the old value
+    delayed_interrupt_disable: Option<bool>,
+    // the interrupts were polled at this boundary already, before a stop()
+    interrupts_polled: bool,
+    // cycles of the instruction started by step_cycle() not yet run on the bus
+    owed_cycles: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StepResult {
+    // where the instruction was fetched, after any interrupt entry
+    pub pc: u16,
+    pub opcode: u8,
+    // CPU cycles including interrupt entry and DMA stalls
+    pub cycles: usize,
+    pub interrupt: Option<InterruptType>,
+    // a trap set to break fired during the instruction
+    pub trap: Option<Trap>,
 }
 
 #[derive(Debug)]
@@ -46,8 +68,20 @@ pub enum AddressingMode {
    NoneAddressing,
 }
 
+impl AddressingMode {
+    // operand bytes after the opcode; instructions without an addressing mode
+    // know their own length
+    pub fn operand_bytes(&self) -> u16 {
+        match self {
+            AddressingMode::Absolute | AddressingMode::Absolute_X | AddressingMode::Absolute_Y => 2,
+            AddressingMode::NoneAddressing => 0,
+            _ => 1,
+        }
+    }
+}
+
 pub fn find_opcode(byte: u8) -> Option<&'static OpCode> {
-    CPU_OPS_CODES.iter().find(|op| op.code == byte)
+    OPCODE_TABLE[byte as usize]
 }
 
 pub trait Mem {
@@ -92,9 +126,11 @@ fn page_cross(addr1: u16, addr2: u16)-> bool {
 }
 
 mod interrupt {
-    #[derive(PartialEq, Eq)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub enum InterruptType {
         NMI,
+        IRQ,
+        BRK,
     }
 
     #[derive(PartialEq, Eq)]
@@ -104,12 +140,58 @@ mod interrupt {
         pub(super) b_flag_mask: u8,
         pub(super) cpu_cycles: u8,
     }
+    // two idle cycles, three stack pushes and the two vector reads
     pub(super) const NMI: Interrupt = Interrupt {
         itype: InterruptType::NMI,
         vector_addr: 0xfffA,
         b_flag_mask: 0b00100000,
-        cpu_cycles: 2,
+        cpu_cycles: 7,
+    };
+    pub(super) const IRQ: Interrupt = Interrupt {
+        itype: InterruptType::IRQ,
+        vector_addr: 0xfffe,
+        b_flag_mask: 0b00100000,
+        cpu_cycles: 7,
     };
+    // shares the IRQ vector
+    pub(super) const BRK: Interrupt = Interrupt {
+        itype: InterruptType::BRK,
+        vector_addr: 0xfffe,
+        b_flag_mask: 0b00110000,
+        cpu_cycles: 7,
+    };
+}
+
+// IRQ is level triggered from the mapper, so its pending state lives there
+impl Savestate for CPU<'_> {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.register_a);
+        state.u8(self.register_x);
+        state.u8(self.register_y);
+        state.u8(self.register_p.bits());
+        state.u16(self.program_counter);
+        state.u8(self.stack_pointer);
+        state.bool(self.delayed_interrupt_disable.is_some());
+        state.bool(self.delayed_interrupt_disable.unwrap_or(false));
+        state.bool(self.interrupts_polled);
+        state.usize(self.owed_cycles);
+        self.bus.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.register_a = state.u8()?;
+        self.register_x = state.u8()?;
+        self.register_y = state.u8()?;
+        self.register_p = CpuFlags::from_bits_truncate(state.u8()?);
+        self.program_counter = state.u16()?;
+        self.stack_pointer = state.u8()?;
+        let delayed = state.bool()?;
+        let interrupt_disable = state.bool()?;
+        self.delayed_interrupt_disable = if delayed { Some(interrupt_disable) } else { None };
+        self.interrupts_polled = state.bool()?;
+        self.owed_cycles = state.usize()?;
+        self.bus.load_state(state)
+    }
 }
 
 impl<'a> CPU<'a> {
@@ -122,15 +204,25 @@ impl<'a> CPU<'a> {
             stack_pointer: STACK_RESET,
             program_counter: 0x8000,
             bus: bus,
+            stop_requested: false,
+            delayed_interrupt_disable: None,
+            interrupts_polled: false,
+            owed_cycles: 0,
         }
     }
 
     fn stack_pop(&mut self) -> u8 {
+        if self.stack_pointer == 0xff {
+            self.bus.traps.stack_underflow(self.program_counter);
+        }
         self.stack_pointer = self.stack_pointer.wrapping_add(1);
         self.mem_read((STACK as u16) + self.stack_pointer as u16)
     }
 
     fn stack_push(&mut self, data: u8) {
+        if self.stack_pointer == 0x00 {
+            self.bus.traps.stack_overflow(self.program_counter);
+        }
         self.mem_write((STACK as u16) + self.stack_pointer as u16, data);
         self.stack_pointer = self.stack_pointer.wrapping_sub(1)
     }
@@ -156,10 +248,31 @@ impl<'a> CPU<'a> {
         self.stack_pointer = STACK_RESET;
         // self.memory = [0; 0xFFFF];
         self.register_p = CpuFlags::from_bits_truncate(0b100100);
+        self.delayed_interrupt_disable = None;
+        self.interrupts_polled = false;
+        self.owed_cycles = 0;
+
+        self.program_counter = self.mem_read_u16(0xFFFC);
+    }
 
+    // console reset button: A/X/Y survive, the stack pointer moves down by
+    // three without writing, interrupts are disabled, the APU is silenced
+    // and PC is reloaded.
+    pub fn soft_reset(&mut self) {
+        self.stack_pointer = self.stack_pointer.wrapping_sub(3);
+        self.register_p.insert(CpuFlags::INTERRUPT_DISABLE);
+        self.delayed_interrupt_disable = None;
+        self.interrupts_polled = false;
+        self.owed_cycles = 0;
+        self.bus.soft_reset();
         self.program_counter = self.mem_read_u16(0xFFFC);
     }
 
+    pub fn power_cycle(&mut self) {
+        self.bus.power_cycle();
+        self.reset();
+    }
+
     pub fn load(&mut self, program: Vec<u8>) {
         for i in 0..(program.len() as u16) {
             self.mem_write(0x0600 + i, program[i as usize]);
@@ -170,551 +283,702 @@ impl<'a> CPU<'a> {
     fn interrupt(&mut self, interrupt: interrupt::Interrupt) {
         self.stack_push_u16(self.program_counter);
         let mut flag = self.register_p.clone();
-        flag.set(CpuFlags::BREAK, interrupt.b_flag_mask & 0b010000 == 1);
-        flag.set(CpuFlags::BREAK2, interrupt.b_flag_mask & 0b100000 == 1);
+        flag.set(CpuFlags::BREAK, interrupt.b_flag_mask & 0b010000 != 0);
+        flag.set(CpuFlags::BREAK2, interrupt.b_flag_mask & 0b100000 != 0);
 
         self.stack_push(flag.bits);
         self.register_p.insert(CpuFlags::INTERRUPT_DISABLE);
 
-        self.bus.tick(interrupt.cpu_cycles);
-        self.program_counter = self.mem_read_u16(interrupt.vector_addr);
+        // The vector is fetched in the last two cycles. An NMI raised before
+        // then hijacks a BRK or IRQ: it jumps through $FFFA but keeps the
+        // flags already pushed, B included.
+        self.bus.tick(interrupt.cpu_cycles - 2);
+        let mut vector_addr = interrupt.vector_addr;
+        if interrupt.itype != interrupt::InterruptType::NMI && self.bus.poll_nmi_status().is_some() {
+            self.bus.events.emit(EmuEvent::Nmi);
+            vector_addr = interrupt::NMI.vector_addr;
+        }
+        self.bus.tick(2);
+        self.program_counter = self.mem_read_u16(vector_addr);
+    }
+
+    fn delay_interrupt_disable(&mut self) {
+        self.delayed_interrupt_disable = Some(self.register_p.contains(CpuFlags::INTERRUPT_DISABLE));
     }
     
+    // ends run()/run_with_callback() before the next instruction
+    pub fn stop(&mut self) {
+        self.stop_requested = true;
+    }
+
     pub fn run(&mut self) {
         self.run_with_callback(|_| {});
     }
 
+    /// Runs until `frames` more frames have finished, or a trap breaks.
+    ///
+    /// ```
+    /// use nes_book_emu::bus::Bus;
+    /// use nes_book_emu::cartridge::builder::RomBuilder;
+    /// use nes_book_emu::cpu::CPU;
+    ///
+    /// // JMP $8000
+    /// let rom = RomBuilder::new().program(0x8000, &[0x4c, 0x00, 0x80]).rom();
+    /// let mut cpu = CPU::new(Bus::new(rom, |_ppu, _joypad1, _joypad2| {}));
+    /// cpu.reset();
+    /// cpu.run_frames(2);
+    /// assert_eq!(cpu.bus.frame_count(), 2);
+    /// assert_eq!(cpu.program_counter, 0x8000);
+    /// ```
+    pub fn run_frames(&mut self, frames: usize) {
+        let until = self.bus.frame_count() + frames;
+        self.run_with_callback(|cpu| {
+            if cpu.bus.frame_count() >= until {
+                cpu.stop();
+            }
+        });
+    }
+
     pub fn run_with_callback<F>(&mut self, mut callback: F)
         where
             F: FnMut(&mut CPU),
         {
-    
-            let ref opcodes: HashMap<u8, &'static opcodes::OpCode> = *opcodes::OPCODES_MAP;
-
+            self.pay_owed_cycles();
             loop {
-                if let Some(_nmi) = self.bus.poll_nmi_status() {
-                    self.interrupt(interrupt::NMI);
+                // a run resumed after stop() is still at the same boundary
+                if !self.interrupts_polled {
+                    self.poll_interrupts();
+                    self.interrupts_polled = true;
                 }
 
                 callback(self);
+                if self.stop_requested {
+                    self.stop_requested = false;
+                    return;
+                }
+
+                self.execute();
+                self.interrupts_polled = false;
+                if self.bus.traps.take_break().is_some() {
+                    return;
+                }
+            }
+        }
+
+    // One instruction, preceded by the interrupt entry if one is pending. For
+    // frontends and debuggers that drive the CPU themselves instead of run().
+    pub fn step(&mut self) -> StepResult {
+        self.pay_owed_cycles();
+        let start = self.bus.cycles();
+        let interrupt = if self.interrupts_polled { None } else { self.poll_interrupts() };
+        let pc = self.program_counter;
+        let opcode = self.execute();
+        self.interrupts_polled = false;
+        StepResult {
+            pc,
+            opcode,
+            cycles: self.bus.cycles() - start,
+            interrupt,
+            trap: self.bus.traps.take_break(),
+        }
+    }
+
+    // One CPU cycle of bus time. The first cycle of an instruction runs the
+    // whole instruction, reads and writes included, and returns its
+    // StepResult; the bus, PPU, APU and mapper then advance one cycle per
+    // call until the instruction's cycles are used up.
+    pub fn step_cycle(&mut self) -> Option<StepResult> {
+        let mut started = None;
+        if self.owed_cycles == 0 {
+            self.bus.defer_ticks();
+            let mut result = self.step();
+            result.cycles = self.bus.end_deferred_ticks();
+            self.owed_cycles = result.cycles;
+            started = Some(result);
+        }
+        if self.owed_cycles > 0 {
+            self.owed_cycles -= 1;
+            self.bus.tick(1);
+        }
+        started
+    }
+
+    fn pay_owed_cycles(&mut self) {
+        while self.owed_cycles > 0 {
+            self.owed_cycles -= 1;
+            self.bus.tick(1);
+        }
+    }
+
+    fn poll_interrupts(&mut self) -> Option<InterruptType> {
+        let irq_disabled = self
+            .delayed_interrupt_disable
+            .take()
+            .unwrap_or(self.register_p.contains(CpuFlags::INTERRUPT_DISABLE));
+        if let Some(_nmi) = self.bus.poll_nmi_status() {
+            self.bus.events.emit(EmuEvent::Nmi);
+            self.interrupt(interrupt::NMI);
+            Some(InterruptType::NMI)
+        } else if self.bus.poll_irq_status() && !irq_disabled {
+            self.bus.events.emit(EmuEvent::Irq);
+            self.interrupt(interrupt::IRQ);
+            Some(InterruptType::IRQ)
+        } else {
+            None
+        }
+    }
+
+    // runs the instruction at the program counter, returns its opcode
+    fn execute(&mut self) -> u8 {
+        self.bus.traps.instruction_pc = self.program_counter;
+        if !self.bus.is_executable(self.program_counter) {
+            self.bus.traps.unmapped_execution(self.program_counter);
+        }
+
+        self.bus.log_instruction(self.program_counter);
+        let code = self.mem_read(self.program_counter);
+        let opcode = match OPCODE_TABLE[code as usize] {
+            Some(opcode) => opcode,
+            None => panic!("OpCode {:x} is in the wrong format", code),
+        };
+
+        // The whole instruction is consumed before it runs: the program
+        // counter points at the next one and handlers read their operand
+        // behind it. Jumps, branches and returns overwrite it.
+        let operand = self.program_counter.wrapping_add(1);
+        self.program_counter = self.program_counter.wrapping_add(opcode.bytes as u16);
+
+
+        match code {
+            /* LDA */
+            0xa9 | 0xa5 | 0xb5 | 0xad | 0xbd | 0xb9 | 0xa1 | 0xb1 => {
+                self.lda(&opcode.mode);
+            }
+            /* BRK */
+            0x00 => {
+                // the byte after BRK is padding, the return address skips it
+                self.program_counter = self.program_counter.wrapping_add(1);
+                self.interrupt(interrupt::BRK);
+            }
+
+            /* CLD */ 0xd8 => self.register_p.remove(CpuFlags::DECIMAL_MODE),
+
+            /* CLI */
+            0x58 => {
+                self.delay_interrupt_disable();
+                self.register_p.remove(CpuFlags::INTERRUPT_DISABLE);
+            }
+
+            /* CLV */ 0xb8 => self.register_p.remove(CpuFlags::OVERFLOW),
+
+            /* CLC */ 0x18 => self.clear_carry_flag(),
+
+            /* SEC */ 0x38 => self.set_carry_flag(),
+
+            /* SEI */
+            0x78 => {
+                self.delay_interrupt_disable();
+                self.register_p.insert(CpuFlags::INTERRUPT_DISABLE);
+            }
+
+            /* SED */ 0xf8 => self.register_p.insert(CpuFlags::DECIMAL_MODE),
+
+            /* PHA */ 0x48 => self.pha(),
+
+            /* PLA */
+            0x68 => {
+                self.pla();
+            }
+
+            /* PHP */
+            0x08 => {
+                self.php();
+            }
+
+            /* PLP */
+            0x28 => {
+                self.delay_interrupt_disable();
+                self.plp();
+            }
+
+            /* ADC */
+            0x69 | 0x65 | 0x75 | 0x6d | 0x7d | 0x79 | 0x61 | 0x71 => {
+                self.adc(&opcode.mode);
+            }
+
+            /* SBC */
+            0xe9 | 0xe5 | 0xf5 | 0xed | 0xfd | 0xf9 | 0xe1 | 0xf1 => {
+                self.sbc(&opcode.mode);
+            }
+
+            /* AND */
+            0x29 | 0x25 | 0x35 | 0x2d | 0x3d | 0x39 | 0x21 | 0x31 => {
+                self.and(&opcode.mode);
+            }
+
+            /* EOR */
+            0x49 | 0x45 | 0x55 | 0x4d | 0x5d | 0x59 | 0x41 | 0x51 => {
+                self.eor(&opcode.mode);
+            }
+
+            /* ORA */
+            0x09 | 0x05 | 0x15 | 0x0d | 0x1d | 0x19 | 0x01 | 0x11 => {
+                self.ora(&opcode.mode);
+            }
+
+            /* LSR */ 0x4a => self.lsr_register_a(),
+
+            /* LSR */
+            0x46 | 0x56 | 0x4e | 0x5e => {
+                self.lsr(&opcode.mode);
+            }
+
+            /*ASL*/ 0x0a => self.asl_register_a(),
+
+            /* ASL */
+            0x06 | 0x16 | 0x0e | 0x1e => {
+                self.asl(&opcode.mode);
+            }
+
+            /*ROL*/ 0x2a => self.rol_register_a(),
+
+            /* ROL */
+            0x26 | 0x36 | 0x2e | 0x3e => {
+                self.rol(&opcode.mode);
+            }
+
+            /* ROR */ 0x6a => self.ror_register_a(),
+
+            /* ROR */
+            0x66 | 0x76 | 0x6e | 0x7e => {
+                self.ror(&opcode.mode);
+            }
+
+            /* INC */
+            0xe6 | 0xf6 | 0xee | 0xfe => {
+                self.inc(&opcode.mode);
+            }
+
+            /* INX */
+            0xe8 => {
+                self.inx();
+            }
+
+            /* INY */
+            0xc8 => {
+                self.iny();
+            }
+
+            /* DEC */
+            0xc6 | 0xd6 | 0xce | 0xde => {
+                self.dec(&opcode.mode);
+            }
+
+            /* DEX */
+            0xca => {
+                self.dex();
+            }
+
+            /* DEY */
+            0x88 => {
+                self.dey();
+            }
+
+            /* CMP */
+            0xc9 | 0xc5 | 0xd5 | 0xcd | 0xdd | 0xd9 | 0xc1 | 0xd1 => {
+                self.compare(&opcode.mode, self.register_a);
+            }
+
+            /* CPY */
+            0xc0 | 0xc4 | 0xcc => {
+                self.compare(&opcode.mode, self.register_y);
+            }
+
+            /* CPX */
+            0xe0 | 0xe4 | 0xec => self.compare(&opcode.mode, self.register_x),
+
+            /* JMP Absolute */
+            0x4c => {
+                let mem_address = self.mem_read_u16(operand);
+                self.program_counter = mem_address;
+            }
+
+            /* JMP Indirect */
+            0x6c => {
+                let mem_address = self.mem_read_u16(operand);
+        
+                let indirect_ref = if mem_address & 0x00FF == 0x00FF {
+                    let lo = self.mem_read(mem_address);
+                    let hi = self.mem_read(mem_address & 0xFF00);
+                    (hi as u16) << 8 | (lo as u16)
+                } else {
+                    self.mem_read_u16(mem_address)
+                };
+
+                self.program_counter = indirect_ref;
+            }
+
+            /* JSR */
+            0x20 => {
+                // the return address is the last byte of the JSR
+                self.stack_push_u16(self.program_counter.wrapping_sub(1));
+                self.program_counter = self.mem_read_u16(operand);
+            }
+
+            /* RTS */
+            0x60 => {
+                self.program_counter = self.stack_pop_u16() + 1;
+            }
+
+            /* RTI */
+            0x40 => {
+                self.register_p.bits = self.stack_pop();
+                self.register_p.remove(CpuFlags::BREAK);
+                self.register_p.insert(CpuFlags::BREAK2);
+
+                self.program_counter = self.stack_pop_u16();
+            }
+
+            /* BNE */
+            0xd0 => {
+                self.branch(!self.register_p.contains(CpuFlags::ZERO));
+            }
+
+            /* BVS */
+            0x70 => {
+                self.branch(self.register_p.contains(CpuFlags::OVERFLOW));
+            }
+
+            /* BVC */
+            0x50 => {
+                self.branch(!self.register_p.contains(CpuFlags::OVERFLOW));
+            }
+
+            /* BPL */
+            0x10 => {
+                self.branch(!self.register_p.contains(CpuFlags::NEGATIV));
+            }
+
+            /* BMI */
+            0x30 => {
+                self.branch(self.register_p.contains(CpuFlags::NEGATIV));
+            }
+
+            /* BEQ */
+            0xf0 => {
+                self.branch(self.register_p.contains(CpuFlags::ZERO));
+            }
+
+            /* BCS */
+            0xb0 => {
+                self.branch(self.register_p.contains(CpuFlags::CARRY));
+            }
 
-                let code = self.mem_read(self.program_counter);
-                self.program_counter += 1;
-                let prev_program_counter = self.program_counter;
+            /* BCC */
+            0x90 => {
+                self.branch(!self.register_p.contains(CpuFlags::CARRY));
+            }
 
-                let opcode = opcodes.get(&code).expect(&format!("OpCode {:x} is in the wrong format", code));
-                
-                match code {
-                    /* LDA */
-                    0xa9 | 0xa5 | 0xb5 | 0xad | 0xbd | 0xb9 | 0xa1 | 0xb1 => {
-                        self.lda(&opcode.mode);
-                    }
-                    0x00 => return,
+            /* BIT */
+            0x24 | 0x2c => {
+                self.bit(&opcode.mode);
+            }
 
-                    /* CLD */ 0xd8 => self.register_p.remove(CpuFlags::DECIMAL_MODE),
+            /* STA */
+            0x85 | 0x95 | 0x8d | 0x9d | 0x99 | 0x81 | 0x91 => {
+                self.sta(&opcode.mode);
+            }
 
-                    /* CLI */ 0x58 => self.register_p.remove(CpuFlags::INTERRUPT_DISABLE),
+            /* STX */
+            0x86 | 0x96 | 0x8e => {
+                self.stx(&opcode.mode);
+            }
 
-                    /* CLV */ 0xb8 => self.register_p.remove(CpuFlags::OVERFLOW),
+            /* STY */
+            0x84 | 0x94 | 0x8c => {
+                self.sty(&opcode.mode);
+            }
 
-                    /* CLC */ 0x18 => self.clear_carry_flag(),
+            /* LDX */
+            0xa2 | 0xa6 | 0xb6 | 0xae | 0xbe => {
+                self.ldx(&opcode.mode);
+            }
 
-                    /* SEC */ 0x38 => self.set_carry_flag(),
+            /* LDY */
+            0xa0 | 0xa4 | 0xb4 | 0xac | 0xbc => {
+                self.ldy(&opcode.mode);
+            }
 
-                    /* SEI */ 0x78 => self.register_p.insert(CpuFlags::INTERRUPT_DISABLE),
+            /* NOP */
+            0xea => {
+                //do nothing
+            }
 
-                    /* SED */ 0xf8 => self.register_p.insert(CpuFlags::DECIMAL_MODE),
+            0xaa => {
+                self.tax();
+            }
 
-                    /* PHA */ 0x48 => self.pha(),
+            /* TAY */
+            0xa8 => {
+                self.tay();
+            }
 
-                    /* PLA */
-                    0x68 => {
-                        self.pla();
-                    }
+            /* TSX */
+            0xba => {
+                self.tsx();
+            }
 
-                    /* PHP */
-                    0x08 => {
-                        self.php();
-                    }
+            /* TXA */
+            0x8a => {
+                self.txa();
+            }
 
-                    /* PLP */
-                    0x28 => {
-                        self.plp();
-                    }
+            /* TXS */
+            0x9a => {
+                self.txs();
+            }
 
-                    /* ADC */
-                    0x69 | 0x65 | 0x75 | 0x6d | 0x7d | 0x79 | 0x61 | 0x71 => {
-                        self.adc(&opcode.mode);
-                    }
+            /* TYA */
+            0x98 => {
+                self.tya();
+            }
 
-                    /* SBC */
-                    0xe9 | 0xe5 | 0xf5 | 0xed | 0xfd | 0xf9 | 0xe1 | 0xf1 => {
-                        self.sbc(&opcode.mode);
-                    }
-
-                    /* AND */
-                    0x29 | 0x25 | 0x35 | 0x2d | 0x3d | 0x39 | 0x21 | 0x31 => {
-                        self.and(&opcode.mode);
-                    }
-
-                    /* EOR */
-                    0x49 | 0x45 | 0x55 | 0x4d | 0x5d | 0x59 | 0x41 | 0x51 => {
-                        self.eor(&opcode.mode);
-                    }
-
-                    /* ORA */
-                    0x09 | 0x05 | 0x15 | 0x0d | 0x1d | 0x19 | 0x01 | 0x11 => {
-                        self.ora(&opcode.mode);
-                    }
-
-                    /* LSR */ 0x4a => self.lsr_register_a(),
-
-                    /* LSR */
-                    0x46 | 0x56 | 0x4e | 0x5e => {
-                        self.lsr(&opcode.mode);
-                    }
-
-                    /*ASL*/ 0x0a => self.asl_register_a(),
-
-                    /* ASL */
-                    0x06 | 0x16 | 0x0e | 0x1e => {
-                        self.asl(&opcode.mode);
-                    }
-
-                    /*ROL*/ 0x2a => self.rol_register_a(),
-
-                    /* ROL */
-                    0x26 | 0x36 | 0x2e | 0x3e => {
-                        self.rol(&opcode.mode);
-                    }
-
-                    /* ROR */ 0x6a => self.ror_register_a(),
-
-                    /* ROR */
-                    0x66 | 0x76 | 0x6e | 0x7e => {
-                        self.ror(&opcode.mode);
-                    }
-
-                    /* INC */
-                    0xe6 | 0xf6 | 0xee | 0xfe => {
-                        self.inc(&opcode.mode);
-                    }
-
-                    /* INX */
-                    0xe8 => {
-                        self.inx();
-                    }
-
-                    /* INY */
-                    0xc8 => {
-                        self.iny();
-                    }
-
-                    /* DEC */
-                    0xc6 | 0xd6 | 0xce | 0xde => {
-                        self.dec(&opcode.mode);
-                    }
-
-                    /* DEX */
-                    0xca => {
-                        self.dex();
-                    }
-
-                    /* DEY */
-                    0x88 => {
-                        self.dey();
-                    }
-
-                    /* CMP */
-                    0xc9 | 0xc5 | 0xd5 | 0xcd | 0xdd | 0xd9 | 0xc1 | 0xd1 => {
-                        self.compare(&opcode.mode, self.register_a);
-                    }
-
-                    /* CPY */
-                    0xc0 | 0xc4 | 0xcc => {
-                        self.compare(&opcode.mode, self.register_y);
-                    }
-
-                    /* CPX */
-                    0xe0 | 0xe4 | 0xec => self.compare(&opcode.mode, self.register_x),
-
-                    /* JMP Absolute */
-                    0x4c => {
-                        let mem_address = self.mem_read_u16(self.program_counter);
-                        self.program_counter = mem_address;
-                    }
-
-                    /* JMP Indirect */
-                    0x6c => {
-                        let mem_address = self.mem_read_u16(self.program_counter);
-                
-                        let indirect_ref = if mem_address & 0x00FF == 0x00FF {
-                            let lo = self.mem_read(mem_address);
-                            let hi = self.mem_read(mem_address & 0xFF00);
-                            (hi as u16) << 8 | (lo as u16)
-                        } else {
-                            self.mem_read_u16(mem_address)
-                        };
-
-                        self.program_counter = indirect_ref;
-                    }
-
-                    /* JSR */
-                    0x20 => {
-                        self.stack_push_u16(self.program_counter + 2 - 1);
-                        let target_address = self.mem_read_u16(self.program_counter);
-                        self.program_counter = target_address
-                    }
-
-                    /* RTS */
-                    0x60 => {
-                        self.program_counter = self.stack_pop_u16() + 1;
-                    }
-
-                    /* RTI */
-                    0x40 => {
-                        self.register_p.bits = self.stack_pop();
-                        self.register_p.remove(CpuFlags::BREAK);
-                        self.register_p.insert(CpuFlags::BREAK2);
-
-                        self.program_counter = self.stack_pop_u16();
-                    }
-
-                    /* BNE */
-                    0xd0 => {
-                        self.branch(!self.register_p.contains(CpuFlags::ZERO));
-                    }
-
-                    /* BVS */
-                    0x70 => {
-                        self.branch(self.register_p.contains(CpuFlags::OVERFLOW));
-                    }
-
-                    /* BVC */
-                    0x50 => {
-                        self.branch(!self.register_p.contains(CpuFlags::OVERFLOW));
-                    }
-
-                    /* BPL */
-                    0x10 => {
-                        self.branch(!self.register_p.contains(CpuFlags::NEGATIV));
-                    }
-
-                    /* BMI */
-                    0x30 => {
-                        self.branch(self.register_p.contains(CpuFlags::NEGATIV));
-                    }
-
-                    /* BEQ */
-                    0xf0 => {
-                        self.branch(self.register_p.contains(CpuFlags::ZERO));
-                    }
-
-                    /* BCS */
-                    0xb0 => {
-                        self.branch(self.register_p.contains(CpuFlags::CARRY));
-                    }
-
-                    /* BCC */
-                    0x90 => {
-                        self.branch(!self.register_p.contains(CpuFlags::CARRY));
-                    }
-
-                    /* BIT */
-                    0x24 | 0x2c => {
-                        self.bit(&opcode.mode);
-                    }
-
-                    /* STA */
-                    0x85 | 0x95 | 0x8d | 0x9d | 0x99 | 0x81 | 0x91 => {
-                        self.sta(&opcode.mode);
-                    }
-
-                    /* STX */
-                    0x86 | 0x96 | 0x8e => {
-                        self.stx(&opcode.mode);
-                    }
-
-                    /* STY */
-                    0x84 | 0x94 | 0x8c => {
-                        self.sty(&opcode.mode);
-                    }
-
-                    /* LDX */
-                    0xa2 | 0xa6 | 0xb6 | 0xae | 0xbe => {
-                        self.ldx(&opcode.mode);
-                    }
-
-                    /* LDY */
-                    0xa0 | 0xa4 | 0xb4 | 0xac | 0xbc => {
-                        self.ldy(&opcode.mode);
-                    }
-
-                    /* NOP */
-                    0xea => {
-                        //do nothing
-                    }
-
-                    0xaa => {
-                        self.tax();
-                    }
-
-                    /* TAY */
-                    0xa8 => {
-                        self.tay();
-                    }
-
-                    /* TSX */
-                    0xba => {
-                        self.tsx();
-                    }
-
-                    /* TXA */
-                    0x8a => {
-                        self.txa();
-                    }
-
-                    /* TXS */
-                    0x9a => {
-                        self.txs();
-                    }
-
-                    /* TYA */
-                    0x98 => {
-                        self.tya();
-                    }
-
-                    /* unofficial */
-
-                    /* DCP */
-                    0xc7 | 0xd7 | 0xCF | 0xdF | 0xdb | 0xd3 | 0xc3 => {
-                        let (addr, _) = self.get_operand_address(&opcode.mode);
-                        let mut data = self.mem_read(addr);
-                        data = data.wrapping_sub(1);
-                        self.mem_write(addr, data);
-                        // self._update_zero_and_negative_flags(data);
-                        if data <= self.register_a {
-                            self.register_p.insert(CpuFlags::CARRY);
-                        }
-
-                        self.update_zero_and_negative_flags(self.register_a.wrapping_sub(data));
-                    }
-
-                    /* RLA */
-                    0x27 | 0x37 | 0x2F | 0x3F | 0x3b | 0x33 | 0x23 => {
-                        let data = self.rol(&opcode.mode);
-                        self.and_with_register_a(data);
-                    }
-
-                    /* SLO */
-                    0x07 | 0x17 | 0x0F | 0x1f | 0x1b | 0x03 | 0x13 => {
-                        let data = self.asl(&opcode.mode);
-                        self.or_with_register_a(data);
-                    }
-
-                    /* SRE */
-                    0x47 | 0x57 | 0x4F | 0x5f | 0x5b | 0x43 | 0x53 => {
-                        let data = self.lsr(&opcode.mode);
-                        self.xor_with_register_a(data);
-                    }
-
-                    /* SKB */
-                    0x80 | 0x82 | 0x89 | 0xc2 | 0xe2 => {
-
-                    }
-
-                    /* AXS */
-                    0xCB => {
-                        let (addr, _) = self.get_operand_address(&opcode.mode);
-                        let data = self.mem_read(addr);
-                        let x_and_a = self.register_x & self.register_a;
-                        let result = x_and_a.wrapping_sub(data);
-
-                        if data <= x_and_a {
-                            self.register_p.insert(CpuFlags::CARRY);
-                        }
-                        self.update_zero_and_negative_flags(result);
-
-                        self.register_x = result;
-                    }
-
-                    /* ARR */
-                    0x6B => {
-                        let (addr, _) = self.get_operand_address(&opcode.mode);
-                        let data = self.mem_read(addr);
-                        self.and_with_register_a(data);
-                        self.ror_register_a();
-                        //todo: registers
-                        let result = self.register_a;
-                        let bit_5 = (result >> 5) & 1;
-                        let bit_6 = (result >> 6) & 1;
-
-                        if bit_6 == 1 {
-                            self.register_p.insert(CpuFlags::CARRY)
-                        } else {
-                            self.register_p.remove(CpuFlags::CARRY)
-                        }
-
-                        if bit_5 ^ bit_6 == 1 {
-                            self.register_p.insert(CpuFlags::OVERFLOW);
-                        } else {
-                            self.register_p.remove(CpuFlags::OVERFLOW);
-                        }
-
-                        self.update_zero_and_negative_flags(result);
-                    }
-
-                    /* unofficial SBC */
-                    0xeb => {
-                        let (addr, _) = self.get_operand_address(&opcode.mode);
-                        let data = self.mem_read(addr);
-                        self.sub_from_register_a(data);
-                    }
-
-                    /* ANC */
-                    0x0b | 0x2b => {
-                        let (addr, _) = self.get_operand_address(&opcode.mode);
-                        let data = self.mem_read(addr);
-                        self.and_with_register_a(data);
-                        if self.register_p.contains(CpuFlags::NEGATIV) {
-                            self.register_p.insert(CpuFlags::CARRY);
-                        } else {
-                            self.register_p.remove(CpuFlags::CARRY);
-                        }
-                    }
-
-                    /* ALR */
-                    0x4b => {
-                        let (addr, _) = self.get_operand_address(&opcode.mode);
-                        let data = self.mem_read(addr);
-                        self.and_with_register_a(data);
-                        self.lsr_register_a();
-                    }
-
-                    /* NOP read */
-                    0x04 | 0x44 | 0x64 | 0x14 | 0x34 | 0x54 | 0x74 | 0xd4 | 0xf4 | 0x0c | 0x1c
-                    | 0x3c | 0x5c | 0x7c | 0xdc | 0xfc => {
-                        let (addr, page_cross) = self.get_operand_address(&opcode.mode);
-                        let _data = self.mem_read(addr);
-
-                        if page_cross {
-                            self.bus.tick(1);
-                        }
-                    }
-
-                    /* RRA */
-                    0x67 | 0x77 | 0x6f | 0x7f | 0x7b | 0x63 | 0x73 => {
-                        let data = self.ror(&opcode.mode);
-                        self.add_to_register_a(data);
-                    }
-
-                    /* ISB */
-                    0xe7 | 0xf7 | 0xef | 0xff | 0xfb | 0xe3 | 0xf3 => {
-                        let data = self.inc(&opcode.mode);
-                        self.sub_from_register_a(data);
-                    }
-
-                    /* NOPs */
-                    0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xb2 | 0xd2
-                    | 0xf2 => { /* do nothing */ }
-
-                    0x1a | 0x3a | 0x5a | 0x7a | 0xda | 0xfa => { /* do nothing */ }
-
-                    /* LAX */
-                    0xa7 | 0xb7 | 0xaf | 0xbf | 0xa3 | 0xb3 => {
-                        let (addr, _) = self.get_operand_address(&opcode.mode);
-                        let data = self.mem_read(addr);
-                        self.set_register_a(data);
-                        self.register_x = self.register_a;
-                    }
-
-                    /* SAX */
-                    0x87 | 0x97 | 0x8f | 0x83 => {
-                        let (addr, _) = self.get_operand_address(&opcode.mode);
-                        let data = self.register_a & self.register_x;
-                        self.mem_write(addr, data);
-                    }
-
-                    /* LXA */
-                    0xab => {
-                        self.lda(&opcode.mode);
-                        self.tax();
-                    }
-
-                    /* XAA */
-                    0x8b => {
-                        self.register_a = self.register_x;
-                        self.update_zero_and_negative_flags(self.register_a);
-                        let (addr, _) = self.get_operand_address(&opcode.mode);
-                        let data = self.mem_read(addr);
-                        self.and_with_register_a(data);
-                    }
-
-                    /* LAS */
-                    0xbb => {
-                        let (addr, _) = self.get_operand_address(&opcode.mode);
-                        let mut data = self.mem_read(addr);
-                        data = data & self.stack_pointer;
-                        self.register_a = data;
-                        self.register_x = data;
-                        self.stack_pointer = data;
-                        self.update_zero_and_negative_flags(data);
-                    }
-
-                    /* TAS */
-                    0x9b => {
-                        let data = self.register_a & self.register_x;
-                        self.stack_pointer = data;
-                        let mem_address =
-                            self.mem_read_u16(self.program_counter) + self.register_y as u16;
-
-                        let data = ((mem_address >> 8) as u8 + 1) & self.stack_pointer;
-                        self.mem_write(mem_address, data)
-                    }
-
-                    /* AHX  Indirect Y */
-                    0x93 => {
-                        let pos: u8 = self.mem_read(self.program_counter);
-                        let mem_address = self.mem_read_u16(pos as u16) + self.register_y as u16;
-                        let data = self.register_a & self.register_x & (mem_address >> 8) as u8;
-                        self.mem_write(mem_address, data)
-                    }
-
-                    /* AHX Absolute Y*/
-                    0x9f => {
-                        let mem_address =
-                            self.mem_read_u16(self.program_counter) + self.register_y as u16;
-
-                        let data = self.register_a & self.register_x & (mem_address >> 8) as u8;
-                        self.mem_write(mem_address, data)
-                    }
-
-                    /* SHX */
-                    0x9e => {
-                        let mem_address =
-                            self.mem_read_u16(self.program_counter) + self.register_y as u16;
-
-                        let data = self.register_x & ((mem_address >> 8) as u8 + 1);
-                        self.mem_write(mem_address, data)
-                    }
-
-                    /* SHY */
-                    0x9c => {
-                        let mem_address =
-                            self.mem_read_u16(self.program_counter) + self.register_x as u16;
-                        let data = self.register_y & ((mem_address >> 8) as u8 + 1);
-                        self.mem_write(mem_address, data)
-                    }
+            /* unofficial */
 
+            /* DCP */
+            0xc7 | 0xd7 | 0xCF | 0xdF | 0xdb | 0xd3 | 0xc3 => {
+                let (addr, mut data) = self.read_for_modify(&opcode.mode);
+                data = data.wrapping_sub(1);
+                self.mem_write(addr, data);
+                // self._update_zero_and_negative_flags(data);
+                if data <= self.register_a {
+                    self.register_p.insert(CpuFlags::CARRY);
                 }
 
-                self.bus.tick(opcode.cycles);
+                self.update_zero_and_negative_flags(self.register_a.wrapping_sub(data));
+            }
+
+            /* RLA */
+            0x27 | 0x37 | 0x2F | 0x3F | 0x3b | 0x33 | 0x23 => {
+                let data = self.rol(&opcode.mode);
+                self.and_with_register_a(data);
+            }
+
+            /* SLO */
+            0x07 | 0x17 | 0x0F | 0x1f | 0x1b | 0x03 | 0x13 => {
+                let data = self.asl(&opcode.mode);
+                self.or_with_register_a(data);
+            }
+
+            /* SRE */
+            0x47 | 0x57 | 0x4F | 0x5f | 0x5b | 0x43 | 0x53 => {
+                let data = self.lsr(&opcode.mode);
+                self.xor_with_register_a(data);
+            }
+
+            /* SKB */
+            0x80 | 0x82 | 0x89 | 0xc2 | 0xe2 => {
+
+            }
 
-                if prev_program_counter == self.program_counter{
-                    self.program_counter += (opcode.bytes-1) as u16;
+            /* AXS */
+            0xCB => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                let x_and_a = self.register_x & self.register_a;
+                let result = x_and_a.wrapping_sub(data);
+
+                if data <= x_and_a {
+                    self.register_p.insert(CpuFlags::CARRY);
+                }
+                self.update_zero_and_negative_flags(result);
+
+                self.register_x = result;
+            }
+
+            /* ARR */
+            0x6B => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                self.and_with_register_a(data);
+                self.ror_register_a();
+                //todo: registers
+                let result = self.register_a;
+                let bit_5 = (result >> 5) & 1;
+                let bit_6 = (result >> 6) & 1;
+
+                if bit_6 == 1 {
+                    self.register_p.insert(CpuFlags::CARRY)
+                } else {
+                    self.register_p.remove(CpuFlags::CARRY)
+                }
+
+                if bit_5 ^ bit_6 == 1 {
+                    self.register_p.insert(CpuFlags::OVERFLOW);
+                } else {
+                    self.register_p.remove(CpuFlags::OVERFLOW);
                 }
+
+                self.update_zero_and_negative_flags(result);
+            }
+
+            /* unofficial SBC */
+            0xeb => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                self.sub_from_register_a(data);
+            }
+
+            /* ANC */
+            0x0b | 0x2b => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                self.and_with_register_a(data);
+                if self.register_p.contains(CpuFlags::NEGATIV) {
+                    self.register_p.insert(CpuFlags::CARRY);
+                } else {
+                    self.register_p.remove(CpuFlags::CARRY);
+                }
+            }
+
+            /* ALR */
+            0x4b => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                self.and_with_register_a(data);
+                self.lsr_register_a();
+            }
+
+            /* NOP read */
+            0x04 | 0x44 | 0x64 | 0x14 | 0x34 | 0x54 | 0x74 | 0xd4 | 0xf4 | 0x0c | 0x1c
+            | 0x3c | 0x5c | 0x7c | 0xdc | 0xfc => {
+                let (addr, page_cross) = self.get_operand_address(&opcode.mode);
+                let _data = self.mem_read(addr);
+
+                if page_cross {
+                    self.bus.tick(1);
+                }
+            }
+
+            /* RRA */
+            0x67 | 0x77 | 0x6f | 0x7f | 0x7b | 0x63 | 0x73 => {
+                let data = self.ror(&opcode.mode);
+                self.add_to_register_a(data);
+            }
+
+            /* ISB */
+            0xe7 | 0xf7 | 0xef | 0xff | 0xfb | 0xe3 | 0xf3 => {
+                let data = self.inc(&opcode.mode);
+                self.sub_from_register_a(data);
             }
-        }  
+
+            /* NOPs */
+            0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xb2 | 0xd2
+            | 0xf2 => { /* do nothing */ }
+
+            0x1a | 0x3a | 0x5a | 0x7a | 0xda | 0xfa => { /* do nothing */ }
+
+            /* LAX */
+            0xa7 | 0xb7 | 0xaf | 0xbf | 0xa3 | 0xb3 => {
+                let (addr, page_cross) = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                self.set_register_a(data);
+                self.register_x = self.register_a;
+                if page_cross {
+                    self.bus.tick(1);
+                }
+            }
+
+            /* SAX */
+            0x87 | 0x97 | 0x8f | 0x83 => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                let data = self.register_a & self.register_x;
+                self.mem_write(addr, data);
+            }
+
+            /* LXA */
+            0xab => {
+                self.lda(&opcode.mode);
+                self.tax();
+            }
+
+            /* XAA */
+            0x8b => {
+                self.register_a = self.register_x;
+                self.update_zero_and_negative_flags(self.register_a);
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                self.and_with_register_a(data);
+            }
+
+            /* LAS */
+            0xbb => {
+                let (addr, page_cross) = self.get_operand_address(&opcode.mode);
+                let mut data = self.mem_read(addr);
+                data = data & self.stack_pointer;
+                self.register_a = data;
+                self.register_x = data;
+                self.stack_pointer = data;
+                self.update_zero_and_negative_flags(data);
+                if page_cross {
+                    self.bus.tick(1);
+                }
+            }
+
+            /* TAS */
+            0x9b => {
+                let data = self.register_a & self.register_x;
+                self.stack_pointer = data;
+                let mem_address =
+                    self.mem_read_u16(operand) + self.register_y as u16;
+
+                let data = ((mem_address >> 8) as u8 + 1) & self.stack_pointer;
+                self.mem_write(mem_address, data)
+            }
+
+            /* AHX  Indirect Y */
+            0x93 => {
+                let pos: u8 = self.mem_read(operand);
+                let mem_address = self.mem_read_u16(pos as u16) + self.register_y as u16;
+                let data = self.register_a & self.register_x & (mem_address >> 8) as u8;
+                self.mem_write(mem_address, data)
+            }
+
+            /* AHX Absolute Y*/
+            0x9f => {
+                let mem_address =
+                    self.mem_read_u16(operand) + self.register_y as u16;
+
+                let data = self.register_a & self.register_x & (mem_address >> 8) as u8;
+                self.mem_write(mem_address, data)
+            }
+
+            /* SHX */
+            0x9e => {
+                let mem_address =
+                    self.mem_read_u16(operand) + self.register_y as u16;
+
+                let data = self.register_x & ((mem_address >> 8) as u8 + 1);
+                self.mem_write(mem_address, data)
+            }
+
+            /* SHY */
+            0x9c => {
+                let mem_address =
+                    self.mem_read_u16(operand) + self.register_x as u16;
+                let data = self.register_y & ((mem_address >> 8) as u8 + 1);
+                self.mem_write(mem_address, data)
+            }
+
+        }
+
+        // BRK ticks its own cycles so an NMI can hijack it part way
+        if code != 0x00 {
+            self.bus.tick(opcode.cycles);
+        }
+
+        code
+    }
     
 
 
@@ -749,8 +1013,7 @@ impl<'a> CPU<'a> {
     }
 
     fn inc(&mut self, mode: &AddressingMode) -> u8{
-        let (addr, _) = self.get_operand_address(mode);
-        let mut val = self.mem_read(addr);
+        let (addr, mut val) = self.read_for_modify(mode);
         val = val.wrapping_add(1);
         self.mem_write(addr, val);
         self.update_zero_and_negative_flags(val);
@@ -789,8 +1052,7 @@ impl<'a> CPU<'a> {
     }
 
     fn asl(&mut self, mode: &AddressingMode) -> u8{
-        let (addr, _) = self.get_operand_address(mode);
-        let mut val = self.mem_read(addr);
+        let (addr, mut val) = self.read_for_modify(mode);
 
         if val >> 7 == 1 {
             self.set_carry_flag();
@@ -820,8 +1082,7 @@ impl<'a> CPU<'a> {
     }
 
     fn dec(&mut self, mode: &AddressingMode) -> u8{
-        let (addr, _) = self.get_operand_address(mode);
-        let val = self.mem_read(addr);
+        let (addr, val) = self.read_for_modify(mode);
         let result = val.wrapping_sub(1);
         self.mem_write(addr, result);
         self.update_zero_and_negative_flags(result);
@@ -838,12 +1099,6 @@ impl<'a> CPU<'a> {
         }
     }
 
-    fn jsr(&mut self){
-        self.stack_push_u16(self.program_counter + 2 - 1);
-        let target_address = self.mem_read_u16(self.program_counter);
-        self.program_counter = target_address
-    }
-
     fn lsr_register_a(&mut self){
         let mut var = self.register_a;
         if var & 1 == 1 {
@@ -856,8 +1111,7 @@ impl<'a> CPU<'a> {
     }
 
     fn lsr(&mut self, mode: &AddressingMode) -> u8{
-        let (addr, _) = self.get_operand_address(mode);
-        let mut var = self.mem_read(addr);
+        let (addr, mut var) = self.read_for_modify(mode);
 
         if var & 1 == 1 {
             self.set_carry_flag();
@@ -922,8 +1176,7 @@ impl<'a> CPU<'a> {
     }
 
     fn rol(&mut self, mode: &AddressingMode) -> u8 {
-        let (addr, _) = self.get_operand_address(mode);
-        let mut var = self.mem_read(addr);
+        let (addr, mut var) = self.read_for_modify(mode);
         let old_carry = self.register_p.contains(CpuFlags::CARRY);
 
         if var >> 7 == 1 {
@@ -941,8 +1194,7 @@ impl<'a> CPU<'a> {
     }
 
     fn ror(&mut self, mode: &AddressingMode) -> u8 {
-        let (addr, _) = self.get_operand_address(mode);
-        let mut var = self.mem_read(addr);
+        let (addr, mut var) = self.read_for_modify(mode);
         let old_carry = self.register_p.contains(CpuFlags::CARRY);
 
         if var & 1 == 1 {
@@ -993,7 +1245,7 @@ impl<'a> CPU<'a> {
     }
 
     fn sta(&mut self, mode: &AddressingMode) {
-        let (addr, _) = self.get_operand_address(mode);
+        let addr = self.get_store_address(mode);
         self.mem_write(addr, self.register_a);
     }
 
@@ -1077,15 +1329,12 @@ impl<'a> CPU<'a> {
         if condition {
             self.bus.tick(1);
 
-            let jump: i8 = self.mem_read(self.program_counter) as i8;
-            let jump_addr = self
-                .program_counter
-                .wrapping_add(1)
-                .wrapping_add(jump as u16);
-
-            self.program_counter = jump_addr;
+            let jump: i8 = self.mem_read(self.program_counter.wrapping_sub(1)) as i8;
+            let next_instruction = self.program_counter;
+            let jump_addr = next_instruction.wrapping_add(jump as u16);
 
-            if self.program_counter.wrapping_add(1) & 0xFF00 != jump_addr & 0xFF00 {
+            // one more cycle when the target is on another page than the next instruction
+            if page_cross(next_instruction, jump_addr) {
                 self.bus.tick(1);
             }
 
@@ -1214,27 +1463,68 @@ impl<'a> CPU<'a> {
         }
     }
 
+    // Indexed modes first read from the address before the carry into the
+    // high byte is added. Loads only pay for that dummy read on a page cross.
     fn get_operand_address(&mut self, mode: &AddressingMode) -> (u16, bool){
         match mode {
-            AddressingMode::Immediate => (self.program_counter, false),
-            _ => self.get_absolute_address(mode, self.program_counter),
+            AddressingMode::Immediate => (self.program_counter.wrapping_sub(1), false),
+            _ => {
+                let operand = self.program_counter.wrapping_sub(mode.operand_bytes());
+                let (addr, page_cross) = self.get_absolute_address(mode, operand);
+                if page_cross {
+                    self.mem_read(addr.wrapping_sub(0x100));
+                }
+                (addr, page_cross)
+            }
+        }
+    }
+
+    // stores and read-modify-writes always take the extra indexing cycle, so
+    // their dummy read happens even without a page cross
+    fn get_store_address(&mut self, mode: &AddressingMode) -> u16 {
+        let (addr, page_cross) = self.get_operand_address(mode);
+        let indexed = matches!(
+            mode,
+            AddressingMode::Absolute_X | AddressingMode::Absolute_Y | AddressingMode::Indirect_Y
+        );
+        if indexed && !page_cross {
+            self.mem_read(addr);
         }
+        addr
+    }
+
+    // read-modify-write instructions write the unmodified value back before
+    // the result, which mappers with write-sensitive registers can see
+    fn read_for_modify(&mut self, mode: &AddressingMode) -> (u16, u8) {
+        let addr = self.get_store_address(mode);
+        let data = self.mem_read(addr);
+        self.mem_write(addr, data);
+        (addr, data)
     }
 }
 
 
 #[cfg(test)]
-mod test {
+pub mod test {
     use super::*;
     use crate::cartridge::test;
     use crate::ppu::NesPPU;
+    use crate::ppu::watch::{PpuSpace, PpuWatch, PpuWrite};
+    use crate::traps::TrapAction;
+
+    // test programs end with BRK; stop there instead of jumping through $FFFE
+    pub fn stop_at_brk(cpu: &mut CPU) {
+        if cpu.bus.peek(cpu.program_counter) == 0x00 {
+            cpu.stop();
+        }
+    }
 
     #[test]
     fn test_0xa9_lda_immediate_load_data() {
         let bus = Bus::new(test::test_rom_containing(vec![0xa9, 0x05, 0x00]), |_ppu, _joypad, _joypad2| {});
         let mut cpu = CPU::new(bus);
 
-        cpu.run();
+        cpu.run_with_callback(stop_at_brk);
 
         assert_eq!(cpu.register_a, 5);
         assert!(cpu.register_p.bits() & 0b0000_0010 == 0b00);
@@ -1247,7 +1537,7 @@ mod test {
         let mut cpu = CPU::new(bus);
         cpu.register_a = 10;
 
-        cpu.run();
+        cpu.run_with_callback(stop_at_brk);
 
         assert_eq!(cpu.register_x, 10)
     }
@@ -1257,7 +1547,7 @@ mod test {
         let bus = Bus::new(test::test_rom_containing(vec![0xa9, 0xc0, 0xaa, 0xe8, 0x00]), |_ppu, _joypad, _joypad2| {});
         let mut cpu = CPU::new(bus);
 
-        cpu.run();
+        cpu.run_with_callback(stop_at_brk);
 
         assert_eq!(cpu.register_x, 0xc1)
     }
@@ -1268,7 +1558,7 @@ mod test {
         let mut cpu = CPU::new(bus);
         cpu.register_x = 0xff;
 
-        cpu.run();
+        cpu.run_with_callback(stop_at_brk);
 
         assert_eq!(cpu.register_x, 1)
     }
@@ -1279,8 +1569,517 @@ mod test {
         let mut cpu = CPU::new(bus);
         cpu.mem_write(0x10, 0x55);
 
-        cpu.run();
+        cpu.run_with_callback(stop_at_brk);
 
         assert_eq!(cpu.register_a, 0x55);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_soft_reset_and_power_cycle() {
+        let mut rom = test::test_rom_containing(vec![]);
+        rom.prg_rom[0x7ffc] = 0x34;
+        rom.prg_rom[0x7ffd] = 0x82;
+        let bus = Bus::new(rom, |_ppu, _joypad, _joypad2| {});
+        let mut cpu = CPU::new(bus);
+        cpu.bus.ram_pattern = [0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff];
+        cpu.register_a = 0x10;
+        cpu.mem_write(0x10, 0x55);
+        cpu.mem_write(0x14, 0x55);
+
+        cpu.soft_reset();
+
+        assert_eq!(cpu.program_counter, 0x8234);
+        assert_eq!(cpu.stack_pointer, STACK_RESET - 3);
+        assert_eq!(cpu.register_a, 0x10);
+        assert_eq!(cpu.mem_read(0x10), 0x55);
+
+        cpu.power_cycle();
+
+        assert_eq!(cpu.program_counter, 0x8234);
+        assert_eq!(cpu.stack_pointer, STACK_RESET);
+        assert_eq!(cpu.register_a, 0);
+        assert_eq!(cpu.mem_read(0x10), 0x00);
+        assert_eq!(cpu.mem_read(0x14), 0xff);
+    }
+
+    #[test]
+    fn test_rom_write_trap_breaks() {
+        // STA $8000; INX
+        let bus = Bus::new(test::test_rom_containing(vec![0x8d, 0x00, 0x80, 0xe8, 0x00]), |_ppu, _joypad, _joypad2| {});
+        let mut cpu = CPU::new(bus);
+
+        cpu.run_with_callback(stop_at_brk);
+
+        assert_eq!(cpu.program_counter, 0x8003);
+        assert_eq!(cpu.register_x, 0);
+    }
+
+    #[test]
+    fn test_ppu_watch_trap_names_the_write() {
+        // $3F10 = $16 through $2006/$2007, then INX
+        let program = vec![0xa9, 0x3f, 0x8d, 0x06, 0x20, 0xa9, 0x10, 0x8d, 0x06, 0x20, 0xa9, 0x16, 0x8d, 0x07, 0x20, 0xe8, 0x00];
+        let bus = Bus::new(test::test_rom_containing(program), |_ppu, _joypad, _joypad2| {});
+        let mut cpu = CPU::new(bus);
+        // the backdrop, which $3F10 mirrors
+        cpu.bus.add_ppu_watch(PpuWatch::parse("palette:00").unwrap());
+
+        let mut trap = None;
+        while trap.is_none() {
+            trap = cpu.step().trap;
+        }
+
+        assert_eq!(cpu.program_counter, 0x800f);
+        match trap {
+            Some(Trap::PpuWrite { write, register, pc, .. }) => {
+                assert_eq!(write, PpuWrite { space: PpuSpace::Palette, index: 0, old: 0, new: 0x16 });
+                assert_eq!((register, pc), (0x2007, 0x800c));
+            }
+            other => panic!("expected a PPU write trap, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_stack_wrap_trap_breaks() {
+        // PHA; PHA; INX
+        let bus = Bus::new(test::test_rom_containing(vec![0x48, 0x48, 0xe8, 0x00]), |_ppu, _joypad, _joypad2| {});
+        let mut cpu = CPU::new(bus);
+        cpu.bus.traps.on_stack_wrap = TrapAction::Break;
+        cpu.stack_pointer = 0x00;
+
+        cpu.run_with_callback(stop_at_brk);
+
+        assert_eq!(cpu.program_counter, 0x8001);
+        assert_eq!(cpu.stack_pointer, 0xff);
+    }
+
+    #[test]
+    fn test_unmapped_execution_trap_breaks() {
+        let bus = Bus::new(test::test_rom(), |_ppu, _joypad, _joypad2| {});
+        let mut cpu = CPU::new(bus);
+        cpu.bus.traps.on_unmapped_execution = TrapAction::Break;
+        cpu.mem_write(0x6000, 0xea); // NOP in PRG RAM
+        cpu.program_counter = 0x6000;
+
+        cpu.run();
+
+        assert_eq!(cpu.program_counter, 0x6001);
+    }
+
+    #[test]
+    fn test_brk_pushes_state_and_jumps_through_irq_vector() {
+        // BRK; padding; (return here) INX
+        let mut program = vec![0x00, 0xff, 0xe8, 0x00];
+        program.resize(0x8000, 0);
+        program[0x10] = 0x40; // handler: RTI
+        program[0x7ffe] = 0x10; // IRQ/BRK vector -> $8010
+        program[0x7fff] = 0x80;
+        let bus = Bus::new(test::test_rom_containing(program), |_ppu, _joypad, _joypad2| {});
+        let mut cpu = CPU::new(bus);
+        cpu.register_p.remove(CpuFlags::INTERRUPT_DISABLE);
+
+        let mut in_handler = None;
+        cpu.run_with_callback(|cpu| {
+            if cpu.program_counter == 0x8010 {
+                let pushed_status = cpu.mem_read(0x01fb);
+                let return_address = cpu.mem_read_u16(0x01fc);
+                in_handler = Some((pushed_status, return_address, cpu.register_p));
+            } else if cpu.program_counter != 0x8000 {
+                stop_at_brk(cpu);
+            }
+        });
+
+        let (pushed_status, return_address, flags) = in_handler.unwrap();
+        assert_eq!(pushed_status & 0b0011_0100, 0b0011_0000);
+        assert_eq!(return_address, 0x8002);
+        assert!(flags.contains(CpuFlags::INTERRUPT_DISABLE));
+        assert_eq!(cpu.program_counter, 0x8003);
+        assert_eq!(cpu.register_x, 1);
+    }
+
+    fn cycles_for(program: Vec<u8>) -> usize {
+        let bus = Bus::new(test::test_rom_containing(program), |_ppu, _joypad, _joypad2| {});
+        let mut cpu = CPU::new(bus);
+        cpu.run_with_callback(stop_at_brk);
+        cpu.bus.cycles()
+    }
+
+    #[test]
+    fn test_page_cross_and_branch_cycles() {
+        // LDX #1; LDA $80FF,X (crosses); LDA $8000,X; LAX $80FF,Y (Y=0, no cross)
+        assert_eq!(cycles_for(vec![0xa2, 0x01, 0xbd, 0xff, 0x80, 0xbd, 0x00, 0x80, 0xbf, 0xff, 0x80]), 2 + 5 + 4 + 4);
+        // LDY #1; LAX $80FF,Y crosses too
+        assert_eq!(cycles_for(vec![0xa0, 0x01, 0xbf, 0xff, 0x80]), 2 + 5);
+        // stores always take the extra cycle, it is part of their base count
+        assert_eq!(cycles_for(vec![0xa2, 0x01, 0x9d, 0xff, 0x02]), 2 + 5);
+
+        // LDX #1; BNE +0 taken; BEQ not taken
+        assert_eq!(cycles_for(vec![0xa2, 0x01, 0xd0, 0x00, 0xf0, 0x00]), 2 + 3 + 2);
+        // NOPs up to $80FC, then BNE from $80FD to $810F crosses into the next page
+        let mut program = vec![0xa2, 0x01];
+        program.resize(0xfd, 0xea);
+        program.extend([0xd0, 0x10]);
+        assert_eq!(cycles_for(program), 2 + (0xfd - 2) * 2 + 4);
+    }
+
+    #[test]
+    fn test_jumps_into_their_own_operand() {
+        let program = vec![
+            0x4c, 0x01, 0x80, // JMP $8001, whose low byte runs as ORA ($80,X)
+            0xa9, 0x00, // LDA #0
+            0xf0, 0xff, // BEQ -1, into its own offset byte: ISB $0300,X
+            0x00, 0x03, // ISB operand, then BRK
+        ];
+        let bus = Bus::new(test::test_rom_containing(program), |_ppu, _joypad, _joypad2| {});
+        let mut cpu = CPU::new(bus);
+        let mut visited = vec![];
+        cpu.run_with_callback(|cpu| {
+            visited.push(cpu.program_counter);
+            stop_at_brk(cpu);
+        });
+
+        assert_eq!(visited, vec![0x8000, 0x8001, 0x8003, 0x8005, 0x8006, 0x8009]);
+
+        // every addressing mode accounts for the rest of its instruction
+        for opcode in crate::opcodes::CPU_OPS_CODES {
+            if !matches!(opcode.mode, AddressingMode::NoneAddressing) {
+                assert_eq!(opcode.bytes as u16, 1 + opcode.mode.operand_bytes(), "{:02x}", opcode.code);
+            }
+        }
+    }
+
+    #[test]
+    fn test_apu_frame_counter_irq() {
+        let mut program = vec![
+            0x58, // CLI
+            0x4c, 0x01, 0x80, // loop: JMP loop
+            0xad, 0x15, 0x40, // irq: LDA $4015 (acknowledge)
+            0xe6, 0x20, // INC $20
+            0x40, // RTI
+        ];
+        program.resize(0x8000, 0);
+        program[0x7ffe] = 0x04; // IRQ vector -> $8004
+        program[0x7fff] = 0x80;
+
+        let bus = Bus::new(test::test_rom_containing(program), |_ppu, _joypad, _joypad2| {});
+        let mut cpu = CPU::new(bus);
+        let mut pushed_status = None;
+        cpu.run_with_callback(|cpu| {
+            if cpu.program_counter == 0x8004 {
+                assert!(cpu.register_p.contains(CpuFlags::INTERRUPT_DISABLE));
+                pushed_status = Some(cpu.mem_read(0x0100 + cpu.stack_pointer as u16 + 1));
+            }
+            if cpu.mem_read(0x20) == 2 {
+                cpu.stop();
+            }
+        });
+        // B clear, bit 5 set, I clear as it was before the IRQ
+        assert_eq!(pushed_status.unwrap() & 0b0011_0100, 0b0010_0000);
+        assert!(cpu.bus.cycles() > 2 * 29830);
+    }
+
+    fn irq_test_cpu(program: &[u8]) -> CPU<'static> {
+        let mut rom = program.to_vec();
+        rom.resize(0x8000, 0);
+        rom[0x10] = 0xea; // handler: NOP
+        rom[0x20] = 0xea;
+        rom[0x7ffa] = 0x20; // NMI vector -> $8020
+        rom[0x7ffb] = 0x80;
+        rom[0x7ffe] = 0x10; // IRQ/BRK vector -> $8010
+        rom[0x7fff] = 0x80;
+        let bus = Bus::new(test::test_rom_containing(rom), |_ppu, _joypad, _joypad2| {});
+        CPU::new(bus)
+    }
+
+    // the pushed status and X when a handler is entered
+    fn run_to_handler(cpu: &mut CPU, handler: u16) -> Option<(u8, u8)> {
+        let mut entered = None;
+        cpu.run_with_callback(|cpu| {
+            if cpu.program_counter == handler {
+                let pushed_status = cpu.mem_read(0x0100 + cpu.stack_pointer as u16 + 1);
+                entered = Some((pushed_status, cpu.register_x));
+                cpu.stop();
+            } else {
+                stop_at_brk(cpu);
+            }
+        });
+        entered
+    }
+
+    #[test]
+    fn test_step_reports_each_instruction() {
+        // CLI; INX; STA $8000 with an IRQ already pending
+        let mut cpu = irq_test_cpu(&[0x58, 0xe8, 0x8d, 0x00, 0x80]);
+        cpu.bus.traps.on_rom_write = TrapAction::Break;
+        while !cpu.bus.poll_irq_status() {
+            cpu.bus.tick(85);
+        }
+
+        let cli = cpu.step();
+        assert_eq!((cli.pc, cli.opcode, cli.cycles, cli.interrupt), (0x8000, 0x58, 2, None));
+        assert_eq!(cpu.step().pc, 0x8001);
+        // the IRQ entry and the handler's NOP come back as one step
+        let irq = cpu.step();
+        assert_eq!((irq.pc, irq.opcode, irq.cycles), (0x8010, 0xea, 7 + 2));
+        assert_eq!(irq.interrupt, Some(InterruptType::IRQ));
+
+        cpu.register_p.insert(CpuFlags::INTERRUPT_DISABLE);
+        cpu.program_counter = 0x8002;
+        let store = cpu.step();
+        assert_eq!(store.trap, Some(Trap::RomWrite { addr: 0x8000, data: 0 }));
+        assert_eq!(cpu.program_counter, 0x8005);
+    }
+
+    #[test]
+    fn test_step_cycle_crosses_instruction_boundaries() {
+        // LDA #$42; STA $10; INX
+        let mut cpu = irq_test_cpu(&[0xa9, 0x42, 0x85, 0x10, 0xe8]);
+        let start = cpu.bus.cycles();
+        let dot = |cpu: &CPU| cpu.bus.ppu().position().1;
+
+        let lda = cpu.step_cycle().unwrap();
+        assert_eq!((lda.pc, lda.opcode, lda.cycles), (0x8000, 0xa9, 2));
+        assert_eq!(cpu.register_a, 0x42);
+        assert_eq!(cpu.bus.cycles(), start + 1);
+        let before = dot(&cpu);
+        assert_eq!(cpu.step_cycle(), None);
+        assert_eq!((cpu.bus.cycles(), dot(&cpu)), (start + 2, before + 3));
+
+        let sta = cpu.step_cycle().unwrap();
+        assert_eq!((sta.pc, sta.opcode, sta.cycles), (0x8002, 0x85, 3));
+        assert_eq!(cpu.mem_read(0x10), 0x42);
+        assert_eq!(cpu.step_cycle(), None);
+        assert_eq!(cpu.bus.cycles(), start + 4);
+
+        // a whole step first runs out the STA's last cycle
+        let inx = cpu.step();
+        assert_eq!((inx.pc, inx.cycles), (0x8004, 2));
+        assert_eq!(cpu.bus.cycles(), start + 7);
+    }
+
+    #[test]
+    fn test_interrupt_disable_changes_are_seen_one_instruction_late() {
+        // CLI; INX; INX with an IRQ already pending: one INX runs first
+        let mut cpu = irq_test_cpu(&[0x58, 0xe8, 0xe8]);
+        while !cpu.bus.poll_irq_status() {
+            cpu.bus.tick(85);
+        }
+        let (pushed_status, x) = run_to_handler(&mut cpu, 0x8010).unwrap();
+        assert_eq!(x, 1);
+        assert_eq!(pushed_status & 0b0011_0100, 0b0010_0000);
+
+        // CLI; SEI; INX: the IRQ sneaks in after SEI and pushes I set
+        let mut cpu = irq_test_cpu(&[0x58, 0x78, 0xe8]);
+        while !cpu.bus.poll_irq_status() {
+            cpu.bus.tick(85);
+        }
+        let (pushed_status, x) = run_to_handler(&mut cpu, 0x8010).unwrap();
+        assert_eq!(x, 0);
+        assert_eq!(pushed_status & 0b0011_0100, 0b0010_0100);
+
+        // PLP clearing I is delayed the same way; RTI is not
+        let mut cpu = irq_test_cpu(&[0xa9, 0x00, 0x48, 0x28, 0xe8, 0xe8]);
+        while !cpu.bus.poll_irq_status() {
+            cpu.bus.tick(85);
+        }
+        let (_, x) = run_to_handler(&mut cpu, 0x8010).unwrap();
+        assert_eq!(x, 1);
+    }
+
+    #[test]
+    fn test_stopping_at_every_instruction_polls_interrupts_once() {
+        // CLI; INX; INX with an IRQ pending, stopped twice (and saved and
+        // reloaded) at every boundary: INX still runs before the IRQ
+        let mut cpu = irq_test_cpu(&[0x58, 0xe8, 0xe8]);
+        while !cpu.bus.poll_irq_status() {
+            cpu.bus.tick(85);
+        }
+        while cpu.program_counter != 0x8010 {
+            cpu.run_with_callback(|cpu| cpu.stop());
+            let state = crate::savestate::save(&cpu);
+            crate::savestate::load(&mut cpu, &state).unwrap();
+            cpu.run_with_callback(|cpu| cpu.stop());
+            if cpu.program_counter != 0x8010 {
+                cpu.step();
+            }
+        }
+        assert_eq!(cpu.register_x, 1);
+    }
+
+    #[test]
+    fn test_nmi_during_brk_hijacks_the_vector() {
+        let mut cpu = irq_test_cpu(&[0x00]);
+        cpu.mem_write(0x2000, 0x80);
+        // vblank starts at dot 241 * 341, on CPU cycle 27394: four cycles into BRK
+        while cpu.bus.cycles() < 27390 - 85 {
+            cpu.bus.tick(85);
+        }
+        cpu.bus.tick((27390 - cpu.bus.cycles()) as u8);
+
+        let mut pushed_status = None;
+        cpu.run_with_callback(|cpu| {
+            if cpu.program_counter != 0x8000 {
+                pushed_status = Some(cpu.mem_read(0x01fb));
+                cpu.stop();
+            }
+        });
+        assert_eq!(cpu.program_counter, 0x8020);
+        // B stays set in the pushed flags, so the handler can tell it was a BRK
+        assert_eq!(pushed_status.unwrap() & 0b0011_0000, 0b0011_0000);
+        assert_eq!(cpu.mem_read_u16(0x01fc), 0x8002);
+        assert_eq!(cpu.bus.cycles(), 27390 + 7);
+    }
+
+    #[test]
+    fn test_enabling_nmi_during_vblank_raises_it() {
+        let mut cpu = irq_test_cpu(&[0xa9, 0x80, 0x8d, 0x00, 0x20, 0xe8]);
+        while cpu.bus.cycles() < 27400 {
+            cpu.bus.tick(85);
+        }
+        // NMI was off at the start of vblank; turning it on with the vblank
+        // flag still set is a fresh edge. STA $2000 then INX: the NMI comes
+        // right after the write.
+        let (_, x) = run_to_handler(&mut cpu, 0x8020).unwrap();
+        assert_eq!(x, 0);
+
+        // once $2002 has cleared the vblank flag there is no edge
+        let mut cpu = irq_test_cpu(&[0xad, 0x02, 0x20, 0xa9, 0x80, 0x8d, 0x00, 0x20, 0xe8]);
+        while cpu.bus.cycles() < 27400 {
+            cpu.bus.tick(85);
+        }
+        assert_eq!(run_to_handler(&mut cpu, 0x8020), None);
+        assert_eq!(cpu.register_x, 1);
+    }
+
+    #[test]
+    fn test_dmc_dma_stalls_and_eats_controller_bits() {
+        let bus = Bus::new(test::test_rom(), |_ppu, _joypad, _joypad2| {});
+        let mut cpu = CPU::new(bus);
+        cpu.mem_write(0x4016, 1);
+        cpu.mem_write(0x4016, 0);
+        cpu.mem_write(0x4010, 0x0f); // fastest rate
+        cpu.mem_write(0x4013, 0x01); // 17 bytes
+        cpu.mem_write(0x4015, 0b1_0000);
+
+        // the fetch lands on the first controller read: 4 cycles and one bit
+        cpu.mem_read(0x4016);
+        assert_eq!(cpu.bus.cycles(), 4);
+        for _ in 0..6 {
+            cpu.mem_read(0x4016);
+        }
+        assert_eq!(cpu.bus.cycles(), 4);
+        // the 8th read is already past the last button
+        assert_eq!(cpu.mem_read(0x4016), 1);
+
+        // OAM DMA: 514 cycles from an odd cycle, and each DMC fetch inside it
+        // costs 2. The buffer empties on every 8th output clock, at cycles
+        // 428 + 7 * 54 = 806 and 806 + 8 * 54 = 1238.
+        while cpu.bus.cycles() < 700 {
+            cpu.bus.tick(85);
+        }
+        let start = cpu.bus.cycles();
+        assert_eq!(start, 769);
+        cpu.mem_write(0x4014, 0x02);
+        assert_eq!(cpu.bus.cycles() - start, 514 + 2 * 2);
+    }
+
+    fn run_from_ram(cpu: &mut CPU, program: &[u8]) {
+        for (i, byte) in program.iter().enumerate() {
+            cpu.mem_write(0x0300 + i as u16, *byte);
+        }
+        cpu.mem_write(0x0300 + program.len() as u16, 0x00);
+        cpu.program_counter = 0x0300;
+        cpu.run_with_callback(stop_at_brk);
+    }
+
+    #[test]
+    fn test_dummy_reads_and_rmw_double_writes() {
+        // INC $A000 on an MMC3: the mirroring register sees the old value, then the new one
+        let mut rom = test::test_rom();
+        rom.mapper = 4;
+        let bus = Bus::new(rom, |_ppu, _joypad, _joypad2| {});
+        let mut cpu = CPU::new(bus);
+        let writes = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+        let log = writes.clone();
+        cpu.bus.events.subscribe(move |event| {
+            if let EmuEvent::BankSwitch { addr, data } = event {
+                log.borrow_mut().push((*addr, *data));
+            }
+        });
+        run_from_ram(&mut cpu, &[0xee, 0x00, 0xa0]);
+        assert_eq!(*writes.borrow(), vec![(0xa000, 0x00), (0xa000, 0x01)]);
+
+        // STA $2000,X with X=7 reads $2007 first, moving the VRAM address on
+        let bus = Bus::new(test::test_rom(), |_ppu, _joypad, _joypad2| {});
+        let mut cpu = CPU::new(bus);
+        cpu.mem_write(0x2006, 0x20);
+        cpu.mem_write(0x2006, 0x00);
+        run_from_ram(&mut cpu, &[0xa2, 0x07, 0xa9, 0x55, 0x9d, 0x00, 0x20]);
+        cpu.mem_write(0x2006, 0x20);
+        cpu.mem_write(0x2006, 0x00);
+        cpu.mem_read(0x2007);
+        assert_eq!(cpu.mem_read(0x2007), 0x00);
+        assert_eq!(cpu.mem_read(0x2007), 0x55);
+    }
+
+    // runs a program that keeps the PPU busy and hashes the machine state at
+    // every frame boundary, optionally round-tripping it through a savestate
+    // into a freshly power-cycled machine each time
+    fn frame_state_hashes(frames: usize, reload_every_frame: bool) -> Vec<u64> {
+        use crate::savestate;
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::{Hash, Hasher};
+
+        let mut program = vec![
+            0xa9, 0x80, 0x8d, 0x00, 0x20, // LDA #$80; STA $2000 (NMI on)
+            0xa9, 0x1e, 0x8d, 0x01, 0x20, // LDA #$1e; STA $2001 (rendering on)
+            0xe8, 0x86, 0x10, // loop: INX; STX $10
+            0xa9, 0x20, 0x8d, 0x06, 0x20, 0x8e, 0x06, 0x20, // PPUADDR = $20xx
+            0x8a, 0x65, 0x11, 0x85, 0x11, // TXA; ADC $11; STA $11
+            0x8d, 0x07, 0x20, 0xad, 0x07, 0x20, // STA $2007; LDA $2007
+            0x4c, 0x0a, 0x80, // JMP loop
+            0xe6, 0x20, 0x40, // nmi: INC $20; RTI
+        ];
+        program.resize(0x8000, 0);
+        program[0x7ffa] = 0x23; // NMI vector -> $8023
+        program[0x7ffb] = 0x80;
+        program[0x7ffc] = 0x00; // reset vector -> $8000
+        program[0x7ffd] = 0x80;
+
+        let bus = Bus::new(test::test_rom_containing(program), |_ppu, _joypad, _joypad2| {});
+        let mut cpu = CPU::new(bus);
+        let mut hashes = vec![];
+        let mut last_frame = 0;
+        cpu.run_with_callback(|cpu| {
+            if cpu.bus.frame_count() == last_frame {
+                return;
+            }
+            last_frame = cpu.bus.frame_count();
+
+            let state = savestate::save(&*cpu);
+            let mut hasher = DefaultHasher::new();
+            state.hash(&mut hasher);
+            hashes.push(hasher.finish());
+
+            if reload_every_frame {
+                cpu.power_cycle();
+                savestate::load(cpu, &state).unwrap();
+            }
+            if last_frame == frames {
+                cpu.stop();
+            }
+        });
+        hashes
+    }
+
+    #[test]
+    fn test_savestate_round_trip_every_frame() {
+        let straight = frame_state_hashes(1000, false);
+        let reloaded = frame_state_hashes(1000, true);
+
+        assert_eq!(straight.len(), 1000);
+        assert_eq!(straight, reloaded);
+        // the program actually changes state from frame to frame
+        assert_ne!(straight[10], straight[11]);
+    }
+}
+
@@ -0,0 +1,737 @@
+// --debug-server 127.0.0.1:6502: the debugger over a WebSocket, for UIs that
+// live outside the emulator (a web page, an editor extension). Requests and
+// replies are JSON text messages:
+//
+// {"id": 1, "cmd": "registers"}
+//     -> {"id": 1, "ok": true, "a": 0, "x": 0, "y": 0, "p": 36, "sp": 253, "pc": 32768, "cycles": 7, "frame": 0}
+// {"cmd": "read_memory", "addr": 0, "len": 16}    -> {"ok": true, "addr": 0, "data": [...]}
+// {"cmd": "write_memory", "addr": 0, "data": [1, 2]}
+// {"cmd": "read_space", "space": "oam", "addr": 0, "len": 16}
+//     -> {"ok": true, "space": "oam", "addr": 0, "data": [...], "changed": [4, 5]}
+// {"cmd": "write_space", "space": "vram", "addr": 32, "data": [36, 36]}
+// {"cmd": "set_breakpoint", "addr": 32768}, {"cmd": "clear_breakpoint", "addr": 32768}
+// {"cmd": "breakpoints"}                          -> {"ok": true, "breakpoints": [32768]}
+// {"cmd": "pause"}, {"cmd": "continue"}, {"cmd": "step", "count": 1}
+// {"cmd": "runto_frame", "frame": 600}
+// {"cmd": "advance_until", "condition": "$0075 == 3", "max_frames": 3600}
+// {"cmd": "palette"}                              -> {"ok": true, "palette": [...32 entries]}
+// {"cmd": "pattern_table", "table": 0, "palette": 0}, {"cmd": "frame"}
+//     -> {"ok": true, "width": 128, "height": 128, "png": "<base64>"}
+// {"cmd": "cheats"}
+//     -> {"ok": true, "cheats": [{"code": "SXIOPO", "name": "...", "addr": 37337, "value": 173, "compare": null, "enabled": true}]}
+// {"cmd": "add_cheat", "code": "SXIOPO", "name": "infinite lives"} -> {"ok": true, "index": 0}
+// {"cmd": "enable_cheat", "index": 0, "enabled": false}, {"cmd": "remove_cheat", "index": 0}
+// {"cmd": "ram_search_reset"}                     -> {"ok": true, "count": 2048}
+// {"cmd": "ram_search", "op": "equal", "value": 3}
+//     -> {"ok": true, "count": 2, "results": [{"addr": 117, "value": 3}, ...]}
+// {"cmd": "freeze", "addr": 117, "value": 9}      -> {"ok": true, "index": 1}
+// {"cmd": "add_watchpoint", "spec": "write 0300-03ff"} -> {"ok": true, "index": 0}
+// {"cmd": "remove_watchpoint", "index": 0}
+// {"cmd": "watchpoints"}                          -> {"ok": true, "watchpoints": ["write 0300-03ff"]}
+// {"cmd": "mixer"}
+//     -> {"ok": true, "channels": [{"name": "pulse1", "volume": 100, "muted": false}, ...], "solo": null}
+// {"cmd": "set_mixer", "channel": "noise", "volume": 50, "muted": true}, {"cmd": "set_mixer", "solo": "dmc"}
+//
+// The id of a request, when it has one, comes back in the reply, and failures
+// are {"ok": false, "error": "..."}. Reads don't touch the hardware registers,
+// writes go through the bus like the CPU's own. When emulation stops every
+// client gets {"event": "paused", "reason": "...", "pc": 32768, "frame": 600},
+// and {"event": "resumed"} when it goes on again. The reasons are breakpoint,
+// step, pause, frame (runto_frame), condition and limit (advance_until),
+// and read_watchpoint, write_watchpoint, ppu_watchpoint and scanline.
+//
+// The spaces of read_space and write_space are those of hex_editor.rs: ram,
+// prg_ram, vram, oam and palette, with offsets from their start. addr
+// defaults to 0 and len to the rest of the space. "changed" lists the offsets
+// read that changed in the last RECENT_FRAMES frames. Edits are made in place
+// and count as changes too.
+//
+// The mixer's channels are those of mixer.rs, expansion audio included.
+// Volumes are percentages up to 200; set_mixer changes what it is given and
+// "solo": null plays every channel again.
+//
+// Watchpoints are the breakpoints of debugger.rs, shared with the command
+// line debugger: the spec is any of its forms, exec and scanline included.
+//
+// Cheat changes are written back to the game's cheats file, see cheats.rs.
+// RAM searches are in ram_search.rs: ram_search narrows the candidates down
+// and lists the first RAM_SEARCH_RESULTS of them, freeze adds a raw cheat.
+//
+// runto_frame and advance_until run whole frames and stop on the first
+// instruction of the frame they are after. The condition, in the syntax of
+// frame_condition.rs, is first checked when the next frame starts, and
+// max_frames (default 36000, ten minutes) gives up on it. Breakpoints still
+// stop either of them.
+
+use crate::cpu::{Mem, CPU};
+use crate::debugger::Breakpoint;
+use crate::frame_condition::FrameCondition;
+use crate::hex_editor::{HexEditor, MemorySpace};
+use crate::json::{self, Json};
+use crate::mixer::{Source, MAX_VOLUME};
+use crate::ram_search::{Comparison, RamSearch};
+use crate::render;
+use crate::render::frame::Frame;
+use crate::render::palette::SYSTEM_PALLETE;
+use crate::websocket::{self, Server};
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+const PAUSED_POLL: Duration = Duration::from_millis(10);
+const DEFAULT_MAX_FRAMES: u64 = 36000;
+const RAM_SEARCH_RESULTS: usize = 100;
+
+#[derive(Debug, Clone, PartialEq)]
+enum RunState {
+    Running,
+    // instructions left before pausing again
+    Stepping(usize),
+    RunToFrame(usize),
+    AdvanceUntil { condition: FrameCondition, last_frame: usize, limit: usize },
+    PauseRequested,
+    Paused,
+}
+
+// The protocol without the sockets: requests in, replies out.
+pub struct Debugger {
+    breakpoints: BTreeSet<u16>,
+    ram_search: Option<RamSearch>,
+    state: RunState,
+    // resuming from a breakpoint shouldn't stop on it again straight away
+    resumed_at: Option<u16>,
+    hex_editor: HexEditor,
+    // the frame hex_editor last looked at memory in
+    tracked_frame: Option<usize>,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Debugger::new()
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Debugger {
+            breakpoints: BTreeSet::new(),
+            ram_search: None,
+            state: RunState::Running,
+            resumed_at: None,
+            hex_editor: HexEditor::new(),
+            tracked_frame: None,
+        }
+    }
+
+    pub fn paused(&self) -> bool {
+        self.state == RunState::Paused
+    }
+
+    pub fn resume(&mut self, cpu: &CPU) {
+        self.state = RunState::Running;
+        self.resumed_at = Some(cpu.program_counter);
+    }
+
+    // Before each instruction: why emulation should stop here, if it should.
+    pub fn check(&mut self, cpu: &mut CPU) -> Option<&'static str> {
+        let pc = cpu.program_counter;
+        let hit = cpu.bus.breakpoints.check(pc).map(|hit| hit.reason());
+        let resumed_here = self.resumed_at.take() == Some(pc);
+        let at_breakpoint = !resumed_here && self.breakpoints.contains(&pc);
+        let frame = cpu.bus.frame_count();
+        if self.tracked_frame != Some(frame) {
+            self.hex_editor.update(&cpu.bus);
+            self.tracked_frame = Some(frame);
+        }
+        let reason = match &mut self.state {
+            RunState::Running => None,
+            RunState::Paused => return None,
+            RunState::PauseRequested => Some("pause"),
+            RunState::Stepping(0) => Some("step"),
+            RunState::Stepping(left) => {
+                *left -= 1;
+                None
+            }
+            RunState::RunToFrame(target) => (frame >= *target).then_some("frame"),
+            RunState::AdvanceUntil { condition, last_frame, limit } => {
+                if frame == *last_frame {
+                    None
+                } else if condition.matches(&cpu.bus) {
+                    Some("condition")
+                } else if frame >= *limit {
+                    Some("limit")
+                } else {
+                    *last_frame = frame;
+                    None
+                }
+            }
+        };
+        let reason = reason.or(at_breakpoint.then_some("breakpoint")).or(hit)?;
+        self.state = RunState::Paused;
+        Some(reason)
+    }
+
+    pub fn handle(&mut self, cpu: &mut CPU, request: &str) -> String {
+        let request = match Json::parse(request) {
+            Ok(request) => request,
+            Err(err) => return error_reply("", &err),
+        };
+        let id = match request.get("id").and_then(Json::as_u64) {
+            Some(id) => format!("\"id\": {}, ", id),
+            None => String::new(),
+        };
+        match self.command(cpu, &request) {
+            Ok(fields) if fields.is_empty() => format!("{{{}\"ok\": true}}", id),
+            Ok(fields) => format!("{{{}\"ok\": true, {}}}", id, fields),
+            Err(err) => error_reply(&id, &err),
+        }
+    }
+
+    // the reply's fields past "ok"
+    fn command(&mut self, cpu: &mut CPU, request: &Json) -> Result<String, String> {
+        let cmd = request.get("cmd").and_then(Json::as_str).ok_or("missing \"cmd\"")?;
+        match cmd {
+            "registers" => Ok(format!(
+                "\"a\": {}, \"x\": {}, \"y\": {}, \"p\": {}, \"sp\": {}, \"pc\": {}, \"cycles\": {}, \"frame\": {}",
+                cpu.register_a,
+                cpu.register_x,
+                cpu.register_y,
+                cpu.register_p.bits(),
+                cpu.stack_pointer,
+                cpu.program_counter,
+                cpu.bus.cycles(),
+                cpu.bus.frame_count()
+            )),
+            "read_memory" => {
+                let addr = number(request, "addr", 0xffff)? as u16;
+                let len = number(request, "len", 0x10000)?;
+                let data: Vec<String> =
+                    (0..len).map(|i| cpu.bus.peek(addr.wrapping_add(i as u16)).to_string()).collect();
+                Ok(format!("\"addr\": {}, \"data\": [{}]", addr, data.join(", ")))
+            }
+            "write_memory" => {
+                let addr = number(request, "addr", 0xffff)? as u16;
+                for (i, byte) in bytes(request)?.iter().enumerate() {
+                    cpu.mem_write(addr.wrapping_add(i as u16), *byte);
+                }
+                Ok(String::new())
+            }
+            "read_space" => {
+                let space = memory_space(request)?;
+                let memory = cpu.bus.memory(space);
+                let addr = match request.get("addr") {
+                    Some(_) => number(request, "addr", memory.len() as u64)? as usize,
+                    None => 0,
+                };
+                let len = match request.get("len") {
+                    Some(_) => number(request, "len", (memory.len() - addr) as u64)? as usize,
+                    None => memory.len() - addr,
+                };
+                self.hex_editor.update(&cpu.bus);
+                let data: Vec<String> = memory[addr..addr + len].iter().map(|byte| byte.to_string()).collect();
+                let changed: Vec<String> = self
+                    .hex_editor
+                    .recent_changes(space, cpu.bus.frame_count())
+                    .iter()
+                    .filter(|offset| (addr..addr + len).contains(offset))
+                    .map(|offset| offset.to_string())
+                    .collect();
+                Ok(format!(
+                    "\"space\": \"{}\", \"addr\": {}, \"data\": [{}], \"changed\": [{}]",
+                    space.name(),
+                    addr,
+                    data.join(", "),
+                    changed.join(", ")
+                ))
+            }
+            "write_space" => {
+                let space = memory_space(request)?;
+                let addr = number(request, "addr", 0xffff)? as usize;
+                // catch up first, so only the edit shows as new
+                self.hex_editor.update(&cpu.bus);
+                for (i, byte) in bytes(request)?.iter().enumerate() {
+                    cpu.bus.poke(space, addr + i, *byte)?;
+                }
+                Ok(String::new())
+            }
+            "set_breakpoint" => {
+                self.breakpoints.insert(number(request, "addr", 0xffff)? as u16);
+                Ok(String::new())
+            }
+            "clear_breakpoint" => {
+                self.breakpoints.remove(&(number(request, "addr", 0xffff)? as u16));
+                Ok(String::new())
+            }
+            "breakpoints" => {
+                let addrs: Vec<String> = self.breakpoints.iter().map(|addr| addr.to_string()).collect();
+                Ok(format!("\"breakpoints\": [{}]", addrs.join(", ")))
+            }
+            "pause" => {
+                if !self.paused() {
+                    self.state = RunState::PauseRequested;
+                }
+                Ok(String::new())
+            }
+            "continue" => {
+                self.resume(cpu);
+                Ok(String::new())
+            }
+            "step" => {
+                let count = match request.get("count") {
+                    Some(_) => number(request, "count", u32::MAX as u64)? as usize,
+                    None => 1,
+                };
+                self.resume(cpu);
+                self.state = RunState::Stepping(count);
+                Ok(String::new())
+            }
+            "runto_frame" => {
+                let target = number(request, "frame", u32::MAX as u64)? as usize;
+                if target <= cpu.bus.frame_count() {
+                    return Err(format!("frame {} has already started", target));
+                }
+                self.resume(cpu);
+                self.state = RunState::RunToFrame(target);
+                Ok(String::new())
+            }
+            "advance_until" => {
+                let condition = request.get("condition").and_then(Json::as_str).ok_or("missing \"condition\"")?;
+                let condition = FrameCondition::parse(condition)?;
+                let max_frames = match request.get("max_frames") {
+                    Some(_) => number(request, "max_frames", u32::MAX as u64)?,
+                    None => DEFAULT_MAX_FRAMES,
+                };
+                let frame = cpu.bus.frame_count();
+                self.resume(cpu);
+                self.state = RunState::AdvanceUntil { condition, last_frame: frame, limit: frame + max_frames as usize };
+                Ok(String::new())
+            }
+            "palette" => {
+                let entries: Vec<String> = cpu.bus.ppu().palette_table.iter().map(|entry| entry.to_string()).collect();
+                Ok(format!("\"palette\": [{}]", entries.join(", ")))
+            }
+            "pattern_table" => {
+                let table = number(request, "table", 1)? as u16;
+                let palette = match request.get("palette") {
+                    Some(_) => number(request, "palette", 7)? as usize,
+                    None => 0,
+                };
+                image_fields(128, 128, &pattern_table(cpu, table, palette))
+            }
+            "frame" => {
+                let mut frame = Frame::new();
+                render::render_peek(cpu.bus.ppu(), &mut frame);
+                image_fields(Frame::WIDTH as u32, Frame::HIGHT as u32, &frame.data)
+            }
+            "cheats" => {
+                let cheats: Vec<String> = cpu
+                    .bus
+                    .cheats
+                    .cheats
+                    .iter()
+                    .map(|cheat| {
+                        format!(
+                            "{{\"code\": \"{}\", \"name\": \"{}\", \"addr\": {}, \"value\": {}, \"compare\": {}, \"enabled\": {}}}",
+                            json::escape(&cheat.code),
+                            json::escape(&cheat.name),
+                            cheat.patch.address,
+                            cheat.patch.value,
+                            cheat.patch.compare.map_or("null".to_string(), |compare| compare.to_string()),
+                            cheat.enabled
+                        )
+                    })
+                    .collect();
+                Ok(format!("\"cheats\": [{}]", cheats.join(", ")))
+            }
+            "add_cheat" => {
+                let code = request.get("code").and_then(Json::as_str).ok_or("missing \"code\"")?;
+                let name = request.get("name").and_then(Json::as_str).unwrap_or(code);
+                let index = cpu.bus.cheats.add(code, name)?;
+                cpu.bus.cheats.save()?;
+                Ok(format!("\"index\": {}", index))
+            }
+            "enable_cheat" => {
+                let index = number(request, "index", u32::MAX as u64)? as usize;
+                let enabled = request.get("enabled").and_then(Json::as_bool).ok_or("\"enabled\" should be true or false")?;
+                cpu.bus.cheats.set_enabled(index, enabled)?;
+                cpu.bus.cheats.save()?;
+                Ok(String::new())
+            }
+            "remove_cheat" => {
+                cpu.bus.cheats.remove(number(request, "index", u32::MAX as u64)? as usize)?;
+                cpu.bus.cheats.save()?;
+                Ok(String::new())
+            }
+            "ram_search_reset" => {
+                self.ram_search = Some(RamSearch::new(cpu.bus.ram()));
+                Ok(format!("\"count\": {}", cpu.bus.ram().len()))
+            }
+            "ram_search" => {
+                let op = request.get("op").and_then(Json::as_str).ok_or("missing \"op\"")?;
+                let comparison = Comparison::parse(op, request.get("value").and_then(Json::as_i64))?;
+                let search = self.ram_search.get_or_insert_with(|| RamSearch::new(cpu.bus.ram()));
+                let count = search.search(cpu.bus.ram(), comparison);
+                let results: Vec<String> = search
+                    .candidates()
+                    .iter()
+                    .take(RAM_SEARCH_RESULTS)
+                    .map(|addr| format!("{{\"addr\": {}, \"value\": {}}}", addr, cpu.bus.ram()[*addr as usize]))
+                    .collect();
+                Ok(format!("\"count\": {}, \"results\": [{}]", count, results.join(", ")))
+            }
+            "freeze" => {
+                let addr = number(request, "addr", 0x7ff)?;
+                let value = number(request, "value", 0xff)?;
+                let code = format!("{:04X}:{:02X}", addr, value);
+                let index = cpu.bus.cheats.add(&code, &format!("freeze ${:04X}", addr))?;
+                cpu.bus.cheats.save()?;
+                Ok(format!("\"index\": {}", index))
+            }
+            "add_watchpoint" => {
+                let spec = request.get("spec").and_then(Json::as_str).ok_or("missing \"spec\"")?;
+                let index = cpu.bus.breakpoints.add(Breakpoint::parse(spec)?);
+                Ok(format!("\"index\": {}", index))
+            }
+            "remove_watchpoint" => {
+                cpu.bus.breakpoints.remove(number(request, "index", u64::MAX)? as usize)?;
+                Ok(String::new())
+            }
+            "watchpoints" => {
+                let specs: Vec<String> = cpu
+                    .bus
+                    .breakpoints
+                    .list()
+                    .iter()
+                    .map(|breakpoint| format!("\"{}\"", breakpoint))
+                    .collect();
+                Ok(format!("\"watchpoints\": [{}]", specs.join(", ")))
+            }
+            "mixer" => {
+                let mixer = &cpu.bus.apu.mixer;
+                let channels: Vec<String> = Source::ALL
+                    .iter()
+                    .map(|source| {
+                        format!(
+                            "{{\"name\": \"{}\", \"volume\": {}, \"muted\": {}}}",
+                            source.name(),
+                            mixer.volume(*source),
+                            mixer.muted(*source)
+                        )
+                    })
+                    .collect();
+                let solo = match mixer.solo() {
+                    Some(source) => format!("\"{}\"", source.name()),
+                    None => "null".to_string(),
+                };
+                Ok(format!("\"channels\": [{}], \"solo\": {}", channels.join(", "), solo))
+            }
+            "set_mixer" => {
+                let mixer = &mut cpu.bus.apu.mixer;
+                match request.get("solo") {
+                    Some(Json::Null) => mixer.set_solo(None),
+                    Some(solo) => mixer.set_solo(Some(Source::parse(solo.as_str().ok_or("\"solo\" should be a channel name or null")?)?)),
+                    None => {}
+                }
+                if request.get("volume").is_some() || request.get("muted").is_some() {
+                    let source = Source::parse(request.get("channel").and_then(Json::as_str).ok_or("missing \"channel\"")?)?;
+                    if request.get("volume").is_some() {
+                        mixer.set_volume(source, number(request, "volume", MAX_VOLUME as u64)? as u8)?;
+                    }
+                    if let Some(muted) = request.get("muted") {
+                        mixer.set_muted(source, muted.as_bool().ok_or("\"muted\" should be true or false")?);
+                    }
+                }
+                Ok(String::new())
+            }
+            cmd => Err(format!("unknown command '{}'", cmd)),
+        }
+    }
+}
+
+// a whole number field no bigger than `max`
+fn number(request: &Json, name: &str, max: u64) -> Result<u64, String> {
+    request
+        .get(name)
+        .and_then(Json::as_u64)
+        .filter(|value| *value <= max)
+        .ok_or(format!("\"{}\" should be a number up to {}", name, max))
+}
+
+fn bytes(request: &Json) -> Result<Vec<u8>, String> {
+    let data = request.get("data").and_then(Json::as_array).ok_or("\"data\" should be an array of bytes")?;
+    data.iter()
+        .map(|byte| byte.as_u64().filter(|byte| *byte <= 0xff).map(|byte| byte as u8))
+        .collect::<Option<Vec<u8>>>()
+        .ok_or("\"data\" should be an array of bytes".to_string())
+}
+
+fn memory_space(request: &Json) -> Result<MemorySpace, String> {
+    MemorySpace::parse(request.get("space").and_then(Json::as_str).ok_or("missing \"space\"")?)
+}
+
+fn error_reply(id: &str, err: &str) -> String {
+    format!("{{{}\"ok\": false, \"error\": \"{}\"}}", id, json::escape(err))
+}
+
+// The 256 tiles of a pattern table as 16 rows of 16, RGB, coloured with one of
+// the eight palettes (0-3 background, 4-7 sprites).
+fn pattern_table(cpu: &CPU, table: u16, palette: usize) -> Vec<u8> {
+    let ppu = cpu.bus.ppu();
+    let colors: Vec<(u8, u8, u8)> = (0..4)
+        .map(|i| {
+            let entry = if i == 0 { 0 } else { palette * 4 + i };
+            SYSTEM_PALLETE[(ppu.palette_table[entry] & 0x3f) as usize]
+        })
+        .collect();
+    let mut image = vec![0; 128 * 128 * 3];
+    for tile in 0..256u16 {
+        let (tile_x, tile_y) = ((tile % 16) as usize * 8, (tile / 16) as usize * 8);
+        for row in 0..8u16 {
+            let addr = table * 0x1000 + tile * 16 + row;
+            let (low, high) = (ppu.peek_chr(addr), ppu.peek_chr(addr + 8));
+            for column in 0..8 {
+                let bit = 7 - column;
+                let value = ((high >> bit) & 1) << 1 | ((low >> bit) & 1);
+                let (r, g, b) = colors[value as usize];
+                let offset = ((tile_y + row as usize) * 128 + tile_x + column) * 3;
+                image[offset..offset + 3].copy_from_slice(&[r, g, b]);
+            }
+        }
+    }
+    image
+}
+
+fn image_fields(width: u32, height: u32, rgb: &[u8]) -> Result<String, String> {
+    let mut png = vec![];
+    {
+        let mut encoder = png::Encoder::new(&mut png, width, height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().map_err(|err| format!("png: {}", err))?;
+        writer.write_image_data(rgb).map_err(|err| format!("png: {}", err))?;
+    }
+    Ok(format!("\"width\": {}, \"height\": {}, \"png\": \"{}\"", width, height, websocket::base64(&png)))
+}
+
+pub struct DebugServer {
+    server: Server,
+    debugger: Debugger,
+}
+
+impl DebugServer {
+    pub fn bind(addr: &str) -> Result<DebugServer, String> {
+        let server = Server::bind(addr)?;
+        println!("debug server listening on ws://{}", addr);
+        Ok(DebugServer { server, debugger: Debugger::new() })
+    }
+
+    // Answers whatever came in while running; once a frame is often enough.
+    pub fn poll(&mut self, cpu: &mut CPU) {
+        for (client, request) in self.server.poll() {
+            let reply = self.debugger.handle(cpu, &request);
+            self.server.send(client, &reply);
+        }
+    }
+
+    // before each instruction; tells the clients when emulation stops here
+    pub fn should_break(&mut self, cpu: &mut CPU) -> bool {
+        match self.debugger.check(cpu) {
+            Some(reason) => {
+                self.server.broadcast(&format!(
+                    "{{\"event\": \"paused\", \"reason\": \"{}\", \"pc\": {}, \"frame\": {}}}",
+                    reason,
+                    cpu.program_counter,
+                    cpu.bus.frame_count()
+                ));
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Serves requests until a client resumes emulation, or the last one
+    // disconnects, which resumes it too.
+    pub fn serve_paused(&mut self, cpu: &mut CPU) {
+        while self.debugger.paused() {
+            self.poll(cpu);
+            if !self.server.has_clients() {
+                self.debugger.resume(cpu);
+            }
+            std::thread::sleep(PAUSED_POLL);
+        }
+        self.server.broadcast("{\"event\": \"resumed\"}");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::builder::RomBuilder;
+
+    // steps until the debugger says to stop
+    fn run(cpu: &mut CPU, debugger: &mut Debugger) -> &'static str {
+        loop {
+            if let Some(reason) = debugger.check(cpu) {
+                return reason;
+            }
+            cpu.step();
+        }
+    }
+
+    #[test]
+    fn test_requests_and_breakpoints() {
+        // LDX #$00; INX; JMP $8002
+        let rom = RomBuilder::new().program(0x8000, &[0xa2, 0x00, 0xe8, 0x4c, 0x02, 0x80]).rom();
+        let mut cpu = CPU::new(Bus::new(rom, |_ppu, _joypad1, _joypad2| {}));
+        cpu.reset();
+        let mut debugger = Debugger::new();
+
+        let reply = debugger.handle(&mut cpu, r#"{"id": 7, "cmd": "registers"}"#);
+        assert!(reply.starts_with("{\"id\": 7, \"ok\": true, \"a\": 0,"), "{}", reply);
+        assert!(reply.contains("\"pc\": 32768"), "{}", reply);
+        assert_eq!(debugger.handle(&mut cpu, r#"{"cmd": "write_memory", "addr": 16, "data": [1, 2]}"#), "{\"ok\": true}");
+        assert_eq!(
+            debugger.handle(&mut cpu, r#"{"cmd": "read_memory", "addr": 16, "len": 3}"#),
+            "{\"ok\": true, \"addr\": 16, \"data\": [1, 2, 0]}"
+        );
+        assert_eq!(
+            debugger.handle(&mut cpu, r#"{"id": 1, "cmd": "read_memory", "addr": 70000}"#),
+            "{\"id\": 1, \"ok\": false, \"error\": \"\\\"addr\\\" should be a number up to 65535\"}"
+        );
+        assert_eq!(
+            debugger.handle(&mut cpu, r#"{"cmd": "write_space", "space": "oam", "addr": 4, "data": [9, 8]}"#),
+            "{\"ok\": true}"
+        );
+        assert_eq!(
+            debugger.handle(&mut cpu, r#"{"cmd": "read_space", "space": "oam", "addr": 3, "len": 4}"#),
+            "{\"ok\": true, \"space\": \"oam\", \"addr\": 3, \"data\": [0, 9, 8, 0], \"changed\": [4, 5]}"
+        );
+        let reply = debugger.handle(&mut cpu, r#"{"cmd": "read_space", "space": "palette"}"#);
+        assert!(reply.contains("\"data\": [0, 0,") && reply.ends_with("\"changed\": []}"), "{}", reply);
+        assert!(debugger.handle(&mut cpu, r#"{"cmd": "write_space", "space": "oam", "addr": 255, "data": [1, 2]}"#)
+            .contains("outside oam"));
+        assert!(debugger.handle(&mut cpu, r#"{"cmd": "read_space", "space": "chr"}"#).contains("unknown memory space"));
+        assert_eq!(
+            debugger.handle(&mut cpu, r#"{"cmd": "set_mixer", "channel": "noise", "volume": 50, "muted": true, "solo": "dmc"}"#),
+            "{\"ok\": true}"
+        );
+        let reply = debugger.handle(&mut cpu, r#"{"cmd": "mixer"}"#);
+        assert!(reply.contains("{\"name\": \"noise\", \"volume\": 50, \"muted\": true}"), "{}", reply);
+        assert!(reply.ends_with("\"solo\": \"dmc\"}"), "{}", reply);
+        debugger.handle(&mut cpu, r#"{"cmd": "set_mixer", "solo": null}"#);
+        assert_eq!(cpu.bus.apu.mixer.solo(), None);
+        assert!(debugger.handle(&mut cpu, r#"{"cmd": "set_mixer", "channel": "noise", "volume": 300}"#).contains("up to 200"));
+        assert!(debugger.handle(&mut cpu, "{\"cmd\": \"warp\"}").contains("unknown command 'warp'"));
+        assert!(debugger.handle(&mut cpu, "not json").starts_with("{\"ok\": false"));
+        let reply = debugger.handle(&mut cpu, r#"{"cmd": "pattern_table", "table": 0}"#);
+        assert!(reply.contains("\"width\": 128, \"height\": 128, \"png\": \"iVBORw0KGgo"), "{}", reply);
+
+        // breaks on INX, then steps over it and runs on to the next one
+        debugger.handle(&mut cpu, r#"{"cmd": "set_breakpoint", "addr": 32770}"#);
+        assert_eq!(debugger.handle(&mut cpu, r#"{"cmd": "breakpoints"}"#), "{\"ok\": true, \"breakpoints\": [32770]}");
+        assert_eq!(run(&mut cpu, &mut debugger), "breakpoint");
+        assert_eq!((cpu.program_counter, cpu.register_x), (0x8002, 0));
+        debugger.handle(&mut cpu, r#"{"cmd": "step"}"#);
+        assert_eq!(run(&mut cpu, &mut debugger), "step");
+        assert_eq!((cpu.program_counter, cpu.register_x), (0x8003, 1));
+        debugger.handle(&mut cpu, r#"{"cmd": "continue"}"#);
+        assert_eq!(run(&mut cpu, &mut debugger), "breakpoint");
+        assert_eq!(cpu.register_x, 1);
+        debugger.handle(&mut cpu, r#"{"cmd": "step", "count": 3}"#);
+        assert_eq!(run(&mut cpu, &mut debugger), "breakpoint");
+        assert_eq!(cpu.register_x, 2);
+
+        debugger.handle(&mut cpu, r#"{"cmd": "clear_breakpoint", "addr": 32770}"#);
+        debugger.handle(&mut cpu, r#"{"cmd": "continue"}"#);
+        cpu.step();
+        debugger.handle(&mut cpu, r#"{"cmd": "pause"}"#);
+        assert_eq!(debugger.check(&mut cpu), Some("pause"));
+        assert!(debugger.paused());
+    }
+
+    #[test]
+    fn test_frame_commands() {
+        // NMI on and spin, the handler counts frames in $00
+        let rom = RomBuilder::new()
+            .program(0x8000, &[0xa9, 0x80, 0x8d, 0x00, 0x20, 0x4c, 0x05, 0x80])
+            .program(0x8008, &[0xe6, 0x00, 0x40])
+            .nmi_vector(0x8008)
+            .rom();
+        let mut cpu = CPU::new(Bus::new(rom, |_ppu, _joypad1, _joypad2| {}));
+        cpu.reset();
+        let mut debugger = Debugger::new();
+
+        assert_eq!(debugger.handle(&mut cpu, r#"{"cmd": "runto_frame", "frame": 3}"#), "{\"ok\": true}");
+        assert_eq!(run(&mut cpu, &mut debugger), "frame");
+        assert_eq!(cpu.bus.frame_count(), 3);
+        assert!(debugger.handle(&mut cpu, r#"{"cmd": "runto_frame", "frame": 3}"#).contains("already started"));
+
+        debugger.handle(&mut cpu, r#"{"cmd": "advance_until", "condition": "$00 >= 5"}"#);
+        assert_eq!(run(&mut cpu, &mut debugger), "condition");
+        assert_eq!((cpu.bus.frame_count(), cpu.bus.peek(0x00)), (5, 5));
+
+        debugger.handle(&mut cpu, r#"{"cmd": "advance_until", "condition": "$00 == 0", "max_frames": 2}"#);
+        assert_eq!(run(&mut cpu, &mut debugger), "limit");
+        assert_eq!(cpu.bus.frame_count(), 7);
+        assert!(debugger.handle(&mut cpu, r#"{"cmd": "advance_until", "condition": "lives"}"#).contains("\"ok\": false"));
+    }
+
+    #[test]
+    fn test_cheat_commands() {
+        // JMP $8000
+        let rom = RomBuilder::new().program(0x8000, &[0x4c, 0x00, 0x80]).rom();
+        let mut cpu = CPU::new(Bus::new(rom, |_ppu, _joypad1, _joypad2| {}));
+        cpu.reset();
+        let mut debugger = Debugger::new();
+
+        // GOSSIP patches $D1DD, ZEXPYGLA $94A7 while it holds $03
+        assert_eq!(debugger.handle(&mut cpu, r#"{"cmd": "add_cheat", "code": "GOSSIP"}"#), "{\"ok\": true, \"index\": 0}");
+        assert_eq!(cpu.mem_read(0xd1dd), 0x14);
+        assert_eq!(
+            debugger.handle(&mut cpu, r#"{"cmd": "cheats"}"#),
+            "{\"ok\": true, \"cheats\": [{\"code\": \"GOSSIP\", \"name\": \"GOSSIP\", \"addr\": 53725, \"value\": 20, \"compare\": null, \"enabled\": true}]}"
+        );
+        assert_eq!(debugger.handle(&mut cpu, r#"{"cmd": "enable_cheat", "index": 0, "enabled": false}"#), "{\"ok\": true}");
+        assert_eq!(cpu.mem_read(0xd1dd), 0x00);
+        assert!(debugger.handle(&mut cpu, r#"{"cmd": "add_cheat", "code": "HELLO"}"#).contains("\"ok\": false"));
+        assert_eq!(debugger.handle(&mut cpu, r#"{"cmd": "remove_cheat", "index": 0}"#), "{\"ok\": true}");
+        assert!(debugger.handle(&mut cpu, r#"{"cmd": "remove_cheat", "index": 0}"#).contains("no cheat #0"));
+
+        cpu.mem_write(0x0075, 3);
+        cpu.mem_write(0x0010, 3);
+        assert_eq!(debugger.handle(&mut cpu, r#"{"cmd": "ram_search_reset"}"#), "{\"ok\": true, \"count\": 2048}");
+        assert_eq!(
+            debugger.handle(&mut cpu, r#"{"cmd": "ram_search", "op": "equal", "value": 3}"#),
+            "{\"ok\": true, \"count\": 2, \"results\": [{\"addr\": 16, \"value\": 3}, {\"addr\": 117, \"value\": 3}]}"
+        );
+        cpu.mem_write(0x0075, 2);
+        assert_eq!(
+            debugger.handle(&mut cpu, r#"{"cmd": "ram_search", "op": "changed_by", "value": -1}"#),
+            "{\"ok\": true, \"count\": 1, \"results\": [{\"addr\": 117, \"value\": 2}]}"
+        );
+        assert_eq!(debugger.handle(&mut cpu, r#"{"cmd": "freeze", "addr": 117, "value": 9}"#), "{\"ok\": true, \"index\": 0}");
+        cpu.mem_write(0x0075, 1);
+        assert_eq!(cpu.mem_read(0x0075), 9);
+        assert!(debugger.handle(&mut cpu, r#"{"cmd": "ram_search", "op": "equal"}"#).contains("needs a value"));
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        // loop: INC $75; JMP loop
+        let rom = RomBuilder::new().program(0x8000, &[0xe6, 0x75, 0x4c, 0x00, 0x80]).rom();
+        let mut cpu = CPU::new(Bus::new(rom, |_ppu, _joypad1, _joypad2| {}));
+        cpu.reset();
+        let mut debugger = Debugger::new();
+
+        assert_eq!(
+            debugger.handle(&mut cpu, r#"{"cmd": "add_watchpoint", "spec": "write 0075"}"#),
+            "{\"ok\": true, \"index\": 0}"
+        );
+        assert_eq!(run(&mut cpu, &mut debugger), "write_watchpoint");
+        assert_eq!(cpu.program_counter, 0x8002);
+        assert_eq!(debugger.handle(&mut cpu, r#"{"cmd": "watchpoints"}"#), "{\"ok\": true, \"watchpoints\": [\"write 0075\"]}");
+        assert!(debugger.handle(&mut cpu, r#"{"cmd": "add_watchpoint", "spec": "poke 0075"}"#).contains("\"ok\": false"));
+        assert_eq!(debugger.handle(&mut cpu, r#"{"cmd": "remove_watchpoint", "index": 0}"#), "{\"ok\": true}");
+        assert!(debugger.handle(&mut cpu, r#"{"cmd": "remove_watchpoint", "index": 0}"#).contains("no breakpoint #0"));
+    }
+}
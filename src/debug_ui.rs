@@ -0,0 +1,696 @@
+// --debug-ui, in builds with `--features debug-ui`: the debugger in a second
+// window, drawn with egui. It shows the registers and flags, the disassembly
+// around the program counter (the instructions that ran before it, greyed, and
+// the ones after it), the top of the stack and the breakpoints, with buttons
+// to continue, pause, step, step over, step out and run to the cursor. Clicking
+// a disassembly line puts the cursor there.
+//
+// The Memory window is a hex editor over RAM, PRG RAM, VRAM, OAM and the
+// palette (hex_editor.rs). Bytes that changed lately are highlighted, brighter
+// the newer the change; click one, type a value and press enter to change it.
+//
+// The APU window reads out each channel's duty, volume, period and length
+// with an oscilloscope of its output, and mutes (M), solos (S) or turns it up
+// or down.
+//
+// egui hands over triangles; they are drawn with SDL_RenderGeometry, so the
+// window needs SDL 2.0.18 or newer. While the debugger holds emulation it
+// pumps SDL events itself, the main window included.
+
+use egui::epaint::{ClippedPrimitive, ImageDelta, Primitive};
+use egui::{Color32, ImageData, RichText, TextureId};
+use nes_book_emu::apu::{Channel, ChannelState};
+use nes_book_emu::cpu::CPU;
+use nes_book_emu::debugger::{Breakpoint, Resume, Stepper};
+use nes_book_emu::hex_editor::{HexEditor, MemorySpace, RECENT_FRAMES};
+use nes_book_emu::mixer::MAX_VOLUME;
+use nes_book_emu::trace::disassemble_at;
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::Keycode;
+use sdl2::mouse::MouseButton;
+use sdl2::pixels::Color;
+use sdl2::render::Canvas;
+use sdl2::sys;
+use sdl2::video::Window;
+use sdl2::{EventPump, EventSubsystem, Sdl};
+use std::collections::HashMap;
+use std::time::Instant;
+
+const WIDTH: u32 = 560;
+const HEIGHT: u32 = 620;
+// disassembly lines from the program counter on
+const LINES_AHEAD: usize = 14;
+const STACK_ENTRIES: usize = 16;
+const PAUSED_POLL_MS: u32 = 16;
+const MEMORY_COLUMNS: usize = 16;
+const SCOPE_HEIGHT: f32 = 40.0;
+
+enum UiAction {
+    Resume(Resume),
+    Pause,
+    AddBreakpoint,
+    RemoveBreakpoint(usize),
+    // writes the typed value to the selected byte
+    Poke,
+    ToggleMute(Channel),
+    ToggleSolo(Channel),
+    SetVolume(Channel, u8),
+}
+
+struct Line {
+    addr: u16,
+    label: Option<String>,
+    text: String,
+    // ran before the stop
+    past: bool,
+    breakpoint: bool,
+}
+
+struct ChannelView {
+    channel: Channel,
+    state: ChannelState,
+    frequency: Option<f64>,
+    muted: bool,
+    soloed: bool,
+    audible: bool,
+    // percent, see mixer.rs
+    volume: u8,
+    // the oscilloscope, oldest output first
+    scope: Vec<u8>,
+}
+
+// what the window shows, read from the machine before egui runs
+struct View {
+    // why emulation stopped, none while it runs
+    paused: Option<String>,
+    registers: String,
+    flags: u8,
+    position: String,
+    lines: Vec<Line>,
+    pc: u16,
+    stack: Vec<(u16, u8)>,
+    breakpoints: Vec<String>,
+    memory: Vec<u8>,
+    // frames since each byte changed, for the ones that did lately
+    memory_ages: Vec<Option<usize>>,
+    channels: Vec<ChannelView>,
+}
+
+// an SDL texture egui draws from
+struct Texture {
+    raw: *mut sys::SDL_Texture,
+}
+
+impl Drop for Texture {
+    fn drop(&mut self) {
+        unsafe { sys::SDL_DestroyTexture(self.raw) };
+    }
+}
+
+pub struct DebugUi {
+    canvas: Canvas<Window>,
+    events: EventSubsystem,
+    ctx: egui::Context,
+    input: Vec<egui::Event>,
+    textures: HashMap<TextureId, Texture>,
+    started: Instant,
+    stepper: Stepper,
+    // closing the window hides it, emulation goes on without the debugger
+    visible: bool,
+    paused: Option<String>,
+    cursor: Option<u16>,
+    // the run to cursor and new breakpoint fields
+    cursor_text: String,
+    breakpoint_spec: String,
+    error: Option<String>,
+    hex_editor: HexEditor,
+    memory_space: MemorySpace,
+    // the selected byte and the value field
+    memory_cursor: Option<usize>,
+    memory_value: String,
+}
+
+impl DebugUi {
+    // `paused` stops before the first instruction
+    pub fn new(sdl: &Sdl, paused: bool) -> Result<DebugUi, String> {
+        let video = sdl.video()?;
+        let window = video
+            .window("debugger", WIDTH, HEIGHT)
+            .resizable()
+            .build()
+            .map_err(|err| format!("debugger window: {}", err))?;
+        let canvas = window.into_canvas().build().map_err(|err| format!("debugger window: {}", err))?;
+        Ok(DebugUi {
+            canvas,
+            events: sdl.event()?,
+            ctx: egui::Context::default(),
+            input: vec![],
+            textures: HashMap::new(),
+            started: Instant::now(),
+            stepper: Stepper::new(paused),
+            visible: true,
+            paused: None,
+            cursor: None,
+            cursor_text: String::new(),
+            breakpoint_spec: String::new(),
+            error: None,
+            hex_editor: HexEditor::new(),
+            memory_space: MemorySpace::Ram,
+            memory_cursor: None,
+            memory_value: String::new(),
+        })
+    }
+
+    pub fn owns(&self, event: &Event) -> bool {
+        event.get_window_id() == Some(self.canvas.window().id())
+    }
+
+    pub fn handle_event(&mut self, event: &Event) {
+        let modifiers = egui::Modifiers::default();
+        let input = match event {
+            Event::MouseMotion { x, y, .. } => egui::Event::PointerMoved(egui::pos2(*x as f32, *y as f32)),
+            Event::MouseButtonDown { mouse_btn, x, y, .. } | Event::MouseButtonUp { mouse_btn, x, y, .. } => {
+                let button = match mouse_btn {
+                    MouseButton::Left => egui::PointerButton::Primary,
+                    MouseButton::Right => egui::PointerButton::Secondary,
+                    MouseButton::Middle => egui::PointerButton::Middle,
+                    _ => return,
+                };
+                let pressed = matches!(event, Event::MouseButtonDown { .. });
+                egui::Event::PointerButton { pos: egui::pos2(*x as f32, *y as f32), button, pressed, modifiers }
+            }
+            Event::MouseWheel { x, y, .. } => egui::Event::MouseWheel {
+                unit: egui::MouseWheelUnit::Line,
+                delta: egui::vec2(*x as f32, *y as f32),
+                modifiers,
+            },
+            Event::TextInput { text, .. } => egui::Event::Text(text.clone()),
+            Event::KeyDown { keycode: Some(keycode), repeat, .. } | Event::KeyUp { keycode: Some(keycode), repeat, .. } => {
+                let key = match *keycode {
+                    Keycode::Backspace => egui::Key::Backspace,
+                    Keycode::Delete => egui::Key::Delete,
+                    Keycode::Return | Keycode::KpEnter => egui::Key::Enter,
+                    Keycode::Left => egui::Key::ArrowLeft,
+                    Keycode::Right => egui::Key::ArrowRight,
+                    Keycode::Home => egui::Key::Home,
+                    Keycode::End => egui::Key::End,
+                    Keycode::Tab => egui::Key::Tab,
+                    _ => return,
+                };
+                let pressed = matches!(event, Event::KeyDown { .. });
+                egui::Event::Key { key, physical_key: None, pressed, repeat: *repeat, modifiers }
+            }
+            Event::Window { win_event: WindowEvent::Leave, .. } => egui::Event::PointerGone,
+            Event::Window { win_event: WindowEvent::Close, .. } => {
+                self.canvas.window_mut().hide();
+                self.visible = false;
+                return;
+            }
+            _ => return,
+        };
+        self.input.push(input);
+    }
+
+    // before each instruction: why emulation should stop here, if it should
+    pub fn check(&mut self, cpu: &mut CPU) -> Option<String> {
+        if !self.visible {
+            return None;
+        }
+        self.stepper.check(cpu)
+    }
+
+    // Redraws while emulation runs, once a frame is enough.
+    pub fn update(&mut self, cpu: &mut CPU) {
+        if !self.visible {
+            return;
+        }
+        cpu.bus.apu.set_scope(true);
+        for action in self.draw(cpu) {
+            self.apply(cpu, action);
+        }
+    }
+
+    // Holds emulation until the window resumes it. Quitting resumes it too,
+    // with the quit event put back for the frontend.
+    pub fn serve_paused(&mut self, cpu: &mut CPU, reason: &str, event_pump: &mut EventPump) {
+        self.paused = Some(reason.to_string());
+        cpu.bus.apu.set_scope(true);
+        while self.paused.is_some() {
+            let first = event_pump.wait_event_timeout(PAUSED_POLL_MS);
+            let events: Vec<Event> = first.into_iter().chain(event_pump.poll_iter()).collect();
+            for event in events {
+                match event {
+                    Event::Quit { .. } => {
+                        let _ = self.events.push_event(event);
+                        self.apply(cpu, UiAction::Resume(Resume::Continue));
+                    }
+                    event if self.owns(&event) => self.handle_event(&event),
+                    _ => {}
+                }
+            }
+            if !self.visible {
+                self.apply(cpu, UiAction::Resume(Resume::Continue));
+            }
+            for action in self.draw(cpu) {
+                self.apply(cpu, action);
+            }
+        }
+    }
+
+    fn apply(&mut self, cpu: &mut CPU, action: UiAction) {
+        self.error = None;
+        match action {
+            UiAction::Resume(resume) => {
+                self.stepper.resume(cpu, resume);
+                self.paused = None;
+            }
+            // stops at the next instruction
+            UiAction::Pause => self.stepper.resume(cpu, Resume::Step(1)),
+            UiAction::AddBreakpoint => match Breakpoint::parse(&self.breakpoint_spec) {
+                Ok(breakpoint) => {
+                    cpu.bus.breakpoints.add(breakpoint);
+                    self.breakpoint_spec.clear();
+                }
+                Err(err) => self.error = Some(err),
+            },
+            UiAction::RemoveBreakpoint(index) => {
+                if let Err(err) = cpu.bus.breakpoints.remove(index) {
+                    self.error = Some(err);
+                }
+            }
+            UiAction::Poke => {
+                let value = u8::from_str_radix(self.memory_value.trim().trim_start_matches('$'), 16);
+                let poked = match (self.memory_cursor, value) {
+                    (Some(offset), Ok(value)) => cpu.bus.poke(self.memory_space, offset, value),
+                    (None, _) => Err("no byte selected".to_string()),
+                    (_, Err(_)) => Err(format!("'{}' isn't a hex byte", self.memory_value)),
+                };
+                match poked {
+                    Ok(()) => {
+                        self.hex_editor.update(&cpu.bus);
+                        // on to the next byte, like typing into a hex editor
+                        self.memory_cursor = self.memory_cursor.map(|offset| offset + 1);
+                        self.memory_value.clear();
+                    }
+                    Err(err) => self.error = Some(err),
+                }
+            }
+            UiAction::ToggleMute(channel) => {
+                let muted = cpu.bus.apu.mixer.muted(channel.into());
+                cpu.bus.apu.mixer.set_muted(channel.into(), !muted);
+            }
+            UiAction::ToggleSolo(channel) => {
+                let solo = cpu.bus.apu.mixer.solo();
+                cpu.bus.apu.mixer.set_solo(if solo == Some(channel.into()) { None } else { Some(channel.into()) });
+            }
+            UiAction::SetVolume(channel, volume) => {
+                if let Err(err) = cpu.bus.apu.mixer.set_volume(channel.into(), volume) {
+                    self.error = Some(err);
+                }
+            }
+        }
+    }
+
+    fn view(&self, cpu: &CPU) -> View {
+        let pc = cpu.program_counter;
+        let exec_breakpoint = |addr: u16| cpu.bus.breakpoints.list().contains(&Breakpoint::Exec(addr));
+        let history = self.stepper.history();
+        // the last entry is the current instruction
+        let past = history.iter().take(history.len().saturating_sub(1)).filter(|_| self.paused.is_some());
+        let mut lines: Vec<Line> = past
+            .map(|addr| Line {
+                addr: *addr,
+                label: cpu.bus.label_at(*addr).map(|label| label.to_string()),
+                text: disassemble_at(&cpu.bus, *addr).0,
+                past: true,
+                breakpoint: exec_breakpoint(*addr),
+            })
+            .collect();
+        let mut addr = pc;
+        for _ in 0..LINES_AHEAD {
+            let (text, len) = disassemble_at(&cpu.bus, addr);
+            let label = cpu.bus.label_at(addr).map(|label| label.to_string());
+            lines.push(Line { addr, label, text, past: false, breakpoint: exec_breakpoint(addr) });
+            addr = addr.wrapping_add(len);
+        }
+        let (scanline, dot) = cpu.bus.ppu().position();
+        let memory = cpu.bus.memory(self.memory_space);
+        let frame = cpu.bus.frame_count();
+        View {
+            paused: self.paused.clone(),
+            registers: format!(
+                "A:{:02X} X:{:02X} Y:{:02X} SP:{:02X} PC:{:04X}",
+                cpu.register_a, cpu.register_x, cpu.register_y, cpu.stack_pointer, pc
+            ),
+            flags: cpu.register_p.bits(),
+            position: format!("frame {}  scanline {}  dot {}  cycle {}", cpu.bus.frame_count(), scanline, dot, cpu.bus.cycles()),
+            lines,
+            pc,
+            stack: (cpu.stack_pointer as u16 + 1..=0xff)
+                .take(STACK_ENTRIES)
+                .map(|offset| (0x100 + offset, cpu.bus.peek(0x100 + offset)))
+                .collect(),
+            breakpoints: cpu.bus.breakpoints.list().iter().map(|breakpoint| breakpoint.to_string()).collect(),
+            memory_ages: (0..memory.len()).map(|offset| self.hex_editor.age(self.memory_space, offset, frame)).collect(),
+            memory,
+            channels: Channel::ALL
+                .iter()
+                .map(|channel| ChannelView {
+                    channel: *channel,
+                    state: cpu.bus.apu.channel(*channel),
+                    frequency: cpu.bus.apu.frequency(*channel),
+                    muted: cpu.bus.apu.mixer.muted((*channel).into()),
+                    soloed: cpu.bus.apu.mixer.solo() == Some((*channel).into()),
+                    audible: cpu.bus.apu.mixer.audible((*channel).into()),
+                    volume: cpu.bus.apu.mixer.volume((*channel).into()),
+                    scope: cpu.bus.apu.scope.as_ref().map(|scope| scope.channel(*channel).iter().copied().collect()).unwrap_or_default(),
+                })
+                .collect(),
+        }
+    }
+
+    fn draw(&mut self, cpu: &CPU) -> Vec<UiAction> {
+        self.hex_editor.update(&cpu.bus);
+        let view = self.view(cpu);
+        let (width, height) = self.canvas.window().size();
+        let raw_input = egui::RawInput {
+            screen_rect: Some(egui::Rect::from_min_size(egui::Pos2::ZERO, egui::vec2(width as f32, height as f32))),
+            time: Some(self.started.elapsed().as_secs_f64()),
+            events: std::mem::take(&mut self.input),
+            ..Default::default()
+        };
+        let mut actions = vec![];
+        // edition 2018 closures borrow whole structs, so the fields go in one by one
+        let DebugUi { ctx, cursor, cursor_text, breakpoint_spec, error, memory_space, memory_cursor, memory_value, .. } =
+            self;
+        let output = ctx.run(raw_input, |ctx| {
+            egui::TopBottomPanel::top("controls").show(ctx, |ui| {
+                controls(ui, &view, cursor, cursor_text, &mut actions);
+            });
+            egui::SidePanel::right("stack").show(ctx, |ui| {
+                ui.heading("Stack");
+                for (addr, value) in view.stack.iter() {
+                    ui.label(RichText::new(format!("{:04X}: {:02X}", addr, value)).monospace());
+                }
+            });
+            egui::CentralPanel::default().show(ctx, |ui| {
+                registers(ui, &view);
+                ui.separator();
+                for line in view.lines.iter() {
+                    if let Some(label) = line.label.as_ref() {
+                        ui.label(RichText::new(format!("{}:", label)).monospace().color(Color32::LIGHT_BLUE));
+                    }
+                    let marker = if line.breakpoint { "*" } else { " " };
+                    let here = if !line.past && line.addr == view.pc { ">" } else { " " };
+                    let mut text = RichText::new(format!("{}{} {}", marker, here, line.text)).monospace();
+                    if line.past {
+                        text = text.color(Color32::GRAY);
+                    }
+                    if ui.selectable_label(*cursor == Some(line.addr) && !line.past, text).clicked() {
+                        *cursor = Some(line.addr);
+                        *cursor_text = format!("{:04X}", line.addr);
+                    }
+                }
+                ui.separator();
+                breakpoints(ui, &view, breakpoint_spec, &mut actions);
+                if let Some(error) = error.as_ref() {
+                    ui.colored_label(Color32::LIGHT_RED, error);
+                }
+            });
+            egui::Window::new("Memory").default_open(false).show(ctx, |ui| {
+                memory(ui, &view, memory_space, memory_cursor, memory_value, &mut actions);
+            });
+            egui::Window::new("APU").default_open(false).show(ctx, |ui| {
+                for channel in view.channels.iter() {
+                    apu_channel(ui, channel, &mut actions);
+                }
+            });
+        });
+        self.paint(output);
+        actions
+    }
+
+    fn paint(&mut self, output: egui::FullOutput) {
+        for (id, delta) in output.textures_delta.set {
+            self.set_texture(id, &delta);
+        }
+        let primitives = self.ctx.tessellate(output.shapes, output.pixels_per_point);
+        self.canvas.set_draw_color(Color::RGB(0, 0, 0));
+        self.canvas.clear();
+        let renderer = self.canvas.raw();
+        for ClippedPrimitive { clip_rect, primitive } in primitives {
+            let mesh = match primitive {
+                Primitive::Mesh(mesh) => mesh,
+                Primitive::Callback(_) => continue,
+            };
+            let texture = match self.textures.get(&mesh.texture_id) {
+                Some(texture) => texture.raw,
+                None => continue,
+            };
+            let clip = sys::SDL_Rect {
+                x: clip_rect.min.x as i32,
+                y: clip_rect.min.y as i32,
+                w: clip_rect.width().ceil() as i32,
+                h: clip_rect.height().ceil() as i32,
+            };
+            // SDL blends straight alpha, egui's colours are premultiplied
+            let vertices: Vec<sys::SDL_Vertex> = mesh
+                .vertices
+                .iter()
+                .map(|vertex| {
+                    let [r, g, b, a] = vertex.color.to_srgba_unmultiplied();
+                    sys::SDL_Vertex {
+                        position: sys::SDL_FPoint { x: vertex.pos.x, y: vertex.pos.y },
+                        color: sys::SDL_Color { r, g, b, a },
+                        tex_coord: sys::SDL_FPoint { x: vertex.uv.x, y: vertex.uv.y },
+                    }
+                })
+                .collect();
+            let indices: Vec<i32> = mesh.indices.iter().map(|index| *index as i32).collect();
+            unsafe {
+                sys::SDL_RenderSetClipRect(renderer, &clip);
+                sys::SDL_RenderGeometry(
+                    renderer,
+                    texture,
+                    vertices.as_ptr(),
+                    vertices.len() as i32,
+                    indices.as_ptr(),
+                    indices.len() as i32,
+                );
+            }
+        }
+        unsafe { sys::SDL_RenderSetClipRect(renderer, std::ptr::null()) };
+        self.canvas.present();
+        for id in output.textures_delta.free {
+            self.textures.remove(&id);
+        }
+    }
+
+    fn set_texture(&mut self, id: TextureId, delta: &ImageDelta) {
+        let (size, pixels): ([usize; 2], Vec<u8>) = match &delta.image {
+            ImageData::Color(image) => (image.size, image.pixels.iter().flat_map(|pixel| pixel.to_srgba_unmultiplied()).collect()),
+            ImageData::Font(image) => (image.size, image.srgba_pixels(None).flat_map(|pixel| pixel.to_srgba_unmultiplied()).collect()),
+        };
+        let renderer = self.canvas.raw();
+        if delta.pos.is_none() {
+            let raw = unsafe {
+                sys::SDL_CreateTexture(
+                    renderer,
+                    sys::SDL_PixelFormatEnum::SDL_PIXELFORMAT_ABGR8888 as u32,
+                    sys::SDL_TextureAccess::SDL_TEXTUREACCESS_STATIC as i32,
+                    size[0] as i32,
+                    size[1] as i32,
+                )
+            };
+            if raw.is_null() {
+                return;
+            }
+            unsafe { sys::SDL_SetTextureBlendMode(raw, sys::SDL_BlendMode::SDL_BLENDMODE_BLEND) };
+            self.textures.insert(id, Texture { raw });
+        }
+        let texture = match self.textures.get(&id) {
+            Some(texture) => texture.raw,
+            None => return,
+        };
+        let [x, y] = delta.pos.unwrap_or([0, 0]);
+        let rect = sys::SDL_Rect { x: x as i32, y: y as i32, w: size[0] as i32, h: size[1] as i32 };
+        unsafe { sys::SDL_UpdateTexture(texture, &rect, pixels.as_ptr() as *const _, (size[0] * 4) as i32) };
+    }
+}
+
+fn controls(ui: &mut egui::Ui, view: &View, cursor: &mut Option<u16>, cursor_text: &mut String, actions: &mut Vec<UiAction>) {
+    let paused = view.paused.is_some();
+    ui.horizontal(|ui| {
+        if paused {
+            if ui.button("Continue").clicked() {
+                actions.push(UiAction::Resume(Resume::Continue));
+            }
+        } else if ui.button("Pause").clicked() {
+            actions.push(UiAction::Pause);
+        }
+        if ui.add_enabled(paused, egui::Button::new("Step")).clicked() {
+            actions.push(UiAction::Resume(Resume::Step(1)));
+        }
+        if ui.add_enabled(paused, egui::Button::new("Step over")).clicked() {
+            actions.push(UiAction::Resume(Resume::StepOver));
+        }
+        if ui.add_enabled(paused, egui::Button::new("Step out")).clicked() {
+            actions.push(UiAction::Resume(Resume::StepOut));
+        }
+        // the field follows clicks on the disassembly and can be typed in
+        if ui.add(egui::TextEdit::singleline(cursor_text).desired_width(48.0)).changed() {
+            *cursor = u16::from_str_radix(cursor_text.trim().trim_start_matches('$'), 16).ok();
+        }
+        let run_to = ui.add_enabled(paused && cursor.is_some(), egui::Button::new("Run to cursor"));
+        if let (true, Some(addr)) = (run_to.clicked(), *cursor) {
+            actions.push(UiAction::Resume(Resume::RunTo(addr)));
+        }
+    });
+    match view.paused.as_ref() {
+        Some(reason) => ui.label(format!("paused: {}", reason)),
+        None => ui.label("running"),
+    };
+}
+
+fn registers(ui: &mut egui::Ui, view: &View) {
+    ui.label(RichText::new(&view.registers).monospace());
+    ui.horizontal(|ui| {
+        for (i, name) in "NV-BDIZC".chars().enumerate() {
+            let set = view.flags & (0x80 >> i) != 0;
+            let color = if set { Color32::LIGHT_GREEN } else { Color32::DARK_GRAY };
+            ui.label(RichText::new(name.to_string()).monospace().color(color));
+        }
+    });
+    ui.label(RichText::new(&view.position).monospace());
+}
+
+fn breakpoints(ui: &mut egui::Ui, view: &View, spec: &mut String, actions: &mut Vec<UiAction>) {
+    ui.heading("Breakpoints");
+    for (i, breakpoint) in view.breakpoints.iter().enumerate() {
+        ui.horizontal(|ui| {
+            ui.label(RichText::new(format!("#{} {}", i, breakpoint)).monospace());
+            if ui.small_button("x").clicked() {
+                actions.push(UiAction::RemoveBreakpoint(i));
+            }
+        });
+    }
+    ui.horizontal(|ui| {
+        let field = ui.add(egui::TextEdit::singleline(spec).hint_text("write 0300-03ff"));
+        let entered = field.lost_focus() && ui.input(|input| input.key_pressed(egui::Key::Enter));
+        if ui.button("Add").clicked() || entered {
+            actions.push(UiAction::AddBreakpoint);
+        }
+    });
+}
+
+fn memory(
+    ui: &mut egui::Ui,
+    view: &View,
+    space: &mut MemorySpace,
+    cursor: &mut Option<usize>,
+    value: &mut String,
+    actions: &mut Vec<UiAction>,
+) {
+    ui.horizontal(|ui| {
+        for other in MemorySpace::ALL.iter() {
+            if ui.selectable_label(*space == *other, other.name()).clicked() && *space != *other {
+                *space = *other;
+                *cursor = None;
+            }
+        }
+    });
+    if view.memory.is_empty() {
+        ui.label("this cartridge has none");
+        return;
+    }
+    ui.horizontal(|ui| {
+        let selected = match *cursor {
+            Some(offset) => format!("{:04X}", space.base() as usize + offset),
+            None => "----".to_string(),
+        };
+        ui.label(RichText::new(selected).monospace());
+        let field = ui.add(egui::TextEdit::singleline(value).desired_width(24.0).hint_text("00"));
+        let entered = field.lost_focus() && ui.input(|input| input.key_pressed(egui::Key::Enter));
+        if ui.button("Write").clicked() || entered {
+            actions.push(UiAction::Poke);
+            field.request_focus();
+        }
+    });
+    let row_height = ui.text_style_height(&egui::TextStyle::Monospace);
+    let rows = view.memory.len().div_ceil(MEMORY_COLUMNS);
+    egui::ScrollArea::vertical().show_rows(ui, row_height, rows, |ui, rows| {
+        for row in rows {
+            ui.horizontal(|ui| {
+                ui.spacing_mut().item_spacing.x = 4.0;
+                let start = row * MEMORY_COLUMNS;
+                ui.label(RichText::new(format!("{:04X}", space.base() as usize + start)).monospace().color(Color32::GRAY));
+                for offset in start..(start + MEMORY_COLUMNS).min(view.memory.len()) {
+                    let mut text = RichText::new(format!("{:02X}", view.memory[offset])).monospace();
+                    if let Some(age) = view.memory_ages[offset] {
+                        // fades from yellow back to the normal colour
+                        let fade = 1.0 - age as f32 / RECENT_FRAMES as f32;
+                        text = text.color(Color32::GRAY.lerp_to_gamma(Color32::YELLOW, fade));
+                    }
+                    if ui.selectable_label(*cursor == Some(offset), text).clicked() {
+                        *cursor = Some(offset);
+                        *value = format!("{:02X}", view.memory[offset]);
+                    }
+                }
+            });
+        }
+    });
+}
+
+fn apu_channel(ui: &mut egui::Ui, view: &ChannelView, actions: &mut Vec<UiAction>) {
+    let state = &view.state;
+    ui.horizontal(|ui| {
+        if ui.selectable_label(view.muted, "M").clicked() {
+            actions.push(UiAction::ToggleMute(view.channel));
+        }
+        if ui.selectable_label(view.soloed, "S").clicked() {
+            actions.push(UiAction::ToggleSolo(view.channel));
+        }
+        let mut volume = view.volume;
+        if ui.add(egui::Slider::new(&mut volume, 0..=MAX_VOLUME).suffix("%").show_value(false)).changed() {
+            actions.push(UiAction::SetVolume(view.channel, volume));
+        }
+        let color = if state.enabled { Color32::LIGHT_GREEN } else { Color32::DARK_GRAY };
+        ui.label(RichText::new(format!("{:<8}", view.channel.name())).monospace().color(color));
+        let waveform = match view.channel {
+            Channel::Pulse1 | Channel::Pulse2 => format!("duty {:>4}", ["12.5%", "25%", "50%", "75%"][state.duty as usize]),
+            Channel::Triangle => "triangle ".to_string(),
+            Channel::Noise => if state.duty == 1 { "short    " } else { "long     " }.to_string(),
+            Channel::Dmc => format!("level {:>3}", state.output),
+        };
+        let frequency = match view.frequency {
+            Some(frequency) => format!("{:>7.1}Hz", frequency),
+            None => "       -  ".to_string(),
+        };
+        ui.label(
+            RichText::new(format!(
+                "{}  vol {:>2}  period ${:03X}  {}  len {:>3}",
+                waveform, state.volume, state.period, frequency, state.length
+            ))
+            .monospace(),
+        );
+    });
+    // the DMC's level goes up to 127, the others' to 15
+    let top = if view.channel == Channel::Dmc { 127.0 } else { 15.0 };
+    let (rect, _) = ui.allocate_exact_size(egui::vec2(ui.available_width(), SCOPE_HEIGHT), egui::Sense::hover());
+    let painter = ui.painter_at(rect);
+    painter.rect_filled(rect, 0.0, Color32::from_gray(16));
+    if view.scope.len() < 2 {
+        return;
+    }
+    let points: Vec<egui::Pos2> = view
+        .scope
+        .iter()
+        .enumerate()
+        .map(|(i, level)| {
+            let x = rect.left() + rect.width() * i as f32 / (view.scope.len() - 1) as f32;
+            egui::pos2(x, rect.bottom() - (rect.height() - 2.0) * *level as f32 / top - 1.0)
+        })
+        .collect();
+    let color = if view.audible { Color32::LIGHT_GREEN } else { Color32::DARK_GRAY };
+    painter.add(egui::Shape::line(points, egui::Stroke::new(1.0, color)));
+}
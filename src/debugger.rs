@@ -0,0 +1,613 @@
+// The built-in debugger: breakpoints on the program counter, watchpoints on
+// CPU reads and writes, and breakpoints on PPU memory accesses (through
+// $2007) and on scanlines. The bus notes a hit while the instruction runs;
+// check() reports it before the next one, where the frontend pauses and hands
+// control to the REPL below (--debug, --break) or the debug server.
+//
+// Breakpoints are written the same way everywhere:
+//
+// 8000, exec 8000      before the instruction at $8000 runs
+// read 0075            a read of $0075, instruction fetches included
+// write 0300-03ff      a write anywhere in $0300-$03FF
+// ppu 2000-23ff        a $2007 read or write of PPU memory in that range
+// scanline 241         the PPU starting scanline 241, in every frame
+
+use crate::cpu::CPU;
+use crate::trace::{parse_addr, trace};
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{BufRead, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Breakpoint {
+    Exec(u16),
+    // inclusive address ranges
+    Read(u16, u16),
+    Write(u16, u16),
+    Ppu(u16, u16),
+    Scanline(u16),
+}
+
+impl Breakpoint {
+    pub fn parse(spec: &str) -> Result<Breakpoint, String> {
+        let mut words = spec.split_whitespace();
+        let (kind, target) = match (words.next(), words.next(), words.next()) {
+            (Some(addr), None, None) => ("exec", addr),
+            (Some(kind), Some(target), None) => (kind, target),
+            _ => return Err(format!("breakpoint '{}' should be <exec|read|write|ppu|scanline> <address>", spec)),
+        };
+        match kind {
+            "exec" => Ok(Breakpoint::Exec(parse_addr(target)?)),
+            "read" => parse_range(target).map(|(from, to)| Breakpoint::Read(from, to)),
+            "write" => parse_range(target).map(|(from, to)| Breakpoint::Write(from, to)),
+            "ppu" => match parse_range(target)? {
+                (_, to) if to > 0x3fff => Err(format!("PPU addresses end at 3fff, got '{}'", target)),
+                (from, to) => Ok(Breakpoint::Ppu(from, to)),
+            },
+            "scanline" => target
+                .parse::<u16>()
+                .map(Breakpoint::Scanline)
+                .map_err(|_| format!("'{}' is not a scanline number", target)),
+            other => Err(format!("unknown breakpoint kind '{}' (exec, read, write, ppu, scanline)", other)),
+        }
+    }
+}
+
+// "0075" or "0300-03ff"
+fn parse_range(value: &str) -> Result<(u16, u16), String> {
+    let (from, to) = match value.split_once('-') {
+        Some((from, to)) => (parse_addr(from)?, parse_addr(to)?),
+        None => (parse_addr(value)?, parse_addr(value)?),
+    };
+    if from > to {
+        return Err(format!("range '{}' starts after it ends", value));
+    }
+    Ok((from, to))
+}
+
+fn format_range(from: u16, to: u16) -> String {
+    if from == to {
+        format!("{:04x}", from)
+    } else {
+        format!("{:04x}-{:04x}", from, to)
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Breakpoint::Exec(addr) => write!(f, "exec {:04x}", addr),
+            Breakpoint::Read(from, to) => write!(f, "read {}", format_range(from, to)),
+            Breakpoint::Write(from, to) => write!(f, "write {}", format_range(from, to)),
+            Breakpoint::Ppu(from, to) => write!(f, "ppu {}", format_range(from, to)),
+            Breakpoint::Scanline(scanline) => write!(f, "scanline {}", scanline),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Hit {
+    Exec { pc: u16 },
+    // `pc` is the instruction that made the access
+    Read { addr: u16, value: u8, pc: u16 },
+    Write { addr: u16, value: u8, pc: u16 },
+    Ppu { addr: u16, write: bool, pc: u16 },
+    Scanline { scanline: u16, frame: usize },
+}
+
+impl Hit {
+    // for the debug server's paused event
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Hit::Exec { .. } => "breakpoint",
+            Hit::Read { .. } => "read_watchpoint",
+            Hit::Write { .. } => "write_watchpoint",
+            Hit::Ppu { .. } => "ppu_watchpoint",
+            Hit::Scanline { .. } => "scanline",
+        }
+    }
+}
+
+impl fmt::Display for Hit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Hit::Exec { pc } => write!(f, "breakpoint at {:04x}", pc),
+            Hit::Read { addr, value, pc } => write!(f, "read of {:02x} from {:04x} at {:04x}", value, addr, pc),
+            Hit::Write { addr, value, pc } => write!(f, "write of {:02x} to {:04x} at {:04x}", value, addr, pc),
+            Hit::Ppu { addr, write, pc } => write!(
+                f,
+                "PPU {} of {:04x} through 2007 at {:04x}",
+                if *write { "write" } else { "read" },
+                addr,
+                pc
+            ),
+            Hit::Scanline { scanline, frame } => write!(f, "scanline {} of frame {}", scanline, frame),
+        }
+    }
+}
+
+// Lives on the bus, which calls the access hooks; they cost a flag test
+// while no watchpoint is set.
+pub struct Breakpoints {
+    list: Vec<Breakpoint>,
+    // any read, write or PPU watchpoints, and any scanline ones
+    watching: bool,
+    on_scanlines: bool,
+    // tools reading memory for display (the trace) aren't the program
+    muted: bool,
+    // the first hit since the last check
+    hit: Option<Hit>,
+    // the breakpoint just reported, so checking again there doesn't stop twice
+    stopped_at: Option<u16>,
+}
+
+impl Default for Breakpoints {
+    fn default() -> Self {
+        Breakpoints::new()
+    }
+}
+
+impl Breakpoints {
+    pub fn new() -> Self {
+        Breakpoints { list: vec![], watching: false, on_scanlines: false, muted: false, hit: None, stopped_at: None }
+    }
+
+    pub fn list(&self) -> &[Breakpoint] {
+        &self.list
+    }
+
+    // returns the new breakpoint's index
+    pub fn add(&mut self, breakpoint: Breakpoint) -> usize {
+        self.list.push(breakpoint);
+        self.update_flags();
+        self.list.len() - 1
+    }
+
+    pub fn remove(&mut self, index: usize) -> Result<Breakpoint, String> {
+        if index >= self.list.len() {
+            return Err(format!("no breakpoint #{}", index));
+        }
+        let breakpoint = self.list.remove(index);
+        self.update_flags();
+        Ok(breakpoint)
+    }
+
+    fn update_flags(&mut self) {
+        self.watching = self
+            .list
+            .iter()
+            .any(|breakpoint| matches!(breakpoint, Breakpoint::Read(..) | Breakpoint::Write(..) | Breakpoint::Ppu(..)));
+        self.on_scanlines = self.list.iter().any(|breakpoint| matches!(breakpoint, Breakpoint::Scanline(_)));
+    }
+
+    pub fn watching(&self) -> bool {
+        self.watching && !self.muted
+    }
+
+    pub fn on_scanlines(&self) -> bool {
+        self.on_scanlines
+    }
+
+    // returns whether it was muted before
+    pub fn mute(&mut self, muted: bool) -> bool {
+        std::mem::replace(&mut self.muted, muted)
+    }
+
+    pub fn read(&mut self, addr: u16, value: u8, pc: u16) {
+        if self.list.iter().any(|breakpoint| matches!(*breakpoint, Breakpoint::Read(from, to) if (from..=to).contains(&addr))) {
+            self.record(Hit::Read { addr, value, pc });
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, value: u8, pc: u16) {
+        if self.list.iter().any(|breakpoint| matches!(*breakpoint, Breakpoint::Write(from, to) if (from..=to).contains(&addr))) {
+            self.record(Hit::Write { addr, value, pc });
+        }
+    }
+
+    pub fn ppu_access(&mut self, addr: u16, write: bool, pc: u16) {
+        if self.list.iter().any(|breakpoint| matches!(*breakpoint, Breakpoint::Ppu(from, to) if (from..=to).contains(&addr))) {
+            self.record(Hit::Ppu { addr, write, pc });
+        }
+    }
+
+    pub fn scanline(&mut self, scanline: u16, frame: usize) {
+        if self.list.contains(&Breakpoint::Scanline(scanline)) {
+            self.record(Hit::Scanline { scanline, frame });
+        }
+    }
+
+    fn record(&mut self, hit: Hit) {
+        if self.hit.is_none() {
+            self.hit = Some(hit);
+        }
+    }
+
+    // drops hits from emulation that didn't count, like frames run ahead
+    pub fn clear_hit(&mut self) {
+        self.hit = None;
+    }
+
+    // Before each instruction: the watchpoint the last one set off, or the
+    // breakpoint on the one about to run.
+    pub fn check(&mut self, pc: u16) -> Option<Hit> {
+        if let Some(hit) = self.hit.take() {
+            return Some(hit);
+        }
+        if self.stopped_at.take() == Some(pc) {
+            return None;
+        }
+        if self.list.contains(&Breakpoint::Exec(pc)) {
+            self.stopped_at = Some(pc);
+            return Some(Hit::Exec { pc });
+        }
+        None
+    }
+}
+
+// Where to stop next, from a stop.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resume {
+    // at the next breakpoint
+    Continue,
+    // after this many instructions
+    Step(usize),
+    // after the next instruction, the whole subroutine for a JSR
+    StepOver,
+    // once the running subroutine returns
+    StepOut,
+    // at this address
+    RunTo(u16),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Until {
+    Steps(usize),
+    Pc(u16),
+    // an RTS or RTI that leaves the stack pointer above this
+    Return(u8),
+}
+
+// instructions the stepper remembers, for showing what ran before the stop
+pub const HISTORY: usize = 16;
+
+const JSR: u8 = 0x20;
+const RTS: u8 = 0x60;
+const RTI: u8 = 0x40;
+
+// Stepping, shared by the REPL and the debugger window: check() before each
+// instruction says whether to stop there, resume() says where to stop next.
+// Breakpoints stop emulation whatever it was told.
+pub struct Stepper {
+    until: Option<Until>,
+    // the addresses checked last, the current instruction last
+    history: VecDeque<u16>,
+    // the instruction just let through returns from a subroutine
+    returning: bool,
+}
+
+impl Stepper {
+    // `paused` stops before the first instruction
+    pub fn new(paused: bool) -> Self {
+        Stepper { until: paused.then_some(Until::Steps(0)), history: VecDeque::with_capacity(HISTORY), returning: false }
+    }
+
+    pub fn history(&self) -> &VecDeque<u16> {
+        &self.history
+    }
+
+    // why emulation should stop here, if it should
+    pub fn check(&mut self, cpu: &mut CPU) -> Option<String> {
+        let pc = cpu.program_counter;
+        if self.history.len() == HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(pc);
+        let returned = std::mem::replace(&mut self.returning, is_return(cpu.bus.peek(pc)));
+        if let Some(hit) = cpu.bus.breakpoints.check(pc) {
+            self.until = None;
+            return Some(hit.to_string());
+        }
+        let stop = match self.until? {
+            Until::Steps(0) => true,
+            Until::Steps(left) => {
+                self.until = Some(Until::Steps(left - 1));
+                false
+            }
+            Until::Pc(target) => pc == target,
+            Until::Return(stack_pointer) => returned && cpu.stack_pointer > stack_pointer,
+        };
+        if !stop {
+            return None;
+        }
+        self.until = None;
+        Some("step".to_string())
+    }
+
+    pub fn resume(&mut self, cpu: &CPU, resume: Resume) {
+        let pc = cpu.program_counter;
+        let opcode = cpu.bus.peek(pc);
+        // the instruction here runs without another check
+        self.returning = is_return(opcode);
+        self.until = match resume {
+            Resume::Continue => None,
+            Resume::Step(count) => Some(Until::Steps(count.max(1) - 1)),
+            Resume::StepOver if opcode == JSR => Some(Until::Pc(pc.wrapping_add(3))),
+            Resume::StepOver => Some(Until::Steps(0)),
+            Resume::StepOut => Some(Until::Return(cpu.stack_pointer)),
+            Resume::RunTo(addr) => Some(Until::Pc(addr)),
+        };
+    }
+}
+
+fn is_return(opcode: u8) -> bool {
+    opcode == RTS || opcode == RTI
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReplAction {
+    Print(String),
+    Resume,
+    Quit,
+}
+
+const HELP: &str = "\
+c, continue           run until the next breakpoint
+s, step [n]           run n instructions (1)
+n, next               run the next instruction, a JSR with its subroutine
+o, out                run until the subroutine returns
+u, until <addr>       run to an address
+r, regs               the next instruction, the registers and the PPU position
+m, mem <addr> [len]   dump memory (16 bytes)
+b, break <spec>       add a breakpoint: 8000, read 0075, write 0300-03ff, ppu 2000-23ff, scanline 241
+                      addresses can be label names from the loaded label files
+d, delete <n>         remove breakpoint #n
+l, list               list the breakpoints
+q, quit               exit the emulator";
+
+// The command line debugger: the frontend calls check() before each
+// instruction and serve_paused() when it says to stop.
+pub struct Repl {
+    stepper: Stepper,
+}
+
+impl Repl {
+    // `paused` stops before the first instruction
+    pub fn new(paused: bool) -> Self {
+        Repl { stepper: Stepper::new(paused) }
+    }
+
+    // why emulation should stop here, if it should
+    pub fn check(&mut self, cpu: &mut CPU) -> Option<String> {
+        self.stepper.check(cpu)
+    }
+
+    pub fn command(&mut self, cpu: &mut CPU, line: &str) -> Result<ReplAction, String> {
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => return Ok(ReplAction::Print(String::new())),
+        };
+        let rest: Vec<&str> = words.collect();
+        match command {
+            "c" | "continue" => self.resume(cpu, Resume::Continue),
+            "s" | "step" => {
+                let count = match rest.first() {
+                    Some(count) => count.parse::<usize>().ok().filter(|count| *count > 0).ok_or(format!("'{}' is not a step count", count))?,
+                    None => 1,
+                };
+                self.resume(cpu, Resume::Step(count))
+            }
+            "n" | "next" => self.resume(cpu, Resume::StepOver),
+            "o" | "out" => self.resume(cpu, Resume::StepOut),
+            "u" | "until" => {
+                let addr = address(cpu, rest.first().ok_or("until needs an address")?)?;
+                self.resume(cpu, Resume::RunTo(addr))
+            }
+            "r" | "regs" => Ok(ReplAction::Print(location(cpu))),
+            "m" | "mem" => {
+                let addr = address(cpu, rest.first().ok_or("mem needs an address")?)?;
+                let len = match rest.get(1) {
+                    Some(len) => len.parse::<usize>().map_err(|_| format!("'{}' is not a length", len))?,
+                    None => 16,
+                };
+                Ok(ReplAction::Print(dump(cpu, addr, len)))
+            }
+            "b" | "break" => {
+                let spec: Vec<String> = rest
+                    .iter()
+                    .map(|word| match cpu.bus.labels.address_of(word) {
+                        Some(addr) => format!("{:04x}", addr),
+                        None => word.to_string(),
+                    })
+                    .collect();
+                let breakpoint = Breakpoint::parse(&spec.join(" "))?;
+                let index = cpu.bus.breakpoints.add(breakpoint);
+                Ok(ReplAction::Print(format!("#{} {}", index, breakpoint)))
+            }
+            "d" | "delete" => {
+                let index = rest.first().ok_or("delete needs a breakpoint number")?;
+                let index = index.trim_start_matches('#').parse::<usize>().map_err(|_| format!("'{}' is not a breakpoint number", index))?;
+                let breakpoint = cpu.bus.breakpoints.remove(index)?;
+                Ok(ReplAction::Print(format!("deleted {}", breakpoint)))
+            }
+            "l" | "list" => {
+                let lines: Vec<String> =
+                    cpu.bus.breakpoints.list().iter().enumerate().map(|(i, breakpoint)| format!("#{} {}", i, breakpoint)).collect();
+                Ok(ReplAction::Print(if lines.is_empty() { "no breakpoints".to_string() } else { lines.join("\n") }))
+            }
+            "q" | "quit" => Ok(ReplAction::Quit),
+            "h" | "help" | "?" => Ok(ReplAction::Print(HELP.to_string())),
+            other => Err(format!("unknown command '{}', try help", other)),
+        }
+    }
+
+    fn resume(&mut self, cpu: &CPU, resume: Resume) -> Result<ReplAction, String> {
+        self.stepper.resume(cpu, resume);
+        Ok(ReplAction::Resume)
+    }
+
+    // Reads commands from stdin until one resumes emulation. The end of
+    // input resumes it too.
+    pub fn serve_paused(&mut self, cpu: &mut CPU, reason: &str) {
+        println!("paused: {}\n{}", reason, location(cpu));
+        let stdin = std::io::stdin();
+        let mut line = String::new();
+        loop {
+            print!("(debug) ");
+            std::io::stdout().flush().ok();
+            line.clear();
+            if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+                return;
+            }
+            match self.command(cpu, &line) {
+                Ok(ReplAction::Print(text)) if text.is_empty() => {}
+                Ok(ReplAction::Print(text)) => println!("{}", text),
+                Ok(ReplAction::Resume) => return,
+                Ok(ReplAction::Quit) => std::process::exit(0),
+                Err(err) => println!("{}", err),
+            }
+        }
+    }
+}
+
+fn location(cpu: &mut CPU) -> String {
+    let (scanline, dot) = cpu.bus.ppu().position();
+    let line = format!("{}  frame {} scanline {} dot {}", trace(cpu), cpu.bus.frame_count(), scanline, dot);
+    match cpu.bus.label_at(cpu.program_counter) {
+        Some(label) => format!("{}:\n{}", label, line),
+        None => line,
+    }
+}
+
+// a hex address or a label name
+fn address(cpu: &CPU, text: &str) -> Result<u16, String> {
+    parse_addr(text).or_else(|err| cpu.bus.labels.address_of(text).ok_or(err))
+}
+
+fn dump(cpu: &CPU, addr: u16, len: usize) -> String {
+    let mut lines = vec![];
+    for row in (0..len).step_by(16) {
+        let start = addr.wrapping_add(row as u16);
+        let bytes: Vec<String> =
+            (0..(len - row).min(16)).map(|i| format!("{:02x}", cpu.bus.peek(start.wrapping_add(i as u16)))).collect();
+        lines.push(format!("{:04x}: {}", start, bytes.join(" ")));
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::builder::RomBuilder;
+
+    // runs from a stop until the REPL says to stop again
+    fn run(cpu: &mut CPU, repl: &mut Repl) -> String {
+        loop {
+            cpu.step();
+            if let Some(reason) = repl.check(cpu) {
+                return reason;
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_breakpoints() {
+        assert_eq!(Breakpoint::parse("8000"), Ok(Breakpoint::Exec(0x8000)));
+        assert_eq!(Breakpoint::parse("read $0075"), Ok(Breakpoint::Read(0x75, 0x75)));
+        assert_eq!(Breakpoint::parse("write 0300-03ff"), Ok(Breakpoint::Write(0x300, 0x3ff)));
+        assert_eq!(Breakpoint::parse("ppu 3f00-3f1f"), Ok(Breakpoint::Ppu(0x3f00, 0x3f1f)));
+        assert_eq!(Breakpoint::parse("scanline 241"), Ok(Breakpoint::Scanline(241)));
+        for spec in ["", "read", "write 03ff-0300", "ppu 4000", "scanline x", "poke 0075", "read 1 2"] {
+            assert!(Breakpoint::parse(spec).is_err(), "{}", spec);
+        }
+        assert_eq!(Breakpoint::Write(0x300, 0x3ff).to_string(), "write 0300-03ff");
+        assert_eq!(Breakpoint::Read(0x75, 0x75).to_string(), "read 0075");
+    }
+
+    #[test]
+    fn test_breakpoints_and_watchpoints() {
+        let rom = RomBuilder::new()
+            .program(
+                0x8000,
+                &[
+                    0xa9, 0x20, 0x8d, 0x06, 0x20, // LDA #$20; STA $2006
+                    0xa9, 0x00, 0x8d, 0x06, 0x20, // LDA #$00; STA $2006
+                    0x8d, 0x07, 0x20, // STA $2007
+                    0xe6, 0x75, // loop: INC $75
+                    0xa5, 0x10, // LDA $10
+                    0x4c, 0x0d, 0x80, // JMP loop
+                ],
+            )
+            .rom();
+        let mut cpu = CPU::new(Bus::new(rom, |_, _, _| {}));
+        cpu.reset();
+        let mut repl = Repl::new(true);
+        assert_eq!(repl.check(&mut cpu), Some("step".to_string()));
+
+        for spec in ["b 800d", "b write 0075", "b ppu 2000-23ff", "b read 0010"] {
+            repl.command(&mut cpu, spec).unwrap();
+        }
+        assert!(matches!(repl.command(&mut cpu, "c"), Ok(ReplAction::Resume)));
+        assert_eq!(run(&mut cpu, &mut repl), "PPU write of 2000 through 2007 at 800a");
+        assert_eq!(cpu.program_counter, 0x800d);
+        // INC writes the old value back before the new one, the first write wins
+        assert_eq!(run(&mut cpu, &mut repl), "write of 00 to 0075 at 800d");
+        assert_eq!(cpu.program_counter, 0x800f);
+        assert_eq!(run(&mut cpu, &mut repl), "read of 00 from 0010 at 800f");
+        assert_eq!(run(&mut cpu, &mut repl), "breakpoint at 800d");
+
+        assert_eq!(repl.command(&mut cpu, "d 0"), Ok(ReplAction::Print("deleted exec 800d".to_string())));
+        repl.command(&mut cpu, "delete #2").unwrap();
+        assert_eq!(repl.command(&mut cpu, "list"), Ok(ReplAction::Print("#0 write 0075\n#1 ppu 2000-23ff".to_string())));
+        repl.command(&mut cpu, "step 3").unwrap();
+        assert_eq!(run(&mut cpu, &mut repl), "write of 01 to 0075 at 800d");
+        assert_eq!(repl.command(&mut cpu, "m 75 2"), Ok(ReplAction::Print("0075: 02 00".to_string())));
+
+        repl.command(&mut cpu, "d 0").unwrap();
+        repl.command(&mut cpu, "b scanline 241").unwrap();
+        assert_eq!(run(&mut cpu, &mut repl), "scanline 241 of frame 0");
+        assert_eq!(cpu.bus.ppu().position().0, 241);
+        assert!(repl.command(&mut cpu, "d 5").is_err());
+        assert!(repl.command(&mut cpu, "jump").is_err());
+        assert_eq!(repl.command(&mut cpu, "q"), Ok(ReplAction::Quit));
+    }
+
+    #[test]
+    fn test_step_over_and_out() {
+        let rom = RomBuilder::new()
+            .program(0x8000, &[0x20, 0x10, 0x80, 0x20, 0x10, 0x80, 0x4c, 0x06, 0x80]) // JSR $8010; JSR $8010; JMP $8006
+            .program(0x8010, &[0xe8, 0x48, 0x68, 0x60]) // INX; PHA; PLA; RTS
+            .rom();
+        let mut cpu = CPU::new(Bus::new(rom, |_, _, _| {}));
+        cpu.reset();
+        let mut stepper = Stepper::new(true);
+        let run = |cpu: &mut CPU, stepper: &mut Stepper, resume: Resume| {
+            stepper.resume(cpu, resume);
+            loop {
+                cpu.step();
+                if let Some(reason) = stepper.check(cpu) {
+                    return reason;
+                }
+            }
+        };
+        assert_eq!(stepper.check(&mut cpu), Some("step".to_string()));
+
+        assert_eq!(run(&mut cpu, &mut stepper, Resume::StepOver), "step");
+        assert_eq!((cpu.program_counter, cpu.register_x), (0x8003, 1));
+        run(&mut cpu, &mut stepper, Resume::Step(1));
+        assert_eq!(cpu.program_counter, 0x8010);
+        // the PLA raises the stack pointer too, only the RTS counts
+        run(&mut cpu, &mut stepper, Resume::StepOut);
+        assert_eq!((cpu.program_counter, cpu.register_x), (0x8006, 2));
+        let history: Vec<u16> = stepper.history().iter().rev().take(5).copied().collect();
+        assert_eq!(history, vec![0x8006, 0x8013, 0x8012, 0x8011, 0x8010]);
+
+        // not a JSR: one instruction
+        run(&mut cpu, &mut stepper, Resume::StepOver);
+        assert_eq!(cpu.program_counter, 0x8006);
+        cpu.bus.breakpoints.add(Breakpoint::Exec(0x8006));
+        assert_eq!(run(&mut cpu, &mut stepper, Resume::RunTo(0x9000)), "breakpoint at 8006");
+    }
+}
@@ -0,0 +1,96 @@
+// Emulator-wide events for features that sit on top of the core (OSD,
+// achievements, scripting, stats views). The bus owns one EventBus and emits
+// into it; subscribers are closures called synchronously, in subscription
+// order, from the emulation loop.
+
+use std::cell::{Cell, RefCell};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EmuEvent {
+    RomLoaded { mapper: u8 },
+    FrameStart(usize),
+    FrameEnd(usize),
+    Nmi,
+    Irq,
+    // a write to the mapper registers at $8000-$FFFF (bank or mirroring switch)
+    BankSwitch { addr: u16, data: u8 },
+    StateSaved,
+    StateLoaded,
+}
+
+pub type SubscriptionId = usize;
+
+type Subscriber = Box<dyn FnMut(&EmuEvent)>;
+
+pub struct EventBus {
+    // a RefCell so events can be emitted from &self (savestates are saved through &self)
+    subscribers: RefCell<Vec<(SubscriptionId, Subscriber)>>,
+    next_id: Cell<SubscriptionId>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        EventBus::new()
+    }
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        EventBus {
+            subscribers: RefCell::new(vec![]),
+            next_id: Cell::new(0),
+        }
+    }
+
+    pub fn subscribe<F>(&self, subscriber: F) -> SubscriptionId
+    where
+        F: FnMut(&EmuEvent) + 'static,
+    {
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+        self.subscribers.borrow_mut().push((id, Box::new(subscriber)));
+        id
+    }
+
+    pub fn unsubscribe(&self, id: SubscriptionId) {
+        self.subscribers.borrow_mut().retain(|(subscribed, _)| *subscribed != id);
+    }
+
+    // Subscribers can't subscribe, unsubscribe or emit while handling an event.
+    pub fn emit(&self, event: EmuEvent) {
+        let mut subscribers = self.subscribers.borrow_mut();
+        for (_, subscriber) in subscribers.iter_mut() {
+            subscriber(&event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_subscribe_emit_unsubscribe() {
+        let events = EventBus::new();
+        let seen = Rc::new(RefCell::new(vec![]));
+        let frames = Rc::new(Cell::new(0));
+
+        let log = seen.clone();
+        let logger = events.subscribe(move |event| log.borrow_mut().push(*event));
+        let counter = frames.clone();
+        events.subscribe(move |event| {
+            if let EmuEvent::FrameEnd(_) = event {
+                counter.set(counter.get() + 1);
+            }
+        });
+
+        events.emit(EmuEvent::RomLoaded { mapper: 4 });
+        events.emit(EmuEvent::FrameEnd(0));
+        events.unsubscribe(logger);
+        events.emit(EmuEvent::FrameEnd(1));
+
+        assert_eq!(*seen.borrow(), vec![EmuEvent::RomLoaded { mapper: 4 }, EmuEvent::FrameEnd(0)]);
+        assert_eq!(frames.get(), 2);
+    }
+}
@@ -0,0 +1,174 @@
+// C ABI over session.rs, for driving the emulator from C, Python (ctypes,
+// cffi) or anything else that can call a shared library. Built with the
+// `ffi` feature as a cdylib:
+//
+//   cargo rustc --release --lib --features ffi --crate-type cdylib
+//
+// nes_session_new() takes the bytes of an .nes file and returns an opaque
+// handle, or null when the ROM can't be loaded; nes_session_free() releases
+// it. Input is one byte per port with the JoypadButton bits (A = 0x01,
+// B = 0x02, Select = 0x04, Start = 0x08, Up = 0x10, Down = 0x20, Left = 0x40,
+// Right = 0x80). States are plain byte buffers and follow the determinism
+// rules described in session.rs, so a state can be loaded into any session
+// running the same ROM.
+//
+// From Python:
+//
+//   nes = ctypes.CDLL("target/release/libnes_book_emu.so")
+//   nes.nes_session_new.restype = ctypes.c_void_p
+//   session = ctypes.c_void_p(nes.nes_session_new(rom, len(rom)))
+//   nes.nes_session_step_frame(session, 0x01, 0x00)
+//   size = nes.nes_session_save_state(session, None, 0)
+//   state = ctypes.create_string_buffer(size)
+//   nes.nes_session_save_state(session, state, size)
+//
+// Every pointer must be valid for the length passed with it, and a session
+// handle must come from nes_session_new() and not be used after it's freed.
+#![allow(clippy::missing_safety_doc)]
+
+use crate::cartridge::Rom;
+use crate::joypad::JoypadButton;
+use crate::render::frame::Frame;
+use crate::session::Session;
+use std::ptr;
+use std::slice;
+
+pub const FRAME_BYTES: usize = Frame::WIDTH * Frame::HIGHT * 3;
+
+#[no_mangle]
+pub unsafe extern "C" fn nes_session_new(rom: *const u8, len: usize) -> *mut Session {
+    if rom.is_null() {
+        return ptr::null_mut();
+    }
+    let raw = slice::from_raw_parts(rom, len).to_vec();
+    match Rom::new(&raw).and_then(Session::new) {
+        Ok(session) => Box::into_raw(Box::new(session)),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn nes_session_free(session: *mut Session) {
+    if !session.is_null() {
+        drop(Box::from_raw(session));
+    }
+}
+
+// returns the number of the frame that starts next
+#[no_mangle]
+pub unsafe extern "C" fn nes_session_step_frame(session: *mut Session, port1: u8, port2: u8) -> usize {
+    (*session).step_frame(buttons(port1, port2))
+}
+
+// returns 1 when this cycle started an instruction, 0 otherwise
+#[no_mangle]
+pub unsafe extern "C" fn nes_session_step_cycle(session: *mut Session, port1: u8, port2: u8) -> i32 {
+    (*session).step_cycle(buttons(port1, port2)).is_some() as i32
+}
+
+// copies the last frame, 256x240 RGB, into `out` when it holds FRAME_BYTES;
+// returns FRAME_BYTES either way
+#[no_mangle]
+pub unsafe extern "C" fn nes_session_frame(session: *const Session, out: *mut u8, len: usize) -> usize {
+    copy_out(&(*session).frame().data, out, len)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn nes_session_peek(session: *const Session, addr: u16) -> u8 {
+    (*session).peek(addr)
+}
+
+// writes the state into `out` when it fits in `len` bytes and returns its
+// size, so a first call with a null `out` asks how big the buffer must be
+#[no_mangle]
+pub unsafe extern "C" fn nes_session_save_state(session: *const Session, out: *mut u8, len: usize) -> usize {
+    copy_out(&(*session).save_state(), out, len)
+}
+
+// returns 0 when the state was loaded, -1 when it was rejected
+#[no_mangle]
+pub unsafe extern "C" fn nes_session_load_state(session: *mut Session, data: *const u8, len: usize) -> i32 {
+    if data.is_null() {
+        return -1;
+    }
+    match (*session).load_state(slice::from_raw_parts(data, len)) {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn nes_session_soft_reset(session: *mut Session) {
+    (*session).soft_reset();
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn nes_session_power_cycle(session: *mut Session) {
+    (*session).power_cycle();
+}
+
+fn buttons(port1: u8, port2: u8) -> [JoypadButton; 2] {
+    [JoypadButton::from_bits_truncate(port1), JoypadButton::from_bits_truncate(port2)]
+}
+
+unsafe fn copy_out(data: &[u8], out: *mut u8, len: usize) -> usize {
+    if !out.is_null() && len >= data.len() {
+        ptr::copy_nonoverlapping(data.as_ptr(), out, data.len());
+    }
+    data.len()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::builder::RomBuilder;
+
+    // counts frames in $00 and adds up the A button in $01
+    fn counting_rom() -> Vec<u8> {
+        RomBuilder::new()
+            // LDA #$80; STA $2000; loop: LDA #1; STA $4016; LDA #0; STA $4016;
+            // LDA $4016; AND #1; ADC $01; STA $01; JMP loop
+            .program(
+                0x8000,
+                &[
+                    0xa9, 0x80, 0x8d, 0x00, 0x20, 0xa9, 0x01, 0x8d, 0x16, 0x40, 0xa9, 0x00, 0x8d, 0x16, 0x40, 0xad,
+                    0x16, 0x40, 0x29, 0x01, 0x65, 0x01, 0x85, 0x01, 0x4c, 0x05, 0x80,
+                ],
+            )
+            // nmi: INC $00; RTI
+            .program(0x8040, &[0xe6, 0x00, 0x40])
+            .nmi_vector(0x8040)
+            .build()
+    }
+
+    #[test]
+    fn test_step_save_and_restore_through_the_c_abi() {
+        let rom = counting_rom();
+        unsafe {
+            assert!(nes_session_new(rom.as_ptr(), 16).is_null());
+            let session = nes_session_new(rom.as_ptr(), rom.len());
+            assert!(!session.is_null());
+
+            assert_eq!(nes_session_step_frame(session, 0, 0), 1);
+            let size = nes_session_save_state(session, ptr::null_mut(), 0);
+            let mut state = vec![0; size];
+            assert_eq!(nes_session_save_state(session, state.as_mut_ptr(), size), size);
+
+            let mut played = vec![];
+            for _ in 0..2 {
+                for _ in 0..10 {
+                    nes_session_step_frame(session, JoypadButton::BUTTON_A.bits(), 0);
+                }
+                played.push((nes_session_peek(session, 0x00), nes_session_peek(session, 0x01)));
+                assert_eq!(nes_session_load_state(session, state.as_ptr(), state.len()), 0);
+            }
+            assert_eq!(played[0], played[1]);
+            assert_ne!(played[0].1, 0);
+            assert_eq!(nes_session_load_state(session, state.as_ptr(), 3), -1);
+
+            let mut frame = vec![0; FRAME_BYTES];
+            assert_eq!(nes_session_frame(session, frame.as_mut_ptr(), frame.len()), FRAME_BYTES);
+            nes_session_free(session);
+        }
+    }
+}
@@ -0,0 +1,178 @@
+// Conditions checked once per frame, for running frames until something
+// happens ("advance until the player's x is past 0x80"):
+//
+//   $0075 == 3                 a byte of CPU memory (RAM or cartridge)
+//   frame >= 600
+//   palette:00 != $0f          a palette entry, as in --watch-ppu
+//   oam:00 < 200               an OAM byte
+//   nametables:2042 == $24     a nametable byte
+//   ppuctrl, ppumask, ppustatus
+//
+// Comparisons are ==, !=, <, <=, > and >= against a number, decimal or hex
+// with $ or 0x. They combine with && and ||, && binding tighter; there are
+// no parentheses.
+
+use crate::bus::Bus;
+use crate::ppu;
+use crate::trace::parse_addr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operand {
+    Frame,
+    Cpu(u16),
+    Palette(u16),
+    Oam(u16),
+    Nametables(u16),
+    PpuCtrl,
+    PpuMask,
+    PpuStatus,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Compare {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+// two-character operators first, so "<=" isn't read as "<"
+const OPERATORS: [(&str, Compare); 6] = [
+    ("==", Compare::Equal),
+    ("!=", Compare::NotEqual),
+    ("<=", Compare::LessOrEqual),
+    (">=", Compare::GreaterOrEqual),
+    ("<", Compare::Less),
+    (">", Compare::Greater),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+struct Comparison {
+    operand: Operand,
+    compare: Compare,
+    value: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameCondition {
+    // any of these, each true when all of its comparisons are
+    any: Vec<Vec<Comparison>>,
+}
+
+impl FrameCondition {
+    pub fn parse(text: &str) -> Result<FrameCondition, String> {
+        let any = text
+            .split("||")
+            .map(|all| all.split("&&").map(parse_comparison).collect::<Result<Vec<_>, _>>())
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(FrameCondition { any })
+    }
+
+    pub fn matches(&self, bus: &Bus) -> bool {
+        self.any.iter().any(|all| {
+            all.iter().all(|comparison| {
+                let value = read(comparison.operand, bus);
+                match comparison.compare {
+                    Compare::Equal => value == comparison.value,
+                    Compare::NotEqual => value != comparison.value,
+                    Compare::Less => value < comparison.value,
+                    Compare::LessOrEqual => value <= comparison.value,
+                    Compare::Greater => value > comparison.value,
+                    Compare::GreaterOrEqual => value >= comparison.value,
+                }
+            })
+        })
+    }
+}
+
+fn parse_comparison(text: &str) -> Result<Comparison, String> {
+    let (at, symbol, compare) = OPERATORS
+        .iter()
+        .filter_map(|(symbol, compare)| text.find(symbol).map(|at| (at, *symbol, *compare)))
+        .min_by_key(|(at, _, _)| *at)
+        .ok_or(format!("'{}' should compare something, like $0075 == 3", text.trim()))?;
+    let operand = parse_operand(text[..at].trim())?;
+    let value = parse_number(text[at + symbol.len()..].trim())?;
+    Ok(Comparison { operand, compare, value })
+}
+
+fn parse_operand(text: &str) -> Result<Operand, String> {
+    let index = |value: &str, max: u16| match parse_addr(value) {
+        Ok(index) if index <= max => Ok(index),
+        _ => Err(format!("'{}' is out of range", text)),
+    };
+    match text.split_once(':') {
+        Some(("palette", value)) => return index(value, 0x1f).map(Operand::Palette),
+        Some(("oam", value)) => return index(value, 0xff).map(Operand::Oam),
+        Some(("nametables", value)) => {
+            let addr = index(value, 0x3eff)?;
+            return match addr {
+                0x2000..=0x3eff => Ok(Operand::Nametables(addr)),
+                _ => Err(format!("'{}' is out of range", text)),
+            };
+        }
+        _ => {}
+    }
+    match text {
+        "frame" => Ok(Operand::Frame),
+        "ppuctrl" => Ok(Operand::PpuCtrl),
+        "ppumask" => Ok(Operand::PpuMask),
+        "ppustatus" => Ok(Operand::PpuStatus),
+        addr if addr.starts_with('$') => parse_addr(addr).map(Operand::Cpu),
+        _ => Err(format!("'{}' is not something a condition can read", text)),
+    }
+}
+
+fn parse_number(text: &str) -> Result<u64, String> {
+    let parsed = match text.strip_prefix('$').or_else(|| text.strip_prefix("0x")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    parsed.map_err(|_| format!("'{}' is not a number", text))
+}
+
+fn read(operand: Operand, bus: &Bus) -> u64 {
+    let ppu = bus.ppu();
+    let value = match operand {
+        Operand::Frame => return bus.frame_count() as u64,
+        Operand::Cpu(addr) => bus.peek(addr),
+        Operand::Palette(index) => ppu.palette_table[ppu::palette_index(0x3f00 + index)],
+        Operand::Oam(index) => ppu.oam_data[index as usize],
+        Operand::Nametables(addr) => ppu.vram[ppu.mirror_vram_addr(addr) as usize],
+        Operand::PpuCtrl => ppu.ctrl.bits(),
+        Operand::PpuMask => ppu.mask.bits(),
+        Operand::PpuStatus => ppu.status.bits(),
+    };
+    value as u64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+    use crate::cpu::Mem;
+
+    #[test]
+    fn test_parse_and_match() {
+        let mut bus = Bus::new(test_rom(), |_ppu, _joypad1, _joypad2| {});
+        bus.mem_write(0x0075, 3);
+        bus.mem_write(0x2006, 0x3f);
+        bus.mem_write(0x2006, 0x10);
+        bus.mem_write(0x2007, 0x0f);
+
+        let matches = |text: &str| FrameCondition::parse(text).unwrap().matches(&bus);
+        assert!(matches("$0075 == 3"));
+        assert!(matches("$75 >= 0x03 && frame < 1"));
+        assert!(!matches("$0075 != 3"));
+        assert!(matches("$0075 > 3 || palette:00 == $0f"));
+        assert!(!matches("$0075 > 3 || palette:00 == $0f && frame > 0"));
+        assert!(matches("nametables:2000 <= 0 && ppuctrl == 0"));
+
+        assert!(FrameCondition::parse("$0075").is_err());
+        assert!(FrameCondition::parse("oam:100 == 1").is_err());
+        assert!(FrameCondition::parse("lives == 1").is_err());
+        assert!(FrameCondition::parse("$0075 == x").is_err());
+    }
+}
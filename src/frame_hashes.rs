@@ -0,0 +1,119 @@
+// Per-frame picture hashes, for checking a run against an earlier one
+// without keeping every frame around.
+//
+// Frame hash files are "NESH": a usize count, then a u32 for each frame in
+// order, the CRC-32 of the frame's RGB bytes (256x240, three bytes a pixel,
+// row by row).
+
+use crate::bus::Bus;
+use crate::cartridge::SharedMapper;
+use crate::cpu::CPU;
+use crate::region::Region;
+use crate::render::{self, frame::Frame};
+use crate::savestate::{Format, StateReader, StateWriter};
+
+pub const FRAME_HASHES: Format = Format { magic: *b"NESH", version: 1, name: "frame hashes" };
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameHashes {
+    pub hashes: Vec<u32>,
+}
+
+impl Default for FrameHashes {
+    fn default() -> Self {
+        FrameHashes::new()
+    }
+}
+
+impl FrameHashes {
+    pub fn new() -> Self {
+        FrameHashes { hashes: vec![] }
+    }
+
+    pub fn push(&mut self, frame: &Frame) {
+        self.hashes.push(crc32fast::hash(&frame.data));
+    }
+
+    // the first frame where the two runs differ, or where one of them ended
+    pub fn first_difference(&self, other: &FrameHashes) -> Option<usize> {
+        let differs = self.hashes.iter().zip(other.hashes.iter()).position(|(a, b)| a != b);
+        match differs {
+            Some(frame) => Some(frame),
+            None if self.hashes.len() != other.hashes.len() => Some(self.hashes.len().min(other.hashes.len())),
+            None => None,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = StateWriter::with_format(&FRAME_HASHES);
+        writer.usize(self.hashes.len());
+        for hash in self.hashes.iter() {
+            writer.u32(*hash);
+        }
+        writer.finish()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<FrameHashes, String> {
+        let mut reader = StateReader::with_format(data, &FRAME_HASHES)?;
+        let count = reader.usize()?;
+        let mut hashes = vec![];
+        for _ in 0..count {
+            hashes.push(reader.u32()?);
+        }
+        reader.finish()?;
+        Ok(FrameHashes { hashes })
+    }
+}
+
+// Runs the ROM headless with no buttons pressed for `frames` frames and
+// hashes every frame the game presents, like the frontend those are the ones
+// ending in an NMI. The last frame comes back too, to look at when a hash
+// stops matching.
+pub fn run_headless(mapper: SharedMapper, region: Region, frames: usize) -> (FrameHashes, Frame) {
+    let mut frame = Frame::new();
+    let mut hashes = FrameHashes::new();
+    {
+        let bus = Bus::with_mapper(mapper, |ppu, _joypad1, _joypad2| {
+            render::render(ppu, &mut frame);
+            hashes.push(&frame);
+        });
+        let mut cpu = CPU::new(bus);
+        cpu.bus.set_region(region);
+        cpu.reset();
+        cpu.run_with_callback(|cpu| {
+            if cpu.bus.frame_count() >= frames {
+                cpu.stop();
+            }
+        });
+    }
+    hashes.hashes.truncate(frames);
+    (hashes, frame)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip_and_compare() {
+        let mut frame = Frame::new();
+        let mut hashes = FrameHashes::new();
+        hashes.push(&frame);
+        frame.set_pixel(10, 10, (255, 0, 0));
+        hashes.push(&frame);
+        assert_ne!(hashes.hashes[0], hashes.hashes[1]);
+
+        let bytes = hashes.to_bytes();
+        assert_eq!(&bytes[..13], b"NESH\x01\x02\x00\x00\x00\x00\x00\x00\x00");
+        assert_eq!(bytes.len(), 13 + 2 * 4);
+        let loaded = FrameHashes::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded.first_difference(&hashes), None);
+        assert!(FrameHashes::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+
+        let mut other = FrameHashes { hashes: vec![hashes.hashes[0]] };
+        assert_eq!(hashes.first_difference(&other), Some(1));
+        other.hashes.push(0);
+        assert_eq!(hashes.first_difference(&other), Some(1));
+        assert_eq!(FrameHashes { hashes: vec![0] }.first_difference(&other), Some(0));
+    }
+}
@@ -0,0 +1,191 @@
+// Gamepads through SDL's GameController API, which gives every supported pad
+// the same Xbox-style layout. Each pad plays as the first player without
+// one when it's connected, and frees the player again when unplugged; the
+// keyboard keeps working for every player alongside.
+//
+// The buttons are mapped in the bindings file's [gamepad] table, the same for
+// every pad. The left stick works as the dpad past a deadzone.
+
+use crate::startup::{diagnose, Stage};
+use nes_book_emu::bindings::{Bindings, PadInput};
+use nes_book_emu::joypad::JoypadButton;
+use sdl2::controller::{Axis, Button, GameController};
+use sdl2::event::Event;
+use sdl2::GameControllerSubsystem;
+use sdl2::Sdl;
+use std::collections::HashMap;
+
+pub const PLAYERS: usize = 4;
+
+// of the stick's range, ~30%
+const DEADZONE: i16 = 10_000;
+
+// a player's button going down or up
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GamepadInput {
+    pub player: usize,
+    pub input: PadInput,
+    pub pressed: bool,
+}
+
+pub struct Gamepads {
+    subsystem: Option<GameControllerSubsystem>,
+    // by player
+    pads: [Option<GameController>; PLAYERS],
+    buttons: HashMap<Button, PadInput>,
+    // the dpad directions each player's stick holds, and where it is
+    sticks: [JoypadButton; PLAYERS],
+    axes: [(i16, i16); PLAYERS],
+}
+
+impl Gamepads {
+    // Without the subsystem there are no gamepads, only the keyboard. SDL
+    // announces the pads already connected as added, like any hotplug.
+    pub fn open(sdl: &Sdl) -> Self {
+        let subsystem = match sdl.game_controller() {
+            Ok(subsystem) => Some(subsystem),
+            Err(err) => {
+                println!("warning: {}", diagnose(Stage::Input, &err));
+                None
+            }
+        };
+        Gamepads {
+            subsystem,
+            pads: std::array::from_fn(|_| None),
+            buttons: HashMap::new(),
+            sticks: [JoypadButton::empty(); PLAYERS],
+            axes: [(0, 0); PLAYERS],
+        }
+    }
+
+    // keeps the old buttons when a name is unknown
+    pub fn set_bindings(&mut self, bindings: &Bindings) -> Result<(), String> {
+        let mut buttons = HashMap::new();
+        for (input, name) in bindings.gamepad_buttons() {
+            let button = Button::from_string(name).ok_or(format!("unknown gamepad button '{}' for {}", name, input.name()))?;
+            buttons.insert(button, input);
+        }
+        self.buttons = buttons;
+        Ok(())
+    }
+
+    fn player(&self, instance_id: u32) -> Option<usize> {
+        self.pads
+            .iter()
+            .position(|pad| pad.as_ref().is_some_and(|pad| pad.instance_id() == instance_id))
+    }
+
+    // Hotplugs pads and turns their buttons and stick into the players'
+    // input. Events for other devices give nothing.
+    pub fn handle_event(&mut self, event: &Event) -> Vec<GamepadInput> {
+        match *event {
+            Event::ControllerDeviceAdded { which, .. } => {
+                self.connect(which);
+                vec![]
+            }
+            Event::ControllerDeviceRemoved { which, .. } => self.disconnect(which),
+            Event::ControllerButtonDown { which, button, .. } | Event::ControllerButtonUp { which, button, .. } => {
+                let pressed = matches!(event, Event::ControllerButtonDown { .. });
+                match (self.player(which), self.buttons.get(&button)) {
+                    (Some(player), Some(&input)) => vec![GamepadInput { player, input, pressed }],
+                    _ => vec![],
+                }
+            }
+            Event::ControllerAxisMotion { which, axis, value, .. } => {
+                let player = match self.player(which) {
+                    Some(player) => player,
+                    None => return vec![],
+                };
+                match axis {
+                    Axis::LeftX => self.axes[player].0 = value,
+                    Axis::LeftY => self.axes[player].1 = value,
+                    _ => return vec![],
+                }
+                let (x, y) = self.axes[player];
+                self.move_stick(player, stick_buttons(x, y))
+            }
+            _ => vec![],
+        }
+    }
+
+    fn connect(&mut self, joystick_index: u32) {
+        let subsystem = match self.subsystem.as_ref() {
+            Some(subsystem) => subsystem,
+            None => return,
+        };
+        let pad = match subsystem.open(joystick_index) {
+            Ok(pad) => pad,
+            Err(err) => {
+                println!("warning: can't open gamepad {}: {}", joystick_index, err);
+                return;
+            }
+        };
+        // already playing, the pads connected at start-up are added twice
+        if self.player(pad.instance_id()).is_some() {
+            return;
+        }
+        match self.pads.iter().position(|pad| pad.is_none()) {
+            Some(player) => {
+                println!("gamepad {} is player {}", pad.name(), player + 1);
+                self.pads[player] = Some(pad);
+            }
+            None => println!("gamepad {} ignored, all {} players have one", pad.name(), PLAYERS),
+        }
+    }
+
+    // lets go of whatever the pad held
+    fn disconnect(&mut self, instance_id: u32) -> Vec<GamepadInput> {
+        let player = match self.player(instance_id) {
+            Some(player) => player,
+            None => return vec![],
+        };
+        if let Some(pad) = self.pads[player].take() {
+            println!("gamepad {} unplugged, player {} is free", pad.name(), player + 1);
+        }
+        self.axes[player] = (0, 0);
+        self.sticks[player] = JoypadButton::empty();
+        PadInput::ALL.iter().map(|&input| GamepadInput { player, input, pressed: false }).collect()
+    }
+
+    // the directions the stick let go of and newly pushed
+    fn move_stick(&mut self, player: usize, held: JoypadButton) -> Vec<GamepadInput> {
+        let before = self.sticks[player];
+        self.sticks[player] = held;
+        JoypadButton::ALL
+            .iter()
+            .filter(|&&button| before.contains(button) != held.contains(button))
+            .map(|&button| GamepadInput { player, input: PadInput::Button(button), pressed: held.contains(button) })
+            .collect()
+    }
+
+    pub fn rumble(&mut self, intensity: u16, duration_ms: u32) {
+        for pad in self.pads.iter_mut().flatten() {
+            let _ = pad.set_rumble(intensity, intensity, duration_ms);
+        }
+    }
+}
+
+// the dpad directions a stick position holds; SDL's y grows downwards
+fn stick_buttons(x: i16, y: i16) -> JoypadButton {
+    let mut buttons = JoypadButton::empty();
+    buttons.set(JoypadButton::LEFT, x < -DEADZONE);
+    buttons.set(JoypadButton::RIGHT, x > DEADZONE);
+    buttons.set(JoypadButton::UP, y < -DEADZONE);
+    buttons.set(JoypadButton::DOWN, y > DEADZONE);
+    buttons
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_stick_deadzone() {
+        assert_eq!(stick_buttons(0, 0), JoypadButton::empty());
+        // a resting stick drifts a little
+        assert_eq!(stick_buttons(-3000, 8000), JoypadButton::empty());
+        assert_eq!(stick_buttons(i16::MIN, 0), JoypadButton::LEFT);
+        assert_eq!(stick_buttons(20_000, -20_000), JoypadButton::RIGHT | JoypadButton::UP);
+        assert_eq!(stick_buttons(0, i16::MAX), JoypadButton::DOWN);
+    }
+}
@@ -0,0 +1,140 @@
+// Read and write counts for every byte of the 2KB work RAM over a window of
+// frames. Game variables that change every frame light up, which narrows a
+// cheat search down before it starts.
+//
+// The picture has one cell per byte, 64 bytes to a row, so row r column c is
+// $0000 + r * 64 + c. Reads go in the green channel, writes in the red one,
+// both on a log scale so a loop counter doesn't drown everything else.
+
+use std::io::Write;
+
+pub const RAM_SIZE: usize = 2048;
+// about five seconds
+pub const DEFAULT_WINDOW_FRAMES: usize = 300;
+const BYTES_PER_ROW: usize = 64;
+const CELL: usize = 8;
+
+pub struct RamHeatmap {
+    pub reads: Vec<u32>,
+    pub writes: Vec<u32>,
+    pub window: usize,
+    pub frames: usize,
+}
+
+impl RamHeatmap {
+    pub fn new(window: usize) -> Self {
+        RamHeatmap {
+            reads: vec![0; RAM_SIZE],
+            writes: vec![0; RAM_SIZE],
+            window,
+            frames: 0,
+        }
+    }
+
+    // addr is already mirrored down into 0..2048
+    pub fn read(&mut self, addr: usize) {
+        self.reads[addr] = self.reads[addr].saturating_add(1);
+    }
+
+    pub fn write(&mut self, addr: usize) {
+        self.writes[addr] = self.writes[addr].saturating_add(1);
+    }
+
+    // true once the window is full
+    pub fn end_frame(&mut self) -> bool {
+        self.frames += 1;
+        self.frames >= self.window
+    }
+
+    // the `count` most written addresses, reads breaking ties
+    pub fn hottest(&self, count: usize) -> Vec<(u16, u32, u32)> {
+        let mut addrs: Vec<usize> = (0..RAM_SIZE)
+            .filter(|&addr| self.reads[addr] > 0 || self.writes[addr] > 0)
+            .collect();
+        addrs.sort_by_key(|&addr| std::cmp::Reverse((self.writes[addr], self.reads[addr])));
+        addrs
+            .into_iter()
+            .take(count)
+            .map(|addr| (addr as u16, self.reads[addr], self.writes[addr]))
+            .collect()
+    }
+
+    pub fn width(&self) -> usize {
+        BYTES_PER_ROW * CELL
+    }
+
+    pub fn height(&self) -> usize {
+        RAM_SIZE / BYTES_PER_ROW * CELL
+    }
+
+    pub fn to_rgb(&self) -> Vec<u8> {
+        let max = self.reads.iter().chain(self.writes.iter()).copied().max().unwrap_or(0);
+        let scale = |count: u32| -> u8 {
+            if count == 0 {
+                0
+            } else {
+                // anything touched at all stays visible
+                (64.0 + 191.0 * (count as f64).ln_1p() / (max as f64).ln_1p()) as u8
+            }
+        };
+
+        let width = self.width();
+        let mut rgb = vec![0; width * self.height() * 3];
+        for addr in 0..RAM_SIZE {
+            let (x0, y0) = (addr % BYTES_PER_ROW * CELL, addr / BYTES_PER_ROW * CELL);
+            let color = [scale(self.writes[addr]), scale(self.reads[addr]), 0];
+            // the last row and column of each cell stay black as a grid
+            for y in y0..y0 + CELL - 1 {
+                for x in x0..x0 + CELL - 1 {
+                    let base = (y * width + x) * 3;
+                    rgb[base..base + 3].copy_from_slice(&color);
+                }
+            }
+        }
+        rgb
+    }
+
+    pub fn save_ppm(&self, path: &str) -> std::io::Result<()> {
+        let mut file = std::fs::File::create(path)?;
+        write!(file, "P6\n{} {}\n255\n", self.width(), self.height())?;
+        file.write_all(&self.to_rgb())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test::test_rom;
+    use crate::cpu::Mem;
+
+    #[test]
+    fn test_counts_ram_accesses_over_a_window() {
+        let mut bus = Bus::new(test_rom(), |_ppu, _joypad, _joypad2| {});
+        bus.request_heatmap(2);
+        for _ in 0..3 {
+            bus.mem_write(0x0010, 1);
+            bus.mem_write(0x0810, 1); // mirror of $0010
+        }
+        bus.mem_read(0x0300);
+        let heatmap = loop {
+            bus.tick(85);
+            if let Some(heatmap) = bus.take_heatmap() {
+                break heatmap;
+            }
+        };
+
+        assert_eq!(bus.frame_count(), 2);
+        assert_eq!(heatmap.hottest(4), vec![(0x0010, 0, 6), (0x0300, 1, 0)]);
+
+        let rgb = heatmap.to_rgb();
+        assert_eq!(rgb.len(), 512 * 256 * 3);
+        // $0010 is the 17th cell of the first row, all red
+        let cell = (16 * CELL) * 3;
+        assert_eq!(&rgb[cell..cell + 3], &[255, 0, 0]);
+        assert_eq!(&rgb[0..3], &[0, 0, 0]);
+        // $0300 is row 12, column 0
+        let cell = (12 * CELL * heatmap.width()) * 3;
+        assert_eq!(&rgb[cell..cell + 3], &[0, 132, 0]);
+    }
+}
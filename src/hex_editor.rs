@@ -0,0 +1,150 @@
+// The memory behind the hex editor views: the spaces that can be looked at
+// and edited in place, and which of their bytes changed lately so a view can
+// highlight them. The debug server (read_space/write_space) and the debugger
+// window both use it.
+//
+// Reads come from Bus::memory() and never touch hardware registers; edits go
+// straight into the memory with Bus::poke(), not through the CPU's bus, so a
+// VRAM edit doesn't move the PPU's address and a PRG RAM edit doesn't hit
+// mapper registers.
+
+use crate::bus::Bus;
+
+// bytes changed within this many frames count as recent
+pub const RECENT_FRAMES: usize = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemorySpace {
+    // the console's 2kb of work RAM
+    Ram,
+    // $6000-$7FFF on the cartridge, empty when there is none
+    PrgRam,
+    // nametable RAM, 4kb on four-screen boards
+    Vram,
+    Oam,
+    Palette,
+}
+
+impl MemorySpace {
+    pub const ALL: [MemorySpace; 5] =
+        [MemorySpace::Ram, MemorySpace::PrgRam, MemorySpace::Vram, MemorySpace::Oam, MemorySpace::Palette];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            MemorySpace::Ram => "ram",
+            MemorySpace::PrgRam => "prg_ram",
+            MemorySpace::Vram => "vram",
+            MemorySpace::Oam => "oam",
+            MemorySpace::Palette => "palette",
+        }
+    }
+
+    pub fn parse(name: &str) -> Result<MemorySpace, String> {
+        MemorySpace::ALL
+            .iter()
+            .copied()
+            .find(|space| space.name() == name)
+            .ok_or(format!("unknown memory space '{}', expected ram, prg_ram, vram, oam or palette", name))
+    }
+
+    // where offset 0 sits in the address space it belongs to, for the views
+    pub fn base(self) -> u16 {
+        match self {
+            MemorySpace::Ram | MemorySpace::Oam => 0,
+            MemorySpace::PrgRam => 0x6000,
+            MemorySpace::Vram => 0x2000,
+            MemorySpace::Palette => 0x3f00,
+        }
+    }
+}
+
+// Remembers the last contents of every space and the frame each byte last
+// changed in. update() is cheap enough to call once a frame, and again after
+// an edit so the edited bytes light up while paused.
+pub struct HexEditor {
+    previous: Vec<Vec<u8>>,
+    changed_at: Vec<Vec<Option<usize>>>,
+}
+
+impl Default for HexEditor {
+    fn default() -> Self {
+        HexEditor::new()
+    }
+}
+
+impl HexEditor {
+    pub fn new() -> Self {
+        HexEditor {
+            previous: vec![vec![]; MemorySpace::ALL.len()],
+            changed_at: vec![vec![]; MemorySpace::ALL.len()],
+        }
+    }
+
+    pub fn update(&mut self, bus: &Bus) {
+        let frame = bus.frame_count();
+        for (i, space) in MemorySpace::ALL.iter().enumerate() {
+            let data = bus.memory(*space);
+            let previous = &mut self.previous[i];
+            let changed_at = &mut self.changed_at[i];
+            // the first look, or a different board: nothing counts as changed
+            if previous.len() != data.len() {
+                *previous = data;
+                *changed_at = vec![None; previous.len()];
+                continue;
+            }
+            for (offset, (old, new)) in previous.iter_mut().zip(data.iter()).enumerate() {
+                if old != new {
+                    *old = *new;
+                    changed_at[offset] = Some(frame);
+                }
+            }
+        }
+    }
+
+    // frames since the byte last changed, None when it hasn't lately
+    pub fn age(&self, space: MemorySpace, offset: usize, frame: usize) -> Option<usize> {
+        let i = MemorySpace::ALL.iter().position(|other| *other == space).unwrap();
+        let changed = self.changed_at[i].get(offset).copied().flatten()?;
+        Some(frame.saturating_sub(changed)).filter(|age| *age < RECENT_FRAMES)
+    }
+
+    pub fn recent_changes(&self, space: MemorySpace, frame: usize) -> Vec<usize> {
+        let i = MemorySpace::ALL.iter().position(|other| *other == space).unwrap();
+        (0..self.changed_at[i].len()).filter(|offset| self.age(space, *offset, frame).is_some()).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+    use crate::cpu::Mem;
+
+    #[test]
+    fn test_spaces_and_changes() {
+        let mut bus = Bus::new(test_rom(), |_ppu, _joypad1, _joypad2| {});
+        assert_eq!(bus.memory(MemorySpace::Ram).len(), 0x800);
+        assert_eq!(bus.memory(MemorySpace::PrgRam).len(), 0x2000);
+        assert_eq!(bus.memory(MemorySpace::Vram).len(), 0x800);
+        assert_eq!(bus.memory(MemorySpace::Oam).len(), 256);
+        assert_eq!(bus.memory(MemorySpace::Palette).len(), 32);
+        assert_eq!(MemorySpace::parse("oam"), Ok(MemorySpace::Oam));
+        assert!(MemorySpace::parse("chr").is_err());
+
+        let mut editor = HexEditor::new();
+        editor.update(&bus);
+        bus.mem_write(0x0075, 3);
+        bus.poke(MemorySpace::Vram, 0x10, 0x24).unwrap();
+        bus.poke(MemorySpace::Palette, 0, 0x0f).unwrap();
+        assert!(bus.poke(MemorySpace::Oam, 256, 0).is_err());
+        editor.update(&bus);
+
+        assert_eq!(bus.memory(MemorySpace::Vram)[0x10], 0x24);
+        assert_eq!(bus.ppu().palette_table[0], 0x0f);
+        assert_eq!(editor.recent_changes(MemorySpace::Ram, 0), vec![0x75]);
+        assert_eq!(editor.recent_changes(MemorySpace::Vram, 0), vec![0x10]);
+        assert_eq!(editor.age(MemorySpace::Palette, 0, 10), Some(10));
+        assert_eq!(editor.age(MemorySpace::Palette, 0, RECENT_FRAMES), None);
+        assert_eq!(editor.age(MemorySpace::Palette, 1, 0), None);
+    }
+}
@@ -0,0 +1,282 @@
+// Frontend hotkeys, rebound in the [hotkeys] table of the bindings file
+// (bindings.rs):
+//
+// soft_reset = "F8"        # SDL key names, as in "Left Shift", "Q", "Keypad 1"
+// ram_heatmap = ""         # an empty name unbinds
+//
+// Hotkeys not listed keep their defaults. Key names are only checked for
+// clashes here; the frontend turns them into key codes.
+
+use toml::value::Table;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Hotkey {
+    Quit,
+    ListHotkeys,
+    SoftReset,
+    PowerCycle,
+    Pause,
+    FrameAdvance,
+    SaveState,
+    NextSlot,
+    LoadState,
+    Rewind,
+    SpriteZeroOverlay,
+    FrameDump,
+    Timeline,
+    RamHeatmap,
+    UnlockSpeed,
+    RecordMovie,
+    PlayMovie,
+    TraceLog,
+    MutePulse1,
+    MutePulse2,
+    MuteTriangle,
+    MuteNoise,
+    MuteDmc,
+    MuteExpansion,
+    VolumeChannel,
+    VolumeDown,
+    VolumeUp,
+    RecordAudio,
+    ReloadBindings,
+}
+
+impl Hotkey {
+    pub const ALL: [Hotkey; 29] = [
+        Hotkey::Quit,
+        Hotkey::ListHotkeys,
+        Hotkey::SoftReset,
+        Hotkey::PowerCycle,
+        Hotkey::Pause,
+        Hotkey::FrameAdvance,
+        Hotkey::SaveState,
+        Hotkey::NextSlot,
+        Hotkey::LoadState,
+        Hotkey::Rewind,
+        Hotkey::SpriteZeroOverlay,
+        Hotkey::FrameDump,
+        Hotkey::Timeline,
+        Hotkey::RamHeatmap,
+        Hotkey::UnlockSpeed,
+        Hotkey::RecordMovie,
+        Hotkey::PlayMovie,
+        Hotkey::TraceLog,
+        Hotkey::MutePulse1,
+        Hotkey::MutePulse2,
+        Hotkey::MuteTriangle,
+        Hotkey::MuteNoise,
+        Hotkey::MuteDmc,
+        Hotkey::MuteExpansion,
+        Hotkey::VolumeChannel,
+        Hotkey::VolumeDown,
+        Hotkey::VolumeUp,
+        Hotkey::RecordAudio,
+        Hotkey::ReloadBindings,
+    ];
+
+    // the key in the [hotkeys] table
+    pub fn name(self) -> &'static str {
+        match self {
+            Hotkey::Quit => "quit",
+            Hotkey::ListHotkeys => "list_hotkeys",
+            Hotkey::SoftReset => "soft_reset",
+            Hotkey::PowerCycle => "power_cycle",
+            Hotkey::Pause => "pause",
+            Hotkey::FrameAdvance => "frame_advance",
+            Hotkey::SaveState => "save_state",
+            Hotkey::NextSlot => "next_slot",
+            Hotkey::LoadState => "load_state",
+            Hotkey::Rewind => "rewind",
+            Hotkey::SpriteZeroOverlay => "sprite_zero_overlay",
+            Hotkey::FrameDump => "frame_dump",
+            Hotkey::Timeline => "timeline",
+            Hotkey::RamHeatmap => "ram_heatmap",
+            Hotkey::UnlockSpeed => "unlock_speed",
+            Hotkey::RecordMovie => "record_movie",
+            Hotkey::PlayMovie => "play_movie",
+            Hotkey::TraceLog => "trace_log",
+            Hotkey::MutePulse1 => "mute_pulse1",
+            Hotkey::MutePulse2 => "mute_pulse2",
+            Hotkey::MuteTriangle => "mute_triangle",
+            Hotkey::MuteNoise => "mute_noise",
+            Hotkey::MuteDmc => "mute_dmc",
+            Hotkey::MuteExpansion => "mute_expansion",
+            Hotkey::VolumeChannel => "volume_channel",
+            Hotkey::VolumeDown => "volume_down",
+            Hotkey::VolumeUp => "volume_up",
+            Hotkey::RecordAudio => "record_audio",
+            Hotkey::ReloadBindings => "reload_bindings",
+        }
+    }
+
+    pub fn describe(self) -> &'static str {
+        match self {
+            Hotkey::Quit => "quit",
+            Hotkey::ListHotkeys => "list the hotkeys",
+            Hotkey::SoftReset => "reset button",
+            Hotkey::PowerCycle => "power cycle",
+            Hotkey::Pause => "pause/resume",
+            Hotkey::FrameAdvance => "run one frame while paused",
+            Hotkey::SaveState => "save to the selected state slot",
+            Hotkey::NextSlot => "select the next state slot",
+            Hotkey::LoadState => "load the selected state slot",
+            Hotkey::Rewind => "hold to rewind",
+            Hotkey::SpriteZeroOverlay => "sprite 0 hit overlay on/off",
+            Hotkey::FrameDump => "dump the next frame's PPU state",
+            Hotkey::Timeline => "record a frame timeline",
+            Hotkey::RamHeatmap => "count RAM accesses",
+            Hotkey::UnlockSpeed => "run as fast as possible on/off",
+            Hotkey::RecordMovie => "start/stop recording a movie",
+            Hotkey::PlayMovie => "play/stop the recorded movie",
+            Hotkey::TraceLog => "nestest-style trace log to file on/off",
+            Hotkey::MutePulse1 => "mute/unmute pulse 1",
+            Hotkey::MutePulse2 => "mute/unmute pulse 2",
+            Hotkey::MuteTriangle => "mute/unmute the triangle",
+            Hotkey::MuteNoise => "mute/unmute noise",
+            Hotkey::MuteDmc => "mute/unmute the DMC",
+            Hotkey::MuteExpansion => "mute/unmute expansion audio",
+            Hotkey::VolumeChannel => "select the channel the volume keys change",
+            Hotkey::VolumeDown => "selected channel 10% quieter",
+            Hotkey::VolumeUp => "selected channel 10% louder",
+            Hotkey::RecordAudio => "start/stop recording the sound to a WAV file",
+            Hotkey::ReloadBindings => "reread the bindings file",
+        }
+    }
+
+    fn default_key(self) -> &'static str {
+        match self {
+            Hotkey::Quit => "Escape",
+            Hotkey::ListHotkeys => "F1",
+            Hotkey::SoftReset => "F2",
+            Hotkey::PowerCycle => "F3",
+            Hotkey::Pause => "P",
+            Hotkey::FrameAdvance => "\\",
+            Hotkey::SaveState => "F5",
+            Hotkey::NextSlot => "F6",
+            Hotkey::LoadState => "F7",
+            Hotkey::Rewind => "Backspace",
+            Hotkey::SpriteZeroOverlay => "F4",
+            Hotkey::FrameDump => "F9",
+            Hotkey::Timeline => "F10",
+            Hotkey::RamHeatmap => "F11",
+            Hotkey::UnlockSpeed => "Tab",
+            Hotkey::RecordMovie => "F12",
+            Hotkey::PlayMovie => "F8",
+            Hotkey::TraceLog => "T",
+            Hotkey::MutePulse1 => "1",
+            Hotkey::MutePulse2 => "2",
+            Hotkey::MuteTriangle => "3",
+            Hotkey::MuteNoise => "4",
+            Hotkey::MuteDmc => "5",
+            Hotkey::MuteExpansion => "6",
+            Hotkey::VolumeChannel => "7",
+            Hotkey::VolumeDown => "-",
+            Hotkey::VolumeUp => "=",
+            Hotkey::RecordAudio => "R",
+            Hotkey::ReloadBindings => "B",
+        }
+    }
+}
+
+pub struct Hotkeys {
+    // in Hotkey::ALL order, None when unbound
+    keys: Vec<Option<String>>,
+}
+
+impl Default for Hotkeys {
+    fn default() -> Self {
+        Hotkeys::new()
+    }
+}
+
+impl Hotkeys {
+    pub fn new() -> Self {
+        Hotkeys {
+            keys: Hotkey::ALL.iter().map(|hotkey| Some(hotkey.default_key().to_string())).collect(),
+        }
+    }
+
+    pub fn from_table(table: &Table) -> Result<Hotkeys, String> {
+        let mut hotkeys = Hotkeys::new();
+        for (name, value) in table {
+            let i = Hotkey::ALL
+                .iter()
+                .position(|hotkey| hotkey.name() == name)
+                .ok_or(format!("hotkeys: unknown hotkey '{}'", name))?;
+            let key = value.as_str().ok_or(format!("hotkeys: {} should be a key name", name))?;
+            hotkeys.keys[i] = if key.is_empty() { None } else { Some(key.to_string()) };
+        }
+        Ok(hotkeys)
+    }
+
+    pub fn key(&self, hotkey: Hotkey) -> Option<&str> {
+        let i = Hotkey::ALL.iter().position(|other| *other == hotkey).unwrap();
+        self.keys[i].as_deref()
+    }
+
+    // the bound hotkeys with their key names
+    pub fn bindings(&self) -> impl Iterator<Item = (Hotkey, &str)> + '_ {
+        Hotkey::ALL.iter().filter_map(move |&hotkey| self.key(hotkey).map(|key| (hotkey, key)))
+    }
+
+    // Keys bound twice: between hotkeys, and between a hotkey and game input,
+    // given as (key name, what it does). Key names compare like SDL does,
+    // ignoring case.
+    pub fn conflicts(&self, game_keys: &[(String, String)]) -> Vec<String> {
+        let mut conflicts = vec![];
+        let bindings: Vec<(Hotkey, &str)> = self.bindings().collect();
+        for (i, (hotkey, key)) in bindings.iter().enumerate() {
+            for (other, other_key) in &bindings[i + 1..] {
+                if key.eq_ignore_ascii_case(other_key) {
+                    conflicts.push(format!("{} is bound to both {} and {}", key, hotkey.name(), other.name()));
+                }
+            }
+            for (game_key, action) in game_keys {
+                if key.eq_ignore_ascii_case(game_key) {
+                    conflicts.push(format!("{} is bound to both {} and {}", key, hotkey.name(), action));
+                }
+            }
+        }
+        conflicts
+    }
+
+    pub fn describe(&self) -> String {
+        self.bindings()
+            .map(|(hotkey, key)| format!("  {:<8} {}", key, hotkey.describe()))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use toml::Value;
+
+    fn parse(text: &str) -> Result<Hotkeys, String> {
+        Hotkeys::from_table(text.parse::<Value>().unwrap().as_table().unwrap())
+    }
+
+    #[test]
+    fn test_parse_and_conflicts() {
+        let hotkeys = parse("soft_reset = \"F5\"\nram_heatmap = \"\"").unwrap();
+        assert_eq!(hotkeys.key(Hotkey::SoftReset), Some("F5"));
+        assert_eq!(hotkeys.key(Hotkey::PowerCycle), Some("F3"));
+        assert_eq!(hotkeys.key(Hotkey::RamHeatmap), None);
+        assert_eq!(hotkeys.bindings().count(), Hotkey::ALL.len() - 1);
+        assert!(parse("fast_forward = \"R\"").is_err());
+        assert!(parse("quit = 1").is_err());
+
+        let game_keys = vec![("k".to_string(), "player 1 A".to_string())];
+        assert!(Hotkeys::new().conflicts(&game_keys).is_empty());
+        let hotkeys = parse("timeline = \"K\"\nframe_dump = \"f1\"").unwrap();
+        assert_eq!(
+            hotkeys.conflicts(&game_keys),
+            vec![
+                "F1 is bound to both list_hotkeys and frame_dump".to_string(),
+                "K is bound to both timeline and player 1 A".to_string(),
+            ]
+        );
+    }
+}
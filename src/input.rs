@@ -0,0 +1,350 @@
+// What plugs into the controller ports. The bus holds one InputDevice per
+// port: a $4016 write reaches both (bit 0 is the strobe), and a read of $4016
+// or $4017 returns whatever the device in port 1 or 2 drives on D0-D4.
+//
+// Standard controllers are Joypads (joypad.rs); a Four Score is a half in
+// each port with two of them behind it. The Zapper and the Arkanoid
+// paddle are aimed with the mouse: the frontend hands them a pointer in NES
+// pixels through set_pointer(). A Zapper only sees light where the picture is
+// bright while the beam passes its aim, which the bus works out from the PPU
+// before each read (see Bus::read_port).
+
+use crate::joypad::{DeviceType, Joypad, JoypadButton};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+// scanlines a Zapper's photodiode keeps seeing light after the beam passed
+pub const LIGHT_LINES: u16 = 20;
+
+pub trait InputDevice: Savestate {
+    fn device_type(&self) -> DeviceType;
+
+    // bit 0 of a $4016 write
+    fn write(&mut self, data: u8);
+
+    // D0-D4 of a read of the port
+    fn read(&mut self) -> u8;
+
+    // the standard controllers plugged in through this device, first one first
+    fn joypads(&mut self) -> Vec<&mut Joypad> {
+        vec![]
+    }
+
+    // what the first player on the port holds, if they have a controller
+    fn set_buttons(&mut self, buttons: JoypadButton) {
+        if let Some(joypad) = self.joypads().into_iter().next() {
+            joypad.set_buttons(buttons);
+        }
+    }
+
+    // the mouse, in NES pixels, and whether its button is down
+    fn set_pointer(&mut self, _x: u8, _y: u8, _pressed: bool) {}
+
+    // where a light gun points; the bus calls sense_light() before each read
+    fn aim(&self) -> Option<(u8, u8)> {
+        None
+    }
+
+    fn sense_light(&mut self, _light: bool) {}
+}
+
+// `port` 0 or 1
+pub fn create(device_type: DeviceType, port: usize) -> Box<dyn InputDevice> {
+    match device_type {
+        DeviceType::Standard => Box::new(Joypad::new()),
+        DeviceType::FourScore => Box::new(FourScore::new(port)),
+        DeviceType::Zapper => Box::new(Zapper::new()),
+        DeviceType::Paddle => Box::new(Paddle::new()),
+    }
+}
+
+impl InputDevice for Joypad {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Standard
+    }
+
+    fn write(&mut self, data: u8) {
+        Joypad::write(self, data);
+    }
+
+    fn read(&mut self) -> u8 {
+        Joypad::read(self)
+    }
+
+    fn joypads(&mut self) -> Vec<&mut Joypad> {
+        vec![self]
+    }
+}
+
+// https://www.nesdev.org/wiki/Four_Score
+// Each port reports 24 bits: the 8 buttons of its first controller (players
+// 1 and 2), those of its second (players 3 and 4), then a signature that
+// tells games it's there, $10 on port 1 and $20 on port 2. Reads after that
+// return 1.
+pub struct FourScore {
+    // only hold the buttons, the Four Score does the shifting
+    pads: [Joypad; 2],
+    signature: u8,
+    strobe: bool,
+    index: u8,
+}
+
+impl FourScore {
+    pub fn new(port: usize) -> Self {
+        FourScore { pads: [Joypad::new(), Joypad::new()], signature: 0x10 << port, strobe: false, index: 0 }
+    }
+}
+
+impl InputDevice for FourScore {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::FourScore
+    }
+
+    fn write(&mut self, data: u8) {
+        self.strobe = data & 1 == 1;
+        if self.strobe {
+            self.index = 0;
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        let byte = match self.index / 8 {
+            0 => self.pads[0].buttons().bits(),
+            1 => self.pads[1].buttons().bits(),
+            2 => self.signature,
+            _ => return 1,
+        };
+        let bit = (byte >> (self.index % 8)) & 1;
+        if !self.strobe {
+            self.index += 1;
+        }
+        bit
+    }
+
+    fn joypads(&mut self) -> Vec<&mut Joypad> {
+        self.pads.iter_mut().collect()
+    }
+}
+
+impl Savestate for FourScore {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.pads[0].save_state(state);
+        self.pads[1].save_state(state);
+        state.bool(self.strobe);
+        state.u8(self.index);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.pads[0].load_state(state)?;
+        self.pads[1].load_state(state)?;
+        self.strobe = state.bool()?;
+        self.index = state.u8()?;
+        Ok(())
+    }
+}
+
+// https://www.nesdev.org/wiki/Zapper
+// D3 is 0 while it sees light, D4 is 1 while the trigger is pulled. It has no
+// shift register and ignores the strobe.
+pub struct Zapper {
+    // None while pointing off screen
+    aim: Option<(u8, u8)>,
+    trigger: bool,
+    light: bool,
+}
+
+impl Default for Zapper {
+    fn default() -> Self {
+        Zapper::new()
+    }
+}
+
+impl Zapper {
+    pub fn new() -> Self {
+        Zapper { aim: None, trigger: false, light: false }
+    }
+}
+
+impl InputDevice for Zapper {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Zapper
+    }
+
+    fn write(&mut self, _data: u8) {}
+
+    fn read(&mut self) -> u8 {
+        let light = if self.light { 0 } else { 0x08 };
+        let trigger = if self.trigger { 0x10 } else { 0 };
+        light | trigger
+    }
+
+    fn set_pointer(&mut self, x: u8, y: u8, pressed: bool) {
+        self.aim = (y < 240).then_some((x, y));
+        self.trigger = pressed;
+    }
+
+    fn aim(&self) -> Option<(u8, u8)> {
+        self.aim
+    }
+
+    fn sense_light(&mut self, light: bool) {
+        self.light = light;
+    }
+}
+
+impl Savestate for Zapper {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.trigger);
+        state.bool(self.light);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.trigger = state.bool()?;
+        self.light = state.bool()?;
+        Ok(())
+    }
+}
+
+// The NES Arkanoid controller (Vaus): https://www.nesdev.org/wiki/Arkanoid_controller
+// The strobe latches the knob's position, which shifts out inverted on D4,
+// most significant bit first; D3 is the fire button.
+pub struct Paddle {
+    position: u8,
+    button: bool,
+    strobe: bool,
+    shift: u8,
+}
+
+// the knob's range on a real controller
+const PADDLE_MIN: u8 = 98;
+const PADDLE_MAX: u8 = 242;
+
+impl Default for Paddle {
+    fn default() -> Self {
+        Paddle::new()
+    }
+}
+
+impl Paddle {
+    pub fn new() -> Self {
+        Paddle { position: PADDLE_MIN, button: false, strobe: false, shift: 0 }
+    }
+}
+
+impl InputDevice for Paddle {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Paddle
+    }
+
+    fn write(&mut self, data: u8) {
+        self.strobe = data & 1 == 1;
+        if self.strobe {
+            self.shift = !self.position;
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        if self.strobe {
+            self.shift = !self.position;
+        }
+        let data = (self.shift & 0x80) >> 3;
+        if !self.strobe {
+            self.shift <<= 1;
+        }
+        let button = if self.button { 0x08 } else { 0 };
+        data | button
+    }
+
+    // the mouse across the screen turns the knob through its range
+    fn set_pointer(&mut self, x: u8, _y: u8, pressed: bool) {
+        let range = (PADDLE_MAX - PADDLE_MIN) as u16;
+        self.position = PADDLE_MIN + (x as u16 * range / 255) as u8;
+        self.button = pressed;
+    }
+}
+
+impl Savestate for Paddle {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.position);
+        state.bool(self.button);
+        state.bool(self.strobe);
+        state.u8(self.shift);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.position = state.u8()?;
+        self.button = state.bool()?;
+        self.strobe = state.bool()?;
+        self.shift = state.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_devices_on_the_port() {
+        let mut port = create(DeviceType::Standard, 0);
+        port.set_buttons(JoypadButton::BUTTON_B);
+        assert_eq!(port.joypads().len(), 1);
+        port.write(1);
+        port.write(0);
+        let bits: Vec<u8> = (0..9).map(|_| port.read()).collect();
+        assert_eq!(bits, vec![0, 1, 0, 0, 0, 0, 0, 0, 1]);
+
+        let mut zapper = create(DeviceType::Zapper, 1);
+        assert!(zapper.joypads().is_empty());
+        zapper.set_pointer(100, 50, true);
+        assert_eq!(zapper.aim(), Some((100, 50)));
+        // no light, trigger pulled
+        assert_eq!(zapper.read(), 0x18);
+        zapper.sense_light(true);
+        zapper.write(1);
+        assert_eq!(zapper.read(), 0x10);
+        // off screen, it aims nowhere
+        zapper.set_pointer(100, 250, false);
+        assert_eq!(zapper.aim(), None);
+    }
+
+    #[test]
+    fn test_four_score_report() {
+        for (port, signature) in [(0, 0x10u8), (1, 0x20)] {
+            let mut four_score = create(DeviceType::FourScore, port);
+            let mut pads = four_score.joypads();
+            pads[0].set_buttons(JoypadButton::START);
+            pads[1].set_buttons(JoypadButton::BUTTON_A | JoypadButton::RIGHT);
+            // strobed, it keeps returning the first controller's A
+            four_score.write(1);
+            assert_eq!(four_score.read(), 0);
+            assert_eq!(four_score.read(), 0);
+            four_score.write(0);
+            let bits: Vec<u8> = (0..26).map(|_| four_score.read()).collect();
+            let byte = |i: usize| bits[i * 8..i * 8 + 8].iter().rev().fold(0, |byte, bit| (byte << 1) | bit);
+            assert_eq!(byte(0), JoypadButton::START.bits());
+            assert_eq!(byte(1), (JoypadButton::BUTTON_A | JoypadButton::RIGHT).bits());
+            assert_eq!(byte(2), signature);
+            assert_eq!(&bits[24..], &[1, 1]);
+        }
+    }
+
+    #[test]
+    fn test_paddle_shifts_out_inverted_position() {
+        let mut paddle = Paddle::new();
+        paddle.set_pointer(255, 0, true);
+        assert_eq!(paddle.position, PADDLE_MAX);
+        // strobed, the first bit repeats
+        paddle.write(1);
+        assert_eq!(paddle.read(), 0x08);
+        assert_eq!(paddle.read(), 0x08);
+        paddle.write(0);
+        let position = (0..8).fold(0u8, |value, _| {
+            let data = paddle.read();
+            assert_eq!(data & 0x08, 0x08);
+            (value << 1) | ((data >> 4) & 1)
+        });
+        assert_eq!(!position, PADDLE_MAX);
+        paddle.set_pointer(0, 0, false);
+        assert_eq!(paddle.position, PADDLE_MIN);
+    }
+}
@@ -1,3 +1,5 @@
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
 bitflags! {
     pub struct JoypadButton: u8 {
         const RIGHT             = 0b10000000;
@@ -11,6 +13,190 @@ bitflags! {
     }
 }
 
+impl JoypadButton {
+    pub const ALL: [JoypadButton; 8] = [
+        JoypadButton::BUTTON_A,
+        JoypadButton::BUTTON_B,
+        JoypadButton::SELECT,
+        JoypadButton::START,
+        JoypadButton::UP,
+        JoypadButton::DOWN,
+        JoypadButton::LEFT,
+        JoypadButton::RIGHT,
+    ];
+
+    // the name parse() takes, for a single button
+    pub fn name(self) -> &'static str {
+        match self {
+            JoypadButton::BUTTON_A => "a",
+            JoypadButton::BUTTON_B => "b",
+            JoypadButton::SELECT => "select",
+            JoypadButton::START => "start",
+            JoypadButton::UP => "up",
+            JoypadButton::DOWN => "down",
+            JoypadButton::LEFT => "left",
+            JoypadButton::RIGHT => "right",
+            _ => "buttons",
+        }
+    }
+
+    // names as used in settings files: a, b, select, start, up, down, left, right
+    pub fn parse(name: &str) -> Result<JoypadButton, String> {
+        match name.to_lowercase().as_str() {
+            "a" => Ok(JoypadButton::BUTTON_A),
+            "b" => Ok(JoypadButton::BUTTON_B),
+            "select" => Ok(JoypadButton::SELECT),
+            "start" => Ok(JoypadButton::START),
+            "up" => Ok(JoypadButton::UP),
+            "down" => Ok(JoypadButton::DOWN),
+            "left" => Ok(JoypadButton::LEFT),
+            "right" => Ok(JoypadButton::RIGHT),
+            other => Err(format!("unknown button '{}'", other)),
+        }
+    }
+}
+
+// The kinds of device that plug into the controller ports (input.rs), as a
+// ROM header or settings file asks for them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeviceType {
+    Standard,
+    FourScore,
+    Zapper,
+    Paddle,
+}
+
+impl DeviceType {
+    // names as used in settings files
+    pub fn parse(name: &str) -> Result<DeviceType, String> {
+        match name {
+            "standard" => Ok(DeviceType::Standard),
+            "four-score" => Ok(DeviceType::FourScore),
+            "zapper" => Ok(DeviceType::Zapper),
+            "paddle" => Ok(DeviceType::Paddle),
+            other => Err(format!("unknown input device '{}', expected standard, four-score, zapper or paddle", other)),
+        }
+    }
+
+    // https://www.nesdev.org/wiki/NES_2.0#Default_Expansion_Device
+    // None for unspecified and for devices not listed here
+    pub fn from_expansion_device(device: u8) -> Option<DeviceType> {
+        match device {
+            0x01 => Some(DeviceType::Standard),
+            // NES Four Score, Famicom four players adapter
+            0x02 | 0x03 => Some(DeviceType::FourScore),
+            // Vs. zapper, zapper in port 2, two zappers
+            0x07..=0x09 => Some(DeviceType::Zapper),
+            // Arkanoid Vaus, NES and Famicom
+            0x0f | 0x10 => Some(DeviceType::Paddle),
+            _ => None,
+        }
+    }
+
+    // the ROM header wins, then the settings file, then standard controllers
+    pub fn select(expansion_device: u8, configured: Option<DeviceType>) -> DeviceType {
+        DeviceType::from_expansion_device(expansion_device)
+            .or(configured)
+            .unwrap_or(DeviceType::Standard)
+    }
+}
+
+// Accessibility: toggle buttons latch on one press and let go on the next, so
+// they never have to be held down. Auto-held buttons are toggles that start
+// latched.
+pub struct ButtonLatches {
+    toggle: JoypadButton,
+    latched: JoypadButton,
+    // ordinary buttons whose key is down
+    held: JoypadButton,
+}
+
+impl ButtonLatches {
+    pub fn new(toggle: JoypadButton, auto_hold: JoypadButton) -> Self {
+        ButtonLatches {
+            toggle: toggle | auto_hold,
+            latched: auto_hold,
+            held: JoypadButton::empty(),
+        }
+    }
+
+    pub fn latched(&self) -> JoypadButton {
+        self.latched
+    }
+
+    // everything down, held or latched
+    pub fn pressed(&self) -> JoypadButton {
+        self.held | self.latched
+    }
+
+    // Key repeats are ignored so a held key doesn't flicker the latch.
+    // Returns true when the latches changed.
+    pub fn key_down(&mut self, joypad: &mut Joypad, button: JoypadButton, repeat: bool) -> bool {
+        if !self.toggle.contains(button) {
+            self.held.insert(button);
+            joypad.set_button_pressed_status(button, true);
+            return false;
+        }
+        if repeat {
+            return false;
+        }
+        self.latched.toggle(button);
+        joypad.set_button_pressed_status(button, self.latched.contains(button));
+        true
+    }
+
+    pub fn key_up(&mut self, joypad: &mut Joypad, button: JoypadButton) {
+        if !self.toggle.contains(button) {
+            self.held.remove(button);
+            joypad.set_button_pressed_status(button, false);
+        }
+    }
+}
+
+// Turbo buttons: while their key is held, the button goes down for
+// `frames_on` frames and up for `frames_off`, starting down. The frontend ORs
+// it with the held and latched buttons as it polls input for each frame, so
+// the button's own key still holds it through the up frames, and ticks it
+// once per real frame, so run-ahead frames see the same buttons.
+pub struct Turbo {
+    frames_on: u8,
+    frames_off: u8,
+    held: JoypadButton,
+    // into the current on/off cycle
+    frame: u8,
+}
+
+impl Turbo {
+    // both at least 1
+    pub fn new(frames_on: u8, frames_off: u8) -> Self {
+        Turbo { frames_on, frames_off, held: JoypadButton::empty(), frame: 0 }
+    }
+
+    pub fn key_down(&mut self, button: JoypadButton) {
+        if self.held.is_empty() {
+            self.frame = 0;
+        }
+        self.held.insert(button);
+    }
+
+    pub fn key_up(&mut self, button: JoypadButton) {
+        self.held.remove(button);
+    }
+
+    // the turbo buttons down this frame
+    pub fn pressed(&self) -> JoypadButton {
+        if self.frame < self.frames_on {
+            self.held
+        } else {
+            JoypadButton::empty()
+        }
+    }
+
+    pub fn tick(&mut self) {
+        self.frame = if self.held.is_empty() { 0 } else { (self.frame + 1) % (self.frames_on + self.frames_off) };
+    }
+}
+
 pub struct Joypad {
     strobe: bool,
     button_index: u8,
@@ -47,6 +233,50 @@ impl Joypad {
     pub fn set_button_pressed_status(&mut self, button: JoypadButton, pressed: bool) {
         self.button_status.set(button, pressed);
     }
+
+    pub fn set_buttons(&mut self, buttons: JoypadButton) {
+        self.button_status = buttons;
+    }
+
+    // what is held right now, for recording
+    pub fn buttons(&self) -> JoypadButton {
+        self.button_status
+    }
+}
+
+impl Savestate for Joypad {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.strobe);
+        state.u8(self.button_index);
+        state.u8(self.button_status.bits);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.strobe = state.bool()?;
+        self.button_index = state.u8()?;
+        self.button_status = JoypadButton::from_bits_truncate(state.u8()?);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<&Joypad> for crate::serde_state::JoypadState {
+    fn from(joypad: &Joypad) -> Self {
+        crate::serde_state::JoypadState {
+            strobe: joypad.strobe,
+            button_index: joypad.button_index,
+            buttons: joypad.button_status.bits,
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl crate::serde_state::JoypadState {
+    pub fn apply(&self, joypad: &mut Joypad) {
+        joypad.strobe = self.strobe;
+        joypad.button_index = self.button_index;
+        joypad.button_status = JoypadButton::from_bits_truncate(self.buttons);
+    }
 }
 
 #[cfg(test)]
@@ -63,6 +293,80 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_toggle_and_auto_hold_buttons() {
+        let mut joypad = Joypad::new();
+        let mut latches = ButtonLatches::new(JoypadButton::BUTTON_A, JoypadButton::BUTTON_B);
+        assert_eq!(latches.pressed(), JoypadButton::BUTTON_B);
+        joypad.set_buttons(latches.pressed());
+
+        // A latches on the first press and stays down after the key is let go
+        assert!(latches.key_down(&mut joypad, JoypadButton::BUTTON_A, false));
+        latches.key_up(&mut joypad, JoypadButton::BUTTON_A);
+        assert!(!latches.key_down(&mut joypad, JoypadButton::BUTTON_A, true));
+        assert_eq!(joypad.button_status, JoypadButton::BUTTON_A | JoypadButton::BUTTON_B);
+
+        // pressing the auto-held B lets it go; ordinary buttons follow the key
+        latches.key_down(&mut joypad, JoypadButton::BUTTON_B, false);
+        assert!(!latches.key_down(&mut joypad, JoypadButton::UP, false));
+        assert_eq!(joypad.button_status, JoypadButton::BUTTON_A | JoypadButton::UP);
+        assert_eq!(latches.pressed(), JoypadButton::BUTTON_A | JoypadButton::UP);
+        latches.key_up(&mut joypad, JoypadButton::UP);
+        latches.key_down(&mut joypad, JoypadButton::BUTTON_A, false);
+        assert_eq!(joypad.button_status, JoypadButton::empty());
+        assert_eq!(latches.latched(), JoypadButton::empty());
+
+        assert_eq!(JoypadButton::parse("Start"), Ok(JoypadButton::START));
+        assert!(JoypadButton::parse("turbo").is_err());
+    }
+
+    #[test]
+    fn test_turbo_rate() {
+        let mut turbo = Turbo::new(2, 1);
+        let mut pressed = vec![];
+        turbo.key_down(JoypadButton::BUTTON_A);
+        for _ in 0..7 {
+            pressed.push(turbo.pressed().contains(JoypadButton::BUTTON_A));
+            turbo.tick();
+        }
+        assert_eq!(pressed, vec![true, true, false, true, true, false, true]);
+
+        // letting go releases the button at once, the next press starts down
+        turbo.key_up(JoypadButton::BUTTON_A);
+        assert_eq!(turbo.pressed(), JoypadButton::empty());
+        turbo.tick();
+        turbo.key_down(JoypadButton::BUTTON_B);
+        assert_eq!(turbo.pressed(), JoypadButton::BUTTON_B);
+    }
+
+    #[test]
+    fn test_turbo_with_the_button_held() {
+        let mut joypad = Joypad::new();
+        let mut latches = ButtonLatches::new(JoypadButton::empty(), JoypadButton::empty());
+        let mut turbo = Turbo::new(1, 1);
+        latches.key_down(&mut joypad, JoypadButton::BUTTON_A, false);
+        turbo.key_down(JoypadButton::BUTTON_A);
+        turbo.key_down(JoypadButton::BUTTON_B);
+        // the up frames only let go of B, the key holds A
+        turbo.tick();
+        assert_eq!(latches.pressed() | turbo.pressed(), JoypadButton::BUTTON_A);
+        turbo.key_up(JoypadButton::BUTTON_A);
+        turbo.tick();
+        assert_eq!(latches.pressed() | turbo.pressed(), JoypadButton::BUTTON_A | JoypadButton::BUTTON_B);
+    }
+
+    #[test]
+    fn test_input_device_from_header_then_settings() {
+        assert_eq!(DeviceType::select(0x08, Some(DeviceType::Paddle)), DeviceType::Zapper);
+        assert_eq!(DeviceType::select(0x02, None), DeviceType::FourScore);
+        // unspecified, or a device we don't know: the settings decide
+        assert_eq!(DeviceType::select(0x00, Some(DeviceType::Paddle)), DeviceType::Paddle);
+        assert_eq!(DeviceType::select(0x2a, Some(DeviceType::Zapper)), DeviceType::Zapper);
+        assert_eq!(DeviceType::select(0x00, None), DeviceType::Standard);
+        assert_eq!(DeviceType::parse("four-score"), Ok(DeviceType::FourScore));
+        assert!(DeviceType::parse("keyboard").is_err());
+    }
+
     #[test]
     fn test_strobe_mode_on_off() {
         let mut joypad = Joypad::new();
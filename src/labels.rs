@@ -0,0 +1,311 @@
+// Names for addresses, shown in place of $addresses in the trace log and the
+// debugger's disassembly. Read from:
+//
+// - FCEUX name lists next to the ROM: game.nes.ram.nl for RAM and
+//   game.nes.<bank>.nl for each 16kb PRG bank, lines like
+//     $C000#Reset#comment
+//     $0300/10#Buffer#      (ten bytes: Buffer, Buffer+1, ...)
+// - Mesen label files (.mlb), lines like
+//     P:0123:Reset:comment  (P PRG ROM offset, R RAM, S/W cartridge RAM, G CPU address)
+//   or with Mesen 2's NesPrgRom/NesInternalRam/NesWorkRam/NesSaveRam/NesMemory
+// - ca65 debug info (.dbg, ld65 --dbgfile), its label symbols
+//
+// Labels on PRG ROM are kept by ROM offset, so a bank switched in at the
+// same address shows its own names.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+const PRG_BANK_SIZE: usize = 0x4000;
+
+pub struct Labels {
+    cpu: HashMap<u16, String>,
+    prg: HashMap<usize, String>,
+    // for going from a name back to an address, where the file tells it
+    addresses: HashMap<String, u16>,
+}
+
+impl Default for Labels {
+    fn default() -> Self {
+        Labels::new()
+    }
+}
+
+impl Labels {
+    pub fn new() -> Self {
+        Labels {
+            cpu: HashMap::new(),
+            prg: HashMap::new(),
+            addresses: HashMap::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cpu.is_empty() && self.prg.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.cpu.len() + self.prg.len()
+    }
+
+    // `size` bytes from `addr` on, the ones after the first as name+1, name+2...
+    fn add_cpu(&mut self, addr: u16, size: u16, name: &str) {
+        for i in 0..size.max(1) {
+            self.cpu.insert(addr.wrapping_add(i), element_name(name, i as usize));
+        }
+        self.addresses.insert(name.to_string(), addr);
+    }
+
+    fn add_prg(&mut self, offset: usize, addr: Option<u16>, size: usize, name: &str) {
+        for i in 0..size.max(1) {
+            self.prg.insert(offset + i, element_name(name, i));
+        }
+        if let Some(addr) = addr {
+            self.addresses.insert(name.to_string(), addr);
+        }
+    }
+
+    // An FCEUX .nl file, `bank` being the 16kb PRG bank it describes or None
+    // for the .ram.nl file.
+    pub fn parse_nl(&mut self, text: &str, bank: Option<usize>) -> Result<(), String> {
+        for (number, line) in text.lines().enumerate() {
+            // comments go on over lines starting with a backslash
+            if !line.starts_with('$') {
+                continue;
+            }
+            let mut fields = line[1..].splitn(3, '#');
+            let target = fields.next().unwrap_or("");
+            let name = fields.next().unwrap_or("").trim();
+            if name.is_empty() {
+                continue;
+            }
+            let (addr, size) = match target.split_once('/') {
+                Some((addr, size)) => (addr, u16::from_str_radix(size, 16).ok()),
+                None => (target, Some(1)),
+            };
+            let addr = u16::from_str_radix(addr, 16).ok();
+            let (addr, size) = match (addr, size) {
+                (Some(addr), Some(size)) => (addr, size),
+                _ => return Err(format!("line {}: '{}' should look like $C000#Name#", number + 1, line)),
+            };
+            match bank {
+                Some(bank) if addr >= 0x8000 => {
+                    let offset = bank * PRG_BANK_SIZE + (addr as usize - 0x8000) % PRG_BANK_SIZE;
+                    self.add_prg(offset, Some(addr), size as usize, name);
+                }
+                _ => self.add_cpu(addr, size, name),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn parse_mlb(&mut self, text: &str) -> Result<(), String> {
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.splitn(4, ':').collect();
+            if fields.len() < 3 {
+                return Err(format!("line {}: '{}' should look like P:0123:Name", number + 1, line));
+            }
+            let name = fields[2].trim();
+            // comment-only lines
+            if name.is_empty() {
+                continue;
+            }
+            let (from, to) = match fields[1].split_once('-') {
+                Some((from, to)) => (from, to),
+                None => (fields[1], fields[1]),
+            };
+            let (from, to) = match (usize::from_str_radix(from, 16), usize::from_str_radix(to, 16)) {
+                (Ok(from), Ok(to)) if from <= to => (from, to),
+                _ => return Err(format!("line {}: '{}' is not an address", number + 1, fields[1])),
+            };
+            let size = to - from + 1;
+            match fields[0] {
+                "P" | "NesPrgRom" => self.add_prg(from, None, size, name),
+                "R" | "NesInternalRam" => self.add_cpu(from as u16 & 0x7ff, size as u16, name),
+                "S" | "W" | "NesSaveRam" | "NesWorkRam" => self.add_cpu(0x6000 + (from as u16 & 0x1fff), size as u16, name),
+                "G" | "NesMemory" => self.add_cpu(from as u16, size as u16, name),
+                // CHR, nametables and other memory the CPU doesn't address
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // ca65 debug info: `sym id=0,name="Reset",...,val=0xC000,...,type=lab`
+    pub fn parse_dbg(&mut self, text: &str) -> Result<(), String> {
+        for line in text.lines() {
+            let fields = match line.strip_prefix("sym") {
+                Some(fields) => fields.trim(),
+                None => continue,
+            };
+            let field = |key: &str| {
+                fields
+                    .split(',')
+                    .find_map(|field| field.strip_prefix(key).and_then(|rest| rest.strip_prefix('=')))
+            };
+            if field("type") != Some("lab") {
+                continue;
+            }
+            let name = field("name").unwrap_or("").trim_matches('"');
+            let val = field("val").and_then(|val| u16::from_str_radix(val.trim_start_matches("0x"), 16).ok());
+            // @cheap locals repeat in every scope
+            match val {
+                Some(val) if !name.is_empty() && !name.starts_with('@') => self.add_cpu(val, 1, name),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // one label file, the format going by its extension
+    pub fn load(&mut self, path: &str) -> Result<(), String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("failed to read {}: {}", path, e))?;
+        let lower = path.to_ascii_lowercase();
+        let parsed = if lower.ends_with(".mlb") {
+            self.parse_mlb(&text)
+        } else if lower.ends_with(".dbg") {
+            self.parse_dbg(&text)
+        } else if lower.ends_with(".nl") {
+            self.parse_nl(&text, nl_bank(path))
+        } else {
+            return Err(format!("{}: label files are .nl, .mlb or .dbg", path));
+        };
+        parsed.map_err(|e| format!("{}: {}", path, e))
+    }
+
+    // the FCEUX .nl files next to the ROM, none is fine
+    pub fn load_for_rom(rom_path: &str) -> Result<Labels, String> {
+        let mut labels = Labels::new();
+        let rom = Path::new(rom_path);
+        let (dir, file) = match (rom.parent(), rom.file_name()) {
+            (Some(dir), Some(file)) => (dir, file.to_string_lossy().to_string()),
+            _ => return Ok(labels),
+        };
+        let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+        let mut paths: Vec<String> = match std::fs::read_dir(dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.file_name().to_string_lossy().to_string())
+                .filter(|name| name.starts_with(&format!("{}.", file)) && name.ends_with(".nl"))
+                .map(|name| dir.join(name).to_string_lossy().to_string())
+                .collect(),
+            Err(_) => vec![],
+        };
+        paths.sort();
+        for path in paths {
+            labels.load(&path)?;
+        }
+        Ok(labels)
+    }
+
+    // ROM labels first, they know which bank is mapped in
+    pub fn lookup(&self, addr: u16, prg_offset: Option<usize>) -> Option<&str> {
+        prg_offset
+            .and_then(|offset| self.prg.get(&offset))
+            .or_else(|| self.cpu.get(&addr))
+            .map(|name| name.as_str())
+    }
+
+    pub fn address_of(&self, name: &str) -> Option<u16> {
+        self.addresses.get(name).copied()
+    }
+
+    // `text` with names in place of the $addresses that have one; immediate
+    // #$values are left alone
+    pub fn substitute(&self, text: &str, prg_offset: impl Fn(u16) -> Option<usize>) -> String {
+        let mut result = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(dollar) = rest.find('$') {
+            let immediate = rest[..dollar].ends_with('#');
+            result.push_str(&rest[..dollar]);
+            let digits = rest[dollar + 1..].chars().take_while(|c| c.is_ascii_hexdigit()).count();
+            let token = &rest[dollar..dollar + 1 + digits];
+            let addr = match digits {
+                2 | 4 if !immediate => u16::from_str_radix(&token[1..], 16).ok(),
+                _ => None,
+            };
+            match addr.and_then(|addr| self.lookup(addr, prg_offset(addr))) {
+                Some(name) => result.push_str(name),
+                None => result.push_str(token),
+            }
+            rest = &rest[dollar + 1 + digits..];
+        }
+        result.push_str(rest);
+        result
+    }
+}
+
+fn element_name(name: &str, index: usize) -> String {
+    if index == 0 {
+        name.to_string()
+    } else {
+        format!("{}+{}", name, index)
+    }
+}
+
+// "game.nes.1.nl" -> Some(1), "game.nes.ram.nl" -> None
+fn nl_bank(path: &str) -> Option<usize> {
+    let stem = &path[..path.len() - ".nl".len()];
+    let bank = stem.rsplit('.').next()?;
+    usize::from_str_radix(bank, 16).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_nl_and_substitution() {
+        let mut labels = Labels::new();
+        labels.parse_nl("$0300/3#Buffer#sprite buffer\n\\continued comment\n$0010##no name\n", None).unwrap();
+        labels.parse_nl("$C000#Reset#\n$8004#Init#", Some(1)).unwrap();
+        assert!(labels.parse_nl("$XYZ#Bad#", None).is_err());
+
+        assert_eq!(labels.lookup(0x0302, None), Some("Buffer+2"));
+        assert_eq!(labels.lookup(0x0010, None), None);
+        // bank 1 covers PRG offsets $4000-$7FFF
+        assert_eq!(labels.lookup(0xc000, Some(0x4000)), Some("Reset"));
+        assert_eq!(labels.lookup(0xc000, Some(0x0000)), None);
+        assert_eq!(labels.address_of("Init"), Some(0x8004));
+
+        let offset = |addr: u16| if addr >= 0x8000 { Some(0x4000 + (addr as usize & 0x3fff)) } else { None };
+        assert_eq!(
+            labels.substitute("C5F5  4C 00 C0  JMP $C000", offset),
+            "C5F5  4C 00 C0  JMP Reset"
+        );
+        assert_eq!(labels.substitute("STA $0301,X @ 0302 = 00", offset), "STA Buffer+1,X @ 0302 = 00");
+        assert_eq!(labels.substitute("LDA #$03", offset), "LDA #$03");
+    }
+
+    #[test]
+    fn test_mlb_and_dbg() {
+        let mut labels = Labels::new();
+        labels
+            .parse_mlb("P:0010:Main:entry point\nR:0075:PlayerX\nG:2000:PPUCTRL\nW:0000-0001:Save\nR:0080::comment only\n")
+            .unwrap();
+        assert_eq!(labels.lookup(0x8010, Some(0x10)), Some("Main"));
+        assert_eq!(labels.lookup(0x0075, None), Some("PlayerX"));
+        assert_eq!(labels.lookup(0x2000, None), Some("PPUCTRL"));
+        assert_eq!(labels.lookup(0x6001, None), Some("Save+1"));
+        assert_eq!(labels.lookup(0x0080, None), None);
+        assert!(labels.parse_mlb("P:zz:Bad").is_err());
+
+        let mut labels = Labels::new();
+        labels
+            .parse_dbg(
+                "version\tmajor=2,minor=0\n\
+                 sym\tid=0,name=\"Reset\",addrsize=absolute,scope=0,def=1,val=0xC000,seg=0,type=lab\n\
+                 sym\tid=1,name=\"@loop\",addrsize=absolute,scope=1,def=2,val=0xC004,seg=0,type=lab\n\
+                 sym\tid=2,name=\"SPEED\",addrsize=zeropage,scope=0,def=3,val=0x3,type=equ\n",
+            )
+            .unwrap();
+        assert_eq!(labels.len(), 1);
+        assert_eq!(labels.lookup(0xc000, None), Some("Reset"));
+        assert_eq!(nl_bank("game.nes.A.nl"), Some(10));
+        assert_eq!(nl_bank("game.nes.ram.nl"), None);
+    }
+}
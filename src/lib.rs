@@ -0,0 +1,79 @@
+//! The emulator core: CPU, PPU, APU, cartridges and the tooling around them
+//! (savestates, movies, traces, test ROM runs). Nothing here needs SDL, the
+//! window, audio device and controllers live in the binary.
+//!
+//! Running a program headless for 60 frames:
+//!
+//! ```
+//! use nes_book_emu::bus::Bus;
+//! use nes_book_emu::cartridge::builder::RomBuilder;
+//! use nes_book_emu::cpu::CPU;
+//!
+//! let rom = RomBuilder::new()
+//!     // enable the vblank NMI and spin: LDA #$80; STA $2000; JMP $8005
+//!     .program(0x8000, &[0xa9, 0x80, 0x8d, 0x00, 0x20, 0x4c, 0x05, 0x80])
+//!     // count frames in $00: INC $00; RTI
+//!     .program(0x8008, &[0xe6, 0x00, 0x40])
+//!     .nmi_vector(0x8008)
+//!     .rom();
+//! let bus = Bus::new(rom, |_ppu, _joypad1, _joypad2| {});
+//! let mut cpu = CPU::new(bus);
+//! cpu.reset();
+//! cpu.run_frames(60);
+//! assert_eq!(cpu.bus.frame_count(), 60);
+//! assert_eq!(cpu.bus.peek(0x0000), 60);
+//! ```
+
+pub mod accuracy;
+pub mod apu;
+pub mod bindings;
+pub mod blip;
+pub mod bus;
+pub mod cartridge;
+pub mod cdl;
+pub mod cheats;
+pub mod cpu;
+pub mod debug_server;
+pub mod debugger;
+pub mod events;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod frame_condition;
+pub mod frame_hashes;
+pub mod heatmap;
+pub mod hex_editor;
+pub mod hotkeys;
+pub mod input;
+pub mod joypad;
+pub mod json;
+pub mod labels;
+pub mod midi;
+pub mod mixer;
+pub mod movie;
+pub mod netplay;
+pub mod opcodes;
+pub mod pacing;
+pub mod ppu;
+pub mod ram_search;
+pub mod region;
+pub mod render;
+pub mod rewind;
+pub mod run_ahead;
+pub mod savestate;
+#[cfg(feature = "serde")]
+pub mod serde_state;
+pub mod session;
+pub mod settings;
+pub mod state_slots;
+pub mod stats;
+pub mod timeline;
+pub mod trace;
+pub mod trace_compare;
+pub mod traps;
+pub mod triggers;
+pub mod watchdog;
+pub mod wav;
+pub mod websocket;
+
+#[macro_use]
+extern crate bitflags;
@@ -1,117 +1,1763 @@
-pub mod bus;
-pub mod cartridge;
-pub mod cpu;
-pub mod joypad;
-pub mod opcodes;
-pub mod ppu;
-pub mod render;
-pub mod trace;
+mod audio;
+#[cfg(feature = "debug-ui")]
+mod debug_ui;
+mod gamepads;
+mod startup;
 
+use audio::{AudioRing, PlaybackState};
+#[cfg(feature = "debug-ui")]
+use debug_ui::DebugUi;
+use gamepads::Gamepads;
+use nes_book_emu::{
+    accuracy, bindings, bus, cartridge, cdl, cheats, cpu, debug_server, debugger, events, frame_hashes, heatmap, hotkeys, input, joypad, labels, midi, mixer, movie, netplay, pacing, ppu, region, render, rewind, run_ahead, savestate, session, settings, state_slots, stats,
+    trace, trace_compare, traps, triggers, watchdog, wav,
+};
+use bindings::{Bindings, PadInput};
 use bus::Bus;
+use cartridge::battery::BatterySave;
+use cdl::CdlFile;
+use labels::Labels;
+use cartridge::nointro::{NoIntroDat, Verification};
 use cartridge::Rom;
+use cheats::Cheats;
 use cpu::CPU;
+use debug_server::DebugServer;
+use debugger::{Breakpoint, Repl};
+use events::EmuEvent;
+use frame_hashes::FrameHashes;
+use hotkeys::Hotkey;
+use input::InputDevice;
+use joypad::{ButtonLatches, DeviceType, Joypad, Turbo};
+use midi::MidiRecorder;
+use mixer::Source;
+use movie::{Movie, MovieMode, MovieRecorder};
+use netplay::Netplay;
+use pacing::{FramePacer, PresentMode};
+use ppu::watch::PpuWatch;
 use ppu::NesPPU;
+use region::Region;
+use rewind::RewindBuffer;
+use run_ahead::RunAheadPhase;
+use render::blend::{Blender, FrameBlend};
 use render::frame::Frame;
+use render::hd_pack::HdPack;
+use render::overlay::SpriteZeroOverlay;
+use render::post::{OverscanCrop, Pipeline};
+use session::Session;
+use settings::GameSettings;
+use state_slots::StateSlots;
 use trace::trace;
+use trace::{TraceFilter, TraceLog};
+use trace_compare::{Progress, TraceComparison, TraceState};
+use traps::{TrapAction, Traps};
+use triggers::{TriggerAction, Triggers};
+use watchdog::Watchdog;
+use wav::WavRecorder;
 
-use sdl2::event::Event;
+use sdl2::event::{Event, WindowEvent};
 use sdl2::keyboard::Keycode;
-use sdl2::pixels::PixelFormatEnum;
-use std::collections::HashMap;
+use sdl2::mouse::MouseButton;
+use sdl2::render::{Canvas, Texture};
+use sdl2::video::Window;
+use sdl2::EventPump;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::io::Write;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
 
-#[macro_use]
-extern crate lazy_static;
+// --trace                  log every executed instruction
+// --trace-range C000-FFFF  only log instructions inside the range
+// --trace-bank 1           only log instructions running from 16kb PRG bank 1
+// --trace-start C5F5       start logging once PC reaches the address
+// --trace-stop C66E        stop logging once PC reaches the address
+// --trace-file trace.log   log to the file in nestest.log format instead,
+//                          the trace_log hotkey switches it on and off
+fn trace_filter_from_args(args: &[String]) -> Result<Option<TraceFilter>, String> {
+    let mut filter: Option<TraceFilter> = None;
+    let mut i = 0;
+    while i < args.len() {
+        let value = args.get(i + 1);
+        let consumed = match args[i].as_str() {
+            "--trace" => {
+                filter.get_or_insert_with(TraceFilter::new);
+                1
+            }
+            "--trace-range" => {
+                let value = value.ok_or("--trace-range expects a value")?;
+                filter.get_or_insert_with(TraceFilter::new).range = Some(TraceFilter::parse_range(value)?);
+                2
+            }
+            "--trace-bank" => {
+                let value = value.ok_or("--trace-bank expects a value")?;
+                let bank = value.parse::<u8>().map_err(|_| format!("'{}' is not a bank number", value))?;
+                filter.get_or_insert_with(TraceFilter::new).bank = Some(bank);
+                2
+            }
+            "--trace-start" => {
+                let value = value.ok_or("--trace-start expects a value")?;
+                filter.get_or_insert_with(TraceFilter::new).set_start_at(trace::parse_addr(value)?);
+                2
+            }
+            "--trace-stop" => {
+                let value = value.ok_or("--trace-stop expects a value")?;
+                filter.get_or_insert_with(TraceFilter::new).set_stop_at(trace::parse_addr(value)?);
+                2
+            }
+            _ => 1,
+        };
+        i += consumed;
+    }
+    Ok(filter)
+}
 
-#[macro_use]
-extern crate bitflags;
+// --trap-rom-write ignore|log|break
+// --trap-unmapped-exec ignore|log|break
+// --trap-stack-wrap ignore|log|break
+// --trap-ppu-write ignore|log|break   what a --watch-ppu hit does
+fn traps_from_args(args: &[String]) -> Result<Traps, String> {
+    let mut traps = Traps::new();
+    for (i, arg) in args.iter().enumerate() {
+        let action = match arg.as_str() {
+            "--trap-rom-write" | "--trap-unmapped-exec" | "--trap-stack-wrap" | "--trap-ppu-write" => {
+                let value = args.get(i + 1).ok_or(format!("{} expects a value", arg))?;
+                TrapAction::parse(value)?
+            }
+            _ => continue,
+        };
+        match arg.as_str() {
+            "--trap-rom-write" => traps.on_rom_write = action,
+            "--trap-unmapped-exec" => traps.on_unmapped_execution = action,
+            "--trap-ppu-write" => traps.on_ppu_write = action,
+            _ => traps.on_stack_wrap = action,
+        }
+    }
+    Ok(traps)
+}
 
-fn main() {
-    // init sdl2
-    let sdl_context = sdl2::init().unwrap();
-    let video_subsystem = sdl_context.video().unwrap();
-    let window = video_subsystem
-        .window("PAC MAN", (256.0 * 3.0) as u32, (240.0 * 3.0) as u32)
-        .position_centered()
-        .build().unwrap();
+// --watch-ppu palette:00-1f, as many as needed, see ppu/watch.rs
+fn ppu_watches_from_args(args: &[String]) -> Result<Vec<PpuWatch>, String> {
+    let mut watches = vec![];
+    for (i, arg) in args.iter().enumerate() {
+        if arg == "--watch-ppu" {
+            let value = args.get(i + 1).ok_or("--watch-ppu expects a value")?;
+            watches.push(PpuWatch::parse(value)?);
+        }
+    }
+    Ok(watches)
+}
 
-    let mut canvas = window.into_canvas().present_vsync().build().unwrap();
-    let mut event_pump = sdl_context.event_pump().unwrap();
-    canvas.set_scale(3.0, 3.0).unwrap();
+// --break "write 0300-03ff", as many as needed, see debugger.rs
+fn breakpoints_from_args(args: &[String]) -> Result<Vec<Breakpoint>, String> {
+    let mut breakpoints = vec![];
+    for (i, arg) in args.iter().enumerate() {
+        if arg == "--break" {
+            let value = args.get(i + 1).ok_or("--break expects a value")?;
+            breakpoints.push(Breakpoint::parse(value)?);
+        }
+    }
+    Ok(breakpoints)
+}
 
-    let creator = canvas.texture_creator();
-    let mut texture = creator
-        .create_texture_target(PixelFormatEnum::RGB24, 256, 240).unwrap();
-    
-    let bytes: Vec<u8> = std::fs::read("snake.nes").unwrap();
-    let rom = Rom::new(&bytes).unwrap();
+// --region ntsc|pal|dendy, overriding the ROM header and the game's settings
+// FCEUX .nl files next to the ROM, then each --labels file (.nl, .mlb or
+// .dbg), see labels.rs
+fn labels_from_args(args: &[String], rom_path: &str) -> Result<Labels, String> {
+    let mut labels = Labels::load_for_rom(rom_path)?;
+    for (i, arg) in args.iter().enumerate() {
+        if arg == "--labels" {
+            labels.load(args.get(i + 1).ok_or("--labels expects a file")?)?;
+        }
+    }
+    Ok(labels)
+}
+
+fn region_from_args(args: &[String]) -> Result<Option<Region>, String> {
+    option_value(args, "--region").map(|value| Region::parse(value)).transpose()
+}
+
+fn exit_with_error(err: String) -> ! {
+    eprintln!("{}", err);
+    std::process::exit(1);
+}
+
+const DEFAULT_ROM: &str = "snake.nes";
+
+#[derive(Clone, Copy)]
+enum ResetRequest {
+    Soft,
+    PowerCycle,
+}
+
+#[derive(Clone, Copy)]
+enum StateRequest {
+    Save,
+    NextSlot,
+    Load,
+}
+
+#[derive(Clone, Copy)]
+enum MixerRequest {
+    ToggleMute(Source),
+    NextVolumeSource,
+    // in percent
+    Volume(i16),
+}
+
+// each starts the movie, or stops it when one is already going
+#[derive(Clone, Copy)]
+enum MovieRequest {
+    Record,
+    Play,
+}
+
+fn is_fm2(path: &std::path::Path) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("fm2"))
+}
+
+// FM2 for a .fm2 path, our movie file otherwise; `rom` is the iNES file
+fn write_movie(movie: &Movie, path: &std::path::Path, rom_path: &str, rom: &[u8]) -> Result<(), String> {
+    let data = if is_fm2(path) {
+        let rom_filename = std::path::Path::new(rom_path).file_stem().and_then(|stem| stem.to_str()).unwrap_or(rom_path);
+        movie.to_fm2(rom_filename, rom)?.into_bytes()
+    } else {
+        movie.to_bytes()
+    };
+    std::fs::write(path, data).map_err(|err| format!("failed to write {}: {}", path.display(), err))
+}
+
+// a recording is written out when it stops
+fn stop_movie(recorder: MovieRecorder, path: &std::path::Path, rom_path: &str, rom: &[u8]) {
+    match recorder.mode {
+        MovieMode::Recording => match write_movie(&recorder.movie, path, rom_path, rom) {
+            Ok(_) => println!("movie of {} frames written to {}", recorder.movie.frames.len(), path.display()),
+            Err(err) => println!("{}", err),
+        },
+        MovieMode::Playback => println!("movie stopped at frame {}", recorder.frame()),
+    }
+}
+
+// Plays from the movie's start state, or from power-on when it has none.
+fn start_movie(cpu: &mut CPU, path: &std::path::Path, rom_sha1: &str) -> Result<MovieRecorder, String> {
+    let data = std::fs::read(path).map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+    let movie = Movie::parse(&data).map_err(|err| format!("{}: {}", path.display(), err))?;
+    movie.check_rom(rom_sha1)?;
+    match movie.start_state.as_ref() {
+        Some(state) => {
+            let before = savestate::save(cpu);
+            if let Err(err) = savestate::load(cpu, state) {
+                savestate::load(cpu, &before).unwrap();
+                return Err(format!("{}: {}", path.display(), err));
+            }
+        }
+        None => cpu.power_cycle(),
+    }
+    Ok(MovieRecorder::play(movie))
+}
+
+// --hd-pack <dir>, otherwise `game.hdpack/` next to `game.nes` when it exists
+fn hd_pack_from_args(args: &[String], rom_path: &str) -> Result<Option<HdPack>, String> {
+    let dir = match option_value(args, "--hd-pack") {
+        Some(dir) => std::path::PathBuf::from(dir),
+        None => std::path::Path::new(rom_path).with_extension("hdpack"),
+    };
+    if option_value(args, "--hd-pack").is_none() && !dir.join("pack.toml").exists() {
+        return Ok(None);
+    }
+    HdPack::load(&dir).map(Some)
+}
+
+// the bindings as SDL key codes
+struct KeyMaps {
+    players: Vec<HashMap<Keycode, PadInput>>,
+    hotkeys: HashMap<Keycode, Hotkey>,
+}
+
+impl KeyMaps {
+    fn new(bindings: &Bindings) -> Result<Self, String> {
+        let keycode = |key: &str, action: &str| {
+            Keycode::from_name(key).ok_or(format!("unknown key '{}' for {}", key, action))
+        };
+        let mut players = vec![];
+        for player in 0..bindings::PLAYERS {
+            let mut key_map = HashMap::new();
+            for (input, key) in bindings.keys(player) {
+                key_map.insert(keycode(key, &format!("player {} {}", player + 1, input.name()))?, input);
+            }
+            players.push(key_map);
+        }
+        let mut hotkeys = HashMap::new();
+        for (hotkey, key) in bindings.hotkeys.bindings() {
+            hotkeys.insert(keycode(key, hotkey.name())?, hotkey);
+        }
+        Ok(KeyMaps { players, hotkeys })
+    }
+}
+
+// what a player holds beyond the keys and buttons that are down right now
+struct PlayerInput {
+    latches: ButtonLatches,
+    turbo: Turbo,
+}
+
+impl PlayerInput {
+    // a bound key or gamepad button going down or up; `player` from 0
+    fn press(&mut self, player: usize, joypad: &mut Joypad, input: PadInput, pressed: bool, repeat: bool) {
+        match input {
+            PadInput::Button(button) if pressed => {
+                if self.latches.key_down(joypad, button, repeat) {
+                    println!("player {} holding: {:?}", player + 1, self.latches.latched());
+                }
+            }
+            PadInput::Button(button) => self.latches.key_up(joypad, button),
+            PadInput::Turbo(button) if pressed => self.turbo.key_down(button),
+            // letting go releases the button at once, unless its key holds it
+            PadInput::Turbo(button) => {
+                self.turbo.key_up(button);
+                self.apply(joypad);
+            }
+        }
+    }
+
+    fn apply(&self, joypad: &mut Joypad) {
+        joypad.set_buttons(self.latches.pressed() | self.turbo.pressed());
+    }
+}
+
+fn option_value<'a>(args: &'a [String], name: &str) -> Option<&'a String> {
+    args.iter().position(|arg| arg == name).and_then(|i| args.get(i + 1))
+}
+
+enum FrameSink {
+    Directory(String),
+    Ffmpeg(std::process::Child),
+}
+
+impl FrameSink {
+    fn open(out: &str, frame_rate: f64) -> Result<FrameSink, String> {
+        if out.ends_with(".mp4") {
+            let frame_rate = frame_rate.to_string();
+            let child = std::process::Command::new("ffmpeg")
+                .args(["-y", "-loglevel", "error", "-f", "rawvideo", "-pix_fmt", "rgb24"])
+                .args(["-s", "256x240", "-r", &frame_rate, "-i", "-", "-pix_fmt", "yuv420p", out])
+                .stdin(std::process::Stdio::piped())
+                .spawn()
+                .map_err(|err| format!("failed to start ffmpeg: {}", err))?;
+            Ok(FrameSink::Ffmpeg(child))
+        } else {
+            std::fs::create_dir_all(out).map_err(|err| format!("failed to create {}: {}", out, err))?;
+            Ok(FrameSink::Directory(out.to_string()))
+        }
+    }
 
+    fn write(&mut self, frame: &Frame, frame_no: usize) -> Result<(), String> {
+        match self {
+            FrameSink::Directory(dir) => {
+                let path = format!("{}/{:06}.ppm", dir, frame_no);
+                frame.save_ppm(&path).map_err(|err| format!("failed to write {}: {}", path, err))
+            }
+            FrameSink::Ffmpeg(child) => child
+                .stdin
+                .as_mut()
+                .unwrap()
+                .write_all(&frame.data)
+                .map_err(|err| format!("failed to pipe frame to ffmpeg: {}", err)),
+        }
+    }
+
+    fn finish(&mut self) {
+        if let FrameSink::Ffmpeg(child) = self {
+            drop(child.stdin.take());
+            let _ = child.wait();
+        }
+    }
+}
+
+// render-movie movie.fm2 --out frames/|out.mp4 [--rom game.nes] [--region pal] [--hashes run.hashes]
+//
+// Plays the movie, FM2 or our own movie file, headless from its start state
+// (power-on when it has none) and writes every frame to disk, the sound to a WAV file next to them (frames/audio.wav, out.wav),
+// and with --hashes a frame hash file (see frame_hashes.rs) as well. The
+// input is applied at each vblank NMI, as the frontend plays movies.
+fn render_movie(args: &[String]) -> Result<(), String> {
+    let usage = "usage: render-movie movie.fm2 --out frames/|out.mp4 [--rom game.nes] [--region pal] [--hashes run.hashes]";
+    let movie_path = args.first().ok_or(usage)?;
+    let out = option_value(args, "--out").ok_or(usage)?;
+    let rom_path = option_value(args, "--rom").map(|s| s.as_str()).unwrap_or(DEFAULT_ROM);
+
+    let data = std::fs::read(movie_path).map_err(|err| format!("failed to read {}: {}", movie_path, err))?;
+    let movie = Movie::parse(&data)?;
+    let hashes_path = option_value(args, "--hashes").cloned();
+    let bytes = std::fs::read(rom_path).map_err(|err| format!("failed to read {}: {}", rom_path, err))?;
+    movie.check_rom(&cartridge::nointro::hash_rom(&bytes).sha1)?;
+    let start_state = movie.start_state.clone();
+    let rom = Rom::new(&bytes)?;
+    let region = Region::select(rom.region, region_from_args(args)?);
+    let mapper = cartridge::create_mapper(rom)?;
+
+    let wav_path = if out.ends_with(".mp4") {
+        std::path::Path::new(out).with_extension("wav")
+    } else {
+        std::path::Path::new(out).join("audio.wav")
+    };
+    let sink = Rc::new(RefCell::new(FrameSink::open(out, region.frame_rate())?));
+    let hashes = Rc::new(RefCell::new(FrameHashes::new()));
+    // frames written so far, and whether the movie has run out
+    let rendered = Rc::new(Cell::new(0));
+    let done = Rc::new(Cell::new(false));
+    let (frame_sink, frame_hashes, frames_rendered, finished) = (sink.clone(), hashes.clone(), rendered.clone(), done.clone());
     let mut frame = Frame::new();
 
-    let mut key_map1 = HashMap::new();
-    key_map1.insert(Keycode::Down, joypad::JoypadButton::DOWN);
-    key_map1.insert(Keycode::Up, joypad::JoypadButton::UP);
-    key_map1.insert(Keycode::Right, joypad::JoypadButton::RIGHT);
-    key_map1.insert(Keycode::Left, joypad::JoypadButton::LEFT);
-    key_map1.insert(Keycode::Space, joypad::JoypadButton::SELECT);
-    key_map1.insert(Keycode::Return, joypad::JoypadButton::START);
-    key_map1.insert(Keycode::K, joypad::JoypadButton::BUTTON_A);
-    key_map1.insert(Keycode::L, joypad::JoypadButton::BUTTON_B);
-
-    let mut key_map2 = HashMap::new();
-    key_map2.insert(Keycode::S, joypad::JoypadButton::DOWN);
-    key_map2.insert(Keycode::W, joypad::JoypadButton::UP);
-    key_map2.insert(Keycode::D, joypad::JoypadButton::RIGHT);
-    key_map2.insert(Keycode::A, joypad::JoypadButton::LEFT);
-    key_map2.insert(Keycode::C, joypad::JoypadButton::SELECT);
-    key_map2.insert(Keycode::V, joypad::JoypadButton::START);
-    key_map2.insert(Keycode::N, joypad::JoypadButton::BUTTON_A);
-    key_map2.insert(Keycode::M, joypad::JoypadButton::BUTTON_B);
-
-    let bus = Bus::new(rom, move |ppu: &NesPPU, joypad1: &mut joypad::Joypad, joypad2: &mut joypad::Joypad| {
+    let bus = Bus::with_mapper(mapper, move |ppu: &mut NesPPU, port1: &mut dyn InputDevice, port2: &mut dyn InputDevice| {
+        if finished.get() {
+            return;
+        }
+        let frame_no = frames_rendered.get();
         render::render(ppu, &mut frame);
-        texture.update(None, &frame.data, 256 * 3).unwrap();
+        if let Err(err) = frame_sink.borrow_mut().write(&frame, frame_no) {
+            exit_with_error(err);
+        }
+        frame_hashes.borrow_mut().push(&frame);
+        frames_rendered.set(frame_no + 1);
 
-        canvas.copy(&texture, None, None).unwrap();
+        // the input for the frame that starts now
+        match movie.frames.get(frame_no) {
+            Some(input) => {
+                port1.set_buttons(input[0]);
+                port2.set_buttons(input[1]);
+            }
+            None => finished.set(true),
+        }
+    });
 
-        canvas.present();
+    let mut cpu = CPU::new(bus);
+    cpu.bus.set_region(region);
+    cpu.bus.apu.set_sample_rate(Some(audio::SAMPLE_RATE as f64));
+    cpu.reset();
+    if let Some(state) = start_state.as_ref() {
+        savestate::load(&mut cpu, state).map_err(|err| format!("{}: {}", movie_path, err))?;
+    }
+    let mut wav = WavRecorder::new(&wav_path.to_string_lossy(), false);
+    wav.start(audio::SAMPLE_RATE as u32)?;
+    let mut wav_error = None;
+    cpu.run_with_callback(|cpu| {
+        if cpu.bus.apu.samples.len() >= audio::SAMPLE_RATE as usize || done.get() {
+            if let Err(err) = wav.record(&std::mem::take(&mut cpu.bus.apu.samples), &[]) {
+                wav_error = Some(err);
+                cpu.stop();
+            }
+        }
+        if done.get() {
+            cpu.stop();
+        }
+    });
+    if let Some(err) = wav_error {
+        return Err(err);
+    }
+    wav.stop()?;
+
+    sink.borrow_mut().finish();
+    if let Some(path) = hashes_path.as_ref() {
+        std::fs::write(path, hashes.borrow().to_bytes()).map_err(|err| format!("failed to write {}: {}", path, err))?;
+    }
+    println!("rendered {} frames, the sound to {}", rendered.get(), wav_path.display());
+    Ok(())
+}
+
+// hash-frames game.nes [--frames 600] [--region pal] [--save run.hashes] [--compare golden.hashes] [--ppm last.ppm]
+//
+// Runs the ROM headless without input and hashes every frame (see
+// frame_hashes.rs), to catch rendering regressions: --save writes the hashes,
+// --compare checks them against an earlier run and exits with 1 at the first
+// frame that differs, --ppm keeps the last frame to look at.
+fn hash_frames(args: &[String]) -> Result<(), String> {
+    let usage = "usage: hash-frames game.nes [--frames 600] [--region pal] [--save run.hashes] [--compare golden.hashes] [--ppm last.ppm]";
+    let rom_path = args.first().filter(|arg| !arg.starts_with("--")).ok_or(usage)?;
+    let frames = match option_value(args, "--frames") {
+        Some(frames) => frames.parse().map_err(|_| format!("--frames expects a number, got '{}'", frames))?,
+        None => 600,
+    };
+    let bytes = std::fs::read(rom_path).map_err(|err| format!("failed to read {}: {}", rom_path, err))?;
+    let rom = Rom::new(&bytes)?;
+    let region = Region::select(rom.region, region_from_args(args)?);
+    let mapper = cartridge::create_mapper(rom)?;
+
+    let (hashes, last_frame) = frame_hashes::run_headless(mapper, region, frames);
+    println!("{} frames, last frame {:08x}", hashes.hashes.len(), hashes.hashes.last().copied().unwrap_or(0));
+    if let Some(path) = option_value(args, "--ppm") {
+        last_frame.save_ppm(path).map_err(|err| format!("failed to write {}: {}", path, err))?;
+    }
+    if let Some(path) = option_value(args, "--save") {
+        std::fs::write(path, hashes.to_bytes()).map_err(|err| format!("failed to write {}: {}", path, err))?;
+        println!("hashes written to {}", path);
+    }
+    if let Some(path) = option_value(args, "--compare") {
+        let data = std::fs::read(path).map_err(|err| format!("failed to read {}: {}", path, err))?;
+        let golden = FrameHashes::from_bytes(&data)?;
+        match hashes.first_difference(&golden) {
+            Some(frame) => {
+                println!("frame {} differs from {}", frame, path);
+                std::process::exit(1);
+            }
+            None => println!("all frames match {}", path),
+        }
+    }
+    Ok(())
+}
+
+// compare-trace reference.log [--rom game.nes] [--movie input.fm2] [--start C000] [--region pal]
+//
+// Runs the ROM headless, optionally with the movie's input, and stops at the
+// first instruction where our registers differ from the reference trace.
+// --start overrides the reset vector, e.g. C000 for nestest's automation mode.
+fn compare_trace(args: &[String]) -> Result<(), String> {
+    let usage = "usage: compare-trace reference.log [--rom game.nes] [--movie input.fm2] [--start C000] [--region pal]";
+    let log_path = args.first().ok_or(usage)?;
+    let rom_path = option_value(args, "--rom").map(|s| s.as_str()).unwrap_or(DEFAULT_ROM);
+
+    let log = std::fs::read_to_string(log_path).map_err(|err| format!("failed to read {}: {}", log_path, err))?;
+    let mut comparison = TraceComparison::parse(&log)?;
+    let movie = match option_value(args, "--movie") {
+        Some(path) => {
+            let data = std::fs::read(path).map_err(|err| format!("failed to read {}: {}", path, err))?;
+            Some(Movie::parse(&data)?)
+        }
+        None => None,
+    };
+    let start = option_value(args, "--start").map(|addr| trace::parse_addr(addr)).transpose()?;
+    let bytes = std::fs::read(rom_path).map_err(|err| format!("failed to read {}: {}", rom_path, err))?;
+    let rom = Rom::new(&bytes)?;
+    let region = Region::select(rom.region, region_from_args(args)?);
+    let mapper = cartridge::create_mapper(rom)?;
+
+    let mut frame_no = 0;
+    let bus = Bus::with_mapper(mapper, move |_ppu: &mut NesPPU, port1: &mut dyn InputDevice, port2: &mut dyn InputDevice| {
+        frame_no += 1;
+        if let Some(input) = movie.as_ref().and_then(|movie| movie.frames.get(frame_no)) {
+            port1.set_buttons(input[0]);
+            port2.set_buttons(input[1]);
+        }
+    });
+
+    let mut cpu = CPU::new(bus);
+    cpu.bus.set_region(region);
+    cpu.reset();
+    if let Some(start) = start {
+        cpu.program_counter = start;
+    }
+    cpu.run_with_callback(move |cpu| {
+        let state = TraceState {
+            pc: cpu.program_counter,
+            a: cpu.register_a,
+            x: cpu.register_x,
+            y: cpu.register_y,
+            p: cpu.register_p.bits(),
+            sp: cpu.stack_pointer,
+        };
+        match comparison.step(state, &trace(cpu)) {
+            Progress::Matching => {}
+            Progress::Diverged(divergence) => {
+                println!("{}", divergence);
+                std::process::exit(1);
+            }
+            Progress::Finished(instructions) => {
+                println!("all {} instructions of the reference trace match", instructions);
+                std::process::exit(0);
+            }
+        }
+    });
+    Ok(())
+}
+
+// --midi <file>, --cdl <file> and the battery save are written when the
+// emulator quits, and a WAV recording still going is finished
+fn quit(
+    midi: &RefCell<Option<MidiRecorder>>,
+    midi_path: Option<&String>,
+    wav: &RefCell<WavRecorder>,
+    battery: Option<&BatterySave>,
+    cdl_file: Option<&CdlFile>,
+) -> ! {
+    if let Some(battery) = battery {
+        match battery.save() {
+            Ok(_) => println!("battery save written to {}", battery.path().display()),
+            Err(err) => println!("{}", err),
+        }
+    }
+    if let Some(cdl_file) = cdl_file {
+        match cdl_file.save() {
+            Ok(_) => println!("code/data log written to {}: {}", cdl_file.path().display(), cdl_file.log.borrow().summary()),
+            Err(err) => println!("{}", err),
+        }
+    }
+    if let (Some(recorder), Some(path)) = (midi.borrow().as_ref(), midi_path) {
+        match std::fs::write(path, recorder.to_smf()) {
+            Ok(_) => println!("midi written to {}", path),
+            Err(err) => println!("failed to write {}: {}", path, err),
+        }
+    }
+    let mut wav = wav.borrow_mut();
+    if wav.recording() {
+        match wav.stop() {
+            Ok(seconds) => println!("{:.1}s of sound written to {}", seconds, wav.path()),
+            Err(err) => println!("{}", err),
+        }
+    }
+    std::process::exit(0);
+}
+
+// --watchdog <seconds> without a frame before a stall is reported, 0 turns
+// it off
+fn watchdog_from_args(args: &[String]) -> Result<Option<Watchdog>, String> {
+    let timeout = match option_value(args, "--watchdog") {
+        Some(seconds) => {
+            let seconds: u64 = seconds.parse().map_err(|_| format!("--watchdog expects seconds, got '{}'", seconds))?;
+            std::time::Duration::from_secs(seconds)
+        }
+        None => watchdog::DEFAULT_TIMEOUT,
+    };
+    if timeout.is_zero() {
+        return Ok(None);
+    }
+    Ok(Some(Watchdog::start(timeout, std::path::Path::new(watchdog::DEFAULT_DIR))))
+}
+
+// instructions kept for a stall report
+const STALL_HISTORY: usize = 32;
+
+fn stall_report(cpu: &mut CPU, recent: &VecDeque<u16>) -> String {
+    let mut report = format!(
+        "emulation stalled: frame {} never finished, the CPU is still running
+         state.sav is the machine at the moment the stall was noticed
+
+         cycles: {}
+next instruction:
+{}
+
+last instructions:
+",
+        cpu.bus.frame_count(),
+        cpu.bus.cycles(),
+        trace(cpu)
+    );
+    for pc in recent {
+        report += &format!("  ${:04X}\n", pc);
+    }
+    report
+}
+
+// how often the window is checked while paused in the background
+const IDLE_POLL_MS: u32 = 250;
+
+// Paused in the background: nothing is emulated, events are polled at a slow
+// cadence and the last frame is only redrawn when the window system asks.
+// Returns false if the user quit meanwhile.
+fn idle_until_focused(event_pump: &mut EventPump, canvas: &mut Canvas<Window>, texture: &Texture) -> bool {
+    println!("paused in the background");
+    loop {
+        match event_pump.wait_event_timeout(IDLE_POLL_MS) {
+            Some(Event::Quit { .. }) => return false,
+            Some(Event::Window {
+                win_event: WindowEvent::FocusGained,
+                ..
+            }) => return true,
+            Some(Event::Window {
+                win_event: WindowEvent::Exposed,
+                ..
+            }) => {
+                let _ = canvas.copy(texture, None, None);
+                canvas.present();
+            }
+            _ => {}
+        }
+    }
+}
+
+// --dat <file>, otherwise nointro.dat in the working directory when it exists
+fn dat_from_args(args: &[String]) -> Result<Option<NoIntroDat>, String> {
+    match option_value(args, "--dat") {
+        Some(path) => NoIntroDat::load(path).map(Some),
+        None if std::path::Path::new(cartridge::nointro::DEFAULT_DAT).exists() => {
+            NoIntroDat::load(cartridge::nointro::DEFAULT_DAT).map(Some)
+        }
+        None => Ok(None),
+    }
+}
+
+// accuracy [--suite all|nestest|<name>] [--dir test-roms] [--frames 3600] [--json report.json]
+//
+// Runs test ROM suites headless and prints a scoreboard, exits with 1 if
+// anything failed. See accuracy.rs for how suites are laid out.
+fn accuracy(args: &[String]) -> Result<(), String> {
+    let suite = option_value(args, "--suite").map(|s| s.as_str()).unwrap_or("all");
+    let dir = std::path::Path::new(option_value(args, "--dir").map(|s| s.as_str()).unwrap_or(accuracy::DEFAULT_DIR));
+    let max_frames = match option_value(args, "--frames") {
+        Some(frames) => frames.parse().map_err(|_| format!("--frames expects a number, got '{}'", frames))?,
+        None => accuracy::DEFAULT_MAX_FRAMES,
+    };
+
+    let mut scoreboard = accuracy::Scoreboard::new();
+    match suite {
+        "all" => {
+            accuracy::run_nestest_suite(&mut scoreboard);
+            for name in accuracy::directory_suites(dir) {
+                if let Err(err) = accuracy::run_directory_suite(&mut scoreboard, &name, &dir.join(&name), max_frames) {
+                    println!("warning: {}", err);
+                }
+            }
+        }
+        "nestest" => accuracy::run_nestest_suite(&mut scoreboard),
+        name => accuracy::run_directory_suite(&mut scoreboard, name, &dir.join(name), max_frames)?,
+    }
+
+    print!("{}", scoreboard.to_text());
+    if let Some(path) = option_value(args, "--json") {
+        std::fs::write(path, scoreboard.to_json()).map_err(|err| format!("failed to write {}: {}", path, err))?;
+        println!("report written to {}", path);
+    }
+    if !scoreboard.all_passed() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+// convert-movie in.fm2|in.movie out.movie|out.fm2 [--rom game.nes]
+//
+// Converts between FM2 (FCEUX) movies and our movie files, by the output's
+// extension. FM2 files carry the ROM's MD5 and our files its SHA-1, so both
+// come from the ROM.
+fn convert_movie(args: &[String]) -> Result<(), String> {
+    let usage = "usage: convert-movie in.fm2|in.movie out.movie|out.fm2 [--rom game.nes]";
+    let (in_path, out_path) = match args {
+        [in_path, out_path, ..] => (in_path, std::path::Path::new(out_path)),
+        _ => return Err(usage.to_string()),
+    };
+    let rom_path = option_value(args, "--rom").map(|s| s.as_str()).unwrap_or(DEFAULT_ROM);
+    let data = std::fs::read(in_path).map_err(|err| format!("failed to read {}: {}", in_path, err))?;
+    let mut movie = Movie::parse(&data).map_err(|err| format!("{}: {}", in_path, err))?;
+    let bytes = std::fs::read(rom_path).map_err(|err| format!("failed to read {}: {}", rom_path, err))?;
+    let rom_sha1 = cartridge::nointro::hash_rom(&bytes).sha1;
+    movie.check_rom(&rom_sha1)?;
+    movie.rom_sha1 = Some(rom_sha1);
+
+    write_movie(&movie, out_path, rom_path, &bytes)?;
+    println!("{} frames written to {}", movie.frames.len(), out_path.display());
+    Ok(())
+}
+
+// netplay game.nes --peer host:6503 [--player 1|2] [--delay 2] [--bind 0.0.0.0:6503]
+//
+// Two players over the internet, see netplay.rs. Both sides run the same ROM
+// from power-on; each plays with the player 1 keys of bindings.toml and shows up as the
+// controller for its --player. Quits on a desync or when the peer goes away.
+fn netplay_session(args: &[String]) -> Result<(), String> {
+    let usage = "usage: netplay game.nes --peer host:6503 [--player 1|2] [--delay 2] [--bind 0.0.0.0:6503]";
+    let rom_path = args.first().ok_or(usage)?;
+    let peer = option_value(args, "--peer").ok_or(usage)?;
+    let player = match option_value(args, "--player") {
+        Some(player) => player.parse().map_err(|_| format!("--player expects 1 or 2, got '{}'", player))?,
+        None => 1,
+    };
+    let delay = match option_value(args, "--delay") {
+        Some(delay) => delay.parse().map_err(|_| format!("--delay expects frames, got '{}'", delay))?,
+        None => netplay::DEFAULT_DELAY,
+    };
+    let default_bind = format!("0.0.0.0:{}", netplay::DEFAULT_PORT);
+    let bind = option_value(args, "--bind").unwrap_or(&default_bind);
+
+    let bytes = std::fs::read(rom_path).map_err(|err| format!("failed to read {}: {}", rom_path, err))?;
+    let mut session = Session::new(Rom::new(&bytes)?)?;
+    println!("waiting for {}", peer);
+    let mut netplay = Netplay::connect(bind, peer, player, delay, &cartridge::nointro::hash_rom(&bytes).sha1)?;
+    println!("connected, playing as player {}", player);
+
+    let sdl_context = startup::init_sdl()?;
+    let mut canvas = startup::create_canvas(&sdl_context, PresentMode::Vsync)?;
+    let creator = canvas.texture_creator();
+    let mut texture = startup::create_texture(&creator, 256, 240)?;
+    let mut event_pump = sdl_context.event_pump()?;
+    let bindings = Bindings::load_or_create(bindings::DEFAULT_PATH)?;
+    // no turbo, both sides' inputs only change with key presses
+    let keys: HashMap<Keycode, joypad::JoypadButton> = KeyMaps::new(&bindings)
+        .map_err(|err| format!("{}: {}", bindings::DEFAULT_PATH, err))?
+        .players
+        .swap_remove(0)
+        .into_iter()
+        .filter_map(|(keycode, input)| match input {
+            PadInput::Button(button) => Some((keycode, button)),
+            PadInput::Turbo(_) => None,
+        })
+        .collect();
+    let mut buttons = joypad::JoypadButton::empty();
+    let mut frame_pacer = FramePacer::new(Region::Ntsc.frame_rate());
+    loop {
         for event in event_pump.poll_iter() {
             match event {
                 Event::Quit { .. }
                 | Event::KeyDown {
                     keycode: Some(Keycode::Escape),
                     ..
-                } => std::process::exit(0),
+                } => return Ok(()),
+                Event::KeyDown { keycode: Some(keycode), .. } if keys.contains_key(&keycode) => buttons.insert(keys[&keycode]),
+                Event::KeyUp { keycode: Some(keycode), .. } if keys.contains_key(&keycode) => buttons.remove(keys[&keycode]),
+                _ => {}
+            }
+        }
+        if netplay.update(&mut session, buttons)? {
+            let frame = session.frame();
+            texture.update(None, &frame.data, 256 * 3).map_err(|err| err.to_string())?;
+        }
+        canvas.copy(&texture, None, None)?;
+        frame_pacer.wait();
+        canvas.present();
+        if netplay.rollback.frame() % 600 == 0 && netplay.rollback.frames_rolled_back > 0 {
+            println!("frame {}: {} frames rolled back", netplay.rollback.frame(), netplay.rollback.frames_rolled_back);
+        }
+    }
+}
 
+// rominfo game.nes [--dat nointro.dat]
+fn rominfo(args: &[String]) -> Result<(), String> {
+    let rom_path = args.first().ok_or("usage: rominfo game.nes [--dat nointro.dat]")?;
+    let bytes = std::fs::read(rom_path).map_err(|err| format!("failed to read {}: {}", rom_path, err))?;
+    let rom = Rom::new(&bytes)?;
+    let hashes = cartridge::nointro::hash_rom(&bytes);
 
-                Event::KeyDown { keycode, .. } => {
-                    if let Some(keycode) = keycode {
-                        if let Some(key) = key_map1.get(&keycode) {
-                            joypad1.set_button_pressed_status(*key, true);
-                        }
-                        if let Some(key) = key_map2.get(&keycode) {
-                            joypad2.set_button_pressed_status(*key, true);
+    println!("file:      {}", rom_path);
+    println!("mapper:    {}", rom.mapper);
+    println!("mirroring: {:?}", rom.screen_mirroring);
+    println!("prg rom:   {}kb", rom.prg_rom.len() / 1024);
+    println!("chr rom:   {}kb", rom.chr_rom.len() / 1024);
+    println!("trainer:   {}", bytes.len() > 6 && bytes[6] & 0b100 != 0);
+    println!("battery:   {}", rom.battery);
+    println!("crc32:     {:08X}", hashes.crc32);
+    println!("sha1:      {}", hashes.sha1);
+    match dat_from_args(args)? {
+        Some(dat) => println!("no-intro:  {}", dat.verify(&bytes)),
+        None => println!("no-intro:  no DAT found, pass --dat or put {} here", cartridge::nointro::DEFAULT_DAT),
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(|arg| arg.as_str()) == Some("render-movie") {
+        render_movie(&args[1..]).unwrap_or_else(|err| exit_with_error(err));
+        return;
+    }
+    if args.first().map(|arg| arg.as_str()) == Some("hash-frames") {
+        hash_frames(&args[1..]).unwrap_or_else(|err| exit_with_error(err));
+        return;
+    }
+    if args.first().map(|arg| arg.as_str()) == Some("compare-trace") {
+        compare_trace(&args[1..]).unwrap_or_else(|err| exit_with_error(err));
+        return;
+    }
+    if args.first().map(|arg| arg.as_str()) == Some("accuracy") {
+        accuracy(&args[1..]).unwrap_or_else(|err| exit_with_error(err));
+        return;
+    }
+    if args.first().map(|arg| arg.as_str()) == Some("convert-movie") {
+        convert_movie(&args[1..]).unwrap_or_else(|err| exit_with_error(err));
+        return;
+    }
+    if args.first().map(|arg| arg.as_str()) == Some("netplay") {
+        netplay_session(&args[1..]).unwrap_or_else(|err| exit_with_error(err));
+        return;
+    }
+    if args.first().map(|arg| arg.as_str()) == Some("rominfo") {
+        rominfo(&args[1..]).unwrap_or_else(|err| exit_with_error(err));
+        return;
+    }
+    let mut trace_filter = trace_filter_from_args(&args).unwrap_or_else(|err| exit_with_error(err));
+    let traps = traps_from_args(&args).unwrap_or_else(|err| exit_with_error(err));
+    let ppu_watches = ppu_watches_from_args(&args).unwrap_or_else(|err| exit_with_error(err));
+    let breakpoints = breakpoints_from_args(&args).unwrap_or_else(|err| exit_with_error(err));
+
+    // init sdl2
+    let sdl_context = startup::init_sdl().unwrap_or_else(|err| exit_with_error(err));
+
+    // --present adaptive: no vsync, frames go out on the emulation timer; the
+    // timer paces emulation either way, see pacing.rs
+    let present_mode = match option_value(&args, "--present") {
+        Some(mode) => PresentMode::parse(mode).unwrap_or_else(|err| exit_with_error(err)),
+        None => PresentMode::Vsync,
+    };
+    let mut canvas = startup::create_canvas(&sdl_context, present_mode).unwrap_or_else(|err| exit_with_error(err));
+    // shared with the debugger window, which pumps events itself while it
+    // holds emulation
+    let event_pump = Rc::new(RefCell::new(
+        sdl_context
+            .event_pump()
+            .unwrap_or_else(|err| exit_with_error(startup::diagnose(startup::Stage::Input, &err))),
+    ));
+    let mut gamepads = Gamepads::open(&sdl_context);
+
+    let audio_ring = Arc::new(Mutex::new(AudioRing::new()));
+    // dropped while paused in the background, which closes the device
+    let mut audio_device = startup::open_audio(&sdl_context, &audio_ring);
+    // the device may not take SAMPLE_RATE, the APU resamples to what it got
+    let sample_rate = audio_device.as_ref().map_or(audio::SAMPLE_RATE, |device| device.spec().freq);
+    let sdl = &sdl_context;
+
+    let rom_path = DEFAULT_ROM;
+    let bytes: Vec<u8> = std::fs::read(rom_path)
+        .unwrap_or_else(|err| exit_with_error(format!("failed to read {}: {}", rom_path, err)));
+    match dat_from_args(&args) {
+        Ok(Some(dat)) => match dat.verify(&bytes) {
+            verified @ Verification::Verified(_) => println!("{}: {}", rom_path, verified),
+            other => println!("warning: {}: {}", rom_path, other),
+        },
+        Ok(None) => {}
+        Err(err) => println!("warning: {}", err),
+    }
+    let rom = Rom::new(&bytes).unwrap_or_else(|err| exit_with_error(format!("{}: {}", rom_path, err)));
+    let has_battery = rom.battery;
+    let header_region = rom.region;
+    let expansion_device = rom.expansion_device;
+    let mapper_number = rom.mapper;
+    // --cdl game.cdl: log which PRG bytes run as code and which are read as
+    // data, FCEUX's format, carrying on from the file when it exists
+    let cdl_file = option_value(&args, "--cdl")
+        .map(|path| CdlFile::open(path, rom.prg_rom.len(), rom.chr_rom.len()))
+        .transpose()
+        .unwrap_or_else(|err| exit_with_error(err));
+    let cdl_log = cdl_file.as_ref().map(|cdl_file| cdl_file.log.clone());
+    let mapper = cartridge::create_mapper(rom).unwrap_or_else(|err| exit_with_error(err));
+    let battery = if has_battery {
+        let battery = BatterySave::new(mapper.clone(), rom_path);
+        match battery.load() {
+            Ok(true) => println!("battery save loaded from {}", battery.path().display()),
+            Ok(false) => {}
+            Err(err) => println!("warning: {}", err),
+        }
+        Some(battery)
+    } else {
+        None
+    };
+    let hd_pack = hd_pack_from_args(&args, rom_path).unwrap_or_else(|err| exit_with_error(err));
+
+    let mut settings = GameSettings::load_for_rom(rom_path).unwrap_or_else(|err| exit_with_error(err));
+    if let Some(lines) = option_value(&args, "--overclock") {
+        settings.overclock_scanlines = settings::parse_overclock(lines).unwrap_or_else(|err| exit_with_error(err));
+    }
+    if args.iter().any(|arg| arg == "--pause-in-background") {
+        settings.pause_in_background = true;
+    }
+    let pause_in_background = settings.pause_in_background;
+    if let Some(limit) = option_value(&args, "--sprite-limit") {
+        settings.sprite_limit = ppu::SpriteLimit::parse(limit).unwrap_or_else(|err| exit_with_error(err));
+    }
+    if let Some(blend) = option_value(&args, "--frame-blend") {
+        settings.frame_blend = FrameBlend::parse(blend).unwrap_or_else(|err| exit_with_error(err));
+    }
+    if let Some(region) = region_from_args(&args).unwrap_or_else(|err| exit_with_error(err)) {
+        settings.region = Some(region);
+    }
+    let region = Region::select(header_region, settings.region);
+    if region != Region::Ntsc {
+        println!("{} timing: {:.2} frames per second", region, region.frame_rate());
+    }
+    let mut frame_pacer = FramePacer::new(region.frame_rate());
+    // --unlocked: start without the speed limit, the unlock_speed hotkey toggles it
+    if args.iter().any(|arg| arg == "--unlocked") {
+        frame_pacer.set_unlocked(true);
+    }
+    // the sound is muted while unlocked, see audio.rs
+    let speed_unlocked = Rc::new(Cell::new(frame_pacer.unlocked()));
+    let unlocked_speed = speed_unlocked.clone();
+    let input_device = DeviceType::select(expansion_device, settings.input_device);
+    if settings.overclock_scanlines > 0 {
+        println!(
+            "overclocked: {} extra scanlines per frame, timing no longer matches real hardware",
+            settings.overclock_scanlines
+        );
+        let title = format!("PAC MAN [overclocked +{}]", settings.overclock_scanlines);
+        canvas.window_mut().set_title(&title).unwrap();
+    }
+
+    let mut frame = Frame::new();
+    let mut pipeline = Pipeline::new();
+    if let Some(pack) = hd_pack {
+        frame.tiles = Some(vec![]);
+        pipeline.push(Box::new(pack), true);
+    }
+    if settings.frame_blend != FrameBlend::Off {
+        pipeline.push(Box::new(Blender::new(settings.frame_blend)), true);
+    }
+    pipeline.push(Box::new(SpriteZeroOverlay), false);
+    // --crop-overscan: hide the 8 lines top and bottom that TVs cut off
+    if args.iter().any(|arg| arg == "--crop-overscan") {
+        pipeline.push(Box::new(OverscanCrop::ntsc()), true);
+    }
+    let (texture_width, texture_height) = pipeline.output_size();
+    let (texture_width, texture_height) = (texture_width as u32, texture_height as u32);
+    let creator = canvas.texture_creator();
+    let mut texture =
+        startup::create_texture(&creator, texture_width, texture_height).unwrap_or_else(|err| exit_with_error(err));
+
+    // --mirror-window: the same picture in a second, borderless window for streaming
+    let mirror_canvas = if args.iter().any(|arg| arg == "--mirror-window") {
+        startup::create_mirror_canvas(sdl)
+            .map_err(|err| println!("warning: {}, continuing without the mirror window", err))
+            .ok()
+    } else {
+        None
+    };
+    let mirror_creator = mirror_canvas.as_ref().map(|canvas| canvas.texture_creator());
+    let mut mirror = match (mirror_canvas, mirror_creator.as_ref()) {
+        (Some(canvas), Some(creator)) => {
+            let texture = startup::create_texture(creator, texture_width, texture_height)
+                .unwrap_or_else(|err| exit_with_error(err));
+            Some((canvas, texture))
+        }
+        _ => None,
+    };
+
+    let mut triggers = Triggers::load_for_rom(rom_path).unwrap_or_else(|err| exit_with_error(err));
+    let fired_actions: Rc<RefCell<Vec<TriggerAction>>> = Rc::new(RefCell::new(vec![]));
+    let pending_actions = fired_actions.clone();
+    let mut screenshots = 0;
+    let reset_request: Rc<Cell<Option<ResetRequest>>> = Rc::new(Cell::new(None));
+    let requested_reset = reset_request.clone();
+    let state_request: Rc<Cell<Option<StateRequest>>> = Rc::new(Cell::new(None));
+    let requested_state = state_request.clone();
+    let mixer_request: Rc<Cell<Option<MixerRequest>>> = Rc::new(Cell::new(None));
+    let requested_mix = mixer_request.clone();
+    // the channel the volume hotkeys change
+    let mut volume_source = Source::Pulse1;
+    let mut state_slots = StateSlots::for_rom(rom_path);
+    let rewinding = Rc::new(Cell::new(false));
+    let rewind_held = rewinding.clone();
+    let mut rewind_buffer = RewindBuffer::new(rewind::DEFAULT_BUDGET_BYTES);
+    // --movie <file>, otherwise `game.movie` next to `game.nes`
+    let movie_path = match option_value(&args, "--movie") {
+        Some(path) => std::path::PathBuf::from(path),
+        None => std::path::Path::new(rom_path).with_extension("movie"),
+    };
+    let rom_sha1 = cartridge::nointro::hash_rom(&bytes).sha1;
+    let movie: Rc<RefCell<Option<MovieRecorder>>> = Rc::new(RefCell::new(None));
+    let movie_input = movie.clone();
+    let movie_request: Rc<Cell<Option<MovieRequest>>> = Rc::new(Cell::new(None));
+    let requested_movie = movie_request.clone();
+    // --run-ahead <frames>: show the frame that many frames on, see run_ahead.rs
+    let run_ahead_frames = match option_value(&args, "--run-ahead") {
+        Some(frames) => run_ahead::parse_frames(frames).unwrap_or_else(|err| exit_with_error(err)),
+        None => 0,
+    };
+    let initial_phase = if run_ahead_frames > 0 { RunAheadPhase::Real } else { RunAheadPhase::Off };
+    let run_ahead_phase = Rc::new(Cell::new(initial_phase));
+    let drawing_phase = run_ahead_phase.clone();
+    let timeline_request = Rc::new(Cell::new(false));
+    let requested_timeline = timeline_request.clone();
+    let heatmap_request = Rc::new(Cell::new(false));
+    let requested_heatmap = heatmap_request.clone();
+    // without --trace-file the hotkey still logs, to trace.log
+    let trace_file = option_value(&args, "--trace-file").cloned();
+    let trace_log = Rc::new(RefCell::new(TraceLog::new(trace_file.as_deref().unwrap_or("trace.log"))));
+    if trace_file.is_some() {
+        trace_log.borrow_mut().set_enabled(true).unwrap_or_else(|err| exit_with_error(err));
+    }
+    let toggled_trace_log = trace_log.clone();
+
+    let watchdog = watchdog_from_args(&args).unwrap_or_else(|err| exit_with_error(err));
+    let paused_watchdog = watchdog.clone();
+    // --debug-server 127.0.0.1:6502: the debugger for external UIs, see debug_server.rs
+    let mut debug_server = option_value(&args, "--debug-server")
+        .map(|addr| DebugServer::bind(addr).unwrap_or_else(|err| exit_with_error(err)));
+    // --debug: the command line debugger, stopped before the first
+    // instruction; --break alone only stops once one is hit
+    let debug_on_start = args.iter().any(|arg| arg == "--debug");
+    let mut repl = (debug_on_start || !breakpoints.is_empty()).then(|| Repl::new(debug_on_start));
+    // --debug-ui: the debugger window instead, see debug_ui.rs
+    #[cfg(feature = "debug-ui")]
+    let debug_ui = args
+        .iter()
+        .any(|arg| arg == "--debug-ui")
+        .then(|| Rc::new(RefCell::new(DebugUi::new(sdl, true).unwrap_or_else(|err| exit_with_error(err)))));
+    #[cfg(feature = "debug-ui")]
+    if debug_ui.is_some() {
+        repl = None;
+    }
+    #[cfg(feature = "debug-ui")]
+    let (debug_ui_events, paused_event_pump) = (debug_ui.clone(), event_pump.clone());
+    let debug_audio = audio_ring.clone();
+    let sample_ring = audio_ring.clone();
+    let mut recent_pcs = VecDeque::with_capacity(STALL_HISTORY);
+
+    let midi_path = option_value(&args, "--midi").cloned();
+    let midi: Rc<RefCell<Option<MidiRecorder>>> = Rc::new(RefCell::new(midi_path.as_ref().map(|_| MidiRecorder::new())));
+    let midi_recording = midi.clone();
+    // --wav <file> records from the start, otherwise the hotkey records to
+    // audio.wav; --wav-stems adds a file per channel, see wav.rs
+    let wav_path = option_value(&args, "--wav").cloned();
+    let wav_stems = args.iter().any(|arg| arg == "--wav-stems");
+    let wav = Rc::new(RefCell::new(WavRecorder::new(wav_path.as_deref().unwrap_or("audio.wav"), wav_stems)));
+    let wav_recording = wav.clone();
+    let wav_request = Rc::new(Cell::new(wav_path.is_some()));
+    let requested_wav = wav_request.clone();
+
+
+    // --bindings <file>, otherwise bindings.toml in the working directory,
+    // written with the defaults when missing
+    let bindings_path = option_value(&args, "--bindings").map_or(bindings::DEFAULT_PATH.to_string(), |s| s.clone());
+    let mut bindings = Bindings::load_or_create(&bindings_path).unwrap_or_else(|err| exit_with_error(err));
+    let mut keys = KeyMaps::new(&bindings).unwrap_or_else(|err| exit_with_error(format!("{}: {}", bindings_path, err)));
+    gamepads
+        .set_bindings(&bindings)
+        .unwrap_or_else(|err| exit_with_error(format!("{}: {}", bindings_path, err)));
+    // hotkeys are checked before game input
+    for conflict in bindings.conflicts() {
+        println!("warning: {}", conflict);
+    }
+    if let Some(key) = bindings.hotkeys.key(Hotkey::ListHotkeys) {
+        println!("{} lists the hotkeys", key);
+    }
+
+    let mut paused = false;
+    let mut players: [PlayerInput; 4] = std::array::from_fn(|_| PlayerInput {
+        latches: ButtonLatches::new(settings.toggle_buttons, settings.auto_hold),
+        turbo: Turbo::new(bindings.turbo_frames_on, bindings.turbo_frames_off),
+    });
+    if !settings.auto_hold.is_empty() {
+        println!("auto-held buttons: {:?}", settings.auto_hold);
+    }
+
+    // the mouse in NES pixels and its left button, for a Zapper or paddle
+    let mut pointer = (0u8, 0u8, false);
+
+    let bus = Bus::with_mapper(mapper, move |ppu: &mut NesPPU, port1: &mut dyn InputDevice, port2: &mut dyn InputDevice| {
+        // as of the last frame's events
+        port1.set_pointer(pointer.0, pointer.1, pointer.2);
+        port2.set_pointer(pointer.0, pointer.1, pointer.2);
+        // Players 1 and 3 are on port 1, 2 and 4 on port 2. The keyboard
+        // still works for a player without a controller, to no effect.
+        let mut unplugged: [Joypad; 4] = std::array::from_fn(|_| Joypad::new());
+        let [unplugged1, unplugged2, unplugged3, unplugged4] = &mut unplugged;
+        let mut port1_joypads = port1.joypads().into_iter();
+        let mut port2_joypads = port2.joypads().into_iter();
+        let mut joypads = [
+            port1_joypads.next().unwrap_or(unplugged1),
+            port2_joypads.next().unwrap_or(unplugged2),
+            port1_joypads.next().unwrap_or(unplugged3),
+            port2_joypads.next().unwrap_or(unplugged4),
+        ];
+        let mut event_pump = event_pump.borrow_mut();
+        // everything traced so far is on disk before a hotkey can quit
+        if let Err(err) = toggled_trace_log.borrow_mut().flush() {
+            println!("{}", err);
+        }
+        // latched buttons stay down, including across a power cycle
+        for (player, joypad) in players.iter().zip(joypads.iter_mut()) {
+            player.apply(joypad);
+        }
+        if drawing_phase.get() == RunAheadPhase::Hidden {
+            return;
+        }
+        // turbo counts real frames only
+        if drawing_phase.get() != RunAheadPhase::Shown {
+            for player in players.iter_mut() {
+                player.turbo.tick();
+            }
+        }
+
+        if let Some(dump) = ppu.take_frame_dump() {
+            std::fs::write("frame_dump.json", dump.to_json()).unwrap();
+            std::fs::write("frame_dump.html", dump.to_html()).unwrap();
+            println!("frame dump written to frame_dump.json and frame_dump.html");
+        }
+
+        // run-ahead: the real frame takes input but isn't drawn, the last
+        // frame run ahead is drawn instead and the others are thrown away
+        let phase = drawing_phase.get();
+        if phase != RunAheadPhase::Real {
+            render::render(ppu, &mut frame);
+            let picture = pipeline.run(&frame);
+            texture.update(None, &picture.data, picture.pitch()).unwrap();
+            if let Some((mirror_canvas, mirror_texture)) = mirror.as_mut() {
+                mirror_texture.update(None, &picture.data, picture.pitch()).unwrap();
+                mirror_canvas.copy(mirror_texture, None, None).unwrap();
+            }
+
+            canvas.copy(&texture, None, None).unwrap();
+
+            frame_pacer.wait();
+            canvas.present();
+            if let Some((mirror_canvas, _)) = mirror.as_mut() {
+                mirror_canvas.present();
+            }
+        }
+        if phase == RunAheadPhase::Shown {
+            return;
+        }
+
+        for action in pending_actions.borrow_mut().drain(..) {
+            match action {
+                TriggerAction::Message(text) => println!("{}", text),
+                TriggerAction::Screenshot => {
+                    screenshots += 1;
+                    let path = format!("screenshot-{}.ppm", screenshots);
+                    match frame.save_ppm(&path) {
+                        Ok(_) => println!("screenshot saved to {}", path),
+                        Err(err) => println!("failed to save {}: {}", path, err),
+                    }
+                }
+                TriggerAction::SaveState => requested_state.set(Some(StateRequest::Save)),
+                TriggerAction::Rumble { strength, duration_ms } => {
+                    let intensity = (strength * u16::MAX as f32) as u16;
+                    gamepads.rumble(intensity, duration_ms);
+                }
+                TriggerAction::Pause => {
+                    println!("paused, press any key to continue");
+                    if let Some(watchdog) = paused_watchdog.as_ref() {
+                        watchdog.set_idle(true);
+                    }
+                    audio_ring.lock().unwrap().set_state(PlaybackState::Paused);
+                    for event in event_pump.wait_iter() {
+                        match event {
+                            Event::Quit { .. } => quit(&midi, midi_path.as_ref(), &wav, battery.as_ref(), cdl_file.as_ref()),
+                            Event::KeyDown { .. } | Event::ControllerButtonDown { .. } => break,
+                            _ => {}
                         }
                     }
+                    if let Some(watchdog) = paused_watchdog.as_ref() {
+                        watchdog.set_idle(false);
+                    }
+                    audio_ring.lock().unwrap().set_state(PlaybackState::Running);
                 }
-                Event::KeyUp { keycode, .. } => {
-                    if let Some(keycode) = keycode {
-                        if let Some(key) = key_map1.get(&keycode) {
-                            joypad1.set_button_pressed_status(*key, false);
+            }
+        }
+
+        let mut in_background = false;
+        let was_paused = paused;
+        let mut advance = false;
+        let mut reload_bindings = false;
+        loop {
+            // paused, wait here for events until resumed or asked for one more frame
+            let events: Vec<Event> = if paused {
+                event_pump.wait_event_timeout(IDLE_POLL_MS).into_iter().collect()
+            } else {
+                event_pump.poll_iter().collect()
+            };
+            for event in events {
+                match event {
+                    #[cfg(feature = "debug-ui")]
+                    event if debug_ui_events.as_ref().is_some_and(|ui| ui.borrow().owns(&event)) => {
+                        debug_ui_events.as_ref().unwrap().borrow_mut().handle_event(&event)
+                    }
+                    Event::Quit { .. } => quit(&midi, midi_path.as_ref(), &wav, battery.as_ref(), cdl_file.as_ref()),
+                    // with the debugger window open, closing this one doesn't quit by itself
+                    Event::Window {
+                        win_event: WindowEvent::Close,
+                        window_id,
+                        ..
+                    } if window_id == canvas.window().id() => quit(&midi, midi_path.as_ref(), &wav, battery.as_ref(), cdl_file.as_ref()),
+
+                    Event::Window {
+                        win_event: WindowEvent::FocusLost,
+                        ..
+                    } if pause_in_background => in_background = true,
+                    Event::MouseMotion { x, y, .. } => {
+                        let (width, height) = canvas.window().size();
+                        pointer.0 = (x.max(0) as u32 * 256 / width.max(1)).min(255) as u8;
+                        pointer.1 = (y.max(0) as u32 * 240 / height.max(1)).min(255) as u8;
+                    }
+                    Event::MouseButtonDown { mouse_btn: MouseButton::Left, .. } => pointer.2 = true,
+                    Event::MouseButtonUp { mouse_btn: MouseButton::Left, .. } => pointer.2 = false,
+                    Event::Window {
+                        win_event: WindowEvent::Exposed,
+                        ..
+                    } if paused => {
+                        let _ = canvas.copy(&texture, None, None);
+                        canvas.present();
+                    }
+
+                    Event::KeyDown {
+                        keycode: Some(keycode),
+                        repeat,
+                        ..
+                    } if keys.hotkeys.contains_key(&keycode) => match keys.hotkeys[&keycode] {
+                        _ if repeat => {}
+                        Hotkey::Quit => quit(&midi, midi_path.as_ref(), &wav, battery.as_ref(), cdl_file.as_ref()),
+                        Hotkey::ListHotkeys => println!("hotkeys:\n{}", bindings.hotkeys.describe()),
+                        Hotkey::SoftReset => requested_reset.set(Some(ResetRequest::Soft)),
+                        Hotkey::PowerCycle => requested_reset.set(Some(ResetRequest::PowerCycle)),
+                        Hotkey::SaveState => requested_state.set(Some(StateRequest::Save)),
+                        Hotkey::NextSlot => requested_state.set(Some(StateRequest::NextSlot)),
+                        Hotkey::LoadState => requested_state.set(Some(StateRequest::Load)),
+                        Hotkey::Rewind => rewind_held.set(true),
+                        Hotkey::SpriteZeroOverlay => {
+                            let on = pipeline.toggle("sprite-zero-hit").unwrap_or(false);
+                            println!("sprite 0 hit overlay {}", if on { "on" } else { "off" });
+                        }
+                        Hotkey::FrameDump => ppu.request_frame_dump(),
+                        Hotkey::Timeline => requested_timeline.set(true),
+                        Hotkey::RamHeatmap => requested_heatmap.set(true),
+                        Hotkey::RecordMovie => requested_movie.set(Some(MovieRequest::Record)),
+                        Hotkey::PlayMovie => requested_movie.set(Some(MovieRequest::Play)),
+                        Hotkey::TraceLog => {
+                            let mut trace_log = toggled_trace_log.borrow_mut();
+                            let enabled = !trace_log.enabled();
+                            match trace_log.set_enabled(enabled) {
+                                Ok(_) if enabled => println!("tracing to {}", trace_log.path()),
+                                Ok(_) => println!("tracing stopped"),
+                                Err(err) => println!("{}", err),
+                            }
+                        }
+                        Hotkey::MutePulse1 => requested_mix.set(Some(MixerRequest::ToggleMute(Source::Pulse1))),
+                        Hotkey::MutePulse2 => requested_mix.set(Some(MixerRequest::ToggleMute(Source::Pulse2))),
+                        Hotkey::MuteTriangle => requested_mix.set(Some(MixerRequest::ToggleMute(Source::Triangle))),
+                        Hotkey::MuteNoise => requested_mix.set(Some(MixerRequest::ToggleMute(Source::Noise))),
+                        Hotkey::MuteDmc => requested_mix.set(Some(MixerRequest::ToggleMute(Source::Dmc))),
+                        Hotkey::MuteExpansion => requested_mix.set(Some(MixerRequest::ToggleMute(Source::Expansion))),
+                        Hotkey::VolumeChannel => requested_mix.set(Some(MixerRequest::NextVolumeSource)),
+                        Hotkey::VolumeDown => requested_mix.set(Some(MixerRequest::Volume(-10))),
+                        Hotkey::VolumeUp => requested_mix.set(Some(MixerRequest::Volume(10))),
+                        Hotkey::RecordAudio => requested_wav.set(true),
+                        Hotkey::ReloadBindings => reload_bindings = true,
+                        Hotkey::Pause => {
+                            paused = !paused;
+                            println!("{}", if paused { "paused" } else { "resumed" });
+                        }
+                        // pauses first when running
+                        Hotkey::FrameAdvance if paused => advance = true,
+                        Hotkey::FrameAdvance => {
+                            paused = true;
+                            println!("paused");
+                        }
+                        Hotkey::UnlockSpeed => {
+                            frame_pacer.set_unlocked(!frame_pacer.unlocked());
+                            unlocked_speed.set(frame_pacer.unlocked());
+                            match (frame_pacer.unlocked(), present_mode) {
+                                (false, _) => println!("speed limited to {:.2} frames per second", region.frame_rate()),
+                                (true, PresentMode::Vsync) => println!("speed unlocked, up to the display's refresh rate with vsync on"),
+                                (true, PresentMode::Adaptive) => println!("speed unlocked"),
+                            }
                         }
-                        if let Some(key) = key_map2.get(&keycode) {
-                            joypad2.set_button_pressed_status(*key, false);
+                    },
+
+                    Event::KeyDown { keycode, repeat, .. } => {
+                        if let Some(keycode) = keycode {
+                            for (player, key_map) in keys.players.iter().enumerate() {
+                                if let Some(input) = key_map.get(&keycode) {
+                                    players[player].press(player, joypads[player], *input, true, repeat);
+                                }
+                            }
+                        }
+                    }
+                    Event::KeyUp { keycode, .. } => {
+                        if let Some(keycode) = keycode {
+                            if keys.hotkeys.get(&keycode) == Some(&Hotkey::Rewind) {
+                                rewind_held.set(false);
+                            }
+                            for (player, key_map) in keys.players.iter().enumerate() {
+                                if let Some(input) = key_map.get(&keycode) {
+                                    players[player].press(player, joypads[player], *input, false, false);
+                                }
+                            }
+                        }
+                    }
+
+                    event => {
+                        for input in gamepads.handle_event(&event) {
+                            let player = input.player;
+                            players[player].press(player, joypads[player], input.input, input.pressed, false);
                         }
                     }
                 }
+            }
+            if !paused || advance || in_background {
+                break;
+            }
+        }
+        // a broken file keeps the bindings in use
+        if reload_bindings {
+            let reloaded = Bindings::load_or_create(&bindings_path).and_then(|new_bindings| {
+                let new_keys = KeyMaps::new(&new_bindings).map_err(|err| format!("{}: {}", bindings_path, err))?;
+                gamepads
+                    .set_bindings(&new_bindings)
+                    .map_err(|err| format!("{}: {}", bindings_path, err))?;
+                Ok((new_bindings, new_keys))
+            });
+            match reloaded {
+                Ok((new_bindings, new_keys)) => {
+                    bindings = new_bindings;
+                    keys = new_keys;
+                    for player in players.iter_mut() {
+                        player.turbo = Turbo::new(bindings.turbo_frames_on, bindings.turbo_frames_off);
+                    }
+                    println!("bindings reloaded from {}", bindings_path);
+                    for conflict in bindings.conflicts() {
+                        println!("warning: {}", conflict);
+                    }
+                }
+                Err(err) => println!("{}", err),
+            }
+        }
+        if paused != was_paused {
+            if let Some(watchdog) = paused_watchdog.as_ref() {
+                watchdog.set_idle(paused);
+            }
+            let state = if paused { PlaybackState::Paused } else { PlaybackState::Running };
+            audio_ring.lock().unwrap().set_state(state);
+        }
+
+        if in_background {
+            audio_ring.lock().unwrap().set_state(PlaybackState::Paused);
+            drop(audio_device.take());
+            if let Some(watchdog) = paused_watchdog.as_ref() {
+                watchdog.set_idle(true);
+            }
+            if !idle_until_focused(&mut event_pump, &mut canvas, &texture) {
+                quit(&midi, midi_path.as_ref(), &wav, battery.as_ref(), cdl_file.as_ref());
+            }
+            if let Some(watchdog) = paused_watchdog.as_ref() {
+                watchdog.set_idle(paused);
+            }
+            audio_device = startup::open_audio(sdl, &audio_ring);
+            if !paused {
+                audio_ring.lock().unwrap().set_state(PlaybackState::Running);
+            }
+        }
 
-                _ => { /* do nothing */ }
+        // a recording logs the input held for the coming frame, playback
+        // replaces it; movies hold players 1 and 2
+        let mut movie = movie_input.borrow_mut();
+        if let Some(recorder) = movie.as_mut() {
+            match recorder.next_frame([joypads[0].buttons(), joypads[1].buttons()]) {
+                Some(input) => {
+                    joypads[0].set_buttons(input[0]);
+                    joypads[1].set_buttons(input[1]);
+                }
+                None => {
+                    println!("movie finished after {} frames", recorder.frame());
+                    players[0].apply(joypads[0]);
+                    players[1].apply(joypads[1]);
+                    *movie = None;
+                }
             }
         }
     });
 
     let mut cpu = CPU::new(bus);
+    cpu.bus.traps = traps;
+    cpu.bus.set_cdl(cdl_log);
+    cpu.bus.cheats = Cheats::load_for_rom(rom_path).unwrap_or_else(|err| exit_with_error(err));
+    if !cpu.bus.cheats.cheats.is_empty() {
+        println!("cheats from {}:\n{}", cpu.bus.cheats.path().unwrap().display(), cpu.bus.cheats.describe());
+    }
+    cpu.bus.labels = labels_from_args(&args, rom_path).unwrap_or_else(|err| exit_with_error(err));
+    if !cpu.bus.labels.is_empty() {
+        println!("{} labels loaded", cpu.bus.labels.len());
+    }
+    for watch in ppu_watches {
+        cpu.bus.add_ppu_watch(watch);
+    }
+    for breakpoint in breakpoints {
+        cpu.bus.breakpoints.add(breakpoint);
+    }
+    cpu.bus.set_overclock_scanlines(settings.overclock_scanlines);
+    cpu.bus.set_sprite_limit(settings.sprite_limit);
+    cpu.bus.set_region(region);
+    // the Zapper and the paddle go in port 2, with a controller in port 1
+    match input_device {
+        DeviceType::Zapper | DeviceType::Paddle => {
+            println!("{:?} in port 2, aim with the mouse", input_device);
+            cpu.bus.plug(1, input::create(input_device, 1));
+        }
+        DeviceType::FourScore => {
+            println!("Four Score plugged in, players 3 and 4 play on the keypad and above the arrow keys");
+            cpu.bus.plug(0, input::create(input_device, 0));
+            cpu.bus.plug(1, input::create(input_device, 1));
+        }
+        DeviceType::Standard => {}
+    }
+    cpu.bus.apu.set_sample_rate(Some(sample_rate as f64));
+    cpu.bus.profiler.enabled = args.iter().any(|arg| arg == "--profile");
+    // --log-events: everything except the once-per-frame events
+    if args.iter().any(|arg| arg == "--log-events") {
+        cpu.bus.events.subscribe(|event| match event {
+            EmuEvent::FrameStart(_) | EmuEvent::FrameEnd(_) | EmuEvent::Nmi => {}
+            event => println!("event: {:?}", event),
+        });
+    }
+    cpu.bus.events.emit(EmuEvent::RomLoaded { mapper: mapper_number });
 
     cpu.reset();
-    cpu.run();
+    let mut last_frame = 0;
+    let mut profile_totals = stats::FrameStats::default();
+    cpu.run_with_callback(move |cpu| {
+        // anything that moves the machine outside the input log would desync
+        // the movie
+        let movie_going = movie.borrow().is_some();
+        match reset_request.take() {
+            Some(_) if movie_going => println!("stop the movie to reset"),
+            Some(ResetRequest::Soft) => cpu.soft_reset(),
+            Some(ResetRequest::PowerCycle) => cpu.power_cycle(),
+            None => {}
+        }
+        match movie_request.take() {
+            Some(_) if movie_going => stop_movie(movie.borrow_mut().take().unwrap(), &movie_path, rom_path, &bytes),
+            Some(MovieRequest::Record) => {
+                let mut recorder = MovieRecorder::record();
+                recorder.movie.rom_sha1 = Some(rom_sha1.clone());
+                // FCEUX can't load our states, FM2 movies start from power-on
+                if is_fm2(&movie_path) {
+                    cpu.power_cycle();
+                } else {
+                    recorder.movie.start_state = Some(savestate::save(cpu));
+                }
+                *movie.borrow_mut() = Some(recorder);
+                println!("recording a movie");
+            }
+            Some(MovieRequest::Play) => match start_movie(cpu, &movie_path, &rom_sha1) {
+                Ok(recorder) => {
+                    println!("playing {}", movie_path.display());
+                    *movie.borrow_mut() = Some(recorder);
+                }
+                Err(err) => println!("{}", err),
+            },
+            None => {}
+        }
+        match state_request.take() {
+            Some(StateRequest::Save) => match state_slots.save(cpu) {
+                Ok(path) => println!("state saved to {}", path.display()),
+                Err(err) => println!("{}", err),
+            },
+            Some(StateRequest::NextSlot) => println!("state slot {}", state_slots.select_next()),
+            Some(StateRequest::Load) if movie_going => println!("stop the movie to load a state"),
+            Some(StateRequest::Load) => match state_slots.load(cpu) {
+                Ok(path) => println!("state loaded from {}", path.display()),
+                Err(err) => println!("{}", err),
+            },
+            None => {}
+        }
+        match mixer_request.take() {
+            Some(MixerRequest::ToggleMute(source)) => {
+                let muted = cpu.bus.apu.mixer.muted(source);
+                cpu.bus.apu.mixer.set_muted(source, !muted);
+                println!("{}", cpu.bus.apu.mixer.describe());
+            }
+            Some(MixerRequest::NextVolumeSource) => {
+                let next = Source::ALL.iter().position(|source| *source == volume_source).unwrap() + 1;
+                volume_source = Source::ALL[next % Source::ALL.len()];
+                println!("volume keys change {}", volume_source.name());
+            }
+            Some(MixerRequest::Volume(change)) => {
+                let volume = (cpu.bus.apu.mixer.volume(volume_source) as i16 + change).clamp(0, mixer::MAX_VOLUME as i16);
+                cpu.bus.apu.mixer.set_volume(volume_source, volume as u8).unwrap();
+                println!("{}", cpu.bus.apu.mixer.describe());
+            }
+            None => {}
+        }
+        if wav_request.take() {
+            let mut wav = wav_recording.borrow_mut();
+            if wav.recording() {
+                match wav.stop() {
+                    Ok(seconds) => println!("{:.1}s of sound written to {}", seconds, wav.path()),
+                    Err(err) => println!("{}", err),
+                }
+                cpu.bus.apu.set_stems(false);
+            } else {
+                match wav.start(sample_rate as u32) {
+                    Ok(_) => println!("recording sound to {}", wav.path()),
+                    Err(err) => println!("{}", err),
+                }
+                cpu.bus.apu.set_stems(wav.recording() && wav.stems());
+            }
+        }
+        if timeline_request.take() {
+            cpu.bus.request_timeline();
+        }
+        if heatmap_request.take() {
+            println!("counting RAM accesses for {} frames", heatmap::DEFAULT_WINDOW_FRAMES);
+            cpu.bus.request_heatmap(heatmap::DEFAULT_WINDOW_FRAMES);
+        }
+
+        // with --trace-file the trace goes to the file only, and only while switched on
+        if trace_log.borrow().enabled() {
+            if trace_filter.as_mut().is_none_or(|filter| filter.should_trace(&cpu.bus, cpu.program_counter)) {
+                let mut trace_log = trace_log.borrow_mut();
+                if let Err(err) = trace_log.log(cpu) {
+                    println!("{}", err);
+                    let _ = trace_log.set_enabled(false);
+                }
+            }
+        } else if let Some(filter) = trace_filter.as_mut().filter(|_| trace_file.is_none()) {
+            if filter.should_trace(&cpu.bus, cpu.program_counter) {
+                println!("{}", trace(cpu));
+            }
+        }
+
+        if let Some(repl) = repl.as_mut() {
+            if let Some(reason) = repl.check(cpu) {
+                if let Some(watchdog) = watchdog.as_ref() {
+                    watchdog.set_idle(true);
+                }
+                debug_audio.lock().unwrap().set_state(PlaybackState::Paused);
+                repl.serve_paused(cpu, &reason);
+                debug_audio.lock().unwrap().set_state(PlaybackState::Running);
+                if let Some(watchdog) = watchdog.as_ref() {
+                    watchdog.set_idle(false);
+                }
+            }
+        }
+
+        #[cfg(feature = "debug-ui")]
+        if let Some(ui) = debug_ui.as_ref() {
+            let mut ui = ui.borrow_mut();
+            if let Some(reason) = ui.check(cpu) {
+                if let Some(watchdog) = watchdog.as_ref() {
+                    watchdog.set_idle(true);
+                }
+                debug_audio.lock().unwrap().set_state(PlaybackState::Paused);
+                ui.serve_paused(cpu, &reason, &mut paused_event_pump.borrow_mut());
+                debug_audio.lock().unwrap().set_state(PlaybackState::Running);
+                if let Some(watchdog) = watchdog.as_ref() {
+                    watchdog.set_idle(false);
+                }
+            }
+        }
+
+        if let Some(server) = debug_server.as_mut() {
+            if server.should_break(cpu) {
+                if let Some(watchdog) = watchdog.as_ref() {
+                    watchdog.set_idle(true);
+                }
+                debug_audio.lock().unwrap().set_state(PlaybackState::Paused);
+                server.serve_paused(cpu);
+                debug_audio.lock().unwrap().set_state(PlaybackState::Running);
+                if let Some(watchdog) = watchdog.as_ref() {
+                    watchdog.set_idle(false);
+                }
+            }
+        }
+
+        if let Some(watchdog) = watchdog.as_ref() {
+            if recent_pcs.len() == STALL_HISTORY {
+                recent_pcs.pop_front();
+            }
+            recent_pcs.push_back(cpu.program_counter);
+            // still executing but no frame: the game or a mapper is stuck
+            if watchdog.stalled() {
+                let report = stall_report(cpu, &recent_pcs);
+                let message = match watchdog.write_bundle(&report, &savestate::save(cpu)) {
+                    Ok(path) => format!("No frame for a while, diagnostics written to {}.", path.display()),
+                    Err(err) => format!("No frame for a while, failed to write diagnostics: {}.", err),
+                };
+                println!("{}", message);
+                if startup::offer_reset(&format!("{}\nReset the console?", message)) {
+                    cpu.soft_reset();
+                }
+            }
+        }
+
+        if cpu.bus.frame_count() == last_frame {
+            return;
+        }
+        last_frame = cpu.bus.frame_count();
+
+        // movies don't rewind
+        let rewound = rewinding.get() && movie.borrow().is_none();
+
+        // what the APU played during the frame that just ended; a frame about
+        // to be rewound is neither heard nor recorded
+        let samples = std::mem::take(&mut cpu.bus.apu.samples);
+        let stem_samples: Vec<Vec<f32>> = cpu.bus.apu.stem_samples.iter_mut().map(std::mem::take).collect();
+        if !rewound {
+            if let Err(err) = wav_recording.borrow_mut().record(&samples, &stem_samples) {
+                println!("{}", err);
+                wav_request.set(true);
+            }
+        }
+        let adjustment = {
+            let mut ring = sample_ring.lock().unwrap();
+            // pauses set and clear Paused where they happen
+            if ring.state() != PlaybackState::Paused {
+                let state = if rewound {
+                    PlaybackState::Rewind
+                } else if speed_unlocked.get() {
+                    PlaybackState::FastForward
+                } else {
+                    PlaybackState::Running
+                };
+                if ring.state() != state {
+                    ring.set_state(state);
+                }
+            }
+            if !rewound {
+                ring.push(&samples);
+            }
+            ring.rate_adjustment()
+        };
+        // keeps the ring from slowly running dry or over, see audio.rs
+        cpu.bus.apu.set_sample_rate(Some(sample_rate as f64 * adjustment));
+
+        // held, step back a state each frame instead of taking one
+        if rewound {
+            if let Some(state) = rewind_buffer.pop() {
+                savestate::load(cpu, &state).unwrap();
+                last_frame = cpu.bus.frame_count();
+            }
+        } else if last_frame % rewind::INTERVAL_FRAMES == 0 {
+            rewind_buffer.push(savestate::save(cpu));
+        }
+        if run_ahead_frames > 0 {
+            run_ahead::run_ahead(cpu, run_ahead_frames, &run_ahead_phase);
+        }
+
+        if let Some(watchdog) = watchdog.as_ref() {
+            watchdog.beat(last_frame);
+            // a known good state in case the emulator itself locks up
+            if last_frame % 60 == 0 {
+                watchdog.checkpoint(last_frame, savestate::save(cpu));
+            }
+        }
+
+        if let Some(server) = debug_server.as_mut() {
+            server.poll(cpu);
+        }
+        #[cfg(feature = "debug-ui")]
+        if let Some(ui) = debug_ui.as_ref() {
+            ui.borrow_mut().update(cpu);
+        }
+
+        if let Some(timeline) = cpu.bus.take_timeline() {
+            let written = std::fs::write("timeline.json", timeline.to_json())
+                .and_then(|_| std::fs::write("timeline.html", timeline.to_html()));
+            match written {
+                Ok(_) => println!("timeline of frame {} written to timeline.json and timeline.html", timeline.frame),
+                Err(err) => println!("failed to write the timeline: {}", err),
+            }
+        }
+
+        if let Some(heatmap) = cpu.bus.take_heatmap() {
+            match heatmap.save_ppm("ram_heatmap.ppm") {
+                Ok(_) => println!("RAM heatmap written to ram_heatmap.ppm, most written:"),
+                Err(err) => println!("failed to write the RAM heatmap: {}", err),
+            }
+            for (addr, reads, writes) in heatmap.hottest(16) {
+                println!("  ${:04X}: {} writes, {} reads", addr, writes, reads);
+            }
+        }
+
+        if let Some(recorder) = midi_recording.borrow_mut().as_mut() {
+            recorder.record_frame(&mut cpu.bus.apu);
+        }
+
+        if let Some(triggers) = triggers.as_mut() {
+            let actions = triggers.evaluate(|addr| cpu.bus.peek(addr));
+            fired_actions.borrow_mut().extend(actions);
+        }
+
+        // --profile: average over the last 60 frames
+        if let Some(frame_stats) = cpu.bus.profiler.last_frame() {
+            profile_totals.accumulate(&frame_stats);
+            if last_frame % 60 == 0 {
+                println!("frame {}: {}", last_frame, profile_totals.average(60));
+                profile_totals = stats::FrameStats::default();
+            }
+        }
+    });
 
 }
\ No newline at end of file
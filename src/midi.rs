@@ -0,0 +1,156 @@
+// Experimental soundtrack transcription: samples the APU registers once per
+// frame and turns pitch changes and note restarts of the pulse and triangle
+// channels into a standard MIDI file (format 0, one MIDI channel per APU
+// channel). One tick is one frame. Envelopes, sweeps and length counters are
+// not followed, so note ends are approximate.
+
+use crate::apu::{ApuRegisters, Channel};
+
+const CHANNELS: [Channel; 3] = [Channel::Pulse1, Channel::Pulse2, Channel::Triangle];
+// 120 bpm with 30 ticks per quarter note makes a tick 1/60s
+const TICKS_PER_QUARTER: u16 = 30;
+const TEMPO_US_PER_QUARTER: u32 = 500_000;
+// square lead for the pulses, a synth bass for the triangle
+const PROGRAMS: [u8; 3] = [80, 80, 38];
+
+pub fn frequency_to_note(frequency: f64) -> Option<u8> {
+    let note = (69.0 + 12.0 * (frequency / 440.0).log2()).round();
+    if (0.0..=127.0).contains(&note) {
+        Some(note as u8)
+    } else {
+        None
+    }
+}
+
+pub struct MidiRecorder {
+    frame: u32,
+    last_event_frame: u32,
+    playing: [Option<u8>; 3],
+    events: Vec<u8>,
+}
+
+impl Default for MidiRecorder {
+    fn default() -> Self {
+        MidiRecorder::new()
+    }
+}
+
+impl MidiRecorder {
+    pub fn new() -> Self {
+        let mut recorder = MidiRecorder {
+            frame: 0,
+            last_event_frame: 0,
+            playing: [None; 3],
+            events: vec![],
+        };
+        recorder.event(&[0xff, 0x51, 0x03]);
+        recorder.events.extend_from_slice(&TEMPO_US_PER_QUARTER.to_be_bytes()[1..]);
+        for (channel, program) in PROGRAMS.iter().enumerate() {
+            recorder.event(&[0xc0 | channel as u8, *program]);
+        }
+        recorder
+    }
+
+    fn event(&mut self, bytes: &[u8]) {
+        write_variable_length(&mut self.events, self.frame - self.last_event_frame);
+        self.last_event_frame = self.frame;
+        self.events.extend_from_slice(bytes);
+    }
+
+    // call once per frame
+    pub fn record_frame(&mut self, apu: &mut ApuRegisters) {
+        for (i, channel) in CHANNELS.iter().enumerate() {
+            let restarted = apu.take_restarted(*channel);
+            let velocity = apu.channel(*channel).volume * 8 + 7;
+            let note = apu.frequency(*channel).and_then(frequency_to_note);
+            if note == self.playing[i] && !restarted {
+                continue;
+            }
+            if let Some(playing) = self.playing[i].take() {
+                self.event(&[0x80 | i as u8, playing, 0]);
+            }
+            if let Some(note) = note {
+                self.event(&[0x90 | i as u8, note, velocity]);
+                self.playing[i] = Some(note);
+            }
+        }
+        self.frame += 1;
+    }
+
+    pub fn to_smf(&self) -> Vec<u8> {
+        // release whatever is still playing and end the track
+        let mut track = self.events.clone();
+        let mut delta = self.frame - self.last_event_frame;
+        for (i, playing) in self.playing.iter().enumerate() {
+            if let Some(note) = playing {
+                write_variable_length(&mut track, delta);
+                track.extend_from_slice(&[0x80 | i as u8, *note, 0]);
+                delta = 0;
+            }
+        }
+        write_variable_length(&mut track, delta);
+        track.extend_from_slice(&[0xff, 0x2f, 0x00]);
+
+        let mut smf = b"MThd".to_vec();
+        smf.extend_from_slice(&6u32.to_be_bytes());
+        smf.extend_from_slice(&0u16.to_be_bytes()); // format 0
+        smf.extend_from_slice(&1u16.to_be_bytes()); // one track
+        smf.extend_from_slice(&TICKS_PER_QUARTER.to_be_bytes());
+        smf.extend_from_slice(b"MTrk");
+        smf.extend_from_slice(&(track.len() as u32).to_be_bytes());
+        smf.extend(track);
+        smf
+    }
+}
+
+fn write_variable_length(out: &mut Vec<u8>, value: u32) {
+    let mut bytes = vec![(value & 0x7f) as u8];
+    let mut value = value >> 7;
+    while value > 0 {
+        bytes.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    out.extend(bytes.iter().rev());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_variable_length_and_notes() {
+        let mut out = vec![];
+        write_variable_length(&mut out, 0);
+        write_variable_length(&mut out, 0x7f);
+        write_variable_length(&mut out, 0x80);
+        write_variable_length(&mut out, 0x3fff);
+        assert_eq!(out, vec![0x00, 0x7f, 0x81, 0x00, 0xff, 0x7f]);
+
+        assert_eq!(frequency_to_note(440.0), Some(69));
+        assert_eq!(frequency_to_note(261.6), Some(60));
+        assert_eq!(frequency_to_note(1.0), None);
+    }
+
+    #[test]
+    fn test_records_note_on_and_off() {
+        let mut apu = ApuRegisters::new();
+        let mut recorder = MidiRecorder::new();
+        let header_len = recorder.events.len();
+
+        apu.write(0x4015, 0b001);
+        apu.write(0x4000, 0b0001_1111);
+        apu.write(0x4002, 0xfd);
+        apu.write(0x4003, 0x00);
+        recorder.record_frame(&mut apu);
+        recorder.record_frame(&mut apu);
+        apu.write(0x4015, 0);
+        recorder.record_frame(&mut apu);
+
+        assert_eq!(&recorder.events[header_len..], &[0, 0x90, 69, 127, 2, 0x80, 69, 0]);
+
+        let smf = recorder.to_smf();
+        assert_eq!(&smf[0..4], b"MThd");
+        assert_eq!(&smf[14..18], b"MTrk");
+        assert_eq!(&smf[smf.len() - 3..], &[0xff, 0x2f, 0x00]);
+    }
+}
@@ -0,0 +1,227 @@
+// Mixes the APU channels into one sample the way the console's resistor
+// network does: the two pulses share one non-linear DAC, the triangle, noise
+// and DMC another (https://www.nesdev.org/wiki/APU_Mixer). Expansion audio
+// from the cartridge is added on top; none of the boards emulated here have
+// any yet. A high-pass filter (HighPass, run at the output sample rate) then
+// takes out the DC offset, as the console's output capacitor does.
+//
+// Each source can be muted, turned up or down (0-200%, before the DACs, so
+// the non-linear mix still sounds like the console), or one soloed, to hear
+// parts of the music alone.
+
+use crate::apu::Channel;
+
+pub const MAX_VOLUME: u8 = 200;
+// pole of the DC-blocking filter, around 30Hz at 44.1kHz
+const HIGH_PASS: f32 = 0.996;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    Dmc,
+    Expansion,
+}
+
+impl Source {
+    pub const ALL: [Source; 6] =
+        [Source::Pulse1, Source::Pulse2, Source::Triangle, Source::Noise, Source::Dmc, Source::Expansion];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Source::Pulse1 => "pulse1",
+            Source::Pulse2 => "pulse2",
+            Source::Triangle => "triangle",
+            Source::Noise => "noise",
+            Source::Dmc => "dmc",
+            Source::Expansion => "expansion",
+        }
+    }
+
+    pub fn parse(name: &str) -> Result<Source, String> {
+        Source::ALL.iter().copied().find(|source| source.name() == name).ok_or(format!(
+            "unknown channel '{}', expected pulse1, pulse2, triangle, noise, dmc or expansion",
+            name
+        ))
+    }
+}
+
+impl From<Channel> for Source {
+    fn from(channel: Channel) -> Source {
+        match channel {
+            Channel::Pulse1 => Source::Pulse1,
+            Channel::Pulse2 => Source::Pulse2,
+            Channel::Triangle => Source::Triangle,
+            Channel::Noise => Source::Noise,
+            Channel::Dmc => Source::Dmc,
+        }
+    }
+}
+
+pub struct Mixer {
+    muted: [bool; 6],
+    // percent
+    volumes: [u8; 6],
+    solo: Option<Source>,
+}
+
+impl Default for Mixer {
+    fn default() -> Self {
+        Mixer::new()
+    }
+}
+
+impl Mixer {
+    pub fn new() -> Self {
+        Mixer { muted: [false; 6], volumes: [100; 6], solo: None }
+    }
+
+    pub fn muted(&self, source: Source) -> bool {
+        self.muted[source as usize]
+    }
+
+    pub fn set_muted(&mut self, source: Source, muted: bool) {
+        self.muted[source as usize] = muted;
+    }
+
+    pub fn volume(&self, source: Source) -> u8 {
+        self.volumes[source as usize]
+    }
+
+    pub fn set_volume(&mut self, source: Source, percent: u8) -> Result<(), String> {
+        if percent > MAX_VOLUME {
+            return Err(format!("volume should be 0-{}%, got {}%", MAX_VOLUME, percent));
+        }
+        self.volumes[source as usize] = percent;
+        Ok(())
+    }
+
+    pub fn solo(&self) -> Option<Source> {
+        self.solo
+    }
+
+    pub fn set_solo(&mut self, solo: Option<Source>) {
+        self.solo = solo;
+    }
+
+    // a soloed source plays even when muted, and alone
+    pub fn audible(&self, source: Source) -> bool {
+        match self.solo {
+            Some(solo) => solo == source,
+            None => !self.muted(source),
+        }
+    }
+
+    // "pulse1 100%  pulse2 50% muted ..."
+    pub fn describe(&self) -> String {
+        let sources: Vec<String> = Source::ALL
+            .iter()
+            .map(|source| {
+                let state = match (self.solo == Some(*source), self.muted(*source)) {
+                    (true, _) => " solo",
+                    (false, true) => " muted",
+                    (false, false) => "",
+                };
+                format!("{} {}%{}", source.name(), self.volume(*source), state)
+            })
+            .collect();
+        sources.join("  ")
+    }
+
+    // `outputs` are the channels' DAC levels in Channel::ALL order,
+    // `expansion` the cartridge's sound at the scale of the mixed output
+    pub fn level(&self, outputs: [u8; 5], expansion: f32) -> f32 {
+        let gain = |source: Source| {
+            if self.audible(source) {
+                self.volume(source) as f32 / 100.0
+            } else {
+                0.0
+            }
+        };
+        let mut levels = [0.0; 5];
+        for (level, channel) in levels.iter_mut().zip(Channel::ALL.iter()) {
+            *level = outputs[*channel as usize] as f32 * gain((*channel).into());
+        }
+        dac(levels, expansion * gain(Source::Expansion))
+    }
+}
+
+// The console's mix of the channels' levels, in Channel::ALL order, as is: no
+// mute, volume or high-pass. Per-channel recordings use it on one channel.
+pub fn dac(levels: [f32; 5], expansion: f32) -> f32 {
+    let level = |channel: Channel| levels[channel as usize];
+    let pulse = level(Channel::Pulse1) + level(Channel::Pulse2);
+    let pulse_out = if pulse == 0.0 { 0.0 } else { 95.88 / (8128.0 / pulse + 100.0) };
+    let tnd = level(Channel::Triangle) / 8227.0 + level(Channel::Noise) / 12241.0 + level(Channel::Dmc) / 22638.0;
+    let tnd_out = if tnd == 0.0 { 0.0 } else { 159.79 / (1.0 / tnd + 100.0) };
+    pulse_out + tnd_out + expansion
+}
+
+// the DC-blocking filter, one output sample at a time
+pub struct HighPass {
+    last_input: f32,
+    last_output: f32,
+}
+
+impl Default for HighPass {
+    fn default() -> Self {
+        HighPass::new()
+    }
+}
+
+impl HighPass {
+    pub fn new() -> Self {
+        HighPass { last_input: 0.0, last_output: 0.0 }
+    }
+
+    pub fn filter(&mut self, input: f32) -> f32 {
+        let output = HIGH_PASS * self.last_output + input - self.last_input;
+        self.last_input = input;
+        self.last_output = output;
+        output
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mute_solo_and_dc() {
+        let mixer = Mixer::new();
+        let mut high_pass = HighPass::new();
+        // a step up shows, then decays back towards 0
+        assert!(high_pass.filter(mixer.level([15, 0, 0, 0, 0], 0.0)) > 0.1);
+        let mut last = 1.0;
+        for _ in 0..2000 {
+            last = high_pass.filter(mixer.level([15, 0, 0, 0, 0], 0.0));
+        }
+        assert!(last.abs() < 0.01);
+
+        let mut muted = Mixer::new();
+        muted.set_muted(Source::Pulse1, true);
+        assert!(!muted.audible(Source::Pulse1));
+        assert_eq!(muted.level([15, 0, 0, 0, 0], 0.0), 0.0);
+        // soloed, the muted pulse plays and the triangle doesn't
+        muted.set_solo(Some(Source::Pulse1));
+        assert!(!muted.audible(Source::Triangle));
+        assert_eq!(muted.level([15, 0, 15, 0, 0], 0.0), Mixer::new().level([15, 0, 0, 0, 0], 0.0));
+        assert_eq!(muted.describe(), "pulse1 100% solo  pulse2 100%  triangle 100%  noise 100%  dmc 100%  expansion 100%");
+    }
+
+    #[test]
+    fn test_volumes() {
+        let loud = Mixer::new().level([0, 0, 15, 0, 0], 0.5);
+        let mut mixer = Mixer::new();
+        mixer.set_volume(Source::Triangle, 50).unwrap();
+        mixer.set_volume(Source::Expansion, 0).unwrap();
+        let quiet = mixer.level([0, 0, 15, 0, 0], 0.5);
+        assert!(quiet > 0.0 && quiet < loud / 2.0, "{} {}", quiet, loud);
+        assert_eq!(quiet, dac([0.0, 0.0, 7.5, 0.0, 0.0], 0.0));
+        assert!(mixer.set_volume(Source::Noise, 201).is_err());
+        assert_eq!(Source::parse("dmc"), Ok(Source::Dmc));
+        assert!(Source::parse("vrc6").is_err());
+    }
+}
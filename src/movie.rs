@@ -0,0 +1,464 @@
+use crate::cartridge::nointro;
+use crate::joypad::JoypadButton;
+use crate::savestate::{self, Format, StateReader, StateWriter};
+
+// Movie files: the ROM's SHA-1 as lowercase hex (empty when unknown), a bool
+// for whether a start state follows and the savestate block if so, then a
+// usize frame count and each frame's input as two bytes, port 0 and port 1,
+// with the buttons as bit 0 A, 1 B, 2 Select, 3 Start, 4 Up, 5 Down, 6 Left
+// and 7 Right.
+pub const MOVIE: Format = Format { magic: *b"NESM", version: 2, name: "movie" };
+// Movie snapshots: the input log up to the snapshot laid out as in a movie
+// file, then the savestate as a block of bytes.
+pub const MOVIE_SNAPSHOT: Format = Format { magic: *b"NESB", version: 1, name: "movie snapshot" };
+
+// FM2 input columns, in the order FCEUX writes them
+const FM2_BUTTONS: [JoypadButton; 8] = [
+    JoypadButton::RIGHT,
+    JoypadButton::LEFT,
+    JoypadButton::DOWN,
+    JoypadButton::UP,
+    JoypadButton::START,
+    JoypadButton::SELECT,
+    JoypadButton::BUTTON_B,
+    JoypadButton::BUTTON_A,
+];
+
+// Per-frame joypad input for both controller ports, and where it starts.
+// Replaying the input from the same start on the same ROM gives the same
+// run, frame for frame.
+pub struct Movie {
+    pub frames: Vec<[JoypadButton; 2]>,
+    // the ROM it was recorded on, as nointro::hash_rom gives it
+    pub rom_sha1: Option<String>,
+    // the savestate it starts from, power-on when None
+    pub start_state: Option<Vec<u8>>,
+}
+
+impl Movie {
+    pub fn new(frames: Vec<[JoypadButton; 2]>) -> Self {
+        Movie {
+            frames,
+            rom_sha1: None,
+            start_state: None,
+        }
+    }
+
+    // movies that don't say which ROM they're for play on any
+    pub fn check_rom(&self, sha1: &str) -> Result<(), String> {
+        match self.rom_sha1.as_deref() {
+            Some(recorded) if recorded != sha1 => {
+                Err(format!("the movie was recorded on ROM {}, this one is {}", recorded, sha1))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // https://fceux.com/web/FM2.html
+    // header lines are `key value`, input lines look like `|0|RLDUTSBA|........||`
+    // Movies that need more than two standard controllers from power-on are
+    // refused rather than played wrong.
+    pub fn parse_fm2(text: &str) -> Result<Movie, String> {
+        let mut frames = vec![];
+        for (line_no, line) in text.lines().enumerate() {
+            if !line.starts_with('|') {
+                let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+                let unsupported = match (key, value.trim()) {
+                    ("savestate", _) => Some("movies that start from an FCEUX savestate"),
+                    ("fourscore", "1") => Some("Four Score movies"),
+                    ("port0", "2") | ("port1", "2") => Some("zapper movies"),
+                    ("FDS", "1") => Some("Famicom Disk System movies"),
+                    _ => None,
+                };
+                if let Some(unsupported) = unsupported {
+                    return Err(format!("fm2 line {}: {} are not supported", line_no + 1, unsupported));
+                }
+                continue;
+            }
+            let fields: Vec<&str> = line.split('|').collect();
+            if fields.len() < 4 {
+                return Err(format!("fm2 line {}: expected |commands|port0|port1|", line_no + 1));
+            }
+            // bit 0 soft reset, 1 power cycle, the rest are FDS and VS commands
+            if fields[1].trim().parse::<u8>() != Ok(0) {
+                return Err(format!("fm2 line {}: command '{}' is not supported", line_no + 1, fields[1]));
+            }
+            frames.push([
+                parse_fm2_port(fields[2], line_no)?,
+                parse_fm2_port(fields[3], line_no)?,
+            ]);
+        }
+        Ok(Movie::new(frames))
+    }
+
+    // FM2 for FCEUX, from power-on only: FCEUX can't load our savestates.
+    // `rom` is the iNES file the movie was recorded on.
+    pub fn to_fm2(&self, rom_filename: &str, rom: &[u8]) -> Result<String, String> {
+        if self.start_state.is_some() {
+            return Err("fm2: only movies recorded from power-on can be written as FM2".to_string());
+        }
+        let checksum = md5::compute(nointro::rom_payload(rom)).0;
+        let mut text = String::new();
+        text.push_str("version 3\n");
+        text.push_str("emuVersion 22020\n");
+        text.push_str("rerecordCount 0\n");
+        text.push_str("palFlag 0\n");
+        text.push_str(&format!("romFilename {}\n", rom_filename));
+        text.push_str(&format!("romChecksum base64:{}\n", base64(&checksum)));
+        text.push_str(&format!("guid {}\n", self.fm2_guid(&checksum)));
+        text.push_str("fourscore 0\nmicrophone 0\nport0 1\nport1 1\nport2 0\nFDS 0\nNewPPU 0\n");
+        for input in self.frames.iter() {
+            text.push_str(&format!("|0|{}|{}||\n", fm2_port(input[0]), fm2_port(input[1])));
+        }
+        Ok(text)
+    }
+
+    // FCEUX ties savestates to a movie by its GUID; the same movie of the
+    // same ROM always gets the same one
+    fn fm2_guid(&self, rom_checksum: &[u8; 16]) -> String {
+        let mut context = md5::Context::new();
+        context.consume(rom_checksum);
+        for input in self.frames.iter() {
+            context.consume([input[0].bits(), input[1].bits()]);
+        }
+        let hex: String = context.compute().0.iter().map(|byte| format!("{:02X}", byte)).collect();
+        format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+    }
+
+    // a movie file, or an FM2 text movie
+    pub fn parse(data: &[u8]) -> Result<Movie, String> {
+        if savestate::is_format(data, &MOVIE) {
+            return Movie::from_bytes(data);
+        }
+        let text = std::str::from_utf8(data).map_err(|_| "not a movie file or an FM2 movie".to_string())?;
+        Movie::parse_fm2(text)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = StateWriter::with_format(&MOVIE);
+        writer.bytes(self.rom_sha1.as_deref().unwrap_or("").as_bytes());
+        writer.bool(self.start_state.is_some());
+        if let Some(state) = self.start_state.as_ref() {
+            writer.bytes(state);
+        }
+        write_inputs(&mut writer, &self.frames);
+        writer.finish()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Movie, String> {
+        let mut reader = StateReader::with_format(data, &MOVIE)?;
+        let sha1 = std::str::from_utf8(reader.bytes()?).map_err(|_| "movie: the ROM hash is not text".to_string())?;
+        let rom_sha1 = if sha1.is_empty() { None } else { Some(sha1.to_string()) };
+        let start_state = if reader.bool()? { Some(reader.bytes()?.to_vec()) } else { None };
+        let frames = read_inputs(&mut reader)?;
+        reader.finish()?;
+        Ok(Movie { frames, rom_sha1, start_state })
+    }
+}
+
+fn write_inputs(writer: &mut StateWriter, inputs: &[[JoypadButton; 2]]) {
+    writer.usize(inputs.len());
+    for input in inputs.iter() {
+        writer.u8(input[0].bits());
+        writer.u8(input[1].bits());
+    }
+}
+
+fn read_inputs(reader: &mut StateReader) -> Result<Vec<[JoypadButton; 2]>, String> {
+    let frames = reader.usize()?;
+    let mut inputs = vec![];
+    for _ in 0..frames {
+        inputs.push([
+            JoypadButton::from_bits_truncate(reader.u8()?),
+            JoypadButton::from_bits_truncate(reader.u8()?),
+        ]);
+    }
+    Ok(inputs)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MovieMode {
+    Recording,
+    // read-only: input comes from the movie and states can't change it
+    Playback,
+}
+
+// A machine state saved during a movie, together with the input log that led
+// up to it. Loading one puts the movie back at the same point.
+pub struct MovieSnapshot {
+    pub inputs: Vec<[JoypadButton; 2]>,
+    pub state: Vec<u8>,
+}
+
+impl MovieSnapshot {
+    pub fn frame(&self) -> usize {
+        self.inputs.len()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = StateWriter::with_format(&MOVIE_SNAPSHOT);
+        write_inputs(&mut writer, &self.inputs);
+        writer.bytes(&self.state);
+        writer.finish()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<MovieSnapshot, String> {
+        let mut reader = StateReader::with_format(data, &MOVIE_SNAPSHOT)?;
+        let inputs = read_inputs(&mut reader)?;
+        let state = reader.bytes()?.to_vec();
+        reader.finish()?;
+        Ok(MovieSnapshot { inputs, state })
+    }
+}
+
+// Keeps the input log in step with savestates (TAS "branches"). Loading a
+// state while recording rewinds the log to that state's input prefix; the
+// frames that get cut off are kept in `branches` instead of being lost. In
+// playback a state only loads if it belongs to the movie being played.
+pub struct MovieRecorder {
+    pub movie: Movie,
+    pub mode: MovieMode,
+    // the next frame to play back, the log length while recording
+    position: usize,
+    // logs as they were before a load cut them, oldest first
+    pub branches: Vec<Movie>,
+}
+
+impl MovieRecorder {
+    pub fn record() -> Self {
+        MovieRecorder {
+            movie: Movie::new(vec![]),
+            mode: MovieMode::Recording,
+            position: 0,
+            branches: vec![],
+        }
+    }
+
+    pub fn play(movie: Movie) -> Self {
+        MovieRecorder {
+            movie,
+            mode: MovieMode::Playback,
+            position: 0,
+            branches: vec![],
+        }
+    }
+
+    pub fn frame(&self) -> usize {
+        self.position
+    }
+
+    // The input for the next frame. Recording logs `live` and returns it,
+    // playback returns the movie's input and None once it has run out.
+    pub fn next_frame(&mut self, live: [JoypadButton; 2]) -> Option<[JoypadButton; 2]> {
+        let input = match self.mode {
+            MovieMode::Recording => {
+                self.movie.frames.push(live);
+                live
+            }
+            MovieMode::Playback => *self.movie.frames.get(self.position)?,
+        };
+        self.position += 1;
+        Some(input)
+    }
+
+    pub fn snapshot(&self, state: Vec<u8>) -> MovieSnapshot {
+        MovieSnapshot {
+            inputs: self.movie.frames[..self.position].to_vec(),
+            state,
+        }
+    }
+
+    // Moves the movie to the snapshot; the caller loads snapshot.state into
+    // the machine once this succeeds.
+    pub fn restore(&mut self, snapshot: &MovieSnapshot) -> Result<(), String> {
+        let on_this_branch = self.movie.frames.starts_with(&snapshot.inputs);
+        match self.mode {
+            MovieMode::Playback if !on_this_branch => {
+                return Err(format!(
+                    "the state at frame {} is from a different branch than the movie being played",
+                    snapshot.frame()
+                ));
+            }
+            MovieMode::Playback => {}
+            MovieMode::Recording => {
+                let cut = !on_this_branch || self.movie.frames.len() > snapshot.frame();
+                if cut {
+                    let frames = std::mem::replace(&mut self.movie.frames, snapshot.inputs.clone());
+                    self.branches.push(Movie {
+                        frames,
+                        rom_sha1: self.movie.rom_sha1.clone(),
+                        start_state: self.movie.start_state.clone(),
+                    });
+                }
+            }
+        }
+        self.position = snapshot.frame();
+        Ok(())
+    }
+}
+
+fn fm2_port(buttons: JoypadButton) -> String {
+    FM2_BUTTONS
+        .iter()
+        .zip("RLDUTSBA".chars())
+        .map(|(button, c)| if buttons.contains(*button) { c } else { '.' })
+        .collect()
+}
+
+// standard base64 with padding, as FM2 checksums are written
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, byte)| bits | (*byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn parse_fm2_port(field: &str, line_no: usize) -> Result<JoypadButton, String> {
+    if field.is_empty() {
+        return Ok(JoypadButton::empty());
+    }
+    if field.chars().count() != 8 {
+        return Err(format!("fm2 line {}: joypad field '{}' should have 8 columns", line_no + 1, field));
+    }
+    let mut buttons = JoypadButton::empty();
+    for (button, c) in FM2_BUTTONS.iter().zip(field.chars()) {
+        if c != '.' && c != ' ' {
+            buttons.insert(*button);
+        }
+    }
+    Ok(buttons)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_fm2() {
+        let movie = Movie::parse_fm2(
+            "version 3\nromFilename smb\nport0 1\nport1 1\n|0|........|........||\n|0|....T...|.......A||\n|0|R......A|||\n",
+        )
+        .unwrap();
+
+        assert_eq!(movie.frames.len(), 3);
+        assert_eq!(movie.frames[0], [JoypadButton::empty(), JoypadButton::empty()]);
+        assert_eq!(movie.frames[1], [JoypadButton::START, JoypadButton::BUTTON_A]);
+        assert_eq!(
+            movie.frames[2],
+            [JoypadButton::RIGHT | JoypadButton::BUTTON_A, JoypadButton::empty()]
+        );
+        assert!(Movie::parse_fm2("|0|RLD|\n").is_err());
+        assert!(Movie::parse_fm2("|1|........|........||\n").is_err());
+        assert!(Movie::parse_fm2("fourscore 1\n|0|........|........|........|........||\n").is_err());
+        assert!(Movie::parse_fm2("savestate base64:AAAA\n").is_err());
+    }
+
+    #[test]
+    fn test_write_fm2() {
+        let mut rom = vec![0; 16];
+        rom.extend_from_slice(b"prg and chr");
+        let movie = Movie::new(vec![
+            [JoypadButton::RIGHT | JoypadButton::BUTTON_A, JoypadButton::empty()],
+            [JoypadButton::START, JoypadButton::UP | JoypadButton::BUTTON_B],
+        ]);
+        let text = movie.to_fm2("game.nes", &rom).unwrap();
+        assert!(text.starts_with("version 3\n"));
+        assert!(text.contains("romFilename game.nes\n"));
+        let checksum = md5::compute(b"prg and chr").0;
+        assert!(text.contains(&format!("romChecksum base64:{}\n", base64(&checksum))));
+        assert!(text.ends_with("|0|R......A|........||\n|0|....T...|...U..B.||\n"));
+        assert_eq!(Movie::parse_fm2(&text).unwrap().frames, movie.frames);
+        assert_eq!(text, movie.to_fm2("game.nes", &rom).unwrap());
+
+        assert_eq!(base64(b"Man"), "TWFu");
+        assert_eq!(base64(b"Ma"), "TWE=");
+        assert_eq!(base64(b"M"), "TQ==");
+
+        let mut from_state = Movie::new(vec![]);
+        from_state.start_state = Some(vec![0]);
+        assert!(from_state.to_fm2("game.nes", &rom).is_err());
+    }
+
+    #[test]
+    fn test_movie_file() {
+        let mut movie = Movie::new(vec![[JoypadButton::BUTTON_A, JoypadButton::RIGHT | JoypadButton::START]]);
+        let bytes = movie.to_bytes();
+        let mut expected = b"NESM\x02".to_vec();
+        expected.extend_from_slice(&[0; 8]); // no ROM hash
+        expected.push(0); // no start state
+        expected.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0, 0x01, 0x88]);
+        assert_eq!(bytes, expected);
+        assert_eq!(Movie::parse(&bytes).unwrap().frames, movie.frames);
+        assert_eq!(Movie::parse(b"|0|.......A|R...T...||\n").unwrap().frames, movie.frames);
+        assert!(Movie::parse(b"NESM\x01").is_err());
+        assert!(Movie::parse(&[0xff, 0xfe]).is_err());
+        assert!(MovieSnapshot::from_bytes(&bytes).is_err());
+
+        movie.rom_sha1 = Some("abc123".to_string());
+        movie.start_state = Some(vec![1, 2, 3]);
+        let loaded = Movie::from_bytes(&movie.to_bytes()).unwrap();
+        assert_eq!(loaded.rom_sha1.as_deref(), Some("abc123"));
+        assert_eq!(loaded.start_state, Some(vec![1, 2, 3]));
+        assert!(loaded.check_rom("abc123").is_ok());
+        assert!(loaded.check_rom("def456").is_err());
+        assert!(Movie::new(vec![]).check_rom("def456").is_ok());
+    }
+
+    fn input(buttons: JoypadButton) -> [JoypadButton; 2] {
+        [buttons, JoypadButton::empty()]
+    }
+
+    #[test]
+    fn test_loading_states_forks_the_recording() {
+        let mut recorder = MovieRecorder::record();
+        recorder.next_frame(input(JoypadButton::START));
+        recorder.next_frame(input(JoypadButton::empty()));
+        let early = recorder.snapshot(vec![1]);
+        recorder.next_frame(input(JoypadButton::BUTTON_A));
+        let late = recorder.snapshot(vec![2]);
+        assert_eq!(late.frame(), 3);
+
+        // back to frame 2 and a different input from there: the A press is kept as a branch
+        recorder.restore(&early).unwrap();
+        recorder.next_frame(input(JoypadButton::BUTTON_B));
+        assert_eq!(recorder.movie.frames, early.inputs.iter().copied().chain([input(JoypadButton::BUTTON_B)]).collect::<Vec<_>>());
+        assert_eq!(recorder.branches.len(), 1);
+        assert_eq!(recorder.branches[0].frames, late.inputs);
+
+        // a state from the abandoned branch brings its whole prefix back
+        recorder.restore(&late).unwrap();
+        assert_eq!(recorder.movie.frames, late.inputs);
+        assert_eq!(recorder.frame(), 3);
+        assert_eq!(recorder.branches.len(), 2);
+
+        // loading the state at the end of the log cuts nothing
+        recorder.restore(&late).unwrap();
+        assert_eq!(recorder.branches.len(), 2);
+
+        let bytes = late.to_bytes();
+        let loaded = MovieSnapshot::from_bytes(&bytes).unwrap();
+        assert_eq!((loaded.inputs, loaded.state), (late.inputs.clone(), vec![2]));
+        assert!(MovieSnapshot::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+
+        // playback refuses states from other branches and never edits the movie
+        let mut player = MovieRecorder::play(Movie::new(late.inputs.clone()));
+        player.restore(&early).unwrap();
+        assert_eq!(player.next_frame(input(JoypadButton::UP)), Some(input(JoypadButton::BUTTON_A)));
+        assert_eq!(player.next_frame(input(JoypadButton::UP)), None);
+        let other = MovieSnapshot {
+            inputs: vec![input(JoypadButton::SELECT)],
+            state: vec![],
+        };
+        assert!(player.restore(&other).is_err());
+        assert_eq!(player.movie.frames, late.inputs);
+    }
+}
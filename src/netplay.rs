@@ -0,0 +1,521 @@
+// Netplay: two emulators, one player each, kept in step over UDP with
+// rollback.
+//
+// Both sides start from power-on with the same ROM and exchange only their
+// controller input. A side doesn't wait for the other's input: it predicts
+// the peer keeps holding what it held last and runs on. When the real input
+// arrives and differs, the machine is loaded from the savestate taken at the
+// start of that frame and the frames since are run again with it. Local input
+// is delayed by a few frames (`delay`) so that on a short link it usually
+// arrives before it is needed and nothing has to be run again. A side never
+// runs more than MAX_PREDICTION frames past the peer's input, which also
+// keeps the two in step when one machine is faster.
+//
+// UDP drops packets, so every input packet carries all of the sender's input
+// the peer hasn't acknowledged yet. Every CHECKSUM_INTERVAL frames both sides
+// send a CRC-32 of the machine state at the start of that frame once all the
+// input before it is known; two different checksums for the same frame mean
+// the games have desynced.
+//
+// Packets are in the savestate container (savestate.rs) as "NESN": a u8 tag
+// then the fields of the packet, see Packet.
+
+use crate::joypad::JoypadButton;
+use crate::savestate::{Format, StateReader, StateWriter};
+use crate::session::Session;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+pub const NETPLAY: Format = Format { magic: *b"NESN", version: 1, name: "netplay" };
+pub const DEFAULT_PORT: u16 = 6503;
+pub const DEFAULT_DELAY: usize = 2;
+pub const MAX_DELAY: usize = 10;
+pub const MAX_PREDICTION: usize = 8;
+pub const CHECKSUM_INTERVAL: usize = 60;
+// input packets carry at most this many frames
+const MAX_PACKET_FRAMES: usize = 64;
+const HELLO_INTERVAL: Duration = Duration::from_millis(200);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(60);
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq)]
+enum Packet {
+    // sent until the peer answers; `heard` once the peer's hello arrived
+    Hello { rom_sha1: String, player: u8, delay: u8, heard: bool },
+    // the sender's input from `first_frame` on, and `ack`, the first frame
+    // of the receiver's input the sender doesn't have yet
+    Input { ack: usize, first_frame: usize, inputs: Vec<JoypadButton> },
+    Checksum { frame: usize, crc: u32 },
+}
+
+impl Packet {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = StateWriter::with_format(&NETPLAY);
+        match self {
+            Packet::Hello { rom_sha1, player, delay, heard } => {
+                writer.u8(0);
+                writer.bytes(rom_sha1.as_bytes());
+                writer.u8(*player);
+                writer.u8(*delay);
+                writer.bool(*heard);
+            }
+            Packet::Input { ack, first_frame, inputs } => {
+                writer.u8(1);
+                writer.usize(*ack);
+                writer.usize(*first_frame);
+                writer.bytes(&inputs.iter().map(|input| input.bits()).collect::<Vec<u8>>());
+            }
+            Packet::Checksum { frame, crc } => {
+                writer.u8(2);
+                writer.usize(*frame);
+                writer.u32(*crc);
+            }
+        }
+        writer.finish()
+    }
+
+    fn from_bytes(data: &[u8]) -> Result<Packet, String> {
+        let mut reader = StateReader::with_format(data, &NETPLAY)?;
+        let packet = match reader.u8()? {
+            0 => Packet::Hello {
+                rom_sha1: String::from_utf8_lossy(reader.bytes()?).into_owned(),
+                player: reader.u8()?,
+                delay: reader.u8()?,
+                heard: reader.bool()?,
+            },
+            1 => Packet::Input {
+                ack: reader.usize()?,
+                first_frame: reader.usize()?,
+                inputs: reader.bytes()?.iter().map(|bits| JoypadButton::from_bits_truncate(*bits)).collect(),
+            },
+            2 => Packet::Checksum { frame: reader.usize()?, crc: reader.u32()? },
+            tag => return Err(format!("netplay: unknown packet {}", tag)),
+        };
+        reader.finish()?;
+        Ok(packet)
+    }
+}
+
+// The rollback bookkeeping for one side, apart from the network.
+pub struct Rollback {
+    // 0 or 1, the controller port this side plays on
+    local_port: usize,
+    delay: usize,
+    // the next frame to emulate
+    frame: usize,
+    // input by frame; this side's starts with `delay` empty frames, and so
+    // does the peer's on its side
+    local: Vec<JoypadButton>,
+    remote: Vec<JoypadButton>,
+    // the peer's input each emulated frame was run with, predicted or not
+    remote_used: Vec<JoypadButton>,
+    // the state at the start of each frame from the first unconfirmed one on
+    states: VecDeque<(usize, Vec<u8>)>,
+    // the first frame that was run with a wrong prediction
+    rollback_to: Option<usize>,
+    local_checksums: BTreeMap<usize, u32>,
+    remote_checksums: BTreeMap<usize, u32>,
+    // taken but not sent yet
+    outgoing_checksums: Vec<(usize, u32)>,
+    // frames run again after a wrong prediction, for the stats
+    pub frames_rolled_back: usize,
+}
+
+impl Rollback {
+    pub fn new(local_port: usize, delay: usize) -> Self {
+        Rollback {
+            local_port,
+            delay,
+            frame: 0,
+            local: vec![JoypadButton::empty(); delay],
+            remote: vec![JoypadButton::empty(); delay],
+            remote_used: vec![],
+            states: VecDeque::new(),
+            rollback_to: None,
+            local_checksums: BTreeMap::new(),
+            remote_checksums: BTreeMap::new(),
+            outgoing_checksums: vec![],
+            frames_rolled_back: 0,
+        }
+    }
+
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    // one input per frame emulated, `delay` frames ahead of it
+    pub fn needs_local_input(&self) -> bool {
+        self.local.len() <= self.frame + self.delay
+    }
+
+    pub fn add_local_input(&mut self, buttons: JoypadButton) {
+        self.local.push(buttons);
+    }
+
+    pub fn add_remote_input(&mut self, first_frame: usize, inputs: &[JoypadButton]) {
+        for (frame, input) in (first_frame..).zip(inputs.iter()) {
+            if frame < self.remote.len() {
+                continue;
+            }
+            // a gap, the packets in between were lost and will come again
+            if frame > self.remote.len() {
+                break;
+            }
+            self.remote.push(*input);
+            if self.remote_used.get(frame).is_some_and(|used| used != input) {
+                self.rollback_to = Some(self.rollback_to.map_or(frame, |earlier| earlier.min(frame)));
+            }
+        }
+    }
+
+    // the peer's input as far as it has arrived; the ack for the peer
+    pub fn remote_len(&self) -> usize {
+        self.remote.len()
+    }
+
+    pub fn local_inputs(&self, first_frame: usize) -> &[JoypadButton] {
+        let end = self.local.len().min(first_frame + MAX_PACKET_FRAMES);
+        self.local.get(first_frame..end).unwrap_or(&[])
+    }
+
+    fn can_advance(&self) -> bool {
+        self.frame < self.local.len() && self.frame < self.remote.len() + MAX_PREDICTION
+    }
+
+    // Puts right what a wrong prediction got wrong, then runs the next frame
+    // if the input allows. Returns whether a new frame was run.
+    pub fn advance(&mut self, session: &mut Session) -> Result<bool, String> {
+        if let Some(from) = self.rollback_to.take() {
+            let first = self.states.front().map(|(frame, _)| *frame).ok_or("netplay: no state to roll back to")?;
+            let index = from.checked_sub(first).ok_or("netplay: the state to roll back to is gone")?;
+            session.load_state(&self.states[index].1)?;
+            self.states.truncate(index);
+            self.remote_used.truncate(from);
+            let end = self.frame;
+            self.frame = from;
+            while self.frame < end {
+                self.run_frame(session);
+                self.frames_rolled_back += 1;
+            }
+        }
+        let advanced = self.can_advance();
+        if advanced {
+            self.run_frame(session);
+        }
+        self.confirm();
+        Ok(advanced)
+    }
+
+    fn run_frame(&mut self, session: &mut Session) {
+        self.states.push_back((self.frame, session.save_state()));
+        let remote = match self.remote.get(self.frame) {
+            Some(input) => *input,
+            // the peer keeps holding what it held last
+            None => self.remote.last().copied().unwrap_or_else(JoypadButton::empty),
+        };
+        self.remote_used.push(remote);
+        let mut input = [remote; 2];
+        input[self.local_port] = self.local[self.frame];
+        session.step_frame(input);
+        self.frame += 1;
+    }
+
+    // states from before the first unconfirmed frame can't be rolled back to
+    // any more, and are final
+    fn confirm(&mut self) {
+        while let Some((frame, state)) = self.states.front() {
+            if *frame >= self.remote.len() {
+                break;
+            }
+            if frame % CHECKSUM_INTERVAL == 0 {
+                let crc = crc32fast::hash(state);
+                self.local_checksums.insert(*frame, crc);
+                self.outgoing_checksums.push((*frame, crc));
+            }
+            self.states.pop_front();
+        }
+    }
+
+    pub fn take_checksums(&mut self) -> Vec<(usize, u32)> {
+        std::mem::take(&mut self.outgoing_checksums)
+    }
+
+    pub fn add_remote_checksum(&mut self, frame: usize, crc: u32) {
+        self.remote_checksums.insert(frame, crc);
+    }
+
+    // an error naming the first frame where the two machines differ
+    pub fn check_desync(&mut self) -> Result<(), String> {
+        let compared: Vec<usize> = self
+            .local_checksums
+            .keys()
+            .filter(|frame| self.remote_checksums.contains_key(frame))
+            .copied()
+            .collect();
+        for frame in compared {
+            let local = self.local_checksums.remove(&frame);
+            if local != self.remote_checksums.remove(&frame) {
+                return Err(format!("netplay: desynced, the machines differ at frame {}", frame));
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct Netplay {
+    socket: UdpSocket,
+    peer: SocketAddr,
+    hello: Packet,
+    pub rollback: Rollback,
+    // the first frame of our input the peer hasn't acknowledged
+    peer_ack: usize,
+    last_heard: Instant,
+}
+
+impl Netplay {
+    // Waits for the peer at `peer_addr` to say hello. Player 1 plays on port
+    // 0, player 2 on port 1; both sides need the same ROM and delay.
+    pub fn connect(bind_addr: &str, peer_addr: &str, player: u8, delay: usize, rom_sha1: &str) -> Result<Netplay, String> {
+        if player != 1 && player != 2 {
+            return Err(format!("netplay: player should be 1 or 2, got {}", player));
+        }
+        if delay > MAX_DELAY {
+            return Err(format!("netplay: delay should be at most {} frames, got {}", MAX_DELAY, delay));
+        }
+        let socket = UdpSocket::bind(bind_addr).map_err(|err| format!("netplay: failed to bind {}: {}", bind_addr, err))?;
+        let peer = std::net::ToSocketAddrs::to_socket_addrs(peer_addr)
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .ok_or_else(|| format!("netplay: can't resolve {}", peer_addr))?;
+        socket.set_nonblocking(true).map_err(|err| format!("netplay: {}", err))?;
+        let mut netplay = Netplay {
+            socket,
+            peer,
+            hello: Packet::Hello { rom_sha1: rom_sha1.to_string(), player, delay: delay as u8, heard: false },
+            rollback: Rollback::new(player as usize - 1, delay),
+            peer_ack: 0,
+            last_heard: Instant::now(),
+        };
+
+        let started = Instant::now();
+        let mut connected = false;
+        while !connected {
+            if started.elapsed() > CONNECT_TIMEOUT {
+                return Err(format!("netplay: no answer from {}", peer_addr));
+            }
+            netplay.send(&netplay.hello.clone());
+            let waited = Instant::now();
+            while waited.elapsed() < HELLO_INTERVAL && !connected {
+                for packet in netplay.receive() {
+                    match packet {
+                        Packet::Hello { heard, .. } => {
+                            netplay.check_hello(&packet)?;
+                            connected = heard;
+                        }
+                        // it only sends input once it heard our hello
+                        Packet::Input { .. } => connected = true,
+                        Packet::Checksum { .. } => {}
+                    }
+                }
+                std::thread::sleep(Duration::from_millis(5));
+            }
+        }
+        // in case the peer is still waiting on our answer
+        netplay.send(&netplay.hello.clone());
+        Ok(netplay)
+    }
+
+    fn check_hello(&mut self, packet: &Packet) -> Result<(), String> {
+        if let (Packet::Hello { rom_sha1, player, delay, .. }, Packet::Hello { rom_sha1: ours, player: our_player, delay: our_delay, heard }) =
+            (packet, &mut self.hello)
+        {
+            if rom_sha1 != ours {
+                return Err("netplay: the peer is running a different ROM".to_string());
+            }
+            if player == our_player {
+                return Err(format!("netplay: both sides are player {}", player));
+            }
+            if delay != our_delay {
+                return Err(format!("netplay: the peer uses a delay of {} frames, this side {}", delay, our_delay));
+            }
+            *heard = true;
+        }
+        Ok(())
+    }
+
+    fn send(&self, packet: &Packet) {
+        // lost or refused packets are sent again with the next update
+        let _ = self.socket.send_to(&packet.to_bytes(), self.peer);
+    }
+
+    // packets from anyone but the peer, or that don't parse, are dropped
+    fn receive(&mut self) -> Vec<Packet> {
+        let mut packets = vec![];
+        let mut buf = [0; 2048];
+        loop {
+            match self.socket.recv_from(&mut buf) {
+                Ok((len, from)) if from == self.peer => {
+                    if let Ok(packet) = Packet::from_bytes(&buf[..len]) {
+                        self.last_heard = Instant::now();
+                        packets.push(packet);
+                    }
+                }
+                Ok(_) => {}
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                // ICMP port unreachable while the peer isn't up yet
+                Err(err) if err.kind() == ErrorKind::ConnectionReset => {}
+                Err(_) => break,
+            }
+        }
+        packets
+    }
+
+    // Once per displayed frame: exchanges input with the peer and runs the
+    // next frame when it can. Returns whether a frame was run; an error when
+    // the games desynced or the peer went away.
+    pub fn update(&mut self, session: &mut Session, buttons: JoypadButton) -> Result<bool, String> {
+        for packet in self.receive() {
+            match packet {
+                Packet::Hello { .. } => {
+                    self.check_hello(&packet)?;
+                    self.send(&self.hello.clone());
+                }
+                Packet::Input { ack, first_frame, inputs } => {
+                    self.peer_ack = self.peer_ack.max(ack);
+                    self.rollback.add_remote_input(first_frame, &inputs);
+                }
+                Packet::Checksum { frame, crc } => self.rollback.add_remote_checksum(frame, crc),
+            }
+        }
+        if self.last_heard.elapsed() > DISCONNECT_TIMEOUT {
+            return Err("netplay: the peer stopped answering".to_string());
+        }
+
+        if self.rollback.needs_local_input() {
+            self.rollback.add_local_input(buttons);
+        }
+        let advanced = self.rollback.advance(session)?;
+        self.send(&Packet::Input {
+            ack: self.rollback.remote_len(),
+            first_frame: self.peer_ack,
+            inputs: self.rollback.local_inputs(self.peer_ack).to_vec(),
+        });
+        for (frame, crc) in self.rollback.take_checksums() {
+            self.send(&Packet::Checksum { frame, crc });
+        }
+        self.rollback.check_desync()?;
+        Ok(advanced)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::builder::RomBuilder;
+    use crate::cartridge::Rom;
+
+    // adds up the A presses of both controllers in $10 and $11
+    fn two_player_rom() -> Rom {
+        RomBuilder::new()
+            .program(
+                0x8000,
+                &[
+                    0xa9, 0x80, 0x8d, 0x00, 0x20, // LDA #$80; STA $2000 (NMI on)
+                    0x4c, 0x05, 0x80, // loop: JMP loop
+                ],
+            )
+            .program(
+                0x8010,
+                &[
+                    0xa9, 0x01, 0x8d, 0x16, 0x40, // nmi: LDA #1; STA $4016
+                    0xa9, 0x00, 0x8d, 0x16, 0x40, // LDA #0; STA $4016
+                    0xad, 0x16, 0x40, 0x29, 0x01, // LDA $4016; AND #1
+                    0x18, 0x65, 0x10, 0x85, 0x10, // CLC; ADC $10; STA $10
+                    0xad, 0x17, 0x40, 0x29, 0x01, // LDA $4017; AND #1
+                    0x18, 0x65, 0x11, 0x85, 0x11, // CLC; ADC $11; STA $11
+                    0x40, // RTI
+                ],
+            )
+            .nmi_vector(0x8010)
+            .rom()
+    }
+
+    fn pressed(player: usize, frame: usize) -> JoypadButton {
+        if ((frame + 2 * player) / 3) & 1 == 0 {
+            JoypadButton::BUTTON_A
+        } else {
+            JoypadButton::empty()
+        }
+    }
+
+    #[test]
+    fn test_packets() {
+        let packets = [
+            Packet::Hello { rom_sha1: "abc".to_string(), player: 2, delay: 3, heard: true },
+            Packet::Input { ack: 7, first_frame: 5, inputs: vec![JoypadButton::BUTTON_A, JoypadButton::LEFT] },
+            Packet::Checksum { frame: 120, crc: 0xdeadbeef },
+        ];
+        for packet in packets.iter() {
+            assert_eq!(Packet::from_bytes(&packet.to_bytes()).as_ref(), Ok(packet));
+        }
+        assert!(Packet::from_bytes(b"NESN\x01\x09").is_err());
+        assert!(Packet::from_bytes(b"NESM\x01\x02").is_err());
+    }
+
+    // Two sides whose packets take `latency` updates to arrive end up on the
+    // same machine state as a run with all the input known up front.
+    #[test]
+    fn test_rollback_converges() {
+        let (latency, delay, frames) = (4, 2, 200);
+        let mut sides = [Rollback::new(0, delay), Rollback::new(1, delay)];
+        let mut sessions = [Session::new(two_player_rom()).unwrap(), Session::new(two_player_rom()).unwrap()];
+        // (arrives at update, to side, first frame, inputs)
+        let mut in_flight: Vec<(usize, usize, usize, Vec<JoypadButton>)> = vec![];
+
+        let mut update = 0;
+        while !sides.iter().all(|side| side.frame() == frames && side.remote_len() == frames) {
+            for player in 0..2 {
+                let other = 1 - player;
+                for (_, _, first_frame, inputs) in in_flight.iter().filter(|packet| packet.0 == update && packet.1 == player) {
+                    sides[player].add_remote_input(*first_frame, inputs);
+                }
+                let ack = sides[other].remote_len();
+                let side = &mut sides[player];
+                if side.needs_local_input() && side.local.len() < frames {
+                    let frame = side.local.len();
+                    side.add_local_input(pressed(player, frame));
+                }
+                side.advance(&mut sessions[player]).unwrap();
+                in_flight.push((update + latency, other, ack, side.local_inputs(ack).to_vec()));
+                for (frame, crc) in side.take_checksums() {
+                    sides[other].add_remote_checksum(frame, crc);
+                }
+            }
+            for side in sides.iter_mut() {
+                side.check_desync().unwrap();
+            }
+            update += 1;
+            assert!(update < 10 * frames);
+        }
+        assert!(sides.iter().all(|side| side.frames_rolled_back > 0));
+
+        // the same frames with nothing predicted
+        let mut reference = Session::new(two_player_rom()).unwrap();
+        for frame in 0..frames {
+            let input = |player| if frame < delay { JoypadButton::empty() } else { pressed(player, frame) };
+            reference.step_frame([input(0), input(1)]);
+        }
+        assert!(reference.peek(0x10) > 0);
+        for session in sessions.iter() {
+            assert!(session.save_state() == reference.save_state());
+        }
+
+        // a machine that went its own way is caught by the checksums
+        let mut side = Rollback::new(0, 0);
+        side.local_checksums.insert(60, 1);
+        side.add_remote_checksum(60, 2);
+        assert!(side.check_desync().is_err());
+    }
+}
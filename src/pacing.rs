@@ -0,0 +1,117 @@
+// Frame pacing and presentation. Emulation speed comes from our own timer,
+// never from the display: left to vsync a 144Hz monitor would run games at
+// 144 frames a second. Vsync is still on by default to avoid tearing; the
+// adaptive mode turns it off and presents each frame when the timer fires,
+// for G-Sync/FreeSync displays that refresh whenever a frame arrives.
+//
+// Unlocked, frames go out as fast as they are emulated, or as fast as the
+// display refreshes while vsync is on.
+
+use std::time::{Duration, Instant};
+
+// NTSC: 341 * 262 - 0.5 PPU dots per frame at 5.369318 MHz
+pub const NES_FRAME_RATE: f64 = 60.0988;
+// after a stall (window drag, breakpoint) restart the schedule instead of
+// running frames back to back to catch up
+const MAX_LAG_FRAMES: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PresentMode {
+    Vsync,
+    Adaptive,
+}
+
+impl PresentMode {
+    pub fn parse(value: &str) -> Result<PresentMode, String> {
+        match value {
+            "vsync" => Ok(PresentMode::Vsync),
+            "adaptive" => Ok(PresentMode::Adaptive),
+            other => Err(format!("unknown present mode '{}', expected vsync or adaptive", other)),
+        }
+    }
+}
+
+pub struct FramePacer {
+    period: Duration,
+    next: Option<Instant>,
+    unlocked: bool,
+}
+
+impl FramePacer {
+    pub fn new(frame_rate: f64) -> Self {
+        FramePacer {
+            period: Duration::from_secs_f64(1.0 / frame_rate),
+            next: None,
+            unlocked: false,
+        }
+    }
+
+    pub fn unlocked(&self) -> bool {
+        self.unlocked
+    }
+
+    // locking again starts a fresh schedule rather than one from before
+    pub fn set_unlocked(&mut self, unlocked: bool) {
+        self.unlocked = unlocked;
+        self.next = None;
+    }
+
+    // How long to wait before presenting a frame finished at `now`. Deadlines
+    // advance by exactly one period so the average rate doesn't drift.
+    pub fn schedule(&mut self, now: Instant) -> Duration {
+        if self.unlocked {
+            return Duration::ZERO;
+        }
+        let deadline = match self.next {
+            Some(next) if now <= next + self.period * MAX_LAG_FRAMES => next,
+            _ => now,
+        };
+        self.next = Some(deadline + self.period);
+        deadline.saturating_duration_since(now)
+    }
+
+    // sleeps until the frame is due and returns its presentation timestamp
+    pub fn wait(&mut self) -> Instant {
+        let delay = self.schedule(Instant::now());
+        if !delay.is_zero() {
+            std::thread::sleep(delay);
+        }
+        Instant::now()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_schedule_keeps_rate_and_resyncs_after_stall() {
+        let mut pacer = FramePacer::new(NES_FRAME_RATE);
+        let period = Duration::from_secs_f64(1.0 / NES_FRAME_RATE);
+        let start = Instant::now();
+
+        assert_eq!(pacer.schedule(start), Duration::ZERO);
+        // emulating the next frame took 4ms
+        let ms = Duration::from_millis(1);
+        assert_eq!(pacer.schedule(start + 4 * ms), period - 4 * ms);
+        // late by a bit: present straight away, the schedule stays put
+        assert_eq!(pacer.schedule(start + period * 2 + ms), Duration::ZERO);
+        assert_eq!(pacer.schedule(start + period * 3), Duration::ZERO);
+        assert_eq!(pacer.next, Some(start + period * 4));
+
+        // a long stall restarts the schedule from now
+        let resumed = start + period * 20;
+        assert_eq!(pacer.schedule(resumed), Duration::ZERO);
+        assert_eq!(pacer.next, Some(resumed + period));
+
+        pacer.set_unlocked(true);
+        assert_eq!(pacer.schedule(resumed + ms), Duration::ZERO);
+        assert_eq!(pacer.schedule(resumed + ms), Duration::ZERO);
+        pacer.set_unlocked(false);
+        assert_eq!(pacer.schedule(resumed + ms), Duration::ZERO);
+        assert_eq!(pacer.schedule(resumed + ms), period);
+
+        assert_eq!(PresentMode::parse("adaptive"), Ok(PresentMode::Adaptive));
+        assert!(PresentMode::parse("gsync").is_err());
+    }
+}
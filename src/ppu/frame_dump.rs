@@ -0,0 +1,127 @@
+// Per-scanline breakdown of one frame, for studying raster effects offline.
+
+pub struct ScanlineState {
+    pub scanline: u16,
+    pub scroll_x: u8,
+    pub scroll_y: u8,
+    // loopy v, the address the background is fetched from
+    pub vram_addr: u16,
+    pub ctrl: u8,
+    pub mask: u8,
+    pub chr_banks: Vec<usize>,
+}
+
+pub struct RegisterWrite {
+    pub scanline: u16,
+    pub cycle: usize,
+    pub register: u16,
+    pub value: u8,
+}
+
+pub struct Sprite {
+    pub index: usize,
+    pub x: u8,
+    pub y: u8,
+    pub tile: u8,
+    pub attributes: u8,
+}
+
+pub struct FrameDump {
+    pub scanlines: Vec<ScanlineState>,
+    pub writes: Vec<RegisterWrite>,
+    pub sprites: Vec<Sprite>,
+}
+
+impl Default for FrameDump {
+    fn default() -> Self {
+        FrameDump::new()
+    }
+}
+
+impl FrameDump {
+    pub fn new() -> Self {
+        FrameDump {
+            scanlines: vec![],
+            writes: vec![],
+            sprites: vec![],
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let scanlines = self
+            .scanlines
+            .iter()
+            .map(|s| {
+                let banks = s.chr_banks.iter().map(|b| b.to_string()).collect::<Vec<String>>().join(",");
+                format!(
+                    "{{\"scanline\":{},\"scroll_x\":{},\"scroll_y\":{},\"vram_addr\":\"${:04X}\",\"ctrl\":{},\"mask\":{},\"chr_banks\":[{}]}}",
+                    s.scanline, s.scroll_x, s.scroll_y, s.vram_addr, s.ctrl, s.mask, banks
+                )
+            })
+            .collect::<Vec<String>>()
+            .join(",\n    ");
+        let writes = self
+            .writes
+            .iter()
+            .map(|w| {
+                format!(
+                    "{{\"scanline\":{},\"cycle\":{},\"register\":\"${:04X}\",\"value\":{}}}",
+                    w.scanline, w.cycle, w.register, w.value
+                )
+            })
+            .collect::<Vec<String>>()
+            .join(",\n    ");
+        let sprites = self
+            .sprites
+            .iter()
+            .map(|s| {
+                format!(
+                    "{{\"index\":{},\"x\":{},\"y\":{},\"tile\":{},\"attributes\":{}}}",
+                    s.index, s.x, s.y, s.tile, s.attributes
+                )
+            })
+            .collect::<Vec<String>>()
+            .join(",\n    ");
+
+        format!(
+            "{{\n  \"scanlines\": [\n    {}\n  ],\n  \"writes\": [\n    {}\n  ],\n  \"sprites\": [\n    {}\n  ]\n}}\n",
+            scanlines, writes, sprites
+        )
+    }
+
+    pub fn to_html(&self) -> String {
+        let mut html = String::from("<html><head><title>Frame dump</title></head><body>\n");
+
+        html.push_str("<h2>Scanlines</h2>\n<table border=\"1\">\n");
+        html.push_str("<tr><th>scanline</th><th>scroll x</th><th>scroll y</th><th>v</th><th>ctrl</th><th>mask</th><th>chr banks</th></tr>\n");
+        for s in self.scanlines.iter() {
+            let banks = s.chr_banks.iter().map(|b| format!("{:05X}", b)).collect::<Vec<String>>().join(" ");
+            html.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{:04X}</td><td>{:02X}</td><td>{:02X}</td><td>{}</td></tr>\n",
+                s.scanline, s.scroll_x, s.scroll_y, s.vram_addr, s.ctrl, s.mask, banks
+            ));
+        }
+        html.push_str("</table>\n");
+
+        html.push_str("<h2>Register writes</h2>\n<table border=\"1\">\n");
+        html.push_str("<tr><th>scanline</th><th>cycle</th><th>register</th><th>value</th></tr>\n");
+        for w in self.writes.iter() {
+            html.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td><td>${:04X}</td><td>{:02X}</td></tr>\n",
+                w.scanline, w.cycle, w.register, w.value
+            ));
+        }
+        html.push_str("</table>\n");
+
+        html.push_str("<h2>Sprites</h2>\n<table border=\"1\">\n");
+        html.push_str("<tr><th>#</th><th>x</th><th>y</th><th>tile</th><th>attributes</th></tr>\n");
+        for s in self.sprites.iter() {
+            html.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{:02X}</td><td>{:02X}</td></tr>\n",
+                s.index, s.x, s.y, s.tile, s.attributes
+            ));
+        }
+        html.push_str("</table>\n</body></html>\n");
+        html
+    }
+}
@@ -1,21 +1,82 @@
-use crate::cartridge::Mirroring;
+use crate::cartridge::nrom::Nrom;
+use crate::cdl::{self, SharedCodeDataLog};
+use crate::cartridge::{Mirroring, Rom, SharedMapper};
+use crate::region::Region;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+use std::cell::RefCell;
+use std::rc::Rc;
 use registers::ctrl::ControlRegister;
 use registers::mask::MaskRegister;
 use registers::status::StatusRegister;
-use registers::scroll::ScrollRegister;
-use registers::addr::AddrRegister;
+use registers::loopy::{self, LoopyRegisters};
+use frame_dump::{FrameDump, RegisterWrite, ScanlineState, Sprite};
+use watch::{PpuSpace, PpuWatch, PpuWrite};
 
+pub mod frame_dump;
 pub mod registers;
+pub mod watch;
+
+pub const SPRITES_PER_SCANLINE: usize = 8;
+pub const VISIBLE_SCANLINES: usize = 240;
+
+// What one visible line's background is fetched from: v as the line starts,
+// fine X, and the pattern table $2000 selected at the time. Recorded per line
+// so mid-frame scroll and $2000 changes (status bars, split screens) show up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LineScroll {
+    pub v: u16,
+    pub fine_x: u8,
+    pub pattern_table: u16,
+}
+
+impl LineScroll {
+    // the line below this one if nothing touches the scroll in between
+    pub fn next_line(&self) -> LineScroll {
+        LineScroll { v: loopy::next_line(self.v), ..*self }
+    }
+}
+
+// a whole screen scrolled to `first`, the picture before anything is recorded
+fn unbroken_lines(first: LineScroll) -> [LineScroll; VISIBLE_SCANLINES] {
+    let mut lines = [first; VISIBLE_SCANLINES];
+    for line in 1..VISIBLE_SCANLINES {
+        lines[line] = lines[line - 1].next_line();
+    }
+    lines
+}
+
+// How many sprites a scanline may show. Hardware drops everything past the
+// eighth sprite found, which is why games flicker; some games also rely on it
+// to mask sprites, so removing the limit is a per-game choice.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpriteLimit {
+    Hardware,
+    NoLimit,
+    // hardware limit, but evaluation starts one sprite later every frame (as
+    // if the game rotated OAMADDR), so dropped sprites take turns instead of
+    // vanishing for good
+    Smart,
+}
+
+impl SpriteLimit {
+    pub fn parse(value: &str) -> Result<SpriteLimit, String> {
+        match value {
+            "hardware" => Ok(SpriteLimit::Hardware),
+            "none" => Ok(SpriteLimit::NoLimit),
+            "smart" => Ok(SpriteLimit::Smart),
+            other => Err(format!("unknown sprite limit '{}', expected hardware, none or smart", other)),
+        }
+    }
+}
 
 pub struct NesPPU{
-    pub chr_rom: Vec<u8>,
-    pub mirroring: Mirroring,
+    pub mapper: SharedMapper,
     pub ctrl: ControlRegister,
     pub mask: MaskRegister,
     pub status: StatusRegister,
-    pub scroll: ScrollRegister,
-    pub addr: AddrRegister,
-    pub vram: [u8; 2048],
+    pub loopy: LoopyRegisters,
+    // the console's 2kb of nametable RAM, then the 2kb four-screen boards add
+    pub vram: [u8; 4096],
 
     pub oam_addr: u8,
     pub oam_data: [u8; 256],
@@ -25,7 +86,38 @@ pub struct NesPPU{
 
     scanline: u16,
     cycles: usize,
+    // dots since power-on, when A12 changes are passed to the mapper
+    dots: u64,
+    // A12 of the last address put on the PPU bus
+    a12: bool,
+    odd_frame: bool,
+    // $2002 was read right before vblank, which keeps the flag down
+    suppress_vblank: bool,
     pub nmi_interrupt: Option<u8>,
+
+    pub line_scroll: [LineScroll; VISIBLE_SCANLINES],
+
+    // Overclocking: idle scanlines inserted after the post-render line, before
+    // vblank. The CPU gets more time per frame while nothing visible changes.
+    // Not something real hardware does.
+    pub overclock_scanlines: u16,
+    overclocked_lines: u16,
+
+    pub sprite_limit: SpriteLimit,
+    pub region: Region,
+    // OAMADDR when rendering started, evaluation begins at that sprite
+    sprite_eval_start: u8,
+    sprite_rotation: u8,
+
+    frame_dump: Option<FrameDump>,
+    finished_frame_dump: Option<FrameDump>,
+
+    pub watches: Vec<PpuWatch>,
+    // changes to watched bytes since the bus last took them
+    watch_hits: Vec<PpuWrite>,
+
+    // the bus's code/data log, for CHR accesses
+    pub cdl: Option<SharedCodeDataLog>,
 }
 
 pub trait PPU {
@@ -49,73 +141,399 @@ impl NesPPU {
     }
 
     pub fn new(chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+        let rom = Rom {
+            prg_rom: vec![],
+            chr_rom,
+            trainer: None,
+            battery: false,
+            mapper: 0,
+            screen_mirroring: mirroring,
+            expansion_device: 0,
+            region: None,
+        };
+        NesPPU::with_mapper(Rc::new(RefCell::new(Nrom::new(rom))))
+    }
+
+    pub fn with_mapper(mapper: SharedMapper) -> Self {
        NesPPU {
-            chr_rom: chr_rom,
-            mirroring: mirroring,
+            mapper,
             ctrl: ControlRegister::new(),
             mask: MaskRegister::new(),
             status: StatusRegister::new(),
             oam_addr: 0,
-            scroll: ScrollRegister::new(),
-            addr: AddrRegister::new(),
-            vram: [0; 2048],
+            loopy: LoopyRegisters::new(),
+            vram: [0; 4096],
             oam_data: [0; 64 * 4],
             palette_table: [0; 32],
             internal_data_buf: 0,
             cycles: 0,
+            dots: 0,
+            a12: false,
             scanline: 0,
+            odd_frame: false,
+            suppress_vblank: false,
             nmi_interrupt: None,
+            line_scroll: unbroken_lines(LineScroll { v: 0, fine_x: 0, pattern_table: 0 }),
+            overclock_scanlines: 0,
+            overclocked_lines: 0,
+            sprite_limit: SpriteLimit::Hardware,
+            region: Region::Ntsc,
+            sprite_eval_start: 0,
+            sprite_rotation: 0,
+            frame_dump: None,
+            finished_frame_dump: None,
+            watches: vec![],
+            watch_hits: vec![],
+            cdl: None,
        }
    }
 
+   // After a $2007 access. While rendering the PPU is fetching through v
+   // itself, and the access bumps coarse X and Y at once instead.
    fn increment_vram_addr(&mut self){
-    self.addr.increment(self.ctrl.vram_addr_increment());
+    if self.rendering() && (self.scanline < 240 || self.scanline == self.pre_render_line()) {
+        self.loopy.increment_x();
+        self.loopy.increment_y();
+    } else {
+        self.loopy.increment(self.ctrl.vram_addr_increment());
+    }
+   }
+
+   fn rendering(&self) -> bool {
+    self.mask.show_background() || self.mask.show_sprites()
+   }
+
+   pub fn mirroring(&self) -> Mirroring {
+    self.mapper.borrow().mirroring()
+   }
+
+   pub fn read_chr(&self, addr: u16) -> u8 {
+    self.mapper.borrow_mut().chr_read(addr)
+   }
+
+   // read_chr() for drawing, the code/data logger counts the byte as drawn
+   pub fn fetch_chr(&self, addr: u16) -> u8 {
+    self.log_chr(addr, cdl::DRAWN);
+    self.read_chr(addr)
+   }
+
+   // what a fetch would read, for tools: no mapper latches, no logging
+   pub fn peek_chr(&self, addr: u16) -> u8 {
+    self.mapper.borrow().chr_peek(addr)
+   }
+
+   fn log_chr(&self, addr: u16, flags: u8) {
+    if let Some(cdl) = self.cdl.as_ref() {
+        let offset = self.mapper.borrow().chr_banks()[addr as usize / 0x400] + addr as usize % 0x400;
+        cdl.borrow_mut().chr_access(offset, flags);
+    }
    }
 
    pub fn mirror_vram_addr(&self, addr: u16) -> u16 {
     let mirrored_vram = addr & 0b10111111111111; // mirror 0x3000-0x3eff down to 0x2000 - 0x2eff
     let vram_index = mirrored_vram - 0x2000; // vram vector
     let name_table = vram_index / 0x400; // name table index
-    match (&self.mirroring, name_table){
+    match (self.mirroring(), name_table){
         (Mirroring::VERTICAL, 2) | (Mirroring::VERTICAL, 3) => vram_index - 0x800,
         (Mirroring::HORIZONTAL, 2) => vram_index - 0x400,
         (Mirroring::HORIZONTAL, 1) => vram_index - 0x400,
         (Mirroring::HORIZONTAL, 3) => vram_index - 0x800,
+        (Mirroring::SINGLE_SCREEN_LOWER, _) => vram_index % 0x400,
+        (Mirroring::SINGLE_SCREEN_UPPER, _) => vram_index % 0x400 + 0x400,
+        // four nametables, the last two in the cartridge's RAM
+        (Mirroring::FOUR_SCREEN, _) => vram_index,
         _ => vram_index,
     }
    }
 
+    // https://www.nesdev.org/wiki/PPU_power_up_state
+    pub fn reset(&mut self) {
+        self.ctrl = ControlRegister::new();
+        self.mask = MaskRegister::new();
+        // $2005 is cleared, $2006 (v) is kept
+        self.loopy = LoopyRegisters { v: self.loopy.v, ..LoopyRegisters::new() };
+        self.internal_data_buf = 0;
+        self.nmi_interrupt = None;
+    }
+
+    // starts recording at the next scanline and runs until the following vblank
+    pub fn request_frame_dump(&mut self) {
+        if self.frame_dump.is_none() {
+            self.frame_dump = Some(FrameDump::new());
+        }
+    }
+
+    pub fn take_frame_dump(&mut self) -> Option<FrameDump> {
+        self.finished_frame_dump.take()
+    }
+
+    pub fn take_watch_hits(&mut self) -> Vec<PpuWrite> {
+        std::mem::take(&mut self.watch_hits)
+    }
+
+    fn watch(&mut self, space: PpuSpace, index: u16, old: u8, new: u8) {
+        if old != new && self.watches.iter().any(|watch| watch.contains(space, index)) {
+            self.watch_hits.push(PpuWrite { space, index, old, new });
+        }
+    }
+
+    fn record_write(&mut self, register: u16, value: u8) {
+        if let Some(dump) = self.frame_dump.as_mut() {
+            dump.writes.push(RegisterWrite {
+                scanline: self.scanline,
+                cycle: self.cycles,
+                register,
+                value,
+            });
+        }
+    }
+
+    fn record_scanline(&mut self) {
+        let chr_banks = match self.frame_dump {
+            Some(_) => self.mapper.borrow().chr_banks(),
+            None => return,
+        };
+        let state = ScanlineState {
+            scanline: self.scanline,
+            scroll_x: self.loopy.scroll_x(),
+            scroll_y: self.loopy.scroll_y(),
+            vram_addr: self.loopy.v,
+            ctrl: self.ctrl.bits(),
+            mask: self.mask.bits(),
+            chr_banks,
+        };
+
+        let dump = self.frame_dump.as_mut().unwrap();
+        if self.scanline == 241 && !dump.scanlines.is_empty() {
+            let mut dump = self.frame_dump.take().unwrap();
+            dump.sprites = self
+                .oam_data
+                .chunks(4)
+                .enumerate()
+                .map(|(index, entry)| Sprite {
+                    index,
+                    y: entry[0],
+                    tile: entry[1],
+                    attributes: entry[2],
+                    x: entry[3],
+                })
+                .collect();
+            self.finished_frame_dump = Some(dump);
+            return;
+        }
+        dump.scanlines.push(state);
+    }
+
     pub fn poll_nmi_status(&mut self) -> Option<u8> {
         self.nmi_interrupt.take()
     }
 
     pub fn tick(&mut self, cycles: u8) -> bool {
+        let dot = self.cycles;
         self.cycles += cycles as usize;
-        if self.cycles >= 341 {
-            if self.is_sprite_0_hit(self.cycles){
-                self.status.set_sprite_zero_hit(true);
-            }
-            
-            self.cycles = self.cycles - 341;
-            self.scanline += 1;
+        self.dots += cycles as u64;
+        self.dot_events(dot, self.cycles);
+        let line_length = self.line_length();
+        if self.cycles < line_length {
+            return false;
+        }
+        if self.scanline == 240 && self.overclocked_lines < self.overclock_scanlines {
+            self.overclocked_lines += 1;
+            self.cycles -= line_length;
+            return false;
+        }
+
+        if self.is_sprite_0_hit(self.cycles){
+            self.status.set_sprite_zero_hit(true);
+        }
+
+        let rendering = self.rendering();
+        if rendering && self.scanline < 240 && self.sprite_overflow_on_scanline() {
+            self.status.set_sprite_overflow(true);
+        }
 
-            if self.scanline == 241 {
+        self.cycles -= line_length;
+        self.scanline += 1;
+        let frame_done = self.scanline >= self.region.scanlines();
+        if frame_done {
+            self.scanline = 0;
+            self.odd_frame = !self.odd_frame;
+            self.overclocked_lines = 0;
+            self.sprite_eval_start = self.oam_addr / 4;
+            self.sprite_rotation = (self.sprite_rotation + 1) % 64;
+        }
+        self.record_scanline();
+        if self.scanline < 240 {
+            self.record_line_scroll(rendering);
+        }
+        self.dot_events(0, self.cycles);
+        frame_done
+    }
+
+    // the last line of the frame, 261 on NTSC
+    fn pre_render_line(&self) -> u16 {
+        self.region.scanlines() - 1
+    }
+
+    // On odd NTSC frames with rendering on, the pre-render line is one dot short.
+    fn line_length(&self) -> usize {
+        let odd_pre_render = self.scanline == self.pre_render_line() && self.odd_frame;
+        if odd_pre_render && self.rendering() && self.region.skips_odd_frame_dot() {
+            340
+        } else {
+            341
+        }
+    }
+
+    // Flag changes at fixed dots, for dots `from` (exclusive) to `to`
+    // (inclusive) of the current line.
+    fn dot_events(&mut self, from: usize, to: usize) {
+        let passed = |dot: usize| from < dot && to >= dot;
+        if self.scanline == self.region.vblank_line() && passed(1) {
+            if !self.suppress_vblank {
                 self.status.set_vblank_status(true);
-                self.status.set_sprite_zero_hit(false);
                 if self.ctrl.generate_vblank_nmi() {
                     self.nmi_interrupt = Some(1);
                 }
             }
+            self.suppress_vblank = false;
+        }
+        if self.scanline == self.pre_render_line() && passed(1) {
+            self.nmi_interrupt = None;
+            self.status.reset_vblank_status();
+            self.status.set_sprite_zero_hit(false);
+            self.status.set_sprite_overflow(false);
+        }
+        self.scroll_updates(from, to);
+        self.pattern_fetches(from, to);
+    }
 
-            if self.scanline >= 262 {
-                self.scanline = 0;
-                self.nmi_interrupt = None;
-                self.status.reset_vblank_status();
-                self.status.set_sprite_zero_hit(false);
+    // The pattern table half of each tile fetch on a rendering line, for dots
+    // `from` (exclusive) to `to` (inclusive). Each fetch reads its low plane
+    // on dot 8n+5: the background on dots 1-256 and 321-336, the eight sprite
+    // slots of the next line on dots 257-320. The picture itself is drawn
+    // at vblank; this only drives A12 for boards that count its rises.
+    fn pattern_fetches(&mut self, from: usize, to: usize) {
+        if !self.rendering() || !(self.scanline < 240 || self.scanline == self.pre_render_line()) {
+            return;
+        }
+        let first = from + 1 + (13 - (from + 1) % 8) % 8;
+        for dot in (first..=to.min(336)).step_by(8) {
+            let table = match dot {
+                257..=320 => self.sprite_fetch_table((dot - 257) / 8),
+                _ => self.ctrl.bknd_pattern_addr(),
+            };
+            self.set_a12(table & 0x1000 != 0, dot);
+        }
+    }
+
+    // 8x16 sprites pick the table with bit 0 of the tile; the slots left
+    // empty fetch tile $FF
+    fn sprite_fetch_table(&self, slot: usize) -> u16 {
+        if self.ctrl.sprite_size() == 8 {
+            return self.ctrl.sprt_pattern_addr();
+        }
+        let tile = self
+            .oam_data
+            .chunks(4)
+            .filter(|sprite| self.scanline.wrapping_sub(sprite[0] as u16) < 16)
+            .nth(slot)
+            .map_or(0xff, |sprite| sprite[1]);
+        (tile as u16 & 1) * 0x1000
+    }
+
+    // `dot` on the current line, at most the dot the PPU has reached
+    fn set_a12(&mut self, high: bool, dot: usize) {
+        if high != self.a12 {
+            self.a12 = high;
+            let at = self.dots - (self.cycles - dot) as u64;
+            self.mapper.borrow_mut().ppu_a12(high, at);
+        }
+    }
+
+    // The updates to v at fixed dots of a rendering line, for dots `from`
+    // (exclusive) to `to` (inclusive). A $2006 write during hblank, after
+    // dot 257, survives into the next line as it is: the mid-frame scroll
+    // trick.
+    fn scroll_updates(&mut self, from: usize, to: usize) {
+        let passed = |dot: usize| from < dot && to >= dot;
+        if !self.rendering() {
+            return;
+        }
+        if self.scanline < 240 || self.scanline == self.pre_render_line() {
+            if passed(256) {
+                self.loopy.increment_y();
+            }
+            if passed(257) {
+                self.loopy.copy_x();
+            }
+        }
+        // dots 280-304, the last copy is the one that counts
+        if self.scanline == self.pre_render_line() && passed(304) {
+            self.loopy.copy_y();
+        }
+    }
+
+    // With rendering off v stays put, but the picture is still drawn from
+    // where the scroll would be.
+    fn record_line_scroll(&mut self, rendering: bool) {
+        let line = self.scanline as usize;
+        let v = match (rendering, line) {
+            (true, _) => self.loopy.v,
+            (false, 0) => self.loopy.t,
+            (false, _) => self.line_scroll[line - 1].next_line().v,
+        };
+        self.line_scroll[line] = LineScroll {
+            v,
+            fine_x: self.loopy.x,
+            pattern_table: self.ctrl.bknd_pattern_addr(),
+        };
+    }
+
+    // where the next $2007 access goes
+    pub fn vram_addr(&self) -> u16 {
+        self.loopy.addr()
+    }
+
+    // (scanline, dot) the PPU is at
+    pub fn position(&self) -> (u16, usize) {
+        (self.scanline, self.cycles)
+    }
+
+    // first sprite looked at during sprite evaluation; the ones after it win
+    // the priority multiplexer and the 8 sprite slots
+    pub fn sprite_evaluation_start(&self) -> usize {
+        match self.sprite_limit {
+            SpriteLimit::Smart => (self.sprite_eval_start + self.sprite_rotation) as usize % 64,
+            _ => self.sprite_eval_start as usize,
+        }
+    }
+
+    // Sprite evaluation for the next line, as far as the overflow flag goes.
+    // After the eighth sprite in range the hardware keeps stepping the byte
+    // index along with the sprite index, so it compares tile numbers,
+    // attributes and X positions as if they were Y: overflow is missed for
+    // real ninth sprites and reported for ones that aren't there.
+    fn sprite_overflow_on_scanline(&self) -> bool {
+        let height = self.ctrl.sprite_size() as u16;
+        let in_range = |y: u8| self.scanline.wrapping_sub(y as u16) < height;
+        let mut found = 0;
+        let mut sprite = self.sprite_eval_start as usize;
+        while sprite < 64 && found < 8 {
+            if in_range(self.oam_data[sprite * 4]) {
+                found += 1;
+            }
+            sprite += 1;
+        }
+        let mut byte = 0;
+        while sprite < 64 {
+            if in_range(self.oam_data[sprite * 4 + byte]) {
                 return true;
             }
+            sprite += 1;
+            byte = (byte + 1) % 4;
         }
-        return false;
+        false
     }
 
     fn is_sprite_0_hit(&self, cycle: usize) -> bool {
@@ -128,25 +546,142 @@ impl NesPPU {
 
 }
 
+// the mapper is shared with the bus and saved there
+impl Savestate for NesPPU {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.ctrl.bits());
+        state.u8(self.mask.bits());
+        state.u8(self.status.bits());
+        self.loopy.save_state(state);
+        state.bytes(&self.vram);
+        state.u8(self.oam_addr);
+        state.bytes(&self.oam_data);
+        state.bytes(&self.palette_table);
+        state.u8(self.internal_data_buf);
+        state.u16(self.scanline);
+        state.usize(self.cycles);
+        state.u64(self.dots);
+        state.bool(self.a12);
+        state.bool(self.odd_frame);
+        state.bool(self.suppress_vblank);
+        state.bool(self.nmi_interrupt.is_some());
+        state.u8(self.nmi_interrupt.unwrap_or(0));
+        state.u16(self.overclocked_lines);
+        state.u8(self.sprite_eval_start);
+        state.u8(self.sprite_rotation);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.ctrl = ControlRegister::from_bits_truncate(state.u8()?);
+        self.mask = MaskRegister::from_bits_truncate(state.u8()?);
+        self.status = StatusRegister::from_bits_truncate(state.u8()?);
+        self.loopy.load_state(state)?;
+        state.bytes_into(&mut self.vram)?;
+        self.oam_addr = state.u8()?;
+        state.bytes_into(&mut self.oam_data)?;
+        state.bytes_into(&mut self.palette_table)?;
+        self.internal_data_buf = state.u8()?;
+        self.scanline = state.u16()?;
+        self.cycles = state.usize()?;
+        self.dots = state.u64()?;
+        self.a12 = state.bool()?;
+        self.odd_frame = state.bool()?;
+        self.suppress_vblank = state.bool()?;
+        let nmi_pending = state.bool()?;
+        let nmi = state.u8()?;
+        self.nmi_interrupt = if nmi_pending { Some(nmi) } else { None };
+        self.overclocked_lines = state.u16()?;
+        self.sprite_eval_start = state.u8()?;
+        self.sprite_rotation = state.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<&NesPPU> for crate::serde_state::PpuState {
+    fn from(ppu: &NesPPU) -> Self {
+        crate::serde_state::PpuState {
+            ctrl: ppu.ctrl.bits(),
+            mask: ppu.mask.bits(),
+            status: ppu.status.bits(),
+            v: ppu.loopy.v,
+            t: ppu.loopy.t,
+            fine_x: ppu.loopy.x,
+            write_latch: ppu.loopy.w,
+            vram: ppu.vram.to_vec(),
+            oam_addr: ppu.oam_addr,
+            oam: ppu.oam_data.to_vec(),
+            palette: ppu.palette_table.to_vec(),
+            read_buffer: ppu.internal_data_buf,
+            scanline: ppu.scanline,
+            dot: ppu.cycles,
+            odd_frame: ppu.odd_frame,
+            suppress_vblank: ppu.suppress_vblank,
+            nmi: ppu.nmi_interrupt,
+            overclocked_lines: ppu.overclocked_lines,
+            sprite_eval_start: ppu.sprite_eval_start,
+            sprite_rotation: ppu.sprite_rotation,
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl crate::serde_state::PpuState {
+    // memories of the wrong size are cut short or left zero-padded
+    pub fn apply(&self, ppu: &mut NesPPU) {
+        fn copy(into: &mut [u8], from: &[u8]) {
+            into.fill(0);
+            let len = into.len().min(from.len());
+            into[..len].copy_from_slice(&from[..len]);
+        }
+        ppu.ctrl = ControlRegister::from_bits_truncate(self.ctrl);
+        ppu.mask = MaskRegister::from_bits_truncate(self.mask);
+        ppu.status = StatusRegister::from_bits_truncate(self.status);
+        ppu.loopy.v = self.v;
+        ppu.loopy.t = self.t;
+        ppu.loopy.x = self.fine_x;
+        ppu.loopy.w = self.write_latch;
+        copy(&mut ppu.vram, &self.vram);
+        ppu.oam_addr = self.oam_addr;
+        copy(&mut ppu.oam_data, &self.oam);
+        copy(&mut ppu.palette_table, &self.palette);
+        ppu.internal_data_buf = self.read_buffer;
+        ppu.scanline = self.scanline;
+        ppu.cycles = self.dot;
+        ppu.odd_frame = self.odd_frame;
+        ppu.suppress_vblank = self.suppress_vblank;
+        ppu.nmi_interrupt = self.nmi;
+        ppu.overclocked_lines = self.overclocked_lines;
+        ppu.sprite_eval_start = self.sprite_eval_start;
+        ppu.sprite_rotation = self.sprite_rotation;
+    }
+}
+
 impl PPU for NesPPU {
 
     fn write_to_ctrl(&mut self, value: u8){
+        self.record_write(0x2000, value);
         let before_nmi_status = self.ctrl.generate_vblank_nmi();
         self.ctrl.update(value);
+        self.loopy.write_ctrl(value);
         if !before_nmi_status && self.ctrl.generate_vblank_nmi() && self.status.is_in_vblank(){
             self.nmi_interrupt = Some(1);
         }
     }
 
     fn write_to_mask(&mut self, value: u8){
+        self.record_write(0x2001, value);
         self.mask.update(value);
     }
 
     fn write_to_oam_addr(&mut self, value: u8) {
+        self.record_write(0x2003, value);
         self.oam_addr = value;
     }
 
     fn write_to_oam_data(&mut self, value: u8) {
+        self.record_write(0x2004, value);
+        self.watch(PpuSpace::Oam, self.oam_addr as u16, self.oam_data[self.oam_addr as usize], value);
         self.oam_data[self.oam_addr as usize] = value;
         self.oam_addr = self.oam_addr.wrapping_add(1);
     }
@@ -156,47 +691,65 @@ impl PPU for NesPPU {
     }
 
     fn write_to_scroll(&mut self, value: u8) {
-        self.scroll.write(value);
+        self.record_write(0x2005, value);
+        self.loopy.write_scroll(value);
     }
 
     fn write_to_ppu_addr(&mut self, value: u8) {
-        self.addr.update(value);
+        self.record_write(0x2006, value);
+        self.loopy.write_addr(value);
+        if !self.loopy.w {
+            self.set_a12(self.loopy.v & 0x1000 != 0, self.cycles);
+        }
     }
 
     fn read_status(&mut self) -> u8 {
+        // The race with vblank starting: read one dot early and the flag
+        // reads clear and never goes up this frame; read on the dot it goes
+        // up or the next and it reads set, but the NMI doesn't happen.
+        if self.scanline == self.region.vblank_line() {
+            match self.cycles {
+                0 => self.suppress_vblank = true,
+                1 | 2 => self.nmi_interrupt = None,
+                _ => {}
+            }
+        }
         let data = self.status.snapshot();
         self.status.reset_vblank_status();
-        self.addr.reset_latch();
-        self.scroll.reset_latch();
+        self.loopy.reset_latch();
         data
     }
 
     fn read_data(&mut self) -> u8 {
-        let addr = self.addr.get();
+        let addr = self.loopy.addr();
+        self.set_a12(addr & 0x1000 != 0, self.cycles);
         self.increment_vram_addr();
 
         match addr {
             // simulate RAM and ROM internal buffer
             0..=0x1fff => {
                 let result = self.internal_data_buf;
-                self.internal_data_buf = self.chr_rom[addr as usize];
+                self.log_chr(addr, cdl::READ);
+                self.internal_data_buf = self.read_chr(addr);
                 result
             }
-            0x2000..=0x2fff => {
+            // 0x3000..0x3eff mirrors the nametables, v walks into it while rendering
+            0x2000..=0x3eff => {
                 let result = self.internal_data_buf;
                 self.internal_data_buf = self.vram[self.mirror_vram_addr(addr) as usize];
                 result
             }
-            0x3000..=0x3eff => panic!("0x3000..0x3eff is not expected to be used, requested = {}", addr),
-            
-            0x3f10 | 0x3f14 | 0x3f18 | 0x3f1c => {
-                let add_mirror = addr - 0x10;
-                self.palette_table[(add_mirror - 0x3f00) as usize]
-            }
-            
-            0x3f00..=0x3fff =>
-            {
-                self.palette_table[(addr- 0x3f00) as usize]
+            // Palette reads skip the buffer, only 6 bits are stored and
+            // grayscale applies. The buffer still gets filled, with the
+            // nametable byte "underneath" at addr - 0x1000.
+            0x3f00..=0x3fff => {
+                self.internal_data_buf = self.vram[self.mirror_vram_addr(addr) as usize];
+                let colour = self.palette_table[palette_index(addr)] & 0x3f;
+                if self.mask.is_grayscale() {
+                    colour & 0x30
+                } else {
+                    colour
+                }
             }
             _ => panic!("unexpected access to mirrored space {}", addr),
         }
@@ -204,27 +757,27 @@ impl PPU for NesPPU {
 
     fn write_oam_dma(&mut self, data: &[u8; 256]) {
         for x in data.iter() {
+            self.watch(PpuSpace::Oam, self.oam_addr as u16, self.oam_data[self.oam_addr as usize], *x);
             self.oam_data[self.oam_addr as usize] = *x;
             self.oam_addr = self.oam_addr.wrapping_add(1);
         }
     }
 
     fn write_to_data(&mut self, val: u8){
-        let addr = self.addr.get();
+        self.record_write(0x2007, val);
+        let addr = self.loopy.addr();
+        self.set_a12(addr & 0x1000 != 0, self.cycles);
         match addr {
-            0..=0x1fff => println!("attempt to write to chr rom space {}", addr),
-            0x2000..=0x2fff => {
-                self.vram[self.mirror_vram_addr(addr) as usize] = val;
+            0..=0x1fff => self.mapper.borrow_mut().chr_write(addr, val),
+            0x2000..=0x3eff => {
+                let index = self.mirror_vram_addr(addr) as usize;
+                self.watch(PpuSpace::Nametables, addr & 0x2fff, self.vram[index], val);
+                self.vram[index] = val;
             }
-            0x3000..=0x3eff => panic!("0x3000..0x3eff is not expected to be used, requested = {}", addr),
-            
-            0x3f10 | 0x3f14 | 0x3f18 | 0x3f1c => {
-                let add_mirror = addr - 0x10;
-                self.palette_table[(add_mirror - 0x3f00) as usize] = val;
-            }
-            
             0x3f00..=0x3fff => {
-                self.palette_table[(addr - 0x3f00) as usize] = val;
+                let index = palette_index(addr);
+                self.watch(PpuSpace::Palette, index as u16, self.palette_table[index], val);
+                self.palette_table[index] = val;
             }
 
             _ => panic!("Unexpected access ot mirrored space {}", addr),
@@ -234,6 +787,16 @@ impl PPU for NesPPU {
     }
 }
 
+// $3F00-$3FFF repeats the 32 palette entries, and $3F10/$3F14/$3F18/$3F1C
+// are the backdrop entries $3F00/$3F04/$3F08/$3F0C
+pub fn palette_index(addr: u16) -> usize {
+    let index = addr as usize & 0x1f;
+    if index & 0x13 == 0x10 {
+        index & 0x0f
+    } else {
+        index
+    }
+}
 
 #[cfg(test)]
 pub mod test {
@@ -259,7 +822,7 @@ pub mod test {
         ppu.write_to_ppu_addr(0x05);
 
         ppu.read_data(); //load_into_buffer
-        assert_eq!(ppu.addr.get(), 0x2306);
+        assert_eq!(ppu.loopy.addr(), 0x2306);
         assert_eq!(ppu.read_data(), 0x66);
     }
 
@@ -295,6 +858,148 @@ pub mod test {
         assert_eq!(ppu.read_data(), 0x88);
     }
 
+    #[test]
+    fn test_palette_reads_skip_the_buffer() {
+        let mut ppu = NesPPU::new_empty_rom();
+        let under = ppu.mirror_vram_addr(0x2f05) as usize;
+        ppu.vram[under] = 0x66;
+        ppu.palette_table[0x05] = 0xe1;
+        ppu.palette_table[0x00] = 0x0f;
+
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0x05);
+        // immediate, 6 bits
+        assert_eq!(ppu.read_data(), 0x21);
+        // the buffer picked up the nametable byte under the palette
+        ppu.write_to_ppu_addr(0x20);
+        ppu.write_to_ppu_addr(0x00);
+        assert_eq!(ppu.read_data(), 0x66);
+
+        // mirrors of the palette and of the backdrop entries
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0x30);
+        assert_eq!(ppu.read_data(), 0x0f);
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0xe5);
+        ppu.write_to_mask(0b0000_0001);
+        assert_eq!(ppu.read_data(), 0x20);
+    }
+
+    fn run_to(ppu: &mut NesPPU, scanline: u16, dot: usize) {
+        while ppu.position() != (scanline, dot) {
+            ppu.tick(1);
+        }
+    }
+
+    #[test]
+    fn test_vblank_dots_and_odd_frames() {
+        let mut ppu = NesPPU::new_empty_rom();
+        run_to(&mut ppu, 241, 0);
+        assert!(!ppu.status.is_in_vblank());
+        ppu.tick(1);
+        assert!(ppu.status.is_in_vblank());
+        run_to(&mut ppu, 261, 1);
+        assert!(!ppu.status.is_in_vblank());
+
+        let frame_length = |ppu: &mut NesPPU| {
+            let mut dots = 1;
+            while !ppu.tick(1) {
+                dots += 1;
+            }
+            dots
+        };
+        while !ppu.tick(1) {}
+        // frames alternate even/odd, the odd one skips a dot with rendering on
+        assert_eq!((frame_length(&mut ppu), frame_length(&mut ppu)), (89342, 89342));
+        ppu.write_to_mask(0b0000_1000);
+        assert_eq!((frame_length(&mut ppu), frame_length(&mut ppu)), (89341, 89342));
+    }
+
+    #[test]
+    fn test_pal_and_dendy_frames() {
+        let frame_length = |ppu: &mut NesPPU| {
+            let mut dots = 1;
+            while !ppu.tick(1) {
+                dots += 1;
+            }
+            dots
+        };
+        for (region, vblank_line) in [(Region::Pal, 241), (Region::Dendy, 291)] {
+            let mut ppu = NesPPU::new_empty_rom();
+            ppu.region = region;
+            run_to(&mut ppu, vblank_line, 1);
+            assert!(ppu.status.is_in_vblank());
+            run_to(&mut ppu, 311, 1);
+            assert!(!ppu.status.is_in_vblank());
+
+            // 312 lines and no skipped dot, rendering or not
+            while !ppu.tick(1) {}
+            ppu.write_to_mask(0b0000_1000);
+            assert_eq!((frame_length(&mut ppu), frame_length(&mut ppu)), (106392, 106392));
+        }
+    }
+
+    #[test]
+    fn test_status_read_races_vblank() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_ctrl(0b1000_0000);
+        // a dot early: reads clear, and no flag or NMI this frame
+        run_to(&mut ppu, 241, 0);
+        assert_eq!(ppu.read_status() & 0x80, 0);
+        run_to(&mut ppu, 241, 10);
+        assert!(!ppu.status.is_in_vblank());
+        assert_eq!(ppu.nmi_interrupt, None);
+
+        // on the dot: reads set, but the NMI is cancelled
+        run_to(&mut ppu, 241, 1);
+        assert_eq!(ppu.nmi_interrupt, Some(1));
+        assert_eq!(ppu.read_status() & 0x80, 0x80);
+        assert_eq!(ppu.nmi_interrupt, None);
+
+        // later reads leave the NMI alone
+        run_to(&mut ppu, 0, 0);
+        run_to(&mut ppu, 241, 3);
+        ppu.read_status();
+        assert_eq!(ppu.nmi_interrupt, Some(1));
+    }
+
+    #[test]
+    fn test_mid_frame_2006_write_timing() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_mask(0b0000_1000);
+
+        // written in hblank: the next line starts exactly at the address
+        run_to(&mut ppu, 100, 300);
+        ppu.write_to_ppu_addr(0x25);
+        ppu.write_to_ppu_addr(0x4a);
+        run_to(&mut ppu, 101, 0);
+        assert_eq!(ppu.line_scroll[101].v, 0x254a);
+
+        // written before dot 256: Y is incremented and X comes from t, which
+        // the $2006 write also set
+        run_to(&mut ppu, 120, 100);
+        ppu.write_to_ppu_addr(0x25);
+        ppu.write_to_ppu_addr(0x4a);
+        run_to(&mut ppu, 121, 0);
+        assert_eq!(ppu.line_scroll[121].v, 0x354a);
+    }
+
+    #[test]
+    fn test_2007_during_rendering_increments_x_and_y() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_mask(0b0000_1000);
+        run_to(&mut ppu, 10, 300);
+        ppu.loopy.v = 0x201f;
+        ppu.write_to_data(0);
+        assert_eq!(ppu.loopy.v, 0x3400);
+
+        // not during vblank
+        run_to(&mut ppu, 250, 0);
+        ppu.loopy.v = 0x201f;
+        ppu.write_to_data(0);
+        assert_eq!(ppu.loopy.v, 0x2020);
+    }
+
     // Horizontal: https://wiki.nesdev.com/w/index.php/Mirroring
     //   [0x2000 A ] [0x2400 a ]
     //   [0x2800 B ] [0x2C00 b ]
@@ -354,6 +1059,32 @@ pub mod test {
         assert_eq!(ppu.read_data(), 0x77); //read from B
     }
 
+    #[test]
+    fn test_vram_single_screen_mirror() {
+        let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::SINGLE_SCREEN_UPPER);
+
+        ppu.write_to_ppu_addr(0x2C);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.write_to_data(0x66);
+
+        assert_eq!(ppu.vram[0x0405], 0x66);
+        assert_eq!(ppu.mirror_vram_addr(0x2005), 0x0405);
+        assert_eq!(ppu.mirror_vram_addr(0x2805), 0x0405);
+    }
+
+    #[test]
+    fn test_vram_four_screen() {
+        let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::FOUR_SCREEN);
+        for nametable in 0..4u8 {
+            ppu.write_to_ppu_addr(0x20 + nametable * 4);
+            ppu.write_to_ppu_addr(0x05);
+            ppu.write_to_data(0x60 + nametable);
+        }
+        assert_eq!([ppu.vram[0x0005], ppu.vram[0x0405], ppu.vram[0x0805], ppu.vram[0x0c05]], [0x60, 0x61, 0x62, 0x63]);
+        // $3000-$3EFF still mirrors all four
+        assert_eq!(ppu.mirror_vram_addr(0x3c05), 0x0c05);
+    }
+
     #[test]
     fn test_read_status_resets_latch() {
         let mut ppu = NesPPU::new_empty_rom();
@@ -414,6 +1145,53 @@ pub mod test {
         assert_eq!(ppu.read_oam_data(), 0x77);
     }
 
+    #[test]
+    fn test_frame_dump_records_scanlines_and_writes() {
+        let mut ppu = NesPPU::new_empty_rom();
+        while ppu.scanline != 241 {
+            ppu.tick(1);
+        }
+        ppu.request_frame_dump();
+        ppu.write_to_scroll(0x10);
+        ppu.write_to_scroll(0x20);
+        ppu.oam_data[4] = 0x30;
+
+        while ppu.scanline != 240 {
+            ppu.tick(1);
+        }
+        assert!(ppu.take_frame_dump().is_none());
+        while ppu.scanline != 241 {
+            ppu.tick(1);
+        }
+
+        let dump = ppu.take_frame_dump().unwrap();
+        assert_eq!(dump.scanlines.len(), 261);
+        assert_eq!(dump.scanlines[0].scanline, 242);
+        assert_eq!(dump.scanlines[0].scroll_x, 0x10);
+        assert_eq!(dump.scanlines[0].scroll_y, 0x20);
+        assert_eq!(dump.writes.len(), 2);
+        assert_eq!(dump.writes[1].register, 0x2005);
+        assert_eq!(dump.sprites.len(), 64);
+        assert_eq!(dump.sprites[1].y, 0x30);
+        assert!(dump.to_json().contains("\"register\":\"$2005\""));
+    }
+
+    #[test]
+    fn test_reset_keeps_memory() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_ctrl(0b1000_0100);
+        ppu.write_to_scroll(0x10);
+        ppu.vram[0x0305] = 0x66;
+        ppu.oam_data[0] = 0x77;
+
+        ppu.reset();
+
+        assert_eq!(ppu.ctrl.bits(), 0);
+        assert_eq!(ppu.loopy.scroll_x(), 0);
+        assert_eq!(ppu.vram[0x0305], 0x66);
+        assert_eq!(ppu.oam_data[0], 0x77);
+    }
+
     #[test]
     fn test_oam_dma() {
         let mut ppu = NesPPU::new_empty_rom();
@@ -434,4 +1212,114 @@ pub mod test {
         ppu.write_to_oam_addr(0x11);
         assert_eq!(ppu.read_oam_data(), 0x66);
     }
-}
\ No newline at end of file
+
+    // overflow flag after rendering the line of `oam`'s first eight sprites
+    fn sprite_overflow_after(oam: &[[u8; 4]]) -> bool {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_mask(0b0001_0000);
+        for sprite in ppu.oam_data.chunks_mut(4) {
+            sprite[0] = 0xff;
+        }
+        for (i, sprite) in oam.iter().enumerate() {
+            ppu.oam_data[i * 4..i * 4 + 4].copy_from_slice(sprite);
+        }
+        while ppu.scanline <= 20 {
+            ppu.tick(255);
+        }
+        ppu.read_status() & 0b0010_0000 != 0
+    }
+
+    #[test]
+    fn test_sprite_overflow_with_evaluation_bug() {
+        let on_line = [16, 0, 0, 0];
+        let off_line = [200, 0, 0, 0];
+        assert!(!sprite_overflow_after(&[on_line; 8]));
+        assert!(sprite_overflow_after(&[on_line; 9]));
+
+        // ninth sprite off the line, the tenth's tile number (read as Y) is on it
+        let mut oam = vec![on_line; 8];
+        oam.extend([off_line, [200, 16, 0, 0]]);
+        assert!(sprite_overflow_after(&oam));
+        // a real ninth sprite missed: its Y is checked as the tile byte of sprite 9
+        let mut oam = vec![on_line; 8];
+        oam.extend([off_line, [16, 0xff, 0, 0]]);
+        assert!(!sprite_overflow_after(&oam));
+
+        // cleared at the end of vblank, kept by status reads until then
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_mask(0b0001_0000);
+        ppu.oam_data.fill(16);
+        while ppu.scanline != 241 {
+            ppu.tick(255);
+        }
+        assert_eq!(ppu.read_status() & 0b0010_0000, 0b0010_0000);
+        assert_eq!(ppu.read_status() & 0b0010_0000, 0b0010_0000);
+        while !ppu.tick(255) {}
+        assert_eq!(ppu.read_status() & 0b0010_0000, 0);
+    }
+
+    #[test]
+    fn test_overclock_delays_vblank() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.overclock_scanlines = 10;
+        ppu.write_to_ctrl(0b1000_0000);
+
+        let mut lines = 0;
+        while ppu.nmi_interrupt.is_none() {
+            ppu.tick(255);
+            ppu.tick(86);
+            lines += 1;
+        }
+        // vblank starts on dot 1, a line after the 241 whole ones
+        assert_eq!(lines, 242 + 10);
+
+        while !ppu.tick(255) {}
+        // the extra lines are counted again on the next frame
+        ppu.cycles = 0;
+        let mut lines = 0;
+        while ppu.scanline != 241 {
+            ppu.tick(255);
+            ppu.tick(86);
+            lines += 1;
+        }
+        assert_eq!(lines, 241 + 10);
+    }
+
+    // IRQ counted down from `latch` by A12 rises, after `lines` rendered lines
+    fn mmc3_irq_after(ctrl: u8, latch: u8, lines: u16) -> bool {
+        use crate::cartridge::mmc3::Mmc3;
+        let mapper: SharedMapper = Rc::new(RefCell::new(Mmc3::new(Rom {
+            prg_rom: vec![0; 0x8000],
+            chr_rom: vec![0; 0x2000],
+            trainer: None,
+            battery: false,
+            mapper: 4,
+            screen_mirroring: Mirroring::VERTICAL,
+            expansion_device: 0,
+            region: None,
+        })));
+        mapper.borrow_mut().prg_write(0xc000, latch);
+        mapper.borrow_mut().prg_write(0xe001, 0);
+        let mut ppu = NesPPU::with_mapper(mapper.clone());
+        ppu.write_to_ctrl(ctrl);
+        ppu.write_to_mask(0b0001_1000);
+        ppu.oam_data.fill(0xff);
+        while ppu.scanline < lines {
+            ppu.tick(7);
+        }
+        let irq = mapper.borrow().irq();
+        irq
+    }
+
+    #[test]
+    fn test_mmc3_counts_a12_rises() {
+        // 8x16 sprites: the empty slots fetch tile $FF from $1000 every line,
+        // reload on the first rise and reach zero on the fourth
+        assert!(!mmc3_irq_after(0b0010_0000, 3, 3));
+        assert!(mmc3_irq_after(0b0010_0000, 3, 4));
+        // background and 8x8 sprites both at $1000: A12 never falls
+        assert!(!mmc3_irq_after(0b0001_1000, 3, 20));
+        // background at $1000 and sprites at $0000: rises at dot 325
+        assert!(mmc3_irq_after(0b0001_0000, 3, 4));
+    }
+}
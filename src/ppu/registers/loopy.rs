@@ -0,0 +1,243 @@
+// binary literals below are grouped like the register layout
+#![allow(clippy::unusual_byte_groupings)]
+
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+// The PPU's internal scroll and address registers, as described in loopy's
+// "The skinny on NES scrolling" (https://www.nesdev.org/wiki/PPU_scrolling).
+// $2000, $2005 and $2006 all write into the same temporary address t, v is the
+// address the PPU actually fetches from, and one shared w toggle decides which
+// half of a $2005/$2006 write comes next.
+//
+// v and t are laid out as
+//   yyy NN YYYYY XXXXX
+//   |   |  |     +------ coarse X scroll
+//   |   |  +------------ coarse Y scroll
+//   |   +--------------- nametable select
+//   +------------------- fine Y scroll
+pub struct LoopyRegisters {
+    pub v: u16,
+    pub t: u16,
+    // fine X scroll, 3 bits
+    pub x: u8,
+    pub w: bool,
+}
+
+const COARSE_X: u16 = 0b000_00_00000_11111;
+const COARSE_Y: u16 = 0b000_00_11111_00000;
+const NAMETABLE_X: u16 = 0b000_01_00000_00000;
+const NAMETABLE_Y: u16 = 0b000_10_00000_00000;
+const FINE_Y: u16 = 0b111_00_00000_00000;
+const HORIZONTAL: u16 = NAMETABLE_X | COARSE_X;
+const VERTICAL: u16 = FINE_Y | NAMETABLE_Y | COARSE_Y;
+
+impl Default for LoopyRegisters {
+    fn default() -> Self {
+        LoopyRegisters::new()
+    }
+}
+
+impl LoopyRegisters {
+    pub fn new() -> Self {
+        LoopyRegisters {
+            v: 0,
+            t: 0,
+            x: 0,
+            w: false,
+        }
+    }
+
+    // $2000: the nametable select bits
+    pub fn write_ctrl(&mut self, data: u8) {
+        self.t = (self.t & !(NAMETABLE_X | NAMETABLE_Y)) | ((data as u16 & 0b11) << 10);
+    }
+
+    // $2005: X first, then Y
+    pub fn write_scroll(&mut self, data: u8) {
+        if !self.w {
+            self.t = (self.t & !COARSE_X) | (data as u16 >> 3);
+            self.x = data & 0b111;
+        } else {
+            self.t = (self.t & !(FINE_Y | COARSE_Y)) | ((data as u16 & 0b111) << 12) | ((data as u16 >> 3) << 5);
+        }
+        self.w = !self.w;
+    }
+
+    // $2006: high byte first (bit 14 of t is cleared), the low byte copies t
+    // into v
+    pub fn write_addr(&mut self, data: u8) {
+        if !self.w {
+            self.t = (self.t & 0x00ff) | ((data as u16 & 0x3f) << 8);
+        } else {
+            self.t = (self.t & 0xff00) | data as u16;
+            self.v = self.t;
+        }
+        self.w = !self.w;
+    }
+
+    // $2002 reads
+    pub fn reset_latch(&mut self) {
+        self.w = false;
+    }
+
+    // the 14 bit address $2007 goes to
+    pub fn addr(&self) -> u16 {
+        self.v & 0x3fff
+    }
+
+    // after a $2007 access outside of rendering
+    pub fn increment(&mut self, inc: u8) {
+        self.v = self.v.wrapping_add(inc as u16) & 0x7fff;
+    }
+
+    // every 8 dots while rendering, wrapping into the horizontal neighbour
+    pub fn increment_x(&mut self) {
+        self.v = next_tile(self.v);
+    }
+
+    // dot 256 of a rendering line. Coarse Y wraps into the vertical neighbour
+    // after row 29; rows 30 and 31 (attribute bytes) wrap without switching.
+    pub fn increment_y(&mut self) {
+        self.v = next_line(self.v);
+    }
+
+    // dot 257 of a rendering line
+    pub fn copy_x(&mut self) {
+        self.v = (self.v & !HORIZONTAL) | (self.t & HORIZONTAL);
+    }
+
+    // dots 280-304 of the pre-render line
+    pub fn copy_y(&mut self) {
+        self.v = (self.v & !VERTICAL) | (self.t & VERTICAL);
+    }
+
+    // t as plain pixel offsets, the way a game writes $2005
+    pub fn scroll_x(&self) -> u8 {
+        ((self.t & COARSE_X) as u8) << 3 | self.x
+    }
+
+    pub fn scroll_y(&self) -> u8 {
+        (((self.t & COARSE_Y) >> 5) as u8) << 3 | ((self.t & FINE_Y) >> 12) as u8
+    }
+}
+
+// v after the horizontal increment
+pub fn next_tile(v: u16) -> u16 {
+    if v & COARSE_X == 31 {
+        (v & !COARSE_X) ^ NAMETABLE_X
+    } else {
+        v + 1
+    }
+}
+
+// v after the vertical increment at the end of a line
+pub fn next_line(v: u16) -> u16 {
+    if v & FINE_Y != FINE_Y {
+        return v + 0x1000;
+    }
+    let v = v & !FINE_Y;
+    match (v & COARSE_Y) >> 5 {
+        29 => (v & !COARSE_Y) ^ NAMETABLE_Y,
+        31 => v & !COARSE_Y,
+        _ => v + 0x20,
+    }
+}
+
+impl Savestate for LoopyRegisters {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.v);
+        state.u16(self.t);
+        state.u8(self.x);
+        state.bool(self.w);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.v = state.u16()?;
+        self.t = state.u16()?;
+        self.x = state.u8()?;
+        self.w = state.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // the register walkthrough from the nesdev scrolling page
+    #[test]
+    fn test_documented_write_sequence() {
+        let mut loopy = LoopyRegisters::new();
+        loopy.write_ctrl(0b11);
+        assert_eq!(loopy.t, 0x0c00);
+
+        loopy.reset_latch();
+        loopy.write_scroll(0b01111_101);
+        assert_eq!(loopy.t, 0x0c0f);
+        assert_eq!(loopy.x, 0b101);
+        assert!(loopy.w);
+        loopy.write_scroll(0b01011_110);
+        assert_eq!(loopy.t, 0b110_11_01011_01111);
+        assert!(!loopy.w);
+
+        loopy.write_addr(0b00_111101);
+        assert_eq!(loopy.t, 0b011_11_01011_01111);
+        loopy.write_addr(0b11110000);
+        assert_eq!(loopy.t, 0b011_11_01111_10000);
+        assert_eq!(loopy.v, loopy.t);
+        assert_eq!(loopy.x, 0b101);
+    }
+
+    // the $2006/$2005/$2005/$2006 sequence games use to set X, Y and the
+    // nametable mid-frame, here nametable 1, X = $7B, Y = $5D
+    #[test]
+    fn test_mid_frame_scroll_write_sequence() {
+        let mut loopy = LoopyRegisters::new();
+        loopy.write_addr(1 << 2);
+        assert_eq!(loopy.t, 0b000_01_00000_00000);
+        loopy.write_scroll(0x5d);
+        loopy.write_scroll(0x7b);
+        assert_eq!(loopy.v, 0);
+        loopy.write_addr(((0x5d & 0xf8) << 2 | 0x7b >> 3) as u8);
+        assert_eq!(loopy.v, 0b101_01_01011_01111);
+        assert_eq!((loopy.x, loopy.w), (3, false));
+
+        // $2006's first write clears bit 14, so fine Y 4-7 can't be reached
+        loopy.write_addr(0xff);
+        loopy.write_addr(0xff);
+        assert_eq!(loopy.v, 0x3fff);
+
+        // a $2002 read between the halves starts over
+        loopy.write_scroll(0x10);
+        loopy.reset_latch();
+        loopy.write_scroll(0x20);
+        assert_eq!(loopy.scroll_x(), 0x20);
+    }
+
+    #[test]
+    fn test_rendering_increments_wrap_into_neighbour_nametables() {
+        let mut loopy = LoopyRegisters::new();
+        loopy.v = 31;
+        loopy.increment_x();
+        assert_eq!(loopy.v, NAMETABLE_X);
+
+        // fine Y carries into coarse Y, row 29 switches nametable
+        loopy.v = FINE_Y | (29 << 5);
+        loopy.increment_y();
+        assert_eq!(loopy.v, NAMETABLE_Y);
+        // row 31 wraps to 0 in the same nametable
+        loopy.v = FINE_Y | (31 << 5);
+        loopy.increment_y();
+        assert_eq!(loopy.v, 0);
+        loopy.v = 0x3000;
+        loopy.increment_y();
+        assert_eq!(loopy.v, 0x4000);
+
+        loopy.t = 0x7fff;
+        loopy.v = 0;
+        loopy.copy_x();
+        assert_eq!(loopy.v, HORIZONTAL);
+        loopy.copy_y();
+        assert_eq!(loopy.v, 0x7fff);
+    }
+}
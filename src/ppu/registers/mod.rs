@@ -1,5 +1,4 @@
 pub mod ctrl;
+pub mod loopy;
 pub mod mask;
 pub mod status;
-pub mod scroll;
-pub mod addr;
\ No newline at end of file
@@ -0,0 +1,110 @@
+use std::fmt;
+
+// Watchpoints on memory the CPU can only reach through the PPU: nametable
+// RAM, OAM and the palette. A watch fires when a write changes a byte inside
+// it; the bus turns that into a trap with the CPU side of the story.
+//
+//   --watch-ppu palette:00-1f     palette entries, $3F10/$3F14/.. are 00/04/..
+//   --watch-ppu oam:00-03         OAM bytes, sprite 0 here
+//   --watch-ppu nametables:2000-23bf
+//
+// Nametable addresses are the ones the game writes, $3000-$3EFF counting as
+// $2000-$2EFF; mirrored nametables are not folded onto each other.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PpuSpace {
+    Nametables,
+    Oam,
+    Palette,
+}
+
+impl PpuSpace {
+    pub fn parse(value: &str) -> Result<PpuSpace, String> {
+        match value {
+            "nametables" => Ok(PpuSpace::Nametables),
+            "oam" => Ok(PpuSpace::Oam),
+            "palette" => Ok(PpuSpace::Palette),
+            _ => Err(format!("'{}' is not a PPU space (nametables, oam, palette)", value)),
+        }
+    }
+}
+
+impl fmt::Display for PpuSpace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PpuSpace::Nametables => write!(f, "nametable address"),
+            PpuSpace::Oam => write!(f, "OAM byte"),
+            PpuSpace::Palette => write!(f, "palette entry"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PpuWatch {
+    pub space: PpuSpace,
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PpuWatch {
+    // space:start-end or space:index, in hex
+    pub fn parse(value: &str) -> Result<PpuWatch, String> {
+        let usage = || format!("PPU watch '{}' should look like palette:00-1f or oam:00", value);
+        let (space, range) = value.split_once(':').ok_or_else(usage)?;
+        let space = PpuSpace::parse(space)?;
+        let parse = |index: &str| u16::from_str_radix(index.trim(), 16).map_err(|_| usage());
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (parse(start)?, parse(end)?),
+            None => (parse(range)?, parse(range)?),
+        };
+        let (low, high) = match space {
+            PpuSpace::Nametables => (0x2000, 0x2fff),
+            PpuSpace::Oam => (0, 0xff),
+            PpuSpace::Palette => (0, 0x1f),
+        };
+        if start > end {
+            return Err(format!("PPU watch '{}' starts after it ends", value));
+        }
+        if start < low || end > high {
+            return Err(format!("PPU watch '{}' is outside {:x}-{:x}", value, low, high));
+        }
+        Ok(PpuWatch { space, start, end })
+    }
+
+    pub fn contains(&self, space: PpuSpace, index: u16) -> bool {
+        self.space == space && (self.start..=self.end).contains(&index)
+    }
+}
+
+// a watched byte that changed
+#[derive(Debug, Clone, PartialEq)]
+pub struct PpuWrite {
+    pub space: PpuSpace,
+    pub index: u16,
+    pub old: u8,
+    pub new: u8,
+}
+
+impl fmt::Display for PpuWrite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {:02x} changed from {:02x} to {:02x}", self.space, self.index, self.old, self.new)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let watch = PpuWatch::parse("palette:00-1f").unwrap();
+        assert!(watch.contains(PpuSpace::Palette, 0x10));
+        assert!(!watch.contains(PpuSpace::Oam, 0x10));
+        assert_eq!(PpuWatch::parse("oam:3").unwrap(), PpuWatch { space: PpuSpace::Oam, start: 3, end: 3 });
+        assert!(PpuWatch::parse("oam:100").is_err());
+        assert!(PpuWatch::parse("nametables:1fff-2000").is_err());
+        assert!(PpuWatch::parse("chr:00").is_err());
+        assert!(PpuWatch::parse("palette:1f-00").is_err());
+        assert!(PpuWatch::parse("palette").is_err());
+    }
+}
@@ -0,0 +1,128 @@
+// RAM search, for finding where a game keeps something (lives, health,
+// timers). Every address of the 2KB work RAM starts out as a candidate and
+// each search keeps the ones that pass a comparison, either against a value
+// or against what the address held at the previous search. Once one address
+// is left, a raw cheat ("0075:09", see cheats.rs) freezes it.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Equal(u8),
+    NotEqual(u8),
+    Greater(u8),
+    Less(u8),
+    Changed,
+    Unchanged,
+    Increased,
+    Decreased,
+    // current - previous, wrapping around as the byte does
+    ChangedBy(i16),
+}
+
+impl Comparison {
+    // names as the debug server takes them: equal, not_equal, greater, less
+    // and changed_by with a value, changed, unchanged, increased, decreased
+    pub fn parse(op: &str, value: Option<i64>) -> Result<Comparison, String> {
+        let byte = || match value {
+            Some(value) if (0..=0xff).contains(&value) => Ok(value as u8),
+            _ => Err(format!("'{}' needs a value between 0 and 255", op)),
+        };
+        match op {
+            "equal" => Ok(Comparison::Equal(byte()?)),
+            "not_equal" => Ok(Comparison::NotEqual(byte()?)),
+            "greater" => Ok(Comparison::Greater(byte()?)),
+            "less" => Ok(Comparison::Less(byte()?)),
+            "changed" => Ok(Comparison::Changed),
+            "unchanged" => Ok(Comparison::Unchanged),
+            "increased" => Ok(Comparison::Increased),
+            "decreased" => Ok(Comparison::Decreased),
+            "changed_by" => match value {
+                Some(value) if (-255..=255).contains(&value) && value != 0 => Ok(Comparison::ChangedBy(value as i16)),
+                _ => Err("'changed_by' needs a value between -255 and 255 other than 0".to_string()),
+            },
+            other => Err(format!("unknown comparison '{}'", other)),
+        }
+    }
+
+    fn matches(&self, previous: u8, current: u8) -> bool {
+        match *self {
+            Comparison::Equal(value) => current == value,
+            Comparison::NotEqual(value) => current != value,
+            Comparison::Greater(value) => current > value,
+            Comparison::Less(value) => current < value,
+            Comparison::Changed => current != previous,
+            Comparison::Unchanged => current == previous,
+            Comparison::Increased => current > previous,
+            Comparison::Decreased => current < previous,
+            Comparison::ChangedBy(delta) => current == previous.wrapping_add(delta as u8),
+        }
+    }
+}
+
+pub struct RamSearch {
+    // ascending
+    candidates: Vec<u16>,
+    // the RAM as of the last search
+    previous: Vec<u8>,
+}
+
+impl RamSearch {
+    pub fn new(ram: &[u8]) -> Self {
+        RamSearch {
+            candidates: (0..ram.len() as u16).collect(),
+            previous: ram.to_vec(),
+        }
+    }
+
+    pub fn candidates(&self) -> &[u16] {
+        &self.candidates
+    }
+
+    // what the address held at the last search
+    pub fn previous(&self, addr: u16) -> u8 {
+        self.previous[addr as usize]
+    }
+
+    // narrows the candidates down, returns how many are left
+    pub fn search(&mut self, ram: &[u8], comparison: Comparison) -> usize {
+        let previous = &self.previous;
+        self.candidates
+            .retain(|addr| comparison.matches(previous[*addr as usize], ram[*addr as usize]));
+        self.previous = ram.to_vec();
+        self.candidates.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_narrows_down_to_the_lives_counter() {
+        let mut ram = vec![0u8; 2048];
+        ram[0x75] = 3; // lives
+        ram[0x10] = 3; // something else that happens to be 3
+        ram[0x20] = 7;
+        let mut search = RamSearch::new(&ram);
+        assert_eq!(search.search(&ram, Comparison::Equal(3)), 2);
+
+        // a life lost, the other 3 goes up
+        ram[0x75] = 2;
+        ram[0x10] = 4;
+        assert_eq!(search.search(&ram, Comparison::Decreased), 1);
+        assert_eq!(search.candidates(), &[0x75]);
+        assert_eq!(search.previous(0x75), 2);
+
+        let mut search = RamSearch::new(&ram);
+        ram[0x75] = 0;
+        ram[0x20] = 5;
+        assert_eq!(search.search(&ram, Comparison::ChangedBy(-2)), 2);
+        ram[0x75] = 0xff;
+        assert_eq!(search.search(&ram, Comparison::parse("changed_by", Some(-1)).unwrap()), 1);
+        assert_eq!(search.candidates(), &[0x75]);
+
+        assert_eq!(Comparison::parse("greater", Some(9)), Ok(Comparison::Greater(9)));
+        assert!(Comparison::parse("greater", None).is_err());
+        assert!(Comparison::parse("changed_by", Some(0)).is_err());
+        assert!(Comparison::parse("bigger", Some(1)).is_err());
+    }
+}
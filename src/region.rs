@@ -0,0 +1,126 @@
+// The three console timings. PAL consoles run a slower CPU, a PPU that draws
+// 3.2 dots per CPU cycle and 312 lines a frame, and APU tables retuned for
+// the slower clock. Dendy, the Famiclone sold in Russia, keeps the NTSC CPU
+// speed and APU tables but runs PAL's 312 lines at 50Hz, with a longer wait
+// before vblank instead of a longer vblank.
+
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    Ntsc,
+    Pal,
+    Dendy,
+}
+
+const NTSC_DMC_RATES: [u16; 16] = [428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54];
+const PAL_DMC_RATES: [u16; 16] = [398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50];
+const NTSC_NOISE_PERIODS: [u16; 16] = [4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068];
+const PAL_NOISE_PERIODS: [u16; 16] = [4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778];
+
+impl Region {
+    pub fn parse(value: &str) -> Result<Region, String> {
+        match value {
+            "ntsc" => Ok(Region::Ntsc),
+            "pal" => Ok(Region::Pal),
+            "dendy" => Ok(Region::Dendy),
+            _ => Err(format!("unknown region '{}', expected ntsc, pal or dendy", value)),
+        }
+    }
+
+    // what the ROM header says, unless overridden; NTSC when neither says
+    pub fn select(from_header: Option<Region>, configured: Option<Region>) -> Region {
+        configured.or(from_header).unwrap_or(Region::Ntsc)
+    }
+
+    pub fn cpu_clock_hz(self) -> f64 {
+        match self {
+            Region::Ntsc => 1_789_773.0,
+            Region::Pal => 1_662_607.0,
+            Region::Dendy => 1_773_448.0,
+        }
+    }
+
+    pub fn frame_rate(self) -> f64 {
+        match self {
+            Region::Ntsc => 60.0988,
+            Region::Pal | Region::Dendy => 50.0070,
+        }
+    }
+
+    // PPU dots per CPU cycle, as a fraction
+    pub fn dots_per_cycle(self) -> (u32, u32) {
+        match self {
+            Region::Ntsc | Region::Dendy => (3, 1),
+            Region::Pal => (16, 5),
+        }
+    }
+
+    // lines per frame, the pre-render line last
+    pub fn scanlines(self) -> u16 {
+        match self {
+            Region::Ntsc => 262,
+            Region::Pal | Region::Dendy => 312,
+        }
+    }
+
+    pub fn vblank_line(self) -> u16 {
+        match self {
+            Region::Ntsc | Region::Pal => 241,
+            Region::Dendy => 291,
+        }
+    }
+
+    // only NTSC drops a dot from the pre-render line on odd frames
+    pub fn skips_odd_frame_dot(self) -> bool {
+        self == Region::Ntsc
+    }
+
+    // APU frame counter 4-step sequence length in CPU cycles
+    pub fn frame_counter_period(self) -> usize {
+        match self {
+            Region::Ntsc | Region::Dendy => 29830,
+            Region::Pal => 33254,
+        }
+    }
+
+    // CPU cycles per DMC output bit for each rate index
+    pub fn dmc_rates(self) -> &'static [u16; 16] {
+        match self {
+            Region::Ntsc | Region::Dendy => &NTSC_DMC_RATES,
+            Region::Pal => &PAL_DMC_RATES,
+        }
+    }
+
+    // CPU cycles per noise shift for each period index
+    pub fn noise_periods(self) -> &'static [u16; 16] {
+        match self {
+            Region::Ntsc | Region::Dendy => &NTSC_NOISE_PERIODS,
+            Region::Pal => &PAL_NOISE_PERIODS,
+        }
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Region::Ntsc => write!(f, "NTSC"),
+            Region::Pal => write!(f, "PAL"),
+            Region::Dendy => write!(f, "Dendy"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_select() {
+        assert_eq!(Region::parse("pal"), Ok(Region::Pal));
+        assert!(Region::parse("PAL-B").is_err());
+        assert_eq!(Region::select(None, None), Region::Ntsc);
+        assert_eq!(Region::select(Some(Region::Pal), None), Region::Pal);
+        assert_eq!(Region::select(Some(Region::Pal), Some(Region::Dendy)), Region::Dendy);
+    }
+}
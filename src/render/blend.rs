@@ -0,0 +1,117 @@
+// Interframe blending for games that flicker sprites every other frame to
+// fake transparency. CRTs smeared that into a see-through sprite; a modern
+// display shows the 30 Hz flicker. Runs as a post-processing stage, so it
+// blends whatever comes before it (the regular frame or the hd pack buffer).
+
+use super::frame::Frame;
+use super::post::{Image, PostProcessor};
+
+// how much of the previous picture survives each frame in phosphor mode
+const PHOSPHOR_DECAY: f32 = 0.6;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameBlend {
+    Off,
+    // even mix of this frame and the last one
+    Mix,
+    // bright pixels fade out over a few frames instead of vanishing
+    Phosphor,
+}
+
+impl FrameBlend {
+    pub fn parse(value: &str) -> Result<FrameBlend, String> {
+        match value {
+            "off" => Ok(FrameBlend::Off),
+            "mix" => Ok(FrameBlend::Mix),
+            "phosphor" => Ok(FrameBlend::Phosphor),
+            other => Err(format!("unknown frame blend '{}', expected off, mix or phosphor", other)),
+        }
+    }
+}
+
+pub struct Blender {
+    pub mode: FrameBlend,
+    // the last frame as rendered for mix, as presented for phosphor
+    previous: Vec<u8>,
+    output: Vec<u8>,
+}
+
+impl Blender {
+    pub fn new(mode: FrameBlend) -> Self {
+        Blender {
+            mode,
+            previous: vec![],
+            output: vec![],
+        }
+    }
+
+    // the picture to present for `current`, an RGB buffer of any size
+    pub fn blend<'a>(&'a mut self, current: &'a [u8]) -> &'a [u8] {
+        if self.mode == FrameBlend::Off {
+            return current;
+        }
+        // first frame, or the buffer changed size: nothing to blend with yet
+        if self.previous.len() != current.len() {
+            self.previous = current.to_vec();
+            self.output = current.to_vec();
+            return &self.output;
+        }
+
+        self.output.resize(current.len(), 0);
+        match self.mode {
+            FrameBlend::Off => unreachable!(),
+            FrameBlend::Mix => {
+                for ((out, &now), &before) in self.output.iter_mut().zip(current).zip(self.previous.iter()) {
+                    *out = (now as u16 + before as u16).div_ceil(2) as u8;
+                }
+                self.previous.copy_from_slice(current);
+            }
+            FrameBlend::Phosphor => {
+                for ((out, &now), &before) in self.output.iter_mut().zip(current).zip(self.previous.iter()) {
+                    *out = now.max((before as f32 * PHOSPHOR_DECAY) as u8);
+                }
+                self.previous.copy_from_slice(&self.output);
+            }
+        }
+        &self.output
+    }
+}
+
+impl PostProcessor for Blender {
+    fn name(&self) -> &'static str {
+        "frame-blend"
+    }
+
+    fn process(&mut self, _frame: &Frame, input: &Image, output: &mut Image) {
+        output.data.copy_from_slice(self.blend(&input.data));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_flickering_pixel_is_blended() {
+        let lit = [200, 100, 0];
+        let dark = [0, 0, 0];
+
+        let mut mix = Blender::new(FrameBlend::Mix);
+        assert_eq!(mix.blend(&lit), &lit);
+        assert_eq!(mix.blend(&dark), &[100, 50, 0]);
+        assert_eq!(mix.blend(&lit), &[100, 50, 0]);
+
+        let mut phosphor = Blender::new(FrameBlend::Phosphor);
+        phosphor.blend(&lit);
+        assert_eq!(phosphor.blend(&dark), &[120, 60, 0]);
+        assert_eq!(phosphor.blend(&dark), &[72, 36, 0]);
+        assert_eq!(phosphor.blend(&lit), &lit);
+
+        let mut off = Blender::new(FrameBlend::Off);
+        off.blend(&lit);
+        assert_eq!(off.blend(&dark), &dark);
+
+        assert_eq!(FrameBlend::parse("phosphor"), Ok(FrameBlend::Phosphor));
+        assert!(FrameBlend::parse("crt").is_err());
+    }
+}
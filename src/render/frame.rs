@@ -1,14 +1,26 @@
+use super::hd_pack::{PixelOwner, TilePlacement};
+use std::io::Write;
+
 pub struct Frame {
     pub data: Vec<u8>,
+    // tiles drawn this frame, collected only when an hd pack is loaded
+    pub tiles: Option<Vec<TilePlacement>>,
+    // per pixel, collected along with the tiles
+    pub owners: Option<Vec<PixelOwner>>,
+    // (x, y) of the first pixel where sprite 0 overlapped the background
+    pub sprite_zero_hit: Option<(usize, usize)>,
 }
 
 impl Frame {
-    const WIDTH: usize = 256;
-    const HIGHT: usize = 240;
+    pub const WIDTH: usize = 256;
+    pub const HIGHT: usize = 240;
 
     pub fn new() -> Self {
         Frame {
             data: vec![ 0; (Frame::WIDTH) * (Frame::HIGHT) * 3],
+            tiles: None,
+            owners: None,
+            sprite_zero_hit: None,
         }
     }
 
@@ -20,4 +32,15 @@ impl Frame {
             self.data[base + 2] = rgb.2;
         }
     }
-}
\ No newline at end of file
+
+    pub fn get_pixel(&self, x: usize, y: usize) -> (u8, u8, u8) {
+        let base = y * 3 * Frame::WIDTH + x * 3;
+        (self.data[base], self.data[base + 1], self.data[base + 2])
+    }
+
+    pub fn save_ppm(&self, path: &str) -> std::io::Result<()> {
+        let mut file = std::fs::File::create(path)?;
+        write!(file, "P6\n{} {}\n255\n", Frame::WIDTH, Frame::HIGHT)?;
+        file.write_all(&self.data)
+    }
+}
@@ -0,0 +1,309 @@
+// High resolution tile replacement packs.
+//
+// A pack is a directory with a `pack.toml` and the replacement images:
+//
+// scale = 4
+//
+// [[tile]]
+// hash = "8c7d0f3a1b2e4d56"   # tile_hash() of the 8x8 tile and its palette
+// image = "mario_head.png"    # (8 * scale) x (8 * scale) PNG, alpha 0 is transparent
+//
+// Tiles drawn by the renderer are hashed together with the palette they were
+// drawn with and replaced in a separate upscaled buffer; the regular frame is
+// left untouched. Replacements only cover the pixels their tile owns in the
+// frame: a background tile stays under the sprites shown over it, and a sprite
+// under higher priority sprites and, when it is behind, the background.
+
+use super::frame::Frame;
+use super::post::{Image, PostProcessor};
+use std::collections::HashMap;
+use std::path::Path;
+use toml::Value;
+
+// One 8x8 tile as drawn by the renderer, in screen coordinates
+pub struct TilePlacement {
+    pub x: isize,
+    pub y: isize,
+    pub chr: [u8; 16],
+    pub palette: [u8; 4],
+    pub flip_horizontal: bool,
+    pub flip_vertical: bool,
+    // visible part of the screen for this tile: x1, y1, x2, y2
+    pub clip: (isize, isize, isize, isize),
+    // for sprites, the place in the priority order (0 in front) and the
+    // behind-background bit
+    pub sprite: Option<(u8, bool)>,
+}
+
+// what the renderer drew at one frame pixel
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PixelOwner {
+    pub background_opaque: bool,
+    // the sprite that won the pixel, by priority, even when hidden behind
+    pub sprite: Option<u8>,
+    // whether that sprite is what the pixel shows
+    pub sprite_shown: bool,
+}
+
+// FNV-1a, stable across platforms and compiler versions so pack files stay valid
+pub fn tile_hash(chr: &[u8; 16], palette: &[u8; 4]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in chr.iter().chain(palette.iter()) {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+struct Replacement {
+    rgba: Vec<u8>,
+}
+
+pub struct HdPack {
+    pub scale: usize,
+    replacements: HashMap<u64, Replacement>,
+    pub data: Vec<u8>,
+}
+
+impl HdPack {
+    pub fn load(dir: &Path) -> Result<HdPack, String> {
+        let manifest = dir.join("pack.toml");
+        let text = std::fs::read_to_string(&manifest)
+            .map_err(|err| format!("hd pack: failed to read {}: {}", manifest.display(), err))?;
+        let root = text.parse::<Value>().map_err(|err| format!("hd pack: {}", err))?;
+
+        let scale = match root.get("scale").and_then(Value::as_integer) {
+            Some(scale) if (1..=8).contains(&scale) => scale as usize,
+            _ => return Err("hd pack: `scale` should be between 1 and 8".to_string()),
+        };
+
+        let mut replacements = HashMap::new();
+        let tiles = root.get("tile").and_then(Value::as_array).cloned().unwrap_or_default();
+        for tile in tiles.iter() {
+            let hash = tile
+                .get("hash")
+                .and_then(Value::as_str)
+                .and_then(|hash| u64::from_str_radix(hash, 16).ok())
+                .ok_or("hd pack: every tile needs a hex `hash`")?;
+            let image = tile
+                .get("image")
+                .and_then(Value::as_str)
+                .ok_or("hd pack: every tile needs an `image`")?;
+            let rgba = load_png(&dir.join(image), 8 * scale)?;
+            replacements.insert(hash, Replacement { rgba });
+        }
+
+        Ok(HdPack {
+            scale,
+            replacements,
+            data: vec![0; Frame::WIDTH * scale * Frame::HIGHT * scale * 3],
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        Frame::WIDTH * self.scale
+    }
+
+    pub fn height(&self) -> usize {
+        Frame::HIGHT * self.scale
+    }
+
+    // upscales the frame and draws the replacements over the tiles they match
+    pub fn render(&mut self, frame: &Frame) {
+        let mut data = std::mem::take(&mut self.data);
+        self.draw(frame, &frame.data, &mut data);
+        self.data = data;
+    }
+
+    // `input` is a frame-sized picture, `output` scale times bigger
+    fn draw(&self, frame: &Frame, input: &[u8], output: &mut [u8]) {
+        let scale = self.scale;
+        let width = self.width();
+        for y in 0..self.height() {
+            for x in 0..width {
+                let src = ((y / scale) * Frame::WIDTH + x / scale) * 3;
+                let dst = (y * width + x) * 3;
+                output[dst..dst + 3].copy_from_slice(&input[src..src + 3]);
+            }
+        }
+
+        let (tiles, owners) = match (frame.tiles.as_ref(), frame.owners.as_ref()) {
+            (Some(tiles), Some(owners)) => (tiles, owners),
+            _ => return,
+        };
+        let size = 8 * scale as isize;
+        for tile in tiles.iter() {
+            let replacement = match self.replacements.get(&tile_hash(&tile.chr, &tile.palette)) {
+                Some(replacement) => replacement,
+                None => continue,
+            };
+            let (x1, y1, x2, y2) = tile.clip;
+            for ty in 0..size {
+                for tx in 0..size {
+                    let screen_x = tile.x * scale as isize + tx;
+                    let screen_y = tile.y * scale as isize + ty;
+                    if screen_x < x1 * scale as isize
+                        || screen_x >= x2 * scale as isize
+                        || screen_y < y1 * scale as isize
+                        || screen_y >= y2 * scale as isize
+                    {
+                        continue;
+                    }
+                    let src_x = if tile.flip_horizontal { size - 1 - tx } else { tx };
+                    let src_y = if tile.flip_vertical { size - 1 - ty } else { ty };
+                    let src = ((src_y * size + src_x) * 4) as usize;
+                    if replacement.rgba[src + 3] == 0 {
+                        continue;
+                    }
+                    let owner = owners[(screen_y as usize / scale) * Frame::WIDTH + screen_x as usize / scale];
+                    let covered = match tile.sprite {
+                        None => owner.sprite_shown,
+                        Some((priority, behind)) => {
+                            owner.sprite.is_some_and(|front| front < priority) || (behind && owner.background_opaque)
+                        }
+                    };
+                    if covered {
+                        continue;
+                    }
+                    let dst = (screen_y as usize * width + screen_x as usize) * 3;
+                    output[dst..dst + 3].copy_from_slice(&replacement.rgba[src..src + 3]);
+                }
+            }
+        }
+    }
+}
+
+// Tiles are placed in frame coordinates, so this has to be the first stage.
+impl PostProcessor for HdPack {
+    fn name(&self) -> &'static str {
+        "hd-pack"
+    }
+
+    fn output_size(&self, width: usize, height: usize) -> (usize, usize) {
+        (width * self.scale, height * self.scale)
+    }
+
+    fn process(&mut self, frame: &Frame, input: &Image, output: &mut Image) {
+        self.draw(frame, &input.data, &mut output.data);
+    }
+}
+
+fn load_png(path: &Path, size: usize) -> Result<Vec<u8>, String> {
+    let file = std::fs::File::open(path).map_err(|err| format!("hd pack: failed to open {}: {}", path.display(), err))?;
+    let mut decoder = png::Decoder::new(file);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info().map_err(|err| format!("hd pack: {}: {}", path.display(), err))?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf).map_err(|err| format!("hd pack: {}: {}", path.display(), err))?;
+    if info.width as usize != size || info.height as usize != size {
+        return Err(format!("hd pack: {} should be {}x{}", path.display(), size, size));
+    }
+
+    let pixels = &buf[..info.buffer_size()];
+    let rgba = match info.color_type {
+        png::ColorType::Rgba => pixels.to_vec(),
+        png::ColorType::Rgb => pixels.chunks(3).flat_map(|p| [p[0], p[1], p[2], 0xff]).collect(),
+        png::ColorType::GrayscaleAlpha => pixels.chunks(2).flat_map(|p| [p[0], p[0], p[0], p[1]]).collect(),
+        png::ColorType::Grayscale => pixels.iter().flat_map(|&p| [p, p, p, 0xff]).collect(),
+        png::ColorType::Indexed => return Err(format!("hd pack: {} could not be expanded", path.display())),
+    };
+    Ok(rgba)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_tile_hash_depends_on_palette() {
+        let chr = [0x18; 16];
+        assert_eq!(tile_hash(&chr, &[0x0f, 0x01, 0x02, 0x03]), tile_hash(&chr, &[0x0f, 0x01, 0x02, 0x03]));
+        assert_ne!(tile_hash(&chr, &[0x0f, 0x01, 0x02, 0x03]), tile_hash(&chr, &[0x0f, 0x11, 0x02, 0x03]));
+    }
+
+    fn placement(x: isize, chr: [u8; 16], sprite: Option<(u8, bool)>) -> TilePlacement {
+        TilePlacement {
+            x,
+            y: 8,
+            chr,
+            palette: [0x0f, 0x01, 0x02, 0x03],
+            flip_horizontal: false,
+            flip_vertical: false,
+            clip: (0, 0, 256, 240),
+            sprite,
+        }
+    }
+
+    // a 2x pack replacing each of `tiles` with a solid colour
+    fn solid_pack(tiles: &[([u8; 16], [u8; 3])]) -> HdPack {
+        let mut replacements = HashMap::new();
+        for (chr, rgb) in tiles.iter() {
+            let rgba = (0..16 * 16).flat_map(|_| [rgb[0], rgb[1], rgb[2], 0xff]).collect();
+            replacements.insert(tile_hash(chr, &[0x0f, 0x01, 0x02, 0x03]), Replacement { rgba });
+        }
+        HdPack {
+            scale: 2,
+            replacements,
+            data: vec![0; Frame::WIDTH * 2 * Frame::HIGHT * 2 * 3],
+        }
+    }
+
+    #[test]
+    fn test_replacement_drawn_over_upscaled_frame() {
+        let chr = [0xff; 16];
+        let mut pack = solid_pack(&[]);
+        let mut rgba = vec![0; 16 * 16 * 4];
+        rgba[0..4].copy_from_slice(&[10, 20, 30, 0xff]);
+        pack.replacements.insert(tile_hash(&chr, &[0x0f, 0x01, 0x02, 0x03]), Replacement { rgba });
+
+        let mut frame = Frame::new();
+        frame.set_pixel(9, 8, (1, 2, 3));
+        frame.tiles = Some(vec![placement(8, chr, None)]);
+        frame.owners = Some(vec![PixelOwner::default(); Frame::WIDTH * Frame::HIGHT]);
+        pack.render(&frame);
+
+        let width = pack.width();
+        let at = |x: usize, y: usize| &pack.data[(y * width + x) * 3..(y * width + x) * 3 + 3];
+        assert_eq!(at(16, 16), &[10, 20, 30]);
+        // transparent replacement pixels keep the upscaled frame
+        assert_eq!(at(18, 16), &[1, 2, 3]);
+        assert_eq!(at(19, 17), &[1, 2, 3]);
+    }
+
+    #[test]
+    fn test_replacements_keep_sprite_priority() {
+        let background = [0x01; 16];
+        let behind = [0x03; 16];
+        let mut pack = solid_pack(&[(background, [1, 1, 1]), (behind, [3, 3, 3])]);
+
+        // x 8..16: a background tile with an unreplaced sprite shown at
+        // (9, 8) and a behind sprite that lost to it at (10, 8). x 16..24: a
+        // replaced behind sprite, over an opaque background at (16, 8).
+        let mut owners = vec![PixelOwner::default(); Frame::WIDTH * Frame::HIGHT];
+        let at = |x: usize, y: usize| y * Frame::WIDTH + x;
+        owners[at(9, 8)] = PixelOwner { background_opaque: true, sprite: Some(0), sprite_shown: true };
+        owners[at(10, 8)] = PixelOwner { background_opaque: false, sprite: Some(0), sprite_shown: true };
+        owners[at(16, 8)] = PixelOwner { background_opaque: true, sprite: Some(2), sprite_shown: false };
+        let mut frame = Frame::new();
+        frame.set_pixel(9, 8, (9, 9, 9));
+        frame.set_pixel(10, 8, (9, 9, 9));
+        frame.set_pixel(16, 8, (5, 5, 5));
+        frame.tiles = Some(vec![
+            placement(8, background, None),
+            placement(8, behind, Some((2, true))),
+            placement(16, behind, Some((2, true))),
+        ]);
+        frame.owners = Some(owners);
+        pack.render(&frame);
+
+        let width = pack.width();
+        let pixel = |x: usize, y: usize| &pack.data[(y * width + x) * 3..(y * width + x) * 3 + 3];
+        assert_eq!(pixel(16, 16), &[3, 3, 3]);
+        // the unreplaced sprite in front keeps its pixels
+        assert_eq!(pixel(18, 16), &[9, 9, 9]);
+        assert_eq!(pixel(20, 16), &[9, 9, 9]);
+        // the behind sprite stays under the opaque background
+        assert_eq!(pixel(32, 16), &[5, 5, 5]);
+        assert_eq!(pixel(34, 16), &[3, 3, 3]);
+    }
+}
@@ -1,22 +1,17 @@
+pub mod blend;
 pub mod frame;
+pub mod hd_pack;
+pub mod overlay;
 pub mod palette;
+pub mod post;
 
-use crate::ppu::NesPPU;
-use crate::cartridge::Mirroring;
+use crate::ppu::registers::loopy;
+use crate::ppu::{LineScroll, NesPPU, SpriteLimit, SPRITES_PER_SCANLINE};
 use frame::Frame;
+use hd_pack::{PixelOwner, TilePlacement};
+use std::ops::Range;
 
-fn bg_pallette(ppu: &NesPPU, attribute_table: &[u8], tile_column: usize, tile_row: usize) -> [u8; 4] {
-    let attr_table_idx = tile_row / 4 * 8 + tile_column / 4;
-    let attr_byte = attribute_table[attr_table_idx]; 
-
-    let pallet_idx = match (tile_column % 4 / 2, tile_row % 4 / 2) {
-        (0, 0) => attr_byte & 0b11,
-        (1, 0) => (attr_byte >> 2) & 0b11,
-        (0, 1) => (attr_byte >> 4) & 0b11,
-        (1, 1) => (attr_byte >> 6) & 0b11,
-        (_, _) => panic!("should not happen"),
-    };
-
+fn bg_pallette(ppu: &NesPPU, pallet_idx: u8) -> [u8; 4] {
     let pallete_start: usize = 1 + (pallet_idx as usize) * 4;
     [
         ppu.palette_table[0],
@@ -37,99 +32,202 @@ fn sprite_palette(ppu: &NesPPU, pallete_idx: u8) -> [u8; 4] {
     ]
 }
 
-struct Rect {
-    x1: usize,
-    y1: usize,
-    x2: usize,
-    y2: usize,
+// how the renderer reads pattern memory: NesPPU::fetch_chr like the PPU does,
+// or NesPPU::peek_chr for a picture that leaves the cartridge alone
+type ChrFetch = fn(&NesPPU, u16) -> u8;
+
+// a pattern table at $0000 or $1000, and how it is read
+#[derive(Clone, Copy)]
+struct PatternTable {
+    fetch: ChrFetch,
+    base: u16,
 }
 
-impl Rect {
-    fn new(x1: usize, y1: usize, x2: usize, y2: usize) -> Self {
-        Rect {
-            x1: x1,
-            y1: y1,
-            x2: x2,
-            y2: y2,
-        }
+fn read_tile(ppu: &NesPPU, table: PatternTable, tile_idx: u16) -> [u8; 16] {
+    let mut tile = [0; 16];
+    let start = table.base + tile_idx * 16;
+    for (i, byte) in tile.iter_mut().enumerate() {
+        *byte = (table.fetch)(ppu, start + i as u16);
     }
+    tile
 }
 
-fn render_name_table(ppu: &NesPPU, frame: &mut Frame, name_table: &[u8], 
-    view_port: Rect, shift_x: isize, shift_y: isize) {
-    let bank = ppu.ctrl.bknd_pattern_addr();
-
-    let attribute_table = &name_table[0x3c0.. 0x400];
+// For each sprite in evaluation order, a bit per row (0-7, or 0-15 for 8x16
+// sprites) that survives the per-scanline sprite limit.
+fn visible_sprite_rows(ppu: &NesPPU, order: &[usize]) -> Vec<u16> {
+    if ppu.sprite_limit == SpriteLimit::NoLimit {
+        return vec![0xffff; order.len()];
+    }
+    let height = ppu.ctrl.sprite_size() as usize;
+    let mut on_scanline = [0; Frame::HIGHT];
+    order
+        .iter()
+        .map(|&sprite| {
+            let top = ppu.oam_data[sprite * 4] as usize;
+            let mut rows = 0;
+            for row in 0..height {
+                let scanline = top + row;
+                if scanline >= Frame::HIGHT {
+                    break;
+                }
+                if on_scanline[scanline] < SPRITES_PER_SCANLINE {
+                    on_scanline[scanline] += 1;
+                    rows |= 1 << row;
+                }
+            }
+            rows
+        })
+        .collect()
+}
 
-    for i in 0..0x3c0 {
-        let tile_column = i % 32;
-        let tile_row = i / 32;
-        let tile_idx = name_table[i] as u16;
-        let tile = &ppu.chr_rom[(bank + tile_idx * 16) as usize..=(bank + tile_idx * 16 + 15) as usize];
-        let palette = bg_pallette(ppu, attribute_table, tile_column, tile_row);
+// First column a layer is drawn in: PPUMASK bits 1 and 2 hide the leftmost 8
+// pixels, which games use to cover scrolling garbage at the screen edge.
+fn left_edge(show_leftmost: bool) -> usize {
+    if show_leftmost {
+        0
+    } else {
+        8
+    }
+}
 
-        for y in 0..=7 {
-            let mut upper = tile[y];
-            let mut lower = tile[y + 8];
+// One background tile fetched from v the way the PPU does, drawn at (x, y)
+// but only on `lines`.
+fn render_bg_tile(ppu: &NesPPU, frame: &mut Frame, background_opaque: &mut [bool], v: u16, table: PatternTable,
+    (x, y): (isize, isize), lines: &Range<usize>) {
+    let tile_idx = ppu.vram[ppu.mirror_vram_addr(0x2000 | (v & 0x0fff)) as usize] as u16;
+    let attr_addr = 0x23c0 | (v & 0x0c00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07);
+    let attr_byte = ppu.vram[ppu.mirror_vram_addr(attr_addr) as usize];
+    let shift = ((v >> 4) & 4) | (v & 2);
+    let palette = bg_pallette(ppu, (attr_byte >> shift) & 0b11);
+    let tile = read_tile(ppu, table, tile_idx);
+    let left = left_edge(ppu.mask.leftmost_8pxl_background());
+    if let Some(tiles) = frame.tiles.as_mut() {
+        tiles.push(TilePlacement {
+            x,
+            y,
+            chr: tile,
+            palette,
+            flip_horizontal: false,
+            flip_vertical: false,
+            clip: (left as isize, lines.start as isize, Frame::WIDTH as isize, lines.end as isize),
+            sprite: None,
+        });
+    }
 
-            for x in (0..=7).rev() {
-                let value = (1 & lower) << 1 | (1 & upper);
-                upper = upper >> 1;
-                lower = lower >> 1;
-                let rgb = match value {
-                    0 => palette::SYSTEM_PALLETE[ppu.palette_table[0] as usize],
-                    1 => palette::SYSTEM_PALLETE[palette[1] as usize],
-                    2 => palette::SYSTEM_PALLETE[palette[2] as usize],
-                    3 => palette::SYSTEM_PALLETE[palette[3] as usize],
-                    _ => panic!("can't be"),
-                };
-                let pixel_x = tile_column * 8 + x;
-                let pixel_y = tile_row * 8 + y;
+    for row in 0..=7 {
+        let pixel_y = y + row as isize;
+        if pixel_y < 0 || !lines.contains(&(pixel_y as usize)) {
+            continue;
+        }
+        let mut upper = tile[row];
+        let mut lower = tile[row + 8];
 
-                if pixel_x >= view_port.x1 && pixel_x < view_port.x2 && pixel_y >= view_port.y1 && pixel_y < view_port.y2 {
-                    frame.set_pixel((shift_x + pixel_x as isize) as usize, (shift_y + pixel_y as isize) as usize, rgb);
-                }
+        for column in (0..=7).rev() {
+            let value = (1 & lower) << 1 | (1 & upper);
+            upper >>= 1;
+            lower >>= 1;
+            let pixel_x = x + column as isize;
+            if pixel_x < 0 || pixel_x >= Frame::WIDTH as isize {
+                continue;
             }
+            let (screen_x, screen_y) = (pixel_x as usize, pixel_y as usize);
+            let value = if screen_x < left { 0 } else { value };
+            let rgb = palette::masked_color(palette[value as usize], &ppu.mask);
+            frame.set_pixel(screen_x, screen_y, rgb);
+            background_opaque[screen_y * Frame::WIDTH + screen_x] = value != 0;
+        }
+    }
+}
+
+// All of `lines` carry on from the scroll of the first one, so they are drawn
+// a tile row at a time, 33 tiles wide to cover fine X.
+fn render_band(ppu: &NesPPU, fetch: ChrFetch, frame: &mut Frame, background_opaque: &mut [bool], scroll: LineScroll,
+    lines: Range<usize>) {
+    let fine_y = (scroll.v >> 12) as isize;
+    let mut row_v = scroll.v & 0x0fff;
+    let mut row_top = lines.start as isize - fine_y;
+    while row_top < lines.end as isize {
+        let mut v = row_v;
+        for column in 0..=32 {
+            let x = column * 8 - scroll.fine_x as isize;
+            let table = PatternTable { fetch, base: scroll.pattern_table };
+            render_bg_tile(ppu, frame, background_opaque, v, table, (x, row_top), &lines);
+            v = loopy::next_tile(v);
         }
+        // from the last pixel row of this tile row into the next one
+        row_v = loopy::next_line(row_v | 0x7000);
+        row_top += 8;
+    }
+}
+
+// A line whose scroll doesn't follow from the one above (the game wrote
+// $2000/$2005/$2006 during the frame) starts a new band.
+fn render_background(ppu: &NesPPU, fetch: ChrFetch, frame: &mut Frame, background_opaque: &mut [bool]) {
+    let mut top = 0;
+    while top < Frame::HIGHT {
+        let mut bottom = top + 1;
+        while bottom < Frame::HIGHT && ppu.line_scroll[bottom] == ppu.line_scroll[bottom - 1].next_line() {
+            bottom += 1;
+        }
+        render_band(ppu, fetch, frame, background_opaque, ppu.line_scroll[top], top..bottom);
+        top = bottom;
     }
 }
 
 pub fn render(ppu: &NesPPU, frame: &mut Frame) {
-    let scroll_x = (ppu.scroll.scroll_x) as usize;
-    let scroll_y = (ppu.scroll.scroll_y) as usize;
-
-    let (main_nametable, second_nametable) = match (&ppu.mirroring, ppu.ctrl.nametable_addr()) {
-        (Mirroring::VERTICAL, 0x2000) | (Mirroring::VERTICAL, 0x2800) | (Mirroring::HORIZONTAL, 0x2000) | (Mirroring::HORIZONTAL, 0x2400) => {
-            (&ppu.vram[0..0x400], &ppu.vram[0x400..0x800])
-        }
-        (Mirroring::VERTICAL, 0x2400) | (Mirroring::VERTICAL, 0x2C00) | (Mirroring::HORIZONTAL, 0x2800) | (Mirroring::HORIZONTAL, 0x2C00) => {
-            ( &ppu.vram[0x400..0x800], &ppu.vram[0..0x400])
-        }
-        (_,_) => {
-            panic!("Not supported mirroring type {:?}", ppu.mirroring);
-        }
-    };
-
-    render_name_table(ppu, frame, 
-        main_nametable, 
-        Rect::new(scroll_x, scroll_y, 256, 240 ),
-        -(scroll_x as isize), -(scroll_y as isize)
-    );
-    if scroll_x > 0 {
-        render_name_table(ppu, frame, 
-            second_nametable, 
-            Rect::new(0, 0, scroll_x, 240),
-            (256 - scroll_x) as isize, 0
-        );
-    } else if scroll_y > 0 {
-        render_name_table(ppu, frame, 
-            second_nametable, 
-            Rect::new(0, 0, 256, scroll_y),
-            0, (240 - scroll_y) as isize
-        );
-    }
-
-    for i in (0..ppu.oam_data.len()).step_by(4).rev() {
+    draw(ppu, NesPPU::fetch_chr, frame);
+}
+
+// The same picture without fetching: mappers latching on fetches (MMC2)
+// don't switch and the code/data logger isn't told. For tools, and for the
+// light gun looking at the screen mid-frame.
+pub fn render_peek(ppu: &NesPPU, frame: &mut Frame) {
+    draw(ppu, NesPPU::peek_chr, frame);
+}
+
+fn draw(ppu: &NesPPU, fetch: ChrFetch, frame: &mut Frame) {
+    if let Some(tiles) = frame.tiles.as_mut() {
+        tiles.clear();
+    }
+    // with the background off every pixel is the backdrop, and transparent
+    // as far as sprite priority goes
+    let mut background_opaque = vec![false; Frame::WIDTH * Frame::HIGHT];
+    if ppu.mask.show_background() {
+        render_background(ppu, fetch, frame, &mut background_opaque);
+    } else {
+        let backdrop = palette::masked_color(ppu.palette_table[0], &ppu.mask);
+        for y in 0..Frame::HIGHT {
+            for x in 0..Frame::WIDTH {
+                frame.set_pixel(x, y, backdrop);
+            }
+        }
+    }
+    let mut owners: Vec<PixelOwner> = background_opaque
+        .iter()
+        .map(|&background_opaque| PixelOwner { background_opaque, ..PixelOwner::default() })
+        .collect();
+    if !ppu.mask.show_sprites() {
+        frame.sprite_zero_hit = None;
+        if frame.tiles.is_some() {
+            frame.owners = Some(owners);
+        }
+        return;
+    }
+
+    // Sprites are drawn per pixel like the PPU's priority multiplexer: the
+    // lowest OAM index with an opaque pixel wins, and only then is its
+    // priority bit checked. A behind-background sprite therefore still hides
+    // higher-index sprites wherever the background is opaque (the SMB
+    // flagpole trick). Evaluation, and with it priority, starts at the sprite
+    // OAMADDR pointed to.
+    let first_sprite = ppu.sprite_evaluation_start();
+    let order: Vec<usize> = (0..64).map(|n| (first_sprite + n) % 64).collect();
+    let visible_rows = visible_sprite_rows(ppu, &order);
+    let sprite_tiles_start = frame.tiles.as_ref().map_or(0, |tiles| tiles.len());
+    let mut sprite_zero_hit: Option<(usize, usize)> = None;
+    let left = left_edge(ppu.mask.leftmost_8pxl_sprite());
+    for (priority, (&sprite, &rows)) in order.iter().zip(visible_rows.iter()).enumerate() {
+        let i = sprite * 4;
         let tile_idx = ppu.oam_data[i + 1] as u16;
         let tile_x = ppu.oam_data[i + 3] as usize;
         let tile_y = ppu.oam_data[i] as usize;
@@ -144,42 +242,283 @@ pub fn render(ppu: &NesPPU, frame: &mut Frame) {
         } else {
             false
         };
+        let behind_background = ppu.oam_data[i + 2] >> 5 & 1 == 1;
         let pallette_idx = ppu.oam_data[i + 2] & 0b11;
         let sprite_palette = sprite_palette(ppu, pallette_idx);
-        let bank: u16 = ppu.ctrl.sprt_pattern_addr();
-
-        let tile =
-            &ppu.chr_rom[(bank + tile_idx * 16) as usize..=(bank + tile_idx * 16 + 15) as usize];
+        // 8x16 sprites take the pattern table from bit 0 of the tile and
+        // stack the next tile under it; flipping vertically swaps the two
+        let height = ppu.ctrl.sprite_size() as usize;
+        let (bank, first_tile) = if height == 16 {
+            ((tile_idx & 1) * 0x1000, tile_idx & 0xfe)
+        } else {
+            (ppu.ctrl.sprt_pattern_addr(), tile_idx)
+        };
+        let tiles: Vec<[u8; 16]> = (0..height as u16 / 8)
+            .map(|half| read_tile(ppu, PatternTable { fetch, base: bank }, first_tile + half))
+            .collect();
+        if let Some(placements) = frame.tiles.as_mut() {
+            for (half, tile) in tiles.iter().enumerate() {
+                let row = if flip_VERTICAL { tiles.len() - 1 - half } else { half };
+                // hd packs paint in list order, so higher OAM indices go first
+                placements.insert(sprite_tiles_start, TilePlacement {
+                    x: tile_x as isize,
+                    y: (tile_y + row * 8) as isize,
+                    chr: *tile,
+                    palette: sprite_palette,
+                    flip_horizontal: flip_HORIZONTAL,
+                    flip_vertical: flip_VERTICAL,
+                    clip: (left as isize, 0, 256, 240),
+                    sprite: Some((priority as u8, behind_background)),
+                });
+            }
+        }
 
-        for y in 0..=7 {
-            let mut upper = tile[y];
-            let mut lower = tile[y + 8];
+        for row in 0..height {
+            if rows & (1 << row) == 0 {
+                continue;
+            }
+            let source = if flip_VERTICAL { height - 1 - row } else { row };
+            let tile = &tiles[source / 8];
+            let mut upper = tile[source % 8];
+            let mut lower = tile[source % 8 + 8];
             'ololo: for x in (0..=7).rev() {
                 let value = (1 & lower) << 1 | (1 & upper);
                 upper = upper >> 1;
                 lower = lower >> 1;
                 let rgb = match value {
                     0 => continue 'ololo, 
-                    1 => palette::SYSTEM_PALLETE[sprite_palette[1] as usize],
-                    2 => palette::SYSTEM_PALLETE[sprite_palette[2] as usize],
-                    3 => palette::SYSTEM_PALLETE[sprite_palette[3] as usize],
+                    1 => palette::masked_color(sprite_palette[1], &ppu.mask),
+                    2 => palette::masked_color(sprite_palette[2], &ppu.mask),
+                    3 => palette::masked_color(sprite_palette[3], &ppu.mask),
                     _ => panic!("can't be"),
                 };
-                match (flip_HORIZONTAL, flip_VERTICAL) {
-                    (false, false) => {
-                        frame.set_pixel(tile_x + x , tile_y + y, rgb);
-                    },
-                    (true, false) => {
-                        frame.set_pixel(tile_x + 7 - x , tile_y + y , rgb);
-                    }
-                    (false, true) => {
-                        frame.set_pixel(tile_x + x  , tile_y + 7 - y, rgb);
-                    }
-                    (true, true) => {
-                        frame.set_pixel(tile_x + 7 - x , tile_y + 7 - y , rgb);
-                    }
+                let pixel_x = if flip_HORIZONTAL { tile_x + 7 - x } else { tile_x + x };
+                let pixel_y = tile_y + row;
+                // a hidden sprite pixel is transparent: no hit, no priority
+                if pixel_x < left || pixel_x >= Frame::WIDTH || pixel_y >= Frame::HIGHT {
+                    continue;
+                }
+                let pixel = pixel_y * Frame::WIDTH + pixel_x;
+                // hit regardless of priority, but never in the last column
+                if sprite == 0 && background_opaque[pixel] && pixel_x != Frame::WIDTH - 1
+                    && sprite_zero_hit.is_none_or(|first| (pixel_y, pixel_x) < first) {
+                    sprite_zero_hit = Some((pixel_y, pixel_x));
+                }
+                let owner = &mut owners[pixel];
+                if owner.sprite.is_some() {
+                    continue;
+                }
+                owner.sprite = Some(priority as u8);
+                owner.sprite_shown = !(behind_background && background_opaque[pixel]);
+                if owner.sprite_shown {
+                    frame.set_pixel(pixel_x, pixel_y, rgb);
                 }
             }
         }
     }
-}
\ No newline at end of file
+    frame.sprite_zero_hit = sprite_zero_hit.map(|(y, x)| (x, y));
+    if frame.tiles.is_some() {
+        frame.owners = Some(owners);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Mirroring;
+    use crate::ppu::PPU;
+
+    #[test]
+    fn test_behind_sprite_hides_later_sprite_over_background() {
+        // tile 0 transparent, tiles 1 and 2 solid colour 1
+        let mut chr = vec![0; 0x2000];
+        chr[16..24].fill(0xff);
+        chr[32..40].fill(0xff);
+        let mut ppu = NesPPU::new(chr, Mirroring::HORIZONTAL);
+        ppu.write_to_mask(0b0001_1110);
+        ppu.vram[0] = 2; // opaque background tile at the top left
+        ppu.palette_table[0] = 0x0f;
+        ppu.palette_table[1] = 0x01;
+        ppu.palette_table[0x11] = 0x16;
+        ppu.palette_table[0x15] = 0x2a;
+        // two sprite pairs, a behind-background sprite in front of a normal one
+        for (i, x) in [0u8, 0, 8, 8].iter().enumerate() {
+            let attributes = if i % 2 == 0 { 0b0010_0000 } else { 0b01 };
+            ppu.oam_data[i * 4..i * 4 + 4].copy_from_slice(&[0, 1, attributes, *x]);
+        }
+
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        let pixel = |x: usize| {
+            let base = x * 3;
+            (frame.data[base], frame.data[base + 1], frame.data[base + 2])
+        };
+        // over the background the behind sprite wins the multiplexer and hides sprite 1
+        assert_eq!(pixel(0), palette::SYSTEM_PALLETE[0x01]);
+        // over transparent background the behind sprite shows
+        assert_eq!(pixel(8), palette::SYSTEM_PALLETE[0x16]);
+
+        // background off: backdrop everywhere, and nothing for a sprite to hide behind
+        ppu.write_to_mask(0b0001_0100);
+        render(&ppu, &mut frame);
+        assert_eq!(frame.get_pixel(0, 0), palette::SYSTEM_PALLETE[0x16]);
+        assert_eq!(frame.get_pixel(16, 0), palette::SYSTEM_PALLETE[0x0f]);
+        // sprites off
+        ppu.write_to_mask(0b0000_1010);
+        render(&ppu, &mut frame);
+        assert_eq!(frame.get_pixel(0, 0), palette::SYSTEM_PALLETE[0x01]);
+        assert_eq!(frame.get_pixel(8, 0), palette::SYSTEM_PALLETE[0x0f]);
+    }
+
+    #[test]
+    fn test_fine_scroll_split_mid_frame() {
+        // a solid tile down the left column of the first nametable
+        let mut chr = vec![0; 0x2000];
+        chr[16..24].fill(0xff);
+        let mut ppu = NesPPU::new(chr, Mirroring::HORIZONTAL);
+        for row in 0..30 {
+            ppu.vram[row * 32] = 1;
+        }
+        ppu.palette_table[0] = 0x0f;
+        ppu.palette_table[1] = 0x01;
+        ppu.write_to_mask(0b0000_1010);
+        ppu.write_to_scroll(3);
+        ppu.write_to_scroll(0);
+        // picked up by the pre-render line
+        while !ppu.tick(255) {}
+        while ppu.position().0 != 100 {
+            ppu.tick(1);
+        }
+        // only the horizontal part reaches v before the next frame
+        ppu.write_to_scroll(0);
+        ppu.write_to_scroll(50);
+        while ppu.position().0 != 241 {
+            ppu.tick(255);
+        }
+
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        let opaque = |x: usize, y: usize| {
+            let base = (y * Frame::WIDTH + x) * 3;
+            (frame.data[base], frame.data[base + 1], frame.data[base + 2]) == palette::SYSTEM_PALLETE[0x01]
+        };
+        assert!(opaque(4, 50) && !opaque(5, 50));
+        assert!(opaque(7, 150) && !opaque(8, 150));
+        // the tile column that scrolled in on the right
+        assert!(opaque(253, 50) && !opaque(252, 50));
+    }
+
+    #[test]
+    fn test_leftmost_8_pixels_masked() {
+        let mut chr = vec![0; 0x2000];
+        chr[16..24].fill(0xff);
+        let mut ppu = NesPPU::new(chr, Mirroring::HORIZONTAL);
+        ppu.vram[0] = 1;
+        ppu.vram[1] = 1;
+        ppu.palette_table[0] = 0x0f;
+        ppu.palette_table[1] = 0x01;
+        ppu.palette_table[0x11] = 0x16;
+        // sprite 0 straddling the edge, over the background
+        ppu.oam_data[0..4].copy_from_slice(&[24, 1, 0, 4]);
+        ppu.vram[3 * 32] = 1;
+        ppu.vram[3 * 32 + 1] = 1;
+        for sprite in ppu.oam_data[4..].chunks_mut(4) {
+            sprite[0] = 0xff;
+        }
+
+        ppu.write_to_mask(0b0001_1110);
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        assert_eq!(frame.get_pixel(0, 0), palette::SYSTEM_PALLETE[0x01]);
+        assert_eq!(frame.get_pixel(4, 24), palette::SYSTEM_PALLETE[0x16]);
+        assert_eq!(frame.sprite_zero_hit, Some((4, 24)));
+
+        // background hidden on the left: backdrop there, and no hit under it
+        ppu.write_to_mask(0b0001_1100);
+        render(&ppu, &mut frame);
+        assert_eq!(frame.get_pixel(7, 0), palette::SYSTEM_PALLETE[0x0f]);
+        assert_eq!(frame.get_pixel(8, 0), palette::SYSTEM_PALLETE[0x01]);
+        assert_eq!(frame.get_pixel(4, 24), palette::SYSTEM_PALLETE[0x16]);
+        assert_eq!(frame.sprite_zero_hit, Some((8, 24)));
+
+        // sprites hidden on the left
+        ppu.write_to_mask(0b0001_1010);
+        render(&ppu, &mut frame);
+        assert_eq!(frame.get_pixel(4, 24), palette::SYSTEM_PALLETE[0x01]);
+        assert_eq!(frame.get_pixel(8, 24), palette::SYSTEM_PALLETE[0x16]);
+        assert_eq!(frame.sprite_zero_hit, Some((8, 24)));
+    }
+
+    fn nine_sprites_on_a_line(limit: SpriteLimit) -> NesPPU {
+        let mut chr = vec![0; 0x2000];
+        chr[16..24].fill(0xff);
+        let mut ppu = NesPPU::new(chr, Mirroring::HORIZONTAL);
+        ppu.write_to_mask(0b0001_0100);
+        ppu.palette_table[0x11] = 0x16;
+        ppu.sprite_limit = limit;
+        for sprite in ppu.oam_data.chunks_mut(4) {
+            sprite[0] = 0xff;
+        }
+        for i in 0..9 {
+            ppu.oam_data[i * 4..i * 4 + 4].copy_from_slice(&[16, 1, 0, i as u8 * 16]);
+        }
+        ppu
+    }
+
+    fn sprite_drawn(ppu: &NesPPU, sprite: usize) -> bool {
+        let mut frame = Frame::new();
+        render(ppu, &mut frame);
+        let base = (16 * Frame::WIDTH + sprite * 16) * 3;
+        (frame.data[base], frame.data[base + 1], frame.data[base + 2]) == palette::SYSTEM_PALLETE[0x16]
+    }
+
+    #[test]
+    fn test_sprite_limit_modes() {
+        let ppu = nine_sprites_on_a_line(SpriteLimit::Hardware);
+        assert!(sprite_drawn(&ppu, 7));
+        assert!(!sprite_drawn(&ppu, 8));
+
+        let ppu = nine_sprites_on_a_line(SpriteLimit::NoLimit);
+        assert!(sprite_drawn(&ppu, 8));
+
+        // the next frame evaluates from sprite 1, so sprite 0 is the one dropped
+        let mut ppu = nine_sprites_on_a_line(SpriteLimit::Smart);
+        assert!(!sprite_drawn(&ppu, 8));
+        while !ppu.tick(255) {}
+        assert!(sprite_drawn(&ppu, 8));
+        assert!(!sprite_drawn(&ppu, 0));
+    }
+
+    #[test]
+    fn test_8x16_sprites() {
+        // tiles 2 and 3 solid, tile 4 solid over an empty tile 5
+        let mut chr = vec![0; 0x2000];
+        chr[32..40].fill(0xff);
+        chr[48..56].fill(0xff);
+        chr[64..72].fill(0xff);
+        let mut ppu = NesPPU::new(chr, Mirroring::HORIZONTAL);
+        ppu.write_to_ctrl(0b0010_0000);
+        ppu.write_to_mask(0b0001_0100);
+        ppu.palette_table[0x11] = 0x16;
+        for sprite in ppu.oam_data.chunks_mut(4) {
+            sprite[0] = 0xff;
+        }
+        // eight sprites over lines 16-31, a ninth starting halfway down them
+        for i in 0..8 {
+            ppu.oam_data[i * 4..i * 4 + 4].copy_from_slice(&[16, 2, 0, i as u8 * 16]);
+        }
+        ppu.oam_data[32..36].copy_from_slice(&[24, 2, 0, 128]);
+        // flipped vertically, the solid tile ends up at the bottom
+        ppu.oam_data[36..40].copy_from_slice(&[100, 4, 0x80, 0]);
+
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        let sprite = palette::SYSTEM_PALLETE[0x16];
+        assert_eq!(frame.get_pixel(0, 31), sprite);
+        assert_ne!(frame.get_pixel(128, 31), sprite);
+        assert_eq!(frame.get_pixel(128, 32), sprite);
+        assert_eq!(frame.get_pixel(128, 39), sprite);
+        assert_ne!(frame.get_pixel(0, 107), sprite);
+        assert_eq!(frame.get_pixel(0, 108), sprite);
+    }
+}
@@ -0,0 +1,140 @@
+// Debug drawing on top of the picture, as post-processing stages. Positions
+// are in NES pixels and scale with the image, so an overlay can sit after an
+// upscaler.
+
+use super::frame::Frame;
+use super::post::{Image, PostProcessor};
+
+const MARKER: (u8, u8, u8) = (0xff, 0x00, 0xff);
+const TEXT: (u8, u8, u8) = (0xff, 0xff, 0xff);
+const TEXT_BACKGROUND: (u8, u8, u8) = (0x00, 0x00, 0x00);
+
+// 3x5 glyphs, one row per byte, bit 2 is the left column
+fn glyph(c: char) -> [u8; 5] {
+    match c {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b010, 0b010],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        _ => [0; 5],
+    }
+}
+
+fn scale(image: &Image) -> usize {
+    (image.width / Frame::WIDTH).max(1)
+}
+
+fn dot(image: &mut Image, x: usize, y: usize, rgb: (u8, u8, u8)) {
+    let scale = scale(image);
+    image.fill_rect(x * scale, y * scale, scale, scale, rgb);
+}
+
+// text on a solid box so it reads over any background
+pub fn draw_text(image: &mut Image, x: usize, y: usize, text: &str) {
+    let scale = scale(image);
+    let width = text.chars().count() * 4 + 1;
+    image.fill_rect(x * scale, y * scale, width * scale, 7 * scale, TEXT_BACKGROUND);
+    for (i, c) in text.chars().enumerate() {
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for column in 0..3 {
+                if bits & (0b100 >> column) != 0 {
+                    dot(image, x + 1 + i * 4 + column, y + 1 + row, TEXT);
+                }
+            }
+        }
+    }
+}
+
+// Crosshair around the pixel where sprite 0 hit this frame, leaving the pixel
+// itself visible, and "scanline:dot" in the top left corner ("---:---" when
+// nothing hit). Pixel x comes out of the PPU on dot x + 1, which is when the
+// flag goes up on hardware.
+pub struct SpriteZeroOverlay;
+
+impl PostProcessor for SpriteZeroOverlay {
+    fn name(&self) -> &'static str {
+        "sprite-zero-hit"
+    }
+
+    fn process(&mut self, frame: &Frame, input: &Image, output: &mut Image) {
+        output.data.copy_from_slice(&input.data);
+        let label = match frame.sprite_zero_hit {
+            Some((x, y)) => {
+                for distance in 2..=4 {
+                    if x >= distance {
+                        dot(output, x - distance, y, MARKER);
+                    }
+                    if y >= distance {
+                        dot(output, x, y - distance, MARKER);
+                    }
+                    if x + distance < Frame::WIDTH {
+                        dot(output, x + distance, y, MARKER);
+                    }
+                    if y + distance < Frame::HIGHT {
+                        dot(output, x, y + distance, MARKER);
+                    }
+                }
+                format!("{:03}:{:03}", y, x + 1)
+            }
+            None => "---:---".to_string(),
+        };
+        // below the 8 lines most TVs cut off
+        draw_text(output, 8, 8, &label);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Mirroring;
+    use crate::ppu::{NesPPU, PPU};
+    use crate::render;
+    use crate::render::post::Pipeline;
+
+    #[test]
+    fn test_sprite_zero_hit_marked_where_it_overlaps_the_background() {
+        // tile 1 solid, tile 2 only its bottom right pixel
+        let mut chr = vec![0; 0x2000];
+        chr[16..24].fill(0xff);
+        chr[32 + 7] = 0x01;
+        let mut ppu = NesPPU::new(chr, Mirroring::HORIZONTAL);
+        ppu.write_to_mask(0b0001_1000);
+        // background tile 2 at tile column 5, row 6: opaque only at (47, 55)
+        ppu.vram[6 * 32 + 5] = 2;
+        ppu.palette_table[1] = 0x01;
+        ppu.palette_table[0x11] = 0x16;
+        ppu.oam_data[0..4].copy_from_slice(&[50, 1, 0, 44]);
+        for sprite in ppu.oam_data[4..].chunks_mut(4) {
+            sprite[0] = 0xff;
+        }
+
+        let mut frame = Frame::new();
+        render::render(&ppu, &mut frame);
+        assert_eq!(frame.sprite_zero_hit, Some((47, 55)));
+
+        let mut pipeline = Pipeline::new();
+        pipeline.push(Box::new(SpriteZeroOverlay), true);
+        let image = pipeline.run(&frame);
+        assert_eq!(image.get_pixel(45, 55), MARKER);
+        assert_eq!(image.get_pixel(47, 59), MARKER);
+        assert_ne!(image.get_pixel(47, 55), MARKER);
+        // "055:048", the first digit's top row
+        assert_eq!(image.get_pixel(9, 9), TEXT);
+        assert_eq!(image.get_pixel(8, 8), TEXT_BACKGROUND);
+        // the frame itself is left alone
+        assert_ne!(frame.get_pixel(45, 55), MARKER);
+
+        // sprite 0 away from anything opaque
+        ppu.oam_data[3] = 100;
+        render::render(&ppu, &mut frame);
+        assert_eq!(frame.sprite_zero_hit, None);
+    }
+}
@@ -1,3 +1,27 @@
+use crate::ppu::registers::mask::MaskRegister;
+
+// how much emphasis darkens the channels it doesn't pick
+const EMPHASIS_ATTENUATION: f32 = 0.746;
+
+// The colour the PPU puts out for a palette entry, after $2001 has had its say:
+// grayscale keeps only the brightness column, and each emphasis bit dims the
+// other two channels (all three set darkens everything). Red is bit 5, green
+// bit 6, blue bit 7 on NTSC.
+pub fn masked_color(index: u8, mask: &MaskRegister) -> (u8, u8, u8) {
+    let index = if mask.is_grayscale() { index & 0x30 } else { index & 0x3f };
+    let (r, g, b) = SYSTEM_PALLETE[index as usize];
+    // a channel is dimmed by emphasis on either of the other two
+    let emphasis = mask.bits() >> 5;
+    let dim = |value: u8, own_bit: u8| {
+        if emphasis & !own_bit == 0 {
+            value
+        } else {
+            (value as f32 * EMPHASIS_ATTENUATION) as u8
+        }
+    };
+    (dim(r, 0b001), dim(g, 0b010), dim(b, 0b100))
+}
+
 #[rustfmt::skip]
 
 pub static SYSTEM_PALLETE: [(u8,u8,u8); 64] = [
@@ -15,3 +39,22 @@ pub static SYSTEM_PALLETE: [(u8,u8,u8); 64] = [
    (0xFF, 0xEF, 0xA6), (0xFF, 0xF7, 0x9C), (0xD7, 0xE8, 0x95), (0xA6, 0xED, 0xAF), (0xA2, 0xF2, 0xDA),
    (0x99, 0xFF, 0xFC), (0xDD, 0xDD, 0xDD), (0x11, 0x11, 0x11), (0x11, 0x11, 0x11)
 ];
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_grayscale_and_emphasis() {
+        let plain = MaskRegister::new();
+        assert_eq!(masked_color(0x16, &plain), SYSTEM_PALLETE[0x16]);
+        let gray = MaskRegister::from_bits_truncate(0b0000_0001);
+        assert_eq!(masked_color(0x16, &gray), SYSTEM_PALLETE[0x10]);
+
+        // red emphasis on white keeps red and dims green and blue
+        let red = MaskRegister::from_bits_truncate(0b0010_0000);
+        assert_eq!(masked_color(0x30, &red), (0xff, 0xbe, 0xbe));
+        let all = MaskRegister::from_bits_truncate(0b1110_0000);
+        assert_eq!(masked_color(0x30, &all), (0xbe, 0xbe, 0xbe));
+    }
+}
@@ -0,0 +1,231 @@
+// Post-processing between the rendered frame and the window: upscalers, frame
+// blending, debug overlays, overscan crop. Stages run in the order they were
+// pushed, each reading the previous stage's image (the frame's own pixels for
+// the first one) and writing a new one, which may be a different size. The
+// Frame is passed along too for what the pixels don't carry: the hd pack's
+// tile list, the sprite 0 hit. Screenshots are taken from the Frame, so they
+// never include any of this.
+
+use super::frame::Frame;
+
+// an RGB picture, 3 bytes per pixel, no padding
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<u8>,
+}
+
+impl Default for Image {
+    fn default() -> Self {
+        Image::new()
+    }
+}
+
+impl Image {
+    pub fn new() -> Self {
+        Image {
+            width: 0,
+            height: 0,
+            data: vec![],
+        }
+    }
+
+    pub fn resize(&mut self, width: usize, height: usize) {
+        self.width = width;
+        self.height = height;
+        self.data.resize(width * height * 3, 0);
+    }
+
+    pub fn pitch(&self) -> usize {
+        self.width * 3
+    }
+
+    pub fn get_pixel(&self, x: usize, y: usize) -> (u8, u8, u8) {
+        let base = (y * self.width + x) * 3;
+        (self.data[base], self.data[base + 1], self.data[base + 2])
+    }
+
+    // clipped to the image
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, rgb: (u8, u8, u8)) {
+        for py in y..(y + height).min(self.height) {
+            for px in x..(x + width).min(self.width) {
+                let base = (py * self.width + px) * 3;
+                self.data[base..base + 3].copy_from_slice(&[rgb.0, rgb.1, rgb.2]);
+            }
+        }
+    }
+}
+
+pub trait PostProcessor {
+    // used to switch the stage on and off at runtime
+    fn name(&self) -> &'static str;
+
+    fn output_size(&self, width: usize, height: usize) -> (usize, usize) {
+        (width, height)
+    }
+
+    // `output` is already sized to output_size()
+    fn process(&mut self, frame: &Frame, input: &Image, output: &mut Image);
+}
+
+struct Stage {
+    processor: Box<dyn PostProcessor>,
+    enabled: bool,
+}
+
+pub struct Pipeline {
+    stages: Vec<Stage>,
+    // the stages write back and forth between these two
+    buffers: [Image; 2],
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Pipeline::new()
+    }
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Pipeline {
+            stages: vec![],
+            buffers: [Image::new(), Image::new()],
+        }
+    }
+
+    pub fn push(&mut self, processor: Box<dyn PostProcessor>, enabled: bool) {
+        self.stages.push(Stage { processor, enabled });
+    }
+
+    // switches the stage called `name` on or off, returns whether it is on
+    // now, None when there is no such stage. Toggling a stage that changes
+    // the size also changes output_size().
+    pub fn toggle(&mut self, name: &str) -> Option<bool> {
+        let stage = self.stages.iter_mut().find(|stage| stage.processor.name() == name)?;
+        stage.enabled = !stage.enabled;
+        Some(stage.enabled)
+    }
+
+    // size of what run() returns with the stages currently enabled
+    pub fn output_size(&self) -> (usize, usize) {
+        self.stages
+            .iter()
+            .filter(|stage| stage.enabled)
+            .fold((Frame::WIDTH, Frame::HIGHT), |(width, height), stage| {
+                stage.processor.output_size(width, height)
+            })
+    }
+
+    pub fn run(&mut self, frame: &Frame) -> &Image {
+        let [first, second] = &mut self.buffers;
+        first.resize(Frame::WIDTH, Frame::HIGHT);
+        first.data.copy_from_slice(&frame.data);
+        let (mut input, mut output) = (first, second);
+        for stage in self.stages.iter_mut().filter(|stage| stage.enabled) {
+            let (width, height) = stage.processor.output_size(input.width, input.height);
+            output.resize(width, height);
+            stage.processor.process(frame, input, output);
+            std::mem::swap(&mut input, &mut output);
+        }
+        input
+    }
+}
+
+// Cuts the edges most TVs hid, where games leave scrolling garbage. Amounts
+// are in NES pixels and scale with the image.
+pub struct OverscanCrop {
+    pub top: usize,
+    pub bottom: usize,
+    pub left: usize,
+    pub right: usize,
+}
+
+impl OverscanCrop {
+    // the usual NTSC crop: 8 lines top and bottom
+    pub fn ntsc() -> Self {
+        OverscanCrop {
+            top: 8,
+            bottom: 8,
+            left: 0,
+            right: 0,
+        }
+    }
+
+    fn scale(width: usize) -> usize {
+        (width / Frame::WIDTH).max(1)
+    }
+}
+
+impl PostProcessor for OverscanCrop {
+    fn name(&self) -> &'static str {
+        "overscan-crop"
+    }
+
+    fn output_size(&self, width: usize, height: usize) -> (usize, usize) {
+        let scale = OverscanCrop::scale(width);
+        (
+            width.saturating_sub((self.left + self.right) * scale),
+            height.saturating_sub((self.top + self.bottom) * scale),
+        )
+    }
+
+    fn process(&mut self, _frame: &Frame, input: &Image, output: &mut Image) {
+        let scale = OverscanCrop::scale(input.width);
+        let (left, top) = (self.left * scale, self.top * scale);
+        let pitch = output.pitch();
+        for y in 0..output.height {
+            let src = ((y + top) * input.width + left) * 3;
+            output.data[y * pitch..(y + 1) * pitch].copy_from_slice(&input.data[src..src + pitch]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // adds `step` to every byte
+    struct Brighten {
+        step: u8,
+    }
+
+    impl PostProcessor for Brighten {
+        fn name(&self) -> &'static str {
+            "brighten"
+        }
+
+        fn process(&mut self, _frame: &Frame, input: &Image, output: &mut Image) {
+            for (out, byte) in output.data.iter_mut().zip(input.data.iter()) {
+                *out = byte + self.step;
+            }
+        }
+    }
+
+    #[test]
+    fn test_stages_chain_in_order() {
+        let mut frame = Frame::new();
+        frame.set_pixel(0, 8, (1, 1, 1));
+        frame.set_pixel(0, 9, (5, 5, 5));
+
+        let mut pipeline = Pipeline::new();
+        pipeline.push(Box::new(Brighten { step: 1 }), true);
+        pipeline.push(Box::new(OverscanCrop::ntsc()), true);
+        pipeline.push(Box::new(Brighten { step: 10 }), false);
+        assert_eq!(pipeline.output_size(), (256, 224));
+
+        let image = pipeline.run(&frame);
+        assert_eq!((image.width, image.height), (256, 224));
+        assert_eq!(image.get_pixel(0, 0), (2, 2, 2));
+        assert_eq!(image.get_pixel(0, 1), (6, 6, 6));
+
+        // the first stage named brighten
+        assert_eq!(pipeline.toggle("brighten"), Some(false));
+        assert_eq!(pipeline.toggle("scanlines"), None);
+        assert_eq!(pipeline.run(&frame).get_pixel(0, 0), (1, 1, 1));
+
+        // with nothing enabled the frame comes out as it is
+        pipeline.toggle("overscan-crop");
+        let image = pipeline.run(&frame);
+        assert_eq!(image.data, frame.data);
+    }
+}
@@ -0,0 +1,188 @@
+// Hold-to-rewind: savestates taken every few frames, kept in a ring buffer
+// bounded by memory rather than by count.
+//
+// Only the newest state is kept whole. Each older one is stored as the XOR
+// against the state after it, run-length encoded: consecutive states differ
+// in a few hundred bytes, so a delta is mostly zero runs and costs a small
+// fraction of a full state. Rewinding walks the chain backwards, one XOR per
+// step; the oldest deltas are dropped when the buffer is over budget.
+
+use std::collections::VecDeque;
+
+// snapshot every other frame, which rewinds at twice the speed it played
+pub const INTERVAL_FRAMES: usize = 2;
+pub const DEFAULT_BUDGET_BYTES: usize = 32 * 1024 * 1024;
+
+pub struct RewindBuffer {
+    latest: Option<Vec<u8>>,
+    // oldest first, each turns the state after it into its own
+    deltas: VecDeque<Vec<u8>>,
+    delta_bytes: usize,
+    budget_bytes: usize,
+}
+
+impl RewindBuffer {
+    pub fn new(budget_bytes: usize) -> Self {
+        RewindBuffer {
+            latest: None,
+            deltas: VecDeque::new(),
+            delta_bytes: 0,
+            budget_bytes,
+        }
+    }
+
+    // how many states a rewind can step back through
+    pub fn len(&self) -> usize {
+        self.deltas.len() + self.latest.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_none()
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        if let Some(latest) = self.latest.as_ref() {
+            // a state of another size (a different machine) can't be chained
+            if latest.len() != state.len() {
+                self.deltas.clear();
+                self.delta_bytes = 0;
+            } else {
+                let delta = encode(&xor(latest, &state));
+                self.delta_bytes += delta.len();
+                self.deltas.push_back(delta);
+            }
+        }
+        self.latest = Some(state);
+        while self.delta_bytes + self.latest_len() > self.budget_bytes {
+            match self.deltas.pop_front() {
+                Some(delta) => self.delta_bytes -= delta.len(),
+                None => break,
+            }
+        }
+    }
+
+    // the newest state, the one before it becomes the newest
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let latest = self.latest.take()?;
+        if let Some(delta) = self.deltas.pop_back() {
+            self.delta_bytes -= delta.len();
+            self.latest = Some(xor(&latest, &decode(&delta, latest.len())));
+        }
+        Some(latest)
+    }
+
+    fn latest_len(&self) -> usize {
+        self.latest.as_ref().map_or(0, |latest| latest.len())
+    }
+}
+
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    a.iter().zip(b.iter()).map(|(a, b)| a ^ b).collect()
+}
+
+// (zero run, literal length, literal bytes) repeated, the lengths as LEB128
+fn encode(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    let mut i = 0;
+    while i < data.len() {
+        let zeros = data[i..].iter().take_while(|&&byte| byte == 0).count();
+        i += zeros;
+        let literal = data[i..].iter().take_while(|&&byte| byte != 0).count();
+        write_length(&mut out, zeros);
+        write_length(&mut out, literal);
+        out.extend_from_slice(&data[i..i + literal]);
+        i += literal;
+    }
+    out
+}
+
+fn decode(encoded: &[u8], len: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(len);
+    let mut pos = 0;
+    while pos < encoded.len() {
+        let zeros = read_length(encoded, &mut pos);
+        let literal = read_length(encoded, &mut pos);
+        out.resize(out.len() + zeros, 0);
+        out.extend_from_slice(&encoded[pos..pos + literal]);
+        pos += literal;
+    }
+    out.resize(len, 0);
+    out
+}
+
+fn write_length(out: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn read_length(data: &[u8], pos: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*pos];
+        *pos += 1;
+        value |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn state(frame: u8) -> Vec<u8> {
+        let mut state = vec![0x55; 4000];
+        state[10] = frame;
+        state[3000..3200].fill(frame);
+        state
+    }
+
+    #[test]
+    fn test_rewinds_in_order_within_budget() {
+        let mut buffer = RewindBuffer::new(DEFAULT_BUDGET_BYTES);
+        for frame in 0..50 {
+            buffer.push(state(frame));
+        }
+        assert_eq!(buffer.len(), 50);
+        // deltas are small next to the 4000-byte states
+        assert!(buffer.delta_bytes < 49 * 250);
+        for frame in (40..50).rev() {
+            assert_eq!(buffer.pop(), Some(state(frame)));
+        }
+
+        // playing on from frame 39 drops what came after it
+        buffer.push(state(100));
+        assert_eq!(buffer.pop(), Some(state(100)));
+        assert_eq!(buffer.pop(), Some(state(39)));
+
+        let mut small = RewindBuffer::new(4000 + 1000);
+        for frame in 0..50 {
+            small.push(state(frame));
+        }
+        assert!(small.len() < 50);
+        let kept = small.len();
+        for frame in (50 - kept..50).rev() {
+            assert_eq!(small.pop(), Some(state(frame as u8)));
+        }
+        assert_eq!(small.pop(), None);
+        assert!(small.is_empty());
+    }
+
+    #[test]
+    fn test_run_length_encoding() {
+        for data in [vec![], vec![0; 300], vec![1, 2, 3], [vec![0; 200], vec![9; 130], vec![0; 3]].concat()] {
+            assert_eq!(decode(&encode(&data), data.len()), data);
+        }
+        assert_eq!(encode(&[0, 0, 0, 7, 8, 0]), vec![3, 2, 7, 8, 1, 0]);
+    }
+}
@@ -0,0 +1,91 @@
+// Run-ahead: hides the lag games build in between reading the controller
+// and drawing the result. After every real frame the machine is saved, run a
+// few frames further on the input just read, the last of those is shown, and
+// the saved state is loaded back. The next real frame then starts from
+// where it should, with whatever input arrives by then.
+//
+// The gameloop callback fires for every frame run ahead too; the phase says
+// which ones to draw. Each real frame costs 1 + frames frames of emulation.
+// Frames run ahead skip the frontend's per-instruction hooks, so traces,
+// breakpoints and triggers only ever see real frames.
+
+use crate::cpu::CPU;
+use crate::savestate;
+use std::cell::Cell;
+
+pub const MAX_FRAMES: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RunAheadPhase {
+    // run-ahead is off, every frame is real and shown
+    Off,
+    // a real frame: takes input, isn't shown
+    Real,
+    // run ahead and thrown away
+    Hidden,
+    // run ahead, the one shown for this real frame
+    Shown,
+}
+
+pub fn parse_frames(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(frames) if frames <= MAX_FRAMES => Ok(frames),
+        _ => Err(format!("run-ahead should be between 0 and {} frames, got '{}'", MAX_FRAMES, value)),
+    }
+}
+
+// Call at the start of a frame; the machine ends up where it started.
+pub fn run_ahead(cpu: &mut CPU, frames: usize, phase: &Cell<RunAheadPhase>) {
+    let state = savestate::save(cpu);
+    // what the frames run ahead play is thrown away with them
+    let samples = std::mem::take(&mut cpu.bus.apu.samples);
+    let stem_samples = std::mem::take(&mut cpu.bus.apu.stem_samples);
+    let target = cpu.bus.frame_count() + frames;
+    while cpu.bus.frame_count() < target {
+        let last = cpu.bus.frame_count() + 1 == target;
+        phase.set(if last { RunAheadPhase::Shown } else { RunAheadPhase::Hidden });
+        cpu.step();
+    }
+    phase.set(RunAheadPhase::Real);
+    savestate::load(cpu, &state).unwrap();
+    cpu.bus.breakpoints.clear_hit();
+    cpu.bus.apu.samples = samples;
+    cpu.bus.apu.stem_samples = stem_samples;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::builder::RomBuilder;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_runs_ahead_and_comes_back() {
+        let rom = RomBuilder::new()
+            .program(0x8000, &[0xa9, 0x80, 0x8d, 0x00, 0x20, 0x4c, 0x05, 0x80]) // NMI on; loop: JMP loop
+            .program(0x8010, &[0xe6, 0x10, 0x40]) // nmi: INC $10; RTI
+            .nmi_vector(0x8010)
+            .rom();
+        let phase = Rc::new(Cell::new(RunAheadPhase::Real));
+        let seen = Rc::new(RefCell::new(vec![]));
+        let (drawing, drawn) = (phase.clone(), seen.clone());
+        let bus = Bus::new(rom, move |_, _, _| drawn.borrow_mut().push(drawing.get()));
+        let mut cpu = CPU::new(bus);
+        cpu.reset();
+        while cpu.bus.frame_count() < 2 {
+            cpu.step();
+        }
+        seen.borrow_mut().clear();
+
+        let before = savestate::save(&cpu);
+        run_ahead(&mut cpu, 3, &phase);
+        assert_eq!(*seen.borrow(), vec![RunAheadPhase::Hidden, RunAheadPhase::Hidden, RunAheadPhase::Shown]);
+        assert!(savestate::save(&cpu) == before);
+        assert_eq!(phase.get(), RunAheadPhase::Real);
+
+        assert_eq!(parse_frames("2"), Ok(2));
+        assert!(parse_frames("5").is_err());
+    }
+}
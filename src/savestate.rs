@@ -0,0 +1,254 @@
+// Binary machine state snapshots, and the container the other binary files
+// (movies, frame hashes) share with them.
+//
+// Every file starts with a 4-byte magic and a version byte. The fields that
+// follow are in a fixed order with no padding:
+//
+//   u8, bool     one byte, a bool is 0 or 1
+//   u16, u32     little endian
+//   u64, usize   little endian, usize always takes 8 bytes so 32- and 64-bit
+//                builds read each other's files
+//   bytes        a usize length, then that many bytes
+//
+// Readers reject another magic or version, values that don't fit (a bool of
+// 2, a length past what usize holds here) and trailing data, so a file either
+// loads exactly as written or not at all.
+//
+// Savestates are "NESS". Every component writes its fields in a fixed order.
+// Anything that changes what the next cycle does has to be in here,
+// including latched-but-not-yet-serviced state: pending NMI/IRQ, the PPU read
+// buffer and write toggles, joypad shift position. OAM DMA completes inside
+// the $4014 write, so there is never a transfer in flight at an instruction
+// boundary.
+
+use std::convert::TryFrom;
+
+pub struct Format {
+    pub magic: [u8; 4],
+    pub version: u8,
+    // what the file is called in error messages
+    pub name: &'static str,
+}
+
+pub const SAVESTATE: Format = Format { magic: *b"NESS", version: 10, name: "savestate" };
+
+pub trait Savestate {
+    fn save_state(&self, state: &mut StateWriter);
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String>;
+}
+
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        StateWriter::new()
+    }
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        StateWriter::with_format(&SAVESTATE)
+    }
+
+    pub fn with_format(format: &Format) -> Self {
+        let mut data = format.magic.to_vec();
+        data.push(format.version);
+        StateWriter { data }
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn usize(&mut self, value: usize) {
+        self.u64(value as u64);
+    }
+
+    // length prefixed, so a state saved with a different RAM size fails to load
+    pub fn bytes(&mut self, value: &[u8]) {
+        self.usize(value.len());
+        self.data.extend_from_slice(value);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    name: &'static str,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Result<StateReader<'a>, String> {
+        StateReader::with_format(data, &SAVESTATE)
+    }
+
+    pub fn with_format(data: &'a [u8], format: &Format) -> Result<StateReader<'a>, String> {
+        if !is_format(data, format) {
+            return Err(format!("{}: not a {}", format.name, format.name));
+        }
+        if data[4] != format.version {
+            return Err(format!("{}: version {} is not supported", format.name, data[4]));
+        }
+        Ok(StateReader { data, pos: 5, name: format.name })
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self.pos.checked_add(len).filter(|&end| end <= self.data.len());
+        match end {
+            Some(end) => {
+                let slice = &self.data[self.pos..end];
+                self.pos = end;
+                Ok(slice)
+            }
+            None => Err(format!("{}: unexpected end of data", self.name)),
+        }
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, String> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            value => Err(format!("{}: {} is not a bool", self.name, value)),
+        }
+    }
+
+    pub fn u16(&mut self) -> Result<u16, String> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn usize(&mut self) -> Result<usize, String> {
+        let value = self.u64()?;
+        usize::try_from(value).map_err(|_| format!("{}: {} is too large for this platform", self.name, value))
+    }
+
+    // fills `into`, which has to be exactly as long as the saved block
+    pub fn bytes_into(&mut self, into: &mut [u8]) -> Result<(), String> {
+        let len = self.usize()?;
+        if len != into.len() {
+            return Err(format!("{}: expected a block of {} bytes, found {}", self.name, into.len(), len));
+        }
+        into.copy_from_slice(self.take(len)?);
+        Ok(())
+    }
+
+    // a block of whatever length was saved
+    pub fn bytes(&mut self) -> Result<&'a [u8], String> {
+        let len = self.usize()?;
+        self.take(len)
+    }
+
+    pub fn finish(self) -> Result<(), String> {
+        if self.pos != self.data.len() {
+            return Err(format!("{}: trailing data", self.name));
+        }
+        Ok(())
+    }
+}
+
+// whether `data` starts with the format's magic, whatever its version
+pub fn is_format(data: &[u8], format: &Format) -> bool {
+    data.len() >= 5 && data[0..4] == format.magic
+}
+
+pub fn save<T: Savestate + ?Sized>(component: &T) -> Vec<u8> {
+    let mut state = StateWriter::new();
+    component.save_state(&mut state);
+    state.finish()
+}
+
+pub fn load<T: Savestate + ?Sized>(component: &mut T, data: &[u8]) -> Result<(), String> {
+    let mut state = StateReader::new(data)?;
+    component.load_state(&mut state)?;
+    state.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reader_checks_header_and_length() {
+        let mut state = StateWriter::new();
+        state.u16(0x1234);
+        state.bytes(&[1, 2, 3]);
+        let data = state.finish();
+
+        let mut reader = StateReader::new(&data).unwrap();
+        assert_eq!(reader.u16().unwrap(), 0x1234);
+        let mut block = [0; 2];
+        assert!(reader.bytes_into(&mut block).is_err());
+
+        assert!(StateReader::new(b"NOPE\x01").is_err());
+        let mut reader = StateReader::new(&data[..7]).unwrap();
+        assert!(reader.u16().is_ok());
+        assert!(reader.u8().is_err());
+    }
+
+    #[test]
+    fn test_layout_is_fixed() {
+        const TEST: Format = Format { magic: *b"TEST", version: 2, name: "test file" };
+        let mut state = StateWriter::with_format(&TEST);
+        state.bool(true);
+        state.u16(0x0102);
+        state.u32(0x03040506);
+        state.usize(7);
+        state.bytes(&[8]);
+        let data = state.finish();
+        let mut expected = b"TEST\x02\x01\x02\x01\x06\x05\x04\x03".to_vec();
+        expected.extend_from_slice(&[7, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 8]);
+        assert_eq!(data, expected);
+
+        assert!(is_format(&data, &TEST));
+        assert_eq!(StateReader::new(&data).err().unwrap(), "savestate: not a savestate");
+        let mut reader = StateReader::with_format(&data, &TEST).unwrap();
+        assert_eq!((reader.bool(), reader.u16(), reader.u32()), (Ok(true), Ok(0x0102), Ok(0x03040506)));
+        assert_eq!((reader.usize(), reader.bytes()), (Ok(7), Ok(&[8][..])));
+        assert!(reader.finish().is_ok());
+
+        let mut newer = data.clone();
+        newer[4] = 3;
+        assert_eq!(StateReader::with_format(&newer, &TEST).err().unwrap(), "test file: version 3 is not supported");
+        let mut bad_bool = data.clone();
+        bad_bool[5] = 2;
+        assert!(StateReader::with_format(&bad_bool, &TEST).unwrap().bool().is_err());
+    }
+}
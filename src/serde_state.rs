@@ -0,0 +1,245 @@
+// Serde support for the CPU registers, the PPU and the joypads, behind the
+// `serde` feature.
+//
+// Each is carried as a plain struct of its fields, so any serde format
+// (bincode, MessagePack, JSON) shows them by name:
+//
+//   let json = serde_json::to_string(&PpuState::from(&ppu))?;
+//   serde_json::from_str::<PpuState>(&json)?.apply(&mut ppu);
+//
+// A PPU can't be built from its state alone, it needs the ROM, so the state
+// is applied to an existing one. A joypad needs nothing else and serializes
+// and deserializes directly.
+//
+// The whole machine (the bus, the APU, the mappers and input devices) is
+// still exported as a savestate, see savestate.rs.
+
+use crate::cpu::{CpuFlags, CPU};
+use crate::joypad::Joypad;
+use serde::de::{self, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::ser::{SerializeStruct, Serializer};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// Serialize and Deserialize for a struct of plain fields, what
+// #[derive(Serialize, Deserialize)] would write without the proc macro
+macro_rules! serde_struct {
+    ($name:ident { $($field:ident: $ty:ty,)* }) => {
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                let mut state = serializer.serialize_struct(stringify!($name), [$(stringify!($field)),*].len())?;
+                $(state.serialize_field(stringify!($field), &self.$field)?;)*
+                state.end()
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<$name, D::Error> {
+                const FIELDS: &[&str] = &[$(stringify!($field)),*];
+
+                struct FieldsVisitor;
+
+                impl<'de> Visitor<'de> for FieldsVisitor {
+                    type Value = $name;
+
+                    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                        write!(f, "struct {}", stringify!($name))
+                    }
+
+                    // binary formats write the fields in order without names
+                    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<$name, A::Error> {
+                        let mut index = 0;
+                        $(
+                            let $field: $ty = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(index, &self))?;
+                            index += 1;
+                        )*
+                        let _ = index;
+                        Ok($name { $($field),* })
+                    }
+
+                    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<$name, A::Error> {
+                        $(let mut $field: Option<$ty> = None;)*
+                        while let Some(key) = map.next_key::<String>()? {
+                            match key.as_str() {
+                                $(stringify!($field) => {
+                                    if $field.is_some() {
+                                        return Err(de::Error::duplicate_field(stringify!($field)));
+                                    }
+                                    $field = Some(map.next_value()?);
+                                })*
+                                other => return Err(de::Error::unknown_field(other, FIELDS)),
+                            }
+                        }
+                        $(let $field = $field.ok_or_else(|| de::Error::missing_field(stringify!($field)))?;)*
+                        Ok($name { $($field),* })
+                    }
+                }
+
+                deserializer.deserialize_struct(stringify!($name), FIELDS, FieldsVisitor)
+            }
+        }
+    };
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CpuRegisters {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub p: u8,
+    pub pc: u16,
+    pub sp: u8,
+}
+
+serde_struct!(CpuRegisters {
+    a: u8,
+    x: u8,
+    y: u8,
+    p: u8,
+    pc: u16,
+    sp: u8,
+});
+
+impl From<&CPU<'_>> for CpuRegisters {
+    fn from(cpu: &CPU) -> CpuRegisters {
+        CpuRegisters {
+            a: cpu.register_a,
+            x: cpu.register_x,
+            y: cpu.register_y,
+            p: cpu.register_p.bits(),
+            pc: cpu.program_counter,
+            sp: cpu.stack_pointer,
+        }
+    }
+}
+
+impl CpuRegisters {
+    pub fn apply(&self, cpu: &mut CPU) {
+        cpu.register_a = self.a;
+        cpu.register_x = self.x;
+        cpu.register_y = self.y;
+        cpu.register_p = CpuFlags::from_bits_truncate(self.p);
+        cpu.program_counter = self.pc;
+        cpu.stack_pointer = self.sp;
+    }
+}
+
+// everything the PPU keeps between frames, as in its savestate; built and
+// applied in ppu/mod.rs, where the private fields are
+#[derive(Debug, Clone, PartialEq)]
+pub struct PpuState {
+    pub ctrl: u8,
+    pub mask: u8,
+    pub status: u8,
+    // the loopy v, t, x and w
+    pub v: u16,
+    pub t: u16,
+    pub fine_x: u8,
+    pub write_latch: bool,
+    pub vram: Vec<u8>,
+    pub oam_addr: u8,
+    pub oam: Vec<u8>,
+    pub palette: Vec<u8>,
+    pub read_buffer: u8,
+    pub scanline: u16,
+    pub dot: usize,
+    pub odd_frame: bool,
+    pub suppress_vblank: bool,
+    pub nmi: Option<u8>,
+    pub overclocked_lines: u16,
+    pub sprite_eval_start: u8,
+    pub sprite_rotation: u8,
+}
+
+serde_struct!(PpuState {
+    ctrl: u8,
+    mask: u8,
+    status: u8,
+    v: u16,
+    t: u16,
+    fine_x: u8,
+    write_latch: bool,
+    vram: Vec<u8>,
+    oam_addr: u8,
+    oam: Vec<u8>,
+    palette: Vec<u8>,
+    read_buffer: u8,
+    scanline: u16,
+    dot: usize,
+    odd_frame: bool,
+    suppress_vblank: bool,
+    nmi: Option<u8>,
+    overclocked_lines: u16,
+    sprite_eval_start: u8,
+    sprite_rotation: u8,
+});
+
+// built and applied in joypad.rs
+#[derive(Debug, Clone, PartialEq)]
+pub struct JoypadState {
+    pub strobe: bool,
+    pub button_index: u8,
+    pub buttons: u8,
+}
+
+serde_struct!(JoypadState {
+    strobe: bool,
+    button_index: u8,
+    buttons: u8,
+});
+
+impl Serialize for Joypad {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        JoypadState::from(self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Joypad {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Joypad, D::Error> {
+        let mut joypad = Joypad::new();
+        JoypadState::deserialize(deserializer)?.apply(&mut joypad);
+        Ok(joypad)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test::test_rom;
+    use crate::joypad::JoypadButton;
+    use crate::ppu::{NesPPU, PPU};
+    use crate::savestate;
+
+    #[test]
+    fn test_json_round_trip() {
+        let mut cpu = CPU::new(Bus::new(test_rom(), |_, _, _| {}));
+        cpu.reset();
+        cpu.register_x = 7;
+        let json = serde_json::to_string(&CpuRegisters::from(&cpu)).unwrap();
+        assert!(json.contains("\"x\":7"));
+        cpu.register_x = 0;
+        serde_json::from_str::<CpuRegisters>(&json).unwrap().apply(&mut cpu);
+        assert_eq!(cpu.register_x, 7);
+
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_ctrl(0x80);
+        ppu.write_to_ppu_addr(0x21);
+        ppu.write_to_ppu_addr(0x08);
+        ppu.write_to_data(0x42);
+        ppu.oam_data[5] = 0x33;
+        let json = serde_json::to_string(&PpuState::from(&ppu)).unwrap();
+        let mut loaded = NesPPU::new_empty_rom();
+        serde_json::from_str::<PpuState>(&json).unwrap().apply(&mut loaded);
+        assert_eq!(savestate::save(&loaded), savestate::save(&ppu));
+
+        // a field missing or of the wrong kind is an error
+        assert!(serde_json::from_str::<CpuRegisters>(r#"{"a":1}"#).is_err());
+        assert!(serde_json::from_str::<CpuRegisters>(r#"{"a":1,"x":2,"y":3,"p":4,"pc":-1,"sp":5}"#).is_err());
+
+        let mut joypad = Joypad::new();
+        joypad.set_button_pressed_status(JoypadButton::START, true);
+        let loaded: Joypad = serde_json::from_str(&serde_json::to_string(&joypad).unwrap()).unwrap();
+        assert_eq!(savestate::save(&loaded), savestate::save(&joypad));
+    }
+}
@@ -0,0 +1,208 @@
+// Headless emulation stepped one frame at a time, with the input for each
+// frame passed in explicitly and the whole machine saved and restored as byte
+// buffers. This is the surface for driving the emulator from other programs
+// (bots, search, training loops); ffi.rs exposes it to C and Python.
+//
+// Determinism: the same ROM, the same starting state and the same inputs
+// always produce the same frames and the same states, on any host. Nothing in
+// the core reads threads or a random source: RAM powers up to the bus's
+// ram_pattern, audio is only generated, never paced, and frame timing is
+// counted in CPU cycles. The one clock read is the Bandai boards' homebrew RTC,
+// which starts from the host's time when the cartridge is created; it is part
+// of the state, so replays from a saved state still match. Loading a state taken from save_state() puts the
+// machine back exactly, so every branch replayed from it matches.
+
+use crate::bus::Bus;
+use crate::cartridge::{self, Rom};
+use crate::cpu::{StepResult, CPU};
+use crate::joypad::JoypadButton;
+use crate::movie::Movie;
+use crate::ppu::NesPPU;
+use crate::render;
+use crate::render::frame::Frame;
+use crate::savestate;
+use std::cell::{Ref, RefCell};
+use std::rc::Rc;
+
+pub struct Session {
+    cpu: CPU<'static>,
+    frame: Rc<RefCell<Frame>>,
+}
+
+impl Session {
+    pub fn new(rom: Rom) -> Result<Session, String> {
+        let mapper = cartridge::create_mapper(rom)?;
+        let frame = Rc::new(RefCell::new(Frame::new()));
+        let rendered = frame.clone();
+        // drawn at the vblank NMI, same as the window does
+        let bus = Bus::with_mapper(mapper, move |ppu: &mut NesPPU, _joypad1, _joypad2| {
+            render::render(ppu, &mut rendered.borrow_mut());
+        });
+        let mut cpu = CPU::new(bus);
+        cpu.reset();
+        Ok(Session { cpu, frame })
+    }
+
+    // runs until the PPU finishes the current frame with `input` held on both
+    // ports, returns the number of the frame that starts next
+    pub fn step_frame(&mut self, input: [JoypadButton; 2]) -> usize {
+        self.cpu.bus.set_buttons(input[0], input[1]);
+        let frame = self.cpu.bus.frame_count();
+        self.cpu.run_with_callback(|cpu| {
+            if cpu.bus.frame_count() != frame {
+                cpu.stop();
+            }
+        });
+        self.cpu.bus.frame_count()
+    }
+
+    // one CPU cycle with `input` held on both ports; returns the instruction
+    // when this cycle starts one, see CPU::step_cycle
+    pub fn step_cycle(&mut self, input: [JoypadButton; 2]) -> Option<StepResult> {
+        self.cpu.bus.set_buttons(input[0], input[1]);
+        self.cpu.step_cycle()
+    }
+
+    // the picture as of the last vblank
+    pub fn frame(&self) -> Ref<'_, Frame> {
+        self.frame.borrow()
+    }
+
+    pub fn peek(&self, addr: u16) -> u8 {
+        self.cpu.bus.peek(addr)
+    }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        savestate::save(&self.cpu)
+    }
+
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        savestate::load(&mut self.cpu, data)
+    }
+
+    // the reset button: RAM and the cartridge keep their contents
+    pub fn soft_reset(&mut self) {
+        self.cpu.soft_reset();
+    }
+
+    // off and on again: RAM goes back to its power-up pattern
+    pub fn power_cycle(&mut self) {
+        self.cpu.power_cycle();
+    }
+
+    // replays a movie from its start state, or from power-on when it has none,
+    // and returns the number of the frame that starts next
+    pub fn play(&mut self, movie: &Movie) -> Result<usize, String> {
+        match movie.start_state.as_ref() {
+            Some(state) => self.load_state(state)?,
+            None => self.power_cycle(),
+        }
+        let mut frame = self.cpu.bus.frame_count();
+        for input in movie.frames.iter() {
+            frame = self.step_frame(*input);
+        }
+        Ok(frame)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom_containing;
+
+    fn input_counting_rom() -> Rom {
+        let mut program = vec![
+            0xa9, 0x80, 0x8d, 0x00, 0x20, // LDA #$80; STA $2000 (NMI on)
+            0xa9, 0x01, 0x8d, 0x16, 0x40, // loop: LDA #1; STA $4016
+            0xa9, 0x00, 0x8d, 0x16, 0x40, // LDA #0; STA $4016
+            0xad, 0x16, 0x40, 0x29, 0x01, // LDA $4016; AND #1 (button A)
+            0x65, 0x10, 0x85, 0x10, // ADC $10; STA $10
+            0x4c, 0x05, 0x80, // JMP loop
+            0xe6, 0x11, 0x40, // nmi: INC $11; RTI
+        ];
+        program.resize(0x8000, 0);
+        program[0x7ffa] = 0x1b; // NMI vector -> $801B
+        program[0x7ffb] = 0x80;
+        program[0x7ffc] = 0x00; // reset vector -> $8000
+        program[0x7ffd] = 0x80;
+        test_rom_containing(program)
+    }
+
+    fn play(session: &mut Session, frames: usize) -> Vec<(u8, u8, Vec<u8>)> {
+        (0..frames)
+            .map(|i| {
+                let a = if i % 3 == 0 { JoypadButton::BUTTON_A } else { JoypadButton::empty() };
+                session.step_frame([a, JoypadButton::empty()]);
+                (session.peek(0x10), session.peek(0x11), session.save_state())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_replay_from_saved_state_is_identical() {
+        let mut session = Session::new(input_counting_rom()).unwrap();
+        assert_eq!(session.step_frame([JoypadButton::empty(); 2]), 1);
+        let idle = session.peek(0x10);
+        play(&mut session, 5);
+        assert_ne!(session.peek(0x10), idle);
+
+        let state = session.save_state();
+        let first = play(&mut session, 20);
+        session.load_state(&state).unwrap();
+        let second = play(&mut session, 20);
+        assert!(first == second);
+        assert_eq!(first[19].1.wrapping_sub(first[0].1), 19);
+
+        // a fresh session fed the same inputs lands on the same state
+        let mut fresh = Session::new(input_counting_rom()).unwrap();
+        fresh.step_frame([JoypadButton::empty(); 2]);
+        play(&mut fresh, 5);
+        assert!(fresh.save_state() == state);
+    }
+
+    #[test]
+    fn test_soft_reset_keeps_ram() {
+        let mut session = Session::new(input_counting_rom()).unwrap();
+        for _ in 0..10 {
+            session.step_frame([JoypadButton::empty(); 2]);
+        }
+        let nmis = session.peek(0x11);
+        assert!(nmis > 0);
+
+        // the program starts over, the NMI count it keeps in RAM doesn't
+        session.soft_reset();
+        session.step_frame([JoypadButton::empty(); 2]);
+        assert!(session.peek(0x11) > nmis);
+
+        session.power_cycle();
+        assert_eq!(session.peek(0x11), 0);
+    }
+
+    #[test]
+    fn test_movie_replays_the_recording() {
+        let mut session = Session::new(input_counting_rom()).unwrap();
+        play(&mut session, 3);
+        let mut movie = Movie::new(vec![]);
+        movie.start_state = Some(session.save_state());
+        for i in 0..30 {
+            let a = if i % 4 == 1 { JoypadButton::BUTTON_A } else { JoypadButton::empty() };
+            movie.frames.push([a, JoypadButton::empty()]);
+            session.step_frame([a, JoypadButton::empty()]);
+        }
+        let recorded = session.save_state();
+
+        let mut replay = Session::new(input_counting_rom()).unwrap();
+        let movie = Movie::from_bytes(&movie.to_bytes()).unwrap();
+        replay.play(&movie).unwrap();
+        assert!(replay.save_state() == recorded);
+
+        // a movie without a start state plays from power-on, wherever the
+        // session was; only the running frame count carries over
+        let from_power_on = Movie::new(vec![[JoypadButton::BUTTON_A, JoypadButton::empty()]; 10]);
+        let ram = |session: &Session| (0..0x800).map(|addr| session.peek(addr)).collect::<Vec<u8>>();
+        replay.play(&from_power_on).unwrap();
+        session.play(&from_power_on).unwrap();
+        assert!(ram(&session) == ram(&replay));
+        assert_eq!(session.peek(0x11), 10);
+    }
+}
@@ -0,0 +1,153 @@
+// Per-game settings read from a `<rom>.settings.toml` file next to the ROM:
+//
+// overclock_scanlines = 100   # extra idle scanlines of CPU time per frame, 0 - 1000
+// sprite_limit = "smart"       # "hardware" (default), "none" or "smart"
+// pause_in_background = true   # stop emulating and close audio while unfocused
+// toggle_buttons = ["a"]       # press once to hold, again to release
+// auto_hold = ["b"]            # toggle buttons that start held
+// frame_blend = "mix"          # "off" (default), "mix" or "phosphor" for flicker transparency
+// input_device = "zapper"      # "standard", "four-score", "zapper" or "paddle", used
+//                              # when the ROM's NES 2.0 header doesn't name one
+// region = "pal"               # "ntsc", "pal" or "dendy", overriding the ROM header
+
+use crate::joypad::{DeviceType, JoypadButton};
+use crate::ppu::SpriteLimit;
+use crate::region::Region;
+use crate::render::blend::FrameBlend;
+use toml::Value;
+
+pub const MAX_OVERCLOCK_SCANLINES: u16 = 1000;
+
+pub struct GameSettings {
+    pub overclock_scanlines: u16,
+    pub sprite_limit: SpriteLimit,
+    pub pause_in_background: bool,
+    pub toggle_buttons: JoypadButton,
+    pub auto_hold: JoypadButton,
+    pub frame_blend: FrameBlend,
+    pub input_device: Option<DeviceType>,
+    pub region: Option<Region>,
+}
+
+impl Default for GameSettings {
+    fn default() -> Self {
+        GameSettings::new()
+    }
+}
+
+impl GameSettings {
+    pub fn new() -> Self {
+        GameSettings {
+            overclock_scanlines: 0,
+            sprite_limit: SpriteLimit::Hardware,
+            pause_in_background: false,
+            toggle_buttons: JoypadButton::empty(),
+            auto_hold: JoypadButton::empty(),
+            frame_blend: FrameBlend::Off,
+            input_device: None,
+            region: None,
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<GameSettings, String> {
+        let root = text.parse::<Value>().map_err(|e| format!("settings: {}", e))?;
+        let mut settings = GameSettings::new();
+        if let Some(value) = root.get("overclock_scanlines") {
+            settings.overclock_scanlines = parse_overclock(&value.to_string())?;
+        }
+        if let Some(value) = root.get("sprite_limit") {
+            let value = value.as_str().ok_or("settings: sprite_limit should be a string")?;
+            settings.sprite_limit = SpriteLimit::parse(value)?;
+        }
+        if let Some(value) = root.get("pause_in_background") {
+            settings.pause_in_background = value.as_bool().ok_or("settings: pause_in_background should be true or false")?;
+        }
+        if let Some(value) = root.get("toggle_buttons") {
+            settings.toggle_buttons = parse_buttons("toggle_buttons", value)?;
+        }
+        if let Some(value) = root.get("auto_hold") {
+            settings.auto_hold = parse_buttons("auto_hold", value)?;
+        }
+        if let Some(value) = root.get("frame_blend") {
+            let value = value.as_str().ok_or("settings: frame_blend should be a string")?;
+            settings.frame_blend = FrameBlend::parse(value)?;
+        }
+        if let Some(value) = root.get("input_device") {
+            let value = value.as_str().ok_or("settings: input_device should be a string")?;
+            settings.input_device = Some(DeviceType::parse(value)?);
+        }
+        if let Some(value) = root.get("region") {
+            let value = value.as_str().ok_or("settings: region should be a string")?;
+            settings.region = Some(Region::parse(value)?);
+        }
+        Ok(settings)
+    }
+
+    // Looks for `game.settings.toml` next to `game.nes`
+    pub fn load_for_rom(rom_path: &str) -> Result<GameSettings, String> {
+        let path = std::path::Path::new(rom_path).with_extension("settings.toml");
+        match std::fs::read_to_string(&path) {
+            Ok(text) => GameSettings::parse(&text),
+            Err(_) => Ok(GameSettings::new()),
+        }
+    }
+}
+
+fn parse_buttons(key: &str, value: &Value) -> Result<JoypadButton, String> {
+    let names = value
+        .as_array()
+        .ok_or(format!("settings: {} should be a list of buttons", key))?;
+    let mut buttons = JoypadButton::empty();
+    for name in names {
+        let name = name.as_str().ok_or(format!("settings: {} should be a list of buttons", key))?;
+        buttons |= JoypadButton::parse(name).map_err(|e| format!("settings: {}: {}", key, e))?;
+    }
+    Ok(buttons)
+}
+
+pub fn parse_overclock(value: &str) -> Result<u16, String> {
+    match value.parse::<u16>() {
+        Ok(lines) if lines <= MAX_OVERCLOCK_SCANLINES => Ok(lines),
+        _ => Err(format!(
+            "overclock should be a number of scanlines between 0 and {}, got '{}'",
+            MAX_OVERCLOCK_SCANLINES, value
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_settings() {
+        assert_eq!(GameSettings::parse("").unwrap().overclock_scanlines, 0);
+        assert_eq!(GameSettings::parse("overclock_scanlines = 120").unwrap().overclock_scanlines, 120);
+        assert!(GameSettings::parse("overclock_scanlines = 5000").is_err());
+        assert!(GameSettings::parse("overclock_scanlines = \"lots\"").is_err());
+        assert_eq!(GameSettings::parse("").unwrap().sprite_limit, SpriteLimit::Hardware);
+        assert_eq!(GameSettings::parse("sprite_limit = \"none\"").unwrap().sprite_limit, SpriteLimit::NoLimit);
+        assert!(GameSettings::parse("sprite_limit = 8").is_err());
+        assert!(!GameSettings::parse("").unwrap().pause_in_background);
+        assert!(GameSettings::parse("pause_in_background = true").unwrap().pause_in_background);
+        assert!(GameSettings::parse("pause_in_background = 1").is_err());
+
+        let settings = GameSettings::parse("toggle_buttons = [\"a\", \"Start\"]\nauto_hold = [\"b\"]").unwrap();
+        assert_eq!(settings.toggle_buttons, JoypadButton::BUTTON_A | JoypadButton::START);
+        assert_eq!(settings.auto_hold, JoypadButton::BUTTON_B);
+        assert!(GameSettings::parse("auto_hold = [\"turbo\"]").is_err());
+        assert!(GameSettings::parse("auto_hold = \"b\"").is_err());
+
+        assert_eq!(GameSettings::parse("").unwrap().frame_blend, FrameBlend::Off);
+        assert_eq!(GameSettings::parse("frame_blend = \"mix\"").unwrap().frame_blend, FrameBlend::Mix);
+        assert!(GameSettings::parse("frame_blend = true").is_err());
+
+        assert_eq!(GameSettings::parse("").unwrap().input_device, None);
+        assert_eq!(GameSettings::parse("input_device = \"zapper\"").unwrap().input_device, Some(DeviceType::Zapper));
+        assert!(GameSettings::parse("input_device = \"mouse\"").is_err());
+
+        assert_eq!(GameSettings::parse("").unwrap().region, None);
+        assert_eq!(GameSettings::parse("region = \"pal\"").unwrap().region, Some(Region::Pal));
+        assert!(GameSettings::parse("region = \"secam\"").is_err());
+    }
+}
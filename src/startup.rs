@@ -0,0 +1,194 @@
+// Frontend start-up in fallible stages. A failing stage either falls back
+// (software renderer, streaming texture, no audio, no controllers) or exits
+// with a hint about the usual cause instead of a bare unwrap panic.
+//
+// A missing SDL2 library can't be reported from here: the dynamic loader
+// refuses to start the binary before main runs.
+
+use crate::audio::{self, AudioOutput, AudioRing};
+use crate::pacing::PresentMode;
+use sdl2::audio::AudioDevice;
+use sdl2::messagebox::{show_message_box, ButtonData, ClickedButton, MessageBoxButtonFlag, MessageBoxFlag};
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::render::{Canvas, Texture, TextureCreator};
+use sdl2::video::{Window, WindowContext};
+use sdl2::Sdl;
+use std::sync::{Arc, Mutex};
+
+const WINDOW_TITLE: &str = "PAC MAN";
+const MIRROR_WINDOW_TITLE: &str = "PAC MAN (mirror)";
+const WINDOW_SCALE: f32 = 3.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stage {
+    Sdl,
+    Video,
+    Window,
+    Renderer,
+    Texture,
+    Audio,
+    Input,
+}
+
+impl Stage {
+    fn describe(self) -> &'static str {
+        match self {
+            Stage::Sdl => "SDL initialisation",
+            Stage::Video => "video initialisation",
+            Stage::Window => "opening the window",
+            Stage::Renderer => "creating the renderer",
+            Stage::Texture => "creating the screen texture",
+            Stage::Audio => "opening the audio device",
+            Stage::Input => "opening the input devices",
+        }
+    }
+}
+
+// the error plus a hint for the common causes of each stage failing
+pub fn diagnose(stage: Stage, err: &str) -> String {
+    let lower = err.to_lowercase();
+    let hint = match stage {
+        Stage::Sdl | Stage::Video | Stage::Window if lower.contains("display") || lower.contains("video device") => {
+            "no display found: run from a desktop session, or check DISPLAY / WAYLAND_DISPLAY"
+        }
+        Stage::Sdl | Stage::Video | Stage::Window => {
+            "check that the SDL2 runtime is installed and up to date \
+             (libsdl2-2.0-0 on Debian/Ubuntu, sdl2 from Homebrew, SDL2.dll next to the executable on Windows)"
+        }
+        Stage::Renderer => "the graphics driver has no accelerated renderer, SDL_RENDER_DRIVER=software forces the fallback",
+        Stage::Texture if lower.contains("format") => {
+            "the renderer doesn't support RGB24 textures, try SDL_RENDER_DRIVER=software"
+        }
+        Stage::Texture => "the renderer may be out of video memory or limited in texture size",
+        Stage::Audio => "no usable audio device, SDL_AUDIODRIVER=dummy silences this warning",
+        Stage::Input => "game controllers are unavailable, the keyboard still works",
+    };
+    format!("{} failed: {}\n  hint: {}", stage.describe(), err, hint)
+}
+
+pub fn init_sdl() -> Result<Sdl, String> {
+    sdl2::init().map_err(|err| diagnose(Stage::Sdl, &err))
+}
+
+fn create_window(sdl: &Sdl) -> Result<Window, String> {
+    let video = sdl.video().map_err(|err| diagnose(Stage::Video, &err))?;
+    video
+        .window(WINDOW_TITLE, (256.0 * WINDOW_SCALE) as u32, (240.0 * WINDOW_SCALE) as u32)
+        .position_centered()
+        .build()
+        .map_err(|err| diagnose(Stage::Window, &err.to_string()))
+}
+
+// Tries the accelerated renderer first. into_canvas() consumes the window, so
+// the software fallback opens a fresh one.
+pub fn create_canvas(sdl: &Sdl, present_mode: PresentMode) -> Result<Canvas<Window>, String> {
+    let builder = create_window(sdl)?.into_canvas().accelerated();
+    let accelerated = match present_mode {
+        PresentMode::Vsync => builder.present_vsync().build(),
+        PresentMode::Adaptive => builder.build(),
+    };
+    let mut canvas = match accelerated {
+        Ok(canvas) => canvas,
+        Err(err) => {
+            println!("warning: {}", diagnose(Stage::Renderer, &err.to_string()));
+            println!("falling back to the software renderer");
+            create_window(sdl)?
+                .into_canvas()
+                .software()
+                .build()
+                .map_err(|err| diagnose(Stage::Renderer, &err.to_string()))?
+        }
+    };
+    canvas
+        .set_scale(WINDOW_SCALE, WINDOW_SCALE)
+        .map_err(|err| diagnose(Stage::Renderer, &err))?;
+    Ok(canvas)
+}
+
+// A borderless second window for capture software. No vsync: the main window
+// already paces presentation and waiting on two swaps would halve the rate.
+pub fn create_mirror_canvas(sdl: &Sdl) -> Result<Canvas<Window>, String> {
+    let video = sdl.video().map_err(|err| diagnose(Stage::Video, &err))?;
+    let window = video
+        .window(MIRROR_WINDOW_TITLE, (256.0 * WINDOW_SCALE) as u32, (240.0 * WINDOW_SCALE) as u32)
+        .borderless()
+        .build()
+        .map_err(|err| diagnose(Stage::Window, &err.to_string()))?;
+    window
+        .into_canvas()
+        .build()
+        .map_err(|err| diagnose(Stage::Renderer, &err.to_string()))
+}
+
+// Render-target textures aren't supported everywhere; a streaming texture
+// takes the same update() calls.
+pub fn create_texture(creator: &TextureCreator<WindowContext>, width: u32, height: u32) -> Result<Texture<'_>, String> {
+    creator
+        .create_texture_target(PixelFormatEnum::RGB24, width, height)
+        .or_else(|_| creator.create_texture_streaming(PixelFormatEnum::RGB24, width, height))
+        .map_err(|err| diagnose(Stage::Texture, &err.to_string()))
+}
+
+// None plays no sound; emulation runs the same either way
+pub fn open_audio(sdl: &Sdl, ring: &Arc<Mutex<AudioRing>>) -> Option<AudioDevice<AudioOutput>> {
+    let spec = sdl2::audio::AudioSpecDesired {
+        freq: Some(audio::SAMPLE_RATE),
+        channels: Some(1),
+        samples: Some(1024),
+    };
+    let device = sdl
+        .audio()
+        .and_then(|audio| audio.open_playback(None, &spec, |_| AudioOutput { ring: ring.clone() }));
+    match device {
+        Ok(device) => {
+            device.resume();
+            Some(device)
+        }
+        Err(err) => {
+            println!("warning: {}", diagnose(Stage::Audio, &err));
+            None
+        }
+    }
+}
+
+// after a stall: true to reset the console, false to keep waiting. Without a
+// usable message box the console is left alone.
+pub fn offer_reset(message: &str) -> bool {
+    let buttons = [
+        ButtonData {
+            flags: MessageBoxButtonFlag::RETURNKEY_DEFAULT,
+            button_id: 0,
+            text: "Reset",
+        },
+        ButtonData {
+            flags: MessageBoxButtonFlag::ESCAPEKEY_DEFAULT,
+            button_id: 1,
+            text: "Keep waiting",
+        },
+    ];
+    match show_message_box(MessageBoxFlag::WARNING, &buttons, "Emulation stalled", message, None, None) {
+        Ok(ClickedButton::CustomButton(button)) => button.button_id == 0,
+        Ok(ClickedButton::CloseButton) => false,
+        Err(err) => {
+            println!("warning: can't show the reset prompt: {}", err);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_diagnose_picks_hint_from_error() {
+        let no_display = diagnose(Stage::Video, "No available video device");
+        assert!(no_display.starts_with("video initialisation failed: No available video device"));
+        assert!(no_display.contains("no display found"));
+        assert!(diagnose(Stage::Sdl, "SDL_Init failed").contains("SDL2 runtime"));
+
+        assert!(diagnose(Stage::Texture, "Unsupported texture format").contains("RGB24"));
+        assert!(diagnose(Stage::Texture, "Out of memory").contains("video memory"));
+        assert!(diagnose(Stage::Audio, "Audio subsystem is not initialized").contains("SDL_AUDIODRIVER"));
+    }
+}
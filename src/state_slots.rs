@@ -0,0 +1,93 @@
+// Numbered savestate slots, stored next to the ROM as <rom>.ss0 to <rom>.ss9
+// in the savestate format (savestate.rs). The frontend saves to and loads
+// from the selected slot.
+
+use crate::savestate::{self, Savestate};
+use std::path::PathBuf;
+
+pub const SLOTS: usize = 10;
+
+pub struct StateSlots {
+    rom_path: PathBuf,
+    pub selected: usize,
+}
+
+impl StateSlots {
+    pub fn for_rom(rom_path: &str) -> Self {
+        StateSlots {
+            rom_path: PathBuf::from(rom_path),
+            selected: 0,
+        }
+    }
+
+    pub fn path(&self, slot: usize) -> PathBuf {
+        self.rom_path.with_extension(format!("ss{}", slot))
+    }
+
+    // 0 follows 9
+    pub fn select_next(&mut self) -> usize {
+        self.selected = (self.selected + 1) % SLOTS;
+        self.selected
+    }
+
+    pub fn save<T: Savestate>(&self, component: &T) -> Result<PathBuf, String> {
+        let path = self.path(self.selected);
+        std::fs::write(&path, savestate::save(component))
+            .map_err(|err| format!("failed to write {}: {}", path.display(), err))?;
+        Ok(path)
+    }
+
+    // A state that fails to load part way leaves the machine as it was before.
+    pub fn load<T: Savestate>(&self, component: &mut T) -> Result<PathBuf, String> {
+        let path = self.path(self.selected);
+        if !path.exists() {
+            return Err(format!("slot {} is empty", self.selected));
+        }
+        let data = std::fs::read(&path).map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+        let before = savestate::save(component);
+        if let Err(err) = savestate::load(component, &data) {
+            savestate::load(component, &before).unwrap();
+            return Err(format!("{}: {}", path.display(), err));
+        }
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test::test_rom;
+    use crate::cpu::{Mem, CPU};
+
+    #[test]
+    fn test_slots_round_trip() {
+        let dir = std::env::temp_dir().join(format!("nes-slots-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let rom_path = dir.join("zelda.nes");
+        let mut slots = StateSlots::for_rom(rom_path.to_str().unwrap());
+        let mut cpu = CPU::new(Bus::new(test_rom(), |_, _, _| {}));
+        cpu.reset();
+
+        for _ in 0..9 {
+            slots.select_next();
+        }
+        assert_eq!(slots.path(slots.selected), dir.join("zelda.ss9"));
+        assert_eq!(slots.select_next(), 0);
+        assert_eq!(slots.load(&mut cpu), Err("slot 0 is empty".to_string()));
+
+        cpu.mem_write(0x0010, 0x42);
+        assert_eq!(slots.save(&cpu), Ok(dir.join("zelda.ss0")));
+        cpu.mem_write(0x0010, 0x00);
+        slots.load(&mut cpu).unwrap();
+        assert_eq!(cpu.mem_read(0x0010), 0x42);
+
+        // a truncated file doesn't leave the machine half loaded
+        let data = std::fs::read(slots.path(0)).unwrap();
+        std::fs::write(slots.path(0), &data[..data.len() / 2]).unwrap();
+        cpu.mem_write(0x0010, 0x07);
+        assert!(slots.load(&mut cpu).is_err());
+        assert_eq!(cpu.mem_read(0x0010), 0x07);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
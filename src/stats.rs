@@ -0,0 +1,160 @@
+// Per-frame wall clock breakdown between the emulated components.
+//
+// Only the PPU, APU, mapper and frontend are timed directly; CPU time is what
+// is left of the frame. Timing every cartridge access isn't free, so nothing
+// is measured until the profiler is enabled.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Component {
+    Ppu,
+    // the channels and the mixer, expansion audio included
+    Apu,
+    Mapper,
+    // rendering, presenting and input handling in the gameloop callback
+    Frontend,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct FrameStats {
+    pub cpu: Duration,
+    pub ppu: Duration,
+    pub apu: Duration,
+    pub mapper: Duration,
+    pub frontend: Duration,
+}
+
+impl FrameStats {
+    pub fn total(&self) -> Duration {
+        self.cpu + self.ppu + self.apu + self.mapper + self.frontend
+    }
+
+    pub fn accumulate(&mut self, other: &FrameStats) {
+        self.cpu += other.cpu;
+        self.ppu += other.ppu;
+        self.apu += other.apu;
+        self.mapper += other.mapper;
+        self.frontend += other.frontend;
+    }
+
+    pub fn average(&self, frames: u32) -> FrameStats {
+        FrameStats {
+            cpu: self.cpu / frames,
+            ppu: self.ppu / frames,
+            apu: self.apu / frames,
+            mapper: self.mapper / frames,
+            frontend: self.frontend / frames,
+        }
+    }
+
+    fn component_mut(&mut self, component: Component) -> &mut Duration {
+        match component {
+            Component::Ppu => &mut self.ppu,
+            Component::Apu => &mut self.apu,
+            Component::Mapper => &mut self.mapper,
+            Component::Frontend => &mut self.frontend,
+        }
+    }
+}
+
+impl fmt::Display for FrameStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        write!(
+            f,
+            "cpu {:.2}ms  ppu {:.2}ms  apu {:.2}ms  mapper {:.2}ms  frontend {:.2}ms  total {:.2}ms",
+            ms(self.cpu),
+            ms(self.ppu),
+            ms(self.apu),
+            ms(self.mapper),
+            ms(self.frontend),
+            ms(self.total())
+        )
+    }
+}
+
+pub struct Profiler {
+    pub enabled: bool,
+    frame_start: Option<Instant>,
+    current: FrameStats,
+    last_frame: Option<FrameStats>,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Profiler::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler {
+            enabled: false,
+            frame_start: None,
+            current: FrameStats::default(),
+            last_frame: None,
+        }
+    }
+
+    // pair with add(): let started = profiler.start(); ...; profiler.add(Component::Ppu, started)
+    pub fn start(&mut self) -> Option<Instant> {
+        if !self.enabled {
+            return None;
+        }
+        let now = Instant::now();
+        self.frame_start.get_or_insert(now);
+        Some(now)
+    }
+
+    pub fn add(&mut self, component: Component, started: Option<Instant>) {
+        if let Some(started) = started {
+            *self.current.component_mut(component) += started.elapsed();
+        }
+    }
+
+    pub fn end_frame(&mut self) {
+        let frame_start = match self.frame_start.take() {
+            Some(frame_start) => frame_start,
+            None => return,
+        };
+        let mut stats = std::mem::take(&mut self.current);
+        let measured = stats.ppu + stats.apu + stats.mapper + stats.frontend;
+        stats.cpu = frame_start.elapsed().saturating_sub(measured);
+        self.last_frame = Some(stats);
+        self.frame_start = Some(Instant::now());
+    }
+
+    pub fn last_frame(&self) -> Option<FrameStats> {
+        self.last_frame
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_profiler_breakdown() {
+        let mut profiler = Profiler::new();
+        assert!(profiler.start().is_none());
+        profiler.end_frame();
+        assert!(profiler.last_frame().is_none());
+
+        profiler.enabled = true;
+        let started = profiler.start();
+        std::thread::sleep(Duration::from_millis(2));
+        profiler.add(Component::Ppu, started);
+        let started = profiler.start();
+        profiler.add(Component::Mapper, started);
+        std::thread::sleep(Duration::from_millis(1));
+        profiler.end_frame();
+
+        let stats = profiler.last_frame().unwrap();
+        assert!(stats.ppu >= Duration::from_millis(2));
+        assert!(stats.cpu >= Duration::from_millis(1));
+        assert_eq!(stats.apu, Duration::from_millis(0));
+        assert!(stats.total() >= Duration::from_millis(3));
+    }
+}
@@ -0,0 +1,173 @@
+// CPU-side activity over one frame: interrupt assertions, DMA windows and
+// controller strobes, with where the PPU was at each. Meant for "music
+// stutters" (IRQ timing, DMC fetches) and "controls drop inputs" (reads
+// colliding with DMC DMA) kinds of bugs.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IrqSource {
+    Mapper,
+    FrameCounter,
+    Dmc,
+}
+
+pub const IRQ_SOURCES: [IrqSource; 3] = [IrqSource::Mapper, IrqSource::FrameCounter, IrqSource::Dmc];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimelineEvent {
+    Nmi,
+    Irq(IrqSource),
+    OamDma { page: u8 },
+    DmcDma { addr: u16 },
+    ControllerStrobe { value: u8 },
+}
+
+impl TimelineEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            TimelineEvent::Nmi => "nmi",
+            TimelineEvent::Irq(_) => "irq",
+            TimelineEvent::OamDma { .. } => "oam_dma",
+            TimelineEvent::DmcDma { .. } => "dmc_dma",
+            TimelineEvent::ControllerStrobe { .. } => "strobe",
+        }
+    }
+
+    fn detail(&self) -> String {
+        match self {
+            TimelineEvent::Nmi => String::new(),
+            TimelineEvent::Irq(source) => format!("{:?}", source),
+            TimelineEvent::OamDma { page } => format!("${:02X}00", page),
+            TimelineEvent::DmcDma { addr } => format!("${:04X}", addr),
+            TimelineEvent::ControllerStrobe { value } => format!("{}", value),
+        }
+    }
+}
+
+pub struct TimelineEntry {
+    // CPU cycles since the frame started
+    pub cycle: usize,
+    pub scanline: u16,
+    pub dot: usize,
+    // CPU cycles the CPU was halted for, 0 for interrupts and strobes
+    pub duration: usize,
+    pub event: TimelineEvent,
+}
+
+pub struct Timeline {
+    pub frame: usize,
+    start_cycle: usize,
+    pub entries: Vec<TimelineEntry>,
+}
+
+impl Timeline {
+    pub fn new(frame: usize, start_cycle: usize) -> Self {
+        Timeline {
+            frame,
+            start_cycle,
+            entries: vec![],
+        }
+    }
+
+    pub fn record(&mut self, cycle: usize, (scanline, dot): (u16, usize), duration: usize, event: TimelineEvent) {
+        self.entries.push(TimelineEntry {
+            cycle: cycle - self.start_cycle,
+            scanline,
+            dot,
+            duration,
+            event,
+        });
+    }
+
+    pub fn to_json(&self) -> String {
+        let entries = self
+            .entries
+            .iter()
+            .map(|e| {
+                format!(
+                    "{{\"cycle\":{},\"scanline\":{},\"dot\":{},\"duration\":{},\"event\":\"{}\",\"detail\":\"{}\"}}",
+                    e.cycle,
+                    e.scanline,
+                    e.dot,
+                    e.duration,
+                    e.event.name(),
+                    e.event.detail()
+                )
+            })
+            .collect::<Vec<String>>()
+            .join(",\n    ");
+        format!("{{\n  \"frame\": {},\n  \"events\": [\n    {}\n  ]\n}}\n", self.frame, entries)
+    }
+
+    pub fn to_html(&self) -> String {
+        let mut html = format!("<html><head><title>Timeline of frame {}</title></head><body>\n", self.frame);
+        html.push_str("<table border=\"1\">\n");
+        html.push_str("<tr><th>cycle</th><th>scanline</th><th>dot</th><th>event</th><th>detail</th><th>cycles</th></tr>\n");
+        for e in self.entries.iter() {
+            html.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+                e.cycle,
+                e.scanline,
+                e.dot,
+                e.event.name(),
+                e.event.detail(),
+                e.duration
+            ));
+        }
+        html.push_str("</table>\n</body></html>\n");
+        html
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test::test_rom;
+    use crate::cpu::Mem;
+
+    #[test]
+    fn test_records_one_frame_of_activity() {
+        let mut bus = Bus::new(test_rom(), |_ppu, _joypad, _joypad2| {});
+        bus.mem_write(0x2000, 0x80);
+        // restart the frame counter so its IRQ lands inside the recorded frame
+        while bus.cycles() < 10_000 {
+            bus.tick(85);
+        }
+        bus.mem_write(0x4017, 0);
+        bus.request_timeline();
+        // recording starts with the next frame
+        while bus.frame_count() == 0 {
+            bus.tick(85);
+        }
+        bus.mem_write(0x4016, 1);
+        bus.mem_write(0x4016, 0);
+        bus.mem_write(0x4014, 0x02);
+        let timeline = loop {
+            bus.tick(85);
+            if let Some(timeline) = bus.take_timeline() {
+                break timeline;
+            }
+        };
+
+        assert_eq!(timeline.frame, 1);
+        let events: Vec<TimelineEvent> = timeline.entries.iter().map(|e| e.event).collect();
+        assert_eq!(
+            events,
+            vec![
+                TimelineEvent::ControllerStrobe { value: 1 },
+                TimelineEvent::ControllerStrobe { value: 0 },
+                TimelineEvent::OamDma { page: 2 },
+                TimelineEvent::Irq(IrqSource::FrameCounter),
+                TimelineEvent::Nmi,
+            ]
+        );
+        let dma = &timeline.entries[2];
+        assert!(dma.duration == 513 || dma.duration == 514);
+        assert_eq!(timeline.entries[4].scanline, 241);
+
+        let json = timeline.to_json();
+        assert!(json.contains("\"event\":\"oam_dma\",\"detail\":\"$0200\""));
+        assert!(json.contains("\"event\":\"irq\",\"detail\":\"FrameCounter\""));
+        assert!(timeline.to_html().contains("<td>nmi</td>"));
+    }
+}
@@ -1,14 +1,173 @@
+use crate::bus::Bus;
 use crate::cpu::AddressingMode;
 use crate::cpu::Mem;
 use crate::cpu::CPU;
 use crate::opcodes;
-use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+const PRG_BANK_SIZE: usize = 0x4000;
+
+// Decides which instructions end up in the trace log, so a capture can be
+// limited to the routine under investigation instead of the whole run.
+pub struct TraceFilter {
+    pub range: Option<(u16, u16)>,
+    pub bank: Option<u8>,
+    pub start_at: Option<u16>,
+    pub stop_at: Option<u16>,
+    active: bool,
+}
+
+impl Default for TraceFilter {
+    fn default() -> Self {
+        TraceFilter::new()
+    }
+}
+
+impl TraceFilter {
+    pub fn new() -> Self {
+        TraceFilter {
+            range: None,
+            bank: None,
+            start_at: None,
+            stop_at: None,
+            active: true,
+        }
+    }
+
+    pub fn set_start_at(&mut self, addr: u16) {
+        self.start_at = Some(addr);
+        self.active = false;
+    }
+
+    pub fn set_stop_at(&mut self, addr: u16) {
+        self.stop_at = Some(addr);
+    }
+
+    // "C000-FFFF" -> (0xc000, 0xffff)
+    pub fn parse_range(value: &str) -> Result<(u16, u16), String> {
+        let mut parts = value.splitn(2, '-');
+        let from = parse_addr(parts.next().unwrap_or(""))?;
+        let to = match parts.next() {
+            Some(to) => parse_addr(to)?,
+            None => return Err(format!("trace range '{}' should look like C000-FFFF", value)),
+        };
+        if from > to {
+            return Err(format!("trace range '{}' starts after it ends", value));
+        }
+        Ok((from, to))
+    }
+
+    // 16kb bank of PRG ROM the mapper has at the address, None outside of
+    // PRG ROM
+    pub fn bank_of(bus: &Bus, pc: u16) -> Option<u8> {
+        bus.prg_offset(pc).map(|offset| (offset / PRG_BANK_SIZE) as u8)
+    }
+
+    pub fn should_trace(&mut self, bus: &Bus, pc: u16) -> bool {
+        if !self.active && self.start_at == Some(pc) {
+            self.active = true;
+        }
+        if !self.active {
+            return false;
+        }
+        if self.stop_at == Some(pc) {
+            self.active = false;
+        }
+
+        if let Some((from, to)) = self.range {
+            if pc < from || pc > to {
+                return false;
+            }
+        }
+        if let Some(bank) = self.bank {
+            if TraceFilter::bank_of(bus, pc) != Some(bank) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+pub fn parse_addr(value: &str) -> Result<u16, String> {
+    let value = value.trim().trim_start_matches('$').trim_start_matches("0x");
+    u16::from_str_radix(value, 16).map_err(|_| format!("'{}' is not a hex address", value))
+}
 
 pub fn trace(cpu: &mut CPU) -> String {
-    let ref opscodes: HashMap<u8, &'static opcodes::OpCode> = *opcodes::OPCODES_MAP;
+    // the disassembly reads memory, watchpoints are for the program's reads
+    let muted = cpu.bus.breakpoints.mute(true);
+    let line = disassemble(cpu);
+    cpu.bus.breakpoints.mute(muted);
+    line
+}
+
+// trace() with the PPU position and CPU cycle count, laid out like
+// nestest.log so the two can be diffed:
+//   C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+pub fn trace_nestest(cpu: &mut CPU) -> String {
+    let line = trace(cpu);
+    let (scanline, dot) = cpu.bus.ppu().position();
+    format!("{} PPU:{:>3},{:>3} CYC:{}", line, scanline, dot, cpu.bus.cycles())
+}
+
+// Streams trace_nestest() lines to a file. Logging can be switched on and off
+// while running; the file is created the first time it's switched on and the
+// lines keep being appended to it after that.
+pub struct TraceLog {
+    path: String,
+    out: Option<BufWriter<File>>,
+    enabled: bool,
+}
+
+impl TraceLog {
+    pub fn new(path: &str) -> Self {
+        TraceLog {
+            path: path.to_string(),
+            out: None,
+            enabled: false,
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) -> Result<(), String> {
+        if enabled && self.out.is_none() {
+            let file = File::create(&self.path).map_err(|e| format!("{}: {}", self.path, e))?;
+            self.out = Some(BufWriter::new(file));
+        }
+        if !enabled {
+            self.flush()?;
+        }
+        self.enabled = enabled;
+        Ok(())
+    }
 
+    pub fn log(&mut self, cpu: &mut CPU) -> Result<(), String> {
+        let out = match self.out.as_mut() {
+            Some(out) if self.enabled => out,
+            _ => return Ok(()),
+        };
+        writeln!(out, "{}", trace_nestest(cpu)).map_err(|e| format!("{}: {}", self.path, e))
+    }
+
+    pub fn flush(&mut self) -> Result<(), String> {
+        match self.out.as_mut() {
+            Some(out) => out.flush().map_err(|e| format!("{}: {}", self.path, e)),
+            None => Ok(()),
+        }
+    }
+}
+
+fn disassemble(cpu: &mut CPU) -> String {
     let code = cpu.mem_read(cpu.program_counter);
-    let ops = opscodes.get(&code).unwrap();
+    let ops = opcodes::OPCODE_TABLE[code as usize].unwrap();
 
     let begin = cpu.program_counter;
     let mut hex_dump = vec![];
@@ -121,20 +280,57 @@ pub fn trace(cpu: &mut CPU) -> String {
         .join(" ");
     let asm_str = format!("{:04x}  {:8} {: >4} {}", begin, hex_str, ops.name, tmp)
         .trim()
-        .to_string();
+        .to_ascii_uppercase();
+    // after uppercasing, labels keep their case
+    let asm_str = cpu.bus.label_operands(&asm_str);
 
     format!(
-        "{:47} A:{:02x} X:{:02x} Y:{:02x} P:{:02x} SP:{:02x}",
+        "{:47} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
         asm_str, cpu.register_a, cpu.register_x, cpu.register_y, cpu.register_p, cpu.stack_pointer,
     )
-    .to_ascii_uppercase()
+}
+
+// The instruction at `addr` and its length, for listings: read with peek(),
+// so nothing is touched, and without the operand values trace() shows.
+pub fn disassemble_at(bus: &Bus, addr: u16) -> (String, u16) {
+    let code = bus.peek(addr);
+    let ops = match opcodes::OPCODE_TABLE[code as usize] {
+        Some(ops) => ops,
+        None => return (format!("{:04X}  {:02X}        .DB ${:02X}", addr, code, code), 1),
+    };
+    let bytes: Vec<u8> = (0..ops.bytes as u16).map(|i| bus.peek(addr.wrapping_add(i))).collect();
+    let byte = bytes.get(1).copied().unwrap_or(0);
+    let word = u16::from_le_bytes([byte, bytes.get(2).copied().unwrap_or(0)]);
+    let operand = match (&ops.mode, ops.bytes) {
+        (AddressingMode::Immediate, _) => format!("#${:02X}", byte),
+        (AddressingMode::ZeroPage, _) => format!("${:02X}", byte),
+        (AddressingMode::ZeroPage_X, _) => format!("${:02X},X", byte),
+        (AddressingMode::ZeroPage_Y, _) => format!("${:02X},Y", byte),
+        (AddressingMode::Absolute, _) => format!("${:04X}", word),
+        (AddressingMode::Absolute_X, _) => format!("${:04X},X", word),
+        (AddressingMode::Absolute_Y, _) => format!("${:04X},Y", word),
+        (AddressingMode::Indirect_X, _) => format!("(${:02X},X)", byte),
+        (AddressingMode::Indirect_Y, _) => format!("(${:02X}),Y", byte),
+        (AddressingMode::NoneAddressing, 1) => match ops.code {
+            0x0a | 0x4a | 0x2a | 0x6a => "A".to_string(),
+            _ => String::new(),
+        },
+        // branches
+        (AddressingMode::NoneAddressing, 2) => format!("${:04X}", addr.wrapping_add(2).wrapping_add(byte as i8 as u16)),
+        (AddressingMode::NoneAddressing, _) if ops.code == 0x6c => format!("(${:04X})", word),
+        (AddressingMode::NoneAddressing, _) => format!("${:04X}", word),
+    };
+    let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+    let text = format!("{:04X}  {:8}  {} {}", addr, hex.join(" "), ops.name.to_uppercase(), operand);
+    (bus.label_operands(text.trim_end()), ops.bytes as u16)
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::bus::Bus;
+    use crate::cartridge::builder::RomBuilder;
     use crate::cartridge::test::test_rom;
+    use crate::cpu::test::stop_at_brk;
     use crate::ppu::NesPPU;
 
     #[test]
@@ -154,6 +350,7 @@ mod test {
         let mut result: Vec<String> = vec![];
         cpu.run_with_callback(|cpu| {
             result.push(trace(cpu));
+            stop_at_brk(cpu);
         });
         assert_eq!(
             "0064  A2 01     LDX #$01                        A:01 X:02 Y:03 P:24 SP:FD",
@@ -169,6 +366,77 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_trace_log() {
+        let mut bus = Bus::new(test_rom(), |_ppu, _joypad, _joypad2| {});
+        // LDX #$01; DEX; DEY; BRK
+        for (i, byte) in [0xa2, 0x01, 0xca, 0x88, 0x00].iter().enumerate() {
+            bus.mem_write(0x64 + i as u16, *byte);
+        }
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = 0x64;
+
+        let dir = std::env::temp_dir().join(format!("nes-trace-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("trace.log");
+        let mut log = TraceLog::new(path.to_str().unwrap());
+        log.set_enabled(true).unwrap();
+        let mut instructions = 0;
+        cpu.run_with_callback(|cpu| {
+            instructions += 1;
+            // the DEX isn't logged
+            log.set_enabled(instructions != 2).unwrap();
+            log.log(cpu).unwrap();
+            stop_at_brk(cpu);
+        });
+        log.flush().unwrap();
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            lines[0],
+            "0064  A2 01     LDX #$01                        A:00 X:00 Y:00 P:24 SP:FD PPU:  0,  0 CYC:0"
+        );
+        assert_eq!(
+            lines[1],
+            "0067  88        DEY                             A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 12 CYC:4"
+        );
+        assert!(lines[2].starts_with("0068  00        BRK"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_disassemble_at() {
+        let mut bus = Bus::new(test_rom(), |_ppu, _joypad, _joypad2| {});
+        // LDA #$01; STA $0400,X; BNE -5; ASL A; JMP ($0200)
+        for (i, byte) in [0xa9, 0x01, 0x9d, 0x00, 0x04, 0xd0, 0xfb, 0x0a, 0x6c, 0x00, 0x02].iter().enumerate() {
+            bus.mem_write(0x100 + i as u16, *byte);
+        }
+        let mut addr = 0x100;
+        let mut lines = vec![];
+        while addr < 0x10b {
+            let (line, len) = disassemble_at(&bus, addr);
+            lines.push(line);
+            addr += len;
+        }
+        assert_eq!(
+            lines,
+            vec![
+                "0100  A9 01     LDA #$01",
+                "0102  9D 00 04  STA $0400,X",
+                "0105  D0 FB     BNE $0102",
+                "0107  0A        ASL A",
+                "0108  6C 00 02  JMP ($0200)",
+            ]
+        );
+        bus.mem_write(0x10, 0x12);
+        assert_eq!(disassemble_at(&bus, 0x10), ("0010  12        *NOP".to_string(), 1));
+
+        bus.labels.parse_nl("$0400#Screen#\n$0102#Loop#", None).unwrap();
+        assert_eq!(disassemble_at(&bus, 0x102).0, "0102  9D 00 04  STA Screen,X");
+        assert_eq!(disassemble_at(&bus, 0x105).0, "0105  D0 FB     BNE Loop");
+    }
+
     #[test]
     fn test_format_mem_access() {
         let mut bus = Bus::new(test_rom(), |_ppu, _joypad, _joypad2| {});
@@ -189,10 +457,52 @@ mod test {
         let mut result: Vec<String> = vec![];
         cpu.run_with_callback(|cpu| {
             result.push(trace(cpu));
+            stop_at_brk(cpu);
         });
         assert_eq!(
             "0064  11 33     ORA ($33),Y = 0400 @ 0400 = AA  A:00 X:00 Y:00 P:24 SP:FD",
             result[0]
         );
     }
+
+    #[test]
+    fn test_trace_filter_range_and_bank() {
+        let mut bus = Bus::new(test_rom(), |_ppu, _joypad, _joypad2| {});
+        let mut filter = TraceFilter::new();
+        filter.range = Some(TraceFilter::parse_range("C000-FFFF").unwrap());
+        assert!(filter.should_trace(&bus, 0xc000));
+        assert!(filter.should_trace(&bus, 0xffff));
+        assert!(!filter.should_trace(&bus, 0x8000));
+
+        let mut filter = TraceFilter::new();
+        filter.bank = Some(0);
+        assert!(filter.should_trace(&bus, 0x8123));
+        assert!(!filter.should_trace(&bus, 0xc123));
+        assert!(!filter.should_trace(&bus, 0x0600));
+
+        // the bank is the one switched in: AxROM with its second 32kb bank
+        bus = Bus::new(RomBuilder::new().mapper(7).prg_pages(8).rom(), |_ppu, _joypad, _joypad2| {});
+        bus.mem_write(0x8000, 1);
+        filter.bank = Some(3);
+        assert!(!filter.should_trace(&bus, 0x8123));
+        assert!(filter.should_trace(&bus, 0xc123));
+
+        assert!(TraceFilter::parse_range("C000").is_err());
+        assert!(TraceFilter::parse_range("FFFF-C000").is_err());
+    }
+
+    #[test]
+    fn test_trace_filter_start_stop() {
+        let bus = Bus::new(test_rom(), |_ppu, _joypad, _joypad2| {});
+        let mut filter = TraceFilter::new();
+        filter.set_start_at(0x8010);
+        filter.set_stop_at(0x8020);
+
+        assert!(!filter.should_trace(&bus, 0x8000));
+        assert!(filter.should_trace(&bus, 0x8010));
+        assert!(filter.should_trace(&bus, 0x8015));
+        assert!(filter.should_trace(&bus, 0x8020));
+        assert!(!filter.should_trace(&bus, 0x8021));
+        assert!(filter.should_trace(&bus, 0x8010));
+    }
 }
\ No newline at end of file
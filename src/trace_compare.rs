@@ -0,0 +1,245 @@
+// Lines up our CPU trace with a trace logged by a reference emulator and
+// finds the first instruction where the two disagree.
+//
+// Understands nestest/FCEUX style logs
+//   C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD
+// and Mesen trace logger output
+//   8000 $78     SEI                A:00 X:00 Y:00 S:FD P:nvubdIzc
+// Only the register state before each instruction is compared; disassembly
+// and cycle columns differ too much between emulators to be useful.
+
+use std::collections::VecDeque;
+use std::fmt;
+
+// lines of matching trace shown before a divergence
+const CONTEXT_LINES: usize = 8;
+// B and the unused bit only exist on the stack, emulators log them differently
+const FLAGS_MASK: u8 = 0b1100_1111;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceState {
+    pub pc: u16,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub p: u8,
+    pub sp: u8,
+}
+
+impl TraceState {
+    pub fn matches(&self, other: &TraceState) -> bool {
+        self.pc == other.pc
+            && self.a == other.a
+            && self.x == other.x
+            && self.y == other.y
+            && self.p & FLAGS_MASK == other.p & FLAGS_MASK
+            && self.sp == other.sp
+    }
+
+    // register names that differ, for the report
+    pub fn differences(&self, other: &TraceState) -> Vec<&'static str> {
+        let mut differences = vec![];
+        if self.pc != other.pc {
+            differences.push("PC");
+        }
+        if self.a != other.a {
+            differences.push("A");
+        }
+        if self.x != other.x {
+            differences.push("X");
+        }
+        if self.y != other.y {
+            differences.push("Y");
+        }
+        if self.p & FLAGS_MASK != other.p & FLAGS_MASK {
+            differences.push("P");
+        }
+        if self.sp != other.sp {
+            differences.push("SP");
+        }
+        differences
+    }
+
+    pub fn parse_line(line: &str) -> Option<TraceState> {
+        let pc = line.split_whitespace().next()?;
+        let pc = u16::from_str_radix(pc.trim_start_matches('$'), 16).ok()?;
+        let register = |name: &str| -> Option<u8> {
+            let value = line.split_whitespace().find_map(|token| token.strip_prefix(name))?;
+            u8::from_str_radix(value.get(0..2)?, 16).ok()
+        };
+        let p = register("P:").or_else(|| {
+            // Mesen: NV-BDIZC as letters, uppercase when set
+            let flags = line.split_whitespace().find_map(|token| token.strip_prefix("P:"))?;
+            if flags.len() != 8 {
+                return None;
+            }
+            Some(flags.chars().fold(0, |p, flag| p << 1 | flag.is_ascii_uppercase() as u8))
+        })?;
+        Some(TraceState {
+            pc,
+            a: register("A:")?,
+            x: register("X:")?,
+            y: register("Y:")?,
+            p,
+            sp: register("SP:").or_else(|| register("S:"))?,
+        })
+    }
+}
+
+impl fmt::Display for TraceState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04X}  A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
+            self.pc, self.a, self.x, self.y, self.p, self.sp
+        )
+    }
+}
+
+pub struct Divergence {
+    pub instruction: usize,
+    pub expected: TraceState,
+    pub actual: TraceState,
+    pub actual_line: String,
+    // our trace lines leading up to the divergence
+    pub context: Vec<String>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "traces diverge at instruction {}:", self.instruction)?;
+        for line in self.context.iter() {
+            writeln!(f, "      {}", line)?;
+        }
+        writeln!(f, "  ours      {}", self.actual_line)?;
+        writeln!(f, "  ours      {}", self.actual)?;
+        writeln!(f, "  reference {}", self.expected)?;
+        write!(f, "  differs in {}", self.actual.differences(&self.expected).join(", "))
+    }
+}
+
+pub enum Progress {
+    Matching,
+    Diverged(Divergence),
+    // the whole reference trace matched
+    Finished(usize),
+}
+
+pub struct TraceComparison {
+    reference: Vec<TraceState>,
+    position: usize,
+    aligned: bool,
+    context: VecDeque<String>,
+}
+
+impl TraceComparison {
+    pub fn parse(reference_log: &str) -> Result<TraceComparison, String> {
+        let reference: Vec<TraceState> = reference_log.lines().filter_map(TraceState::parse_line).collect();
+        if reference.is_empty() {
+            return Err("reference trace has no lines with PC, A, X, Y, P and SP".to_string());
+        }
+        Ok(TraceComparison {
+            reference,
+            position: 0,
+            aligned: false,
+            context: VecDeque::new(),
+        })
+    }
+
+    // Called before every instruction we execute. The reference may start
+    // earlier (e.g. before reset), so its first lines are skipped until the
+    // PC matches ours.
+    pub fn step(&mut self, actual: TraceState, actual_line: &str) -> Progress {
+        if !self.aligned {
+            match self.reference.iter().position(|state| state.pc == actual.pc) {
+                Some(position) => {
+                    self.position = position;
+                    self.aligned = true;
+                }
+                None => return Progress::Matching,
+            }
+        }
+        let expected = match self.reference.get(self.position) {
+            Some(expected) => *expected,
+            None => return Progress::Finished(self.position),
+        };
+        if !actual.matches(&expected) {
+            return Progress::Diverged(Divergence {
+                instruction: self.position,
+                expected,
+                actual,
+                actual_line: actual_line.to_string(),
+                context: self.context.iter().cloned().collect(),
+            });
+        }
+
+        self.position += 1;
+        if self.context.len() == CONTEXT_LINES {
+            self.context.pop_front();
+        }
+        self.context.push_back(actual_line.to_string());
+        if self.position == self.reference.len() {
+            return Progress::Finished(self.position);
+        }
+        Progress::Matching
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_reference_formats() {
+        let nestest = "C5F7  86 00     STX $00 = 00                    A:00 X:00 Y:00 P:26 SP:FD";
+        assert_eq!(
+            TraceState::parse_line(nestest),
+            Some(TraceState {
+                pc: 0xc5f7,
+                a: 0,
+                x: 0,
+                y: 0,
+                p: 0x26,
+                sp: 0xfd
+            })
+        );
+
+        let mesen = "8000 $78     SEI                A:01 X:02 Y:03 S:FD P:nvubdIzC V:0   H:27";
+        let state = TraceState::parse_line(mesen).unwrap();
+        assert_eq!(state.pc, 0x8000);
+        assert_eq!((state.a, state.x, state.y, state.sp), (1, 2, 3, 0xfd));
+        assert_eq!(state.p, 0b0000_0101);
+
+        assert!(TraceState::parse_line("-- reset --").is_none());
+    }
+
+    #[test]
+    fn test_reports_first_divergence() {
+        let log = "\
+            8000  A9 01     LDA #$01    A:00 X:00 Y:00 P:24 SP:FD\n\
+            C000  4C F5 C5  JMP $C5F5   A:00 X:00 Y:00 P:24 SP:FD\n\
+            C5F5  A2 00     LDX #$00    A:00 X:00 Y:00 P:24 SP:FD\n\
+            C5F7  86 00     STX $00     A:00 X:00 Y:00 P:26 SP:FD\n";
+        let mut comparison = TraceComparison::parse(log).unwrap();
+        let state = |pc, p| TraceState {
+            pc,
+            a: 0,
+            x: 0,
+            y: 0,
+            p,
+            sp: 0xfd,
+        };
+
+        // aligned on the first matching PC, B/unused bits are ignored
+        assert!(matches!(comparison.step(state(0xc000, 0x34), "C000"), Progress::Matching));
+        assert!(matches!(comparison.step(state(0xc5f5, 0x24), "C5F5"), Progress::Matching));
+        match comparison.step(state(0xc5f7, 0x24), "C5F7") {
+            Progress::Diverged(divergence) => {
+                assert_eq!(divergence.instruction, 3);
+                assert_eq!(divergence.actual.differences(&divergence.expected), vec!["P"]);
+                assert_eq!(divergence.context, vec!["C000", "C5F5"]);
+            }
+            _ => panic!("expected a divergence"),
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use crate::ppu::watch::PpuWrite;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrapAction {
+    Ignore,
+    Log,
+    Break,
+}
+
+impl TrapAction {
+    pub fn parse(value: &str) -> Result<TrapAction, String> {
+        match value {
+            "ignore" => Ok(TrapAction::Ignore),
+            "log" => Ok(TrapAction::Log),
+            "break" => Ok(TrapAction::Break),
+            _ => Err(format!("'{}' is not a trap action (ignore, log, break)", value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Trap {
+    RomWrite { addr: u16, data: u8 },
+    UnmappedExecution { pc: u16 },
+    StackOverflow { pc: u16 },
+    StackUnderflow { pc: u16 },
+    // a PPU watchpoint: the CPU write to `register` that changed the byte,
+    // and where the PPU was at the time
+    PpuWrite { write: PpuWrite, register: u16, pc: u16, frame: usize, scanline: u16, dot: usize },
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Trap::RomWrite { addr, data } => {
+                write!(f, "write of {:02x} to cartridge ROM space at {:04x}", data, addr)
+            }
+            Trap::UnmappedExecution { pc } => write!(f, "executing from unmapped memory at {:04x}", pc),
+            Trap::StackOverflow { pc } => write!(f, "stack pointer wrapped below 0100 at {:04x}", pc),
+            Trap::StackUnderflow { pc } => write!(f, "stack pointer wrapped above 01ff at {:04x}", pc),
+            Trap::PpuWrite { write, register, pc, frame, scanline, dot } => write!(
+                f,
+                "{} by a write to {:04x} at {:04x}, frame {} scanline {} dot {}",
+                write, register, pc, frame, scanline, dot
+            ),
+        }
+    }
+}
+
+// Catches behaviour that real games almost never rely on, so game and
+// emulator bugs surface where they happen rather than frames later.
+pub struct Traps {
+    pub on_rom_write: TrapAction,
+    pub on_unmapped_execution: TrapAction,
+    pub on_stack_wrap: TrapAction,
+    pub on_ppu_write: TrapAction,
+    // the instruction running now, for traps the bus raises
+    pub instruction_pc: u16,
+    pending_break: Option<Trap>,
+}
+
+impl Default for Traps {
+    fn default() -> Self {
+        Traps::new()
+    }
+}
+
+impl Traps {
+    pub fn new() -> Self {
+        Traps {
+            on_rom_write: TrapAction::Break,
+            on_unmapped_execution: TrapAction::Ignore,
+            on_stack_wrap: TrapAction::Ignore,
+            on_ppu_write: TrapAction::Break,
+            instruction_pc: 0,
+            pending_break: None,
+        }
+    }
+
+    pub fn rom_write(&mut self, addr: u16, data: u8) {
+        self.hit(self.on_rom_write, Trap::RomWrite { addr, data });
+    }
+
+    pub fn unmapped_execution(&mut self, pc: u16) {
+        self.hit(self.on_unmapped_execution, Trap::UnmappedExecution { pc });
+    }
+
+    pub fn stack_overflow(&mut self, pc: u16) {
+        self.hit(self.on_stack_wrap, Trap::StackOverflow { pc });
+    }
+
+    pub fn stack_underflow(&mut self, pc: u16) {
+        self.hit(self.on_stack_wrap, Trap::StackUnderflow { pc });
+    }
+
+    pub fn ppu_write(&mut self, write: PpuWrite, register: u16, frame: usize, (scanline, dot): (u16, usize)) {
+        let pc = self.instruction_pc;
+        self.hit(self.on_ppu_write, Trap::PpuWrite { write, register, pc, frame, scanline, dot });
+    }
+
+    pub fn take_break(&mut self) -> Option<Trap> {
+        self.pending_break.take()
+    }
+
+    fn hit(&mut self, action: TrapAction, trap: Trap) {
+        match action {
+            TrapAction::Ignore => {}
+            TrapAction::Log => println!("trap: {}", trap),
+            TrapAction::Break => {
+                println!("trap (break): {}", trap);
+                if self.pending_break.is_none() {
+                    self.pending_break = Some(trap);
+                }
+            }
+        }
+    }
+}
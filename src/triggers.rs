@@ -0,0 +1,227 @@
+// Per-game event triggers declared in a `<rom>.triggers.toml` file:
+//
+// [[trigger]]
+// name = "extra life"
+// address = "07DD"
+// condition = "increased"   # changed | increased | decreased | equals | above | below
+// action = "message"        # message | screenshot | savestate | pause | rumble
+// message = "1UP!"
+//
+// `equals`, `above` and `below` take a `value` and fire when the condition
+// becomes true; the other conditions compare against the previous frame.
+// `rumble` takes an optional `strength` (0.0 - 1.0) and `duration` in ms.
+
+use crate::trace::parse_addr;
+use toml::Value;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    Changed,
+    Increased,
+    Decreased,
+    Equals(u8),
+    Above(u8),
+    Below(u8),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TriggerAction {
+    Message(String),
+    Screenshot,
+    SaveState,
+    Pause,
+    Rumble { strength: f32, duration_ms: u32 },
+}
+
+pub struct Trigger {
+    pub name: String,
+    pub address: u16,
+    pub condition: Condition,
+    pub action: TriggerAction,
+    previous: Option<u8>,
+    was_true: bool,
+}
+
+impl Trigger {
+    fn check(&mut self, value: u8) -> bool {
+        let previous = self.previous.replace(value);
+        match self.condition {
+            Condition::Changed => previous.is_some_and(|p| p != value),
+            Condition::Increased => previous.is_some_and(|p| value > p),
+            Condition::Decreased => previous.is_some_and(|p| value < p),
+            Condition::Equals(v) => self.edge(value == v),
+            Condition::Above(v) => self.edge(value > v),
+            Condition::Below(v) => self.edge(value < v),
+        }
+    }
+
+    fn edge(&mut self, is_true: bool) -> bool {
+        let fired = is_true && !self.was_true;
+        self.was_true = is_true;
+        fired
+    }
+}
+
+pub struct Triggers {
+    pub triggers: Vec<Trigger>,
+}
+
+impl Triggers {
+    pub fn parse(text: &str) -> Result<Triggers, String> {
+        let root = text.parse::<Value>().map_err(|e| format!("triggers: {}", e))?;
+        let entries = match root.get("trigger") {
+            Some(Value::Array(entries)) => entries.clone(),
+            Some(_) => return Err("triggers: `trigger` should be an array of tables ([[trigger]])".to_string()),
+            None => vec![],
+        };
+
+        let mut triggers = vec![];
+        for (i, entry) in entries.iter().enumerate() {
+            let name = match entry.get("name").and_then(Value::as_str) {
+                Some(name) => name.to_string(),
+                None => format!("trigger #{}", i + 1),
+            };
+            let field = |key: &str| -> Result<&str, String> {
+                entry
+                    .get(key)
+                    .and_then(Value::as_str)
+                    .ok_or(format!("triggers: '{}' is missing `{}`", name, key))
+            };
+            let value = || -> Result<u8, String> {
+                match entry.get("value").and_then(Value::as_integer) {
+                    Some(v) if (0..=0xff).contains(&v) => Ok(v as u8),
+                    _ => Err(format!("triggers: '{}' needs a `value` between 0 and 255", name)),
+                }
+            };
+
+            let address = parse_addr(field("address")?)?;
+            let condition = match field("condition")? {
+                "changed" => Condition::Changed,
+                "increased" => Condition::Increased,
+                "decreased" => Condition::Decreased,
+                "equals" => Condition::Equals(value()?),
+                "above" => Condition::Above(value()?),
+                "below" => Condition::Below(value()?),
+                other => return Err(format!("triggers: '{}' has unknown condition '{}'", name, other)),
+            };
+            let action = match field("action")? {
+                "message" => TriggerAction::Message(field("message").unwrap_or(&name).to_string()),
+                "screenshot" => TriggerAction::Screenshot,
+                "savestate" => TriggerAction::SaveState,
+                "pause" => TriggerAction::Pause,
+                "rumble" => {
+                    let strength = entry.get("strength").and_then(Value::as_float).unwrap_or(1.0);
+                    let duration_ms = entry.get("duration").and_then(Value::as_integer).unwrap_or(200);
+                    if !(0.0..=1.0).contains(&strength) || duration_ms < 0 {
+                        return Err(format!("triggers: '{}' needs a strength between 0 and 1 and a positive duration", name));
+                    }
+                    TriggerAction::Rumble {
+                        strength: strength as f32,
+                        duration_ms: duration_ms as u32,
+                    }
+                }
+                other => return Err(format!("triggers: '{}' has unknown action '{}'", name, other)),
+            };
+
+            triggers.push(Trigger {
+                name,
+                address,
+                condition,
+                action,
+                previous: None,
+                was_true: false,
+            });
+        }
+        Ok(Triggers { triggers })
+    }
+
+    // Looks for `game.triggers.toml` next to `game.nes`
+    pub fn load_for_rom(rom_path: &str) -> Result<Option<Triggers>, String> {
+        let path = std::path::Path::new(rom_path).with_extension("triggers.toml");
+        match std::fs::read_to_string(&path) {
+            Ok(text) => Triggers::parse(&text).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    // called once per frame; returns the actions of the triggers that fired
+    pub fn evaluate<F>(&mut self, mut read: F) -> Vec<TriggerAction>
+    where
+        F: FnMut(u16) -> u8,
+    {
+        let mut fired = vec![];
+        for trigger in self.triggers.iter_mut() {
+            if trigger.check(read(trigger.address)) {
+                fired.push(trigger.action.clone());
+            }
+        }
+        fired
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TRIGGERS: &str = r#"
+        [[trigger]]
+        name = "extra life"
+        address = "$07DD"
+        condition = "increased"
+        action = "message"
+        message = "1UP!"
+
+        [[trigger]]
+        name = "boss"
+        address = "0010"
+        condition = "equals"
+        value = 5
+        action = "pause"
+
+        [[trigger]]
+        name = "hit"
+        address = "0075"
+        condition = "decreased"
+        action = "rumble"
+        strength = 0.5
+    "#;
+
+    #[test]
+    fn test_parse_triggers() {
+        let triggers = Triggers::parse(TRIGGERS).unwrap();
+        assert_eq!(triggers.triggers.len(), 3);
+        assert_eq!(triggers.triggers[0].address, 0x07dd);
+        assert_eq!(triggers.triggers[0].condition, Condition::Increased);
+        assert_eq!(triggers.triggers[0].action, TriggerAction::Message("1UP!".to_string()));
+        assert_eq!(triggers.triggers[1].condition, Condition::Equals(5));
+        assert_eq!(
+            triggers.triggers[2].action,
+            TriggerAction::Rumble {
+                strength: 0.5,
+                duration_ms: 200
+            }
+        );
+
+        assert!(Triggers::parse("[[trigger]]\naddress = \"10\"\ncondition = \"equals\"\naction = \"pause\"").is_err());
+    }
+
+    #[test]
+    fn test_evaluate_triggers() {
+        let mut triggers = Triggers::parse(TRIGGERS).unwrap();
+        let mut ram = [0u8; 2048];
+
+        assert!(triggers.evaluate(|addr| ram[addr as usize]).is_empty());
+
+        ram[0x07dd] = 1;
+        assert_eq!(triggers.evaluate(|addr| ram[addr as usize]), vec![TriggerAction::Message("1UP!".to_string())]);
+        assert!(triggers.evaluate(|addr| ram[addr as usize]).is_empty());
+
+        ram[0x10] = 5;
+        assert_eq!(triggers.evaluate(|addr| ram[addr as usize]), vec![TriggerAction::Pause]);
+        assert!(triggers.evaluate(|addr| ram[addr as usize]).is_empty());
+        ram[0x10] = 4;
+        assert!(triggers.evaluate(|addr| ram[addr as usize]).is_empty());
+        ram[0x10] = 5;
+        assert_eq!(triggers.evaluate(|addr| ram[addr as usize]), vec![TriggerAction::Pause]);
+    }
+}
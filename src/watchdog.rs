@@ -0,0 +1,191 @@
+// Notices when the emulator stops producing frames instead of leaving a
+// silently frozen window. The watchdog thread only sees a frame counter and
+// the last checkpoint. When frames stop for `timeout` it raises a flag, and
+// the emulation loop, if it is still executing instructions (a game or mapper
+// spinning in a loop), writes a bundle with the full machine state and offers
+// a reset. If nothing picks the flag up within another `timeout`, emulation is
+// stuck inside the emulator itself, and the watchdog writes what it has: the
+// last checkpoint.
+//
+// Pauses stop frames on purpose, the frontend marks them with set_idle().
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_DIR: &str = "stalls";
+const POLL: Duration = Duration::from_millis(50);
+
+// what the emulation side last reported while it was healthy
+struct Checkpoint {
+    frame: usize,
+    state: Vec<u8>,
+}
+
+struct Shared {
+    frame: AtomicUsize,
+    idle: AtomicBool,
+    stalled: AtomicBool,
+    // a bundle was written for the current stall
+    handled: AtomicBool,
+    checkpoint: Mutex<Option<Checkpoint>>,
+    dir: PathBuf,
+    timeout: Duration,
+}
+
+#[derive(Clone)]
+pub struct Watchdog {
+    shared: Arc<Shared>,
+}
+
+impl Watchdog {
+    // the thread ends with the last Watchdog handle
+    pub fn start(timeout: Duration, dir: &Path) -> Self {
+        let shared = Arc::new(Shared {
+            frame: AtomicUsize::new(0),
+            idle: AtomicBool::new(false),
+            stalled: AtomicBool::new(false),
+            handled: AtomicBool::new(false),
+            checkpoint: Mutex::new(None),
+            dir: dir.to_path_buf(),
+            timeout,
+        });
+        let watched = Arc::downgrade(&shared);
+        std::thread::spawn(move || watch(watched));
+        Watchdog { shared }
+    }
+
+    // once per frame
+    pub fn beat(&self, frame: usize) {
+        self.shared.frame.store(frame, Ordering::Relaxed);
+        if self.shared.stalled.swap(false, Ordering::Relaxed) {
+            self.shared.handled.store(false, Ordering::Relaxed);
+            println!("emulation resumed at frame {}", frame);
+        }
+    }
+
+    // a savestate to fall back on when the emulation side can't answer
+    pub fn checkpoint(&self, frame: usize, state: Vec<u8>) {
+        *self.shared.checkpoint.lock().unwrap() = Some(Checkpoint { frame, state });
+    }
+
+    // paused on purpose: no frames and no stall
+    pub fn set_idle(&self, idle: bool) {
+        self.shared.idle.store(idle, Ordering::Relaxed);
+    }
+
+    // a stall nobody has written a bundle for yet; cheap enough to check
+    // every instruction
+    pub fn stalled(&self) -> bool {
+        self.shared.stalled.load(Ordering::Relaxed) && !self.shared.handled.load(Ordering::Relaxed)
+    }
+
+    // the bundle for a stall the emulation side noticed itself
+    pub fn write_bundle(&self, report: &str, state: &[u8]) -> std::io::Result<PathBuf> {
+        self.shared.handled.store(true, Ordering::Relaxed);
+        write_bundle(&self.shared.dir, report, state)
+    }
+}
+
+fn watch(shared: Weak<Shared>) {
+    let mut last_frame = 0;
+    let mut since = Instant::now();
+    while let Some(shared) = shared.upgrade() {
+        let frame = shared.frame.load(Ordering::Relaxed);
+        if frame != last_frame || shared.idle.load(Ordering::Relaxed) {
+            last_frame = frame;
+            since = Instant::now();
+        }
+        let stalled_for = since.elapsed();
+        if stalled_for >= shared.timeout && !shared.stalled.swap(true, Ordering::Relaxed) {
+            println!("emulation stalled: no frame for {}s after frame {}", stalled_for.as_secs(), frame);
+        }
+        if stalled_for >= shared.timeout * 2 && !shared.handled.swap(true, Ordering::Relaxed) {
+            write_fallback_bundle(&shared, frame, stalled_for);
+        }
+        drop(shared);
+        std::thread::sleep(POLL);
+    }
+}
+
+fn write_fallback_bundle(shared: &Shared, frame: usize, stalled_for: Duration) {
+    let checkpoint = shared.checkpoint.lock().unwrap();
+    let mut report = format!(
+        "emulation stopped inside the emulator: no frame for {}s after frame {}, \
+         and no instruction has run since the stall was flagged\n",
+        stalled_for.as_secs(),
+        frame
+    );
+    let state = match checkpoint.as_ref() {
+        Some(checkpoint) => {
+            report += &format!("state.sav is the last checkpoint, taken at frame {}\n", checkpoint.frame);
+            checkpoint.state.as_slice()
+        }
+        None => {
+            report += "no checkpoint was taken before the stall\n";
+            &[]
+        }
+    };
+    match write_bundle(&shared.dir, &report, state) {
+        Ok(path) => println!("diagnostics written to {}, the emulator has to be restarted", path.display()),
+        Err(err) => println!("failed to write the stall diagnostics: {}", err),
+    }
+}
+
+// <dir>/stall-<unix time>/ with report.txt and state.sav (when there is one)
+fn write_bundle(dir: &Path, report: &str, state: &[u8]) -> std::io::Result<PathBuf> {
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+    let bundle = dir.join(format!("stall-{}", time));
+    std::fs::create_dir_all(&bundle)?;
+    std::fs::write(bundle.join("report.txt"), report)?;
+    if !state.is_empty() {
+        std::fs::write(bundle.join("state.sav"), state)?;
+    }
+    Ok(bundle)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn wait_for(what: impl Fn() -> bool) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(5) {
+            if what() {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    #[test]
+    fn test_stall_flagged_then_fallback_bundle() {
+        let dir = std::env::temp_dir().join(format!("nes-watchdog-{}", std::process::id()));
+        let watchdog = Watchdog::start(Duration::from_millis(100), &dir);
+        watchdog.checkpoint(7, vec![1, 2, 3]);
+
+        // idle doesn't count as a stall
+        watchdog.set_idle(true);
+        std::thread::sleep(Duration::from_millis(300));
+        assert!(!watchdog.stalled());
+        watchdog.set_idle(false);
+
+        assert!(wait_for(|| watchdog.stalled()));
+        watchdog.beat(1);
+        assert!(!watchdog.stalled());
+
+        // nothing answers the flag: the watchdog writes the checkpoint itself
+        assert!(wait_for(|| watchdog.stalled()));
+        let bundle = || std::fs::read_dir(&dir).ok()?.next()?.ok().map(|entry| entry.path());
+        assert!(wait_for(|| bundle().is_some_and(|bundle| bundle.join("state.sav").exists())));
+        assert!(!watchdog.stalled());
+        let bundle = bundle().unwrap();
+        assert_eq!(std::fs::read(bundle.join("state.sav")).unwrap(), vec![1, 2, 3]);
+        let report = std::fs::read_to_string(bundle.join("report.txt")).unwrap();
+        assert!(report.contains("taken at frame 7"), "{}", report);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
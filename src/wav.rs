@@ -0,0 +1,184 @@
+// Records what the APU plays to 16-bit mono WAV files, for soundtrack rips:
+// the mix as heard, and optionally a stem per source next to it
+// (music.wav, music-pulse1.wav, ... music-expansion.wav). Stems are each
+// source alone at full volume, whatever the mixer says, so they can be mixed
+// again elsewhere.
+//
+// Like the trace log, recording can be switched on and off while running;
+// each start overwrites the files and each stop finishes them.
+
+use crate::mixer::Source;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+// bytes before the samples
+const HEADER_SIZE: u32 = 44;
+
+pub struct WavWriter {
+    path: String,
+    out: BufWriter<File>,
+    samples: u32,
+}
+
+impl WavWriter {
+    pub fn create(path: &str, sample_rate: u32) -> Result<Self, String> {
+        let file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+        let mut writer = WavWriter { path: path.to_string(), out: BufWriter::new(file), samples: 0 };
+        // the sizes are filled in by finish()
+        writer.write_header(sample_rate)?;
+        Ok(writer)
+    }
+
+    fn write_header(&mut self, sample_rate: u32) -> Result<(), String> {
+        let data_size = self.samples * 2;
+        let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&(HEADER_SIZE - 8 + data_size).to_le_bytes());
+        header.extend_from_slice(b"WAVEfmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        // PCM, mono
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&sample_rate.to_le_bytes());
+        // bytes per second, bytes per sample, bits per sample
+        header.extend_from_slice(&(sample_rate * 2).to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&16u16.to_le_bytes());
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&data_size.to_le_bytes());
+        self.out.write_all(&header).map_err(|e| format!("{}: {}", self.path, e))
+    }
+
+    pub fn write(&mut self, samples: &[f32]) -> Result<(), String> {
+        for sample in samples {
+            let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.out.write_all(&sample.to_le_bytes()).map_err(|e| format!("{}: {}", self.path, e))?;
+        }
+        self.samples += samples.len() as u32;
+        Ok(())
+    }
+
+    // fills in the sizes; returns the length in samples
+    pub fn finish(mut self, sample_rate: u32) -> Result<u32, String> {
+        self.out.seek(SeekFrom::Start(0)).map_err(|e| format!("{}: {}", self.path, e))?;
+        self.write_header(sample_rate)?;
+        self.out.flush().map_err(|e| format!("{}: {}", self.path, e))?;
+        Ok(self.samples)
+    }
+}
+
+// music.wav -> music-pulse1.wav
+pub fn stem_path(path: &str, source: Source) -> String {
+    let path = Path::new(path);
+    let stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("audio");
+    let name = format!("{}-{}.wav", stem, source.name());
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+pub struct WavRecorder {
+    path: String,
+    stems: bool,
+    sample_rate: u32,
+    mix: Option<WavWriter>,
+    // in Source::ALL order, while recording with stems
+    stem_writers: Vec<WavWriter>,
+}
+
+impl WavRecorder {
+    pub fn new(path: &str, stems: bool) -> Self {
+        WavRecorder { path: path.to_string(), stems, sample_rate: 0, mix: None, stem_writers: vec![] }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn stems(&self) -> bool {
+        self.stems
+    }
+
+    pub fn recording(&self) -> bool {
+        self.mix.is_some()
+    }
+
+    pub fn start(&mut self, sample_rate: u32) -> Result<(), String> {
+        let mix = WavWriter::create(&self.path, sample_rate)?;
+        let mut stem_writers = vec![];
+        if self.stems {
+            for source in Source::ALL.iter() {
+                stem_writers.push(WavWriter::create(&stem_path(&self.path, *source), sample_rate)?);
+            }
+        }
+        self.sample_rate = sample_rate;
+        self.mix = Some(mix);
+        self.stem_writers = stem_writers;
+        Ok(())
+    }
+
+    // `stems` in Source::ALL order; ignored when not recording stems
+    pub fn record(&mut self, mix: &[f32], stems: &[Vec<f32>]) -> Result<(), String> {
+        let writer = match self.mix.as_mut() {
+            Some(writer) => writer,
+            None => return Ok(()),
+        };
+        writer.write(mix)?;
+        for (writer, samples) in self.stem_writers.iter_mut().zip(stems.iter()) {
+            writer.write(samples)?;
+        }
+        Ok(())
+    }
+
+    // returns the recording's length in seconds
+    pub fn stop(&mut self) -> Result<f64, String> {
+        let samples = match self.mix.take() {
+            Some(writer) => writer.finish(self.sample_rate)?,
+            None => return Ok(0.0),
+        };
+        for writer in self.stem_writers.drain(..) {
+            writer.finish(self.sample_rate)?;
+        }
+        Ok(samples as f64 / self.sample_rate as f64)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_records_mix_and_stems() {
+        let dir = std::env::temp_dir().join(format!("nes-wav-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("music.wav").to_string_lossy().into_owned();
+        assert_eq!(stem_path(&path, Source::Triangle), dir.join("music-triangle.wav").to_string_lossy());
+
+        let mut recorder = WavRecorder::new(&path, true);
+        // not recording yet, nothing is written
+        recorder.record(&[0.5; 10], &[]).unwrap();
+        recorder.start(44100).unwrap();
+        let mut stems = vec![vec![]; Source::ALL.len()];
+        stems[Source::Noise as usize] = vec![-1.0, 2.0];
+        recorder.record(&[0.5, -0.5], &stems).unwrap();
+        recorder.record(&[0.0; 98], &stems).unwrap();
+        assert_eq!(recorder.stop().unwrap(), 100.0 / 44100.0);
+        assert!(!recorder.recording());
+
+        let wav = std::fs::read(&path).unwrap();
+        assert_eq!(wav.len(), 44 + 200);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes([wav[4], wav[5], wav[6], wav[7]]), 36 + 200);
+        assert_eq!(u32::from_le_bytes([wav[24], wav[25], wav[26], wav[27]]), 44100);
+        assert_eq!(u32::from_le_bytes([wav[40], wav[41], wav[42], wav[43]]), 200);
+        assert_eq!(i16::from_le_bytes([wav[44], wav[45]]), 16383);
+        assert_eq!(i16::from_le_bytes([wav[46], wav[47]]), -16383);
+
+        // out of range samples clip
+        let noise = std::fs::read(stem_path(&path, Source::Noise)).unwrap();
+        assert_eq!(noise.len(), 44 + 8);
+        assert_eq!(i16::from_le_bytes([noise[44], noise[45]]), -i16::MAX);
+        assert_eq!(i16::from_le_bytes([noise[46], noise[47]]), i16::MAX);
+        assert_eq!(std::fs::read(stem_path(&path, Source::Dmc)).unwrap().len(), 44);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,310 @@
+// A WebSocket server (RFC 6455) small enough for the debug server: text
+// messages only, no extensions, and no thread of its own. The emulation loop
+// polls it, so sockets are non-blocking except while a reply is written out.
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(1);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+// the largest message a client may send; requests are small JSON objects
+const MAX_MESSAGE: usize = 1 << 20;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+#[derive(Debug, PartialEq)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+struct Client {
+    id: usize,
+    stream: TcpStream,
+    // bytes received but not yet a whole frame
+    received: Vec<u8>,
+    // a fragmented text message so far
+    message: Option<Vec<u8>>,
+}
+
+pub struct Server {
+    listener: TcpListener,
+    clients: Vec<Client>,
+    next_id: usize,
+}
+
+impl Server {
+    pub fn bind(addr: &str) -> Result<Server, String> {
+        let listener = TcpListener::bind(addr).map_err(|err| format!("websocket: failed to listen on {}: {}", addr, err))?;
+        listener
+            .set_nonblocking(true)
+            .map_err(|err| format!("websocket: {}", err))?;
+        Ok(Server { listener, clients: vec![], next_id: 0 })
+    }
+
+    pub fn local_addr(&self) -> Option<std::net::SocketAddr> {
+        self.listener.local_addr().ok()
+    }
+
+    pub fn has_clients(&self) -> bool {
+        !self.clients.is_empty()
+    }
+
+    // Accepts new connections and returns the text messages that arrived
+    // since the last poll, with the id of the client that sent them. Clients
+    // that close or misbehave are dropped.
+    pub fn poll(&mut self) -> Vec<(usize, String)> {
+        while let Ok((stream, peer)) = self.listener.accept() {
+            match handshake(stream) {
+                Ok((stream, received)) => {
+                    println!("debug client {} connected from {}", self.next_id, peer);
+                    self.clients.push(Client { id: self.next_id, stream, received, message: None });
+                    self.next_id += 1;
+                }
+                Err(err) => println!("warning: {}: {}", peer, err),
+            }
+        }
+
+        let mut messages = vec![];
+        self.clients.retain_mut(|client| match client.receive(&mut messages) {
+            Ok(()) => true,
+            Err(reason) => {
+                println!("debug client {} disconnected: {}", client.id, reason);
+                false
+            }
+        });
+        messages
+    }
+
+    pub fn send(&mut self, client: usize, text: &str) {
+        self.clients.retain_mut(|other| {
+            other.id != client || other.write_frame(OP_TEXT, text.as_bytes()).is_ok()
+        });
+    }
+
+    pub fn broadcast(&mut self, text: &str) {
+        self.clients.retain_mut(|client| client.write_frame(OP_TEXT, text.as_bytes()).is_ok());
+    }
+}
+
+impl Client {
+    fn receive(&mut self, messages: &mut Vec<(usize, String)>) -> Result<(), String> {
+        let mut buf = [0; 4096];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Err("connection closed".to_string()),
+                Ok(n) => self.received.extend_from_slice(&buf[..n]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err.to_string()),
+            }
+        }
+
+        while let Some((frame, used)) = decode_frame(&self.received)? {
+            self.received.drain(..used);
+            match frame.opcode {
+                OP_TEXT | OP_CONTINUATION => {
+                    let message = self.message.get_or_insert_with(Vec::new);
+                    message.extend(frame.payload);
+                    if message.len() > MAX_MESSAGE {
+                        return Err("message too large".to_string());
+                    }
+                    if frame.fin {
+                        let message = self.message.take().unwrap();
+                        let text = String::from_utf8(message).map_err(|_| "text message is not UTF-8")?;
+                        messages.push((self.id, text));
+                    }
+                }
+                OP_BINARY => return Err("binary messages are not supported".to_string()),
+                OP_PING => self.write_frame(OP_PONG, &frame.payload).map_err(|err| err.to_string())?,
+                OP_PONG => {}
+                OP_CLOSE => {
+                    let _ = self.write_frame(OP_CLOSE, &frame.payload);
+                    return Err("closed by the client".to_string());
+                }
+                opcode => return Err(format!("unknown opcode {:#x}", opcode)),
+            }
+        }
+        Ok(())
+    }
+
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> std::io::Result<()> {
+        self.stream.set_nonblocking(false)?;
+        let written = self.stream.write_all(&encode_frame(opcode, payload));
+        self.stream.set_nonblocking(true)?;
+        written
+    }
+}
+
+// Reads the HTTP upgrade request and answers it. The stream comes back
+// non-blocking, with whatever the client sent after the request.
+fn handshake(mut stream: TcpStream) -> Result<(TcpStream, Vec<u8>), String> {
+    stream.set_nonblocking(false).map_err(|err| err.to_string())?;
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)).map_err(|err| err.to_string())?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT)).map_err(|err| err.to_string())?;
+    let mut request = vec![];
+    let mut buf = [0; 1024];
+    let end = loop {
+        if let Some(end) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end + 4;
+        }
+        let n = stream.read(&mut buf).map_err(|err| format!("websocket handshake: {}", err))?;
+        if n == 0 || request.len() > 8192 {
+            return Err("websocket handshake: incomplete request".to_string());
+        }
+        request.extend_from_slice(&buf[..n]);
+    };
+    let received = request.split_off(end);
+    let request = String::from_utf8_lossy(&request);
+    let key = request
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("sec-websocket-key"))
+        .map(|(_, value)| value.trim())
+        .ok_or("websocket handshake: not a WebSocket upgrade request")?;
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    );
+    stream.write_all(response.as_bytes()).map_err(|err| format!("websocket handshake: {}", err))?;
+    stream.set_nonblocking(true).map_err(|err| err.to_string())?;
+    Ok((stream, received))
+}
+
+pub fn accept_key(key: &str) -> String {
+    let digest = sha1_smol::Sha1::from(format!("{}{}", key, ACCEPT_GUID)).digest();
+    base64(&digest.bytes())
+}
+
+pub fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut text = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, &byte)| group | (byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                text.push(ALPHABET[(group >> (18 - 6 * i)) as usize & 0x3f] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+// A frame from a client, which are always masked, and how many bytes it took.
+// None until the whole frame is there.
+pub fn decode_frame(data: &[u8]) -> Result<Option<(Frame, usize)>, String> {
+    if data.len() < 2 {
+        return Ok(None);
+    }
+    let fin = data[0] & 0x80 != 0;
+    let opcode = data[0] & 0x0f;
+    if data[1] & 0x80 == 0 {
+        return Err("client frames must be masked".to_string());
+    }
+    let (len, mut pos) = match data[1] & 0x7f {
+        126 if data.len() >= 4 => (u16::from_be_bytes([data[2], data[3]]) as usize, 4),
+        127 if data.len() >= 10 => {
+            let mut len = [0; 8];
+            len.copy_from_slice(&data[2..10]);
+            (u64::from_be_bytes(len) as usize, 10)
+        }
+        126 | 127 => return Ok(None),
+        len => (len as usize, 2),
+    };
+    if len > MAX_MESSAGE {
+        return Err("frame too large".to_string());
+    }
+    if data.len() < pos + 4 + len {
+        return Ok(None);
+    }
+    let mask = [data[pos], data[pos + 1], data[pos + 2], data[pos + 3]];
+    pos += 4;
+    let payload = data[pos..pos + len].iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]).collect();
+    Ok(Some((Frame { fin, opcode, payload }, pos + len)))
+}
+
+// a whole, unmasked frame from the server
+pub fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_handshake_and_frames() {
+        // the example from RFC 6455
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert_eq!(base64(b"nes"), "bmVz");
+        assert_eq!(base64(b"ne"), "bmU=");
+        assert_eq!(base64(b"n"), "bg==");
+
+        // a masked "Hello" from the RFC
+        let hello = [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+        assert_eq!(decode_frame(&hello[..6]), Ok(None));
+        let (frame, used) = decode_frame(&hello).unwrap().unwrap();
+        assert_eq!(frame, Frame { fin: true, opcode: OP_TEXT, payload: b"Hello".to_vec() });
+        assert_eq!(used, hello.len());
+        assert!(decode_frame(&[0x81, 0x05, b'H']).is_err());
+
+        assert_eq!(encode_frame(OP_TEXT, b"Hello"), [0x81, 0x05, b'H', b'e', b'l', b'l', b'o']);
+        let long = encode_frame(OP_TEXT, &[0; 300]);
+        assert_eq!(long[..4], [0x81, 126, 0x01, 0x2c]);
+        assert_eq!(long.len(), 304);
+    }
+
+    #[test]
+    fn test_server_round_trip() {
+        let mut server = Server::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: x\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n")
+            .unwrap();
+        client.write_all(&[0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58]).unwrap();
+
+        let mut messages = vec![];
+        for _ in 0..100 {
+            messages.extend(server.poll());
+            if !messages.is_empty() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(messages, vec![(0, "Hello".to_string())]);
+
+        server.send(0, "hi");
+        client.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let mut response = vec![];
+        let mut buf = [0; 256];
+        while !response.ends_with(&[0x81, 0x02, b'h', b'i']) {
+            let n = client.read(&mut buf).unwrap();
+            assert!(n > 0);
+            response.extend_from_slice(&buf[..n]);
+        }
+        assert!(String::from_utf8_lossy(&response).contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+    }
+}
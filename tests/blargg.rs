@@ -0,0 +1,46 @@
+// Boots blargg's CPU, PPU and APU test ROMs headless and reports pass/fail
+// per ROM, using the $6000/$6004 status protocol described in accuracy.rs.
+// The ROMs aren't bundled: put them under test-roms/cpu, test-roms/ppu and
+// test-roms/apu (or point NES_TEST_ROMS somewhere else) and run
+//
+//   cargo test --test blargg -- --ignored --nocapture
+//
+// Suites without a directory are skipped, but the test fails when no ROM ran
+// at all, so a missing ROM directory doesn't pass as green.
+
+use nes_book_emu::accuracy::{self, Scoreboard};
+use std::path::PathBuf;
+
+const SUITES: [&str; 3] = ["cpu", "ppu", "apu"];
+
+fn test_roms_dir() -> PathBuf {
+    match std::env::var("NES_TEST_ROMS") {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(accuracy::DEFAULT_DIR),
+    }
+}
+
+#[test]
+#[ignore = "needs blargg's test ROMs, run with --ignored"]
+fn test_blargg_roms() {
+    let dir = test_roms_dir();
+    let mut scoreboard = Scoreboard::new();
+    for suite in SUITES.iter() {
+        let suite_dir = dir.join(suite);
+        if !suite_dir.is_dir() {
+            println!("skipping {}: no {}", suite, suite_dir.display());
+            continue;
+        }
+        if let Err(err) = accuracy::run_directory_suite(&mut scoreboard, suite, &suite_dir, accuracy::DEFAULT_MAX_FRAMES) {
+            println!("skipping {}: {}", suite, err);
+        }
+    }
+
+    print!("{}", scoreboard.to_text());
+    assert!(
+        !scoreboard.results.is_empty(),
+        "no test ROMs found under {}, see the top of tests/blargg.rs",
+        dir.display()
+    );
+    assert!(scoreboard.all_passed(), "blargg tests failed:\n{}", scoreboard.to_text());
+}
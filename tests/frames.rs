@@ -0,0 +1,39 @@
+// Rendering regression tests: the bundled ROMs run headless for a couple of
+// seconds and every frame's hash has to match the golden hashes under
+// tests/golden/. When a change is meant to alter the picture, look at the
+// frame the failure points at and regenerate with
+//
+//   cargo run -- hash-frames nestest.nes --frames 120 --save tests/golden/nestest.hashes
+
+use nes_book_emu::cartridge::{self, Rom};
+use nes_book_emu::frame_hashes::{self, FrameHashes};
+use nes_book_emu::region::Region;
+
+const FRAMES: usize = 120;
+
+fn check_against_golden(name: &str) {
+    let root = env!("CARGO_MANIFEST_DIR");
+    let bytes = std::fs::read(format!("{}/{}.nes", root, name)).unwrap();
+    let rom = Rom::new(&bytes).unwrap();
+    let region = Region::select(rom.region, None);
+    let mapper = cartridge::create_mapper(rom).unwrap();
+    let (hashes, last_frame) = frame_hashes::run_headless(mapper, region, FRAMES);
+
+    let golden = std::fs::read(format!("{}/tests/golden/{}.hashes", root, name)).unwrap();
+    let golden = FrameHashes::from_bytes(&golden).unwrap();
+    if let Some(frame) = hashes.first_difference(&golden) {
+        let path = std::env::temp_dir().join(format!("{}-last-frame.ppm", name));
+        last_frame.save_ppm(path.to_str().unwrap()).unwrap();
+        panic!("{}: frame {} differs from the golden hashes, the last frame is in {}", name, frame, path.display());
+    }
+}
+
+#[test]
+fn test_nestest_frames() {
+    check_against_golden("nestest");
+}
+
+#[test]
+fn test_blaster_frames() {
+    check_against_golden("blaster");
+}
@@ -0,0 +1,72 @@
+// Runs nestest.nes in automation mode (from $C000, no PPU needed) and diffs
+// our trace against nestest_no_cycle.log line by line. Ignored by default;
+// run with
+//
+//   cargo test --test nestest -- --ignored
+//
+// before touching the CPU core.
+
+use nes_book_emu::bus::Bus;
+use nes_book_emu::cartridge::{self, Rom};
+use nes_book_emu::cpu::CPU;
+use nes_book_emu::trace::trace;
+
+const ROM: &str = "nestest.nes";
+const LOG: &str = "nestest_no_cycle.log";
+// lines of matching trace shown above the first difference
+const CONTEXT_LINES: usize = 5;
+
+fn run_nestest(instructions: usize) -> Vec<String> {
+    let root = env!("CARGO_MANIFEST_DIR");
+    let rom = std::fs::read(format!("{}/{}", root, ROM)).unwrap();
+    let mapper = Rom::new(&rom).and_then(cartridge::create_mapper).unwrap();
+    let bus = Bus::with_mapper(mapper, |_ppu, _joypad1, _joypad2| {});
+    let mut cpu = CPU::new(bus);
+    cpu.reset();
+    cpu.program_counter = 0xc000;
+
+    let mut lines = vec![];
+    cpu.run_with_callback(|cpu| {
+        lines.push(trace(cpu));
+        if lines.len() == instructions {
+            cpu.stop();
+        }
+    });
+    lines
+}
+
+// The value shown for a PPU or APU register is whatever a read would return
+// at that moment; the logging emulator's reads and ours don't have to agree,
+// so those are left out of the comparison.
+fn without_io_value(line: &str) -> String {
+    let operand = line.get(16..48).and_then(|asm| asm.split('$').nth(1)).and_then(|addr| addr.get(0..4));
+    let io = operand
+        .and_then(|addr| u16::from_str_radix(addr, 16).ok())
+        .is_some_and(|addr| (0x2000..0x4020).contains(&addr));
+    match line.find(" = ") {
+        Some(i) if io => format!("{} = ??{}", &line[..i], &line[i + 5..]),
+        _ => line.to_string(),
+    }
+}
+
+#[test]
+#[ignore = "golden log diff, run with --ignored before touching the CPU core"]
+fn test_nestest_golden_log() {
+    let log = std::fs::read_to_string(format!("{}/{}", env!("CARGO_MANIFEST_DIR"), LOG)).unwrap();
+    let expected: Vec<&str> = log.lines().collect();
+    let actual = run_nestest(expected.len());
+
+    for (i, (ours, reference)) in actual.iter().zip(expected.iter()).enumerate() {
+        if without_io_value(ours) != without_io_value(reference) {
+            let context = actual[i.saturating_sub(CONTEXT_LINES)..i].join("\n      ");
+            panic!(
+                "nestest diverges at line {}:\n      {}\n  ours      {}\n  reference {}",
+                i + 1,
+                context,
+                ours,
+                reference
+            );
+        }
+    }
+    assert_eq!(actual.len(), expected.len(), "nestest stopped before the end of the log");
+}